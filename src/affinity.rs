@@ -0,0 +1,128 @@
+use anyhow::anyhow;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+//------------------------------------------
+
+/// A set of cpus, given on the command line in the list format used by
+/// taskset(1) and /sys/devices/system/cpu, eg. "0-7,16-23".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuSet {
+    cpus: Vec<usize>, // sorted, without duplicates
+}
+
+impl CpuSet {
+    pub fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+
+    /// Returns the cpu for the nth of a group of threads, so the threads
+    /// are spread round robin across the set.
+    pub fn nth(&self, n: usize) -> usize {
+        self.cpus[n % self.cpus.len()]
+    }
+
+    fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        // Safety: the cpus were checked against CPU_SETSIZE when parsed, and
+        // a pid of 0 selects the calling thread.
+        let r = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_ZERO(&mut set);
+            for cpu in cpus {
+                libc::CPU_SET(*cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Restricts the calling thread to the set.  Threads spawned afterwards
+    /// inherit the restriction.
+    pub fn pin_current_thread(&self) -> io::Result<()> {
+        Self::set_affinity(&self.cpus)
+    }
+
+    /// Binds the calling thread to a single cpu of the set.
+    pub fn pin_current_thread_to(&self, cpu: usize) -> io::Result<()> {
+        Self::set_affinity(&[cpu])
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_cpu = |s: &str| {
+            let cpu = s
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| anyhow!("invalid cpu '{}'", s))?;
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(anyhow!("cpu {} is out of range", cpu));
+            }
+            Ok(cpu)
+        };
+
+        let mut cpus = Vec::new();
+        for range in s.split(',') {
+            match range.split_once('-') {
+                Some((b, e)) => {
+                    let (b, e) = (parse_cpu(b)?, parse_cpu(e)?);
+                    if e < b {
+                        return Err(anyhow!("badly formed cpu range '{}'", range));
+                    }
+                    cpus.extend(b..=e);
+                }
+                None => cpus.push(parse_cpu(range)?),
+            }
+        }
+
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuSet { cpus })
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpus: Vec<String> = self.cpus.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod cpu_set_tests {
+    use super::*;
+
+    #[test]
+    fn parses_lists_and_ranges() {
+        let set = "8,0-3,2".parse::<CpuSet>().unwrap();
+        assert_eq!(set.cpus(), &[0, 1, 2, 3, 8]);
+        assert_eq!(set.to_string(), "0,1,2,3,8");
+    }
+
+    #[test]
+    fn spreads_round_robin() {
+        let set = "4-5".parse::<CpuSet>().unwrap();
+        assert_eq!(set.nth(0), 4);
+        assert_eq!(set.nth(1), 5);
+        assert_eq!(set.nth(2), 4);
+    }
+
+    #[test]
+    fn rejects_bad_lists() {
+        for s in ["", "a", "1,", "3-1", "0-", "1024"] {
+            assert!(s.parse::<CpuSet>().is_err(), "'{}' was accepted", s);
+        }
+    }
+}
+
+//------------------------------------------
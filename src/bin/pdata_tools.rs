@@ -10,7 +10,9 @@ fn get_basename(path: &OsStr) -> &Path {
 }
 
 fn register_commands<'a>() -> Vec<Box<dyn Command<'a>>> {
-    vec![
+    #[allow(unused_mut)]
+    let mut commands: Vec<Box<dyn Command<'a>>> = vec![
+        Box::new(cache_adjust_hints::CacheAdjustHintsCommand),
         Box::new(cache_check::CacheCheckCommand),
         Box::new(cache_dump::CacheDumpCommand),
         Box::new(cache_metadata_size::CacheMetadataSizeCommand),
@@ -22,19 +24,29 @@ fn register_commands<'a>() -> Vec<Box<dyn Command<'a>>> {
         Box::new(era_invalidate::EraInvalidateCommand),
         Box::new(era_repair::EraRepairCommand),
         Box::new(era_restore::EraRestoreCommand),
+        Box::new(era_writeset::EraWritesetCommand),
         Box::new(thin_check::ThinCheckCommand),
+        Box::new(thin_commit_overlay::ThinCommitOverlayCommand),
+        Box::new(thin_convert_metadata::ThinConvertMetadataCommand),
         Box::new(thin_delta::ThinDeltaCommand),
         Box::new(thin_dump::ThinDumpCommand),
         Box::new(thin_ls::ThinLsCommand),
         Box::new(thin_metadata_pack::ThinMetadataPackCommand),
         Box::new(thin_metadata_size::ThinMetadataSizeCommand),
         Box::new(thin_metadata_unpack::ThinMetadataUnpackCommand),
+        Box::new(thin_provision_report::ThinProvisionReportCommand),
         Box::new(thin_repair::ThinRepairCommand),
         Box::new(thin_restore::ThinRestoreCommand),
         Box::new(thin_rmap::ThinRmapCommand),
         Box::new(thin_shrink::ThinShrinkCommand),
         Box::new(thin_trim::ThinTrimCommand),
-    ]
+    ];
+
+    // thin_top pulls in a terminal ui, so it's only built if asked for
+    #[cfg(feature = "thin_top")]
+    commands.push(Box::new(thin_top::ThinTopCommand));
+
+    commands
 }
 
 fn usage(commands: &[Box<dyn Command>]) {
@@ -0,0 +1,216 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cache::hint::Hint;
+use crate::cache::mapping::Mapping;
+use crate::cache::superblock::*;
+use crate::checksum;
+use crate::commands::engine::*;
+use crate::io_engine::*;
+use crate::pdata::array::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::report::Report;
+
+//------------------------------------------
+
+// The policies shipped with the kernel (smq, and mq which is now an alias
+// for it) keep a single 32 bit level per cache block as their hint, with
+// the hottest blocks at the top level.  Hints are rewritten in place, so
+// no blocks are allocated and the space maps are untouched.
+
+const HINT_SIZE: u32 = 4;
+
+/// The highest level used by the smq policy
+pub const MAX_LEVEL: u32 = 63;
+
+// The names the smq policy goes by
+const LEVEL_POLICIES: [&str; 3] = ["smq", "mq", "default"];
+
+pub struct CacheAdjustHintsOptions<'a> {
+    pub dev: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub reset: bool,
+    pub import: Option<&'a Path>,
+    pub promote: Option<Range<u64>>, // origin blocks
+    pub level: u32,
+    pub report: Arc<Report>,
+}
+
+//------------------------------------------
+
+// Each line of an imported file holds a cache block and its level,
+// separated by whitespace or a comma.  Blank lines and lines starting with
+// '#' are skipped.
+fn read_hints_file(path: &Path, cache_blocks: u32) -> Result<BTreeMap<u32, u32>> {
+    let mut hints = BTreeMap::new();
+    let file = File::open(path)?;
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        if fields.len() != 2 {
+            return Err(anyhow!("line {}: expected a cache block and level", i + 1));
+        }
+
+        let cblock = fields[0]
+            .parse::<u32>()
+            .map_err(|_| anyhow!("line {}: invalid cache block '{}'", i + 1, fields[0]))?;
+        let level = fields[1]
+            .parse::<u32>()
+            .map_err(|_| anyhow!("line {}: invalid level '{}'", i + 1, fields[1]))?;
+        if level > MAX_LEVEL {
+            return Err(anyhow!(
+                "line {}: level {} is above the highest level, {}",
+                i + 1,
+                level,
+                MAX_LEVEL
+            ));
+        }
+        if cblock >= cache_blocks {
+            return Err(anyhow!(
+                "line {}: cache block {} is beyond the {} cache blocks",
+                i + 1,
+                cblock,
+                cache_blocks
+            ));
+        }
+
+        hints.insert(cblock, level);
+    }
+
+    Ok(hints)
+}
+
+// Finds the cache blocks holding the given range of origin blocks
+fn find_cached(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    oblocks: &Range<u64>,
+) -> Result<Vec<u32>> {
+    let mut path = Vec::new();
+    let ablocks = btree_to_value_vec::<u64>(&mut path, engine.clone(), false, sb.mapping_root)?;
+    let max_entries = array::calc_max_entries::<Mapping>() as u32;
+
+    let mut cblocks = Vec::new();
+    for (index, blocknr) in ablocks.iter().enumerate() {
+        let b = engine.read(*blocknr)?;
+        let ablock = unpack_array_block::<Mapping>(&path, b.get_data())?;
+        let cbegin = index as u32 * max_entries;
+        for (m, cblock) in ablock.values.iter().zip(cbegin..) {
+            if m.is_valid() && oblocks.contains(&m.oblock) {
+                cblocks.push(cblock);
+            }
+        }
+    }
+
+    Ok(cblocks)
+}
+
+// Rewrites the hint array, returning the number of hints changed
+fn update_hints(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    reset: bool,
+    levels: &BTreeMap<u32, u32>,
+) -> Result<u64> {
+    let mut path = Vec::new();
+    let ablocks = btree_to_value_vec::<u64>(&mut path, engine.clone(), false, sb.hint_root)?;
+    let max_entries = array::calc_max_entries::<Hint>() as u32;
+
+    let mut nr_changed = 0;
+    for (index, blocknr) in ablocks.iter().enumerate() {
+        let b = engine.read(*blocknr)?;
+        let mut ablock = unpack_array_block::<Hint>(&path, b.get_data())?;
+        let cbegin = index as u32 * max_entries;
+
+        let mut needs_update = false;
+        for (h, cblock) in ablock.values.iter_mut().zip(cbegin..) {
+            let level = match levels.get(&cblock) {
+                Some(level) => *level,
+                None if reset => 0,
+                None => continue,
+            };
+
+            let hint = level.to_le_bytes();
+            if h.hint != hint {
+                h.hint = hint;
+                needs_update = true;
+                nr_changed += 1;
+            }
+        }
+
+        if needs_update {
+            let mut cursor = Cursor::new(b.get_data());
+            pack_array_block(&ablock, &mut cursor)?;
+            checksum::write_checksum(b.get_data(), checksum::BT::ARRAY)?;
+            engine.write(&b)?;
+        }
+    }
+
+    Ok(nr_changed)
+}
+
+//------------------------------------------
+
+pub fn adjust_hints(opts: CacheAdjustHintsOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.dev, &opts.engine_opts)
+        .write(true)
+        .build()?;
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    if sb.flags.needs_check {
+        return Err(anyhow!(
+            "the metadata needs checking, run cache_check before adjusting hints"
+        ));
+    }
+    let policy = String::from_utf8_lossy(&sb.policy_name);
+    if !LEVEL_POLICIES.contains(&policy.as_ref()) {
+        return Err(anyhow!(
+            "the hints of the {} policy aren't levels, only those of smq may be adjusted",
+            policy
+        ));
+    }
+    if sb.policy_hint_size != HINT_SIZE {
+        return Err(anyhow!(
+            "unsupported hint size {}, only {} byte hints may be adjusted",
+            sb.policy_hint_size,
+            HINT_SIZE
+        ));
+    }
+
+    // Imported levels take precedence over the reset, and promotions over
+    // both.
+    let mut levels = match opts.import {
+        Some(path) => read_hints_file(path, sb.cache_blocks)?,
+        None => BTreeMap::new(),
+    };
+
+    if let Some(oblocks) = &opts.promote {
+        for cblock in find_cached(engine.clone(), &sb, oblocks)? {
+            levels.insert(cblock, opts.level);
+        }
+    }
+
+    let nr_changed = update_hints(engine, &sb, opts.reset, &levels)?;
+    opts.report.info(&format!(
+        "adjusted the hints of {} cache blocks",
+        nr_changed
+    ));
+
+    Ok(())
+}
+
+//------------------------------------------
@@ -2,6 +2,7 @@ use anyhow::anyhow;
 use fixedbitset::FixedBitSet;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::thread;
 
 use crate::cache::hint::*;
 use crate::cache::mapping::*;
@@ -30,12 +31,19 @@ fn inc_superblock(sm: &ASpaceMap) -> anyhow::Result<()> {
 
 //------------------------------------------
 
+// The origin blocks the mappings refer to
+struct OriginBlocks {
+    highest: Option<usize>,
+    dirty: Vec<u64>,
+}
+
 mod format1 {
     use super::*;
 
     pub struct MappingChecker {
         nr_origin_blocks: u64,
         seen_oblocks: Mutex<FixedBitSet>,
+        dirty_oblocks: Mutex<Vec<u64>>,
     }
 
     impl MappingChecker {
@@ -44,15 +52,24 @@ mod format1 {
                 MappingChecker {
                     nr_origin_blocks: n,
                     seen_oblocks: Mutex::new(FixedBitSet::with_capacity(n as usize)),
+                    dirty_oblocks: Mutex::new(Vec::new()),
                 }
             } else {
                 MappingChecker {
                     nr_origin_blocks: MAX_ORIGIN_BLOCKS,
                     seen_oblocks: Mutex::new(FixedBitSet::with_capacity(DEFAULT_OBLOCKS)),
+                    dirty_oblocks: Mutex::new(Vec::new()),
                 }
             }
         }
 
+        pub fn into_oblocks(self) -> OriginBlocks {
+            OriginBlocks {
+                highest: self.seen_oblocks.into_inner().unwrap().ones().last(),
+                dirty: self.dirty_oblocks.into_inner().unwrap(),
+            }
+        }
+
         fn check_flags(&self, m: &Mapping) -> array::Result<()> {
             if (m.flags & !(MappingFlags::Valid as u32 | MappingFlags::Dirty as u32)) != 0 {
                 return Err(array::value_err(format!(
@@ -105,6 +122,9 @@ mod format1 {
                 if let Err(e) = self.check_oblock(m) {
                     errs.push(e);
                 }
+                if m.is_valid() && m.is_dirty() {
+                    self.dirty_oblocks.lock().unwrap().push(m.oblock);
+                }
             }
 
             // FIXME: duplicate to BTreeWalker::build_aggregate()
@@ -128,6 +148,7 @@ mod format2 {
     struct Inner {
         seen_oblocks: FixedBitSet,
         dirty_bits: CheckedBitSet,
+        dirty_oblocks: Vec<u64>,
     }
 
     impl MappingChecker {
@@ -138,6 +159,7 @@ mod format2 {
                     inner: Mutex::new(Inner {
                         seen_oblocks: FixedBitSet::with_capacity(n as usize),
                         dirty_bits,
+                        dirty_oblocks: Vec::new(),
                     }),
                 }
             } else {
@@ -146,11 +168,20 @@ mod format2 {
                     inner: Mutex::new(Inner {
                         seen_oblocks: FixedBitSet::with_capacity(DEFAULT_OBLOCKS),
                         dirty_bits,
+                        dirty_oblocks: Vec::new(),
                     }),
                 }
             }
         }
 
+        pub fn into_oblocks(self) -> OriginBlocks {
+            let inner = self.inner.into_inner().unwrap();
+            OriginBlocks {
+                highest: inner.seen_oblocks.ones().last(),
+                dirty: inner.dirty_oblocks,
+            }
+        }
+
         fn check_flags(&self, m: &Mapping, dirty_bit: Option<bool>) -> array::Result<()> {
             if (m.flags & !(MappingFlags::Valid as u32)) != 0 {
                 return Err(array::value_err(format!(
@@ -199,12 +230,16 @@ mod format2 {
             let cbegin = index as u32 * b.header.max_entries;
             let cend = cbegin + b.header.nr_entries;
             for (m, cblock) in b.values.iter().zip(cbegin..cend) {
-                if let Err(e) = self.check_flags(m, inner.dirty_bits.contains(cblock as usize)) {
+                let dirty_bit = inner.dirty_bits.contains(cblock as usize);
+                if let Err(e) = self.check_flags(m, dirty_bit) {
                     errs.push(e);
                 }
                 if let Err(e) = self.check_oblock(m, &mut inner.seen_oblocks) {
                     errs.push(e);
                 }
+                if m.is_valid() && dirty_bit == Some(true) {
+                    inner.dirty_oblocks.push(m.oblock);
+                }
             }
 
             // FIXME: duplicate to BTreeWalker::build_aggregate()
@@ -249,15 +284,16 @@ pub struct CacheCheckOptions<'a> {
     pub report: Arc<Report>,
 }
 
-// TODO: thread pool
 struct Context {
     report: Arc<Report>,
     engine: Arc<dyn IoEngine + Send + Sync>,
 }
 
 fn mk_context(opts: &CacheCheckOptions) -> anyhow::Result<Context> {
+    let writable = opts.auto_repair | opts.clear_needs_check;
     let engine = EngineBuilder::new(opts.dev, &opts.engine_opts)
-        .write(opts.auto_repair | opts.clear_needs_check)
+        .write(writable)
+        .read_only(!writable)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
 
@@ -267,11 +303,221 @@ fn mk_context(opts: &CacheCheckOptions) -> anyhow::Result<Context> {
     })
 }
 
+fn check_mappings(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    metadata_sm: ASpaceMap,
+    sb: &Superblock,
+    nr_origin_blocks: Option<u64>,
+    ignore_non_fatal: bool,
+) -> anyhow::Result<OriginBlocks> {
+    let w = ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), ignore_non_fatal)?;
+    match sb.version {
+        1 => {
+            let c = format1::MappingChecker::new(nr_origin_blocks);
+            if let Err(e) = w.walk(&c, sb.mapping_root) {
+                report.fatal(&format!("{}", e));
+            }
+            Ok(c.into_oblocks())
+        }
+        2 => {
+            let (dirty_bits, err) = read_bitset_checked_with_sm(
+                engine,
+                sb.dirty_root.unwrap(),
+                sb.cache_blocks as usize,
+                metadata_sm,
+                ignore_non_fatal,
+            )?;
+            if err.is_some() {
+                report.fatal(&format!("{}", err.unwrap()));
+            }
+            let c = format2::MappingChecker::new(nr_origin_blocks, dirty_bits);
+            if let Err(e) = w.walk(&c, sb.mapping_root) {
+                report.fatal(&format!("{}", e));
+            }
+            Ok(c.into_oblocks())
+        }
+        v => Err(anyhow!("unsupported metadata version {}", v)),
+    }
+}
+
+fn check_hints(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    metadata_sm: ASpaceMap,
+    hint_root: u64,
+    ignore_non_fatal: bool,
+) -> anyhow::Result<()> {
+    let w = ArrayWalker::new_with_sm(engine, metadata_sm, ignore_non_fatal)?;
+    let c = HintChecker::new();
+    if let Err(e) = w.walk(&c, hint_root) {
+        report.fatal(&format!("{}", e));
+    }
+    Ok(())
+}
+
+fn check_discards(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    metadata_sm: ASpaceMap,
+    discard_root: u64,
+    discard_nr_blocks: u64,
+    ignore_non_fatal: bool,
+) -> anyhow::Result<CheckedBitSet> {
+    let (discard_bits, err) = read_bitset_checked_with_sm(
+        engine,
+        discard_root,
+        discard_nr_blocks as usize,
+        metadata_sm,
+        ignore_non_fatal,
+    )?;
+    if err.is_some() {
+        report.fatal(&format!("{}", err.unwrap()));
+    } else {
+        let nr_present = (0..discard_nr_blocks as usize)
+            .filter(|b| discard_bits.contains(*b).is_some())
+            .count();
+        if nr_present < discard_nr_blocks as usize {
+            report.fatal(&discard_error(
+                "D003",
+                format!(
+                    "the discard bitset holds {} bits, but the superblock gives {} discard blocks",
+                    nr_present, discard_nr_blocks
+                ),
+            ));
+        }
+    }
+    Ok(discard_bits)
+}
+
+//------------------------------------------
+
+// Inconsistencies between the discard bitset and the rest of the metadata.
+// The kernel loads the bitset as it is, so they'd otherwise only show up as
+// odd discard behaviour.  Each is reported with its own code, as an error or,
+// where the data isn't at risk, a warning.
+
+fn discard_error(code: &str, msg: String) -> String {
+    format!("error {}: {}", code, msg)
+}
+
+fn discard_warning(code: &str, msg: String) -> String {
+    format!("warning {}: {}", code, msg)
+}
+
+fn check_discard_geometry(sb: &Superblock) -> anyhow::Result<()> {
+    if sb.discard_nr_blocks == 0 {
+        return Ok(());
+    }
+
+    let bs = sb.data_block_size as u64;
+    if sb.discard_block_size == 0 || bs == 0 || sb.discard_block_size % bs != 0 {
+        return Err(anyhow!(discard_error(
+            "D001",
+            format!(
+                "the discard block size of {} sectors is not a multiple of the data block size of {} sectors",
+                sb.discard_block_size, bs
+            ),
+        )));
+    }
+
+    Ok(())
+}
+
+// The superblock doesn't record the size of the origin, but it's at least
+// large enough to hold every mapped block, and the kernel sizes the discard
+// bitset to cover the whole origin.  The bitset is only written on a clean
+// shutdown, so after a crash it may predate a resize of the origin.
+fn check_discard_coverage(report: &Report, sb: &Superblock, highest_oblock: Option<usize>) {
+    let highest = match highest_oblock {
+        Some(b) if sb.discard_nr_blocks > 0 => b as u128,
+        _ => return,
+    };
+
+    let discard_sectors = sb.discard_block_size as u128 * sb.discard_nr_blocks as u128;
+    let mapped_sectors = (highest + 1) * sb.data_block_size as u128;
+    if discard_sectors < mapped_sectors {
+        let msg = format!(
+            "the discard bitset covers {} sectors, but origin block {} is mapped, which ends at sector {}",
+            discard_sectors, highest, mapped_sectors
+        );
+        if sb.flags.clean_shutdown {
+            report.fatal(&discard_error("D002", msg));
+        } else {
+            report.warning(&discard_warning("D002", msg));
+        }
+    }
+}
+
+// A write to a discarded region clears its discard bit, so no dirty block
+// should lie within one.  The mapping is looked up before the discard bit,
+// so the dirty data is still read and written back, and this is only a
+// warning.
+fn discarded_dirty_oblocks(
+    data_block_size: u32,
+    discard_block_size: u64,
+    dirty_oblocks: &[u64],
+    discard_bits: &CheckedBitSet,
+) -> Vec<u64> {
+    if discard_block_size == 0 {
+        return Vec::new();
+    }
+
+    dirty_oblocks
+        .iter()
+        .copied()
+        .filter(|oblock| {
+            let sector = *oblock as u128 * data_block_size as u128;
+            let dblock = sector / discard_block_size as u128;
+            dblock < discard_bits.len() as u128
+                && discard_bits.contains(dblock as usize) == Some(true)
+        })
+        .collect()
+}
+
+fn check_dirty_not_discarded(
+    report: &Report,
+    sb: &Superblock,
+    dirty_oblocks: &[u64],
+    discard_bits: &CheckedBitSet,
+) {
+    let overlaps = discarded_dirty_oblocks(
+        sb.data_block_size,
+        sb.discard_block_size,
+        dirty_oblocks,
+        discard_bits,
+    );
+    if let Some(first) = overlaps.iter().min() {
+        report.warning(&discard_warning(
+            "D004",
+            format!(
+                "{} dirty blocks lie within discarded regions, the first at origin block {}",
+                overlaps.len(),
+                first
+            ),
+        ));
+    }
+}
+
+//------------------------------------------
+
 fn check_superblock(sb: &Superblock) -> anyhow::Result<()> {
     if sb.version >= 2 && sb.dirty_root.unwrap_or(0) == 0 {
         return Err(anyhow!("dirty bitset not found"));
     }
-    Ok(())
+    check_discard_geometry(sb)
+}
+
+fn join_walker<T>(
+    walker: Option<thread::JoinHandle<anyhow::Result<T>>>,
+) -> anyhow::Result<Option<T>> {
+    match walker {
+        Some(h) => h
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("metadata walker panicked")))
+            .map(Some),
+        None => Ok(None),
+    }
 }
 
 pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
@@ -301,64 +547,90 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
             None
         };
 
-    // TODO: factor out into check_mappings()
-    if !opts.skip_mappings {
-        let w =
-            ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), opts.ignore_non_fatal)?;
-        match sb.version {
-            1 => {
-                let c = format1::MappingChecker::new(nr_origin_blocks);
-                if let Err(e) = w.walk(&c, sb.mapping_root) {
-                    ctx.report.fatal(&format!("{}", e));
-                }
-            }
-            2 => {
-                let (dirty_bits, err) = read_bitset_checked_with_sm(
-                    engine.clone(),
-                    sb.dirty_root.unwrap(),
-                    sb.cache_blocks as usize,
-                    metadata_sm.clone(),
-                    opts.ignore_non_fatal,
-                )?;
-                if err.is_some() {
-                    ctx.report.fatal(&format!("{}", err.unwrap()));
-                }
-                let c = format2::MappingChecker::new(nr_origin_blocks, dirty_bits);
-                if let Err(e) = w.walk(&c, sb.mapping_root) {
-                    ctx.report.fatal(&format!("{}", e));
-                }
-            }
-            v => {
-                return Err(anyhow!("unsupported metadata version {}", v));
-            }
-        }
+    let walk_hints = !opts.skip_hints && sb.hint_root != 0 && sb.policy_hint_size != 0;
+    if walk_hints && sb.policy_hint_size != 4 {
+        return Err(anyhow!("cache_check only supports policy hint size of 4"));
     }
 
-    if !opts.skip_hints && sb.hint_root != 0 && sb.policy_hint_size != 0 {
-        if sb.policy_hint_size != 4 {
-            return Err(anyhow!("cache_check only supports policy hint size of 4"));
-        }
-        let w =
-            ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), opts.ignore_non_fatal)?;
-        let c = HintChecker::new();
-        if let Err(e) = w.walk(&c, sb.hint_root) {
-            ctx.report.fatal(&format!("{}", e));
-        }
-    }
+    // Progress is measured by the metadata blocks visited so far
+    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let mon_sm = metadata_sm.clone();
+    let monitor = ProgressMonitor::new(ctx.report.clone(), metadata_root.nr_allocated, move || {
+        mon_sm.lock().unwrap().get_nr_allocated().unwrap()
+    });
+
+    // The mapping array, hint array and discard bitset are independent
+    // structures, so they're walked concurrently.  The metadata space map
+    // they count into is shared.
+    let mapping_walker = if !opts.skip_mappings {
+        let engine = engine.clone();
+        let report = ctx.report.clone();
+        let metadata_sm = metadata_sm.clone();
+        let sb = sb.clone();
+        let ignore_non_fatal = opts.ignore_non_fatal;
+        Some(thread::spawn(move || {
+            check_mappings(
+                engine,
+                report,
+                metadata_sm,
+                &sb,
+                nr_origin_blocks,
+                ignore_non_fatal,
+            )
+        }))
+    } else {
+        None
+    };
+
+    let hint_walker = if walk_hints {
+        let engine = engine.clone();
+        let report = ctx.report.clone();
+        let metadata_sm = metadata_sm.clone();
+        let hint_root = sb.hint_root;
+        let ignore_non_fatal = opts.ignore_non_fatal;
+        Some(thread::spawn(move || {
+            check_hints(engine, report, metadata_sm, hint_root, ignore_non_fatal)
+        }))
+    } else {
+        None
+    };
 
     // The discard bitset might not be available if the cache has never been suspended,
     // e.g., a crash of freshly created cache.
-    if !opts.skip_discards && sb.discard_root != 0 {
-        let (_discard_bits, err) = read_bitset_checked_with_sm(
-            engine.clone(),
-            sb.discard_root,
-            sb.discard_nr_blocks as usize,
-            metadata_sm.clone(),
-            opts.ignore_non_fatal,
-        )?;
-        if err.is_some() {
-            ctx.report.fatal(&format!("{}", err.unwrap()));
-        }
+    let discard_walker = if !opts.skip_discards && sb.discard_root != 0 {
+        let engine = engine.clone();
+        let report = ctx.report.clone();
+        let metadata_sm = metadata_sm.clone();
+        let (discard_root, discard_nr_blocks) = (sb.discard_root, sb.discard_nr_blocks);
+        let ignore_non_fatal = opts.ignore_non_fatal;
+        Some(thread::spawn(move || {
+            check_discards(
+                engine,
+                report,
+                metadata_sm,
+                discard_root,
+                discard_nr_blocks,
+                ignore_non_fatal,
+            )
+        }))
+    } else {
+        None
+    };
+
+    // Join all the walkers before returning any error
+    let oblocks = join_walker(mapping_walker);
+    let hints = join_walker(hint_walker);
+    let discard_bits = join_walker(discard_walker);
+    monitor.stop();
+    let oblocks = oblocks?;
+    hints?;
+    let discard_bits = discard_bits?;
+
+    if let Some(oblocks) = &oblocks {
+        check_discard_coverage(&ctx.report, &sb, oblocks.highest);
+    }
+    if let (Some(oblocks), Some(discard_bits)) = (oblocks, discard_bits) {
+        check_dirty_not_discarded(&ctx.report, &sb, &oblocks.dirty, &discard_bits);
     }
 
     let outcome = ctx.report.get_outcome();
@@ -413,3 +685,84 @@ fn clear_needs_check_flag(engine: Arc<dyn IoEngine + Send + Sync>) -> anyhow::Re
 }
 
 //------------------------------------------
+
+#[cfg(test)]
+mod discard_tests {
+    use super::*;
+
+    #[test]
+    fn finds_dirty_blocks_in_discarded_regions() {
+        // 4 data blocks per discard block, with the second one discarded
+        let mut bits = CheckedBitSet::with_capacity(4);
+        for b in 0..4 {
+            bits.set(b, b == 1);
+        }
+
+        let dirty = [0, 3, 4, 7, 8, 100];
+        assert_eq!(discarded_dirty_oblocks(64, 256, &dirty, &bits), vec![4, 7]);
+        assert!(discarded_dirty_oblocks(64, 0, &dirty, &bits).is_empty());
+    }
+
+    // 64 sector blocks, with a discard bitset covering 16 of them
+    fn mk_sb(clean_shutdown: bool) -> Superblock {
+        Superblock {
+            flags: SuperblockFlags {
+                clean_shutdown,
+                needs_check: false,
+            },
+            block: SUPERBLOCK_LOCATION,
+            version: 2,
+            policy_name: b"smq".to_vec(),
+            policy_version: vec![2, 0, 0],
+            policy_hint_size: 4,
+            metadata_sm_root: vec![0; SPACE_MAP_ROOT_SIZE],
+            mapping_root: 0,
+            dirty_root: Some(0),
+            hint_root: 0,
+            discard_root: 0,
+            discard_block_size: 256,
+            discard_nr_blocks: 4,
+            data_block_size: 64,
+            cache_blocks: 0,
+            compat_flags: 0,
+            compat_ro_flags: 0,
+            incompat_flags: 0,
+            read_hits: 0,
+            read_misses: 0,
+            write_hits: 0,
+            write_misses: 0,
+        }
+    }
+
+    #[test]
+    fn discard_bitset_must_cover_the_mappings() {
+        let report = mk_quiet_report();
+        check_discard_coverage(&report, &mk_sb(true), Some(15));
+        check_discard_coverage(&report, &mk_sb(true), None);
+        assert!(report.get_outcome() == ReportOutcome::Success);
+
+        check_discard_coverage(&report, &mk_sb(true), Some(16));
+        assert!(report.get_outcome() == ReportOutcome::Fatal);
+    }
+
+    #[test]
+    fn short_discard_bitset_after_a_crash_is_a_warning() {
+        let report = mk_quiet_report();
+        check_discard_coverage(&report, &mk_sb(false), Some(100));
+        assert!(report.get_outcome() == ReportOutcome::Success);
+    }
+
+    #[test]
+    fn dirty_blocks_in_discarded_regions_are_a_warning() {
+        let mut bits = CheckedBitSet::with_capacity(4);
+        for b in 0..4 {
+            bits.set(b, true);
+        }
+
+        let report = mk_quiet_report();
+        check_dirty_not_discarded(&report, &mk_sb(true), &[0, 5], &bits);
+        assert!(report.get_outcome() == ReportOutcome::Success);
+    }
+}
+
+//------------------------------------------
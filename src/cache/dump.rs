@@ -3,6 +3,7 @@ use fixedbitset::FixedBitSet;
 use std::fs::File;
 use std::io::BufWriter;
 use std::io::Write;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
@@ -17,6 +18,7 @@ use crate::io_engine::*;
 use crate::pdata::array::ArrayBlock;
 use crate::pdata::array_walker::*;
 use crate::pdata::bitset::{read_bitset_checked, CheckedBitSet};
+use crate::report::Report;
 
 //------------------------------------------
 
@@ -299,20 +301,106 @@ impl<'a> MetadataVisitor for OutputVisitor<'a> {
 
 //------------------------------------------
 
+// Passes on only the mappings into a range of origin blocks, along with
+// the hints of the cache blocks holding them.
+struct OriginFilter<'a> {
+    out: &'a mut dyn MetadataVisitor,
+    oblocks: Range<u64>,
+    selected: FixedBitSet,
+}
+
+impl<'a> OriginFilter<'a> {
+    fn new(out: &'a mut dyn MetadataVisitor, oblocks: Range<u64>) -> Self {
+        Self {
+            out,
+            oblocks,
+            selected: FixedBitSet::new(),
+        }
+    }
+}
+
+impl<'a> MetadataVisitor for OriginFilter<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> anyhow::Result<ir::Visit> {
+        self.selected = FixedBitSet::with_capacity(sb.nr_cache_blocks as usize);
+        self.out.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.superblock_e()
+    }
+
+    fn mappings_b(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.mappings_b()
+    }
+
+    fn mappings_e(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.mappings_e()
+    }
+
+    fn mapping(&mut self, m: &ir::Map) -> anyhow::Result<ir::Visit> {
+        if !self.oblocks.contains(&m.oblock) {
+            return Ok(ir::Visit::Continue);
+        }
+        self.selected.insert(m.cblock as usize);
+        self.out.mapping(m)
+    }
+
+    fn hints_b(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.hints_b()
+    }
+
+    fn hints_e(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.hints_e()
+    }
+
+    fn hint(&mut self, h: &ir::Hint) -> anyhow::Result<ir::Visit> {
+        if !self.selected.contains(h.cblock as usize) {
+            return Ok(ir::Visit::Continue);
+        }
+        self.out.hint(h)
+    }
+
+    fn discards_b(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.discards_b()
+    }
+
+    fn discards_e(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.discards_e()
+    }
+
+    fn discard(&mut self, d: &ir::Discard) -> anyhow::Result<ir::Visit> {
+        self.out.discard(d)
+    }
+
+    fn eof(&mut self) -> anyhow::Result<ir::Visit> {
+        self.out.eof()
+    }
+}
+
+//------------------------------------------
+
 pub struct CacheDumpOptions<'a> {
     pub input: &'a Path,
     pub output: Option<&'a Path>,
     pub engine_opts: EngineOptions,
     pub repair: bool,
+    pub origin_range: Option<Range<u64>>, // origin blocks
+    pub report: Arc<Report>,
 }
 
 struct CacheDumpContext {
     engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
 }
 
 fn mk_context(opts: &CacheDumpOptions) -> anyhow::Result<CacheDumpContext> {
-    let engine = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
-    Ok(CacheDumpContext { engine })
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
+        .build()?;
+    Ok(CacheDumpContext {
+        engine,
+        report: opts.report.clone(),
+    })
 }
 
 pub fn dump_metadata(
@@ -377,6 +465,18 @@ pub fn dump(opts: CacheDumpOptions) -> anyhow::Result<()> {
     };
     let mut out = xml::XmlWriter::new(writer);
 
+    if let Some(oblocks) = opts.origin_range {
+        let mut filter = OriginFilter::new(&mut out, oblocks.clone());
+        dump_metadata(ctx.engine, &mut filter, &sb, opts.repair)?;
+        ctx.report.info(&format!(
+            "dumped {} mappings of origin blocks {}..{}",
+            filter.selected.count_ones(..),
+            oblocks.start,
+            oblocks.end
+        ));
+        return Ok(());
+    }
+
     dump_metadata(ctx.engine, &mut out, &sb, opts.repair)
 }
 
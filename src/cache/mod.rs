@@ -1,3 +1,4 @@
+pub mod adjust_hints;
 pub mod check;
 pub mod dump;
 pub mod hint;
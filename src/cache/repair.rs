@@ -3,6 +3,7 @@ use std::path::Path;
 use std::sync::Arc;
 
 use crate::cache::dump::*;
+use crate::cache::ir::{self, MetadataVisitor};
 use crate::cache::restore::*;
 use crate::cache::superblock::*;
 use crate::commands::engine::*;
@@ -21,7 +22,7 @@ pub struct CacheRepairOptions<'a> {
 }
 
 struct Context {
-    _report: Arc<Report>,
+    report: Arc<Report>,
     engine_in: Arc<dyn IoEngine + Send + Sync>,
     engine_out: Arc<dyn IoEngine + Send + Sync>,
 }
@@ -33,7 +34,7 @@ fn new_context(opts: &CacheRepairOptions) -> Result<Context> {
         .build()?;
 
     Ok(Context {
-        _report: opts.report.clone(),
+        report: opts.report.clone(),
         engine_in,
         engine_out,
     })
@@ -41,6 +42,102 @@ fn new_context(opts: &CacheRepairOptions) -> Result<Context> {
 
 //------------------------------------------
 
+// Reports the progress of the repair by the cache blocks passed on.  Each
+// cache block is seen once for its mapping and once for its hint, in
+// roughly ascending order, and a record is only written as the percentage
+// changes.
+struct ProgressVisitor<'a> {
+    out: &'a mut dyn MetadataVisitor,
+    report: Arc<Report>,
+    total: u64,
+    offset: u64, // the blocks done in earlier passes
+    completed: u64,
+    percent: u64,
+}
+
+impl<'a> ProgressVisitor<'a> {
+    fn new(out: &'a mut dyn MetadataVisitor, report: Arc<Report>) -> Self {
+        ProgressVisitor {
+            out,
+            report,
+            total: 0,
+            offset: 0,
+            completed: 0,
+            percent: 0,
+        }
+    }
+
+    fn update(&mut self, cblock: u32) {
+        self.completed = self.completed.max(self.offset + cblock as u64 + 1);
+        let percent = self.completed * 100 / self.total.max(1);
+        if percent > self.percent {
+            self.percent = percent;
+            self.report.progress_of(self.completed, self.total);
+        }
+    }
+}
+
+impl<'a> MetadataVisitor for ProgressVisitor<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<ir::Visit> {
+        self.total = sb.nr_cache_blocks as u64 * 2;
+        self.report.set_title("Repairing cache metadata");
+        self.report.progress_of(0, self.total);
+        self.out.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<ir::Visit> {
+        self.out.superblock_e()
+    }
+
+    fn mappings_b(&mut self) -> Result<ir::Visit> {
+        self.offset = 0;
+        self.out.mappings_b()
+    }
+
+    fn mappings_e(&mut self) -> Result<ir::Visit> {
+        self.out.mappings_e()
+    }
+
+    fn mapping(&mut self, m: &ir::Map) -> Result<ir::Visit> {
+        self.update(m.cblock);
+        self.out.mapping(m)
+    }
+
+    fn hints_b(&mut self) -> Result<ir::Visit> {
+        self.offset = self.total / 2;
+        self.out.hints_b()
+    }
+
+    fn hints_e(&mut self) -> Result<ir::Visit> {
+        self.out.hints_e()
+    }
+
+    fn hint(&mut self, h: &ir::Hint) -> Result<ir::Visit> {
+        self.update(h.cblock);
+        self.out.hint(h)
+    }
+
+    fn discards_b(&mut self) -> Result<ir::Visit> {
+        self.out.discards_b()
+    }
+
+    fn discards_e(&mut self) -> Result<ir::Visit> {
+        self.out.discards_e()
+    }
+
+    fn discard(&mut self, d: &ir::Discard) -> Result<ir::Visit> {
+        self.out.discard(d)
+    }
+
+    fn eof(&mut self) -> Result<ir::Visit> {
+        // mappings and hints of invalid blocks are never seen
+        self.report.progress_of(self.total, self.total);
+        self.out.eof()
+    }
+}
+
+//------------------------------------------
+
 pub fn repair(opts: CacheRepairOptions) -> Result<()> {
     let ctx = new_context(&opts)?;
 
@@ -48,10 +145,14 @@ pub fn repair(opts: CacheRepairOptions) -> Result<()> {
 
     let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
     let batch_size = ctx.engine_out.get_batch_size();
-    let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size);
+    let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size)
+        .with_sync_policy(opts.engine_opts.sync_policy);
     let mut restorer = Restorer::new(&mut w, sb.version as u8);
+    let mut progress = ProgressVisitor::new(&mut restorer, ctx.report.clone());
 
-    dump_metadata(ctx.engine_in, &mut restorer, &sb, true)
+    dump_metadata(ctx.engine_in, &mut progress, &sb, true)?;
+    ctx.report.complete();
+    Ok(())
 }
 
 //------------------------------------------
@@ -1,7 +1,6 @@
 use anyhow::{anyhow, Result};
 
 use std::convert::TryInto;
-use std::fs::OpenOptions;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -18,6 +17,7 @@ use crate::pdata::space_map::common::pack_root;
 use crate::pdata::space_map::metadata::*;
 use crate::report::*;
 use crate::write_batcher::*;
+use crate::xml::open_input;
 
 //------------------------------------------
 
@@ -28,6 +28,8 @@ pub struct CacheRestoreOptions<'a> {
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
     pub omit_clean_shutdown: bool,
+    /// Lay the metadata out the same way on any machine
+    pub deterministic: bool,
 }
 
 struct Context {
@@ -310,15 +312,14 @@ fn build_metadata_sm(w: &mut WriteBatcher) -> Result<Vec<u8>> {
 //------------------------------------------
 
 pub fn restore(opts: CacheRestoreOptions) -> Result<()> {
-    let input = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(opts.input)?;
+    let input = open_input(opts.input)?;
 
     let ctx = mk_context(&opts)?;
 
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), u32::MAX);
-    let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
+    let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size())
+        .with_sync_policy(opts.engine_opts.sync_policy)
+        .with_deterministic_layout(opts.deterministic);
 
     // build cache mappings
     let mut restorer = Restorer::new(&mut w, opts.metadata_version);
@@ -1,12 +1,13 @@
 use anyhow::anyhow;
 use roaring::RoaringBitmap;
 use std::fs::File;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::{self, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::cache::mapping::*;
 use crate::cache::superblock::*;
@@ -14,6 +15,7 @@ use crate::checksum;
 use crate::commands::engine::*;
 use crate::copier::batcher::CopyOpBatcher;
 use crate::copier::*;
+use crate::io_engine::errors::find_block_io_error;
 use crate::io_engine::utils::{SimpleBlockIo, VectoredBlockIo};
 use crate::io_engine::{self, *};
 use crate::pdata::array::{self, *};
@@ -39,18 +41,176 @@ struct WritebackStats {
     nr_write_errors: u64,
 }
 
+/// Writes the progress of the writeback, then a summary once it's done, to
+/// a file as json lines.  This lets whatever is driving the writeback track
+/// it without scraping the progress bar.  The summary is written whether or
+/// not the writeback succeeded.
+struct EventLog {
+    file: Mutex<Option<File>>,
+    last_progress: Mutex<Option<Instant>>,
+
+    // The stats of the last progress event, for the summary of a failed
+    // writeback
+    last_stats: Mutex<Option<WritebackStats>>,
+    nr_errors: AtomicU64,
+    summarised: AtomicBool,
+}
+
+// Progress updates from within a batch are limited to one a second
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+impl EventLog {
+    fn new(file: Option<File>) -> Self {
+        Self {
+            file: Mutex::new(file),
+            last_progress: Mutex::new(None),
+            last_stats: Mutex::new(None),
+            nr_errors: AtomicU64::new(0),
+            summarised: AtomicBool::new(false),
+        }
+    }
+
+    fn write(&self, event: &str) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            // A reader that went away mustn't stop the writeback
+            let _ = writeln!(file, "{}", event);
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    fn progress(&self, phase: &str, stats: &WritebackStats, throttle: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+        *self.last_stats.lock().unwrap() = Some(stats.clone());
+
+        {
+            let mut last = self.last_progress.lock().unwrap();
+            if throttle && last.map_or(false, |t| t.elapsed() < PROGRESS_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+
+        self.write(&format!(
+            "{{\"event\": \"progress\", \"phase\": \"{}\", \"copied\": {}, \"total\": {}, \"read_errors\": {}, \"write_errors\": {}}}",
+            phase, stats.nr_copied, stats.nr_blocks, stats.nr_read_errors, stats.nr_write_errors
+        ));
+    }
+
+    fn error(&self, e: &anyhow::Error) {
+        self.nr_errors.fetch_add(1, Ordering::Relaxed);
+        self.write(&error_event(e));
+    }
+
+    fn summary(&self, s: &WritebackSummary) {
+        self.summarised.store(true, Ordering::Relaxed);
+        let failed = s.stats.nr_read_errors + s.stats.nr_write_errors;
+        self.write(&format!(
+            "{{\"event\": \"summary\", \"dirty\": {}, \"flushed\": {}, \"skipped\": {}, \"failed\": {}, \"read_errors\": {}, \"write_errors\": {}, \"metadata_updated\": {}, \"succeeded\": {}, \"elapsed_secs\": {:.3}}}",
+            s.stats.nr_blocks,
+            s.stats.nr_copied,
+            s.nr_skipped,
+            failed,
+            s.stats.nr_read_errors,
+            s.stats.nr_write_errors,
+            s.metadata_updated,
+            s.succeeded,
+            s.elapsed.as_secs_f64()
+        ));
+    }
+
+    // Makes sure a writeback that stopped early has an error event and a
+    // summary, giving what was copied before it stopped.
+    fn failed(&self, e: &anyhow::Error, elapsed: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.nr_errors.load(Ordering::Relaxed) == 0 {
+            self.error(e);
+        }
+        if !self.summarised.load(Ordering::Relaxed) {
+            let stats = self.last_stats.lock().unwrap().clone();
+            self.summary(&WritebackSummary {
+                stats: stats.unwrap_or(WritebackStats {
+                    nr_blocks: 0,
+                    nr_copied: 0,
+                    nr_read_errors: 0,
+                    nr_write_errors: 0,
+                }),
+                nr_skipped: 0,
+                metadata_updated: false,
+                succeeded: false,
+                elapsed,
+            });
+        }
+    }
+}
+
+// An io error of a metadata block is given with its class and location, so
+// a media error can be told apart from a device that has gone away.
+fn error_event(e: &anyhow::Error) -> String {
+    let msg = json_escape(&e.to_string());
+    match find_block_io_error(e) {
+        Some(be) => format!(
+            "{{\"event\": \"error\", \"message\": \"{}\", \"io_error\": \"{}\", \"block\": {}}}",
+            msg,
+            be.class.as_str(),
+            be.loc
+        ),
+        None => format!("{{\"event\": \"error\", \"message\": \"{}\"}}", msg),
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The final outcome of a writeback
+struct WritebackSummary {
+    stats: WritebackStats,
+
+    /// Number of cached blocks that were clean, so didn't need copying
+    nr_skipped: u64,
+
+    metadata_updated: bool,
+    succeeded: bool,
+    elapsed: Duration,
+}
+
 /// Updates the progress bar in the reporter as the stats are
 /// updated by the copier threads.
 struct ProgressReporter {
     report: Arc<Report>,
+    events: Arc<EventLog>,
+    phase: &'static str,
     inner: Mutex<WritebackStats>,
 }
 
 impl ProgressReporter {
     /// nr_blocks - nr to be copied
-    fn new(report: Arc<Report>, nr_blocks: u64) -> Self {
+    fn new(
+        report: Arc<Report>,
+        events: Arc<EventLog>,
+        phase: &'static str,
+        nr_blocks: u64,
+    ) -> Self {
         Self {
             report,
+            events,
+            phase,
             inner: Mutex::new(WritebackStats {
                 nr_blocks,
                 nr_copied: 0,
@@ -76,6 +236,7 @@ impl ProgressReporter {
             .checked_div(inner.nr_blocks)
             .unwrap_or(100);
         self.report.progress(percent as u8);
+        self.events.progress(self.phase, &inner, false);
     }
 
     /// Accessor for the current stats
@@ -99,6 +260,14 @@ impl CopyProgress for ProgressReporter {
 
         let percent = (inner.nr_copied + stats.nr_copied * 100) / inner.nr_blocks;
         self.report.progress(percent as u8);
+
+        let current = WritebackStats {
+            nr_blocks: inner.nr_blocks,
+            nr_copied: inner.nr_copied + stats.nr_copied,
+            nr_read_errors: inner.nr_read_errors + stats.read_errors.len() as u64,
+            nr_write_errors: inner.nr_write_errors + stats.write_errors.len() as u64,
+        };
+        self.events.progress(self.phase, &current, true);
     }
 }
 
@@ -596,11 +765,13 @@ pub struct CacheWritebackOptions<'a> {
     pub list_failed_blocks: bool,
     pub update_metadata: bool,
     pub retry_count: u32,
+    pub json_progress: Option<&'a Path>,
     pub report: Arc<Report>,
 }
 
 struct Context {
     report: Arc<Report>,
+    events: Arc<EventLog>,
     engine: Arc<dyn IoEngine + Send + Sync>,
 }
 
@@ -609,8 +780,14 @@ fn mk_context(opts: &CacheWritebackOptions) -> anyhow::Result<Context> {
         .write(true)
         .build()?;
 
+    let events = match opts.json_progress {
+        Some(path) => Some(File::create(path)?),
+        None => None,
+    };
+
     Ok(Context {
         report: opts.report.clone(),
+        events: Arc::new(EventLog::new(events)),
         engine,
     })
 }
@@ -643,7 +820,7 @@ fn copy_dirty_blocks(
             .dest_offset(origin_dev_offset)?,
         );
 
-        copy_all_dirty_blocks(ctx.engine.clone(), sb, copier, ctx.report.clone())?
+        copy_all_dirty_blocks(ctx, sb, copier)?
     };
 
     // Retry blocks ignoed by vectored io
@@ -661,8 +838,7 @@ fn copy_dirty_blocks(
 
         let failed = &read_failed | &write_failed;
         let c;
-        (c, read_failed, write_failed) =
-            copy_selected_blocks(ctx.engine.clone(), sb, copier, &failed, ctx.report.clone())?;
+        (c, read_failed, write_failed) = copy_selected_blocks(ctx, sb, copier, &failed)?;
         cleaned |= c;
     }
 
@@ -677,8 +853,7 @@ fn copy_dirty_blocks(
 
         let failed = &read_failed | &write_failed;
         let c;
-        (c, read_failed, write_failed) =
-            copy_selected_blocks(ctx.engine.clone(), sb, copier, &failed, ctx.report.clone())?;
+        (c, read_failed, write_failed) = copy_selected_blocks(ctx, sb, copier, &failed)?;
         cleaned |= c;
 
         retries += 1;
@@ -695,14 +870,19 @@ fn copy_dirty_blocks(
 }
 
 fn copy_all_dirty_blocks(
-    engine: Arc<dyn IoEngine + Send + Sync>,
+    ctx: &Context,
     sb: &Superblock,
     copier: Box<dyn Copier + Send>,
-    report: Arc<Report>,
 ) -> anyhow::Result<(u32, RoaringBitmap, RoaringBitmap, RoaringBitmap)> {
+    let engine = ctx.engine.clone();
     let selector = mk_selector(engine.clone(), sb)?;
     let nr_blocks = selector.get_nr_to_writeback();
-    let progress = Arc::new(ProgressReporter::new(report, nr_blocks as u64));
+    let progress = Arc::new(ProgressReporter::new(
+        ctx.report.clone(),
+        ctx.events.clone(),
+        "copy",
+        nr_blocks as u64,
+    ));
 
     // We pass work to the copy thread via a sync channel with a limit
     // of a single entry, this allows us to prepare one vector of copy ops
@@ -727,14 +907,19 @@ fn copy_all_dirty_blocks(
 }
 
 fn copy_selected_blocks(
-    engine: Arc<dyn IoEngine + Send + Sync>,
+    ctx: &Context,
     sb: &Superblock,
     copier: Box<dyn Copier + Send>,
     blocks: &RoaringBitmap,
-    report: Arc<Report>,
 ) -> anyhow::Result<(RoaringBitmap, RoaringBitmap, RoaringBitmap)> {
+    let engine = ctx.engine.clone();
     let (tx, rx) = mpsc::sync_channel::<Vec<CopyOp>>(1);
-    let progress = Arc::new(ProgressReporter::new(report, blocks.len()));
+    let progress = Arc::new(ProgressReporter::new(
+        ctx.report.clone(),
+        ctx.events.clone(),
+        "retry",
+        blocks.len(),
+    ));
 
     let copier = ThreadedCopier::new(copier);
     let copy_thread = copier.run(rx, progress);
@@ -780,8 +965,7 @@ fn report_stats(report: Arc<Report>, stats: &WritebackStats) {
     }
 }
 
-pub fn writeback(opts: CacheWritebackOptions) -> anyhow::Result<()> {
-    let ctx = mk_context(&opts)?;
+fn writeback_(ctx: &Context, opts: &CacheWritebackOptions, start: Instant) -> anyhow::Result<()> {
     let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
 
     if sb.version > 2 {
@@ -795,8 +979,9 @@ pub fn writeback(opts: CacheWritebackOptions) -> anyhow::Result<()> {
         return Err(anyhow!("offsets must be page aligned"));
     }
 
-    match copy_dirty_blocks(&ctx, &sb, &opts) {
+    match copy_dirty_blocks(ctx, &sb, opts) {
         Err(e) => {
+            ctx.events.error(&e);
             ctx.report
                 .fatal("Metadata corruption was found, some data may not have been copied.");
             if opts.update_metadata {
@@ -806,9 +991,24 @@ pub fn writeback(opts: CacheWritebackOptions) -> anyhow::Result<()> {
         }
         Ok((stats, cleaned)) => {
             report_stats(ctx.report.clone(), &stats);
-            if opts.update_metadata {
-                update_metadata(&ctx, &sb, &cleaned)?;
+            let updated = if opts.update_metadata {
+                update_metadata(ctx, &sb, &cleaned).map(|_| true)
+            } else {
+                Ok(false)
+            };
+
+            if ctx.events.is_enabled() {
+                let nr_cached = count_mappings(&ctx.engine, &sb, |_| true).map(|n| n as u64);
+                let metadata_updated = *updated.as_ref().unwrap_or(&false);
+                ctx.events.summary(&WritebackSummary {
+                    nr_skipped: nr_cached.map_or(0, |n| n.saturating_sub(stats.nr_blocks)),
+                    stats: stats.clone(),
+                    metadata_updated,
+                    succeeded: updated.is_ok() && stats.nr_copied == stats.nr_blocks,
+                    elapsed: start.elapsed(),
+                });
             }
+            updated?;
 
             if stats.nr_copied != stats.nr_blocks {
                 return Err(anyhow!("Incompleted writeback"));
@@ -819,4 +1019,36 @@ pub fn writeback(opts: CacheWritebackOptions) -> anyhow::Result<()> {
     Ok(())
 }
 
+pub fn writeback(opts: CacheWritebackOptions) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let ctx = mk_context(&opts)?;
+    let r = writeback_(&ctx, &opts, start);
+    if let Err(e) = &r {
+        ctx.events.failed(e, start.elapsed());
+    }
+    r
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod writeback_tests {
+    use super::*;
+    use crate::io_engine::errors::block_io_error;
+
+    #[test]
+    fn error_events_carry_the_io_error_class() {
+        let e = block_io_error(42, std::io::Error::from_raw_os_error(libc::EIO));
+        let e = anyhow::Error::from(e).context("couldn't read the mappings");
+        let event = error_event(&e);
+        assert!(event.contains("\"io_error\": \"media_error\", \"block\": 42"));
+
+        let event = error_event(&anyhow!("metadata errors present"));
+        assert_eq!(
+            event,
+            "{\"event\": \"error\", \"message\": \"metadata errors present\"}"
+        );
+    }
+}
+
 //------------------------------------------
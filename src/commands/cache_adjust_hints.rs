@@ -0,0 +1,123 @@
+extern crate clap;
+
+use clap::{value_parser, Arg, ArgAction, ArgGroup};
+use std::path::Path;
+
+use crate::cache::adjust_hints::{adjust_hints, CacheAdjustHintsOptions, MAX_LEVEL};
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::commands::Command;
+use crate::version::*;
+
+//------------------------------------------
+
+pub struct CacheAdjustHintsCommand;
+
+impl CacheAdjustHintsCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Rewrite the policy hints of an inactive cache")
+            // flags
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress output messages, return only exit code.")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("RESET")
+                    .help("Reset the hints of all the cache blocks to the lowest level")
+                    .long("reset")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("IMPORT")
+                    .help("Import the levels of cache blocks from a file")
+                    .long("import")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("LEVEL")
+                    .help("Specify the level given to promoted blocks")
+                    .long("level")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32).range(0..=MAX_LEVEL as i64))
+                    .default_value("63")
+                    .requires("PROMOTE"),
+            )
+            .arg(
+                Arg::new("PROMOTE")
+                    .help("Promote the cache blocks holding a range of origin blocks")
+                    .long("promote")
+                    .value_name("BLOCK_RANGE")
+                    .value_parser(value_parser!(RangeU64)),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input device")
+                    .required(true)
+                    .index(1),
+            )
+            .group(
+                ArgGroup::new("ADJUSTMENTS")
+                    .args(["RESET", "IMPORT", "PROMOTE"])
+                    .required(true)
+                    .multiple(true),
+            );
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for CacheAdjustHintsCommand {
+    fn name(&self) -> &'a str {
+        "cache_adjust_hints"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let import = matches.get_one::<String>("IMPORT").map(Path::new);
+
+        let report = mk_report(matches.get_flag("QUIET"));
+
+        if let Err(e) = check_input_file(input_file)
+            .and_then(check_file_not_tiny)
+            .and_then(check_not_xml)
+        {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        if let Some(Err(e)) = import.map(check_input_file) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Cache, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        let opts = CacheAdjustHintsOptions {
+            dev: input_file,
+            engine_opts: engine_opts.unwrap(),
+            reset: matches.get_flag("RESET"),
+            import,
+            promote: matches
+                .get_one::<RangeU64>("PROMOTE")
+                .map(|r| r.start..r.end),
+            level: *matches.get_one::<u32>("LEVEL").unwrap(),
+            report: report.clone(),
+        };
+
+        to_exit_code(&report, adjust_hints(opts))
+    }
+}
+
+//------------------------------------------
@@ -78,7 +78,9 @@ impl CacheCheckCommand {
                     .required(true)
                     .index(1),
             );
-        verbose_args(engine_args(version_args(cmd)))
+        log_target_args(progress_fd_args(verbose_args(engine_args(version_args(
+            cmd,
+        )))))
     }
 }
 
@@ -93,13 +95,23 @@ impl<'a> Command<'a> for CacheCheckCommand {
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
 
-        let report = mk_report(matches.get_flag("QUIET"));
+        let report = mk_target_report(
+            matches.get_flag("QUIET"),
+            parse_log_target(&matches),
+            self.name(),
+        );
         let log_level = match parse_log_level(&matches) {
             Ok(level) => level,
             Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
         };
         report.set_level(log_level);
 
+        match parse_progress_fd(&matches) {
+            Ok(Some(file)) => report.set_progress_fd(file),
+            Ok(None) => {}
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        }
+
         if let Err(e) = check_input_file(input_file)
             .and_then(check_file_not_tiny)
             .and_then(check_not_xml)
@@ -1,12 +1,13 @@
 extern crate clap;
 
-use clap::{Arg, ArgAction};
+use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
 use crate::cache::dump::{dump, CacheDumpOptions};
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
+use crate::report::{parse_log_level, verbose_args};
 use crate::version::*;
 
 //------------------------------------------
@@ -20,6 +21,13 @@ impl CacheDumpCommand {
             .version(crate::tools_version!())
             .disable_version_flag(true)
             .about("Dump the cache metadata to stdout in XML format")
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress output messages, return only exit code.")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
             .arg(
                 Arg::new("REPAIR")
                     .help("Repair the metadata whilst dumping it")
@@ -28,6 +36,13 @@ impl CacheDumpCommand {
                     .action(ArgAction::SetTrue),
             )
             // options
+            .arg(
+                Arg::new("ORIGIN_RANGE")
+                    .help("Only dump the mappings into a range of origin blocks")
+                    .long("origin-range")
+                    .value_name("BLOCK_RANGE")
+                    .value_parser(value_parser!(RangeU64)),
+            )
             .arg(
                 Arg::new("OUTPUT")
                     .help("Specify the output file rather than stdout")
@@ -42,7 +57,7 @@ impl CacheDumpCommand {
                     .required(true)
                     .index(1),
             );
-        engine_args(version_args(cmd))
+        verbose_args(engine_args(version_args(cmd)))
     }
 }
 
@@ -58,9 +73,12 @@ impl<'a> Command<'a> for CacheDumpCommand {
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
         let output_file = matches.get_one::<String>("OUTPUT").map(Path::new);
 
-        // Create a temporary report just in case these checks
-        // need to report anything.
-        let report = std::sync::Arc::new(crate::report::mk_simple_report());
+        let report = mk_report(matches.get_flag("QUIET"));
+        let log_level = match parse_log_level(&matches) {
+            Ok(level) => level,
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        };
+        report.set_level(log_level);
 
         if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
             return to_exit_code::<()>(&report, Err(e));
@@ -77,6 +95,10 @@ impl<'a> Command<'a> for CacheDumpCommand {
             output: output_file,
             engine_opts,
             repair: matches.get_flag("REPAIR"),
+            origin_range: matches
+                .get_one::<RangeU64>("ORIGIN_RANGE")
+                .map(|r| r.start..r.end),
+            report: report.clone(),
         };
 
         to_exit_code(&report, dump(opts))
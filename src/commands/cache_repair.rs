@@ -46,7 +46,7 @@ impl CacheRepairCommand {
             // a dummy argument for compatibility with lvconvert
             .arg(Arg::new("DUMMY").required(false).hide(true).index(1));
 
-        verbose_args(engine_args(version_args(cmd)))
+        progress_fd_args(verbose_args(engine_args(version_args(cmd))))
     }
 }
 
@@ -66,6 +66,12 @@ impl<'a> Command<'a> for CacheRepairCommand {
         };
         report.set_level(log_level);
 
+        match parse_progress_fd(&matches) {
+            Ok(Some(file)) => report.set_progress_fd(file),
+            Ok(None) => {}
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        }
+
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
         let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
 
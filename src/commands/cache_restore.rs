@@ -27,6 +27,14 @@ impl CacheRestoreCommand {
                     .long("quiet")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("DETERMINISTIC")
+                    .help(
+                        "Lay the metadata out the same way on any machine, for reproducible output",
+                    )
+                    .long("deterministic")
+                    .action(ArgAction::SetTrue),
+            )
             .arg(
                 Arg::new("OMIT_CLEAN_SHUTDOWN")
                     .help("Don't set the clean shutdown flag")
@@ -36,7 +44,7 @@ impl CacheRestoreCommand {
             // options
             .arg(
                 Arg::new("INPUT")
-                    .help("Specify the input xml")
+                    .help("Specify the input xml, or '-' for stdin")
                     .short('i')
                     .long("input")
                     .value_name("FILE")
@@ -83,7 +91,13 @@ impl<'a> Command<'a> for CacheRestoreCommand {
         };
         report.set_level(log_level);
 
-        if let Err(e) = check_input_file(input_file).and_then(|_| check_output_file(output_file)) {
+        // The input may be piped in
+        let checked = if input_file == Path::new("-") {
+            Ok(input_file)
+        } else {
+            check_input_file(input_file)
+        };
+        if let Err(e) = checked.and_then(|_| check_output_file(output_file)) {
             return to_exit_code::<()>(&report, Err(e));
         }
 
@@ -99,6 +113,7 @@ impl<'a> Command<'a> for CacheRestoreCommand {
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
             omit_clean_shutdown: matches.get_flag("OMIT_CLEAN_SHUTDOWN"),
+            deterministic: matches.get_flag("DETERMINISTIC"),
         };
 
         to_exit_code(&report, restore(opts))
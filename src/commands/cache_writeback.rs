@@ -82,6 +82,12 @@ impl CacheWritebackCommand {
                     .value_name("MB")
                     .value_parser(value_parser!(usize)),
             )
+            .arg(
+                Arg::new("JSON_PROGRESS")
+                    .help("Write progress events and a summary to a file as json lines")
+                    .long("json-progress")
+                    .value_name("FILE"),
+            )
             .arg(
                 Arg::new("RETRY_COUNT")
                     .help("Specify how many times to retry data copying on failed data block")
@@ -121,10 +127,11 @@ impl<'a> Command<'a> for CacheWritebackCommand {
             return to_exit_code::<()>(&report, Err(e));
         }
 
-        let engine_opts = match parse_engine_opts(ToolType::Cache, &matches) {
-            Ok(opt) => opt,
-            Err(_) => return exitcode::USAGE,
-        };
+        let engine_opts = parse_engine_opts(ToolType::Cache, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+        let engine_opts = engine_opts.unwrap();
 
         let check_opts = CacheCheckOptions {
             dev: metadata_dev,
@@ -160,6 +167,7 @@ impl<'a> Command<'a> for CacheWritebackCommand {
             list_failed_blocks: matches.get_flag("LIST_FAILED_BLOCKS"),
             update_metadata: !matches.get_flag("NO_METADATA_UPDATE"),
             retry_count: *matches.get_one::<u32>("RETRY_COUNT").unwrap(),
+            json_progress: matches.get_one::<String>("JSON_PROGRESS").map(Path::new),
             report: report.clone(),
         };
 
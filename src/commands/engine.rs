@@ -1,14 +1,18 @@
 use anyhow::{anyhow, Result};
 use clap::ArgMatches;
 use roaring::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::affinity::CpuSet;
+use crate::io_engine::sector_size::*;
 use crate::io_engine::*;
 use crate::pdata::space_map::allocated_blocks::*;
 use crate::pdata::space_map::common::*;
 use crate::pdata::unpack::*;
 use crate::thin::superblock::*;
+use crate::write_batcher::SyncPolicy;
 
 //------------------------------------------
 
@@ -17,6 +21,9 @@ pub enum EngineType {
     #[cfg(feature = "io_uring")]
     Async,
     Sync,
+    // The sync engine reading through the page cache, which suits repeated
+    // runs over the same metadata
+    Cached,
     Spindle,
 }
 
@@ -33,6 +40,101 @@ pub struct EngineOptions {
     pub tool: ToolType,
     pub engine_type: EngineType,
     pub use_metadata_snap: bool,
+    pub io_threads: usize,
+    pub cpu_affinity: Option<CpuSet>,
+    /// The depth of the io_uring queues, for the async engine
+    pub queue_depth: Option<usize>,
+    /// Poll the submission queue from a kernel thread, for the async engine
+    pub sq_poll: Option<bool>,
+    /// Read through the page cache
+    pub buffered: bool,
+    /// How often the tools writing metadata force it to stable storage
+    pub sync_policy: SyncPolicy,
+    /// Redirect the writes to this file, leaving the metadata untouched
+    pub overlay: Option<PathBuf>,
+    /// The logical sector size of the metadata device, in place of the one
+    /// it reports.  Sectors that direct io can't be aligned to are read
+    /// through the page cache.
+    pub sector_size: Option<u32>,
+}
+
+//------------------------------------------
+
+// io_uring won't set up a ring with more entries than this
+const MAX_QUEUE_DEPTH: usize = 32768;
+
+/// The tunables given with --engine-opts, as comma separated key=value
+/// pairs, eg. "queue_depth=64,sqpoll=true".  Each is checked against the
+/// engine chosen once all the options have been parsed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineTunables {
+    pub queue_depth: Option<usize>,
+    pub sqpoll: Option<bool>,
+    pub threads: Option<usize>,
+    pub buffered: Option<bool>,
+    pub sync_every: Option<SyncPolicy>,
+    pub sector_size: Option<u32>,
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool> {
+    match value {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(anyhow!(
+            "invalid value '{}' for {}, expected true or false",
+            value,
+            key
+        )),
+    }
+}
+
+fn parse_sector_size(key: &str, value: &str) -> Result<u32> {
+    match value.parse::<u32>() {
+        Ok(n) if (MIN_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&n) => Ok(n),
+        _ => Err(anyhow!(
+            "invalid value '{}' for {}, expected {} to {}",
+            value,
+            key,
+            MIN_SECTOR_SIZE,
+            MAX_SECTOR_SIZE
+        )),
+    }
+}
+
+fn parse_count(key: &str, value: &str, max: usize) -> Result<usize> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 && n <= max => Ok(n),
+        _ => Err(anyhow!(
+            "invalid value '{}' for {}, expected 1 to {}",
+            value,
+            key,
+            max
+        )),
+    }
+}
+
+impl FromStr for EngineTunables {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut t = EngineTunables::default();
+        for pair in s.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected key=value, found '{}'", pair))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "queue_depth" => t.queue_depth = Some(parse_count(key, value, MAX_QUEUE_DEPTH)?),
+                "sqpoll" => t.sqpoll = Some(parse_bool(key, value)?),
+                "threads" => t.threads = Some(parse_count(key, value, 1024)?),
+                "buffered" => t.buffered = Some(parse_bool(key, value)?),
+                "sync_every" => t.sync_every = Some(value.parse::<SyncPolicy>()?),
+                "sector_size" => t.sector_size = Some(parse_sector_size(key, value)?),
+                _ => return Err(anyhow!("unknown engine option '{}'", key)),
+            }
+        }
+        Ok(t)
+    }
 }
 
 //------------------------------------------
@@ -48,6 +150,21 @@ pub fn engine_args(cmd: clap::Command) -> clap::Command {
             .value_name("IO_ENGINE")
             .hide(true),
     )
+    .arg(
+        Arg::new("ENGINE_OPTS")
+            .help("Tune the io engine with comma separated key=value pairs")
+            .long("engine-opts")
+            .value_name("OPTS")
+            .value_parser(clap::value_parser!(EngineTunables))
+            .hide(true),
+    )
+    .arg(
+        Arg::new("OVERLAY")
+            .help("Keep the writes to the metadata in an overlay file, and read through it")
+            .long("overlay")
+            .value_name("FILE")
+            .value_parser(clap::value_parser!(PathBuf)),
+    )
 }
 
 //------------------------------------------
@@ -56,6 +173,7 @@ fn parse_type(matches: &ArgMatches) -> Result<EngineType> {
     let engine_type = if let Some(engine) = matches.get_one::<String>("IO_ENGINE") {
         match engine.as_str() {
             "sync" => EngineType::Sync,
+            "cached" => EngineType::Cached,
             "spindle" => EngineType::Spindle,
             #[cfg(feature = "io_uring")]
             "async" => EngineType::Async,
@@ -89,15 +207,79 @@ fn metadata_snap_flag(matches: &ArgMatches) -> bool {
     )
 }
 
+// The tuning options are only offered by some tools
+fn io_threads(matches: &ArgMatches) -> usize {
+    match matches.try_get_one::<usize>("IO_THREADS") {
+        Ok(Some(n)) => *n,
+        _ => 1,
+    }
+}
+
+fn cpu_affinity(matches: &ArgMatches) -> Option<CpuSet> {
+    match matches.try_get_one::<CpuSet>("CPU_AFFINITY") {
+        Ok(cpus) => cpus.cloned(),
+        Err(_) => None,
+    }
+}
+
+fn engine_tunables(matches: &ArgMatches) -> EngineTunables {
+    match matches.try_get_one::<EngineTunables>("ENGINE_OPTS") {
+        Ok(Some(t)) => t.clone(),
+        _ => EngineTunables::default(),
+    }
+}
+
+// Rejects the tunables that the chosen engine can't honour
+fn check_tunables(engine_type: &EngineType, t: &EngineTunables) -> Result<()> {
+    let is_async = {
+        #[cfg(feature = "io_uring")]
+        {
+            *engine_type == EngineType::Async
+        }
+        #[cfg(not(feature = "io_uring"))]
+        {
+            false
+        }
+    };
+
+    if !is_async && (t.queue_depth.is_some() || t.sqpoll.is_some()) {
+        return Err(anyhow!(
+            "queue_depth and sqpoll only apply to the async io engine"
+        ));
+    }
+    if t.threads.is_some() && !matches!(engine_type, EngineType::Sync | EngineType::Cached) {
+        return Err(anyhow!(
+            "threads only applies to the sync and cached io engines"
+        ));
+    }
+    if t.buffered.is_some() && *engine_type == EngineType::Spindle {
+        return Err(anyhow!("buffered doesn't apply to the spindle io engine"));
+    }
+    Ok(())
+}
+
 pub fn parse_engine_opts(tool: ToolType, matches: &ArgMatches) -> Result<EngineOptions> {
     let engine_type = parse_type(matches)?;
     let use_metadata_snap =
         (tool == ToolType::Thin || tool == ToolType::Era) && metadata_snap_flag(matches);
 
+    let tunables = engine_tunables(matches);
+    check_tunables(&engine_type, &tunables)?;
+
     Ok(EngineOptions {
         tool,
-        engine_type,
         use_metadata_snap,
+        io_threads: tunables.threads.unwrap_or_else(|| io_threads(matches)),
+        cpu_affinity: cpu_affinity(matches),
+        queue_depth: tunables.queue_depth,
+        sq_poll: tunables.sqpoll,
+        buffered: tunables
+            .buffered
+            .unwrap_or(engine_type == EngineType::Cached),
+        sync_policy: tunables.sync_every.unwrap_or_default(),
+        overlay: matches.get_one::<PathBuf>("OVERLAY").cloned(),
+        sector_size: tunables.sector_size,
+        engine_type,
     })
 }
 
@@ -158,6 +340,8 @@ pub struct EngineBuilder<'a, P: AsRef<Path>> {
     opts: &'a EngineOptions,
     write: bool,
     exclusive: bool,
+    read_only: bool,
+    throttle: Option<u64>, // bytes per second
 }
 
 impl<'a, P: AsRef<Path>> EngineBuilder<'a, P> {
@@ -167,40 +351,123 @@ impl<'a, P: AsRef<Path>> EngineBuilder<'a, P> {
             opts,
             write: false,
             exclusive: true,
+            read_only: false,
+            throttle: None,
         }
     }
 
     pub fn write(self, flag: bool) -> Self {
         Self {
-            path: self.path,
-            opts: self.opts,
             write: flag,
-            exclusive: self.exclusive,
+            ..self
         }
     }
 
     pub fn exclusive(self, flag: bool) -> Self {
         Self {
-            path: self.path,
-            opts: self.opts,
-            write: self.write,
             exclusive: flag,
+            ..self
+        }
+    }
+
+    // Non-mutating tools assert this, so the engine rejects any write that
+    // slips through rather than relying on the open mode alone.
+    pub fn read_only(self, flag: bool) -> Self {
+        Self {
+            read_only: flag,
+            ..self
+        }
+    }
+
+    // Caps the rate the metadata is read at, in bytes per second, so a
+    // tool run against a live pool doesn't degrade its latency.
+    pub fn throttle(self, rate: Option<u64>) -> Self {
+        Self {
+            throttle: rate,
+            ..self
         }
     }
 
     pub fn build(self) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+        if self.read_only && self.write {
+            return Err(anyhow!("a read-only engine can't be opened for writing"));
+        }
+
+        // An overlay takes the writes to the metadata it was created for,
+        // and is read through by the tools examining that metadata.  The
+        // other devices a tool opens, such as the input of thin_repair,
+        // are left alone.
+        let overlay = match &self.opts.overlay {
+            Some(overlay)
+                if self.write || OverlayIoEngine::is_overlay_of(overlay, self.path.as_ref()) =>
+            {
+                Some((overlay.clone(), self.path.as_ref().to_path_buf()))
+            }
+            _ => None,
+        };
+        let write = self.write && overlay.is_none();
+
+        // Buffered writes would only reach the disk once flushed
+        if self.opts.buffered && write {
+            return Err(anyhow!(match self.opts.engine_type {
+                EngineType::Cached => "the cached io engine can only be used for reading",
+                _ => "buffered io can only be used for reading",
+            }));
+        }
+
+        // Sectors that direct io can't be aligned to would fail every io
+        // with EINVAL, so they're read through the page cache instead
+        let buffered = self.opts.buffered || {
+            let logical = match self.opts.sector_size {
+                Some(logical) => logical,
+                None => get_logical_sector_size(self.path.as_ref())?,
+            };
+            if fits_direct_io(logical) {
+                false
+            } else if write {
+                return Err(anyhow!(
+                    "the device has {} byte sectors, which {} byte metadata blocks can't be \
+                     written to with direct io",
+                    logical,
+                    BLOCK_SIZE
+                ));
+            } else if self.opts.engine_type == EngineType::Spindle {
+                return Err(anyhow!(
+                    "the spindle io engine can't read a device with {} byte sectors",
+                    logical
+                ));
+            } else {
+                true
+            }
+        };
+
         let engine: Arc<dyn IoEngine + Send + Sync> = match self.opts.engine_type {
             #[cfg(feature = "io_uring")]
-            EngineType::Async => Arc::new(AsyncIoEngine::new_with(
-                self.path,
-                self.write,
-                self.exclusive,
-            )?),
-            EngineType::Sync => Arc::new(SyncIoEngine::new_with(
-                self.path,
-                self.write,
-                self.exclusive,
-            )?),
+            EngineType::Async => {
+                let sq_cpu = self.opts.cpu_affinity.as_ref().map(|cpus| cpus.nth(0));
+                let defaults = RingConfig::default();
+                let cfg = RingConfig {
+                    depth: self.opts.queue_depth.unwrap_or(defaults.depth),
+                    sq_poll: self.opts.sq_poll.unwrap_or(sq_cpu.is_some()),
+                    sq_cpu,
+                    buffered,
+                };
+                Arc::new(AsyncIoEngine::new_configured(
+                    self.path,
+                    write,
+                    self.exclusive,
+                    cfg,
+                )?)
+            }
+            EngineType::Sync | EngineType::Cached if buffered => Arc::new(
+                SyncIoEngine::new_with(self.path, false, self.exclusive)?
+                    .with_nowait_probe()?
+                    .with_io_threads(self.opts.io_threads, self.opts.cpu_affinity.clone())?,
+            ),
+            EngineType::Sync | EngineType::Cached => Arc::new(
+                SyncIoEngine::new_with(self.path, write, self.exclusive)?
+                    .with_io_threads(self.opts.io_threads, self.opts.cpu_affinity.clone())?,
+            ),
             EngineType::Spindle => {
                 let valid_blocks = match self.opts.tool {
                     ToolType::Thin => thin_valid_blocks(self.path.as_ref(), self.opts),
@@ -212,10 +479,120 @@ impl<'a, P: AsRef<Path>> EngineBuilder<'a, P> {
                     }
                 };
 
-                Arc::new(SpindleIoEngine::new(self.path, valid_blocks, self.write)?)
+                Arc::new(SpindleIoEngine::new(self.path, valid_blocks, write)?)
             }
         };
-        Ok(engine)
+
+        let engine: Arc<dyn IoEngine + Send + Sync> = match overlay {
+            Some((overlay, origin)) => Arc::new(OverlayIoEngine::new(engine, overlay, origin)?),
+            None => engine,
+        };
+
+        let engine: Arc<dyn IoEngine + Send + Sync> = match self.throttle {
+            Some(rate) => Arc::new(ThrottledIoEngine::new(engine, rate)),
+            None => engine,
+        };
+
+        if self.read_only {
+            Ok(Arc::new(ReadOnlyIoEngine::new(engine)))
+        } else {
+            Ok(engine)
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tunables_tests {
+    use super::*;
+
+    #[test]
+    fn parses_pairs() {
+        let t = "queue_depth=64, sqpoll=on,threads=4,buffered=false"
+            .parse::<EngineTunables>()
+            .unwrap();
+        assert_eq!(t.queue_depth, Some(64));
+        assert_eq!(t.sqpoll, Some(true));
+        assert_eq!(t.threads, Some(4));
+        assert_eq!(t.buffered, Some(false));
+        assert_eq!(t.sync_every, None);
+        assert_eq!(t.sector_size, None);
+
+        let t = "sync_every=30s".parse::<EngineTunables>().unwrap();
+        assert_eq!(
+            t.sync_every,
+            Some(SyncPolicy::Interval(std::time::Duration::from_secs(30)))
+        );
+
+        let t = "sector_size=4096".parse::<EngineTunables>().unwrap();
+        assert_eq!(t.sector_size, Some(4096));
+
+        let t = "sector_size=520".parse::<EngineTunables>().unwrap();
+        assert_eq!(t.sector_size, Some(520));
+    }
+
+    #[test]
+    fn rejects_bad_pairs() {
+        for s in [
+            "threads",
+            "threads=0",
+            "queue_depth=65536",
+            "sqpoll=2",
+            "depth=1",
+            "sync_every=0",
+            "sector_size=256",
+            "sector_size=131072",
+            "register_buffers=true",
+        ] {
+            assert!(s.parse::<EngineTunables>().is_err(), "'{}' was accepted", s);
+        }
+    }
+
+    #[test]
+    fn checks_against_the_engine() {
+        let t = "threads=2".parse::<EngineTunables>().unwrap();
+        assert!(check_tunables(&EngineType::Sync, &t).is_ok());
+        assert!(check_tunables(&EngineType::Spindle, &t).is_err());
+
+        let t = "queue_depth=8".parse::<EngineTunables>().unwrap();
+        assert!(check_tunables(&EngineType::Sync, &t).is_err());
+    }
+
+    fn mk_opts(engine_type: EngineType, sector_size: u32) -> EngineOptions {
+        EngineOptions {
+            tool: ToolType::Thin,
+            engine_type,
+            use_metadata_snap: false,
+            io_threads: 1,
+            cpu_affinity: None,
+            queue_depth: None,
+            sq_poll: None,
+            buffered: false,
+            sync_policy: SyncPolicy::default(),
+            overlay: None,
+            sector_size: Some(sector_size),
+        }
+    }
+
+    #[test]
+    fn odd_sectors_are_read_through_the_page_cache() -> Result<()> {
+        let tmp = crate::file_utils::TempFile::new(&std::env::temp_dir())?;
+        crate::file_utils::create_sized_file(tmp.path(), 16 * BLOCK_SIZE as u64)?;
+
+        let opts = mk_opts(EngineType::Sync, 520);
+        let engine = EngineBuilder::new(tmp.path(), &opts).build()?;
+        assert_eq!(engine.get_nr_blocks(), 16);
+        assert!(engine.read(3).is_ok());
+
+        assert!(EngineBuilder::new(tmp.path(), &opts)
+            .write(true)
+            .build()
+            .is_err());
+        assert!(EngineBuilder::new(tmp.path(), &mk_opts(EngineType::Spindle, 520))
+            .build()
+            .is_err());
+        Ok(())
     }
 }
 
@@ -48,7 +48,7 @@ impl EraCheckCommand {
                     .required(true)
                     .index(1),
             );
-        verbose_args(engine_args(version_args(cmd)))
+        progress_fd_args(verbose_args(engine_args(version_args(cmd))))
     }
 }
 
@@ -70,6 +70,12 @@ impl<'a> Command<'a> for EraCheckCommand {
         };
         report.set_level(log_level);
 
+        match parse_progress_fd(&matches) {
+            Ok(Some(file)) => report.set_progress_fd(file),
+            Ok(None) => {}
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        }
+
         if let Err(e) = check_input_file(input_file)
             .and_then(check_file_not_tiny)
             .and_then(check_not_xml)
@@ -33,6 +33,13 @@ impl EraDumpCommand {
                     .long("repair")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("STATS")
+                    .help("Print the number of blocks marked in each writeset rather than the metadata")
+                    .long("stats")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("LOGICAL"),
+            )
             // options
             .arg(
                 Arg::new("OUTPUT")
@@ -81,6 +88,7 @@ impl<'a> Command<'a> for EraDumpCommand {
             engine_opts: engine_opts.unwrap(),
             logical: matches.get_flag("LOGICAL"),
             repair: matches.get_flag("REPAIR"),
+            stats: matches.get_flag("STATS"),
         };
 
         to_exit_code(&report, dump(opts))
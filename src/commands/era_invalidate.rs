@@ -1,10 +1,11 @@
-use clap::{value_parser, Arg};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
-use crate::era::invalidate::{invalidate, EraInvalidateOptions};
+use crate::era::invalidate::{invalidate, CombineOp, EraInvalidateOptions, InvalidateFormat};
 use crate::version::*;
 
 //------------------------------------------
@@ -24,6 +25,40 @@ impl EraInvalidateCommand {
                     .long("metadata-snapshot"),
             )
             // options
+            .arg(
+                Arg::new("COMBINE")
+                    .help("Combine the blocks of several inputs by union or intersection")
+                    .long("combine")
+                    .value_name("OP")
+                    .value_parser(
+                        PossibleValuesParser::new(["union", "intersection"])
+                            .map(|s| s.parse::<CombineOp>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("union")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("CLONE_REGION_SIZE")
+                    .help("Specify the region size of the dm-clone target in sectors, if not the era block size")
+                    .long("clone-region-size")
+                    .value_name("SECTORS")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the output format, xml or a dm-clone region list")
+                    .short('f')
+                    .long("format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["xml", "dm-clone"])
+                            .map(|s| s.parse::<InvalidateFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("xml")
+                    .hide_default_value(true),
+            )
             .arg(
                 Arg::new("OUTPUT")
                     .help("Specify the output file rather than stdout")
@@ -42,9 +77,10 @@ impl EraInvalidateCommand {
             // arguments
             .arg(
                 Arg::new("INPUT")
-                    .help("Specify the input device")
+                    .help("Specify the input devices")
                     .required(true)
-                    .index(1),
+                    .index(1)
+                    .action(ArgAction::Append),
             );
         engine_args(version_args(cmd))
     }
@@ -59,15 +95,21 @@ impl<'a> Command<'a> for EraInvalidateCommand {
         let matches = self.cli().get_matches_from(args);
         display_version(&matches);
 
-        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let input_files: Vec<&Path> = matches
+            .get_many::<String>("INPUT")
+            .unwrap()
+            .map(Path::new)
+            .collect();
         let output_file = matches.get_one::<String>("OUTPUT").map(Path::new);
 
         // Create a temporary report just in case these checks
         // need to report anything.
         let report = std::sync::Arc::new(crate::report::mk_simple_report());
 
-        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
-            return to_exit_code::<()>(&report, Err(e));
+        for input_file in &input_files {
+            if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
+                return to_exit_code::<()>(&report, Err(e));
+            }
         }
 
         let engine_opts = parse_engine_opts(ToolType::Era, &matches);
@@ -76,10 +118,13 @@ impl<'a> Command<'a> for EraInvalidateCommand {
         }
 
         let opts = EraInvalidateOptions {
-            input: input_file,
+            inputs: input_files,
             output: output_file,
             engine_opts: engine_opts.unwrap(),
             threshold: matches.get_one::<u32>("WRITTEN_SINCE").map_or(0, |v| *v),
+            combine: *matches.get_one::<CombineOp>("COMBINE").unwrap(),
+            format: *matches.get_one::<InvalidateFormat>("FORMAT").unwrap(),
+            clone_region_size: matches.get_one::<u32>("CLONE_REGION_SIZE").copied(),
         };
 
         to_exit_code(&report, invalidate(&opts))
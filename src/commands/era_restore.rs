@@ -1,12 +1,12 @@
 extern crate clap;
 
-use clap::{Arg, ArgAction};
+use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
-use crate::era::restore::{restore, EraRestoreOptions};
+use crate::era::restore::{restore, EraRestoreOptions, SuperblockOverrides};
 use crate::report::{parse_log_level, verbose_args};
 use crate::version::*;
 
@@ -28,6 +28,13 @@ impl EraRestoreCommand {
                     .action(ArgAction::SetTrue),
             )
             // options
+            .arg(
+                Arg::new("DATA_BLOCK_SIZE")
+                    .help("Override the data block size if needed")
+                    .long("data-block-size")
+                    .value_name("SECTORS")
+                    .value_parser(value_parser!(u32)),
+            )
             .arg(
                 Arg::new("INPUT")
                     .help("Specify the input xml")
@@ -36,6 +43,13 @@ impl EraRestoreCommand {
                     .value_name("FILE")
                     .required(true),
             )
+            .arg(
+                Arg::new("NR_BLOCKS")
+                    .help("Override the number of blocks if needed")
+                    .long("nr-blocks")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32)),
+            )
             .arg(
                 Arg::new("OUTPUT")
                     .help("Specify the output device")
@@ -43,6 +57,12 @@ impl EraRestoreCommand {
                     .long("output")
                     .value_name("FILE")
                     .required(true),
+            )
+            .arg(
+                Arg::new("UUID")
+                    .help("Override the uuid if needed")
+                    .long("uuid")
+                    .value_name("UUID"),
             );
         verbose_args(engine_args(version_args(cmd)))
     }
@@ -80,6 +100,11 @@ impl<'a> Command<'a> for EraRestoreCommand {
             input: input_file,
             output: output_file,
             engine_opts: engine_opts.unwrap(),
+            overrides: SuperblockOverrides {
+                uuid: matches.get_one::<String>("UUID").cloned(),
+                block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
+                nr_blocks: matches.get_one::<u32>("NR_BLOCKS").cloned(),
+            },
             report: report.clone(),
         };
 
@@ -0,0 +1,129 @@
+extern crate clap;
+
+use clap::{value_parser, Arg, ArgAction, ArgGroup};
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::commands::Command;
+use crate::era::writeset_file::*;
+use crate::version::*;
+
+//------------------------------------------
+
+pub struct EraWritesetCommand;
+
+impl EraWritesetCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Export or import the writeset of a single era")
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress output messages, return only exit code.")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("ERA")
+                    .help("Import the writeset as the given era")
+                    .long("era")
+                    .value_name("ERA")
+                    .value_parser(value_parser!(u32))
+                    .requires("IMPORT"),
+            )
+            .arg(
+                Arg::new("EXPORT")
+                    .help("Export the writeset of an era to the output file")
+                    .long("export")
+                    .value_name("ERA")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("IMPORT")
+                    .help("Import a writeset file, writing the metadata to the output")
+                    .long("import")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("OUTPUT")
+                    .help("Specify the output file")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input device")
+                    .required(true)
+                    .index(1),
+            )
+            .group(
+                ArgGroup::new("ACTION")
+                    .args(["EXPORT", "IMPORT"])
+                    .required(true),
+            );
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for EraWritesetCommand {
+    fn name(&self) -> &'a str {
+        "era_writeset"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+        let import_file = matches.get_one::<String>("IMPORT").map(Path::new);
+
+        let report = mk_report(matches.get_flag("QUIET"));
+
+        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+        if let Some(import_file) = import_file {
+            if let Err(e) =
+                check_input_file(import_file).and_then(|_| check_output_file(output_file))
+            {
+                return to_exit_code::<()>(&report, Err(e));
+            }
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Era, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        let result = match import_file {
+            Some(writeset) => import_writeset(EraImportWritesetOptions {
+                input: input_file,
+                output: output_file,
+                writeset,
+                era: matches.get_one::<u32>("ERA").copied(),
+                engine_opts: engine_opts.unwrap(),
+                report: report.clone(),
+            }),
+            None => export_writeset(EraExportWritesetOptions {
+                input: input_file,
+                output: output_file,
+                era: *matches.get_one::<u32>("EXPORT").unwrap(),
+                engine_opts: engine_opts.unwrap(),
+                report: report.clone(),
+            }),
+        };
+
+        to_exit_code(&report, result)
+    }
+}
+
+//------------------------------------------
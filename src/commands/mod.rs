@@ -1,3 +1,4 @@
+pub mod cache_adjust_hints;
 pub mod cache_check;
 pub mod cache_dump;
 pub mod cache_metadata_size;
@@ -10,17 +11,23 @@ pub mod era_dump;
 pub mod era_invalidate;
 pub mod era_repair;
 pub mod era_restore;
+pub mod era_writeset;
 pub mod thin_check;
+pub mod thin_commit_overlay;
+pub mod thin_convert_metadata;
 pub mod thin_delta;
 pub mod thin_dump;
 pub mod thin_ls;
 pub mod thin_metadata_pack;
 pub mod thin_metadata_size;
 pub mod thin_metadata_unpack;
+pub mod thin_provision_report;
 pub mod thin_repair;
 pub mod thin_restore;
 pub mod thin_rmap;
 pub mod thin_shrink;
+#[cfg(feature = "thin_top")]
+pub mod thin_top;
 pub mod thin_trim;
 pub mod utils;
 
@@ -37,6 +44,8 @@ pub mod thin_generate_damage;
 #[cfg(feature = "devtools")]
 pub mod thin_generate_metadata;
 #[cfg(feature = "devtools")]
+pub mod thin_metadata_layout;
+#[cfg(feature = "devtools")]
 pub mod thin_stat;
 
 pub trait Command<'a> {
@@ -1,13 +1,65 @@
-use clap::{value_parser, Arg, ArgAction};
+use anyhow::{anyhow, Context};
+use clap::{value_parser, Arg, ArgAction, ArgMatches};
 use std::path::Path;
+use std::time::Duration;
 
+use crate::affinity::CpuSet;
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
-use crate::report::{parse_log_level, verbose_args};
-use crate::thin::check::{check, ThinCheckOptions};
+use crate::file_utils::TempFile;
+use crate::pack::toplevel::{is_pack_file, unpack};
+use crate::report::{
+    log_target_args, mk_simple_report, parse_log_level, parse_log_target, parse_progress_fd,
+    progress_fd_args, verbose_args,
+};
+use crate::thin::check::{check, watch, CheckOutcome, RepairableError, ThinCheckOptions};
+use crate::thin::check_history::show_history;
+use crate::thin::compare::compare;
+use crate::thin::metadata_repair::SuperblockOverrides;
 use crate::version::*;
 
+// Distinct exit codes let activation scripts grade their response to the
+// problems found.  clap exits with 2 on a bad command line, so that code is
+// left to it, and other usage errors are still reported as exitcode::USAGE.
+const EXIT_FATAL: exitcode::ExitCode = 1;
+const EXIT_WARNINGS: exitcode::ExitCode = 3;
+const EXIT_REPAIRABLE: exitcode::ExitCode = 4;
+
+fn exit_code(result: &anyhow::Result<CheckOutcome>) -> exitcode::ExitCode {
+    match result {
+        Ok(CheckOutcome::Clean) => exitcode::OK,
+        Ok(CheckOutcome::Warnings) => EXIT_WARNINGS,
+        Err(e) if e.downcast_ref::<RepairableError>().is_some() => EXIT_REPAIRABLE,
+        Err(_) => EXIT_FATAL,
+    }
+}
+
+// The options that write to the metadata would only change the unpacked
+// copy of a packed file, so they're refused rather than silently lost.
+const WRITE_OPTIONS: [(&str, &str); 4] = [
+    ("AUTO_REPAIR", "--auto-repair"),
+    ("CLEAR_NEEDS_CHECK", "--clear-needs-check-flag"),
+    ("FIX_CHECKSUMS", "--fix-checksums"),
+    ("WATCH", "--watch"),
+];
+
+fn unpack_if_packed(input: &Path, matches: &ArgMatches) -> anyhow::Result<Option<TempFile>> {
+    if !is_pack_file(input)? {
+        return Ok(None);
+    }
+
+    for (id, flag) in WRITE_OPTIONS {
+        if matches.get_flag(id) {
+            return Err(anyhow!("{} can't be used with packed metadata", flag));
+        }
+    }
+
+    let tmp = TempFile::new(&std::env::temp_dir())?;
+    unpack(input, tmp.path()).context("unable to unpack the metadata")?;
+    Ok(Some(tmp))
+}
+
 pub struct ThinCheckCommand;
 
 impl ThinCheckCommand {
@@ -45,6 +97,19 @@ impl ThinCheckCommand {
                         "OVERRIDE_DETAILS_ROOT",
                     ]),
             )
+            .arg(
+                Arg::new("FIX_CHECKSUMS")
+                    .help("Rewrite the checksums of btree nodes that are otherwise intact")
+                    .long("fix-checksums")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all([
+                        "METADATA_SNAPSHOT",
+                        "OVERRIDE_MAPPING_ROOT",
+                        "OVERRIDE_DETAILS_ROOT",
+                        "SB_ONLY",
+                        "WATCH",
+                    ]),
+            )
             .arg(
                 Arg::new("IGNORE_NON_FATAL")
                     .help("Only return a non-zero exit code if a fatal error is found.")
@@ -65,6 +130,26 @@ impl ThinCheckCommand {
                     .long("quiet")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("REF_COUNT_HISTOGRAM")
+                    .help("Print a histogram of the data block reference counts")
+                    .long("ref-count-histogram")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all([
+                        "METADATA_SNAPSHOT",
+                        "SB_ONLY",
+                        "SKIP_MAPPINGS",
+                        "SAMPLE_MAPPINGS",
+                        "WATCH",
+                    ]),
+            )
+            .arg(
+                Arg::new("SHOW_HISTORY")
+                    .help("Print the history of checks, rather than checking")
+                    .long("show-history")
+                    .action(ArgAction::SetTrue)
+                    .requires("HISTORY"),
+            )
             .arg(
                 Arg::new("SB_ONLY")
                     .help("Only check the superblock.")
@@ -77,7 +162,113 @@ impl ThinCheckCommand {
                     .long("skip-mappings")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("WATCH")
+                    .help("Repeatedly check the metadata snapshot of a live pool")
+                    .long("watch")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["AUTO_REPAIR", "CLEAR_NEEDS_CHECK"]),
+            )
+            .arg(
+                Arg::new("VERIFY_DATA_BOUNDS")
+                    .help("Check the mapped blocks are within the data device")
+                    .long("verify-data-bounds")
+                    .action(ArgAction::SetTrue)
+                    .requires("DATA_DEV"),
+            )
             // options
+            .arg(
+                Arg::new("COMPARE")
+                    .help("Check a copy of the metadata too, and report how it differs")
+                    .long("compare")
+                    .value_name("FILE")
+                    .conflicts_with_all([
+                        "AUTO_REPAIR",
+                        "CLEAR_NEEDS_CHECK",
+                        "FIX_CHECKSUMS",
+                        "WATCH",
+                    ]),
+            )
+            .arg(
+                Arg::new("COUNT")
+                    .help("Stop watching after the given number of passes")
+                    .long("count")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64))
+                    .requires("WATCH"),
+            )
+            .arg(
+                Arg::new("CPU_AFFINITY")
+                    .help("Restrict the checker and io threads to a list of cpus")
+                    .long("cpu-affinity")
+                    .value_name("CPUS")
+                    .value_parser(value_parser!(CpuSet)),
+            )
+            .arg(
+                Arg::new("DATA_DEV")
+                    .help("Specify the data device to cross check the mappings against")
+                    .long("data-dev")
+                    .value_name("FILE")
+                    .conflicts_with_all(["SB_ONLY", "SKIP_MAPPINGS"]),
+            )
+            .arg(
+                Arg::new("DATA_BLOCK_SIZE")
+                    .help("Override the data block size if needed")
+                    .long("data-block-size")
+                    .value_name("SECTORS")
+                    .value_parser(value_parser!(u32).range(1..))
+                    .conflicts_with_all(["AUTO_REPAIR", "CLEAR_NEEDS_CHECK"]),
+            )
+            .arg(
+                Arg::new("ERROR_BUDGET")
+                    .help("Tolerate up to this many leaked blocks before failing")
+                    .long("error-budget")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("HISTORY")
+                    .help("Append the result of the check to a history file")
+                    .long("history")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("INTERVAL")
+                    .help("Specify the seconds between passes when watching")
+                    .long("interval")
+                    .value_name("SECS")
+                    .value_parser(value_parser!(u64))
+                    .default_value("60")
+                    .requires("WATCH"),
+            )
+            .arg(
+                Arg::new("IO_THREADS")
+                    .help("Specify the number of threads issuing reads")
+                    .long("io-threads")
+                    .value_name("NUM")
+                    .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..)),
+            )
+            .arg(
+                Arg::new("MEMORY_LIMIT")
+                    .help("Limit memory used for reference counting, spilling to disk")
+                    .long("memory-limit")
+                    .value_name("MB")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("METRICS_FILE")
+                    .help("Write the check results in Prometheus text format to a file")
+                    .long("metrics-file")
+                    .value_name("PATH"),
+            )
+            .arg(
+                Arg::new("NR_DATA_BLOCKS")
+                    .help("Override the number of data blocks if needed")
+                    .long("nr-data-blocks")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64))
+                    .conflicts_with_all(["AUTO_REPAIR", "CLEAR_NEEDS_CHECK"]),
+            )
             .arg(
                 Arg::new("OVERRIDE_MAPPING_ROOT")
                     .help("Specify a mapping root to use")
@@ -92,14 +283,53 @@ impl ThinCheckCommand {
                     .value_name("BLOCKNR")
                     .value_parser(value_parser!(u64)),
             )
+            .arg(
+                Arg::new("SAMPLE_DATA")
+                    .help("Read a sample of the mapped data blocks from the data device")
+                    .long("sample-data")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64))
+                    .requires("DATA_DEV"),
+            )
+            .arg(
+                Arg::new("SAMPLE_MAPPINGS")
+                    .help("Only check a random sample of the mapping leaves, in percent")
+                    .long("sample-mappings")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(1..=100))
+                    .conflicts_with_all([
+                        "SB_ONLY",
+                        "SKIP_MAPPINGS",
+                        "AUTO_REPAIR",
+                        "CLEAR_NEEDS_CHECK",
+                        "DATA_DEV",
+                    ]),
+            )
+            .arg(
+                Arg::new("THREADS")
+                    .help("Specify the number of threads for checking the mappings")
+                    .long("threads")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(usize)),
+            )
+            .arg(
+                Arg::new("TRANSACTION_ID")
+                    .help("Override the transaction id if needed")
+                    .long("transaction-id")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64))
+                    .conflicts_with_all(["AUTO_REPAIR", "CLEAR_NEEDS_CHECK"]),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
-                    .help("Specify the input device to check")
-                    .required(true)
+                    .help("Specify the input device, or packed metadata file, to check")
+                    .required_unless_present("SHOW_HISTORY")
                     .index(1),
             );
-        verbose_args(engine_args(version_args(cmd)))
+        log_target_args(progress_fd_args(verbose_args(engine_args(version_args(
+            cmd,
+        )))))
     }
 }
 
@@ -112,19 +342,68 @@ impl<'a> Command<'a> for ThinCheckCommand {
         let matches = self.cli().get_matches_from(args);
         display_version(&matches);
 
+        if matches.get_flag("SHOW_HISTORY") {
+            let history = Path::new(matches.get_one::<String>("HISTORY").unwrap());
+            let report = mk_simple_report();
+            return to_exit_code(&report, show_history(history, &mut std::io::stdout()));
+        }
+
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
 
-        let report = mk_report(matches.get_flag("QUIET"));
+        let report = mk_target_report(
+            matches.get_flag("QUIET"),
+            parse_log_target(&matches),
+            self.name(),
+        );
         let log_level = match parse_log_level(&matches) {
             Ok(level) => level,
             Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
         };
         report.set_level(log_level);
 
-        if let Err(e) = check_input_file(input_file)
-            .and_then(check_file_not_tiny)
-            .and_then(check_not_xml)
-        {
+        match parse_progress_fd(&matches) {
+            Ok(Some(file)) => report.set_progress_fd(file),
+            Ok(None) => {}
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        }
+
+        if let Err(e) = check_input_file(input_file) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        // Packed metadata is unpacked to a temporary file that's removed once
+        // the check completes.
+        let unpacked = match unpack_if_packed(input_file, &matches) {
+            Ok(unpacked) => unpacked,
+            Err(e) => return to_exit_code::<()>(&report, Err(e)),
+        };
+        let given_input = input_file;
+        let input_file = unpacked.as_ref().map_or(input_file, |tmp| tmp.path());
+
+        if let Err(e) = check_file_not_tiny(input_file).and_then(check_not_xml) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        // The copy is checked in the same way as the input
+        let compare_file = matches.get_one::<String>("COMPARE").map(Path::new);
+        if let Some(Err(e)) = compare_file.map(check_input_file) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+        let unpacked_copy = match compare_file.map(|f| unpack_if_packed(f, &matches)) {
+            Some(Ok(unpacked)) => unpacked,
+            Some(Err(e)) => return to_exit_code::<()>(&report, Err(e)),
+            None => None,
+        };
+        let compare_file = unpacked_copy
+            .as_ref()
+            .map(|tmp| tmp.path())
+            .or(compare_file);
+        if let Some(Err(e)) = compare_file.map(|f| check_file_not_tiny(f).and_then(check_not_xml)) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let data_dev = matches.get_one::<String>("DATA_DEV").map(Path::new);
+        if let Some(Err(e)) = data_dev.map(check_input_file) {
             return to_exit_code::<()>(&report, Err(e));
         }
 
@@ -144,9 +423,39 @@ impl<'a> Command<'a> for ThinCheckCommand {
             clear_needs_check: matches.get_flag("CLEAR_NEEDS_CHECK"),
             override_mapping_root: matches.get_one::<u64>("OVERRIDE_MAPPING_ROOT").cloned(),
             override_details_root: matches.get_one::<u64>("OVERRIDE_DETAILS_ROOT").cloned(),
+            memory_limit: matches
+                .get_one::<u64>("MEMORY_LIMIT")
+                .map(|mb| mb.saturating_mul(1024 * 1024)),
+            nr_threads: matches.get_one::<usize>("THREADS").cloned(),
+            data_dev,
+            verify_data_bounds: matches.get_flag("VERIFY_DATA_BOUNDS"),
+            data_samples: matches.get_one::<u64>("SAMPLE_DATA").cloned(),
+            error_budget: matches.get_one::<u64>("ERROR_BUDGET").cloned(),
+            mapping_sample: matches.get_one::<u8>("SAMPLE_MAPPINGS").cloned(),
+            metrics_file: matches.get_one::<String>("METRICS_FILE").map(Path::new),
+            // the temporary file a packed input is unpacked to changes each run
+            metrics_device: Some(given_input),
+            history: matches.get_one::<String>("HISTORY").map(Path::new),
+            ref_count_histogram: matches.get_flag("REF_COUNT_HISTOGRAM"),
+            fix_checksums: matches.get_flag("FIX_CHECKSUMS"),
+            overrides: SuperblockOverrides {
+                transaction_id: matches.get_one::<u64>("TRANSACTION_ID").cloned(),
+                data_block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
+                nr_data_blocks: matches.get_one::<u64>("NR_DATA_BLOCKS").cloned(),
+            },
             report: report.clone(),
         };
 
-        to_exit_code(&report, check(opts))
+        let result = if matches.get_flag("WATCH") {
+            let interval = Duration::from_secs(*matches.get_one::<u64>("INTERVAL").unwrap());
+            watch(opts, interval, matches.get_one::<u64>("COUNT").cloned())
+        } else if let Some(copy) = compare_file {
+            compare(opts, copy)
+        } else {
+            check(opts)
+        };
+        let code = exit_code(&result);
+        to_exit_code(&report, result);
+        code
     }
 }
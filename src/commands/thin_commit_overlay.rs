@@ -0,0 +1,64 @@
+extern crate clap;
+
+use clap::{Arg, ArgAction};
+use std::path::Path;
+
+use crate::commands::utils::*;
+use crate::commands::Command;
+use crate::io_engine::commit_overlay;
+use crate::version::*;
+
+//------------------------------------------
+
+pub struct ThinCommitOverlayCommand;
+
+impl ThinCommitOverlayCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Copy the blocks held in an overlay file to the metadata it was created for")
+            // flags
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress output messages, return only exit code.")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the overlay file")
+                    .required(true)
+                    .index(1),
+            );
+        version_args(cmd)
+    }
+}
+
+impl<'a> Command<'a> for ThinCommitOverlayCommand {
+    fn name(&self) -> &'a str {
+        "thin_commit_overlay"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let overlay = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let report = mk_report(matches.get_flag("QUIET"));
+
+        if let Err(e) = check_input_file(overlay) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let result = commit_overlay(overlay)
+            .map(|nr_blocks| report.info(&format!("committed {} blocks", nr_blocks)))
+            .map_err(anyhow::Error::from);
+        to_exit_code(&report, result)
+    }
+}
+
+//------------------------------------------
@@ -1,6 +1,7 @@
 extern crate clap;
 
 use anyhow::anyhow;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction, ArgGroup};
 use std::path::Path;
 
@@ -9,6 +10,7 @@ use crate::commands::utils::*;
 use crate::commands::Command;
 use crate::thin::delta::*;
 use crate::thin::delta_visitor::Snap;
+use crate::units::StorageSize;
 use crate::version::*;
 
 //------------------------------------------
@@ -24,10 +26,41 @@ impl ThinDeltaCommand {
             .about("Print the differences in the mappings between two thin devices")
             .arg(
                 Arg::new("METADATA_SNAPSHOT")
-                    .help("Use metadata snapshot")
+                    .help("Access the metadata snapshot on a live pool")
                     .short('m')
                     .long("metadata-snap")
-                    .action(ArgAction::SetTrue),
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64))
+                    .num_args(0..=1)
+                    .require_equals(true),
+            )
+            .arg(
+                Arg::new("EXCLUDE_UNMAPPED")
+                    .help("Report the ranges mapped in only one device as different")
+                    .long("exclude-unmapped")
+                    .action(ArgAction::SetTrue)
+                    .overrides_with("INCLUDE_UNMAPPED"),
+            )
+            .arg(
+                Arg::new("INCLUDE_UNMAPPED")
+                    .help("Report the ranges mapped in only one device as left or right only")
+                    .long("include-unmapped")
+                    .action(ArgAction::SetTrue)
+                    .overrides_with("EXCLUDE_UNMAPPED"),
+            )
+            .arg(
+                Arg::new("SUMMARY_ONLY")
+                    .help("Print the number of blocks of each type rather than the ranges")
+                    .long("summary-only")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["FORMAT", "VERBOSE"]),
+            )
+            .arg(
+                Arg::new("ESTIMATE_TRANSFER")
+                    .help("Print the number of data blocks a backup of each differing range needs to read")
+                    .long("estimate-transfer")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["FORMAT", "VERBOSE", "SUMMARY_ONLY", "GRANULARITY"]),
             )
             .arg(
                 Arg::new("VERBOSE")
@@ -36,6 +69,51 @@ impl ThinDeltaCommand {
                     .action(ArgAction::SetTrue),
             )
             // options
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the output format")
+                    .short('f')
+                    .long("format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["xml", "bitmap", "json", "csv"])
+                            .map(|s| s.parse::<DeltaFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("xml")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("GRANULARITY")
+                    .help("List the chunks of the given size holding changes")
+                    .long("granularity")
+                    .value_name("SIZE")
+                    .value_parser(value_parser!(StorageSize))
+                    .conflicts_with_all(["FORMAT", "SUMMARY_ONLY", "VERBOSE"]),
+            )
+            .arg(
+                Arg::new("OUTPUT")
+                    .help("Specify the output file rather than stdout")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("CHAIN")
+                    .help("Diff each consecutive pair of an ordered list of thin volumes")
+                    .long("chain")
+                    .value_name("DEV_IDS")
+                    .value_parser(|s: &str| s.parse::<ThinSpec>())
+                    .value_delimiter(',')
+                    .conflicts_with_all(["PAIRS_FILE", "SNAP1", "SNAP2"]),
+            )
+            .arg(
+                Arg::new("PAIRS_FILE")
+                    .help("Diff each pair of thin volumes listed in a file")
+                    .long("pairs-file")
+                    .value_name("FILE")
+                    .conflicts_with_all(["SNAP1", "SNAP2"]),
+            )
             .arg(
                 Arg::new("ROOT1")
                     .help("The root block for the first thin volume to diff")
@@ -55,7 +133,7 @@ impl ThinDeltaCommand {
                     .help("The numeric identifier for the first thin volume to diff")
                     .long("thin1")
                     .value_name("DEV_ID")
-                    .value_parser(value_parser!(u64))
+                    .value_parser(|s: &str| s.parse::<ThinSpec>())
                     .visible_alias("snap1"),
             )
             .arg(
@@ -63,9 +141,16 @@ impl ThinDeltaCommand {
                     .help("The numeric identifier for the second thin volume to diff")
                     .long("thin2")
                     .value_name("DEV_ID")
-                    .value_parser(value_parser!(u64))
+                    .value_parser(|s: &str| s.parse::<ThinSpec>())
                     .visible_alias("snap2"),
             )
+            .arg(
+                Arg::new("VERIFY_DATA")
+                    .help("Compare the data of blocks sharing a mapping on data devices")
+                    .long("verify-data")
+                    .value_name("DEV")
+                    .action(ArgAction::Append),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
@@ -96,6 +181,7 @@ impl<'a> Command<'a> for ThinDeltaCommand {
         display_version(&matches);
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let output_file = matches.get_one::<String>("OUTPUT").map(Path::new);
 
         let report = mk_report(false);
 
@@ -103,33 +189,55 @@ impl<'a> Command<'a> for ThinDeltaCommand {
             return to_exit_code::<()>(&report, Err(e));
         }
 
-        let snap1 = match matches
-            .get_one::<clap::Id>("SNAP1")
-            .unwrap_or(&clap::Id::default())
-            .as_str()
-        {
-            "THIN1" => Snap::DeviceId(*matches.get_one::<u64>("THIN1").unwrap()),
-            "ROOT1" => Snap::RootBlock(*matches.get_one::<u64>("ROOT1").unwrap()),
-            _ => {
-                return to_exit_code::<()>(
-                    &report,
-                    Err(anyhow!("--thin1 or --root1 not specified")),
-                )
-            }
-        };
+        let chain = matches
+            .get_many::<ThinSpec>("CHAIN")
+            .map(|specs| specs.cloned().collect::<Vec<_>>());
+
+        let pairs = match (matches.get_one::<String>("PAIRS_FILE"), chain) {
+            (Some(path), _) => match read_pairs_file(Path::new(path)) {
+                Ok(pairs) => pairs,
+                Err(e) => return to_exit_code::<()>(&report, Err(e)),
+            },
+            (None, Some(chain)) => match chain_pairs(&chain) {
+                Ok(pairs) => pairs,
+                Err(e) => return to_exit_code::<()>(&report, Err(e)),
+            },
+            (None, None) => {
+                let snap1 = match matches
+                    .get_one::<clap::Id>("SNAP1")
+                    .unwrap_or(&clap::Id::default())
+                    .as_str()
+                {
+                    "THIN1" => matches.get_one::<ThinSpec>("THIN1").unwrap().clone(),
+                    "ROOT1" => {
+                        ThinSpec::Input(Snap::RootBlock(*matches.get_one::<u64>("ROOT1").unwrap()))
+                    }
+                    _ => {
+                        return to_exit_code::<()>(
+                            &report,
+                            Err(anyhow!("--thin1 or --root1 not specified")),
+                        )
+                    }
+                };
+
+                let snap2 = match matches
+                    .get_one::<clap::Id>("SNAP2")
+                    .unwrap_or(&clap::Id::default())
+                    .as_str()
+                {
+                    "THIN2" => matches.get_one::<ThinSpec>("THIN2").unwrap().clone(),
+                    "ROOT2" => {
+                        ThinSpec::Input(Snap::RootBlock(*matches.get_one::<u64>("ROOT2").unwrap()))
+                    }
+                    _ => {
+                        return to_exit_code::<()>(
+                            &report,
+                            Err(anyhow!("--thin2 or --root2 not specified")),
+                        )
+                    }
+                };
 
-        let snap2 = match matches
-            .get_one::<clap::Id>("SNAP2")
-            .unwrap_or(&clap::Id::default())
-            .as_str()
-        {
-            "THIN2" => Snap::DeviceId(*matches.get_one::<u64>("THIN2").unwrap()),
-            "ROOT2" => Snap::RootBlock(*matches.get_one::<u64>("ROOT2").unwrap()),
-            _ => {
-                return to_exit_code::<()>(
-                    &report,
-                    Err(anyhow!("--thin2 or --root2 not specified")),
-                )
+                vec![(snap1, snap2)]
             }
         };
 
@@ -140,11 +248,24 @@ impl<'a> Command<'a> for ThinDeltaCommand {
 
         let opts = ThinDeltaOptions {
             input: input_file,
+            output: output_file,
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
-            snap1,
-            snap2,
+            metadata_snap: matches.get_one::<u64>("METADATA_SNAPSHOT").copied(),
+            pairs,
             verbose: matches.get_flag("VERBOSE"),
+            format: *matches.get_one::<DeltaFormat>("FORMAT").unwrap(),
+            summary_only: matches.get_flag("SUMMARY_ONLY"),
+            estimate_transfer: matches.get_flag("ESTIMATE_TRANSFER"),
+            granularity: matches
+                .get_one::<StorageSize>("GRANULARITY")
+                .map(|s| s.size_bytes()),
+            exclude_unmapped: matches.get_flag("EXCLUDE_UNMAPPED"),
+            verify_data: matches
+                .get_many::<String>("VERIFY_DATA")
+                .unwrap_or_default()
+                .map(Path::new)
+                .collect(),
         };
 
         to_exit_code(&report, delta(opts))
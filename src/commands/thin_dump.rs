@@ -1,5 +1,6 @@
 extern crate clap;
 
+use anyhow::anyhow;
 use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
@@ -96,6 +97,34 @@ impl ThinDumpCommand {
                     .long("output")
                     .value_name("FILE"),
             )
+            .arg(
+                Arg::new("OUTPUT_DIR")
+                    .help("Write each device to its own file in a directory, with a manifest")
+                    .long("output-dir")
+                    .value_name("DIR")
+                    .conflicts_with_all(["OUTPUT", "FORMAT", "RENUMBER_FROM"]),
+            )
+            .arg(
+                Arg::new("RENUMBER_FROM")
+                    .help("Renumber the devices sequentially, starting from the given id")
+                    .long("renumber-from")
+                    .value_name("THIN_ID")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("ID_MAP")
+                    .help("Write the mapping of original to renumbered device ids")
+                    .long("id-map")
+                    .value_name("FILE")
+                    .requires("RENUMBER_FROM"),
+            )
+            .arg(
+                Arg::new("THROTTLE_IO")
+                    .help("Limit the rate the metadata is read at, in MiB/s")
+                    .long("throttle-io")
+                    .value_name("MiB/s")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
             .arg(
                 Arg::new("TRANSACTION_ID")
                     .help("Override the transaction id if needed")
@@ -125,6 +154,7 @@ impl<'a> Command<'a> for ThinDumpCommand {
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
         let output_file = matches.get_one::<String>("OUTPUT").map(Path::new);
+        let output_dir = matches.get_one::<String>("OUTPUT_DIR").map(Path::new);
 
         let report = mk_report(matches.get_flag("QUIET"));
         let log_level = match parse_log_level(&matches) {
@@ -142,6 +172,19 @@ impl<'a> Command<'a> for ThinDumpCommand {
             return to_exit_code(&report, engine_opts);
         }
 
+        let throttle_io = match matches.get_one::<u64>("THROTTLE_IO") {
+            Some(mib) => match mib.checked_mul(1 << 20) {
+                Some(rate) => Some(rate),
+                None => {
+                    return to_exit_code::<()>(
+                        &report,
+                        Err(anyhow!("--throttle-io of {} MiB/s is too large", mib)),
+                    )
+                }
+            },
+            None => None,
+        };
+
         let selected_devs: Option<Vec<u64>> = matches
             .get_many::<u64>("DEV_ID")
             .map(|devs| devs.copied().collect());
@@ -149,6 +192,7 @@ impl<'a> Command<'a> for ThinDumpCommand {
         let opts = ThinDumpOptions {
             input: input_file,
             output: output_file,
+            output_dir,
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
             repair: matches.get_flag("REPAIR"),
@@ -160,6 +204,9 @@ impl<'a> Command<'a> for ThinDumpCommand {
             },
             selected_devs,
             format: matches.get_one::<OutputFormat>("FORMAT").unwrap().clone(),
+            renumber_from: matches.get_one::<u32>("RENUMBER_FROM").cloned(),
+            id_map: matches.get_one::<String>("ID_MAP").map(Path::new),
+            throttle_io,
         };
 
         to_exit_code(&report, dump(opts))
@@ -2,6 +2,7 @@ extern crate clap;
 
 use anyhow::{anyhow, Result};
 use clap::Arg;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::{self, Write};
 use std::path::Path;
@@ -25,7 +26,9 @@ use tui::{
     style::{Color, Modifier, Style},
     terminal::Frame,
     text::Span,
-    widgets::{Block, Borders, List, ListItem, ListState, Row, StatefulWidget, Table, Widget},
+    widgets::{
+        Block, Borders, List, ListItem, ListState, Paragraph, Row, StatefulWidget, Table, Widget,
+    },
     Terminal,
 };
 
@@ -478,6 +481,7 @@ enum Action {
     PushDeviceDetail(u64),
     PushTopLevel(u64),
     PushBottomLevel(u32, u64),
+    PushSearch(u64),
     PopPanel,
 }
 
@@ -773,6 +777,153 @@ impl Panel for BottomLevelPanel {
 
 //------------------------------------
 
+// A reference to a data block from a thin device
+#[derive(Clone, Copy)]
+struct SearchHit {
+    thin_id: u32,
+    thin_block: u64,
+    leaf: u64,
+}
+
+// Finds the thin blocks mapped to a data block beneath a node of a mapping
+// tree, along with the leaves holding them.  The subtrees shared between
+// snapshots are only searched once.
+fn search_mapping_tree(
+    engine: &dyn IoEngine,
+    loc: u64,
+    data_block: u64,
+    searched: &mut BTreeMap<u64, Vec<(u64, u64)>>,
+) -> Result<Vec<(u64, u64)>> {
+    if let Some(hits) = searched.get(&loc) {
+        return Ok(hits.clone());
+    }
+
+    let hits = match read_node::<BlockTime>(engine, loc)? {
+        btree::Node::Internal { values, .. } => {
+            let mut hits = Vec::new();
+            for child in values {
+                hits.extend(search_mapping_tree(engine, child, data_block, searched)?);
+            }
+            hits
+        }
+        btree::Node::Leaf { keys, values, .. } => keys
+            .iter()
+            .zip(values.iter())
+            .filter(|(_, bt)| bt.block == data_block)
+            .map(|(k, _)| (*k, loc))
+            .collect(),
+    };
+
+    searched.insert(loc, hits.clone());
+    Ok(hits)
+}
+
+fn search_devices(
+    engine: &dyn IoEngine,
+    loc: u64,
+    data_block: u64,
+    searched: &mut BTreeMap<u64, Vec<(u64, u64)>>,
+    hits: &mut Vec<SearchHit>,
+) -> Result<()> {
+    match read_node::<u64>(engine, loc)? {
+        btree::Node::Internal { values, .. } => {
+            for child in values {
+                search_devices(engine, child, data_block, searched, hits)?;
+            }
+        }
+        btree::Node::Leaf { keys, values, .. } => {
+            for (thin_id, root) in keys.iter().zip(values.iter()) {
+                for (thin_block, leaf) in search_mapping_tree(engine, *root, data_block, searched)?
+                {
+                    hits.push(SearchHit {
+                        thin_id: *thin_id as u32,
+                        thin_block,
+                        leaf,
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct SearchPanel {
+    data_block: u64,
+    hits: Vec<SearchHit>,
+    state: ListState,
+}
+
+impl SearchPanel {
+    fn new(data_block: u64, hits: Vec<SearchHit>) -> SearchPanel {
+        let mut state = ListState::default();
+        state.select(Some(0));
+
+        SearchPanel {
+            data_block,
+            hits,
+            state,
+        }
+    }
+}
+
+impl Panel for SearchPanel {
+    fn render(&mut self, area: Rect, f: &mut Frame_) {
+        let items: Vec<ListItem> = if self.hits.is_empty() {
+            vec![ListItem::new(Span::raw("no references".to_string()))]
+        } else {
+            self.hits
+                .iter()
+                .map(|h| {
+                    ListItem::new(Span::raw(format!(
+                        "thin dev #{}, block {}",
+                        h.thin_id, h.thin_block
+                    )))
+                })
+                .collect()
+        };
+
+        let title = format!(
+            "Data block {} ({} references)",
+            self.data_block,
+            self.hits.len()
+        );
+        let items = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(
+                Style::default()
+                    .bg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+            );
+
+        f.render_stateful_widget(items, area, &mut self.state);
+    }
+
+    fn input(&mut self, k: Key) -> Option<Action> {
+        match k {
+            Key::Char('j') | Key::Down => {
+                ls_next(&mut self.state, std::cmp::max(self.hits.len(), 1));
+                None
+            }
+            Key::Char('k') | Key::Up => {
+                ls_previous(&mut self.state);
+                None
+            }
+            Key::Char('l') | Key::Right => self
+                .hits
+                .get(self.state.selected().unwrap())
+                .map(|h| PushBottomLevel(h.thin_id, h.leaf)),
+            Key::Char('h') | Key::Left => Some(PopPanel),
+            _ => None,
+        }
+    }
+
+    fn path_action(&mut self, _child: u64) -> Option<Action> {
+        None
+    }
+}
+
+//------------------------------------
+
 fn perform_action(
     panels: &mut Vec<Box<dyn Panel>>,
     engine: &dyn IoEngine,
@@ -791,6 +942,19 @@ fn perform_action(
             let node = read_node::<BlockTime>(engine, b)?;
             panels.push(Box::new(BottomLevelPanel::new(thin_id, node)));
         }
+        PushSearch(data_block) => {
+            let sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+            let mut searched = BTreeMap::new();
+            let mut hits = Vec::new();
+            search_devices(
+                engine,
+                sb.mapping_root,
+                data_block,
+                &mut searched,
+                &mut hits,
+            )?;
+            panels.push(Box::new(SearchPanel::new(data_block, hits)));
+        }
         PopPanel => {
             if panels.len() > 1 {
                 panels.pop();
@@ -823,7 +987,10 @@ fn explore(path: &Path, node_path: Option<Vec<u64>>) -> Result<()> {
         panels.push(Box::new(SBPanel::new(sb)));
     }
 
-    let events = Events::new();
+    let mut events = Events::new();
+
+    // the data block being typed in after '/'
+    let mut search: Option<String> = None;
 
     let stdout = io::stdout();
     let mut stdout = stdout.lock().into_raw_mode()?;
@@ -833,10 +1000,25 @@ fn explore(path: &Path, node_path: Option<Vec<u64>>) -> Result<()> {
 
     'main: loop {
         let render_panels = |f: &mut Frame_| {
+            let mut area = f.size();
+            if let Some(text) = &search {
+                let rows = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+                    .split(area);
+                let prompt = Paragraph::new(format!("data block: {}", text)).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Search references"),
+                );
+                f.render_widget(prompt, rows[1]);
+                area = rows[0];
+            }
+
             let chunks = Layout::default()
                 .direction(Direction::Horizontal)
                 .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-                .split(f.size());
+                .split(area);
 
             let mut base = panels.len();
             if base >= 2 {
@@ -855,8 +1037,35 @@ fn explore(path: &Path, node_path: Option<Vec<u64>>) -> Result<()> {
         let last = panels.len() - 1;
         let active_panel = &mut panels[last];
         if let Event::Input(key) = events.next()? {
+            if let Some(text) = search.as_mut() {
+                match key {
+                    Key::Char(c) if c.is_ascii_digit() => text.push(c),
+                    Key::Backspace => {
+                        text.pop();
+                    }
+                    Key::Char('\n') => {
+                        let data_block = text.parse::<u64>().ok();
+                        search = None;
+                        events.enable_exit_key();
+                        if let Some(data_block) = data_block {
+                            perform_action(&mut panels, &engine, PushSearch(data_block))?;
+                        }
+                    }
+                    Key::Esc => {
+                        search = None;
+                        events.enable_exit_key();
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
             match key {
                 Key::Char('q') => break 'main,
+                Key::Char('/') => {
+                    search = Some(String::new());
+                    events.disable_exit_key();
+                }
                 _ => {
                     if let Some(action) = active_panel.input(key) {
                         perform_action(&mut panels, &engine, action)?;
@@ -920,3 +1129,78 @@ impl<'a> Command<'a> for ThinExploreCommand {
 }
 
 //------------------------------------
+
+#[cfg(test)]
+mod search_tests {
+    use super::*;
+    use crate::io_engine::core::CoreIoEngine;
+    use crate::pdata::btree_builder::test_utils::build_btree_from_mappings;
+    use crate::pdata::space_map::CoreSpaceMap;
+    use crate::write_batcher::WriteBatcher;
+
+    fn mk_mappings(mappings: &[(u64, u64)]) -> Vec<(u64, BlockTime)> {
+        mappings
+            .iter()
+            .map(|&(thin_block, block)| (thin_block, BlockTime { block, time: 0 }))
+            .collect()
+    }
+
+    // Devices 0 and 1 share a mapping tree of several leaves, while device
+    // 2 has a tree of its own that also maps data block 500.
+    fn search(data_block: u64) -> Result<(Arc<dyn IoEngine + Send + Sync>, Vec<SearchHit>)> {
+        let nr_metadata_blocks = 1024;
+        let engine: Arc<dyn IoEngine + Send + Sync> =
+            Arc::new(CoreIoEngine::new(nr_metadata_blocks));
+        let sm = Arc::new(std::sync::Mutex::new(CoreSpaceMap::<u8>::new(
+            nr_metadata_blocks,
+        )));
+        let mut w = WriteBatcher::new(engine.clone(), sm, 16);
+
+        let shared: Vec<(u64, u64)> = (0..1000).map(|b| (b, b)).collect();
+        let shared = build_btree_from_mappings(&mut w, &mk_mappings(&shared));
+        let own = build_btree_from_mappings(&mut w, &mk_mappings(&[(0, 1000), (5000, 500)]));
+        let roots = [
+            (0, shared.root().block),
+            (1, shared.root().block),
+            (2, own.root().block),
+        ];
+        let top_level = build_btree_from_mappings::<u64>(&mut w, &roots);
+
+        let mut searched = BTreeMap::new();
+        let mut hits = Vec::new();
+        search_devices(
+            engine.as_ref(),
+            top_level.root().block,
+            data_block,
+            &mut searched,
+            &mut hits,
+        )?;
+        Ok((engine, hits))
+    }
+
+    #[test]
+    fn every_reference_to_a_data_block_is_found() -> Result<()> {
+        let (engine, hits) = search(500)?;
+        let found: Vec<(u32, u64)> = hits.iter().map(|h| (h.thin_id, h.thin_block)).collect();
+        assert_eq!(found, [(0, 500), (1, 500), (2, 5000)]);
+
+        // the shared subtree is searched once, so both devices see its leaf
+        assert_eq!(hits[0].leaf, hits[1].leaf);
+        for h in hits {
+            match read_node::<BlockTime>(engine.as_ref(), h.leaf)? {
+                btree::Node::Leaf { keys, .. } => assert!(keys.contains(&h.thin_block)),
+                _ => panic!("the hit isn't in a leaf"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unreferenced_data_blocks_have_no_hits() -> Result<()> {
+        let (_, hits) = search(3000)?;
+        assert!(hits.is_empty());
+        Ok(())
+    }
+}
+
+//------------------------------------
@@ -1,5 +1,6 @@
 extern crate clap;
 
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
@@ -10,6 +11,13 @@ use crate::report::{parse_log_level, verbose_args};
 use crate::thin::ls::*;
 use crate::version::*;
 
+// Distinct exit codes let monitoring scripts tell which usage threshold
+// was crossed.  They start above 2, which clap exits with on a bad command
+// line, and other errors are still reported as exitcode::USAGE.
+const EXIT_DATA_ALARM: exitcode::ExitCode = 3;
+const EXIT_METADATA_ALARM: exitcode::ExitCode = 4;
+const EXIT_BOTH_ALARMS: exitcode::ExitCode = 5;
+
 pub struct ThinLsCommand;
 
 impl ThinLsCommand {
@@ -42,6 +50,75 @@ impl ThinLsCommand {
                     .value_name("FIELDS")
                     .value_parser(value_parser!(OutputField)),
             )
+            .arg(
+                Arg::new("OUTPUT_FORMAT")
+                    .help("Choose the output format")
+                    .long("output-format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["table", "json"])
+                            .map(|s| s.parse::<LsFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("table")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("SORT")
+                    .help("Sort the devices by a field, append ':desc' for the largest first")
+                    .long("sort")
+                    .value_name("FIELD")
+                    .value_parser(value_parser!(SortKey)),
+            )
+            .arg(
+                Arg::new("FILTER")
+                    .help("Only list the devices matching an expression, such as 'mapped_blocks>1000'")
+                    .long("filter")
+                    .value_name("EXPR")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(Filter)),
+            )
+            .arg(
+                Arg::new("BASELINE")
+                    .help("Print the growth of each device since an xml or pack dump")
+                    .long("baseline")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("AUTO_SNAP")
+                    .help("Reserve a metadata snapshot of a live pool while listing")
+                    .long("auto-snap")
+                    .value_name("POOL")
+                    .conflicts_with("METADATA_SNAPSHOT"),
+            )
+            .arg(
+                Arg::new("TREE")
+                    .help("Show the snapshots of each device below it")
+                    .long("tree")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["FILTER", "OUTPUT_FORMAT"]),
+            )
+            .arg(
+                Arg::new("ALERT_THRESHOLD")
+                    .help("Raise the data and metadata alarms at this usage percentage")
+                    .long("alert-threshold")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(0..=100)),
+            )
+            .arg(
+                Arg::new("WARN_DATA")
+                    .help("Exit with code 2 if the data usage reaches this percentage")
+                    .long("warn-data")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(0..=100)),
+            )
+            .arg(
+                Arg::new("WARN_METADATA")
+                    .help("Exit with code 3 if the metadata usage reaches this percentage")
+                    .long("warn-metadata")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(0..=100)),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
@@ -77,8 +154,15 @@ impl<'a> Command<'a> for ThinLsCommand {
             return to_exit_code::<()>(&report, Err(e));
         }
 
+        let tree = matches.get_flag("TREE");
         let fields = matches.get_many::<OutputField>("FORMAT").map_or_else(
-            || vec![DeviceId, Mapped, CreationTime, SnapshottedTime],
+            || {
+                if tree {
+                    vec![DeviceId, Mapped, Exclusive, CreationTime, SnapshottedTime]
+                } else {
+                    vec![DeviceId, Mapped, CreationTime, SnapshottedTime]
+                }
+            },
             |fmt| fmt.cloned().collect(),
         );
 
@@ -87,14 +171,41 @@ impl<'a> Command<'a> for ThinLsCommand {
             return to_exit_code(&report, engine_opts);
         }
 
+        // The specific thresholds take precedence
+        let alert_threshold = matches.get_one::<u8>("ALERT_THRESHOLD");
+
         let opts = ThinLsOptions {
             input: input_file,
             engine_opts: engine_opts.unwrap(),
             fields,
+            format: *matches.get_one::<LsFormat>("OUTPUT_FORMAT").unwrap(),
+            sort: matches.get_one::<SortKey>("SORT").cloned(),
+            filters: matches
+                .get_many::<Filter>("FILTER")
+                .map_or_else(Vec::new, |f| f.cloned().collect()),
+            tree,
+            baseline: matches.get_one::<String>("BASELINE").map(Path::new),
+            auto_snap: matches.get_one::<String>("AUTO_SNAP").map(String::as_str),
             no_headers: matches.get_flag("NO_HEADERS"),
+            warn_data: matches
+                .get_one::<u8>("WARN_DATA")
+                .or(alert_threshold)
+                .cloned(),
+            warn_metadata: matches
+                .get_one::<u8>("WARN_METADATA")
+                .or(alert_threshold)
+                .cloned(),
             report: report.clone(),
         };
 
-        to_exit_code(&report, ls(opts))
+        match ls(opts) {
+            Ok(UsageAlarms {
+                data: true,
+                metadata: true,
+            }) => EXIT_BOTH_ALARMS,
+            Ok(UsageAlarms { data: true, .. }) => EXIT_DATA_ALARM,
+            Ok(UsageAlarms { metadata: true, .. }) => EXIT_METADATA_ALARM,
+            r => to_exit_code(&report, r),
+        }
     }
 }
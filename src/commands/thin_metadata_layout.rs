@@ -0,0 +1,87 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::Arg;
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::pdata::layout::LayoutFormat;
+use crate::report::mk_simple_report;
+use crate::thin::layout::*;
+use crate::version::*;
+
+//------------------------------------------
+use crate::commands::Command;
+
+pub struct ThinMetadataLayoutCommand;
+
+impl ThinMetadataLayoutCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Map the role of every metadata block, for visualising its layout")
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the output format")
+                    .short('f')
+                    .long("format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["csv", "json"])
+                            .map(|s| s.parse::<LayoutFormat>().unwrap()),
+                    )
+                    .default_value("csv"),
+            )
+            .arg(
+                Arg::new("OUTPUT")
+                    .help("Specify the output file rather than stdout")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input device")
+                    .required(true)
+                    .index(1),
+            );
+
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for ThinMetadataLayoutCommand {
+    fn name(&self) -> &'a str {
+        "thin_metadata_layout"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+        let report = std::sync::Arc::new(mk_simple_report());
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let output_file = matches.get_one::<String>("OUTPUT").map(Path::new);
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let opts = ThinLayoutOptions {
+            input: input_file,
+            output: output_file,
+            engine_opts: engine_opts.unwrap(),
+            format: *matches.get_one::<LayoutFormat>("FORMAT").unwrap(),
+        };
+
+        to_exit_code(&report, metadata_layout(opts))
+    }
+}
+
+//------------------------------------------
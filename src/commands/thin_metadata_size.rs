@@ -88,6 +88,24 @@ impl ThinMetadataSizeCommand {
                     .value_name("NUM")
                     .value_parser(value_parser!(u64)),
             )
+            .arg(
+                Arg::new("SNAPSHOTS_PER_THIN")
+                    .help("Expected number of snapshots of each thin device")
+                    .long("snapshots-per-thin")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64))
+                    .default_value("0")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("DIVERGENCE")
+                    .help("Expected percentage of each snapshot diverging from its origin")
+                    .long("divergence")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(f64))
+                    .default_value("0")
+                    .hide_default_value(true),
+            )
             .arg(
                 Arg::new("UNIT")
                     .help("Specify the output unit in {bskKmMgG}")
@@ -118,6 +136,8 @@ impl ThinMetadataSizeCommand {
             .unwrap()
             .size_bytes();
         let max_thins = *matches.get_one::<u64>("MAX_THINS").unwrap();
+        let snapshots_per_thin = *matches.get_one::<u64>("SNAPSHOTS_PER_THIN").unwrap();
+        let divergence = *matches.get_one::<f64>("DIVERGENCE").unwrap();
         let unit = *matches.get_one::<Units>("UNIT").unwrap();
 
         let format = if let Some(fmt) = matches.get_one::<OutputFormat>("NUMERIC_ONLY") {
@@ -138,10 +158,14 @@ impl ThinMetadataSizeCommand {
             return Err(anyhow!("pool size must be larger than block size"));
         }
 
+        check_divergence(divergence)?;
+
         Ok((
             ThinMetadataSizeOptions {
                 nr_blocks: pool_size / block_size,
                 max_thins,
+                snapshots_per_thin,
+                divergence,
             },
             unit,
             format,
@@ -1,12 +1,13 @@
 extern crate clap;
 
+use anyhow::anyhow;
 use clap::{Arg, ArgAction};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
-use crate::pack::toplevel::unpack;
+use crate::pack::toplevel::unpack_onto;
 use crate::report::mk_simple_report;
 use crate::version::*;
 
@@ -22,7 +23,9 @@ impl ThinMetadataUnpackCommand {
             // flags
             .arg(
                 Arg::new("FORCE")
-                    .help("Force overwrite the output file")
+                    .help(
+                        "Force overwrite the output, even onto a device smaller than the metadata",
+                    )
                     .short('f')
                     .long("force")
                     .action(ArgAction::SetTrue),
@@ -66,13 +69,23 @@ impl<'a> Command<'a> for ThinMetadataUnpackCommand {
             return to_exit_code::<()>(&report, Err(e));
         }
 
-        if !matches.get_flag("FORCE") {
+        // The blocks are written straight to the output, there's no engine
+        // to put an overlay in front of
+        if matches.get_one::<PathBuf>("OVERLAY").is_some() {
+            return to_exit_code::<()>(
+                &report,
+                Err(anyhow!("--overlay isn't supported, unpack to a file instead")),
+            );
+        }
+
+        let force = matches.get_flag("FORCE");
+        if !force {
             if let Err(e) = check_overwrite_metadata(&report, output_file) {
                 return to_exit_code::<()>(&report, Err(e));
             }
         }
 
         let report = std::sync::Arc::new(report);
-        to_exit_code(&report, unpack(input_file, output_file))
+        to_exit_code(&report, unpack_onto(input_file, output_file, force))
     }
 }
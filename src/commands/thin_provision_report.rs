@@ -0,0 +1,104 @@
+extern crate clap;
+
+use clap::{value_parser, Arg, ArgAction};
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::commands::Command;
+use crate::thin::provision_report::*;
+use crate::version::*;
+
+//------------------------------------------
+
+pub struct ThinProvisionReportCommand;
+
+impl ThinProvisionReportCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Report which files of a filesystem on a thin device share its data blocks")
+            .arg(
+                Arg::new("METADATA_SNAPSHOT")
+                    .help("Use metadata snapshot")
+                    .short('m')
+                    .long("metadata-snap")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("EXTENTS")
+                    .help("Specify the extent map of the filesystem, from filefrag -e or xfs_bmap")
+                    .long("extents")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("OUTPUT")
+                    .help("Specify the output file rather than stdout")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("THIN")
+                    .help("The numeric identifier of the thin device holding the filesystem")
+                    .long("thin")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .required(true),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input device")
+                    .required(true)
+                    .index(1),
+            );
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for ThinProvisionReportCommand {
+    fn name(&self) -> &'a str {
+        "thin_provision_report"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let extents_file = Path::new(matches.get_one::<String>("EXTENTS").unwrap());
+        let output_file = matches.get_one::<String>("OUTPUT").map(Path::new);
+
+        let report = mk_report(false);
+
+        if let Err(e) = check_input_file(input_file)
+            .and_then(check_file_not_tiny)
+            .and_then(|_| check_input_file(extents_file))
+        {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        let opts = ThinProvisionReportOptions {
+            input: input_file,
+            extents: extents_file,
+            output: output_file,
+            thin_id: *matches.get_one::<u64>("THIN").unwrap(),
+            engine_opts: engine_opts.unwrap(),
+            report: report.clone(),
+        };
+
+        to_exit_code(&report, provision_report(opts))
+    }
+}
+
+//------------------------------------------
@@ -1,16 +1,30 @@
 extern crate clap;
 
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
-use crate::report::{parse_log_level, verbose_args};
-use crate::thin::metadata_repair::SuperblockOverrides;
-use crate::thin::repair::{repair, ThinRepairOptions};
+use crate::file_utils;
+use crate::io_engine::SECTOR_SHIFT;
+use crate::report::{parse_log_level, parse_progress_fd, progress_fd_args, verbose_args};
+use crate::thin::metadata_repair::{SuperblockInference, SuperblockOverrides};
+use crate::thin::repair::{
+    backup_path_in, default_journal_path, repair, scan, RepairFormat, ThinRepairOptions,
+    ThinScanRootsOptions,
+};
+use crate::thin::repair_answers::RepairAnswers;
+use crate::thin::sm_report::SmReportFormat;
 use crate::version::*;
 
+fn get_data_dev_sectors(data_dev: &Path) -> anyhow::Result<u64> {
+    check_input_file(data_dev)?;
+    let size = file_utils::file_size(data_dev)?;
+    Ok(size >> SECTOR_SHIFT)
+}
+
 pub struct ThinRepairCommand;
 
 impl ThinRepairCommand {
@@ -20,6 +34,28 @@ impl ThinRepairCommand {
             .version(crate::tools_version!())
             .disable_version_flag(true)
             .about("Repair thin-provisioning metadata, and write it to different device or file")
+            .arg(
+                Arg::new("IN_PLACE")
+                    .help("Repair the input in place, through a journal")
+                    .long("in-place")
+                    .action(ArgAction::SetTrue)
+                    // an overlay would take the writes to the journal too
+                    .conflicts_with_all(["OUTPUT", "OUTPUT_FORMAT", "OVERLAY", "SCAN_ROOTS"]),
+            )
+            .arg(
+                Arg::new("DETERMINISTIC")
+                    .help(
+                        "Lay the metadata out the same way on any machine, for reproducible output",
+                    )
+                    .long("deterministic")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("IGNORE_SPACE_ESTIMATE")
+                    .help("Go ahead even if the output looks too small for the repaired metadata")
+                    .long("ignore-space-estimate")
+                    .action(ArgAction::SetTrue),
+            )
             .arg(
                 Arg::new("QUIET")
                     .help("Suppress output messages, return only exit code.")
@@ -27,7 +63,58 @@ impl ThinRepairCommand {
                     .long("quiet")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("INFER")
+                    .help("Apply the inferred values of lost superblock fields")
+                    .long("infer")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("INTERACTIVE")
+                    .help("Ask at the choices made in rebuilding a lost superblock")
+                    .long("interactive")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["ANSWERS_FILE", "SCAN_ROOTS"]),
+            )
+            .arg(
+                Arg::new("SCAN_ROOTS")
+                    .help("List the candidate roots to repair from, then exit")
+                    .long("scan-roots")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("SALVAGE")
+                    .help("Recover what can be read of damaged mapping trees")
+                    .long("salvage")
+                    .action(ArgAction::SetTrue),
+            )
             // options
+            .arg(
+                Arg::new("ANSWERS_FILE")
+                    .help("Take the answers to the questions of --interactive from a file")
+                    .long("answers-file")
+                    .value_name("FILE")
+                    .conflicts_with("SCAN_ROOTS"),
+            )
+            .arg(
+                Arg::new("BACKUP")
+                    .help("Specify the file the input is packed to before repairing")
+                    .long("backup")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("BACKUP_DIR")
+                    .help("Pack the input to a new file in a directory before repairing")
+                    .long("backup-dir")
+                    .value_name("DIR")
+                    .conflicts_with("BACKUP"),
+            )
+            .arg(
+                Arg::new("DATA_DEV")
+                    .help("Specify the data device to infer the block size from")
+                    .long("data-dev")
+                    .value_name("FILE"),
+            )
             .arg(
                 Arg::new("DATA_BLOCK_SIZE")
                     .help("Provide the data block size for repairing")
@@ -43,6 +130,13 @@ impl ThinRepairCommand {
                     .value_name("FILE")
                     .required(true),
             )
+            .arg(
+                Arg::new("JOURNAL")
+                    .help("Specify the journal file of an in-place repair")
+                    .long("journal")
+                    .value_name("FILE")
+                    .requires("IN_PLACE"),
+            )
             .arg(
                 Arg::new("NR_DATA_BLOCKS")
                     .help("Override the number of data blocks if needed")
@@ -56,7 +150,56 @@ impl ThinRepairCommand {
                     .short('o')
                     .long("output")
                     .value_name("FILE")
-                    .required(true),
+                    .required_unless_present_any(["SCAN_ROOTS", "IN_PLACE"]),
+            )
+            .arg(
+                Arg::new("OUTPUT_FORMAT")
+                    .help("Write the repaired metadata as binary metadata or an xml dump")
+                    .long("output-format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["metadata", "xml"])
+                            .map(|s| s.parse::<RepairFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("metadata")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("RECONCILE")
+                    .help("Fill in the lost mappings from an earlier xml or pack backup")
+                    .long("reconcile")
+                    .value_name("BACKUP_FILE")
+                    .conflicts_with("SCAN_ROOTS"),
+            )
+            .arg(
+                Arg::new("SM_REPORT")
+                    .help("Write a summary of the space map adjustments to a file")
+                    .long("sm-report")
+                    .value_name("FILE")
+                    .conflicts_with("SCAN_ROOTS"),
+            )
+            .arg(
+                Arg::new("SM_REPORT_FORMAT")
+                    .help("Write the summary as text or json")
+                    .long("sm-report-format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["text", "json"])
+                            .map(|s| s.parse::<SmReportFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("text")
+                    .hide_default_value(true)
+                    .requires("SM_REPORT"),
+            )
+            .arg(
+                Arg::new("USE_ROOTS")
+                    .help("Repair from the candidate roots of the given index")
+                    .long("use-roots")
+                    .value_name("INDEX")
+                    .value_parser(value_parser!(usize))
+                    .conflicts_with("SCAN_ROOTS"),
             )
             .arg(
                 Arg::new("TRANSACTION_ID")
@@ -68,7 +211,7 @@ impl ThinRepairCommand {
             // a dummy argument for compatibility with lvconvert
             .arg(Arg::new("DUMMY").required(false).hide(true).index(1));
 
-        verbose_args(engine_args(version_args(cmd)))
+        progress_fd_args(verbose_args(engine_args(version_args(cmd))))
     }
 }
 
@@ -82,7 +225,7 @@ impl<'a> Command<'a> for ThinRepairCommand {
         display_version(&matches);
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
-        let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+        let format = *matches.get_one::<RepairFormat>("OUTPUT_FORMAT").unwrap();
 
         let report = mk_report(matches.get_flag("QUIET"));
         let log_level = match parse_log_level(&matches) {
@@ -91,10 +234,13 @@ impl<'a> Command<'a> for ThinRepairCommand {
         };
         report.set_level(log_level);
 
-        if let Err(e) = check_input_file(input_file)
-            .and_then(check_file_not_tiny)
-            .and_then(|_| check_output_file(output_file))
-        {
+        match parse_progress_fd(&matches) {
+            Ok(Some(file)) => report.set_progress_fd(file),
+            Ok(None) => {}
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        }
+
+        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
             return to_exit_code::<()>(&report, Err(e));
         }
 
@@ -103,9 +249,80 @@ impl<'a> Command<'a> for ThinRepairCommand {
             return to_exit_code(&report, engine_opts);
         }
 
+        if matches.get_flag("SCAN_ROOTS") {
+            let opts = ThinScanRootsOptions {
+                input: input_file,
+                engine_opts: engine_opts.unwrap(),
+                report: report.clone(),
+            };
+            return to_exit_code(&report, scan(opts));
+        }
+
+        let journal = if matches.get_flag("IN_PLACE") {
+            Some(
+                matches
+                    .get_one::<String>("JOURNAL")
+                    .map_or_else(|| default_journal_path(input_file), PathBuf::from),
+            )
+        } else {
+            None
+        };
+
+        // An xml dump is written to a new file, rather than an existing device
+        let output_file = match matches.get_one::<String>("OUTPUT") {
+            Some(output) => Path::new(output),
+            None => input_file,
+        };
+        if let Err(e) = match format {
+            RepairFormat::Metadata => check_output_file(output_file),
+            RepairFormat::XML => Ok(output_file),
+        } {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let data_dev_sectors = match matches.get_one::<String>("DATA_DEV") {
+            Some(data_dev) => match get_data_dev_sectors(Path::new(data_dev)) {
+                Ok(sectors) => Some(sectors),
+                Err(e) => return to_exit_code::<()>(&report, Err(e)),
+            },
+            None => None,
+        };
+
+        let reconcile = matches.get_one::<String>("RECONCILE").map(Path::new);
+        if let Some(Err(e)) = reconcile.map(check_input_file) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let answers = if matches.get_flag("INTERACTIVE") {
+            if !atty::is(atty::Stream::Stdin) {
+                return to_exit_code::<()>(
+                    &report,
+                    Err(anyhow::anyhow!("--interactive needs a terminal to ask on")),
+                );
+            }
+            RepairAnswers::Interactive
+        } else if let Some(path) = matches.get_one::<String>("ANSWERS_FILE") {
+            match RepairAnswers::from_file(Path::new(path)) {
+                Ok(answers) => answers,
+                Err(e) => return to_exit_code::<()>(&report, Err(e)),
+            }
+        } else {
+            RepairAnswers::Automatic
+        };
+
+        let backup = match (
+            matches.get_one::<String>("BACKUP"),
+            matches.get_one::<String>("BACKUP_DIR"),
+        ) {
+            (Some(path), _) => Some(PathBuf::from(path)),
+            (None, Some(dir)) => Some(backup_path_in(Path::new(dir), input_file)),
+            (None, None) => None,
+        };
+
         let opts = ThinRepairOptions {
             input: input_file,
             output: output_file,
+            format,
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
             overrides: SuperblockOverrides {
@@ -113,6 +330,22 @@ impl<'a> Command<'a> for ThinRepairCommand {
                 data_block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
                 nr_data_blocks: matches.get_one::<u64>("NR_DATA_BLOCKS").cloned(),
             },
+            inference: SuperblockInference {
+                apply: matches.get_flag("INFER"),
+                data_dev_sectors,
+                answers,
+            },
+            salvage: matches.get_flag("SALVAGE"),
+            reconcile,
+            backup,
+            roots: matches.get_one::<usize>("USE_ROOTS").cloned(),
+            journal,
+            sm_report: matches.get_one::<String>("SM_REPORT").map(Path::new),
+            sm_report_format: *matches
+                .get_one::<SmReportFormat>("SM_REPORT_FORMAT")
+                .unwrap(),
+            ignore_space_estimate: matches.get_flag("IGNORE_SPACE_ESTIMATE"),
+            deterministic: matches.get_flag("DETERMINISTIC"),
         };
 
         to_exit_code(&report, repair(opts))
@@ -1,5 +1,6 @@
 extern crate clap;
 
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
@@ -8,7 +9,7 @@ use crate::commands::utils::*;
 use crate::commands::Command;
 use crate::report::{parse_log_level, verbose_args};
 use crate::thin::metadata_repair::SuperblockOverrides;
-use crate::thin::restore::{restore, ThinRestoreOptions};
+use crate::thin::restore::{restore, InputFormat, SummaryFormat, ThinRestoreOptions};
 use crate::version::*;
 
 pub struct ThinRestoreCommand;
@@ -35,6 +36,20 @@ impl ThinRestoreCommand {
                     .value_name("SECTORS")
                     .value_parser(value_parser!(u32)),
             )
+            .arg(
+                Arg::new("DETERMINISTIC")
+                    .help(
+                        "Lay the metadata out the same way on any machine, for reproducible output",
+                    )
+                    .long("deterministic")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("ID_MAP")
+                    .help("Restore the original device ids recorded by thin_dump --id-map")
+                    .long("id-map")
+                    .value_name("FILE"),
+            )
             .arg(
                 Arg::new("INPUT")
                     .help("Specify the input xml")
@@ -43,6 +58,19 @@ impl ThinRestoreCommand {
                     .value_name("FILE")
                     .required(true),
             )
+            .arg(
+                Arg::new("INPUT_FORMAT")
+                    .help("Choose the input format, xml or extents")
+                    .long("input-format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["xml", "extents"])
+                            .map(|s| s.parse::<InputFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("xml")
+                    .hide_default_value(true),
+            )
             .arg(
                 Arg::new("NR_DATA_BLOCKS")
                     .help("Override the number of data blocks if needed")
@@ -58,6 +86,19 @@ impl ThinRestoreCommand {
                     .value_name("FILE")
                     .required(true),
             )
+            .arg(
+                Arg::new("SUMMARY_FORMAT")
+                    .help("Print the summary of what was restored as text or json")
+                    .long("summary-format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["text", "json"])
+                            .map(|s| s.parse::<SummaryFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("text")
+                    .hide_default_value(true),
+            )
             .arg(
                 Arg::new("TRANSACTION_ID")
                     .help("Override the transaction id if needed")
@@ -99,6 +140,7 @@ impl<'a> Command<'a> for ThinRestoreCommand {
 
         let opts = ThinRestoreOptions {
             input: input_file,
+            input_format: *matches.get_one::<InputFormat>("INPUT_FORMAT").unwrap(),
             output: output_file,
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
@@ -107,6 +149,9 @@ impl<'a> Command<'a> for ThinRestoreCommand {
                 data_block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
                 nr_data_blocks: matches.get_one::<u64>("NR_DATA_BLOCKS").cloned(),
             },
+            id_map: matches.get_one::<String>("ID_MAP").map(Path::new),
+            deterministic: matches.get_flag("DETERMINISTIC"),
+            summary_format: *matches.get_one::<SummaryFormat>("SUMMARY_FORMAT").unwrap(),
         };
 
         to_exit_code(&report, restore(opts))
@@ -1,6 +1,10 @@
 extern crate clap;
 
+use anyhow::Context;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::ops::Range;
 use std::path::Path;
 
@@ -8,10 +12,77 @@ use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
 use crate::thin::rmap::*;
+use crate::thin::rmap_index::*;
 use crate::version::*;
 
 //------------------------------------------
 
+fn open_list(path: &Path, what: &str) -> anyhow::Result<Box<dyn BufRead>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(BufReader::new(std::io::stdin())));
+    }
+    let file = File::open(path)
+        .with_context(|| format!("couldn't open the {} '{}'", what, path.display()))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+// Calls the function with each line that isn't blank or a '#' comment,
+// and its line number
+fn for_each_line<F>(input: Box<dyn BufRead>, mut f: F) -> anyhow::Result<()>
+where
+    F: FnMut(&str, usize) -> anyhow::Result<()>,
+{
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        f(line, i + 1)?;
+    }
+    Ok(())
+}
+
+// Reads a range per line, in the format of --region.  Blank lines and
+// lines starting with '#' are skipped.
+fn read_regions(path: &Path) -> anyhow::Result<Vec<Range<u64>>> {
+    let mut regions = Vec::new();
+    for_each_line(open_list(path, "regions file")?, |line, nr| {
+        let r = line
+            .parse::<RangeU64>()
+            .with_context(|| format!("bad region '{}' on line {}", line, nr))?;
+        regions.push(r.start..r.end);
+        Ok(())
+    })?;
+    Ok(regions)
+}
+
+// Reads a sector per line, as badblocks and smartctl list them, and joins
+// adjacent sectors into ranges.
+fn read_sector_list(path: &Path) -> anyhow::Result<Vec<Range<u64>>> {
+    let mut sectors = Vec::new();
+    for_each_line(open_list(path, "sector list")?, |line, nr| {
+        let s = line
+            .parse::<u64>()
+            .with_context(|| format!("bad sector '{}' on line {}", line, nr))?;
+        sectors.push(s);
+        Ok(())
+    })?;
+    sectors.sort_unstable();
+    sectors.dedup();
+
+    let mut regions: Vec<Range<u64>> = Vec::new();
+    for s in sectors {
+        match regions.last_mut() {
+            Some(r) if r.end == s => r.end += 1,
+            _ => regions.push(s..s + 1),
+        }
+    }
+    Ok(regions)
+}
+
+//------------------------------------------
+
 pub struct ThinRmapCommand;
 
 impl ThinRmapCommand {
@@ -22,6 +93,25 @@ impl ThinRmapCommand {
             .disable_version_flag(true)
             .about("Output reverse map of a thin provisioned region of blocks")
             // options
+            .arg(
+                Arg::new("BUILD_INDEX")
+                    .help("Write an index of the reverse map of every data block, for queries with --index")
+                    .long("build-index")
+                    .value_name("FILE")
+                    .conflicts_with_all(["REGION", "REGIONS_FILE", "SECTOR_LIST", "INDEX"]),
+            )
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the output format")
+                    .short('f')
+                    .long("format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["text", "json"])
+                            .map(|s| s.parse::<RmapFormat>().unwrap()),
+                    )
+                    .default_value("text"),
+            )
             .arg(
                 // FIXME: clap doesn't support placing the index argument
                 //        after the multiple arguments, e.g.,
@@ -30,10 +120,40 @@ impl ThinRmapCommand {
                     .help("Specify range of blocks on the data device")
                     .long("region")
                     .action(clap::ArgAction::Append)
-                    .required(true)
+                    .required_unless_present_any(["REGIONS_FILE", "SECTOR_LIST", "BUILD_INDEX"])
                     .value_name("BLOCK_RANGE")
                     .value_parser(value_parser!(RangeU64)),
             )
+            .arg(
+                Arg::new("INDEX")
+                    .help("Answer from an index built with --build-index, rather than the mappings")
+                    .long("index")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("REGIONS_FILE")
+                    .help("Read the ranges of blocks from a file, one per line, or '-' for stdin")
+                    .long("regions-file")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("SECTOR_LIST")
+                    .help("Read a list of bad 512 byte sectors, one per line, or '-' for stdin")
+                    .long("sector-list")
+                    .value_name("FILE")
+                    .conflicts_with_all(["REGION", "REGIONS_FILE", "UNITS"]),
+            )
+            .arg(
+                Arg::new("UNITS")
+                    .help("Give the regions in data blocks, 512 byte sectors or bytes")
+                    .long("units")
+                    .value_name("UNITS")
+                    .value_parser(
+                        PossibleValuesParser::new(["blocks", "sectors", "bytes"])
+                            .map(|s| s.parse::<RegionUnits>().unwrap()),
+                    )
+                    .default_value("blocks"),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
@@ -61,25 +181,55 @@ impl<'a> Command<'a> for ThinRmapCommand {
             return to_exit_code::<()>(&report, Err(e));
         }
 
-        // FIXME: get rid of the intermediate RangeU64 struct
-        let regions: Vec<Range<u64>> = matches
-            .get_many::<RangeU64>("REGION")
-            .unwrap()
-            .map(|v| Range::<u64> {
-                start: v.start,
-                end: v.end,
-            })
-            .collect();
-
         let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
         if engine_opts.is_err() {
             return to_exit_code(&report, engine_opts);
         }
 
+        if let Some(output) = matches.get_one::<String>("BUILD_INDEX") {
+            let opts = RmapIndexOptions {
+                input: input_file,
+                engine_opts: engine_opts.unwrap(),
+                output: Path::new(output),
+            };
+            return to_exit_code(&report, build_rmap_index(opts));
+        }
+
+        // FIXME: get rid of the intermediate RangeU64 struct
+        let mut regions: Vec<Range<u64>> =
+            matches
+                .get_many::<RangeU64>("REGION")
+                .map_or_else(Vec::new, |r| {
+                    r.map(|v| Range::<u64> {
+                        start: v.start,
+                        end: v.end,
+                    })
+                    .collect()
+                });
+
+        if let Some(path) = matches.get_one::<String>("REGIONS_FILE") {
+            match read_regions(Path::new(path)) {
+                Ok(r) => regions.extend(r),
+                Err(e) => return to_exit_code::<()>(&report, Err(e)),
+            }
+        }
+
+        let mut units = *matches.get_one::<RegionUnits>("UNITS").unwrap();
+        if let Some(path) = matches.get_one::<String>("SECTOR_LIST") {
+            match read_sector_list(Path::new(path)) {
+                Ok(r) => regions = r,
+                Err(e) => return to_exit_code::<()>(&report, Err(e)),
+            }
+            units = RegionUnits::Sectors;
+        }
+
         let opts = ThinRmapOptions {
             input: input_file,
             engine_opts: engine_opts.unwrap(),
             regions,
+            units,
+            index: matches.get_one::<String>("INDEX").map(Path::new),
+            format: *matches.get_one::<RmapFormat>("FORMAT").unwrap(),
             report: report.clone(),
         };
 
@@ -61,6 +61,14 @@ impl ThinShrinkCommand {
                     .value_name("NUM")
                     .value_parser(value_parser!(u64)),
             )
+            .arg(
+                Arg::new("METADATA_NR_BLOCKS")
+                    .help("Specify new size for the metadata (in 4k blocks)")
+                    .long("metadata-nr-blocks")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64))
+                    .requires("BINARY"),
+            )
             .arg(
                 Arg::new("BINARY")
                     .help("Perform binary metadata rebuild rather than XML rewrite")
@@ -82,6 +90,7 @@ impl ThinShrinkCommand {
         let input = Path::new(matches.get_one::<String>("INPUT").unwrap());
         let output = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
         let nr_blocks = *matches.get_one::<u64>("NR_BLOCKS").unwrap();
+        let nr_metadata_blocks = matches.get_one::<u64>("METADATA_NR_BLOCKS").cloned();
         let data_device = Path::new(matches.get_one::<String>("DATA").unwrap());
         let do_copy = !matches.get_flag("NOCOPY");
         let binary_mode = matches.get_flag("BINARY");
@@ -91,6 +100,7 @@ impl ThinShrinkCommand {
             input: input.to_path_buf(),
             output: output.to_path_buf(),
             nr_blocks,
+            nr_metadata_blocks,
             data_device: data_device.to_path_buf(),
             do_copy,
             binary_mode,
@@ -0,0 +1,250 @@
+extern crate clap;
+
+use anyhow::Result;
+use clap::{value_parser, Arg, ArgAction};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use tui::{
+    backend::TermionBackend,
+    layout::Constraint,
+    style::{Color, Style},
+    widgets::{Block, Borders, Row, Table},
+    Terminal,
+};
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::commands::Command;
+use crate::report::mk_simple_report;
+use crate::thin::top::*;
+use crate::version::*;
+
+//------------------------------------------
+
+const COLUMNS: [&str; 6] = [
+    "DEV",
+    "MAPPED",
+    "EXCLUSIVE",
+    "SHARED",
+    "ALLOC/s",
+    "UNSHARE/s",
+];
+
+fn mk_row(a: &DeviceActivity) -> Vec<String> {
+    vec![
+        a.dev_id.to_string(),
+        a.counts.mapped_blocks.to_string(),
+        a.counts.exclusive_blocks.to_string(),
+        a.counts.shared_blocks.to_string(),
+        format!("{:.1}", a.alloc_rate),
+        format!("{:.1}", a.unshare_rate),
+    ]
+}
+
+fn mk_title(sample: &Sample, interval: Duration) -> String {
+    format!(
+        "transaction {}, {} sector blocks, every {}s",
+        sample.transaction_id,
+        sample.data_block_size,
+        interval.as_secs()
+    )
+}
+
+//------------------------------------------
+
+// Prints a table of the devices after each interval, for logging
+fn run_batch(opts: &TopOptions, interval: Duration, count: Option<u64>) -> Result<()> {
+    let mut prev = take_sample(opts)?;
+    let mut nr_reports = 0;
+    while count.map_or(true, |c| nr_reports < c) {
+        thread::sleep(interval.saturating_sub(prev.taken.elapsed()));
+        let cur = take_sample(opts)?;
+
+        let mut out = io::stdout().lock();
+        writeln!(out, "{}", mk_title(&cur, interval))?;
+        writeln!(
+            out,
+            "{:>8} {:>12} {:>12} {:>12} {:>10} {:>10}",
+            COLUMNS[0], COLUMNS[1], COLUMNS[2], COLUMNS[3], COLUMNS[4], COLUMNS[5]
+        )?;
+        for a in activity(&prev, &cur) {
+            let row = mk_row(&a);
+            writeln!(
+                out,
+                "{:>8} {:>12} {:>12} {:>12} {:>10} {:>10}",
+                row[0], row[1], row[2], row[3], row[4], row[5]
+            )?;
+        }
+        writeln!(out)?;
+        out.flush()?;
+
+        prev = cur;
+        nr_reports += 1;
+    }
+    Ok(())
+}
+
+fn spawn_input() -> mpsc::Receiver<Key> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for key in io::stdin().keys().flatten() {
+            if tx.send(key).is_err() || key == Key::Char('q') {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+fn run_tui(opts: &TopOptions, interval: Duration) -> Result<()> {
+    let mut prev = take_sample(opts)?;
+    // idle until there's a second sample to compare with
+    let mut devs = activity(&prev, &prev);
+    let mut title = mk_title(&prev, interval);
+
+    let keys = spawn_input();
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock().into_raw_mode()?;
+    write!(stdout, "{}", termion::clear::All)?;
+    let backend = TermionBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    loop {
+        terminal.draw(|f| {
+            let rows: Vec<Row> = devs.iter().map(|a| Row::new(mk_row(a))).collect();
+            let table = Table::new(rows)
+                .header(Row::new(COLUMNS.to_vec()).style(Style::default().fg(Color::Yellow)))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!("{} (q to quit)", title)),
+                )
+                .widths(&[
+                    Constraint::Length(8),
+                    Constraint::Length(12),
+                    Constraint::Length(12),
+                    Constraint::Length(12),
+                    Constraint::Length(10),
+                    Constraint::Length(10),
+                ]);
+            f.render_widget(table, f.size());
+        })?;
+
+        let deadline = prev.taken + interval;
+        match keys.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Key::Char('q')) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(_) => continue,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+
+        let cur = take_sample(opts)?;
+        devs = activity(&prev, &cur);
+        title = mk_title(&cur, interval);
+        prev = cur;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
+
+pub struct ThinTopCommand;
+
+impl ThinTopCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Show the thin devices allocating or breaking sharing the fastest")
+            // flags
+            .arg(
+                Arg::new("BATCH")
+                    .help("Print a table after each interval rather than refresh the screen")
+                    .long("batch")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            //
+            // There's no --metadata-snap, as a snapshot reserved beforehand
+            // wouldn't change between samples, and every rate would be zero
+            .arg(
+                Arg::new("AUTO_SNAP")
+                    .help("Reserve a metadata snapshot of a live pool for each sample")
+                    .long("auto-snap")
+                    .value_name("POOL"),
+            )
+            .arg(
+                Arg::new("COUNT")
+                    .help("Stop after printing this many tables")
+                    .long("count")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64).range(1..))
+                    .requires("BATCH"),
+            )
+            .arg(
+                Arg::new("INTERVAL")
+                    .help("Specify the seconds between samples")
+                    .long("interval")
+                    .value_name("SECS")
+                    .value_parser(value_parser!(u64).range(1..))
+                    .default_value("5"),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input device")
+                    .required(true)
+                    .index(1),
+            );
+
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for ThinTopCommand {
+    fn name(&self) -> &'a str {
+        "thin_top"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+        let report = mk_simple_report();
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        let opts = TopOptions {
+            input: input_file,
+            engine_opts: engine_opts.unwrap(),
+            auto_snap: matches.get_one::<String>("AUTO_SNAP").map(|s| s.as_str()),
+        };
+        let interval = Duration::from_secs(*matches.get_one::<u64>("INTERVAL").unwrap());
+
+        let result = if matches.get_flag("BATCH") {
+            run_batch(&opts, interval, matches.get_one::<u64>("COUNT").cloned())
+        } else {
+            run_tui(&opts, interval)
+        };
+        to_exit_code(&report, result)
+    }
+}
+
+//------------------------------------------
@@ -1,13 +1,15 @@
 extern crate clap;
 
-use clap::{Arg, ArgAction};
+use anyhow::anyhow;
+use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
 use crate::commands::engine::*;
 use crate::commands::utils::*;
-use crate::report::{parse_log_level, verbose_args};
+use crate::report::{parse_log_level, parse_progress_fd, progress_fd_args, verbose_args};
 use crate::thin::check::{check, ThinCheckOptions};
-use crate::thin::trim::{trim, ThinTrimOptions};
+use crate::thin::metadata_repair::SuperblockOverrides;
+use crate::thin::trim::{trim, DiscardGranularity, ThinTrimOptions};
 use crate::version::*;
 
 //------------------------------------------
@@ -29,7 +31,48 @@ impl ThinTrimCommand {
                     .long("quiet")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("ALIGN_DISCARDS")
+                    .help("Round discards to the discard granularity of the data device")
+                    .long("align-discards")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("DISCARD_GRANULARITY"),
+            )
+            .arg(
+                Arg::new("DRY_RUN")
+                    .help("Print the free space that would be discarded, without discarding it")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["MAX_DISCARD_RATE", "JOURNAL"]),
+            )
             // options
+            .arg(
+                Arg::new("DISCARD_GRANULARITY")
+                    .help("Round discards to whole granules of this many bytes")
+                    .long("discard-granularity")
+                    .value_name("BYTES")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
+            .arg(
+                Arg::new("JOURNAL")
+                    .help("Record the progress in a file, and resume an interrupted trim from it")
+                    .long("journal")
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("RANGE")
+                    .help("Only trim the free data blocks within a range")
+                    .long("range")
+                    .value_name("BLOCK_RANGE")
+                    .value_parser(value_parser!(RangeU64)),
+            )
+            .arg(
+                Arg::new("MAX_DISCARD_RATE")
+                    .help("Limit the rate discards are issued at, in MiB/s")
+                    .long("max-discard-rate")
+                    .value_name("MiB/s")
+                    .value_parser(value_parser!(u64).range(1..)),
+            )
             .arg(
                 Arg::new("METADATA_DEV")
                     .help("Specify the pool metadata device")
@@ -44,7 +87,7 @@ impl ThinTrimCommand {
                     .value_name("FILE")
                     .required(true),
             );
-        verbose_args(engine_args(version_args(cmd)))
+        progress_fd_args(verbose_args(engine_args(version_args(cmd))))
     }
 }
 
@@ -67,6 +110,12 @@ impl<'a> Command<'a> for ThinTrimCommand {
         };
         report.set_level(log_level);
 
+        match parse_progress_fd(&matches) {
+            Ok(Some(file)) => report.set_progress_fd(file),
+            Ok(None) => {}
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        }
+
         if let Err(e) = check_input_file(metadata_dev)
             .and_then(check_file_not_tiny)
             .and_then(|_| check_input_file(data_dev))
@@ -89,6 +138,19 @@ impl<'a> Command<'a> for ThinTrimCommand {
             clear_needs_check: false,
             override_mapping_root: None,
             override_details_root: None,
+            memory_limit: None,
+            nr_threads: None,
+            data_dev: None,
+            verify_data_bounds: false,
+            data_samples: None,
+            error_budget: None,
+            mapping_sample: None,
+            metrics_file: None,
+            metrics_device: None,
+            history: None,
+            ref_count_histogram: false,
+            fix_checksums: false,
+            overrides: SuperblockOverrides::default(),
             report: report.clone(),
         };
 
@@ -100,10 +162,34 @@ impl<'a> Command<'a> for ThinTrimCommand {
             return exitcode::DATAERR;
         }
 
+        let discard_granularity = match matches.get_one::<u64>("DISCARD_GRANULARITY") {
+            Some(bytes) => Some(DiscardGranularity::Bytes(*bytes)),
+            None if matches.get_flag("ALIGN_DISCARDS") => Some(DiscardGranularity::Device),
+            None => None,
+        };
+
+        let max_discard_rate = match matches.get_one::<u64>("MAX_DISCARD_RATE") {
+            Some(mib) => match mib.checked_mul(1 << 20) {
+                Some(rate) => Some(rate),
+                None => {
+                    return to_exit_code::<()>(
+                        &report,
+                        Err(anyhow!("--max-discard-rate of {} MiB/s is too large", mib)),
+                    )
+                }
+            },
+            None => None,
+        };
+
         let opts = ThinTrimOptions {
             metadata_dev,
             data_dev,
             engine_opts,
+            max_discard_rate,
+            discard_granularity,
+            range: matches.get_one::<RangeU64>("RANGE").map(|r| r.start..r.end),
+            journal: matches.get_one::<String>("JOURNAL").map(Path::new),
+            dry_run: matches.get_flag("DRY_RUN"),
             report: report.clone(),
         };
 
@@ -101,6 +101,14 @@ pub fn mk_report(quiet: bool) -> std::sync::Arc<Report> {
     }
 }
 
+// Tools that take --log-target may send their messages to syslog instead
+pub fn mk_target_report(quiet: bool, target: LogTarget, ident: &str) -> Arc<Report> {
+    match target {
+        LogTarget::Syslog(facility) => Arc::new(mk_syslog_report(ident, facility, quiet)),
+        LogTarget::Stderr => mk_report(quiet),
+    }
+}
+
 fn is_xml(line: &[u8]) -> bool {
     line.starts_with(b"<superblock") || line.starts_with(b"?xml") || line.starts_with(b"<!DOCTYPE")
 }
@@ -12,6 +12,7 @@ use crate::copier::*;
 use crate::io_engine::buffer::*;
 use crate::io_engine::is_page_aligned;
 use crate::io_engine::utils::*;
+use crate::io_engine::zoned::ZoneTracker;
 
 #[cfg(test)]
 mod tests;
@@ -25,6 +26,7 @@ pub struct SyncCopier<T: ReadBlocks + WriteBlocks + Send> {
     src_offset: u64,
     dst: Arc<Mutex<T>>,
     dst_offset: u64,
+    dst_zones: Option<Arc<Mutex<ZoneTracker>>>,
 }
 
 #[derive(Clone)]
@@ -73,6 +75,7 @@ impl<T: ReadBlocks + WriteBlocks + Send> SyncCopier<T> {
             src_offset: 0,
             dst: Arc::new(Mutex::new(dst)),
             dst_offset: 0,
+            dst_zones: None,
         })
     }
 
@@ -94,6 +97,7 @@ impl<T: ReadBlocks + WriteBlocks + Send> SyncCopier<T> {
             src_offset: 0,
             dst,
             dst_offset: 0,
+            dst_zones: None,
         })
     }
 
@@ -113,6 +117,13 @@ impl<T: ReadBlocks + WriteBlocks + Send> SyncCopier<T> {
         Ok(self)
     }
 
+    // Writes to a zoned destination are issued in ascending order, as
+    // the sequential zones may only be written at their write pointers.
+    pub fn dest_zones(mut self, zones: ZoneTracker) -> SyncCopier<T> {
+        self.dst_zones = Some(Arc::new(Mutex::new(zones)));
+        self
+    }
+
     // Returns a bitset that indicates whether the read succeeded for
     // that op (true == succeeded).
     fn do_reads(
@@ -178,6 +189,7 @@ impl<T: ReadBlocks + WriteBlocks + Send> SyncCopier<T> {
 
     fn do_writes(
         dst: &Arc<Mutex<T>>,
+        zones: &Option<Arc<Mutex<ZoneTracker>>>,
         offset: u64,
         block_size: usize,
         ops: &[CopyOp],
@@ -220,8 +232,22 @@ impl<T: ReadBlocks + WriteBlocks + Send> SyncCopier<T> {
 
             // issue io
             let pos = (op.block_begin * block_size as u64) + offset;
+            let len = iovec.len() as u64 * block_size as u64;
+            let mut tracker = zones.as_ref().map(|z| z.lock().unwrap());
+            if let Some(tracker) = tracker.as_mut() {
+                if tracker.prepare_write(&*dst, pos, len).is_err() {
+                    // error everything
+                    continue;
+                }
+            }
             let results = dst.write_blocks(&iovec[..], pos);
 
+            // the write pointer only moves past the blocks written in order
+            if let (Some(tracker), Ok(results)) = (tracker.as_mut(), &results) {
+                let nr_written = results.iter().take_while(|r| r.is_ok()).count();
+                tracker.complete_write(pos, nr_written as u64 * block_size as u64);
+            }
+
             // check results
             #[allow(clippy::single_match)]
             match results {
@@ -264,8 +290,14 @@ impl<T: ReadBlocks + WriteBlocks + From<File> + Send> SyncCopier<T> {
             .write(true)
             .custom_flags(libc::O_EXCL | libc::O_DIRECT)
             .open(dst)?;
+        let dst_zones = ZoneTracker::from_file(&dst_file)?;
 
-        SyncCopier::<T>::new(buffer_size, block_size, src_file.into(), dst_file.into())
+        let copier =
+            SyncCopier::<T>::new(buffer_size, block_size, src_file.into(), dst_file.into())?;
+        Ok(match dst_zones {
+            Some(zones) => copier.dest_zones(zones),
+            None => copier,
+        })
     }
 }
 
@@ -282,6 +314,7 @@ impl<T: ReadBlocks + WriteBlocks + Send + 'static> Copier for SyncCopier<T> {
         let write_thread = {
             let stats = stats.clone();
             let dst = self.dst.clone();
+            let zones = self.dst_zones.clone();
             let offset = self.dst_offset;
             let block_size = self.block_size;
             thread::spawn(move || loop {
@@ -293,6 +326,7 @@ impl<T: ReadBlocks + WriteBlocks + Send + 'static> Copier for SyncCopier<T> {
                 let (ops, read_success, buffer) = msg.unwrap();
                 let write_success = SyncCopier::do_writes(
                     &dst,
+                    &zones,
                     offset,
                     block_size,
                     &ops,
@@ -315,6 +349,17 @@ impl<T: ReadBlocks + WriteBlocks + Send + 'static> Copier for SyncCopier<T> {
             })
         };
 
+        // Each chunk is written in order, so sorting the ops by dst keeps
+        // the writes to a zoned destination ascending.
+        let mut sorted_ops = Vec::new();
+        let ops = if self.dst_zones.is_some() {
+            sorted_ops.extend_from_slice(ops);
+            sorted_ops.sort_by_key(|op| op.dst);
+            &sorted_ops[..]
+        } else {
+            ops
+        };
+
         // read loop
         let chunk_size = self.buffer_size / self.block_size;
         for chunk in ops.chunks(chunk_size) {
@@ -8,6 +8,7 @@ use std::os::unix::fs::FileExt;
 use crate::copier::test_utils::*;
 use crate::io_engine::base::PAGE_SIZE;
 use crate::io_engine::ramdisk::Ramdisk;
+use crate::io_engine::zoned::Zone;
 use crate::math::div_up;
 use crate::random::Generator;
 
@@ -219,6 +220,34 @@ impl CopierTest {
         let progress = Arc::new(IgnoreProgress {});
         copier.copy(ops, progress)
     }
+
+    // The dst is split into sequential zones of the given number of blocks,
+    // all of them empty.
+    fn copy_zoned(&self, ops: &[CopyOp], blocks_per_zone: u64) -> Result<CopyStats> {
+        let zone_size = blocks_per_zone * self.block_size as u64;
+        let zones = (0..div_up(self.nr_dst_blocks, blocks_per_zone))
+            .map(|i| Zone {
+                start: i * zone_size,
+                len: zone_size,
+                capacity: zone_size,
+                wp: i * zone_size,
+                sequential: true,
+                writable: true,
+            })
+            .collect();
+
+        let mut copier = SyncCopier::<SimpleBlockIo<Ramdisk>>::new(
+            BUFFER_SIZE,
+            self.block_size,
+            self.src.try_clone().unwrap().into(),
+            self.dst.try_clone().unwrap().into(),
+        )
+        .unwrap()
+        .dest_zones(ZoneTracker::new(zones));
+
+        let progress = Arc::new(IgnoreProgress {});
+        copier.copy(ops, progress)
+    }
 }
 
 //------------------------------------------
@@ -431,3 +460,41 @@ fn copy_length_is_not_a_multiple_of_buffer_size_should_success() -> Result<()> {
 }
 
 //------------------------------------------
+
+// the ops are reordered to suit the write pointers
+#[test]
+fn copy_randomly_to_zoned_dst() -> Result<()> {
+    const NR_BLOCKS: u64 = 1024;
+
+    let t = CopierTest::new(BLOCK_SIZE, NR_BLOCKS as u32, NR_BLOCKS as u32);
+    t.stamp_src_dev()?;
+    t.stamp_dst_dev()?;
+
+    let ops = mk_random_ops(0..NR_BLOCKS, 0..NR_BLOCKS, NR_BLOCKS as usize);
+    let stats = t.copy_zoned(&ops, 64)?;
+    assert_eq!(stats.nr_copied, NR_BLOCKS);
+    assert!(stats.write_errors.is_empty());
+
+    t.verify(&ops)
+}
+
+#[test]
+fn holes_in_zoned_dst_are_zeroed() -> Result<()> {
+    const NR_BLOCKS: u64 = 256;
+
+    let t = CopierTest::new(BLOCK_SIZE, NR_BLOCKS as u32, NR_BLOCKS as u32);
+    t.stamp_src_dev()?;
+    t.stamp_dst_dev()?;
+
+    let ops = mk_ops(0..NR_BLOCKS / 2, (0..NR_BLOCKS).step_by(2));
+    let stats = t.copy_zoned(&ops, 64)?;
+    assert_eq!(stats.nr_copied, ops.len() as u64);
+    assert!(stats.write_errors.is_empty());
+
+    let mut buf = vec![0xffu8; BLOCK_SIZE as usize];
+    t.dst.read_exact_at(&mut buf, BLOCK_SIZE as u64)?;
+    assert!(buf.iter().all(|v| *v == 0));
+    Ok(())
+}
+
+//------------------------------------------
@@ -10,7 +10,9 @@ use crate::pdata::array::{self, ArrayBlock, ArrayError};
 use crate::pdata::array_walker::*;
 use crate::pdata::bitset::*;
 use crate::pdata::btree_walker::*;
+use crate::pdata::space_map::common::SMRoot;
 use crate::pdata::space_map::*;
+use crate::pdata::unpack::unpack;
 use crate::report::*;
 
 //------------------------------------------
@@ -73,6 +75,7 @@ struct Context {
 
 fn mk_context(opts: &EraCheckOptions) -> anyhow::Result<Context> {
     let engine = EngineBuilder::new(opts.dev, &opts.engine_opts)
+        .read_only(true)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
 
@@ -93,7 +96,6 @@ pub fn check(opts: &EraCheckOptions) -> Result<()> {
     let ctx = mk_context(opts)?;
     let engine = &ctx.engine;
     let report = &ctx.report;
-    let mut fatal = false;
 
     report.set_title("Checking era metadata");
 
@@ -107,11 +109,37 @@ pub fn check(opts: &EraCheckOptions) -> Result<()> {
         return Ok(());
     }
 
+    // Progress is measured by the metadata blocks visited so far
+    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let mon_sm = metadata_sm.clone();
+    let monitor = ProgressMonitor::new(report.clone(), metadata_root.nr_allocated, move || {
+        mon_sm.lock().unwrap().get_nr_allocated().unwrap()
+    });
+    let r = check_structures(&ctx, &sb, &metadata_sm, opts.ignore_non_fatal);
+    monitor.stop();
+
+    if r? {
+        Err(anyhow!("fatal errors in metadata"))
+    } else {
+        Ok(())
+    }
+}
+
+// Returns true if fatal errors were found
+fn check_structures(
+    ctx: &Context,
+    sb: &Superblock,
+    metadata_sm: &ASpaceMap,
+    ignore_non_fatal: bool,
+) -> Result<bool> {
+    let engine = &ctx.engine;
+    let mut fatal = false;
+
     let mut path = vec![0];
     let writesets = btree_to_map::<Writeset>(
         &mut path,
         engine.clone(),
-        opts.ignore_non_fatal,
+        ignore_non_fatal,
         sb.writeset_tree_root,
     )?;
 
@@ -121,7 +149,7 @@ pub fn check(opts: &EraCheckOptions) -> Result<()> {
             ws.root,
             ws.nr_bits as usize,
             metadata_sm.clone(),
-            opts.ignore_non_fatal,
+            ignore_non_fatal,
         )?;
         if err.is_some() {
             ctx.report.fatal(&format!("{}", err.unwrap()));
@@ -129,16 +157,12 @@ pub fn check(opts: &EraCheckOptions) -> Result<()> {
         }
     }
 
-    let w = ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), opts.ignore_non_fatal)?;
+    let w = ArrayWalker::new_with_sm(engine.clone(), metadata_sm.clone(), ignore_non_fatal)?;
     let c = EraChecker::new(sb.current_era);
     if let Err(e) = w.walk(&c, sb.era_array_root) {
         ctx.report.fatal(&format!("{}", e));
         fatal = true;
     }
 
-    if fatal {
-        Err(anyhow!("fatal errors in metadata"))
-    } else {
-        Ok(())
-    }
+    Ok(fatal)
 }
@@ -208,6 +208,7 @@ pub struct EraDumpOptions<'a> {
     pub engine_opts: EngineOptions,
     pub logical: bool,
     pub repair: bool,
+    pub stats: bool,
 }
 
 struct EraDumpContext {
@@ -216,6 +217,7 @@ struct EraDumpContext {
 
 fn mk_context(opts: &EraDumpOptions) -> anyhow::Result<EraDumpContext> {
     let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
     Ok(EraDumpContext { engine })
@@ -316,7 +318,7 @@ pub fn dump_metadata(
     let out: &mut dyn MetadataVisitor = &mut OutputVisitor::new(out);
 
     let xml_sb = ir::Superblock {
-        uuid: "".to_string(),
+        uuid: format_uuid(&sb.uuid),
         block_size: sb.data_block_size,
         nr_blocks: sb.nr_blocks,
         current_era: sb.current_era,
@@ -340,7 +342,8 @@ pub fn dump_metadata(
 
 //-----------------------------------------
 
-fn get_writesets_ordered(
+/// Reads the writesets, including the current one, in order of era.
+pub fn get_writesets_ordered(
     engine: Arc<dyn IoEngine + Send + Sync>,
     sb: &Superblock,
     repair: bool,
@@ -433,7 +436,7 @@ pub fn dump_metadata_logical(
     let out: &mut dyn MetadataVisitor = &mut OutputVisitor::new(out);
 
     let xml_sb = ir::Superblock {
-        uuid: "".to_string(),
+        uuid: format_uuid(&sb.uuid),
         block_size: sb.data_block_size,
         nr_blocks: sb.nr_blocks,
         current_era: sb.current_era,
@@ -452,16 +455,57 @@ pub fn dump_metadata_logical(
 
 //-----------------------------------------
 
+// Writes the number of blocks marked in each writeset, rather than the
+// metadata, for a quick look at a device.  Only the writesets are read,
+// the era array is skipped.
+fn dump_stats(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    w: &mut dyn Write,
+    sb: &Superblock,
+    repair: bool,
+) -> Result<()> {
+    let writesets = get_writesets_ordered(engine.clone(), sb, repair)?;
+
+    let mut marked = Vec::with_capacity(writesets.len());
+    for (era, ws) in &writesets {
+        let bits = read_bitset(engine.clone(), ws.root, ws.nr_bits as usize, repair)?;
+        marked.push((*era, bits.count_ones(..)));
+    }
+
+    // the current writeset is held in the superblock, and read last
+    let has_current = sb.current_writeset.root != 0;
+    let current = if has_current {
+        marked.last().map_or(0, |(_, n)| *n)
+    } else {
+        0
+    };
+    let nr_archived = writesets.len() - has_current as usize;
+
+    writeln!(w, "current_era: {}", sb.current_era)?;
+    writeln!(w, "nr_blocks: {}", sb.nr_blocks)?;
+    writeln!(w, "current_writeset_marked_blocks: {}", current)?;
+    writeln!(w, "nr_archived_writesets: {}", nr_archived)?;
+    for (era, n) in marked {
+        writeln!(w, "era_{}_marked_blocks: {}", era, n)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
 pub fn dump(opts: EraDumpOptions) -> anyhow::Result<()> {
     let ctx = mk_context(&opts)?;
     let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
 
-    let writer: Box<dyn Write> = if opts.output.is_some() {
+    let mut writer: Box<dyn Write> = if opts.output.is_some() {
         let f = File::create(opts.output.unwrap()).context(OutputError)?;
         Box::new(BufWriter::new(f))
     } else {
         Box::new(BufWriter::new(std::io::stdout()))
     };
+    if opts.stats {
+        return dump_stats(ctx.engine, &mut writer, &sb, opts.repair);
+    }
+
     let mut out = xml::XmlWriter::new(writer, false);
 
     let writesets = get_writesets_ordered(ctx.engine.clone(), &sb, opts.repair)?;
@@ -1,9 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use quick_xml::events::{BytesEnd, BytesStart, Event};
 use quick_xml::Writer;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 use crate::commands::engine::*;
@@ -176,12 +177,14 @@ fn emit_range<W: Write>(w: &mut Writer<W>, begin: u32, end: u32) -> Result<()> {
     Ok(())
 }
 
-fn emit_blocks<W: Write>(marked_bits: &[u64], nr_blocks: u32, w: &mut Writer<W>) -> Result<()> {
+// Calls the function with each run of marked blocks, in order
+fn for_each_range<F>(marked_bits: &[u64], nr_blocks: u32, mut f: F) -> Result<()>
+where
+    F: FnMut(u32, u32) -> Result<()>,
+{
     let mut begin: u32 = 0;
     let mut end: u32 = 0;
 
-    emit_start(w)?;
-
     for (index, entry) in marked_bits.iter().enumerate() {
         let mut n = *entry;
 
@@ -194,7 +197,7 @@ fn emit_blocks<W: Write>(marked_bits: &[u64], nr_blocks: u32, w: &mut Writer<W>)
             let zeros = n.trailing_zeros();
             if zeros > 0 {
                 if end > begin {
-                    emit_range(w, begin, end)?;
+                    f(begin, end)?;
                 }
                 n >>= zeros;
                 end += zeros;
@@ -209,7 +212,7 @@ fn emit_blocks<W: Write>(marked_bits: &[u64], nr_blocks: u32, w: &mut Writer<W>)
         let endpos = (index << 6) as u32 + 64;
         if end < endpos {
             if end > begin {
-                emit_range(w, begin, end)?;
+                f(begin, end)?;
             }
             begin = endpos;
             end = begin;
@@ -217,36 +220,126 @@ fn emit_blocks<W: Write>(marked_bits: &[u64], nr_blocks: u32, w: &mut Writer<W>)
     }
 
     if end > begin {
-        emit_range(w, begin, end)?;
+        f(begin, end)?;
     }
 
+    Ok(())
+}
+
+fn emit_blocks<W: Write>(marked_bits: &[u64], nr_blocks: u32, w: &mut Writer<W>) -> Result<()> {
+    emit_start(w)?;
+    for_each_range(marked_bits, nr_blocks, |begin, end| {
+        emit_range(w, begin, end)
+    })?;
     emit_end(w)?;
+    Ok(())
+}
 
+// Writes the regions of a dm-clone target covering the marked blocks, a
+// line of "<first region> <nr regions>" for each run.  The blocks are
+// rounded out to whole regions, so runs that meet once rounded are joined.
+fn emit_clone_regions<W: Write>(
+    marked_bits: &[u64],
+    nr_blocks: u32,
+    block_size: u64,  // sectors
+    region_size: u64, // sectors
+    w: &mut W,
+) -> Result<()> {
+    let mut pending: Option<(u64, u64)> = None;
+    for_each_range(marked_bits, nr_blocks, |begin, end| {
+        let r_begin = begin as u64 * block_size / region_size;
+        let r_end = div_up(end as u64 * block_size, region_size);
+        pending = match pending {
+            Some((b, e)) if r_begin <= e => Some((b, e.max(r_end))),
+            Some((b, e)) => {
+                writeln!(w, "{} {}", b, e - b)?;
+                Some((r_begin, r_end))
+            }
+            None => Some((r_begin, r_end)),
+        };
+        Ok(())
+    })?;
+    if let Some((b, e)) = pending {
+        writeln!(w, "{} {}", b, e - b)?;
+    }
+    w.flush()?;
     Ok(())
 }
 
 //------------------------------------------
 
+/// How the blocks marked in several inputs are combined
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CombineOp {
+    /// Blocks changed on any of the inputs
+    Union,
+    /// Blocks changed on all of the inputs
+    Intersection,
+}
+
+impl FromStr for CombineOp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "union" => Ok(CombineOp::Union),
+            "intersection" => Ok(CombineOp::Intersection),
+            _ => Err(anyhow!("unknown combine operation")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidateFormat {
+    Xml,
+    /// A region list for hydrating a dm-clone target
+    Clone,
+}
+
+impl FromStr for InvalidateFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xml" => Ok(InvalidateFormat::Xml),
+            "dm-clone" => Ok(InvalidateFormat::Clone),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
 pub struct EraInvalidateOptions<'a> {
-    pub input: &'a Path,
+    pub inputs: Vec<&'a Path>,
     pub output: Option<&'a Path>,
     pub engine_opts: EngineOptions,
     pub threshold: u32,
+    pub combine: CombineOp,
+    pub format: InvalidateFormat,
+    /// The region size of the dm-clone target in sectors, or the era block
+    /// size if none is given
+    pub clone_region_size: Option<u32>,
 }
 
 struct Context {
     engine: Arc<dyn IoEngine + Send + Sync>,
 }
 
-fn mk_context(opts: &EraInvalidateOptions) -> anyhow::Result<Context> {
-    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+fn mk_context(input: &Path, opts: &EraInvalidateOptions) -> anyhow::Result<Context> {
+    let engine = EngineBuilder::new(input, &opts.engine_opts)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
     Ok(Context { engine })
 }
 
-pub fn invalidate(opts: &EraInvalidateOptions) -> Result<()> {
-    let ctx = mk_context(opts)?;
+struct MarkedBlocks {
+    block_size: u32, // sectors
+    nr_blocks: u32,
+    bits: Vec<u64>,
+}
+
+// Returns the blocks tracked, with those marked since the threshold
+fn mark_input(input: &Path, opts: &EraInvalidateOptions) -> Result<MarkedBlocks> {
+    let ctx = mk_context(input, opts)?;
 
     let sb = if opts.engine_opts.use_metadata_snap {
         read_superblock_snap(ctx.engine.as_ref())?
@@ -254,15 +347,80 @@ pub fn invalidate(opts: &EraInvalidateOptions) -> Result<()> {
         read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?
     };
 
-    let w: Box<dyn Write> = if opts.output.is_some() {
+    let bits = mark_blocks_since(ctx.engine, &sb, opts.threshold)?;
+    Ok(MarkedBlocks {
+        block_size: sb.data_block_size,
+        nr_blocks: sb.nr_blocks,
+        bits,
+    })
+}
+
+pub fn invalidate(opts: &EraInvalidateOptions) -> Result<()> {
+    if let Some(size) = opts.clone_region_size {
+        if !size.is_power_of_two() {
+            return Err(anyhow!("the clone region size must be a power of two"));
+        }
+    }
+
+    let mut combined: Option<MarkedBlocks> = None;
+    for input in &opts.inputs {
+        let marked = mark_input(input, opts)?;
+        combined = match combined {
+            None => Some(marked),
+            Some(mut acc) => {
+                // The replicas of an origin must track the same blocks, of
+                // the same size
+                if marked.nr_blocks != acc.nr_blocks {
+                    return Err(anyhow!(
+                        "{} tracks {} blocks, but the inputs before it track {}",
+                        input.display(),
+                        marked.nr_blocks,
+                        acc.nr_blocks
+                    ));
+                }
+                if marked.block_size != acc.block_size {
+                    return Err(anyhow!(
+                        "{} has a block size of {} sectors, but the inputs before it have {}",
+                        input.display(),
+                        marked.block_size,
+                        acc.block_size
+                    ));
+                }
+
+                for (lhs, rhs) in acc.bits.iter_mut().zip(marked.bits) {
+                    match opts.combine {
+                        CombineOp::Union => *lhs |= rhs,
+                        CombineOp::Intersection => *lhs &= rhs,
+                    }
+                }
+                Some(acc)
+            }
+        };
+    }
+    let marked = combined.ok_or_else(|| anyhow!("no inputs given"))?;
+
+    let mut w: Box<dyn Write> = if opts.output.is_some() {
         Box::new(BufWriter::new(File::create(opts.output.unwrap())?))
     } else {
         Box::new(BufWriter::new(std::io::stdout()))
     };
-    let mut writer = Writer::new_with_indent(w, 0x20, 2);
 
-    let marked_bits = mark_blocks_since(ctx.engine, &sb, opts.threshold)?;
-    emit_blocks(&marked_bits, sb.nr_blocks, &mut writer)
+    match opts.format {
+        InvalidateFormat::Xml => {
+            let mut writer = Writer::new_with_indent(w, 0x20, 2);
+            emit_blocks(&marked.bits, marked.nr_blocks, &mut writer)
+        }
+        InvalidateFormat::Clone => {
+            let region_size = opts.clone_region_size.unwrap_or(marked.block_size);
+            emit_clone_regions(
+                &marked.bits,
+                marked.nr_blocks,
+                marked.block_size as u64,
+                region_size as u64,
+                &mut w,
+            )
+        }
+    }
 }
 
 //------------------------------------------
@@ -6,6 +6,7 @@ pub mod repair;
 pub mod restore;
 pub mod superblock;
 pub mod writeset;
+pub mod writeset_file;
 pub mod xml;
 
 #[cfg(feature = "devtools")]
@@ -48,7 +48,8 @@ pub fn repair(opts: EraRepairOptions) -> Result<()> {
 
     let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
     let batch_size = ctx.engine_out.get_batch_size();
-    let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size);
+    let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size)
+        .with_sync_policy(opts.engine_opts.sync_policy);
     let mut restorer = Restorer::new(&mut w);
 
     dump_metadata(ctx.engine_in, &mut restorer, &sb, true)
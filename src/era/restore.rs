@@ -21,10 +21,20 @@ use crate::write_batcher::*;
 
 //------------------------------------------
 
+/// Superblock fields that replace those given in the input xml, so the
+/// metadata can be adapted to a replacement device.
+#[derive(Clone, Default)]
+pub struct SuperblockOverrides {
+    pub uuid: Option<String>,
+    pub block_size: Option<u32>,
+    pub nr_blocks: Option<u32>,
+}
+
 pub struct EraRestoreOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub engine_opts: EngineOptions,
+    pub overrides: SuperblockOverrides,
     pub report: Arc<Report>,
 }
 
@@ -57,7 +67,9 @@ enum Section {
 
 pub struct Restorer<'a> {
     w: &'a mut WriteBatcher,
+    overrides: SuperblockOverrides,
     sb: Option<ir::Superblock>,
+    uuid: [u8; UUID_SIZE],
     writesets: BTreeMap<u32, Writeset>,
     writeset_builder: Option<ArrayBuilder<u64>>, // bitset
     current_writeset: Option<ir::Writeset>,
@@ -69,9 +81,15 @@ pub struct Restorer<'a> {
 
 impl<'a> Restorer<'a> {
     pub fn new(w: &'a mut WriteBatcher) -> Restorer<'a> {
+        Self::new_with(w, &SuperblockOverrides::default())
+    }
+
+    pub fn new_with(w: &'a mut WriteBatcher, overrides: &SuperblockOverrides) -> Restorer<'a> {
         Restorer {
             w,
+            overrides: overrides.clone(),
             sb: None,
+            uuid: [0; UUID_SIZE],
             writesets: BTreeMap::new(),
             writeset_builder: None,
             current_writeset: None,
@@ -113,6 +131,7 @@ impl<'a> Restorer<'a> {
                 clean_shutdown: true,
             },
             block: SUPERBLOCK_LOCATION,
+            uuid: self.uuid,
             version: 1,
             metadata_sm_root,
             data_block_size: src_sb.block_size,
@@ -131,6 +150,10 @@ impl<'a> Restorer<'a> {
         self.in_section = Section::Finalized;
         Ok(())
     }
+
+    fn nr_blocks(&self) -> u32 {
+        self.sb.as_ref().map_or(0, |sb| sb.nr_blocks)
+    }
 }
 
 impl<'a> MetadataVisitor for Restorer<'a> {
@@ -139,7 +162,22 @@ impl<'a> MetadataVisitor for Restorer<'a> {
             return Err(anyhow!("duplicated superblock"));
         }
 
-        self.sb = Some(sb.clone());
+        let mut sb = sb.clone();
+        if let Some(uuid) = &self.overrides.uuid {
+            sb.uuid = uuid.clone();
+        }
+        if let Some(bs) = self.overrides.block_size {
+            sb.block_size = bs;
+        }
+        if let Some(nr_blocks) = self.overrides.nr_blocks {
+            sb.nr_blocks = nr_blocks;
+        }
+
+        if sb.block_size == 0 {
+            return Err(anyhow!("invalid data block size"));
+        }
+        self.uuid = parse_uuid(&sb.uuid)?;
+
         let b = self.w.alloc()?;
         if b.loc != SUPERBLOCK_LOCATION {
             return Err(anyhow!("superblock was occupied"));
@@ -147,6 +185,7 @@ impl<'a> MetadataVisitor for Restorer<'a> {
 
         self.writeset_builder = None;
         self.era_array_builder = Some(ArrayBuilder::new(sb.nr_blocks as u64));
+        self.sb = Some(sb);
         self.in_section = Section::Superblock;
 
         Ok(Visit::Continue)
@@ -161,10 +200,22 @@ impl<'a> MetadataVisitor for Restorer<'a> {
         if self.in_section != Section::Superblock {
             return Err(anyhow!("not in superblock"));
         }
-        self.writeset_builder = Some(ArrayBuilder::new(div_up(ws.nr_bits as u64, 64)));
+
+        // Writesets are resized along with the origin, the marked blocks
+        // are checked to fit as they arrive.
+        let nr_bits = if self.overrides.nr_blocks.is_some() {
+            self.nr_blocks()
+        } else {
+            ws.nr_bits
+        };
+
+        self.writeset_builder = Some(ArrayBuilder::new(div_up(nr_bits as u64, 64)));
         self.entry_index = 0;
         self.writeset_entry = 0;
-        self.current_writeset = Some(ws.clone());
+        self.current_writeset = Some(ir::Writeset {
+            era: ws.era,
+            nr_bits,
+        });
         self.in_section = Section::Writeset;
         Ok(Visit::Continue)
     }
@@ -199,6 +250,21 @@ impl<'a> MetadataVisitor for Restorer<'a> {
     }
 
     fn writeset_blocks(&mut self, blocks: &ir::MarkedBlocks) -> Result<Visit> {
+        let ws = self
+            .current_writeset
+            .as_ref()
+            .ok_or_else(|| anyhow!("not in writeset"))?;
+        if blocks.len == 0 {
+            return Ok(Visit::Continue);
+        }
+        if blocks.begin as u64 + blocks.len as u64 > ws.nr_bits as u64 {
+            return Err(anyhow!(
+                "writeset for era {} marks blocks beyond the {} blocks of the device",
+                ws.era,
+                ws.nr_bits
+            ));
+        }
+
         let first = blocks.begin;
         let last = first + blocks.len - 1; // inclusive
         let mut idx = first >> 6;
@@ -259,6 +325,13 @@ impl<'a> MetadataVisitor for Restorer<'a> {
     }
 
     fn era(&mut self, era: &ir::Era) -> Result<Visit> {
+        if era.block >= self.nr_blocks() {
+            return Err(anyhow!(
+                "era array entry for block {} is beyond the {} blocks of the device",
+                era.block,
+                self.nr_blocks()
+            ));
+        }
         let builder = self.era_array_builder.as_mut().unwrap();
         builder.push_value(self.w, era.block as u64, era.era)?;
         Ok(Visit::Continue)
@@ -292,9 +365,10 @@ pub fn restore(opts: EraRestoreOptions) -> Result<()> {
     let ctx = mk_context(&opts)?;
 
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), u32::MAX);
-    let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
+    let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size())
+        .with_sync_policy(opts.engine_opts.sync_policy);
 
-    let mut restorer = Restorer::new(&mut w);
+    let mut restorer = Restorer::new_with(&mut w, &opts.overrides);
     xml::read(input, &mut restorer)?;
 
     Ok(())
@@ -13,7 +13,7 @@ pub const SPACE_MAP_ROOT_SIZE: usize = 128;
 pub const SUPERBLOCK_LOCATION: u64 = 0;
 
 const MAGIC: u64 = 0o17660203573; // 0x7EC1077B in hex
-const UUID_SIZE: usize = 16;
+pub const UUID_SIZE: usize = 16;
 
 //------------------------------------------
 
@@ -26,6 +26,7 @@ pub struct SuperblockFlags {
 pub struct Superblock {
     pub flags: SuperblockFlags,
     pub block: u64,
+    pub uuid: [u8; UUID_SIZE],
     pub version: u32,
 
     pub metadata_sm_root: Vec<u8>,
@@ -46,7 +47,7 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
     let (i, _csum) = le_u32(data)?;
     let (i, flags) = le_u32(i)?;
     let (i, block) = le_u64(i)?;
-    let (i, _uuid) = take(16usize)(i)?;
+    let (i, uuid) = take(UUID_SIZE)(i)?;
     let (i, _magic) = le_u64(i)?;
     let (i, version) = le_u32(i)?;
 
@@ -70,6 +71,7 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
                 clean_shutdown: (flags & 0x1) != 0,
             },
             block,
+            uuid: uuid.try_into().unwrap(),
             version,
             metadata_sm_root: metadata_sm_root.to_vec(),
             data_block_size,
@@ -83,6 +85,54 @@ fn unpack(data: &[u8]) -> IResult<&[u8], Superblock> {
     ))
 }
 
+/// Parses a uuid given as 32 hex digits, either run together or split by
+/// dashes in the canonical 8-4-4-4-12 form.  An empty string stands for
+/// the nil uuid.
+pub fn parse_uuid(s: &str) -> Result<[u8; UUID_SIZE]> {
+    let mut uuid = [0u8; UUID_SIZE];
+    if s.is_empty() {
+        return Ok(uuid);
+    }
+
+    let groups: Vec<&str> = s.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    if groups.len() > 1 && lengths != [8, 4, 4, 4, 12] {
+        return Err(anyhow!("invalid uuid '{}'", s));
+    }
+
+    let digits = groups
+        .concat()
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>();
+    match digits {
+        Some(digits) if digits.len() == UUID_SIZE * 2 => {
+            for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+                *byte = (pair[0] << 4) | pair[1];
+            }
+            Ok(uuid)
+        }
+        _ => Err(anyhow!("invalid uuid '{}'", s)),
+    }
+}
+
+/// Formats a uuid in the canonical 8-4-4-4-12 form, or as an empty string
+/// if it is nil.
+pub fn format_uuid(uuid: &[u8; UUID_SIZE]) -> String {
+    if uuid.iter().all(|b| *b == 0) {
+        return String::new();
+    }
+
+    let mut s = String::with_capacity(UUID_SIZE * 2 + 4);
+    for (i, b) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
+        }
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
 pub fn read_superblock(engine: &dyn IoEngine, loc: u64) -> Result<Superblock> {
     let b = engine.read(loc)?;
 
@@ -119,7 +169,7 @@ fn pack_superblock<W: WriteBytesExt>(sb: &Superblock, w: &mut W) -> Result<()> {
     w.write_u32::<LittleEndian>(flags)?;
     w.write_u64::<LittleEndian>(sb.block)?;
 
-    w.write_all(&[0; UUID_SIZE])?;
+    w.write_all(&sb.uuid)?;
     w.write_u64::<LittleEndian>(MAGIC)?;
     w.write_u32::<LittleEndian>(sb.version)?;
 
@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use roaring::RoaringBitmap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::commands::engine::*;
+use crate::era::dump::{dump_metadata, get_writesets_ordered};
+use crate::era::ir::{self, MetadataVisitor, Visit};
+use crate::era::restore::Restorer;
+use crate::era::superblock::*;
+use crate::io_engine::*;
+use crate::pdata::bitset::read_bitset;
+use crate::pdata::space_map::metadata::*;
+use crate::report::*;
+use crate::write_batcher::*;
+
+//------------------------------------------
+
+// A writeset file holds the blocks written in a single era, so they may be
+// kept past a rollup or merged into the metadata of another device.  The
+// header is followed by the marked blocks as a serialized roaring bitmap:
+//
+//   magic (8 bytes), era, nr_bits, data_block_size, checksum of the bitmap
+//
+// with each field a little endian u32.
+
+const MAGIC: &[u8; 8] = b"erawset1";
+
+/// The blocks written in one era
+pub struct WritesetFile {
+    pub era: u32,
+    pub nr_bits: u32,
+    pub data_block_size: u32,
+    pub bits: RoaringBitmap,
+}
+
+pub fn write_writeset_file(path: &Path, ws: &WritesetFile) -> Result<()> {
+    let mut bitmap = Vec::new();
+    ws.bits.serialize_into(&mut bitmap)?;
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_u32::<LittleEndian>(ws.era)?;
+    w.write_u32::<LittleEndian>(ws.nr_bits)?;
+    w.write_u32::<LittleEndian>(ws.data_block_size)?;
+    w.write_u32::<LittleEndian>(crc32c::crc32c(&bitmap))?;
+    w.write_all(&bitmap)?;
+    w.flush()?;
+    Ok(())
+}
+
+pub fn read_writeset_file(path: &Path) -> Result<WritesetFile> {
+    let bad_file = || anyhow!("'{}' isn't a writeset file", path.display());
+
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).map_err(|_| bad_file())?;
+    if &magic != MAGIC {
+        return Err(bad_file());
+    }
+
+    let mut header = || r.read_u32::<LittleEndian>().map_err(|_| bad_file());
+    let era = header()?;
+    let nr_bits = header()?;
+    let data_block_size = header()?;
+    let csum = header()?;
+
+    let mut bitmap = Vec::new();
+    r.read_to_end(&mut bitmap)?;
+    if crc32c::crc32c(&bitmap) != csum {
+        return Err(anyhow!(
+            "checksum mismatch in writeset file '{}'",
+            path.display()
+        ));
+    }
+
+    let bits = RoaringBitmap::deserialize_from(&bitmap[..]).map_err(|_| bad_file())?;
+    if bits.max().map_or(false, |b| b >= nr_bits) {
+        return Err(anyhow!(
+            "writeset file '{}' marks blocks beyond its {} bits",
+            path.display(),
+            nr_bits
+        ));
+    }
+
+    Ok(WritesetFile {
+        era,
+        nr_bits,
+        data_block_size,
+        bits,
+    })
+}
+
+//------------------------------------------
+
+pub struct EraExportWritesetOptions<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub era: u32,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+}
+
+pub fn export_writeset(opts: EraExportWritesetOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+
+    let writesets = get_writesets_ordered(engine.clone(), &sb, false)?;
+    let ws = writesets
+        .iter()
+        .find(|(era, _)| *era == opts.era)
+        .map(|(_, ws)| ws)
+        .ok_or_else(|| anyhow!("there's no writeset for era {}", opts.era))?;
+
+    let bitset = read_bitset(engine, ws.root, ws.nr_bits as usize, false)?;
+    let bits: RoaringBitmap = bitset.ones().map(|b| b as u32).collect();
+
+    write_writeset_file(
+        opts.output,
+        &WritesetFile {
+            era: opts.era,
+            nr_bits: ws.nr_bits,
+            data_block_size: sb.data_block_size,
+            bits: bits.clone(),
+        },
+    )
+    .context(crate::dump_utils::OutputError)?;
+
+    opts.report.info(&format!(
+        "exported the {} blocks written in era {}",
+        bits.len(),
+        opts.era
+    ));
+    Ok(())
+}
+
+//------------------------------------------
+
+#[derive(PartialEq)]
+enum ImportTarget {
+    Writeset,
+    EraArray,
+}
+
+// Merges the imported blocks into the metadata passing through to the
+// inner visitor.  The blocks are or'd into the writeset of the era if it's
+// still held, otherwise the era has been rolled up, and the era array
+// entries of the blocks are raised to it.
+struct WritesetImporter<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    era: u32,
+    bits: &'a RoaringBitmap,
+    target: ImportTarget,
+    merged: Option<RoaringBitmap>,
+}
+
+impl<'a> MetadataVisitor for WritesetImporter<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn writeset_b(&mut self, ws: &ir::Writeset) -> Result<Visit> {
+        if self.target == ImportTarget::Writeset && ws.era == self.era {
+            self.merged = Some(self.bits.clone());
+        }
+        self.inner.writeset_b(ws)
+    }
+
+    fn writeset_e(&mut self) -> Result<Visit> {
+        if let Some(merged) = self.merged.take() {
+            // emit the runs of marked blocks
+            let mut run: Option<ir::MarkedBlocks> = None;
+            for b in merged.iter() {
+                match run {
+                    Some(ref mut r) if r.begin + r.len == b => r.len += 1,
+                    _ => {
+                        if let Some(r) = run.replace(ir::MarkedBlocks { begin: b, len: 1 }) {
+                            self.inner.writeset_blocks(&r)?;
+                        }
+                    }
+                }
+            }
+            if let Some(r) = run {
+                self.inner.writeset_blocks(&r)?;
+            }
+        }
+        self.inner.writeset_e()
+    }
+
+    fn writeset_blocks(&mut self, blocks: &ir::MarkedBlocks) -> Result<Visit> {
+        match self.merged.as_mut() {
+            Some(merged) => {
+                merged.insert_range(blocks.begin..blocks.begin + blocks.len);
+                Ok(Visit::Continue)
+            }
+            None => self.inner.writeset_blocks(blocks),
+        }
+    }
+
+    fn era_b(&mut self) -> Result<Visit> {
+        self.inner.era_b()
+    }
+
+    fn era_e(&mut self) -> Result<Visit> {
+        self.inner.era_e()
+    }
+
+    fn era(&mut self, era: &ir::Era) -> Result<Visit> {
+        if self.target == ImportTarget::EraArray
+            && era.era < self.era
+            && self.bits.contains(era.block)
+        {
+            return self.inner.era(&ir::Era {
+                block: era.block,
+                era: self.era,
+            });
+        }
+        self.inner.era(era)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+pub struct EraImportWritesetOptions<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub writeset: &'a Path,
+    pub era: Option<u32>,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+}
+
+pub fn import_writeset(opts: EraImportWritesetOptions) -> Result<()> {
+    let ws = read_writeset_file(opts.writeset)?;
+    let era = opts.era.unwrap_or(ws.era);
+
+    let engine_in = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
+    let sb = read_superblock(engine_in.as_ref(), SUPERBLOCK_LOCATION)?;
+
+    if ws.data_block_size != sb.data_block_size {
+        return Err(anyhow!(
+            "the writeset was taken with a block size of {} sectors, but the metadata uses {}",
+            ws.data_block_size,
+            sb.data_block_size
+        ));
+    }
+    if ws.nr_bits != sb.nr_blocks {
+        return Err(anyhow!(
+            "the writeset covers {} blocks, but the metadata has {}",
+            ws.nr_bits,
+            sb.nr_blocks
+        ));
+    }
+    if era > sb.current_era {
+        return Err(anyhow!(
+            "era {} is beyond the current era {}",
+            era,
+            sb.current_era
+        ));
+    }
+
+    // The writesets held run up to the current era, so an era without one
+    // has already been rolled up into the era array.
+    let writesets = get_writesets_ordered(engine_in.clone(), &sb, false)?;
+    let target = if writesets.iter().any(|(e, _)| *e == era) {
+        ImportTarget::Writeset
+    } else {
+        ImportTarget::EraArray
+    };
+    let msg = format!(
+        "imported the {} blocks written in era {} into the {}",
+        ws.bits.len(),
+        era,
+        if target == ImportTarget::Writeset {
+            "writeset"
+        } else {
+            "era array"
+        }
+    );
+
+    let engine_out = EngineBuilder::new(opts.output, &opts.engine_opts)
+        .write(true)
+        .build()?;
+    let sm = core_metadata_sm(engine_out.get_nr_blocks(), u32::MAX);
+    let batch_size = engine_out.get_batch_size();
+    let mut w = WriteBatcher::new(engine_out, sm, batch_size)
+        .with_sync_policy(opts.engine_opts.sync_policy);
+    let mut restorer = Restorer::new(&mut w);
+    let mut importer = WritesetImporter {
+        inner: &mut restorer,
+        era,
+        bits: &ws.bits,
+        target,
+        merged: None,
+    };
+
+    dump_metadata(engine_in, &mut importer, &sb, false)?;
+    opts.report.info(&msg);
+    Ok(())
+}
+
+//------------------------------------------
@@ -3,7 +3,7 @@ use std::io;
 use std::io::{Seek, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::ioctl::{self, *};
 
@@ -100,4 +100,96 @@ pub fn create_sized_file(path: &Path, nr_bytes: u64) -> io::Result<std::fs::File
     Ok(file)
 }
 
+/// Deallocates a range of a file, which reads back as zeroes afterwards.
+/// The size of the file is unchanged.  Returns false if the filesystem
+/// can't punch holes.
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    let r = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+
+    if r == 0 {
+        return Ok(true);
+    }
+
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+//---------------------------------------
+
+/// Creates a file in the given directory and unlinks it straight away, so
+/// the space is reclaimed once the returned handle is dropped.
+pub fn create_unlinked_file(dir: &Path) -> io::Result<File> {
+    for _ in 0..16 {
+        let path = dir.join(format!(
+            ".thinp-{}-{:016x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => {
+                std::fs::remove_file(&path)?;
+                return Ok(file);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    fail("couldn't create a temporary file")
+}
+
+/// A file in a temporary directory, for tools that need to hand a path to
+/// an io engine.  The file is removed when this is dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(dir: &Path) -> io::Result<TempFile> {
+        for _ in 0..16 {
+            let path = dir.join(format!(
+                ".thinp-{}-{:016x}",
+                std::process::id(),
+                rand::random::<u64>()
+            ));
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(TempFile { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        fail("couldn't create a temporary file")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
 //---------------------------------------
@@ -6,25 +6,83 @@ use std::io::{self, Result};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
+use crate::io_engine::errors::block_io_error;
 use crate::io_engine::*;
 
 //------------------------------------------
 
+// A block only partly transferred, as at the end of a truncated device
+fn short_io(loc: u64, what: &str) -> io::Error {
+    block_io_error(loc, io::Error::new(io::ErrorKind::UnexpectedEof, what))
+}
+
+//------------------------------------------
+
 // We hang waiting for completions on spindle devices if the QUEUE_DEPTH
 // is larger than this.  This doesn't give me confidence in io_uring.
 const QUEUE_DEPTH: usize = 256;
 
+/// How the ring is set up
+#[derive(Clone, Copy, Debug)]
+pub struct RingConfig {
+    pub depth: usize,
+    /// Poll the submission queue from a kernel thread
+    pub sq_poll: bool,
+    /// The cpu the polling thread is bound to
+    pub sq_cpu: Option<usize>,
+    /// Read through the page cache, rather than with O_DIRECT
+    pub buffered: bool,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            depth: QUEUE_DEPTH,
+            sq_poll: false,
+            sq_cpu: None,
+            buffered: false,
+        }
+    }
+}
+
 pub struct AsyncIoEngine {
     input: File,
     nr_blocks: u64,
     ring: Rio,
+    depth: usize,
 }
 
 impl AsyncIoEngine {
     pub fn new_with<P: AsRef<Path>>(path: P, writable: bool, excl: bool) -> Result<Self> {
+        Self::new_pinned(path, writable, excl, None)
+    }
+
+    /// If a cpu is given the submission queue is polled by a kernel thread
+    /// bound to that cpu, rather than being submitted from whichever cpu
+    /// the caller happens to be running on.
+    pub fn new_pinned<P: AsRef<Path>>(
+        path: P,
+        writable: bool,
+        excl: bool,
+        sq_cpu: Option<usize>,
+    ) -> Result<Self> {
+        let cfg = RingConfig {
+            sq_poll: sq_cpu.is_some(),
+            sq_cpu,
+            ..Default::default()
+        };
+        Self::new_configured(path, writable, excl, cfg)
+    }
+
+    pub fn new_configured<P: AsRef<Path>>(
+        path: P,
+        writable: bool,
+        excl: bool,
+        cfg: RingConfig,
+    ) -> Result<Self> {
         let nr_blocks = get_nr_blocks(path.as_ref())?;
 
-        let mut flags = libc::O_DIRECT;
+        let mut flags = if cfg.buffered { 0 } else { libc::O_DIRECT };
         if excl {
             flags |= libc::O_EXCL;
         }
@@ -34,17 +92,20 @@ impl AsyncIoEngine {
             .custom_flags(flags)
             .open(path)?;
 
-        let cfg = rio::Config {
-            depth: QUEUE_DEPTH,
+        let ring = rio::Config {
+            depth: cfg.depth,
             io_poll: false,
+            sq_poll: cfg.sq_poll,
+            sq_poll_affinity: cfg.sq_cpu.unwrap_or(0) as u32,
             ..Default::default()
-        };
-        let ring = cfg.start()?;
+        }
+        .start()?;
 
         Ok(Self {
             input,
             nr_blocks,
             ring,
+            depth: cfg.depth,
         })
     }
 
@@ -61,7 +122,7 @@ impl IoEngine for AsyncIoEngine {
     }
 
     fn get_batch_size(&self) -> usize {
-        QUEUE_DEPTH
+        self.depth
     }
 
     fn suggest_nr_threads(&self) -> usize {
@@ -74,9 +135,9 @@ impl IoEngine for AsyncIoEngine {
         let loc = b.loc * BLOCK_SIZE as u64;
         let completion = self.ring.read_at(&self.input, &b, loc);
 
-        let nr_read = completion.wait()?;
+        let nr_read = completion.wait().map_err(|e| block_io_error(b.loc, e))?;
         if nr_read != BLOCK_SIZE {
-            return Err(io::Error::new(io::ErrorKind::Other, "short read"));
+            return Err(short_io(b.loc, "short read"));
         }
 
         Ok(b)
@@ -98,11 +159,11 @@ impl IoEngine for AsyncIoEngine {
             // eprintln!("waiting for completion {}", i);
             match completion.wait() {
                 Err(e) => {
-                    errs.insert(i, e);
+                    errs.insert(i, block_io_error(blocks[i].loc, e));
                 }
                 Ok(nr_read) => {
                     if nr_read != BLOCK_SIZE {
-                        errs.insert(i, io::Error::new(io::ErrorKind::Other, "short read"));
+                        errs.insert(i, short_io(blocks[i].loc, "short read"));
                     }
                 }
             }
@@ -126,9 +187,9 @@ impl IoEngine for AsyncIoEngine {
         let loc = b.loc * BLOCK_SIZE as u64;
         let completion = self.ring.write_at(&self.input, &b, loc);
 
-        let nr_written = completion.wait()?;
+        let nr_written = completion.wait().map_err(|e| block_io_error(b.loc, e))?;
         if nr_written != BLOCK_SIZE {
-            return Err(io::Error::new(io::ErrorKind::Other, "short write"));
+            return Err(short_io(b.loc, "short write"));
         }
 
         Ok(())
@@ -147,11 +208,11 @@ impl IoEngine for AsyncIoEngine {
         for (i, completion) in completions {
             match completion.wait() {
                 Err(e) => {
-                    errs.insert(i, e);
+                    errs.insert(i, block_io_error(blocks[i].loc, e));
                 }
                 Ok(nr_written) => {
                     if nr_written != BLOCK_SIZE {
-                        errs.insert(i, io::Error::new(io::ErrorKind::Other, "short write"));
+                        errs.insert(i, short_io(blocks[i].loc, "short write"));
                     }
                 }
             }
@@ -170,6 +231,10 @@ impl IoEngine for AsyncIoEngine {
 
         Ok(results)
     }
+
+    fn sync(&self) -> Result<()> {
+        self.input.sync_data()
+    }
 }
 
 //------------------------------------------
@@ -100,6 +100,11 @@ pub trait IoEngine: Send + Sync {
     fn write(&self, block: &Block) -> Result<()>;
     // The whole io could fail, or individual blocks
     fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>>;
+
+    // Forces the blocks written so far to stable storage
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub fn get_nr_blocks<P: AsRef<Path>>(path: P) -> io::Result<u64> {
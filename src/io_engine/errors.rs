@@ -0,0 +1,169 @@
+use std::fmt;
+use std::io;
+
+//------------------------------------------
+
+/// What an io failure means to the user, as far as the errno tells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoErrorClass {
+    Media,
+    DeviceGone,
+    Permission,
+    OutOfSpace,
+    Timeout,
+    Other,
+}
+
+impl IoErrorClass {
+    pub fn from_errno(errno: i32) -> Self {
+        use IoErrorClass::*;
+
+        match errno {
+            libc::EIO | libc::EILSEQ | libc::ENODATA | libc::EBADMSG => Media,
+            libc::ENXIO | libc::ENODEV | libc::ENOMEDIUM => DeviceGone,
+            libc::EACCES | libc::EPERM | libc::EROFS => Permission,
+            libc::ENOSPC | libc::EDQUOT => OutOfSpace,
+            libc::ETIMEDOUT | libc::ETIME => Timeout,
+            _ => Other,
+        }
+    }
+
+    /// Classifies an error, looking through the location a block io error
+    /// was tagged with.
+    pub fn of(e: &io::Error) -> Self {
+        if let Some(be) = e.get_ref().and_then(|r| r.downcast_ref::<BlockIoError>()) {
+            return be.class;
+        }
+        e.raw_os_error()
+            .map_or(IoErrorClass::Other, IoErrorClass::from_errno)
+    }
+
+    /// A short name for machine readable output, eg. "media_error"
+    pub fn as_str(&self) -> &'static str {
+        use IoErrorClass::*;
+
+        match self {
+            Media => "media_error",
+            DeviceGone => "device_gone",
+            Permission => "permission",
+            OutOfSpace => "out_of_space",
+            Timeout => "timeout",
+            Other => "io_error",
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        use IoErrorClass::*;
+
+        match self {
+            Media => "disk returned media errors",
+            DeviceGone => "the device has gone away",
+            Permission => "permission denied",
+            OutOfSpace => "the device is out of space",
+            Timeout => "the io timed out",
+            Other => "io failed",
+        }
+    }
+}
+
+impl fmt::Display for IoErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.describe())
+    }
+}
+
+//------------------------------------------
+
+/// An io error of a metadata block.  The engines wrap these in an
+/// io::Error of the same kind, so callers that don't care see no change.
+#[derive(Debug)]
+pub struct BlockIoError {
+    pub loc: u64,
+    pub class: IoErrorClass,
+    source: io::Error,
+}
+
+impl fmt::Display for BlockIoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at block {}", self.class, self.loc)
+    }
+}
+
+impl std::error::Error for BlockIoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Tags an error with the block it happened on
+pub fn block_io_error(loc: u64, e: io::Error) -> io::Error {
+    if e.get_ref().map_or(false, |r| r.is::<BlockIoError>()) {
+        return e;
+    }
+
+    let class = IoErrorClass::of(&e);
+    io::Error::new(
+        e.kind(),
+        BlockIoError {
+            loc,
+            class,
+            source: e,
+        },
+    )
+}
+
+/// Finds the block io error behind an error, if any
+pub fn find_block_io_error(e: &anyhow::Error) -> Option<&BlockIoError> {
+    e.chain().find_map(|c| {
+        c.downcast_ref::<BlockIoError>().or_else(|| {
+            c.downcast_ref::<io::Error>()
+                .and_then(|io| io.get_ref())
+                .and_then(|r| r.downcast_ref::<BlockIoError>())
+        })
+    })
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod errors_tests {
+    use super::*;
+
+    #[test]
+    fn errnos_are_classified() {
+        assert_eq!(IoErrorClass::from_errno(libc::EIO), IoErrorClass::Media);
+        assert_eq!(
+            IoErrorClass::from_errno(libc::ENXIO),
+            IoErrorClass::DeviceGone
+        );
+        assert_eq!(
+            IoErrorClass::from_errno(libc::EACCES),
+            IoErrorClass::Permission
+        );
+        assert_eq!(
+            IoErrorClass::from_errno(libc::ENOSPC),
+            IoErrorClass::OutOfSpace
+        );
+        assert_eq!(
+            IoErrorClass::from_errno(libc::ETIMEDOUT),
+            IoErrorClass::Timeout
+        );
+        assert_eq!(IoErrorClass::from_errno(libc::EINVAL), IoErrorClass::Other);
+    }
+
+    #[test]
+    fn block_errors_keep_their_class_and_location() {
+        let e = block_io_error(42, io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(IoErrorClass::of(&e), IoErrorClass::Media);
+        assert_eq!(e.to_string(), "disk returned media errors at block 42");
+
+        // tagging again keeps the first location
+        let e = block_io_error(7, e);
+        let e = anyhow::Error::from(e).context("couldn't read the superblock");
+        let be = find_block_io_error(&e).unwrap();
+        assert_eq!(be.loc, 42);
+        assert_eq!(be.class, IoErrorClass::Media);
+    }
+}
+
+//------------------------------------------
@@ -1,19 +1,28 @@
 pub mod base;
 pub mod buffer;
+pub mod errors;
 pub mod gaps;
+pub mod overlay;
+pub mod read_only;
+pub mod sector_size;
 pub mod spindle;
 pub mod sync;
+pub mod throttle;
 pub mod utils;
+pub mod zoned;
 
 pub use crate::io_engine::base::*;
+pub use crate::io_engine::overlay::{commit_overlay, OverlayIoEngine};
+pub use crate::io_engine::read_only::ReadOnlyIoEngine;
 pub use crate::io_engine::spindle::SpindleIoEngine;
 pub use crate::io_engine::sync::SyncIoEngine;
+pub use crate::io_engine::throttle::ThrottledIoEngine;
 
 #[cfg(feature = "io_uring")]
 pub mod async_;
 
 #[cfg(feature = "io_uring")]
-pub use crate::io_engine::async_::AsyncIoEngine;
+pub use crate::io_engine::async_::{AsyncIoEngine, RingConfig};
 
 #[cfg(test)]
 pub mod core;
@@ -0,0 +1,394 @@
+use byteorder::{ByteOrder, LittleEndian};
+use fixedbitset::FixedBitSet;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::file_utils;
+use crate::io_engine::*;
+use crate::math::div_up;
+
+//------------------------------------------
+
+const MAGIC: &[u8; 8] = b"thinpovl";
+const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
+
+// The overlay file starts with a header block naming the device it
+// belongs to, followed by a bitmap of the blocks written, then a sparse
+// copy of the device holding only those blocks.
+struct Header {
+    nr_blocks: u64,
+    origin: PathBuf,
+}
+
+fn bad_overlay<T>(msg: String) -> Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn pack_header(h: &Header) -> Result<Vec<u8>> {
+    let origin = h.origin.as_os_str().as_bytes();
+    if origin.len() > BLOCK_SIZE - 20 {
+        return bad_overlay(format!("device path too long: {}", h.origin.display()));
+    }
+
+    let mut buf = vec![0; BLOCK_SIZE];
+    buf[0..8].copy_from_slice(MAGIC);
+    LittleEndian::write_u64(&mut buf[8..16], h.nr_blocks);
+    LittleEndian::write_u32(&mut buf[16..20], origin.len() as u32);
+    buf[20..20 + origin.len()].copy_from_slice(origin);
+    Ok(buf)
+}
+
+fn unpack_header(buf: &[u8]) -> Result<Header> {
+    if &buf[0..8] != MAGIC {
+        return bad_overlay("not an overlay file".to_string());
+    }
+    let nr_blocks = LittleEndian::read_u64(&buf[8..16]);
+    let len = LittleEndian::read_u32(&buf[16..20]) as usize;
+    if len > BLOCK_SIZE - 20 {
+        return bad_overlay("corrupt overlay header".to_string());
+    }
+    Ok(Header {
+        nr_blocks,
+        origin: PathBuf::from(OsStr::from_bytes(&buf[20..20 + len])),
+    })
+}
+
+fn read_header(file: &File) -> Result<Header> {
+    let mut buf = vec![0; BLOCK_SIZE];
+    file.read_exact_at(&mut buf, 0)?;
+    unpack_header(&buf)
+}
+
+fn nr_bitmap_blocks(nr_blocks: u64) -> u64 {
+    div_up(nr_blocks, BITS_PER_BLOCK as u64)
+}
+
+fn data_offset(nr_blocks: u64, loc: u64) -> u64 {
+    (1 + nr_bitmap_blocks(nr_blocks) + loc) * BLOCK_SIZE as u64
+}
+
+fn read_bitmap(file: &File, nr_blocks: u64) -> Result<FixedBitSet> {
+    let mut bytes = vec![0u8; (nr_bitmap_blocks(nr_blocks) as usize) * BLOCK_SIZE];
+    file.read_exact_at(&mut bytes, BLOCK_SIZE as u64)?;
+
+    let mut bits = FixedBitSet::with_capacity(nr_blocks as usize);
+    for b in 0..nr_blocks as usize {
+        if bytes[b / 8] & (1 << (b % 8)) != 0 {
+            bits.insert(b);
+        }
+    }
+    Ok(bits)
+}
+
+fn canonical_origin(path: &Path) -> Result<PathBuf> {
+    std::fs::canonicalize(path)
+}
+
+//------------------------------------------
+
+/// Wraps an engine, redirecting the writes to an overlay file.
+///
+/// The blocks written are kept in the overlay, and read back from there,
+/// while the others are read through to the device, which is never
+/// written.  This lets a repair be tried for real, and its results
+/// examined by opening the device with the same overlay, before
+/// commit_overlay() copies the blocks to the device.  The overlay
+/// remembers which device it belongs to, so it can't be applied to
+/// another.
+pub struct OverlayIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    file: File,
+    nr_blocks: u64,
+    written: RwLock<FixedBitSet>,
+}
+
+impl OverlayIoEngine {
+    /// Opens the overlay of a device, creating it if it doesn't exist.
+    /// The inner engine should be opened read only.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        inner: Arc<dyn IoEngine + Send + Sync>,
+        overlay: P,
+        origin: Q,
+    ) -> Result<Self> {
+        let nr_blocks = inner.get_nr_blocks();
+        let origin = canonical_origin(origin.as_ref())?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(overlay.as_ref())?;
+
+        let written = if file.metadata()?.len() == 0 {
+            let header = Header { nr_blocks, origin };
+            file.write_all_at(&pack_header(&header)?, 0)?;
+            file.set_len(data_offset(nr_blocks, nr_blocks))?;
+            FixedBitSet::with_capacity(nr_blocks as usize)
+        } else {
+            let header = read_header(&file)?;
+            if header.origin != origin {
+                return bad_overlay(format!(
+                    "{} is the overlay of {}, not {}",
+                    overlay.as_ref().display(),
+                    header.origin.display(),
+                    origin.display()
+                ));
+            }
+            if header.nr_blocks != nr_blocks {
+                return bad_overlay(format!(
+                    "{} has changed size since the overlay was created",
+                    origin.display()
+                ));
+            }
+            read_bitmap(&file, nr_blocks)?
+        };
+
+        Ok(OverlayIoEngine {
+            inner,
+            file,
+            nr_blocks,
+            written: RwLock::new(written),
+        })
+    }
+
+    /// Whether the overlay file exists and was created for the device
+    pub fn is_overlay_of<P: AsRef<Path>, Q: AsRef<Path>>(overlay: P, origin: Q) -> bool {
+        let header = File::open(overlay).and_then(|f| read_header(&f));
+        match (header, canonical_origin(origin.as_ref())) {
+            (Ok(h), Ok(origin)) => h.origin == origin,
+            _ => false,
+        }
+    }
+
+    /// The number of blocks held in the overlay
+    pub fn nr_written(&self) -> usize {
+        self.written.read().unwrap().count_ones(..)
+    }
+
+    fn read_overlay(&self, loc: u64) -> Result<Block> {
+        let b = Block::new(loc);
+        self.file
+            .read_exact_at(b.get_data(), data_offset(self.nr_blocks, loc))?;
+        Ok(b)
+    }
+
+    fn write_overlay(&self, b: &Block) -> Result<()> {
+        if b.loc >= self.nr_blocks {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("write beyond the end of the device, block {}", b.loc),
+            ));
+        }
+
+        // The data goes down before the bit that says it's there
+        self.file
+            .write_all_at(b.get_data(), data_offset(self.nr_blocks, b.loc))?;
+
+        let mut written = self.written.write().unwrap();
+        let loc = b.loc as usize;
+        if !written.contains(loc) {
+            written.insert(loc);
+            let first = loc & !7;
+            let mut byte = 0u8;
+            for bit in 0..8 {
+                if first + bit < self.nr_blocks as usize && written.contains(first + bit) {
+                    byte |= 1 << bit;
+                }
+            }
+            self.file
+                .write_all_at(&[byte], BLOCK_SIZE as u64 + (loc / 8) as u64)?;
+        }
+        Ok(())
+    }
+
+    fn in_overlay(&self, loc: u64) -> bool {
+        loc < self.nr_blocks && self.written.read().unwrap().contains(loc as usize)
+    }
+}
+
+impl IoEngine for OverlayIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        if self.in_overlay(loc) {
+            self.read_overlay(loc)
+        } else {
+            self.inner.read(loc)
+        }
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        let in_overlay: Vec<bool> = blocks.iter().map(|loc| self.in_overlay(*loc)).collect();
+        let through: Vec<u64> = blocks
+            .iter()
+            .zip(&in_overlay)
+            .filter(|(_, o)| !**o)
+            .map(|(loc, _)| *loc)
+            .collect();
+        let mut inner_results = self.inner.read_many(&through)?.into_iter();
+
+        let mut results = Vec::with_capacity(blocks.len());
+        for (loc, o) in blocks.iter().zip(in_overlay) {
+            if o {
+                results.push(self.read_overlay(*loc));
+            } else {
+                results.push(inner_results.next().unwrap());
+            }
+        }
+        Ok(results)
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        self.write_overlay(b)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write_overlay(b)).collect())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync_data()
+    }
+}
+
+//------------------------------------------
+
+/// Copies the blocks held in an overlay to the device it was created
+/// for, returning the number of blocks copied.  The overlay is left in
+/// place.
+pub fn commit_overlay<P: AsRef<Path>>(overlay: P) -> Result<usize> {
+    let file = File::open(overlay.as_ref())?;
+    let header = read_header(&file)?;
+    let written = read_bitmap(&file, header.nr_blocks)?;
+
+    if file_utils::file_size(&header.origin)? / BLOCK_SIZE as u64 != header.nr_blocks {
+        return bad_overlay(format!(
+            "{} has changed size since the overlay was created",
+            header.origin.display()
+        ));
+    }
+    let dest = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_EXCL)
+        .open(&header.origin)?;
+
+    let mut buf = vec![0; BLOCK_SIZE];
+    for loc in written.ones() {
+        let loc = loc as u64;
+        file.read_exact_at(&mut buf, data_offset(header.nr_blocks, loc))?;
+        dest.write_all_at(&buf, loc * BLOCK_SIZE as u64)?;
+    }
+    dest.sync_all()?;
+
+    Ok(written.count_ones(..))
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod overlay_tests {
+    use super::*;
+    use crate::file_utils::TempFile;
+    use crate::io_engine::core::CoreIoEngine;
+
+    const NR_BLOCKS: u64 = 16;
+
+    // The origin file only names the device, its blocks are in the core
+    // engine
+    fn mk_origin() -> (TempFile, Arc<CoreIoEngine>) {
+        let origin = TempFile::new(&std::env::temp_dir()).unwrap();
+        let core = Arc::new(CoreIoEngine::new(NR_BLOCKS));
+        for loc in 0..NR_BLOCKS {
+            let b = Block::zeroed(loc);
+            b.get_data()[0] = loc as u8;
+            core.write(&b).unwrap();
+        }
+        (origin, core)
+    }
+
+    fn write_byte(engine: &dyn IoEngine, loc: u64, v: u8) {
+        let b = Block::zeroed(loc);
+        b.get_data()[0] = v;
+        engine.write(&b).unwrap();
+    }
+
+    #[test]
+    fn writes_are_kept_in_the_overlay() {
+        let (origin, core) = mk_origin();
+        let overlay = TempFile::new(&std::env::temp_dir()).unwrap();
+
+        let engine = OverlayIoEngine::new(core.clone(), overlay.path(), origin.path()).unwrap();
+        write_byte(&engine, 3, 0xaa);
+        assert_eq!(engine.nr_written(), 1);
+
+        let blocks = engine.read_many(&[2, 3, 4]).unwrap();
+        let firsts: Vec<u8> = blocks
+            .iter()
+            .map(|b| b.as_ref().unwrap().get_data()[0])
+            .collect();
+        assert_eq!(firsts, [2, 0xaa, 4]);
+        drop(engine);
+
+        // the device is untouched, and the overlay survives reopening
+        assert_eq!(core.read(3).unwrap().get_data()[0], 3);
+        let engine = OverlayIoEngine::new(core, overlay.path(), origin.path()).unwrap();
+        assert_eq!(engine.nr_written(), 1);
+        assert_eq!(engine.read(3).unwrap().get_data()[0], 0xaa);
+        assert!(OverlayIoEngine::is_overlay_of(
+            overlay.path(),
+            origin.path()
+        ));
+    }
+
+    #[test]
+    fn commit_copies_the_written_blocks() {
+        let (origin, core) = mk_origin();
+        file_utils::create_sized_file(origin.path(), NR_BLOCKS * BLOCK_SIZE as u64).unwrap();
+        let overlay = TempFile::new(&std::env::temp_dir()).unwrap();
+
+        let engine = OverlayIoEngine::new(core, overlay.path(), origin.path()).unwrap();
+        write_byte(&engine, 0, 0xbb);
+        write_byte(&engine, 9, 0xbb);
+        drop(engine);
+
+        assert_eq!(commit_overlay(overlay.path()).unwrap(), 2);
+        let dev = File::open(origin.path()).unwrap();
+        let mut buf = [0u8; 1];
+        for (loc, v) in [(0, 0xbb), (1, 0), (9, 0xbb)] {
+            dev.read_exact_at(&mut buf, loc * BLOCK_SIZE as u64)
+                .unwrap();
+            assert_eq!(buf[0], v);
+        }
+    }
+
+    #[test]
+    fn rejects_the_overlay_of_another_device() {
+        let (origin, core) = mk_origin();
+        let overlay = TempFile::new(&std::env::temp_dir()).unwrap();
+        drop(OverlayIoEngine::new(core.clone(), overlay.path(), origin.path()).unwrap());
+
+        let other = TempFile::new(&std::env::temp_dir()).unwrap();
+        assert!(OverlayIoEngine::new(core, overlay.path(), other.path()).is_err());
+        assert!(!OverlayIoEngine::is_overlay_of(
+            overlay.path(),
+            other.path()
+        ));
+    }
+}
+
+//------------------------------------------
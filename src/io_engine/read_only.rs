@@ -0,0 +1,100 @@
+use std::io::{self, Result};
+use std::sync::Arc;
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+/// Wraps an engine opened without write access, rejecting any writes.
+///
+/// The underlying file is already opened O_RDONLY, so a write would fail
+/// anyway; this catches it before it reaches the kernel, with an error
+/// that names the cause, so a non-mutating tool can guarantee that it
+/// never touches the metadata.
+pub struct ReadOnlyIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+}
+
+impl ReadOnlyIoEngine {
+    pub fn new(inner: Arc<dyn IoEngine + Send + Sync>) -> Self {
+        ReadOnlyIoEngine { inner }
+    }
+
+    fn reject<T>(loc: u64) -> Result<T> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("refusing to write block {} of read-only metadata", loc),
+        ))
+    }
+}
+
+impl IoEngine for ReadOnlyIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        self.inner.read(loc)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        self.inner.read_many(blocks)
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        Self::reject(b.loc)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        match blocks.first() {
+            Some(b) => Self::reject(b.loc),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod read_only_tests {
+    use super::*;
+    use crate::io_engine::core::CoreIoEngine;
+
+    #[test]
+    fn reads_are_passed_through() {
+        let core = Arc::new(CoreIoEngine::new(4));
+        let b = Block::zeroed(1);
+        b.get_data()[0] = 0xaa;
+        core.write(&b).unwrap();
+
+        let engine = ReadOnlyIoEngine::new(core);
+        assert_eq!(engine.get_nr_blocks(), 4);
+        assert_eq!(engine.read(1).unwrap().get_data()[0], 0xaa);
+    }
+
+    #[test]
+    fn writes_are_rejected() {
+        let core = Arc::new(CoreIoEngine::new(4));
+        core.write(&Block::zeroed(2)).unwrap();
+        let engine = ReadOnlyIoEngine::new(core.clone());
+
+        let b = Block::zeroed(2);
+        b.get_data()[0] = 0xaa;
+        let e = engine.write(&b).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(engine.write_many(&[b]).is_err());
+        assert!(engine.write_many(&[]).unwrap().is_empty());
+
+        assert_eq!(core.read(2).unwrap().get_data()[0], 0);
+    }
+}
+
+//------------------------------------------
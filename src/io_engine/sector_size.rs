@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::io_engine::base::{BLOCK_SIZE, PAGE_SIZE};
+use crate::ioctl::{self, *};
+
+//------------------------------------------
+
+// O_DIRECT io has to start and end on logical sector boundaries, from a
+// buffer aligned to one.  The metadata blocks are page sized and page
+// aligned, so that holds for the usual 512 byte and 4Kn devices, but not
+// for devices with larger or odd sized sectors, such as drives formatted
+// with 520 byte sectors, where every io would fail with EINVAL.  Those are
+// read through the page cache instead.
+
+const BLKSSZGET: ioctl::RequestType = crate::request_code_none!(0x12, 104);
+
+/// The smallest and largest sector sizes the engine options accept
+pub const MIN_SECTOR_SIZE: u32 = 512;
+pub const MAX_SECTOR_SIZE: u32 = 65536;
+
+fn ioctl_u32(file: &File, request: ioctl::RequestType) -> io::Result<u32> {
+    let mut v: libc::c_uint = 0;
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), request, &mut v) == 0 {
+            Ok(v)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Reads the logical sector size of a block device, the unit O_DIRECT io
+/// must be aligned to.  Regular files are taken to have 512 byte sectors,
+/// as the file system looks after their alignment.
+pub fn get_logical_sector_size(path: &Path) -> io::Result<u32> {
+    let file = File::open(path)?;
+    if !file.metadata()?.file_type().is_block_device() {
+        return Ok(MIN_SECTOR_SIZE);
+    }
+    ioctl_u32(&file, BLKSSZGET)
+}
+
+/// Whether the metadata blocks can be read and written with O_DIRECT on a
+/// device with logical sectors of this size.
+pub fn fits_direct_io(logical: u32) -> bool {
+    let logical = logical as usize;
+    logical != 0 && BLOCK_SIZE % logical == 0 && PAGE_SIZE % logical == 0
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod sector_size_tests {
+    use super::*;
+
+    #[test]
+    fn blocks_fit_the_usual_sectors() {
+        assert!(fits_direct_io(512));
+        assert!(fits_direct_io(4096));
+    }
+
+    #[test]
+    fn odd_and_large_sectors_dont_fit() {
+        assert!(!fits_direct_io(520));
+        assert!(!fits_direct_io(8192));
+        assert!(!fits_direct_io(0));
+    }
+
+    #[test]
+    fn files_have_512_byte_sectors() {
+        let tmp = crate::file_utils::TempFile::new(&std::env::temp_dir()).unwrap();
+        assert_eq!(get_logical_sector_size(tmp.path()).unwrap(), MIN_SECTOR_SIZE);
+    }
+}
+
+//------------------------------------------
@@ -13,6 +13,7 @@ use std::thread;
 
 use crate::checksum::*;
 use crate::io_engine::buffer::*;
+use crate::io_engine::errors::block_io_error;
 use crate::io_engine::*;
 use crate::pack::node_encode::*;
 use crate::run_iter::*;
@@ -152,7 +153,8 @@ impl SpindleIoEngine_ {
         } else {
             let b = Block::new(loc);
             self.input
-                .read_exact_at(b.get_data(), loc * BLOCK_SIZE as u64)?;
+                .read_exact_at(b.get_data(), loc * BLOCK_SIZE as u64)
+                .map_err(|e| block_io_error(loc, e))?;
             Ok(b)
         }
     }
@@ -161,6 +163,7 @@ impl SpindleIoEngine_ {
         self.compressed.remove(&(b.loc as u32));
         self.input
             .write_all_at(b.get_data(), b.loc * BLOCK_SIZE as u64)
+            .map_err(|e| block_io_error(b.loc, e))
     }
 }
 
@@ -220,6 +223,11 @@ impl IoEngine for SpindleIoEngine {
         }
         Ok(bs)
     }
+
+    fn sync(&self) -> io::Result<()> {
+        let inner = self.inner.read().unwrap();
+        inner.input.sync_data()
+    }
 }
 
 //------------------------------------------
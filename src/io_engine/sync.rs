@@ -2,17 +2,90 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{self, Result};
 use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
+use crate::affinity::CpuSet;
+use crate::io_engine::errors::block_io_error;
 use crate::io_engine::gaps::*;
 use crate::io_engine::utils::*;
 use crate::io_engine::*;
+use crate::math::div_up;
 
 //------------------------------------------
 
+// Each io thread is given at least this many blocks, so small reads aren't
+// split up.
+const MIN_BLOCKS_PER_THREAD: usize = 64;
+
+type ReadResults = Result<Vec<Result<Block>>>;
+
+// A chunk of blocks to read, and where to send the results
+type ReadJob = (Vec<u64>, Sender<ReadResults>);
+
+// An io thread lives as long as the engine, so threads aren't spawned and
+// pinned for every read.
+struct IoThread {
+    jobs: Mutex<Option<Sender<ReadJob>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IoThread {
+    fn spawn(file: Arc<File>, cpus: Option<CpuSet>, index: usize) -> Result<Self> {
+        let (tx, rx): (Sender<ReadJob>, Receiver<ReadJob>) = channel();
+        let handle = thread::Builder::new()
+            .name("io".to_string())
+            .spawn(move || {
+                // A thread that couldn't be pinned fails every read given
+                // to it, rather than quietly running on any cpu.
+                let pinned = match &cpus {
+                    Some(cpus) => cpus.pin_current_thread_to(cpus.nth(index)),
+                    None => Ok(()),
+                };
+                for (blocks, results) in rx {
+                    let r = match &pinned {
+                        Ok(()) => SyncIoEngine::read_many_(&file, &blocks),
+                        Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                    };
+                    let _ = results.send(r);
+                }
+            })?;
+
+        Ok(IoThread {
+            jobs: Mutex::new(Some(tx)),
+            handle: Some(handle),
+        })
+    }
+
+    fn submit(&self, blocks: Vec<u64>) -> Result<Receiver<ReadResults>> {
+        let (tx, rx) = channel();
+        let jobs = self.jobs.lock().unwrap();
+        jobs.as_ref()
+            .and_then(|jobs| jobs.send((blocks, tx)).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "the io thread has exited"))?;
+        Ok(rx)
+    }
+}
+
+impl Drop for IoThread {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread's loop
+        self.jobs.lock().unwrap().take();
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
 pub struct SyncIoEngine {
     nr_blocks: u64,
-    file: File,
+    file: Arc<File>,
+    io_threads: Vec<IoThread>,
+    nowait_probe: AtomicBool,
 }
 
 impl SyncIoEngine {
@@ -38,11 +111,97 @@ impl SyncIoEngine {
         let nr_blocks = get_nr_blocks(path.as_ref())?; // check file mode before opening it
         let file = SyncIoEngine::open_file(path.as_ref(), writable, excl)?;
 
-        Ok(SyncIoEngine { nr_blocks, file })
+        Ok(SyncIoEngine {
+            nr_blocks,
+            file: Arc::new(file),
+            io_threads: Vec::new(),
+            nowait_probe: AtomicBool::new(false),
+        })
     }
 
-    fn bad_read<T>() -> Result<T> {
-        Err(io::Error::new(io::ErrorKind::Other, "read failed"))
+    /// Splits large vectored reads across several threads, so more ios are
+    /// in flight on devices with deep queues.  If a cpu set is given, each
+    /// io thread is bound to one of its cpus, so the completions stay on
+    /// the same node as the threads consuming the blocks.  The threads are
+    /// started here, and run until the engine is dropped.
+    pub fn with_io_threads(self, io_threads: usize, cpus: Option<CpuSet>) -> Result<Self> {
+        // A single thread reads on the caller's thread
+        let mut threads = Vec::new();
+        if io_threads > 1 {
+            for i in 0..io_threads {
+                threads.push(IoThread::spawn(self.file.clone(), cpus.clone(), i)?);
+            }
+        }
+
+        Ok(Self {
+            io_threads: threads,
+            ..self
+        })
+    }
+
+    /// Reads through the page cache, rather than bypassing it.  Each block
+    /// is first read with RWF_NOWAIT, which only succeeds if the block is
+    /// already cached, so hot metadata is returned without occupying an io
+    /// thread.  The blocks that miss are read as usual.
+    pub fn with_nowait_probe(self) -> Result<Self> {
+        // Safety: the fd is owned by self.file, and only its status flags
+        // are changed.
+        let fd = self.file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.nowait_probe.store(true, Ordering::Relaxed);
+        Ok(self)
+    }
+
+    // Returns the block if it could be read without waiting.  Probing stops
+    // if the kernel or file system doesn't support RWF_NOWAIT.
+    fn probe_cached(&self, loc: u64) -> Option<Block> {
+        if !self.nowait_probe.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let b = Block::new(loc);
+        let iov = libc::iovec {
+            iov_base: b.get_data().as_mut_ptr() as *mut libc::c_void,
+            iov_len: BLOCK_SIZE,
+        };
+        // Safety: the iovec covers the block's buffer, which outlives the call.
+        let r = unsafe {
+            libc::preadv2(
+                self.file.as_raw_fd(),
+                &iov,
+                1,
+                (loc * BLOCK_SIZE as u64) as libc::off_t,
+                libc::RWF_NOWAIT,
+            )
+        };
+
+        if r == BLOCK_SIZE as isize {
+            return Some(b);
+        }
+
+        if r < 0 {
+            if let Some(libc::EOPNOTSUPP | libc::EINVAL) = io::Error::last_os_error().raw_os_error()
+            {
+                self.nowait_probe.store(false, Ordering::Relaxed);
+            }
+        }
+        None
+    }
+
+    fn bad_read<T>(loc: u64, e: Option<&anyhow::Error>) -> Result<T> {
+        // The errno is all that's needed to classify the error
+        let errno = e
+            .and_then(|e| e.downcast_ref::<io::Error>())
+            .and_then(|e| e.raw_os_error());
+        let e = match errno {
+            Some(errno) => io::Error::from_raw_os_error(errno),
+            None => io::Error::new(io::ErrorKind::Other, "read failed"),
+        };
+        Err(block_io_error(loc, e))
     }
 
     fn read_many_(input: &File, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
@@ -113,8 +272,8 @@ impl SyncIoEngine {
                     match op {
                         RunOp::Run(b, e) => {
                             for i in b..e {
-                                if run_results[rindex].is_err() {
-                                    results.push(Self::bad_read());
+                                if let Err(e) = &run_results[rindex] {
+                                    results.push(Self::bad_read(i, Some(e)));
                                 } else {
                                     let b = bs[bs_index].take().unwrap();
                                     assert_eq!(i, b.loc);
@@ -134,8 +293,8 @@ impl SyncIoEngine {
                 for op in batch {
                     match op {
                         RunOp::Run(b, e) => {
-                            for _ in b..e {
-                                results.push(Self::bad_read());
+                            for loc in b..e {
+                                results.push(Self::bad_read(loc, run_results.as_ref().err()));
                                 bs_index += 1;
                             }
                         }
@@ -150,6 +309,30 @@ impl SyncIoEngine {
 
         Ok(results)
     }
+
+    fn read_blocking(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        let nr_threads = std::cmp::min(self.io_threads.len(), blocks.len() / MIN_BLOCKS_PER_THREAD);
+        if nr_threads <= 1 {
+            return Self::read_many_(&self.file, blocks);
+        }
+
+        // The chunks are contiguous, so the results can just be
+        // concatenated in the order the chunks were submitted.
+        let chunk_size = div_up(blocks.len(), nr_threads);
+        let mut pending = Vec::with_capacity(nr_threads);
+        for (t, chunk) in self.io_threads.iter().zip(blocks.chunks(chunk_size)) {
+            pending.push(t.submit(chunk.to_vec())?);
+        }
+
+        let mut results = Vec::with_capacity(blocks.len());
+        for rx in pending {
+            let rs = rx.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "an io thread exited during a read")
+            })??;
+            results.extend(rs);
+        }
+        Ok(results)
+    }
 }
 
 impl IoEngine for SyncIoEngine {
@@ -166,19 +349,44 @@ impl IoEngine for SyncIoEngine {
     }
 
     fn read(&self, loc: u64) -> Result<Block> {
+        if let Some(b) = self.probe_cached(loc) {
+            return Ok(b);
+        }
+
         let b = Block::new(loc);
         self.file
-            .read_exact_at(b.get_data(), b.loc * BLOCK_SIZE as u64)?;
+            .read_exact_at(b.get_data(), b.loc * BLOCK_SIZE as u64)
+            .map_err(|e| block_io_error(loc, e))?;
         Ok(b)
     }
 
     fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
-        Self::read_many_(&self.file, blocks)
+        if !self.nowait_probe.load(Ordering::Relaxed) {
+            return self.read_blocking(blocks);
+        }
+
+        let mut results: Vec<Option<Result<Block>>> = blocks
+            .iter()
+            .map(|loc| self.probe_cached(*loc).map(Ok))
+            .collect();
+        let misses: Vec<u64> = blocks
+            .iter()
+            .zip(&results)
+            .filter(|(_, r)| r.is_none())
+            .map(|(loc, _)| *loc)
+            .collect();
+
+        let mut fetched = self.read_blocking(&misses)?.into_iter();
+        for r in results.iter_mut().filter(|r| r.is_none()) {
+            *r = fetched.next();
+        }
+        Ok(results.into_iter().map(|r| r.unwrap()).collect())
     }
 
     fn write(&self, b: &Block) -> Result<()> {
         self.file
-            .write_all_at(b.get_data(), b.loc * BLOCK_SIZE as u64)?;
+            .write_all_at(b.get_data(), b.loc * BLOCK_SIZE as u64)
+            .map_err(|e| block_io_error(b.loc, e))?;
         Ok(())
     }
 
@@ -189,6 +397,10 @@ impl IoEngine for SyncIoEngine {
         }
         Ok(bs)
     }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync_data()
+    }
 }
 
 //------------------------------------------
@@ -0,0 +1,138 @@
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+// The bytes read since the start of the current burst of reads
+struct Budget {
+    start: Instant,
+    nr_bytes: u64,
+}
+
+/// Wraps an engine, capping the rate blocks are read at.
+///
+/// Dumping the metadata snapshot of a production pool competes with the
+/// pool's own metadata io, so the reads are spread out to keep the extra
+/// load under the given number of bytes per second.  Each read waits until
+/// the bytes read so far, itself included, fit within the rate.  Time spent
+/// idle isn't banked, so a tool that pauses doesn't then burst.  Writes
+/// aren't throttled.
+pub struct ThrottledIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    bytes_per_sec: u64,
+    budget: Mutex<Budget>,
+}
+
+impl ThrottledIoEngine {
+    pub fn new(inner: Arc<dyn IoEngine + Send + Sync>, bytes_per_sec: u64) -> Self {
+        ThrottledIoEngine {
+            inner,
+            bytes_per_sec: bytes_per_sec.max(1),
+            budget: Mutex::new(Budget {
+                start: Instant::now(),
+                nr_bytes: 0,
+            }),
+        }
+    }
+
+    fn wait_for(&self, nr_blocks: usize) {
+        let nr_bytes = (nr_blocks * BLOCK_SIZE) as u64;
+        let due = {
+            let mut budget = self.budget.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(budget.start);
+            let earned = elapsed.as_nanos() * self.bytes_per_sec as u128 / 1_000_000_000;
+            if earned > budget.nr_bytes as u128 {
+                // we've fallen behind the rate, start a new burst
+                budget.start = now;
+                budget.nr_bytes = 0;
+            }
+            budget.nr_bytes += nr_bytes;
+            let nanos = budget.nr_bytes as u128 * 1_000_000_000 / self.bytes_per_sec as u128;
+            budget.start + Duration::from_nanos(nanos as u64)
+        };
+
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+}
+
+impl IoEngine for ThrottledIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        self.wait_for(1);
+        self.inner.read(loc)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        self.wait_for(blocks.len());
+        self.inner.read_many(blocks)
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        self.inner.write(b)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        self.inner.write_many(blocks)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod throttle_tests {
+    use super::*;
+    use crate::io_engine::core::CoreIoEngine;
+
+    #[test]
+    fn reads_are_passed_through() {
+        let core = Arc::new(CoreIoEngine::new(4));
+        let b = Block::zeroed(1);
+        b.get_data()[0] = 0xaa;
+        core.write(&b).unwrap();
+
+        let engine = ThrottledIoEngine::new(core, 1 << 30);
+        assert_eq!(engine.get_nr_blocks(), 4);
+        assert_eq!(engine.read(1).unwrap().get_data()[0], 0xaa);
+        let blocks = engine.read_many(&[0, 1]).unwrap();
+        assert_eq!(blocks[1].as_ref().unwrap().get_data()[0], 0xaa);
+    }
+
+    #[test]
+    fn reads_are_held_to_the_rate() {
+        let core = Arc::new(CoreIoEngine::new(64));
+        let rate = 100 * BLOCK_SIZE as u64;
+        let engine = ThrottledIoEngine::new(core, rate);
+
+        // 20 blocks at 100 blocks a second take at least 200ms
+        let start = Instant::now();
+        for b in 0..10 {
+            engine.read(b).unwrap();
+        }
+        engine.read_many(&(10..20).collect::<Vec<u64>>()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
+
+//------------------------------------------
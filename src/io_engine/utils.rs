@@ -57,12 +57,12 @@ impl<T: VectoredIo> ReadBlocks for VectoredBlockIo<T> {
                         results.push(Ok(()));
                     }
                 }
-                Err(_) => {
-                    // Skip to the next iovec
+                Err(e) => {
+                    // Skip to the next iovec, keeping the errno for the caller
                     remaining -= block_size;
                     pos += block_size as u64;
                     os_bufs = &mut os_bufs[1..];
-                    results.push(Err(anyhow!("read failed")));
+                    results.push(Err(e.into()));
                 }
             }
         }
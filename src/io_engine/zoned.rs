@@ -0,0 +1,287 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+
+use crate::io_engine::buffer::Buffer;
+use crate::io_engine::utils::WriteBlocks;
+use crate::ioctl::{self, *};
+
+#[cfg(test)]
+mod tests;
+
+//-------------------------------------
+
+// Zoned block devices, ie. SMR drives (ZBC/ZAC) and ZNS namespaces, split
+// the device into zones.  Sequential write required zones may only be
+// written at their write pointer, so the random writes a copier usually
+// issues fail with unaligned write errors.
+//
+// Zone append isn't available to userland through the block device, the
+// kernel only exposes it to in-kernel users and nvme passthrough.  So
+// instead we issue writes in write pointer order, filling any holes with
+// zeroes.  Reading the unwritten part of a zone returns zeroes anyway.
+
+const BLKREPORTZONE: ioctl::RequestType = crate::request_code_readwrite!(0x12, 130, ZoneReport);
+
+const BLK_ZONE_TYPE_CONVENTIONAL: u8 = 0x1;
+
+const BLK_ZONE_COND_READONLY: u8 = 0xd;
+const BLK_ZONE_COND_FULL: u8 = 0xe;
+const BLK_ZONE_COND_OFFLINE: u8 = 0xf;
+
+const BLK_ZONE_REP_CAPACITY: u32 = 1 << 0;
+
+const SECTOR_SHIFT: u64 = 9;
+const NR_ZONES_PER_REPORT: usize = 256;
+const PAD_BUFFER_SIZE: usize = 1024 * 1024;
+
+// struct blk_zone from include/uapi/linux/blkzoned.h
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    type_: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+// struct blk_zone_report, followed by the array of zones
+#[repr(C)]
+#[allow(dead_code)]
+struct ZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct ZoneReportBuffer {
+    header: ZoneReport,
+    zones: [BlkZone; NR_ZONES_PER_REPORT],
+}
+
+//-------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZonedModel {
+    None,
+    HostAware,
+    HostManaged,
+}
+
+/// A zone, with all positions in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Zone {
+    pub start: u64,
+    pub len: u64,
+
+    // The writable part of the zone, which may be less than len on ZNS
+    pub capacity: u64,
+    pub wp: u64,
+    pub sequential: bool,
+    pub writable: bool,
+}
+
+impl Zone {
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+fn sysfs_zoned_model(file: &File) -> io::Result<ZonedModel> {
+    let md = file.metadata()?;
+    if !md.file_type().is_block_device() {
+        return Ok(ZonedModel::None);
+    }
+
+    let rdev = md.rdev();
+    let path = format!(
+        "/sys/dev/block/{}:{}/queue/zoned",
+        libc::major(rdev),
+        libc::minor(rdev)
+    );
+    match std::fs::read_to_string(path) {
+        Ok(model) => match model.trim() {
+            "host-aware" => Ok(ZonedModel::HostAware),
+            "host-managed" => Ok(ZonedModel::HostManaged),
+            _ => Ok(ZonedModel::None),
+        },
+        // Older kernels, and partitions, don't have the attribute
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ZonedModel::None),
+        Err(e) => Err(e),
+    }
+}
+
+fn report_zones(file: &File) -> io::Result<Vec<Zone>> {
+    let fd = file.as_raw_fd();
+
+    // The kernel reports no zones once the sector is beyond the device
+    let mut zones = Vec::new();
+    let mut sector = 0;
+    loop {
+        let mut report = Box::new(ZoneReportBuffer {
+            header: ZoneReport {
+                sector,
+                nr_zones: NR_ZONES_PER_REPORT as u32,
+                flags: 0,
+            },
+            zones: [BlkZone::default(); NR_ZONES_PER_REPORT],
+        });
+
+        unsafe {
+            if libc::ioctl(fd, BLKREPORTZONE, report.as_mut() as *mut ZoneReportBuffer) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let nr_zones = report.header.nr_zones as usize;
+        if nr_zones == 0 {
+            break;
+        }
+
+        let has_capacity = report.header.flags & BLK_ZONE_REP_CAPACITY != 0;
+        for z in &report.zones[..nr_zones] {
+            let capacity = if has_capacity { z.capacity } else { z.len };
+            let wp = match z.cond {
+                BLK_ZONE_COND_FULL => z.start + capacity,
+                _ => z.wp,
+            };
+            zones.push(Zone {
+                start: z.start << SECTOR_SHIFT,
+                len: z.len << SECTOR_SHIFT,
+                capacity: capacity << SECTOR_SHIFT,
+                wp: wp << SECTOR_SHIFT,
+                sequential: z.type_ != BLK_ZONE_TYPE_CONVENTIONAL,
+                writable: !matches!(z.cond, BLK_ZONE_COND_READONLY | BLK_ZONE_COND_OFFLINE),
+            });
+            sector = z.start + z.len;
+        }
+    }
+
+    Ok(zones)
+}
+
+//-------------------------------------
+
+/// Tracks the write pointers of the sequential zones of a destination
+/// device, so writes may be issued in an order the device accepts.
+pub struct ZoneTracker {
+    zones: Vec<Zone>,
+    zeroes: Option<Buffer>,
+}
+
+impl ZoneTracker {
+    pub fn new(mut zones: Vec<Zone>) -> Self {
+        zones.sort_by_key(|z| z.start);
+        Self {
+            zones,
+            zeroes: None,
+        }
+    }
+
+    /// Returns a tracker if the file is a host managed zoned device.
+    /// Host aware devices accept random writes, so are treated like any
+    /// other device.
+    pub fn from_file(file: &File) -> Result<Option<Self>> {
+        if sysfs_zoned_model(file)? != ZonedModel::HostManaged {
+            return Ok(None);
+        }
+
+        let zones = report_zones(file).map_err(|e| anyhow!("couldn't report zones: {}", e))?;
+        Ok(Some(Self::new(zones)))
+    }
+
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    fn zone_index(&self, pos: u64) -> Option<usize> {
+        let i = self.zones.partition_point(|z| z.end() <= pos);
+        (i < self.zones.len() && self.zones[i].start <= pos).then_some(i)
+    }
+
+    fn pad<W: WriteBlocks>(&mut self, dst: &W, mut begin: u64, end: u64) -> Result<()> {
+        let zeroes = self.zeroes.get_or_insert_with(|| {
+            let buf = Buffer::new(PAD_BUFFER_SIZE, 4096);
+            buf.get_data().fill(0);
+            buf
+        });
+
+        while begin < end {
+            let len = std::cmp::min(end - begin, PAD_BUFFER_SIZE as u64) as usize;
+            let results = dst.write_blocks(&[&zeroes.get_data()[..len]], begin)?;
+            if results.into_iter().any(|r| r.is_err()) {
+                return Err(anyhow!("couldn't fill the zone up to {}", end));
+            }
+            begin += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Prepares for a write of len bytes at pos, filling the zone up to pos
+    /// if the write pointer lies before it.  Fails if the write would land
+    /// behind a write pointer or beyond the capacity of a zone, in which
+    /// case the zone has to be reset before it can be written.
+    pub fn prepare_write<W: WriteBlocks>(&mut self, dst: &W, pos: u64, len: u64) -> Result<()> {
+        let end = pos + len;
+        let mut begin = pos;
+        while begin < end {
+            let i = self
+                .zone_index(begin)
+                .ok_or_else(|| anyhow!("write at {} is beyond the last zone", begin))?;
+            let z = self.zones[i];
+            if !z.writable {
+                return Err(anyhow!("zone at {} is not writable", z.start));
+            }
+
+            let zone_end = std::cmp::min(end, z.end());
+            if z.sequential {
+                if zone_end > z.start + z.capacity {
+                    return Err(anyhow!("write at {} exceeds the zone capacity", begin));
+                }
+                if begin < z.wp {
+                    return Err(anyhow!(
+                        "write at {} is behind the zone write pointer at {}",
+                        begin,
+                        z.wp
+                    ));
+                }
+                if begin > z.wp {
+                    self.pad(dst, z.wp, begin)?;
+                    self.zones[i].wp = begin;
+                }
+            }
+            begin = zone_end;
+        }
+        Ok(())
+    }
+
+    /// Advances the write pointers past the len bytes written at pos
+    pub fn complete_write(&mut self, pos: u64, len: u64) {
+        let end = pos + len;
+        let mut begin = pos;
+        while begin < end {
+            let i = match self.zone_index(begin) {
+                Some(i) => i,
+                None => break,
+            };
+            let z = &mut self.zones[i];
+            let zone_end = std::cmp::min(end, z.end());
+            if z.sequential && z.wp == begin {
+                z.wp = zone_end;
+            }
+            begin = zone_end;
+        }
+    }
+}
+
+//-------------------------------------
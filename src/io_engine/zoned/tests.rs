@@ -0,0 +1,115 @@
+use super::*;
+
+use std::sync::Mutex;
+
+//-------------------------------------
+
+const ZONE_SIZE: u64 = 1 << 20;
+
+// Records the writes issued, rejecting those not at a write pointer like
+// a host managed device would.
+#[derive(Default)]
+struct RecordingDev {
+    writes: Mutex<Vec<(u64, u64)>>,
+}
+
+impl WriteBlocks for RecordingDev {
+    fn write_blocks(&self, buffers: &[&[u8]], pos: u64) -> Result<Vec<Result<()>>> {
+        let len: usize = buffers.iter().map(|b| b.len()).sum();
+        assert!(buffers.iter().all(|b| b.iter().all(|v| *v == 0)));
+        self.writes.lock().unwrap().push((pos, len as u64));
+        Ok(buffers.iter().map(|_| Ok(())).collect())
+    }
+}
+
+fn mk_zones(nr_zones: u64, nr_conventional: u64) -> Vec<Zone> {
+    (0..nr_zones)
+        .map(|i| Zone {
+            start: i * ZONE_SIZE,
+            len: ZONE_SIZE,
+            capacity: ZONE_SIZE,
+            wp: i * ZONE_SIZE,
+            sequential: i >= nr_conventional,
+            writable: true,
+        })
+        .collect()
+}
+
+#[test]
+fn write_at_write_pointer_needs_no_padding() -> Result<()> {
+    let dev = RecordingDev::default();
+    let mut zones = ZoneTracker::new(mk_zones(2, 0));
+
+    zones.prepare_write(&dev, 0, 4096)?;
+    zones.complete_write(0, 4096);
+    zones.prepare_write(&dev, 4096, 4096)?;
+    zones.complete_write(4096, 4096);
+
+    assert!(dev.writes.lock().unwrap().is_empty());
+    assert_eq!(zones.zones()[0].wp, 8192);
+    Ok(())
+}
+
+#[test]
+fn holes_are_filled_with_zeroes() -> Result<()> {
+    let dev = RecordingDev::default();
+    let mut zones = ZoneTracker::new(mk_zones(2, 0));
+
+    zones.prepare_write(&dev, ZONE_SIZE + 8192, 4096)?;
+    zones.complete_write(ZONE_SIZE + 8192, 4096);
+
+    assert_eq!(*dev.writes.lock().unwrap(), vec![(ZONE_SIZE, 8192)]);
+    assert_eq!(zones.zones()[1].wp, ZONE_SIZE + 12288);
+    Ok(())
+}
+
+#[test]
+fn write_behind_write_pointer_fails() -> Result<()> {
+    let dev = RecordingDev::default();
+    let mut zones = ZoneTracker::new(mk_zones(1, 0));
+
+    zones.prepare_write(&dev, 8192, 4096)?;
+    zones.complete_write(8192, 4096);
+    assert!(zones.prepare_write(&dev, 4096, 4096).is_err());
+    Ok(())
+}
+
+#[test]
+fn conventional_zones_accept_random_writes() -> Result<()> {
+    let dev = RecordingDev::default();
+    let mut zones = ZoneTracker::new(mk_zones(2, 1));
+
+    zones.prepare_write(&dev, 8192, 4096)?;
+    zones.complete_write(8192, 4096);
+    zones.prepare_write(&dev, 0, 4096)?;
+    zones.complete_write(0, 4096);
+
+    assert!(dev.writes.lock().unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn writes_may_span_zones() -> Result<()> {
+    let dev = RecordingDev::default();
+    let mut zones = ZoneTracker::new(mk_zones(2, 0));
+
+    zones.prepare_write(&dev, ZONE_SIZE - 4096, 8192)?;
+    zones.complete_write(ZONE_SIZE - 4096, 8192);
+
+    assert_eq!(*dev.writes.lock().unwrap(), vec![(0, ZONE_SIZE - 4096)]);
+    assert_eq!(zones.zones()[0].wp, ZONE_SIZE);
+    assert_eq!(zones.zones()[1].wp, ZONE_SIZE + 4096);
+    Ok(())
+}
+
+#[test]
+fn writes_beyond_zone_capacity_fail() {
+    let dev = RecordingDev::default();
+    let mut layout = mk_zones(1, 0);
+    layout[0].capacity = ZONE_SIZE / 2;
+    let mut zones = ZoneTracker::new(layout);
+
+    assert!(zones.prepare_write(&dev, ZONE_SIZE / 2, 4096).is_err());
+}
+
+//-------------------------------------
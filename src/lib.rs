@@ -5,6 +5,7 @@ extern crate quickcheck;
 #[cfg(test)]
 extern crate quickcheck_macros;
 
+pub mod affinity;
 pub mod cache;
 pub mod checksum;
 pub mod commands;
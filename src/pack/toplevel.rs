@@ -5,7 +5,7 @@ use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
 use std::os::unix::fs::{FileExt, OpenOptionsExt};
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io,
     io::prelude::*,
     io::Write,
@@ -19,8 +19,18 @@ use rand::prelude::*;
 use std::sync::mpsc::{sync_channel, Receiver};
 
 use crate::checksum::*;
-use crate::file_utils;
+use crate::file_utils::{self, TempFile};
+use crate::io_engine::{Block, IoEngine, SyncIoEngine};
 use crate::pack::node_encode::*;
+use crate::pdata::space_map::common::SMRoot;
+use crate::pdata::space_map::metadata::core_metadata_sm;
+use crate::pdata::unpack;
+use crate::report::mk_quiet_report;
+use crate::thin::dump::dump_metadata;
+use crate::thin::metadata::{build_metadata, optimise_metadata, ThinSuperblock};
+use crate::thin::restore::Restorer;
+use crate::thin::superblock::{read_superblock, SUPERBLOCK_LOCATION};
+use crate::write_batcher::WriteBatcher;
 
 const BLOCK_SIZE: u64 = 4096;
 const MAGIC: u64 = 0xa537a0aa6309ef77;
@@ -189,6 +199,16 @@ where
     r.read_u64::<LittleEndian>()
 }
 
+/// Returns true if the file starts with the magic of a packed metadata file.
+pub fn is_pack_file(path: &Path) -> io::Result<bool> {
+    let mut file = OpenOptions::new().read(true).open(path)?;
+    match file.read_u64::<LittleEndian>() {
+        Ok(magic) => Ok(magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 fn get_nr_blocks(path: &Path) -> io::Result<u64> {
     let len = file_utils::file_size(path)?;
     Ok(len / BLOCK_SIZE)
@@ -227,30 +247,47 @@ where
     Ok(())
 }
 
-fn write_blocks<W>(w: &Arc<Mutex<W>>, blocks: &mut Vec<(u64, Vec<u8>)>) -> io::Result<()>
-where
-    W: Write + Seek + FileExt,
-{
-    let w = w.lock().unwrap();
-    while let Some((b, block)) = blocks.pop() {
-        w.write_all_at(&block, b * BLOCK_SIZE)?;
+fn write_blocks(w: &Arc<Mutex<WriteBatcher>>, blocks: &mut Vec<(Block, BT)>) -> Result<()> {
+    let mut w = w.lock().unwrap();
+    while let Some((block, kind)) = blocks.pop() {
+        w.write(block, kind)?;
     }
     Ok(())
 }
 
-fn decode_worker<W>(rx: Receiver<Vec<u8>>, w: Arc<Mutex<W>>) -> io::Result<()>
+fn zero_blocks<W>(w: &mut W, nr_blocks: u64) -> io::Result<()>
 where
     W: Write + Seek + FileExt,
 {
+    const CHUNK_BLOCKS: u64 = 256;
+
+    let zeroes: Vec<u8> = vec![0; (BLOCK_SIZE * CHUNK_BLOCKS) as usize];
+    let mut b = 0;
+    while b < nr_blocks {
+        let len = std::cmp::min(CHUNK_BLOCKS, nr_blocks - b);
+        w.write_all_at(&zeroes[..(len * BLOCK_SIZE) as usize], b * BLOCK_SIZE)?;
+        b += len;
+    }
+    Ok(())
+}
+
+fn decode_worker(rx: Receiver<Vec<u8>>, w: Arc<Mutex<WriteBatcher>>, nr_blocks: u64) -> Result<()> {
     let mut blocks = Vec::new();
 
     while let Ok(bytes) = rx.recv() {
         let mut z = ZlibDecoder::new(&bytes[0..]);
 
         while let Ok(b) = z.read_u64::<LittleEndian>() {
-            let block = crate::pack::vm::unpack(&mut z, BLOCK_SIZE as usize).unwrap();
-            assert!(metadata_block_type(&block[0..]) != BT::UNKNOWN);
-            blocks.push((b, block));
+            if b >= nr_blocks {
+                return Err(anyhow!("block {} is beyond the end of the output", b));
+            }
+
+            let data = crate::pack::vm::unpack(&mut z, BLOCK_SIZE as usize).unwrap();
+            let kind = metadata_block_type(&data[0..]);
+            assert!(kind != BT::UNKNOWN);
+            let block = Block::new(b);
+            block.get_data().copy_from_slice(&data);
+            blocks.push((block, kind));
 
             if blocks.len() >= 32 {
                 write_blocks(&w, &mut blocks)?;
@@ -262,26 +299,13 @@ where
     Ok(())
 }
 
-pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
-    let mut input = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(input_file)?;
-
-    let nr_blocks = read_header(&input)?;
-
-    let mut output = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(output_file)?;
-
-    // zero the last block to size the file
-    write_zero_block(&mut output, nr_blocks - 1)?;
-
-    // Run until we hit the end
-    let output = Arc::new(Mutex::new(output));
+// Writes every block of the pack to the output, which must have room for
+// the nr_blocks of the original metadata.
+fn unpack_blocks(mut input: File, output: &Path, nr_blocks: u64) -> Result<()> {
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(output, true)?);
+    let sm = core_metadata_sm(nr_blocks, u32::MAX);
+    let batch_size = engine.get_batch_size();
+    let w = Arc::new(Mutex::new(WriteBatcher::new(engine, sm, batch_size)));
 
     // kick off the workers
     let nr_jobs = num_cpus::get();
@@ -290,17 +314,20 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
 
     for _ in 0..nr_jobs {
         let (tx, rx) = sync_channel(1);
-        let output = Arc::clone(&output);
+        let w = Arc::clone(&w);
         senders.push(tx);
-        threads.push(spawn(move || decode_worker(rx, output)));
+        threads.push(spawn(move || decode_worker(rx, w, nr_blocks)));
     }
 
-    // Read z compressed chunk, and hand to worker thread.
+    // Read z compressed chunk, and hand to worker thread.  A worker that
+    // failed has dropped its receiver, and its error is collected below.
     let mut next_worker = 0;
     while let Ok(len) = input.read_u64::<LittleEndian>() {
         let mut bytes = vec![0; len as usize];
         input.read_exact(&mut bytes)?;
-        senders[next_worker].send(bytes).unwrap();
+        if senders[next_worker].send(bytes).is_err() {
+            break;
+        }
         next_worker = (next_worker + 1) % nr_jobs;
     }
 
@@ -311,5 +338,192 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
     for t in threads {
         t.join().unwrap()?;
     }
+
+    let mut w = w.lock().unwrap();
+    w.flush()?;
+    w.engine.sync()?;
     Ok(())
 }
+
+// Rewrites the unpacked metadata into the first nr_blocks of the output,
+// so its space map is sized to the device rather than to the original
+// metadata.  Only thin metadata can be rebuilt like this.
+fn rebuild_onto(unpacked: &Path, output: &Path, nr_blocks: u64) -> Result<()> {
+    let input: Arc<dyn IoEngine + Send + Sync> =
+        Arc::new(SyncIoEngine::new_with(unpacked, false, false)?);
+    if metadata_block_type(input.read(SUPERBLOCK_LOCATION)?.get_data()) != BT::THIN_SUPERBLOCK {
+        return Err(anyhow!(
+            "only thin metadata may be unpacked onto a device smaller than the original"
+        ));
+    }
+
+    let sb = read_superblock(input.as_ref(), SUPERBLOCK_LOCATION)?;
+    let nr_allocated = unpack::unpack::<SMRoot>(&sb.metadata_sm_root[0..])?.nr_allocated;
+    if nr_allocated > nr_blocks {
+        return Err(anyhow!(
+            "the metadata uses {} blocks, but the output device only holds {}",
+            nr_allocated,
+            nr_blocks
+        ));
+    }
+
+    let sb = ThinSuperblock::OnDisk(sb);
+    let md = build_metadata(input.clone(), &sb)?;
+    let md = optimise_metadata(md)?;
+
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(output, true)?);
+    let sm = core_metadata_sm(nr_blocks, u32::MAX);
+    let batch_size = engine.get_batch_size();
+    let mut w = WriteBatcher::new(engine.clone(), sm, batch_size);
+    let mut restorer = Restorer::new(&mut w, Arc::new(mk_quiet_report()));
+    dump_metadata(input, &mut restorer, &sb, &md)
+        .context("unable to rebuild the metadata onto the output device")?;
+    drop(restorer);
+    w.flush()?;
+    engine.sync()?;
+    Ok(())
+}
+
+pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
+    unpack_onto(input_file, output_file, false)
+}
+
+/// Unpacks onto a file, which is created to the size of the original
+/// metadata, or a block device, which is written in place.
+///
+/// A device smaller than the original metadata is refused unless
+/// allow_truncate is set.  The metadata is then unpacked to a temporary
+/// file and rebuilt onto the device, so the space maps match its size.
+pub fn unpack_onto(input_file: &Path, output_file: &Path, allow_truncate: bool) -> Result<()> {
+    let input = OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(input_file)?;
+
+    let nr_blocks = read_header(&input)?;
+
+    let is_dev = output_file.exists() && !file_utils::is_file(output_file)?;
+    if is_dev {
+        let dev_blocks = file_utils::file_size(output_file)? / BLOCK_SIZE;
+        if dev_blocks < nr_blocks {
+            if !allow_truncate {
+                return Err(anyhow!(
+                    "the output device holds {} blocks, but the metadata was {} blocks",
+                    dev_blocks,
+                    nr_blocks
+                ));
+            }
+
+            let dir = std::env::temp_dir();
+            let tmp = TempFile::new(&dir)?;
+            drop(input);
+            unpack_onto(input_file, tmp.path(), false)?;
+            return rebuild_onto(tmp.path(), output_file, dev_blocks);
+        }
+
+        let mut output = OpenOptions::new()
+            .read(false)
+            .write(true)
+            .open(output_file)?;
+
+        // Only the blocks in use were packed, so stale metadata left in the
+        // others must not be taken for part of the new one.
+        zero_blocks(&mut output, nr_blocks).context("unable to zero the output device")?;
+    } else {
+        let mut output = OpenOptions::new()
+            .read(false)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output_file)?;
+
+        // zero the last block to size the file
+        write_zero_block(&mut output, nr_blocks - 1)?;
+    }
+
+    unpack_blocks(input, output_file, nr_blocks)
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod unpack_tests {
+    use super::*;
+    use crate::thin::ir::{self, MetadataVisitor};
+
+    // Restores a pool with a single device into a metadata file of nr_blocks
+    fn mk_metadata(path: &Path, nr_blocks: u64) -> Result<()> {
+        file_utils::create_sized_file(path, nr_blocks * BLOCK_SIZE)?;
+        let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(path, true)?);
+        let sm = core_metadata_sm(nr_blocks, u32::MAX);
+        let mut w = WriteBatcher::new(engine, sm, 16);
+        let mut restorer = Restorer::new(&mut w, Arc::new(mk_quiet_report()));
+        restorer.superblock_b(&ir::Superblock {
+            uuid: "".to_string(),
+            time: 0,
+            transaction: 1,
+            flags: None,
+            version: Some(2),
+            data_block_size: 128,
+            nr_data_blocks: 1024,
+            metadata_snap: None,
+        })?;
+        restorer.device_b(&ir::Device {
+            dev_id: 0,
+            mapped_blocks: 100,
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        })?;
+        restorer.map(&ir::Map {
+            thin_begin: 0,
+            data_begin: 0,
+            time: 0,
+            len: 100,
+        })?;
+        restorer.device_e()?;
+        restorer.superblock_e()?;
+        restorer.eof()?;
+        Ok(())
+    }
+
+    fn metadata_sm_nr_blocks(path: &Path) -> Result<u64> {
+        let engine = SyncIoEngine::new_with(path, false, false)?;
+        let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+        Ok(unpack::unpack::<SMRoot>(&sb.metadata_sm_root[0..])?.nr_blocks)
+    }
+
+    #[test]
+    fn truncated_metadata_is_sized_to_the_output() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let md = TempFile::new(&dir)?;
+        let packed = TempFile::new(&dir)?;
+        let unpacked = TempFile::new(&dir)?;
+        let output = TempFile::new(&dir)?;
+
+        mk_metadata(md.path(), 1024)?;
+        pack(md.path(), packed.path())?;
+        unpack(packed.path(), unpacked.path())?;
+        assert_eq!(metadata_sm_nr_blocks(unpacked.path())?, 1024);
+
+        file_utils::create_sized_file(output.path(), 256 * BLOCK_SIZE)?;
+        rebuild_onto(unpacked.path(), output.path(), 256)?;
+        assert_eq!(metadata_sm_nr_blocks(output.path())?, 256);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_must_fit_the_output() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let md = TempFile::new(&dir)?;
+        let output = TempFile::new(&dir)?;
+
+        mk_metadata(md.path(), 1024)?;
+        file_utils::create_sized_file(output.path(), 2 * BLOCK_SIZE)?;
+        let err = rebuild_onto(md.path(), output.path(), 2).unwrap_err();
+        assert!(err.to_string().contains("only holds 2"));
+        Ok(())
+    }
+}
+
+//------------------------------------------
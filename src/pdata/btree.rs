@@ -127,7 +127,7 @@ fn convert_result<V>(r: IResult<&[u8], V>) -> std::result::Result<(&[u8], V), No
 }
 
 pub fn convert_io_err<V>(path: &[u64], r: std::io::Result<V>) -> Result<V> {
-    r.map_err(|_| io_err(path))
+    r.map_err(|e| io_err(path, &e))
 }
 
 pub fn unpack_node<V: Unpack>(
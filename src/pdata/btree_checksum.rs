@@ -0,0 +1,114 @@
+use anyhow::Result;
+use fixedbitset::FixedBitSet;
+use std::sync::Arc;
+
+use crate::checksum;
+use crate::io_engine::*;
+use crate::pdata::btree::*;
+use crate::pdata::unpack::*;
+use crate::report::Report;
+
+//------------------------------------------
+
+// A crash may tear the write of a btree node such that only the checksum
+// is stale, leaving contents that are otherwise intact.  Rather than
+// rebuilding the whole tree, the checksums of such nodes can be rewritten.
+//
+// A node is deemed plausible if its header records the block it lives in
+// and it unpacks cleanly, with sorted keys and sensible entry counts.
+// Nodes that fail either test are left alone for the usual check to report.
+
+/// Walks btrees, rewriting the checksums of plausible nodes whose checksum
+/// doesn't match.  Nodes shared between trees are only visited once.
+pub struct ChecksumFixer {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    seen: FixedBitSet,
+    nr_fixed: u64,
+}
+
+impl ChecksumFixer {
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, report: Arc<Report>) -> Self {
+        let nr_blocks = engine.get_nr_blocks() as usize;
+        ChecksumFixer {
+            engine,
+            report,
+            seen: FixedBitSet::with_capacity(nr_blocks),
+            nr_fixed: 0,
+        }
+    }
+
+    /// The number of checksums rewritten so far
+    pub fn nr_fixed(&self) -> u64 {
+        self.nr_fixed
+    }
+
+    fn unpack_or_fix<V: Unpack>(&mut self, b: &Block, is_root: bool) -> Result<Option<Node<V>>> {
+        let data = b.get_data();
+        let csum_ok = checksum::metadata_block_type(data) == checksum::BT::NODE;
+
+        let node = match unpack_node_raw::<V>(data, false, is_root) {
+            Ok(node) => node,
+            Err(_) => return Ok(None),
+        };
+
+        if !csum_ok {
+            if node.get_header().block != b.loc {
+                return Ok(None);
+            }
+            checksum::write_checksum(data, checksum::BT::NODE)?;
+            self.engine.write(b)?;
+            self.nr_fixed += 1;
+            self.report
+                .warning(&format!("fixed the checksum of btree node {}", b.loc));
+        }
+
+        Ok(Some(node))
+    }
+
+    fn walk<V, F>(&mut self, b: &Block, is_root: bool, leaf_fn: &mut F) -> Result<()>
+    where
+        V: Unpack,
+        F: FnMut(&[V]),
+    {
+        match self.unpack_or_fix::<V>(b, is_root)? {
+            Some(Node::Internal { values, .. }) => {
+                let nr_blocks = self.engine.get_nr_blocks();
+                let children: Vec<u64> = values
+                    .into_iter()
+                    .filter(|loc| *loc < nr_blocks && !self.seen.put(*loc as usize))
+                    .collect();
+                for child in self.engine.read_many(&children)?.into_iter().flatten() {
+                    self.walk(&child, false, leaf_fn)?;
+                }
+            }
+            Some(Node::Leaf { values, .. }) => leaf_fn(&values),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Fixes the nodes of the tree at root, passing the values of each
+    /// leaf visited to leaf_fn.
+    pub fn fix_tree_with<V, F>(&mut self, root: u64, mut leaf_fn: F) -> Result<()>
+    where
+        V: Unpack,
+        F: FnMut(&[V]),
+    {
+        if root >= self.engine.get_nr_blocks() || self.seen.put(root as usize) {
+            return Ok(());
+        }
+
+        match self.engine.read(root) {
+            Ok(b) => self.walk(&b, true, &mut leaf_fn),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Fixes the nodes of the tree at root
+    pub fn fix_tree<V: Unpack>(&mut self, root: u64) -> Result<()> {
+        self.fix_tree_with::<V, _>(root, |_| {})
+    }
+}
+
+//------------------------------------------
@@ -4,6 +4,7 @@ use data_encoding::BASE64;
 use std::fmt;
 use thiserror::Error;
 
+use crate::io_engine::errors::IoErrorClass;
 use crate::pack::vm;
 
 //------------------------------------------
@@ -311,7 +312,7 @@ fn test_encode_path() {
 
 #[derive(Clone, Debug)]
 pub enum NodeError {
-    IoError,
+    IoError(IoErrorClass),
     NotANode,
     ChecksumError,
     BlockNrMismatch,
@@ -329,7 +330,8 @@ impl fmt::Display for NodeError {
         use NodeError::*;
 
         match self {
-            IoError => write!(f, "io error"),
+            IoError(IoErrorClass::Other) => write!(f, "io error"),
+            IoError(class) => write!(f, "io error, {}", class),
             NotANode => write!(f, "not a btree node"),
             ChecksumError => write!(f, "checksum error"),
             BlockNrMismatch => write!(f, "blocknr mismatch"),
@@ -386,10 +388,12 @@ pub fn node_err(path: &[u64], e: NodeError) -> BTreeError {
     BTreeError::Path(path.to_vec(), Box::new(BTreeError::NodeError(e)))
 }
 
-pub fn io_err(path: &[u64]) -> BTreeError {
+pub fn io_err(path: &[u64], e: &std::io::Error) -> BTreeError {
     BTreeError::Path(
         path.to_vec(),
-        Box::new(BTreeError::NodeError(NodeError::IoError)),
+        Box::new(BTreeError::NodeError(NodeError::IoError(IoErrorClass::of(
+            e,
+        )))),
     )
 }
 
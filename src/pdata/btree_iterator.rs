@@ -69,7 +69,8 @@ impl<V: Unpack + Clone> BTreeIterator<V> {
     fn push_loc(&mut self, loc: u64) -> Result<bool> {
         let is_root = self.stack.is_empty();
         let block = self.engine.read(loc)?;
-        let node = unpack_node::<V>(&self.path, block.get_data(), true, is_root)?;
+        let node = check_and_unpack_node::<V>(&block, true, is_root)
+            .map_err(|e| node_err(&self.path, e))?;
         let done = is_leaf(&node);
         self.push_frame(block, node);
         Ok(done)
@@ -42,6 +42,9 @@ pub struct LeafWalker<'a> {
     sm: &'a mut dyn SpaceMap,
     leaves: FixedBitSet,
     ignore_non_fatal: bool,
+
+    // The key ranges of damaged subtrees, if salvaging
+    lost: Option<Vec<KeyRange>>,
 }
 
 impl<'a> LeafWalker<'a> {
@@ -56,6 +59,31 @@ impl<'a> LeafWalker<'a> {
             sm,
             leaves: FixedBitSet::with_capacity(nr_blocks),
             ignore_non_fatal,
+            lost: None,
+        }
+    }
+
+    // Damaged subtrees below the root are skipped, rather than ending the
+    // walk, with their key ranges kept for take_lost().
+    pub fn salvage(self) -> Self {
+        LeafWalker {
+            lost: Some(Vec::new()),
+            ..self
+        }
+    }
+
+    // Returns the key ranges skipped since the last call
+    pub fn take_lost(&mut self) -> Vec<KeyRange> {
+        self.lost.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn lose(&mut self, kr: &KeyRange, e: BTreeError) -> Result<()> {
+        match self.lost.as_mut() {
+            Some(lost) => {
+                lost.push(kr.clone());
+                Ok(())
+            }
+            None => Err(e),
         }
     }
 
@@ -92,15 +120,20 @@ impl<'a> LeafWalker<'a> {
         let rblocks = self
             .engine
             .read_many(&blocks[0..])
-            .map_err(|_e| io_err(path))?;
+            .map_err(|e| io_err(path, &e))?;
 
         for (i, rb) in rblocks.into_iter().enumerate() {
             match rb {
-                Err(_) => {
-                    return Err(io_err(path).keys_context(&filtered_krs[i]));
+                Err(ioe) => {
+                    let e = io_err(path, &ioe).keys_context(&filtered_krs[i]);
+                    self.lose(&filtered_krs[i], e)?;
                 }
                 Ok(b) => {
-                    self.walk_node(depth - 1, path, visitor, &filtered_krs[i], &b, false)?;
+                    if let Err(e) =
+                        self.walk_node(depth - 1, path, visitor, &filtered_krs[i], &b, false)
+                    {
+                        self.lose(&filtered_krs[i], e)?;
+                    }
                 }
             }
         }
@@ -176,7 +209,7 @@ impl<'a> LeafWalker<'a> {
     fn get_depth<V: Unpack>(&self, path: &mut Vec<u64>, root: u64, is_root: bool) -> Result<usize> {
         use Node::*;
 
-        let b = self.engine.read(root).map_err(|_| io_err(path))?;
+        let b = self.engine.read(root).map_err(|e| io_err(path, &e))?;
 
         let bt = checksum::metadata_block_type(b.get_data());
         if bt != checksum::BT::NODE {
@@ -187,8 +220,16 @@ impl<'a> LeafWalker<'a> {
 
         match node {
             Internal { values, .. } => {
-                let n = self.get_depth::<V>(path, values[0], false)?;
-                Ok(n + 1)
+                // All the children are at the same depth, so when salvaging
+                // any that can be read will do.
+                let mut r = Err(context_err(path, "internal node has no children"));
+                for v in &values {
+                    r = self.get_depth::<V>(path, *v, false);
+                    if r.is_ok() || self.lost.is_none() {
+                        break;
+                    }
+                }
+                Ok(r? + 1)
             }
             Leaf { .. } => Ok(0),
         }
@@ -213,7 +254,7 @@ impl<'a> LeafWalker<'a> {
             visitor.visit(&kr, root)?;
             Ok(())
         } else {
-            let root = self.engine.read(root).map_err(|_| io_err(path))?;
+            let root = self.engine.read(root).map_err(|e| io_err(path, &e))?;
 
             self.walk_node(depth - 1, path, visitor, &kr, &root, true)
         }
@@ -0,0 +1,90 @@
+use anyhow::Result;
+
+use crate::io_engine::*;
+use crate::pdata::btree::*;
+use crate::pdata::btree_error::node_err;
+use crate::pdata::unpack::*;
+
+//------------------------------------------
+
+/// Looks up a single key, reading only the nodes on the path from the root
+/// to the leaf that would hold it.  Each node is checksummed as it is read.
+pub fn btree_lookup<V: Unpack>(engine: &dyn IoEngine, root: u64, key: u64) -> Result<Option<V>> {
+    let mut path = Vec::new();
+    let mut loc = root;
+
+    loop {
+        let b = engine.read(loc)?;
+        let is_root = path.is_empty();
+        path.push(loc);
+        let node =
+            check_and_unpack_node::<V>(&b, false, is_root).map_err(|e| node_err(&path, e))?;
+
+        match node {
+            Node::Internal { keys, values, .. } => match keys.binary_search(&key) {
+                Ok(i) => loc = values[i],
+                Err(0) => return Ok(None),
+                Err(i) => loc = values[i - 1],
+            },
+            Node::Leaf {
+                keys, mut values, ..
+            } => {
+                return Ok(keys.binary_search(&key).ok().map(|i| values.swap_remove(i)));
+            }
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io_engine::core::*;
+    use crate::pdata::btree_builder::test_utils::*;
+    use crate::pdata::space_map::*;
+    use crate::write_batcher::*;
+
+    use std::sync::{Arc, Mutex};
+
+    // Every third key is present, mapped to three times its value
+    fn build_tree(nr_entries: u64) -> (Arc<CoreIoEngine>, u64) {
+        let nr_metadata_blocks = 1024;
+        let engine = Arc::new(CoreIoEngine::new(nr_metadata_blocks));
+        let sm = Arc::new(Mutex::new(CoreSpaceMap::<u8>::new(nr_metadata_blocks)));
+        let mut batcher = WriteBatcher::new(engine.clone(), sm, 16);
+        let values: Vec<(u64, u64)> = (0..nr_entries).map(|i| (i * 3 + 1, i * 9)).collect();
+        let tree = build_btree_from_mappings(&mut batcher, &values);
+        (engine, tree.root().block)
+    }
+
+    fn do_test(nr_entries: u64) -> Result<()> {
+        let (engine, root) = build_tree(nr_entries);
+        for key in 0..nr_entries * 3 + 2 {
+            let expected = if key % 3 == 1 {
+                Some(key * 3 - 3)
+            } else {
+                None
+            };
+            assert_eq!(btree_lookup::<u64>(engine.as_ref(), root, key)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_empty_tree() -> Result<()> {
+        do_test(0)
+    }
+
+    #[test]
+    fn lookup_single_leaf() -> Result<()> {
+        do_test(100)
+    }
+
+    #[test]
+    fn lookup_several_levels() -> Result<()> {
+        do_test(100_000)
+    }
+}
+
+//------------------------------------------
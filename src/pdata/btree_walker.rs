@@ -1,4 +1,5 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use threadpool::ThreadPool;
 
@@ -31,14 +32,92 @@ pub trait NodeVisitor<V: Unpack> {
     fn end_walk(&self) -> Result<()>;
 }
 
+// The order reads are issued in is chosen from the shape of the trees, as
+// seen in the child counts gathered at each level on the way down.  The
+// leaves of shallow, wide trees are prefetched below several internal
+// nodes at once, so they're read in a few large batches.  Deep, narrow
+// trees are walked depth first, which keeps the blocks held to one path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PrefetchOrder {
+    DepthFirst,
+    BreadthFirst,
+}
+
+// Trees of up to this many levels, leaves included, are shallow
+const SHALLOW_DEPTH: usize = 3;
+
+// The mean number of children of the nodes above the leaves of a wide tree
+const WIDE_FANOUT: u64 = 64;
+
+// The most blocks prefetched in one go
+const MAX_PREFETCH: usize = 4096;
+
+// The stats are updated as every node is walked, by all the threads
+// sharing a walker, so they're kept in atomics rather than under a lock.
+#[derive(Default)]
+struct WalkStats {
+    // The internal nodes seen at each level, and their children.  Only
+    // the levels of shallow trees matter to the order.
+    nr_nodes: [AtomicU64; SHALLOW_DEPTH],
+    nr_children: [AtomicU64; SHALLOW_DEPTH],
+
+    // The number of levels, zero until a leaf has been reached
+    depth: AtomicUsize,
+}
+
+impl WalkStats {
+    fn internal(&self, level: usize, nr_children: usize) {
+        if level < SHALLOW_DEPTH {
+            self.nr_nodes[level].fetch_add(1, Ordering::Relaxed);
+            self.nr_children[level].fetch_add(nr_children as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn leaf(&self, level: usize) {
+        self.depth.fetch_max(level + 1, Ordering::Relaxed);
+    }
+
+    // Chooses how to read the children of the nodes at a level.  Nothing
+    // is known of the shape until the first leaf is reached, so the first
+    // descent is always depth first.
+    fn order(&self, level: usize) -> PrefetchOrder {
+        let depth = self.depth.load(Ordering::Relaxed);
+        if depth == 0 {
+            return PrefetchOrder::DepthFirst;
+        }
+
+        // only internal nodes have children worth prefetching
+        if depth > SHALLOW_DEPTH || level + 2 > depth {
+            return PrefetchOrder::DepthFirst;
+        }
+
+        let above_leaves = depth - 2;
+        let n = self.nr_nodes[above_leaves].load(Ordering::Relaxed);
+        let nr_children = self.nr_children[above_leaves].load(Ordering::Relaxed);
+        if n > 0 && nr_children >= n * WIDE_FANOUT {
+            PrefetchOrder::BreadthFirst
+        } else {
+            PrefetchOrder::DepthFirst
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct BTreeWalker {
     engine: Arc<dyn IoEngine + Send + Sync>,
     sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     fails: Arc<Mutex<BTreeMap<u64, BTreeError>>>,
     ignore_non_fatal: bool,
+
+    // The stats are kept across walks, since the trees walked by one
+    // walker, eg. the mappings of each thin, tend to be of the same shape.
+    stats: Arc<WalkStats>,
 }
 
+// The blocks read ahead of a walk reaching them.  Each walk has its own,
+// so walks sharing a walker never take or drop each other's blocks.
+type Prefetched = BTreeMap<u64, Block>;
+
 impl BTreeWalker {
     pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, ignore_non_fatal: bool) -> BTreeWalker {
         let nr_blocks = engine.get_nr_blocks();
@@ -47,6 +126,7 @@ impl BTreeWalker {
             sm: Arc::new(Mutex::new(RestrictedSpaceMap::new(nr_blocks))),
             fails: Arc::new(Mutex::new(BTreeMap::new())),
             ignore_non_fatal,
+            stats: Arc::new(WalkStats::default()),
         };
         r
     }
@@ -66,6 +146,7 @@ impl BTreeWalker {
             sm,
             fails: Arc::new(Mutex::new(BTreeMap::new())),
             ignore_non_fatal,
+            stats: Arc::new(WalkStats::default()),
         })
     }
 
@@ -88,6 +169,82 @@ impl BTreeWalker {
         Ok(count)
     }
 
+    fn prefetch_order(&self, level: usize) -> PrefetchOrder {
+        self.stats.order(level)
+    }
+
+    // Reads the children of a run of internal nodes in one batch, ahead of
+    // walking them.  The nodes are only peeked at here, they're checked
+    // when they're walked.  Returns the number of nodes whose children
+    // were all read.
+    fn prefetch(&self, nodes: &[std::io::Result<Block>], prefetched: &mut Prefetched) -> usize {
+        let mut bs = Vec::new();
+        let mut nr_covered = 0;
+        {
+            let sm = self.sm.lock().unwrap();
+            for n in nodes {
+                let node = n
+                    .as_ref()
+                    .ok()
+                    .and_then(|b| unpack_node_raw::<u64>(b.get_data(), true, false).ok());
+                if let Some(Node::Internal { values, .. }) = node {
+                    if bs.len() + values.len() > MAX_PREFETCH {
+                        break;
+                    }
+
+                    // skip the nodes already seen
+                    bs.extend(
+                        values
+                            .into_iter()
+                            .filter(|b| matches!(sm.get(*b), Ok(0)) && !prefetched.contains_key(b)),
+                    );
+                }
+                nr_covered += 1;
+            }
+        }
+
+        if bs.is_empty() {
+            return nr_covered;
+        }
+
+        if let Ok(rblocks) = self.engine.read_many(&bs) {
+            for b in rblocks.into_iter().flatten() {
+                prefetched.insert(b.loc, b);
+            }
+        }
+        nr_covered
+    }
+
+    // Reads the blocks, taking those that have been prefetched
+    fn read_many(
+        &self,
+        bs: &[u64],
+        prefetched: &mut Prefetched,
+    ) -> std::io::Result<Vec<std::io::Result<Block>>> {
+        let mut cached = Vec::with_capacity(bs.len());
+        let mut missing = Vec::new();
+        for b in bs {
+            let blk = prefetched.remove(b);
+            if blk.is_none() {
+                missing.push(*b);
+            }
+            cached.push(blk);
+        }
+
+        let mut rblocks = if missing.is_empty() {
+            Vec::new().into_iter()
+        } else {
+            self.engine.read_many(&missing)?.into_iter()
+        };
+        Ok(cached
+            .into_iter()
+            .map(|b| match b {
+                Some(b) => Ok(b),
+                None => rblocks.next().unwrap(),
+            })
+            .collect())
+    }
+
     fn build_aggregate(&self, b: u64, errs: Vec<BTreeError>) -> Result<()> {
         match errs.len() {
             0 => Ok(()),
@@ -110,6 +267,8 @@ impl BTreeWalker {
         visitor: &NV,
         krs: &[KeyRange],
         bs: &[u64],
+        level: usize,
+        prefetched: &mut Prefetched,
     ) -> Vec<BTreeError>
     where
         NV: NodeVisitor<V>,
@@ -151,27 +310,42 @@ impl BTreeWalker {
             }
         }
 
-        match self.engine.read_many(&blocks[0..]) {
-            Err(_) => {
+        match self.read_many(&blocks[0..], prefetched) {
+            Err(ioe) => {
                 // IO completely failed, error every block
                 for (i, b) in blocks.iter().enumerate() {
-                    let e = io_err(path).keys_context(&filtered_krs[i]);
+                    let e = io_err(path, &ioe).keys_context(&filtered_krs[i]);
                     errs.push(e.clone());
                     self.set_fail(*b, e);
                 }
             }
             Ok(rblocks) => {
-                for (i, rb) in rblocks.into_iter().enumerate() {
+                // the children of the nodes before this index have been
+                // read, or are left to the first descent
+                let mut prefetched_to = 1;
+
+                for (i, rb) in rblocks.iter().enumerate() {
+                    if i >= prefetched_to
+                        && self.prefetch_order(level) == PrefetchOrder::BreadthFirst
+                    {
+                        prefetched_to = i + self.prefetch(&rblocks[i..], prefetched).max(1);
+                    }
+
                     match rb {
-                        Err(_) => {
-                            let e = io_err(path).keys_context(&filtered_krs[i]);
+                        Err(ioe) => {
+                            let e = io_err(path, &ioe).keys_context(&filtered_krs[i]);
                             errs.push(e.clone());
                             self.set_fail(blocks[i], e);
                         }
                         Ok(b) => {
-                            if let Err(e) =
-                                self.walk_node(path, visitor, &filtered_krs[i], &b, false)
-                            {
+                            if let Err(e) = self.walk_node(
+                                path,
+                                visitor,
+                                &filtered_krs[i],
+                                b,
+                                level,
+                                prefetched,
+                            ) {
                                 errs.push(e);
                             }
                         }
@@ -189,7 +363,8 @@ impl BTreeWalker {
         visitor: &NV,
         kr: &KeyRange,
         b: &Block,
-        is_root: bool,
+        level: usize,
+        prefetched: &mut Prefetched,
     ) -> Result<()>
     where
         NV: NodeVisitor<V>,
@@ -197,7 +372,7 @@ impl BTreeWalker {
     {
         use Node::*;
 
-        let node = match check_and_unpack_node::<V>(b, self.ignore_non_fatal, is_root) {
+        let node = match check_and_unpack_node::<V>(b, self.ignore_non_fatal, level == 0) {
             Ok(n) => n,
             Err(err) => {
                 let e = node_err(path, err).keys_context(kr);
@@ -208,8 +383,9 @@ impl BTreeWalker {
 
         match node {
             Internal { keys, values, .. } => {
+                self.stats.internal(level, values.len());
                 let krs = split_key_ranges(path, kr, &keys)?;
-                let errs = self.walk_nodes(path, visitor, &krs, &values);
+                let errs = self.walk_nodes(path, visitor, &krs, &values, level + 1, prefetched);
                 return self.build_aggregate(b.loc, errs); // implicitly calls set_fail()
             }
             Leaf {
@@ -217,6 +393,7 @@ impl BTreeWalker {
                 keys,
                 values,
             } => {
+                self.stats.leaf(level);
                 if let Err(e) = visitor.visit(path, kr, &header, &keys, &values) {
                     let e = BTreeError::Path(path.clone(), Box::new(e)).keys_context(kr);
                     self.set_fail(b.loc, e.clone());
@@ -234,14 +411,15 @@ impl BTreeWalker {
         visitor: &NV,
         kr: &KeyRange,
         b: &Block,
-        is_root: bool,
+        level: usize,
+        prefetched: &mut Prefetched,
     ) -> Result<()>
     where
         NV: NodeVisitor<V>,
         V: Unpack,
     {
         path.push(b.loc);
-        let r = self.walk_node_(path, visitor, kr, b, is_root);
+        let r = self.walk_node_(path, visitor, kr, b, level, prefetched);
         path.pop();
         r
     }
@@ -258,12 +436,12 @@ impl BTreeWalker {
                 visitor.visit_again(path, root)
             }
         } else {
-            let root = self.engine.read(root).map_err(|_| io_err(path))?;
+            let root = self.engine.read(root).map_err(|e| io_err(path, &e))?;
             let kr = KeyRange {
                 start: None,
                 end: None,
             };
-            self.walk_node(path, visitor, &kr, &root, true)
+            self.walk_node(path, visitor, &kr, &root, 0, &mut Prefetched::new())
         };
 
         if let Err(e) = visitor.end_walk() {
@@ -305,7 +483,7 @@ where
     match node {
         Internal { keys, values, .. } => {
             let krs = split_key_ranges(path, kr, &keys)?;
-            let errs = walk_nodes_threaded(w.clone(), path, pool, visitor, &krs, &values);
+            let errs = walk_nodes_threaded(w.clone(), path, pool, visitor, &krs, &values, 1);
             return w.build_aggregate(b.loc, errs); // implicitly calls set_fail()
         }
         Leaf {
@@ -346,6 +524,7 @@ fn walk_nodes_threaded<NV, V>(
     visitor: Arc<NV>,
     krs: &[KeyRange],
     bs: &[u64],
+    level: usize,
 ) -> Vec<BTreeError>
 where
     NV: NodeVisitor<V> + Send + Sync + 'static,
@@ -388,10 +567,10 @@ where
     }
 
     match w.engine.read_many(&blocks[0..]) {
-        Err(_) => {
+        Err(ioe) => {
             // IO completely failed error every block
             for (i, b) in blocks.iter().enumerate() {
-                let e = io_err(path).keys_context(&filtered_krs[i]);
+                let e = io_err(path, &ioe).keys_context(&filtered_krs[i]);
                 errs.push(e.clone());
                 w.set_fail(*b, e);
             }
@@ -401,8 +580,8 @@ where
 
             for (i, rb) in rblocks.into_iter().enumerate() {
                 match rb {
-                    Err(_) => {
-                        let e = io_err(path).keys_context(&filtered_krs[i]);
+                    Err(ioe) => {
+                        let e = io_err(path, &ioe).keys_context(&filtered_krs[i]);
                         let mut errs = child_errs.lock().unwrap();
                         errs.push(e.clone());
                         w.set_fail(blocks[i], e);
@@ -414,9 +593,17 @@ where
                         let errs = child_errs.clone();
                         let mut path = path.to_vec();
 
+                        // each subtree is walked with a cache of its own
                         pool.execute(move || {
-                            if let Err(e) = w.walk_node(&mut path, visitor.as_ref(), &kr, &b, false)
-                            {
+                            let mut prefetched = Prefetched::new();
+                            if let Err(e) = w.walk_node(
+                                &mut path,
+                                visitor.as_ref(),
+                                &kr,
+                                &b,
+                                level,
+                                &mut prefetched,
+                            ) {
                                 let mut errs = errs.lock().unwrap();
                                 errs.push(e);
                             }
@@ -452,7 +639,7 @@ where
             visitor.visit_again(path, root)
         }
     } else {
-        let root = w.engine.read(root).map_err(|_| io_err(path))?;
+        let root = w.engine.read(root).map_err(|e| io_err(path, &e))?;
         let kr = KeyRange {
             start: None,
             end: None,
@@ -351,3 +351,114 @@ fn walk_tree_with_damaged_leaves_and_internals() {
 }
 
 //------------------------------------------
+
+// Records the blocks read, and the size of each batch
+struct RecordingEngine {
+    inner: CoreIoEngine,
+    reads: Mutex<Vec<u64>>,
+    batches: Mutex<Vec<usize>>,
+}
+
+impl RecordingEngine {
+    fn new(nr_blocks: u64) -> Self {
+        RecordingEngine {
+            inner: CoreIoEngine::new(nr_blocks),
+            reads: Mutex::new(Vec::new()),
+            batches: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl IoEngine for RecordingEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, b: u64) -> std::io::Result<Block> {
+        self.reads.lock().unwrap().push(b);
+        self.inner.read(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> std::io::Result<Vec<std::io::Result<Block>>> {
+        self.reads.lock().unwrap().extend_from_slice(blocks);
+        self.batches.lock().unwrap().push(blocks.len());
+        self.inner.read_many(blocks)
+    }
+
+    fn write(&self, block: &Block) -> std::io::Result<()> {
+        self.inner.write(block)
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> std::io::Result<Vec<std::io::Result<()>>> {
+        self.inner.write_many(blocks)
+    }
+}
+
+#[test]
+fn prefetches_the_leaves_of_a_shallow_wide_tree() {
+    let engine = Arc::new(RecordingEngine::new(2048));
+
+    type ValueType = u32;
+    let mut t = BTreeWalkerTests::<ValueType>::new(engine.clone());
+
+    let nr_entries = 400000;
+    let mappings = (0..nr_entries as u64)
+        .zip(1234u32..1234u32 + nr_entries as u32)
+        .collect::<Vec<(u64, ValueType)>>();
+    t.build_btree(mappings);
+    assert_eq!(t.layout.as_ref().unwrap().height(), 2);
+    engine.reads.lock().unwrap().clear();
+    engine.batches.lock().unwrap().clear();
+
+    t.run();
+
+    // every node is read once ...
+    let mut reads = engine.reads.lock().unwrap().clone();
+    let nr_reads = reads.len();
+    reads.sort_unstable();
+    reads.dedup();
+    assert_eq!(reads.len(), nr_reads);
+    assert_eq!(nr_reads as u64, t.layout.as_ref().unwrap().nr_nodes());
+
+    // ... and the leaves below several internal nodes are read together
+    let max_batch = *engine.batches.lock().unwrap().iter().max().unwrap();
+    assert!(max_batch > calc_max_entries::<u64>());
+}
+
+#[test]
+fn prefetch_order_follows_the_shape_of_the_tree() {
+    let stats = WalkStats::default();
+    assert_eq!(stats.order(1), PrefetchOrder::DepthFirst);
+
+    // shallow and wide
+    stats.internal(0, 4);
+    stats.internal(1, 200);
+    stats.leaf(2);
+    assert_eq!(stats.order(1), PrefetchOrder::BreadthFirst);
+    assert_eq!(stats.order(2), PrefetchOrder::DepthFirst);
+
+    // shallow, but narrow
+    let stats = WalkStats::default();
+    stats.internal(0, 2);
+    stats.internal(1, 10);
+    stats.leaf(2);
+    assert_eq!(stats.order(1), PrefetchOrder::DepthFirst);
+
+    // deep
+    let stats = WalkStats::default();
+    for level in 0..4 {
+        stats.internal(level, 200);
+    }
+    stats.leaf(4);
+    assert_eq!(stats.order(1), PrefetchOrder::DepthFirst);
+}
+
+//------------------------------------------
@@ -0,0 +1,378 @@
+use anyhow::{anyhow, Result};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::checksum;
+use crate::io_engine::*;
+use crate::pdata::btree::*;
+use crate::pdata::btree_walker::btree_to_value_vec;
+use crate::pdata::space_map::common::*;
+use crate::pdata::space_map::disk::DiskSpaceMap;
+use crate::pdata::space_map::metadata::load_metadata_index;
+use crate::pdata::space_map::SpaceMap;
+use crate::pdata::unpack::unpack;
+
+//------------------------------------------
+
+// A map of what each block of the metadata is used for, so the layout and
+// fragmentation of the metadata itself can be studied.  The tools that
+// know the structures of a target claim the blocks they reach from its
+// superblock, and the blocks left over are classed as free or unknown by
+// the metadata space map.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Free,
+    Unknown, // allocated, but not reached from the superblock
+    Superblock,
+    Index,
+    Bitmap,
+    Node,
+}
+
+impl BlockKind {
+    fn as_str(&self) -> &'static str {
+        use BlockKind::*;
+
+        match self {
+            Free => "free",
+            Unknown => "unknown",
+            Superblock => "superblock",
+            Index => "index",
+            Bitmap => "bitmap",
+            Node => "node",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockRole {
+    pub kind: BlockKind,
+    /// The structure the block belongs to, eg. "data_sm"
+    pub structure: &'static str,
+    /// The device first found to use the block, for per device structures
+    pub dev_id: Option<u64>,
+    /// The height of a btree node above the leaves, which are level 0
+    pub level: Option<u32>,
+}
+
+impl BlockRole {
+    pub fn new(kind: BlockKind, structure: &'static str) -> Self {
+        BlockRole {
+            kind,
+            structure,
+            dev_id: None,
+            level: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for LayoutFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(LayoutFormat::Csv),
+            "json" => Ok(LayoutFormat::Json),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
+pub struct MetadataLayout {
+    roles: Vec<Option<BlockRole>>,
+    nr_refs: Vec<u32>, // the times each block was reached
+}
+
+impl MetadataLayout {
+    pub fn new(nr_blocks: u64) -> Self {
+        MetadataLayout {
+            roles: vec![None; nr_blocks as usize],
+            nr_refs: vec![0; nr_blocks as usize],
+        }
+    }
+
+    pub fn nr_blocks(&self) -> u64 {
+        self.roles.len() as u64
+    }
+
+    /// Records the role of a block.  Returns false if the block had already
+    /// been claimed, in which case its first role is kept, so that shared
+    /// structures are only walked once.
+    pub fn claim(&mut self, b: u64, role: BlockRole) -> Result<bool> {
+        let i = b as usize;
+        if i >= self.roles.len() {
+            return Err(anyhow!("block {} is beyond the end of the metadata", b));
+        }
+        self.nr_refs[i] = self.nr_refs[i].saturating_add(1);
+        if self.roles[i].is_some() {
+            return Ok(false);
+        }
+        self.roles[i] = Some(role);
+        Ok(true)
+    }
+
+    pub fn get(&self, b: u64) -> Option<&BlockRole> {
+        self.roles.get(b as usize).and_then(|r| r.as_ref())
+    }
+
+    pub fn nr_refs(&self, b: u64) -> u32 {
+        self.nr_refs.get(b as usize).cloned().unwrap_or(0)
+    }
+
+    /// Classes the unclaimed blocks as free or unknown, by their reference
+    /// counts in the metadata space map.
+    pub fn fill_unclaimed(&mut self, sm: &dyn SpaceMap) -> Result<()> {
+        for b in 0..self.roles.len() {
+            if self.roles[b].is_none() {
+                let kind = if sm.get(b as u64)? == 0 {
+                    BlockKind::Free
+                } else {
+                    BlockKind::Unknown
+                };
+                self.roles[b] = Some(BlockRole::new(kind, ""));
+            }
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+fn read_typed(engine: &dyn IoEngine, b: u64, bt: checksum::BT) -> Option<Block> {
+    let blk = engine.read(b).ok()?;
+    if checksum::metadata_block_type(blk.get_data()) != bt {
+        return None;
+    }
+    Some(blk)
+}
+
+// Returns the level of the node, or None if it couldn't be read
+fn claim_node(
+    engine: &dyn IoEngine,
+    layout: &mut MetadataLayout,
+    b: u64,
+    structure: &'static str,
+    dev_id: Option<u64>,
+) -> Result<Option<u32>> {
+    let role = BlockRole {
+        kind: BlockKind::Node,
+        structure,
+        dev_id,
+        level: None,
+    };
+    if !layout.claim(b, role)? {
+        // already walked, or being walked further up a loop
+        return Ok(layout.get(b).and_then(|r| r.level));
+    }
+
+    let blk = match read_typed(engine, b, checksum::BT::NODE) {
+        Some(blk) => blk,
+        None => return Ok(None),
+    };
+    let header = match unpack::<NodeHeader>(blk.get_data()) {
+        Ok(h) => h,
+        Err(_) => return Ok(None),
+    };
+
+    let level = if header.is_leaf {
+        0
+    } else {
+        let values = match unpack_node_raw::<u64>(blk.get_data(), true, true) {
+            Ok(Node::Internal { values, .. }) => values,
+            _ => return Ok(None),
+        };
+        let mut level = None;
+        for v in values {
+            if let Some(l) = claim_node(engine, layout, v, structure, dev_id)? {
+                level = Some(level.map_or(l, |m: u32| m.max(l)));
+            }
+        }
+        level.map_or(1, |l| l + 1)
+    };
+
+    if let Some(Some(role)) = layout.roles.get_mut(b as usize) {
+        role.level = Some(level);
+    }
+    Ok(Some(level))
+}
+
+/// Claims every node of a btree
+pub fn claim_btree(
+    engine: &dyn IoEngine,
+    layout: &mut MetadataLayout,
+    root: u64,
+    structure: &'static str,
+    dev_id: Option<u64>,
+) -> Result<()> {
+    claim_node(engine, layout, root, structure, dev_id)?;
+    Ok(())
+}
+
+/// Claims the index, bitmaps and ref count tree of a metadata space map
+pub fn claim_metadata_sm(
+    engine: &dyn IoEngine,
+    layout: &mut MetadataLayout,
+    root: &SMRoot,
+) -> Result<()> {
+    layout.claim(
+        root.bitmap_root,
+        BlockRole::new(BlockKind::Index, "metadata_sm"),
+    )?;
+    if let Some(b) = read_typed(engine, root.bitmap_root, checksum::BT::INDEX) {
+        if let Ok(index) = load_metadata_index(&b, root.nr_blocks) {
+            for ie in index.indexes {
+                layout.claim(ie.blocknr, BlockRole::new(BlockKind::Bitmap, "metadata_sm"))?;
+            }
+        }
+    }
+    claim_btree(
+        engine,
+        layout,
+        root.ref_count_root,
+        "metadata_sm_ref_counts",
+        None,
+    )
+}
+
+/// Claims the index tree, bitmaps and ref count tree of a data space map
+pub fn claim_data_sm(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    layout: &mut MetadataLayout,
+    root: &SMRoot,
+) -> Result<()> {
+    claim_btree(
+        engine.as_ref(),
+        layout,
+        root.bitmap_root,
+        "data_sm_index",
+        None,
+    )?;
+    if let Ok(entries) =
+        btree_to_value_vec::<IndexEntry>(&mut vec![0], engine.clone(), true, root.bitmap_root)
+    {
+        for ie in entries {
+            layout.claim(ie.blocknr, BlockRole::new(BlockKind::Bitmap, "data_sm"))?;
+        }
+    }
+    claim_btree(
+        engine.as_ref(),
+        layout,
+        root.ref_count_root,
+        "data_sm_ref_counts",
+        None,
+    )
+}
+
+/// Classes the blocks left over by the metadata space map
+pub fn fill_from_metadata_sm(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    layout: &mut MetadataLayout,
+    root: &SMRoot,
+) -> Result<()> {
+    let sm = DiskSpaceMap::open_metadata(engine, root.clone())?;
+    layout.fill_unclaimed(&sm)
+}
+
+//------------------------------------------
+
+fn opt_to_string<T: ToString>(v: Option<T>, none: &str) -> String {
+    v.map_or_else(|| none.to_string(), |v| v.to_string())
+}
+
+/// Writes a row for each block, after a header naming the columns
+pub fn write_layout_csv(w: &mut dyn Write, layout: &MetadataLayout) -> Result<()> {
+    writeln!(w, "block,kind,structure,dev_id,level,nr_refs")?;
+    for b in 0..layout.nr_blocks() {
+        if let Some(role) = layout.get(b) {
+            writeln!(
+                w,
+                "{},{},{},{},{},{}",
+                b,
+                role.kind.as_str(),
+                role.structure,
+                opt_to_string(role.dev_id, ""),
+                opt_to_string(role.level, ""),
+                layout.nr_refs(b)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes an array with an object for each block
+pub fn write_layout_json(w: &mut dyn Write, layout: &MetadataLayout) -> Result<()> {
+    let rows: Vec<String> = (0..layout.nr_blocks())
+        .filter_map(|b| {
+            layout.get(b).map(|role| {
+                format!(
+                    "{{\"block\": {}, \"kind\": \"{}\", \"structure\": \"{}\", \"dev_id\": {}, \"level\": {}, \"nr_refs\": {}}}",
+                    b,
+                    role.kind.as_str(),
+                    role.structure,
+                    opt_to_string(role.dev_id, "null"),
+                    opt_to_string(role.level, "null"),
+                    layout.nr_refs(b)
+                )
+            })
+        })
+        .collect();
+
+    if rows.is_empty() {
+        writeln!(w, "[]")?;
+        return Ok(());
+    }
+
+    writeln!(w, "[")?;
+    for (i, row) in rows.iter().enumerate() {
+        let sep = if i + 1 < rows.len() { "," } else { "" };
+        writeln!(w, "  {}{}", row, sep)?;
+    }
+    writeln!(w, "]")?;
+    Ok(())
+}
+
+pub fn write_layout(
+    w: &mut dyn Write,
+    layout: &MetadataLayout,
+    format: LayoutFormat,
+) -> Result<()> {
+    match format {
+        LayoutFormat::Csv => write_layout_csv(w, layout),
+        LayoutFormat::Json => write_layout_json(w, layout),
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+
+    #[test]
+    fn blocks_keep_their_first_role() {
+        let mut layout = MetadataLayout::new(4);
+        assert!(layout
+            .claim(1, BlockRole::new(BlockKind::Node, "details"))
+            .unwrap());
+        assert!(!layout
+            .claim(1, BlockRole::new(BlockKind::Bitmap, "data_sm"))
+            .unwrap());
+        assert!(layout
+            .claim(4, BlockRole::new(BlockKind::Index, "data_sm"))
+            .is_err());
+
+        assert_eq!(layout.get(1).unwrap().kind, BlockKind::Node);
+        assert_eq!(layout.nr_refs(1), 2);
+        assert!(layout.get(0).is_none());
+    }
+}
+
+//------------------------------------------
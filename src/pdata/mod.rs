@@ -4,10 +4,14 @@ pub mod array_walker;
 pub mod bitset;
 pub mod btree;
 pub mod btree_builder;
+pub mod btree_checksum;
 pub mod btree_error;
 pub mod btree_iterator;
 pub mod btree_leaf_walker;
+pub mod btree_lookup;
 pub mod btree_merge;
 pub mod btree_walker;
+pub mod layout;
 pub mod space_map;
+pub mod spill_bitset;
 pub mod unpack;
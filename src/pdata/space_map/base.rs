@@ -1,10 +1,11 @@
 use anyhow::{anyhow, Result};
-use fixedbitset::FixedBitSet;
 use num_traits::Bounded;
 use std::boxed::Box;
 use std::convert::{TryFrom, TryInto};
 use std::sync::{Arc, Mutex};
 
+use crate::pdata::spill_bitset::SpillBitSet;
+
 //------------------------------------------
 
 pub trait SpaceMap {
@@ -170,6 +171,17 @@ pub fn core_sm(nr_entries: u64, max_count: u32) -> Arc<Mutex<dyn SpaceMap + Send
     }
 }
 
+/// Returns the number of bytes of reference counts allocated by core_sm()
+pub fn core_sm_size(nr_entries: u64, max_count: u32) -> u64 {
+    if max_count <= u8::MAX as u32 {
+        nr_entries
+    } else if max_count <= u16::MAX as u32 {
+        nr_entries * 2
+    } else {
+        nr_entries * 4
+    }
+}
+
 pub fn core_sm_without_mutex(nr_entries: u64, max_count: u32) -> Box<dyn SpaceMap> {
     if max_count <= u8::MAX as u32 {
         Box::new(CoreSpaceMap::<u8>::new(nr_entries))
@@ -188,18 +200,28 @@ pub fn core_sm_without_mutex(nr_entries: u64, max_count: u32) -> Box<dyn SpaceMa
 pub struct RestrictedSpaceMap {
     nr_allocated: u64,
     alloc_begin: usize,
-    counts: FixedBitSet,
+    counts: SpillBitSet,
 }
 
 impl RestrictedSpaceMap {
     pub fn new(nr_entries: u64) -> RestrictedSpaceMap {
         RestrictedSpaceMap {
             nr_allocated: 0,
-            counts: FixedBitSet::with_capacity(nr_entries as usize),
+            counts: SpillBitSet::with_capacity(nr_entries as usize),
             alloc_begin: 0,
         }
     }
 
+    /// The bits are spilled to a temporary file if they take more than
+    /// core_limit bytes.
+    pub fn with_limit(nr_entries: u64, core_limit: Option<u64>) -> Result<RestrictedSpaceMap> {
+        Ok(RestrictedSpaceMap {
+            nr_allocated: 0,
+            counts: SpillBitSet::with_limit(nr_entries as usize, core_limit)?,
+            alloc_begin: 0,
+        })
+    }
+
     #[inline]
     fn check_index_out_of_bounds(&self, b: u64) -> Result<()> {
         if b >= self.counts.len() as u64 {
@@ -301,14 +323,14 @@ impl SpaceMap for RestrictedSpaceMap {
 pub struct RestrictedTwoSpaceMap {
     nr_allocated: u64,
     alloc_begin: usize,
-    counts: FixedBitSet,
+    counts: SpillBitSet,
 }
 
 impl RestrictedTwoSpaceMap {
     pub fn new(nr_entries: u64) -> RestrictedTwoSpaceMap {
         RestrictedTwoSpaceMap {
             nr_allocated: 0,
-            counts: FixedBitSet::with_capacity((nr_entries << 1) as usize),
+            counts: SpillBitSet::with_capacity((nr_entries << 1) as usize),
             alloc_begin: 0,
         }
     }
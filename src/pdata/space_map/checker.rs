@@ -1,9 +1,11 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use std::io::Cursor;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
 
 use crate::checksum;
 use crate::io_engine::IoEngine;
+use crate::math::div_up;
 use crate::pdata::btree::{self, *};
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::common::*;
@@ -15,8 +17,47 @@ use crate::report::Report;
 //------------------------------------------
 
 pub struct BitmapLeak {
-    blocknr: u64, // blocknr for the first entry in the bitmap
-    loc: u64,     // location of the bitmap
+    blocknr: u64,   // blocknr for the first entry in the bitmap
+    loc: u64,       // location of the bitmap
+    nr_leaked: u64, // number of leaked blocks within the bitmap
+}
+
+pub fn count_leaked_blocks(leaks: &[BitmapLeak]) -> u64 {
+    leaks.iter().map(|l| l.nr_leaked).sum()
+}
+
+//------------------------------------------
+
+/// The number of blocks holding each reference count, gathered while
+/// checking a space map.  Shows how much sharing the snapshots have.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RefCountHistogram {
+    pub nr_single: u64,
+    pub nr_double: u64,
+
+    // Blocks with three or more references
+    pub nr_shared: u64,
+
+    // Blocks whose counts are held in the overflow tree, rather than the
+    // bitmaps.  This matches nr_shared if the space map is consistent.
+    pub nr_overflow: u64,
+
+    pub max_count: u32,
+}
+
+impl RefCountHistogram {
+    fn add(&mut self, count: u32, overflow: bool) {
+        match count {
+            0 => {}
+            1 => self.nr_single += 1,
+            2 => self.nr_double += 1,
+            _ => self.nr_shared += 1,
+        }
+        if overflow {
+            self.nr_overflow += 1;
+        }
+        self.max_count = std::cmp::max(self.max_count, count);
+    }
 }
 
 //------------------------------------------
@@ -79,12 +120,14 @@ fn inc_entries(sm: &ASpaceMap, entries: &[IndexEntry]) -> Result<()> {
 // Compare the reference counts in bitmaps against the expected values
 //
 // `sm` - The in-core space map of expected reference counts
+// `histogram` - Accumulates the expected reference counts, if given
 fn check_low_ref_counts(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
     kind: &str,
     entries: Vec<IndexEntry>,
     sm: ASpaceMap,
+    mut histogram: Option<&mut RefCountHistogram>,
 ) -> Result<Vec<BitmapLeak>> {
     // gathering bitmap blocknr
     let mut blocks = Vec::with_capacity(entries.len());
@@ -121,7 +164,7 @@ fn check_low_ref_counts(
 
                 let bitmap = unpack::<Bitmap>(b.get_data())?;
                 let first_blocknr = blocknr;
-                let mut contains_leak = false;
+                let mut nr_leaked = 0;
                 for e in bitmap.entries.iter() {
                     if blocknr >= nr_blocks {
                         break;
@@ -130,9 +173,12 @@ fn check_low_ref_counts(
                     match e {
                         BitmapEntry::Small(actual) => {
                             let expected = sm.get(blocknr)?;
+                            if let Some(h) = histogram.as_deref_mut() {
+                                h.add(expected, false);
+                            }
                             if *actual == 1 && expected == 0 {
                                 leaks += 1;
-                                contains_leak = true;
+                                nr_leaked += 1;
                             } else if *actual != expected as u8 {
                                 report.fatal(&format!("Bad reference count for {} block {}.  Expected {}, but space map contains {}.",
                                           kind, blocknr, expected, actual));
@@ -141,6 +187,9 @@ fn check_low_ref_counts(
                         }
                         BitmapEntry::Overflow => {
                             let expected = sm.get(blocknr)?;
+                            if let Some(h) = histogram.as_deref_mut() {
+                                h.add(expected, true);
+                            }
                             if expected < 3 {
                                 report.fatal(&format!("Bad reference count for {} block {}.  Expected {}, but space map says it's >= 3.",
                                                   kind, blocknr, expected));
@@ -150,10 +199,11 @@ fn check_low_ref_counts(
                     }
                     blocknr += 1;
                 }
-                if contains_leak {
+                if nr_leaked > 0 {
                     bitmap_leaks.push(BitmapLeak {
                         blocknr: first_blocknr,
                         loc: b.loc,
+                        nr_leaked,
                     });
                 }
             }
@@ -211,6 +261,7 @@ fn gather_metadata_index_entries(
 //
 // `disk_sm` - The in-core space map of expected data block ref-counts
 // `metadata_sm` - The in-core space for storing ref-counts of verified blocks
+// `histogram` - Filled in with the data block ref-counts, if given
 pub fn check_disk_space_map(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
@@ -218,6 +269,7 @@ pub fn check_disk_space_map(
     disk_sm: ASpaceMap,
     metadata_sm: ASpaceMap,
     ignore_non_fatal: bool,
+    histogram: Option<&mut RefCountHistogram>,
 ) -> Result<Vec<BitmapLeak>> {
     let entries = gather_disk_index_entries(
         engine.clone(),
@@ -235,19 +287,15 @@ pub fn check_disk_space_map(
     }
 
     // check low ref-counts in bitmaps
-    check_low_ref_counts(engine, report, "data", entries, disk_sm)
+    check_low_ref_counts(engine, report, "data", entries, disk_sm, histogram)
 }
 
-// This checks the space map and returns any leak blocks for auto-repair to process.
-//
-// `metadata_sm`: The in-core space map of expected metadata block ref-counts
-pub fn check_metadata_space_map(
+// Counts the blocks of the metadata space map itself, and reads its index.
+fn read_metadata_space_map(
     engine: Arc<dyn IoEngine + Send + Sync>,
-    report: Arc<Report>,
-    root: SMRoot,
+    root: &SMRoot,
     metadata_sm: ASpaceMap,
-    ignore_non_fatal: bool,
-) -> Result<Vec<BitmapLeak>> {
+) -> Result<Vec<IndexEntry>> {
     count_btree_blocks::<u32>(
         engine.clone(),
         &mut vec![0],
@@ -256,13 +304,19 @@ pub fn check_metadata_space_map(
         false,
     )?;
 
-    let entries = gather_metadata_index_entries(
-        engine.clone(),
-        root.bitmap_root,
-        root.nr_blocks,
-        metadata_sm.clone(),
-    )?;
+    gather_metadata_index_entries(engine, root.bitmap_root, root.nr_blocks, metadata_sm)
+}
 
+// Compares the ref-counts of the metadata space map against the expected
+// values, which must be complete by now.
+fn compare_metadata_space_map(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    root: &SMRoot,
+    entries: Vec<IndexEntry>,
+    metadata_sm: ASpaceMap,
+    ignore_non_fatal: bool,
+) -> Result<Vec<BitmapLeak>> {
     // check overflow ref-counts
     {
         let sm = metadata_sm.lock().unwrap();
@@ -272,7 +326,166 @@ pub fn check_metadata_space_map(
     }
 
     // check low ref-counts in bitmaps
-    check_low_ref_counts(engine, report, "metadata", entries, metadata_sm)
+    check_low_ref_counts(engine, report, "metadata", entries, metadata_sm, None)
+}
+
+// This checks the space map and returns any leak blocks for auto-repair to process.
+//
+// `metadata_sm`: The in-core space map of expected metadata block ref-counts
+pub fn check_metadata_space_map(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    root: SMRoot,
+    metadata_sm: ASpaceMap,
+    ignore_non_fatal: bool,
+) -> Result<Vec<BitmapLeak>> {
+    let entries = read_metadata_space_map(engine.clone(), &root, metadata_sm.clone())?;
+    compare_metadata_space_map(
+        engine,
+        report,
+        &root,
+        entries,
+        metadata_sm,
+        ignore_non_fatal,
+    )
+}
+
+// Checks both the data and metadata space maps, returning the leaked blocks
+// of each for auto-repair to process.
+//
+// The two checks are pipelined: the metadata space map's ref count tree and
+// index are read while the data space map is checked.  Only the comparison
+// of the metadata ref-counts has to wait, as the blocks of the data space
+// map count towards them.  Each side is checked as by check_disk_space_map()
+// and check_metadata_space_map().
+//
+// `data_sm` - The in-core space map of expected data block ref-counts
+// `metadata_sm` - The in-core space map of expected metadata block ref-counts
+// `histogram` - Filled in with the data block ref-counts, if given
+#[allow(clippy::too_many_arguments)]
+pub fn check_space_maps(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    data_root: SMRoot,
+    metadata_root: SMRoot,
+    data_sm: ASpaceMap,
+    metadata_sm: ASpaceMap,
+    ignore_non_fatal: bool,
+    histogram: Option<&mut RefCountHistogram>,
+) -> Result<(Vec<BitmapLeak>, Vec<BitmapLeak>)> {
+    let (data_counted, wait_for_data) = mpsc::channel::<()>();
+
+    let metadata_checker = {
+        let engine = engine.clone();
+        let report = report.clone();
+        let metadata_sm = metadata_sm.clone();
+        thread::spawn(move || -> Result<Vec<BitmapLeak>> {
+            let entries =
+                read_metadata_space_map(engine.clone(), &metadata_root, metadata_sm.clone())?;
+
+            wait_for_data
+                .recv()
+                .map_err(|_| anyhow!("the data space map couldn't be checked"))?;
+
+            compare_metadata_space_map(
+                engine,
+                report,
+                &metadata_root,
+                entries,
+                metadata_sm,
+                ignore_non_fatal,
+            )
+        })
+    };
+
+    let data_leaks = check_disk_space_map(
+        engine,
+        report,
+        data_root,
+        data_sm,
+        metadata_sm,
+        ignore_non_fatal,
+        histogram,
+    );
+    if data_leaks.is_ok() {
+        let _ = data_counted.send(());
+    }
+
+    // Wake the metadata checker if the data check failed
+    drop(data_counted);
+    let metadata_leaks = metadata_checker
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("the metadata space map checker panicked")));
+
+    Ok((
+        data_leaks.context("data space map")?,
+        metadata_leaks.context("metadata space map")?,
+    ))
+}
+
+// Checks the structure of a space map, ie. the index, the bitmaps and the
+// overflow ref count tree, without verifying the ref counts themselves.
+// This needs no in-core space map, so is quick even on huge pools.
+pub fn check_space_map_structure(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    kind: &str,
+    root: SMRoot,
+    is_metadata: bool,
+    ignore_non_fatal: bool,
+) -> Result<()> {
+    let entries: Vec<IndexEntry> = if is_metadata {
+        let b = engine.read(root.bitmap_root)?;
+        load_metadata_index(&b, root.nr_blocks)?.indexes
+    } else {
+        btree_to_map::<IndexEntry>(
+            &mut vec![0],
+            engine.clone(),
+            ignore_non_fatal,
+            root.bitmap_root,
+        )?
+        .into_values()
+        .collect()
+    };
+
+    let nr_bitmaps = div_up(root.nr_blocks, ENTRIES_PER_BITMAP as u64);
+    if (entries.len() as u64) < nr_bitmaps {
+        return Err(anyhow!(
+            "{} space map index has {} entries, expected {}",
+            kind,
+            entries.len(),
+            nr_bitmaps
+        ));
+    }
+
+    let blocks: Vec<u64> = entries
+        .iter()
+        .take(nr_bitmaps as usize)
+        .map(|ie| ie.blocknr)
+        .collect();
+    let mut failed = false;
+    for chunk in blocks.chunks(1024) {
+        for (loc, b) in chunk.iter().zip(engine.read_many(chunk)?) {
+            let is_bitmap = b.map_or(false, |b| {
+                checksum::metadata_block_type(b.get_data()) == checksum::BT::BITMAP
+            });
+            if !is_bitmap {
+                report.fatal(&format!(
+                    "Index entry points to block ({}) that isn't a bitmap",
+                    loc
+                ));
+                failed = true;
+            }
+        }
+    }
+
+    btree_to_map::<u32>(&mut vec![0], engine, ignore_non_fatal, root.ref_count_root)?;
+
+    if failed {
+        Err(anyhow!("Fatal errors in {} space map", kind))
+    } else {
+        Ok(())
+    }
 }
 
 // This assumes the only errors in the space map are leaks.  Entries should just be
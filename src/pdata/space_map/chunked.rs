@@ -0,0 +1,302 @@
+use anyhow::{anyhow, Result};
+use num_traits::Bounded;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use crate::file_utils;
+use crate::pdata::space_map::*;
+
+//------------------------------------------
+
+// A space map that keeps its reference counts in an unlinked temporary
+// file, caching a bounded number of fixed sized chunks in memory.  This
+// trades speed for memory, allowing tools to process pools whose in-core
+// space maps wouldn't fit in RAM.
+
+const ENTRIES_PER_CHUNK_SHIFT: u64 = 18;
+const ENTRIES_PER_CHUNK: u64 = 1 << ENTRIES_PER_CHUNK_SHIFT;
+
+struct Chunk<V> {
+    counts: Vec<V>,
+    last_used: u64,
+    dirty: bool,
+}
+
+pub struct ChunkedSpaceMap<V> {
+    nr_blocks: u64,
+    nr_allocated: u64,
+    alloc_begin: u64,
+    max_chunks: usize,
+    tick: u64,
+    file: File,
+    chunks: HashMap<u64, Chunk<V>>,
+}
+
+fn as_bytes<V>(v: &[V]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, std::mem::size_of_val(v)) }
+}
+
+fn as_bytes_mut<V>(v: &mut [V]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(v.as_mut_ptr() as *mut u8, std::mem::size_of_val(v)) }
+}
+
+impl<V> ChunkedSpaceMap<V>
+where
+    V: Copy + Default,
+{
+    /// Creates a space map that holds at most max_memory bytes of counts
+    /// in core.  At least one chunk is always cached.
+    pub fn new(nr_entries: u64, max_memory: usize) -> Result<ChunkedSpaceMap<V>> {
+        let entry_size = std::mem::size_of::<V>() as u64;
+        let file = file_utils::create_unlinked_file(&std::env::temp_dir())?;
+
+        // The file is sparse, so unwritten entries read back as zeroes.
+        file.set_len(nr_entries * entry_size)?;
+
+        let chunk_size = (ENTRIES_PER_CHUNK * entry_size) as usize;
+        let max_chunks = std::cmp::max(1, max_memory / chunk_size);
+
+        Ok(ChunkedSpaceMap {
+            nr_blocks: nr_entries,
+            nr_allocated: 0,
+            alloc_begin: 0,
+            max_chunks,
+            tick: 0,
+            file,
+            chunks: HashMap::new(),
+        })
+    }
+
+    #[inline]
+    fn check_index_out_of_bounds(&self, b: u64) -> Result<()> {
+        if b >= self.nr_blocks {
+            return Err(anyhow!("block out of bounds"));
+        }
+        Ok(())
+    }
+
+    fn chunk_len(&self, index: u64) -> usize {
+        let begin = index << ENTRIES_PER_CHUNK_SHIFT;
+        std::cmp::min(ENTRIES_PER_CHUNK, self.nr_blocks - begin) as usize
+    }
+
+    fn chunk_offset(index: u64) -> u64 {
+        (index << ENTRIES_PER_CHUNK_SHIFT) * std::mem::size_of::<V>() as u64
+    }
+
+    fn write_back(file: &File, index: u64, chunk: &Chunk<V>) -> Result<()> {
+        file.write_all_at(as_bytes(&chunk.counts), Self::chunk_offset(index))?;
+        Ok(())
+    }
+
+    fn evict_one(&mut self) -> Result<()> {
+        let victim = self
+            .chunks
+            .iter()
+            .min_by_key(|(_, c)| c.last_used)
+            .map(|(index, _)| *index);
+
+        if let Some(index) = victim {
+            let chunk = self.chunks.remove(&index).unwrap();
+            if chunk.dirty {
+                Self::write_back(&self.file, index, &chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_chunk_mut(&mut self, index: u64) -> Result<&mut Chunk<V>> {
+        self.tick += 1;
+
+        if !self.chunks.contains_key(&index) {
+            if self.chunks.len() >= self.max_chunks {
+                self.evict_one()?;
+            }
+
+            let mut counts = vec![V::default(); self.chunk_len(index)];
+            self.file
+                .read_exact_at(as_bytes_mut(&mut counts), Self::chunk_offset(index))?;
+            self.chunks.insert(
+                index,
+                Chunk {
+                    counts,
+                    last_used: 0,
+                    dirty: false,
+                },
+            );
+        }
+
+        let chunk = self.chunks.get_mut(&index).unwrap();
+        chunk.last_used = self.tick;
+        Ok(chunk)
+    }
+
+    /// Writes all dirty chunks back to the backing file.
+    pub fn flush(&mut self) -> Result<()> {
+        for (index, chunk) in self.chunks.iter_mut() {
+            if chunk.dirty {
+                Self::write_back(&self.file, *index, chunk)?;
+                chunk.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<V> SpaceMap for ChunkedSpaceMap<V>
+where
+    V: Copy
+        + Default
+        + Eq
+        + std::ops::AddAssign
+        + From<u8>
+        + Into<u32>
+        + Bounded
+        + TryFrom<u32>
+        + std::cmp::PartialOrd,
+    <V as TryFrom<u32>>::Error: std::fmt::Debug,
+{
+    fn get_nr_blocks(&self) -> Result<u64> {
+        Ok(self.nr_blocks)
+    }
+
+    fn get_nr_allocated(&self) -> Result<u64> {
+        Ok(self.nr_allocated)
+    }
+
+    fn get(&self, b: u64) -> Result<u32> {
+        self.check_index_out_of_bounds(b)?;
+
+        let index = b >> ENTRIES_PER_CHUNK_SHIFT;
+        let offset = (b & (ENTRIES_PER_CHUNK - 1)) as usize;
+        if let Some(chunk) = self.chunks.get(&index) {
+            return Ok(chunk.counts[offset].into());
+        }
+
+        // Read the single entry rather than caching a chunk, since we
+        // only have a shared reference.
+        let mut v = [V::default(); 1];
+        self.file
+            .read_exact_at(as_bytes_mut(&mut v), b * std::mem::size_of::<V>() as u64)?;
+        Ok(v[0].into())
+    }
+
+    fn set(&mut self, b: u64, v: u32) -> Result<u32> {
+        self.check_index_out_of_bounds(b)?;
+        assert!(v <= V::max_value().into());
+
+        let index = b >> ENTRIES_PER_CHUNK_SHIFT;
+        let offset = (b & (ENTRIES_PER_CHUNK - 1)) as usize;
+        let chunk = self.get_chunk_mut(index)?;
+        let old: u32 = chunk.counts[offset].into();
+        chunk.counts[offset] = v.try_into().unwrap(); // FIXME: do not panic
+        chunk.dirty = true;
+
+        if old == 0 && v != 0 {
+            self.nr_allocated += 1;
+        } else if old != 0 && v == 0 {
+            self.nr_allocated -= 1;
+        }
+
+        Ok(old)
+    }
+
+    fn inc(&mut self, begin: u64, len: u64) -> Result<()> {
+        if begin + len > self.nr_blocks {
+            return Err(anyhow!("block out of bounds"));
+        }
+
+        let end = begin + len;
+        let mut b = begin;
+        let mut nr_new = 0;
+        while b < end {
+            let index = b >> ENTRIES_PER_CHUNK_SHIFT;
+            let chunk_end = std::cmp::min((index + 1) << ENTRIES_PER_CHUNK_SHIFT, end);
+            let base = index << ENTRIES_PER_CHUNK_SHIFT;
+
+            let chunk = self.get_chunk_mut(index)?;
+            for i in (b - base)..(chunk_end - base) {
+                let c = &mut chunk.counts[i as usize];
+                assert!(*c < V::max_value());
+                if *c == V::from(0u8) {
+                    nr_new += 1;
+                    *c = V::from(1u8);
+                } else {
+                    *c += V::from(1u8);
+                }
+            }
+            chunk.dirty = true;
+
+            b = chunk_end;
+        }
+        self.nr_allocated += nr_new;
+
+        Ok(())
+    }
+
+    fn alloc(&mut self) -> Result<Option<u64>> {
+        let mut b = self.find_free(self.alloc_begin, self.nr_blocks)?;
+        if b.is_none() {
+            b = self.find_free(0, self.alloc_begin)?;
+            if b.is_none() {
+                return Ok(None);
+            }
+        }
+
+        self.set(b.unwrap(), 1)?;
+        self.alloc_begin = b.unwrap() + 1;
+
+        Ok(b)
+    }
+
+    fn find_free(&mut self, begin: u64, end: u64) -> Result<Option<u64>> {
+        let mut b = begin;
+        while b < end {
+            let index = b >> ENTRIES_PER_CHUNK_SHIFT;
+            let chunk_end = std::cmp::min((index + 1) << ENTRIES_PER_CHUNK_SHIFT, end);
+            let base = index << ENTRIES_PER_CHUNK_SHIFT;
+
+            let chunk = self.get_chunk_mut(index)?;
+            for i in (b - base)..(chunk_end - base) {
+                if chunk.counts[i as usize] == V::from(0u8) {
+                    return Ok(Some(base + i));
+                }
+            }
+
+            b = chunk_end;
+        }
+        Ok(None)
+    }
+
+    fn get_alloc_begin(&self) -> Result<u64> {
+        Ok(self.alloc_begin)
+    }
+}
+
+/// Creates a disk backed space map that caches at most max_memory bytes
+/// of reference counts in core.
+pub fn chunked_sm(
+    nr_entries: u64,
+    max_count: u32,
+    max_memory: usize,
+) -> Result<Arc<Mutex<dyn SpaceMap + Send + Sync>>> {
+    let sm: Arc<Mutex<dyn SpaceMap + Send + Sync>> = if max_count <= u8::MAX as u32 {
+        Arc::new(Mutex::new(ChunkedSpaceMap::<u8>::new(
+            nr_entries, max_memory,
+        )?))
+    } else if max_count <= u16::MAX as u32 {
+        Arc::new(Mutex::new(ChunkedSpaceMap::<u16>::new(
+            nr_entries, max_memory,
+        )?))
+    } else {
+        Arc::new(Mutex::new(ChunkedSpaceMap::<u32>::new(
+            nr_entries, max_memory,
+        )?))
+    };
+    Ok(sm)
+}
+
+//------------------------------------------
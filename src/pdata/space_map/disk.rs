@@ -1,8 +1,16 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
+use crate::checksum;
+use crate::io_engine::*;
+use crate::pdata::btree::*;
 use crate::pdata::btree_builder::*;
+use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::common::*;
+use crate::pdata::space_map::metadata::*;
 use crate::pdata::space_map::*;
+use crate::pdata::unpack::*;
 use crate::write_batcher::*;
 
 //------------------------------------------
@@ -27,3 +35,187 @@ pub fn write_disk_sm(w: &mut WriteBatcher, sm: &dyn SpaceMap) -> Result<SMRoot>
 }
 
 //------------------------------------------
+
+// The number of bitmaps kept in memory by default, each covering
+// ENTRIES_PER_BITMAP blocks.
+const DEFAULT_CACHED_BITMAPS: usize = 16;
+
+// Bitmaps in most recently used order
+struct BitmapCache {
+    capacity: usize,
+    bitmaps: VecDeque<(usize, Arc<Bitmap>)>,
+}
+
+impl BitmapCache {
+    fn get(&mut self, index: usize) -> Option<Arc<Bitmap>> {
+        let pos = self.bitmaps.iter().position(|(i, _)| *i == index)?;
+        let entry = self.bitmaps.remove(pos).unwrap();
+        let bitmap = entry.1.clone();
+        self.bitmaps.push_front(entry);
+        Some(bitmap)
+    }
+
+    fn insert(&mut self, index: usize, bitmap: Arc<Bitmap>) {
+        if self.bitmaps.len() >= self.capacity {
+            self.bitmaps.pop_back();
+        }
+        self.bitmaps.push_front((index, bitmap));
+    }
+}
+
+/// A read only view of an on-disk space map.
+///
+/// Only the index is read up front.  Bitmaps are read as reference counts
+/// are queried, and a few are cached, so tools that look up the odd block
+/// don't pay for loading the whole space map.  Counts above two are looked
+/// up in the ref count tree.
+pub struct DiskSpaceMap {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: SMRoot,
+    entries: Vec<IndexEntry>,
+    cache: Mutex<BitmapCache>,
+}
+
+impl DiskSpaceMap {
+    fn new(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        root: SMRoot,
+        entries: Vec<IndexEntry>,
+        nr_cached: usize,
+    ) -> Self {
+        DiskSpaceMap {
+            engine,
+            root,
+            entries,
+            cache: Mutex::new(BitmapCache {
+                capacity: std::cmp::max(nr_cached, 1),
+                bitmaps: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Opens a data space map, whose index is a btree
+    pub fn open_data(engine: Arc<dyn IoEngine + Send + Sync>, root: SMRoot) -> Result<Self> {
+        let entries = btree_to_value_vec::<IndexEntry>(
+            &mut vec![0],
+            engine.clone(),
+            false,
+            root.bitmap_root,
+        )?;
+        Ok(Self::new(engine, root, entries, DEFAULT_CACHED_BITMAPS))
+    }
+
+    /// Opens a metadata space map, whose index is a single block
+    pub fn open_metadata(engine: Arc<dyn IoEngine + Send + Sync>, root: SMRoot) -> Result<Self> {
+        let b = engine.read(root.bitmap_root)?;
+        let entries = load_metadata_index(&b, root.nr_blocks)?.indexes;
+        Ok(Self::new(engine, root, entries, DEFAULT_CACHED_BITMAPS))
+    }
+
+    /// Changes the number of bitmaps kept in memory
+    pub fn with_cache_size(self, nr_cached: usize) -> Self {
+        Self::new(self.engine, self.root, self.entries, nr_cached)
+    }
+
+    fn read_bitmap(&self, index: usize) -> Result<Arc<Bitmap>> {
+        if let Some(bitmap) = self.cache.lock().unwrap().get(index) {
+            return Ok(bitmap);
+        }
+
+        let ie = self
+            .entries
+            .get(index)
+            .ok_or_else(|| anyhow!("no index entry for bitmap {}", index))?;
+        let b = self.engine.read(ie.blocknr)?;
+        if checksum::metadata_block_type(b.get_data()) != checksum::BT::BITMAP {
+            return Err(anyhow!(
+                "index entry points to block ({}) that isn't a bitmap",
+                b.loc
+            ));
+        }
+
+        let bitmap = Arc::new(unpack::<Bitmap>(b.get_data())?);
+        self.cache.lock().unwrap().insert(index, bitmap.clone());
+        Ok(bitmap)
+    }
+
+    fn lookup_overflow(&self, b: u64) -> Result<u32> {
+        let mut loc = self.root.ref_count_root;
+        let mut path = vec![0];
+        loop {
+            let node = self.engine.read(loc)?;
+            if checksum::metadata_block_type(node.get_data()) != checksum::BT::NODE {
+                return Err(anyhow!("ref count tree node {} failed its checksum", loc));
+            }
+
+            let is_root = loc == self.root.ref_count_root;
+            match unpack_node::<u32>(&path, node.get_data(), true, is_root)? {
+                Node::Internal { keys, values, .. } => {
+                    let i = match keys.binary_search(&b) {
+                        Ok(i) => i,
+                        Err(0) => break,
+                        Err(i) => i - 1,
+                    };
+                    path.push(values[i]);
+                    loc = values[i];
+                }
+                Node::Leaf { keys, values, .. } => {
+                    if let Ok(i) = keys.binary_search(&b) {
+                        return Ok(values[i]);
+                    }
+                    break;
+                }
+            }
+        }
+
+        Err(anyhow!("ref count of block {} is missing from the tree", b))
+    }
+
+    fn read_only<T>(&self) -> Result<T> {
+        Err(anyhow!("the on-disk space map is read only"))
+    }
+}
+
+impl SpaceMap for DiskSpaceMap {
+    fn get_nr_blocks(&self) -> Result<u64> {
+        Ok(self.root.nr_blocks)
+    }
+
+    fn get_nr_allocated(&self) -> Result<u64> {
+        Ok(self.root.nr_allocated)
+    }
+
+    fn get(&self, b: u64) -> Result<u32> {
+        if b >= self.root.nr_blocks {
+            return Err(anyhow!("block out of bounds"));
+        }
+
+        let bitmap = self.read_bitmap((b / ENTRIES_PER_BITMAP as u64) as usize)?;
+        match bitmap.entries[(b % ENTRIES_PER_BITMAP as u64) as usize] {
+            BitmapEntry::Small(count) => Ok(count as u32),
+            BitmapEntry::Overflow => self.lookup_overflow(b),
+        }
+    }
+
+    fn set(&mut self, _b: u64, _v: u32) -> Result<u32> {
+        self.read_only()
+    }
+
+    fn inc(&mut self, _begin: u64, _len: u64) -> Result<()> {
+        self.read_only()
+    }
+
+    fn alloc(&mut self) -> Result<Option<u64>> {
+        self.read_only()
+    }
+
+    fn find_free(&mut self, _begin: u64, _end: u64) -> Result<Option<u64>> {
+        self.read_only()
+    }
+
+    fn get_alloc_begin(&self) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+//------------------------------------------
@@ -1,6 +1,7 @@
 pub mod allocated_blocks;
 pub mod base;
 pub mod checker;
+pub mod chunked;
 pub mod common;
 pub mod disk;
 pub mod metadata;
@@ -153,6 +153,73 @@ mod core_sm_u8 {
 
 //------------------------------------------
 
+mod chunked_sm_u8 {
+    use super::*;
+    use crate::pdata::space_map::chunked::*;
+
+    // spans several chunks, with only one of them cached
+    const NR_BLOCKS: u64 = 589824;
+    const MAX_MEMORY: usize = 0;
+
+    fn mk_sm() -> ChunkedSpaceMap<u8> {
+        ChunkedSpaceMap::<u8>::new(NR_BLOCKS, MAX_MEMORY).unwrap()
+    }
+
+    #[test]
+    fn get_nr_blocks() {
+        let sm = mk_sm();
+        tests::test_get_nr_blocks(&sm, NR_BLOCKS);
+    }
+
+    #[test]
+    fn get_nr_allocated() {
+        let mut sm = mk_sm();
+        tests::test_get_nr_allocated(&mut sm);
+    }
+
+    #[test]
+    fn runs_out_of_space() {
+        let mut sm = mk_sm();
+        tests::test_runs_out_of_space(&mut sm);
+    }
+
+    #[test]
+    fn inc_and_dec() {
+        let mut sm = mk_sm();
+        tests::test_inc_and_dec(&mut sm);
+    }
+
+    #[test]
+    fn not_allocated_twice() {
+        let mut sm = mk_sm();
+        tests::test_not_allocated_twice(&mut sm);
+    }
+
+    #[test]
+    fn set_affects_nr_allocated() {
+        let mut sm = mk_sm();
+        tests::test_set_affects_nr_allocated(&mut sm);
+    }
+
+    #[test]
+    fn wraparound_allocation() {
+        let mut sm = mk_sm();
+        tests::test_wraparound_allocation(&mut sm);
+    }
+
+    #[test]
+    fn counts_survive_eviction() {
+        let mut sm = mk_sm();
+        sm.inc(0, NR_BLOCKS).unwrap();
+        sm.inc(NR_BLOCKS - 10, 10).unwrap();
+        assert_eq!(sm.get(0).unwrap(), 1);
+        assert_eq!(sm.get(NR_BLOCKS - 1).unwrap(), 2);
+        assert_eq!(sm.get_nr_allocated().unwrap(), NR_BLOCKS);
+    }
+}
+
+//------------------------------------------
+
 mod metadata_sm {
     use anyhow::{ensure, Result};
     use std::sync::Arc;
@@ -276,6 +343,205 @@ mod disk_sm {
     fn check_multiple_index_entries() -> Result<()> {
         check_index_entries(ENTRIES_PER_BITMAP as u64 * 16 + 1000)
     }
+
+    fn check_lazy_data_sm(nr_cached: usize) -> Result<()> {
+        let engine = Arc::new(CoreIoEngine::new(1024));
+        let meta_sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
+
+        let mut w = WriteBatcher::new(engine.clone(), meta_sm, engine.get_batch_size());
+        w.alloc()?; // reserved for the superblock
+
+        // spread the counts across several bitmaps, with some overflowing
+        let nr_blocks = ENTRIES_PER_BITMAP as u64 * 4 + 1000;
+        let data_sm = core_sm(nr_blocks, u32::MAX);
+        {
+            let mut sm = data_sm.lock().unwrap();
+            for b in (0..nr_blocks).step_by(97) {
+                sm.set(b, (b % 7) as u32)?;
+            }
+            sm.set(nr_blocks - 1, 1000)?;
+        }
+
+        let root = write_disk_sm(&mut w, data_sm.lock().unwrap().deref())?;
+        drop(w);
+
+        let disk_sm = DiskSpaceMap::open_data(engine, root)?.with_cache_size(nr_cached);
+        let sm = data_sm.lock().unwrap();
+        ensure!(disk_sm.get_nr_blocks()? == nr_blocks);
+        ensure!(disk_sm.get_nr_allocated()? == sm.get_nr_allocated()?);
+
+        // visit the bitmaps out of order, so the cache has to evict some
+        let mut blocks: Vec<u64> = (0..nr_blocks).step_by(97).collect();
+        blocks.reverse();
+        blocks.extend([nr_blocks - 1, 1, 0]);
+        for b in blocks {
+            ensure!(disk_sm.get(b)? == sm.get(b)?);
+        }
+        ensure!(disk_sm.get(nr_blocks).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn lazy_data_sm_matches_counts() -> Result<()> {
+        check_lazy_data_sm(16)
+    }
+
+    #[test]
+    fn lazy_data_sm_with_a_single_cached_bitmap() -> Result<()> {
+        check_lazy_data_sm(1)
+    }
+
+    #[test]
+    fn lazy_metadata_sm_matches_counts() -> Result<()> {
+        let engine = Arc::new(CoreIoEngine::new(1024));
+        let meta_sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
+
+        let mut w = WriteBatcher::new(engine.clone(), meta_sm.clone(), engine.get_batch_size());
+        w.alloc()?; // reserved for the superblock
+        let root = write_metadata_sm(&mut w)?;
+        drop(w);
+
+        let disk_sm = DiskSpaceMap::open_metadata(engine.clone(), root)?;
+        let sm = meta_sm.lock().unwrap();
+        for b in 0..engine.get_nr_blocks() {
+            ensure!(disk_sm.get(b)? == sm.get(b)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lazy_sm_rejects_changes() -> Result<()> {
+        let engine = Arc::new(CoreIoEngine::new(1024));
+        let meta_sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
+
+        let mut w = WriteBatcher::new(engine.clone(), meta_sm, engine.get_batch_size());
+        w.alloc()?; // reserved for the superblock
+        let data_sm = core_sm(1000, u32::MAX);
+        let root = write_disk_sm(&mut w, data_sm.lock().unwrap().deref())?;
+        drop(w);
+
+        let mut disk_sm = DiskSpaceMap::open_data(engine, root)?;
+        ensure!(disk_sm.inc(0, 1).is_err());
+        ensure!(disk_sm.alloc().is_err());
+        ensure!(disk_sm.get(0)? == 0);
+
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+mod checker {
+    use anyhow::{ensure, Result};
+    use std::ops::Deref;
+    use std::sync::Arc;
+
+    use crate::io_engine::core::CoreIoEngine;
+    use crate::io_engine::*;
+    use crate::pdata::space_map::checker::*;
+    use crate::pdata::space_map::common::{SMRoot, ENTRIES_PER_BITMAP};
+    use crate::pdata::space_map::disk::*;
+    use crate::pdata::space_map::metadata::*;
+    use crate::pdata::space_map::*;
+    use crate::report::mk_quiet_report;
+    use crate::write_batcher::WriteBatcher;
+
+    // Writes a data and a metadata space map, returns their roots and the
+    // data block ref-counts written
+    fn mk_space_maps(engine: &Arc<CoreIoEngine>) -> Result<(SMRoot, SMRoot, ASpaceMap)> {
+        let meta_sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
+        let mut w = WriteBatcher::new(engine.clone(), meta_sm, engine.get_batch_size());
+        w.alloc()?; // reserved for the superblock
+
+        // spread the counts across several bitmaps, with one overflowing
+        let nr_blocks = ENTRIES_PER_BITMAP as u64 * 2 + 1000;
+        let data_sm = core_sm(nr_blocks, u32::MAX);
+        {
+            let mut sm = data_sm.lock().unwrap();
+            for b in (0..nr_blocks).step_by(97) {
+                sm.set(b, (b % 4) as u32)?;
+            }
+            sm.set(nr_blocks - 1, 1000)?;
+        }
+
+        let data_root = write_disk_sm(&mut w, data_sm.lock().unwrap().deref())?;
+        let metadata_root = write_metadata_sm(&mut w)?;
+        Ok((data_root, metadata_root, data_sm))
+    }
+
+    // The checker counts the blocks of the space maps, which leaves the
+    // superblock
+    fn mk_metadata_sm(engine: &Arc<CoreIoEngine>) -> Result<ASpaceMap> {
+        let sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
+        sm.lock().unwrap().inc(0, 1)?;
+        Ok(sm)
+    }
+
+    #[test]
+    fn consistent_space_maps_have_no_leaks() -> Result<()> {
+        let engine = Arc::new(CoreIoEngine::new(1024));
+        let (data_root, metadata_root, data_sm) = mk_space_maps(&engine)?;
+
+        let (data_leaks, metadata_leaks) = check_space_maps(
+            engine.clone(),
+            Arc::new(mk_quiet_report()),
+            data_root,
+            metadata_root,
+            data_sm,
+            mk_metadata_sm(&engine)?,
+            false,
+            None,
+        )?;
+        ensure!(data_leaks.is_empty());
+        ensure!(metadata_leaks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pipelined_check_matches_the_separate_checks() -> Result<()> {
+        let engine = Arc::new(CoreIoEngine::new(1024));
+        let (data_root, metadata_root, data_sm) = mk_space_maps(&engine)?;
+
+        // block 97 is written with a count of one, so expecting none leaks it
+        data_sm.lock().unwrap().set(97, 0)?;
+        let report = Arc::new(mk_quiet_report());
+
+        let mut histogram = RefCountHistogram::default();
+        let (data_leaks, metadata_leaks) = check_space_maps(
+            engine.clone(),
+            report.clone(),
+            data_root.clone(),
+            metadata_root.clone(),
+            data_sm.clone(),
+            mk_metadata_sm(&engine)?,
+            false,
+            Some(&mut histogram),
+        )?;
+
+        let mut expected_histogram = RefCountHistogram::default();
+        let metadata_sm = mk_metadata_sm(&engine)?;
+        let expected_data_leaks = check_disk_space_map(
+            engine.clone(),
+            report.clone(),
+            data_root,
+            data_sm,
+            metadata_sm.clone(),
+            false,
+            Some(&mut expected_histogram),
+        )?;
+        let expected_metadata_leaks =
+            check_metadata_space_map(engine.clone(), report, metadata_root, metadata_sm, false)?;
+
+        ensure!(count_leaked_blocks(&data_leaks) == 1);
+        ensure!(count_leaked_blocks(&data_leaks) == count_leaked_blocks(&expected_data_leaks));
+        ensure!(
+            count_leaked_blocks(&metadata_leaks) == count_leaked_blocks(&expected_metadata_leaks)
+        );
+        ensure!(histogram == expected_histogram);
+        Ok(())
+    }
 }
 
 //------------------------------------------
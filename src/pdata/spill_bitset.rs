@@ -0,0 +1,224 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use crate::file_utils;
+use crate::math::div_up;
+
+#[cfg(test)]
+mod tests;
+
+//------------------------------------------
+
+// A fixed size bitset that is held in core if possible, but spills to a
+// memory mapped temporary file if the allocation fails or exceeds the
+// given limit.  The kernel writes the mapped pages back to the file under
+// memory pressure, so walks over very large metadata still complete,
+// albeit slower, rather than aborting on allocation failure.
+
+const BITS_PER_WORD: usize = 64;
+
+struct MappedWords {
+    ptr: *mut u64,
+    len: usize,
+    _file: File,
+}
+
+impl MappedWords {
+    fn new(len: usize) -> io::Result<MappedWords> {
+        let nr_bytes = len * std::mem::size_of::<u64>();
+        let file = file_utils::create_unlinked_file(&std::env::temp_dir())?;
+        file.set_len(nr_bytes as u64)?;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                nr_bytes,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MappedWords {
+            ptr: ptr as *mut u64,
+            len,
+            _file: file,
+        })
+    }
+}
+
+impl Drop for MappedWords {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.ptr as *mut libc::c_void,
+                self.len * std::mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+unsafe impl Send for MappedWords {}
+unsafe impl Sync for MappedWords {}
+
+enum Storage {
+    Core(Vec<u64>),
+    Mapped(MappedWords),
+}
+
+pub struct SpillBitSet {
+    nr_bits: usize,
+    storage: Storage,
+}
+
+impl SpillBitSet {
+    /// Creates a bitset that is always held in core.
+    pub fn with_capacity(nr_bits: usize) -> SpillBitSet {
+        SpillBitSet {
+            nr_bits,
+            storage: Storage::Core(vec![0; div_up(nr_bits, BITS_PER_WORD)]),
+        }
+    }
+
+    /// Creates a bitset that spills to disk if it needs more than
+    /// core_limit bytes, or if the in-core allocation fails.
+    pub fn with_limit(nr_bits: usize, core_limit: Option<u64>) -> io::Result<SpillBitSet> {
+        let len = div_up(nr_bits, BITS_PER_WORD);
+        let nr_bytes = (len * std::mem::size_of::<u64>()) as u64;
+
+        let within_limit = core_limit.map_or(true, |limit| nr_bytes <= limit);
+        if within_limit || len == 0 {
+            let mut words = Vec::new();
+            if words.try_reserve_exact(len).is_ok() {
+                words.resize(len, 0);
+                return Ok(SpillBitSet {
+                    nr_bits,
+                    storage: Storage::Core(words),
+                });
+            }
+        }
+
+        Ok(SpillBitSet {
+            nr_bits,
+            storage: Storage::Mapped(MappedWords::new(len)?),
+        })
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.storage, Storage::Mapped(_))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nr_bits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nr_bits == 0
+    }
+
+    fn words(&self) -> &[u64] {
+        match &self.storage {
+            Storage::Core(words) => words,
+            Storage::Mapped(m) => unsafe { std::slice::from_raw_parts(m.ptr, m.len) },
+        }
+    }
+
+    fn words_mut(&mut self) -> &mut [u64] {
+        match &mut self.storage {
+            Storage::Core(words) => words,
+            Storage::Mapped(m) => unsafe { std::slice::from_raw_parts_mut(m.ptr, m.len) },
+        }
+    }
+
+    #[inline]
+    fn check_bounds(&self, bit: usize) {
+        assert!(
+            bit < self.nr_bits,
+            "insert at index {} exceeds bitset size {}",
+            bit,
+            self.nr_bits
+        );
+    }
+
+    /// Returns false if the bit is beyond the end of the bitset
+    pub fn contains(&self, bit: usize) -> bool {
+        if bit >= self.nr_bits {
+            return false;
+        }
+        self.words()[bit / BITS_PER_WORD] & (1u64 << (bit % BITS_PER_WORD)) != 0
+    }
+
+    pub fn insert(&mut self, bit: usize) {
+        self.set(bit, true);
+    }
+
+    pub fn set(&mut self, bit: usize, enabled: bool) {
+        self.check_bounds(bit);
+        let w = &mut self.words_mut()[bit / BITS_PER_WORD];
+        if enabled {
+            *w |= 1u64 << (bit % BITS_PER_WORD);
+        } else {
+            *w &= !(1u64 << (bit % BITS_PER_WORD));
+        }
+    }
+
+    pub fn toggle(&mut self, bit: usize) {
+        self.check_bounds(bit);
+        self.words_mut()[bit / BITS_PER_WORD] ^= 1u64 << (bit % BITS_PER_WORD);
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.words().iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Iterates the indices of the set bits, in ascending order
+    pub fn ones(&self) -> Ones {
+        let words = self.words();
+        Ones {
+            words,
+            index: 0,
+            current: words.first().cloned().unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Debug for SpillBitSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpillBitSet")
+            .field("nr_bits", &self.nr_bits)
+            .field("spilled", &self.is_spilled())
+            .finish()
+    }
+}
+
+pub struct Ones<'a> {
+    words: &'a [u64],
+    index: usize,
+    current: u64,
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.current == 0 {
+            self.index += 1;
+            if self.index >= self.words.len() {
+                return None;
+            }
+            self.current = self.words[self.index];
+        }
+
+        let bit = self.current.trailing_zeros() as usize;
+        self.current &= self.current - 1;
+        Some(self.index * BITS_PER_WORD + bit)
+    }
+}
+
+//------------------------------------------
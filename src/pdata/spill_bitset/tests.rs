@@ -0,0 +1,61 @@
+use super::*;
+
+//------------------------------------------
+
+fn test_set_and_clear(bits: &mut SpillBitSet) {
+    let nr_bits = bits.len();
+    let set: Vec<usize> = (0..nr_bits).step_by(7).collect();
+
+    for b in &set {
+        bits.insert(*b);
+    }
+    assert_eq!(bits.count_ones(), set.len());
+    assert_eq!(bits.ones().collect::<Vec<usize>>(), set);
+
+    for b in 0..nr_bits {
+        assert_eq!(bits.contains(b), b % 7 == 0);
+    }
+    assert!(!bits.contains(nr_bits));
+
+    for b in &set {
+        bits.toggle(*b);
+    }
+    assert_eq!(bits.count_ones(), 0);
+    assert_eq!(bits.ones().next(), None);
+
+    bits.set(nr_bits - 1, true);
+    assert!(bits.contains(nr_bits - 1));
+    bits.set(nr_bits - 1, false);
+    assert!(!bits.contains(nr_bits - 1));
+}
+
+#[test]
+fn core_bitset() {
+    let mut bits = SpillBitSet::with_capacity(10000);
+    assert!(!bits.is_spilled());
+    test_set_and_clear(&mut bits);
+}
+
+#[test]
+fn spilled_bitset() {
+    let mut bits = SpillBitSet::with_limit(10000, Some(0)).unwrap();
+    assert!(bits.is_spilled());
+    test_set_and_clear(&mut bits);
+}
+
+#[test]
+fn empty_bitset_stays_in_core() {
+    let bits = SpillBitSet::with_limit(0, Some(0)).unwrap();
+    assert!(!bits.is_spilled());
+    assert!(bits.is_empty());
+    assert_eq!(bits.ones().next(), None);
+}
+
+#[test]
+#[should_panic]
+fn insert_out_of_bounds() {
+    let mut bits = SpillBitSet::with_capacity(100);
+    bits.insert(100);
+}
+
+//------------------------------------------
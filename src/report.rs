@@ -1,8 +1,12 @@
 use indicatif::{ProgressBar, ProgressStyle};
 
+use std::ffi::CString;
+use std::fs::File;
 use std::io::{self, Write};
 use std::ops::Add;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::unix::io::FromRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
@@ -54,6 +58,101 @@ pub fn parse_log_level(matches: &clap::ArgMatches) -> Result<LogLevel, String> {
     }
 }
 
+pub fn progress_fd_args(cmd: clap::Command) -> clap::Command {
+    use clap::{value_parser, Arg};
+
+    cmd.arg(
+        Arg::new("PROGRESS_FD")
+            .help("Write progress records to the given file descriptor")
+            .long("progress-fd")
+            .value_name("FD")
+            .value_parser(value_parser!(i32).range(0..)),
+    )
+}
+
+// Opens a duplicate of the file descriptor given with --progress-fd, which
+// the caller is expected to have left open for us, eg. the write end of a
+// pipe.  The descriptor itself isn't taken over, so one the tool also uses,
+// such as stdout, stays open once the records are done with.
+pub fn parse_progress_fd(matches: &clap::ArgMatches) -> Result<Option<File>, String> {
+    match matches.get_one::<i32>("PROGRESS_FD") {
+        Some(fd) => {
+            let flags = unsafe { libc::fcntl(*fd, libc::F_GETFL) };
+            if flags < 0 {
+                return Err(format!("bad progress file descriptor {}", fd));
+            }
+            if flags & libc::O_ACCMODE == libc::O_RDONLY {
+                return Err(format!("progress file descriptor {} isn't writable", fd));
+            }
+
+            let dup = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if dup < 0 {
+                return Err(format!(
+                    "couldn't duplicate progress file descriptor {}: {}",
+                    fd,
+                    io::Error::last_os_error()
+                ));
+            }
+            Ok(Some(unsafe { File::from_raw_fd(dup) }))
+        }
+        None => Ok(None),
+    }
+}
+
+// Where log messages are sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Stderr,
+    Syslog(libc::c_int), // facility
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let facility = match s.split_once(':') {
+            None if s == "stderr" => return Ok(LogTarget::Stderr),
+            None if s == "syslog" => "user",
+            Some(("syslog", facility)) => facility,
+            _ => return Err(format!("unknown log target '{}'", s)),
+        };
+
+        let facility = match facility {
+            "user" => libc::LOG_USER,
+            "daemon" => libc::LOG_DAEMON,
+            "local0" => libc::LOG_LOCAL0,
+            "local1" => libc::LOG_LOCAL1,
+            "local2" => libc::LOG_LOCAL2,
+            "local3" => libc::LOG_LOCAL3,
+            "local4" => libc::LOG_LOCAL4,
+            "local5" => libc::LOG_LOCAL5,
+            "local6" => libc::LOG_LOCAL6,
+            "local7" => libc::LOG_LOCAL7,
+            _ => return Err(format!("unknown syslog facility '{}'", facility)),
+        };
+        Ok(LogTarget::Syslog(facility))
+    }
+}
+
+pub fn log_target_args(cmd: clap::Command) -> clap::Command {
+    use clap::{value_parser, Arg};
+
+    cmd.arg(
+        Arg::new("LOG_TARGET")
+            .help("Send log messages to stderr, or to syslog[:facility]")
+            .long("log-target")
+            .value_name("TARGET")
+            .value_parser(value_parser!(LogTarget)),
+    )
+}
+
+pub fn parse_log_target(matches: &clap::ArgMatches) -> LogTarget {
+    matches
+        .get_one::<LogTarget>("LOG_TARGET")
+        .cloned()
+        .unwrap_or(LogTarget::Stderr)
+}
+
 //------------------------------------------
 
 #[derive(Clone, PartialEq, Eq)]
@@ -79,7 +178,11 @@ impl ReportOutcome {
 
 pub struct Report {
     outcome: Mutex<ReportOutcome>,
+    nr_errors: AtomicU64,
     inner: Mutex<Box<dyn ReportInner + Send>>,
+
+    // Receives "completed/total" records, for GUIs to draw progress bars with
+    progress_fd: Mutex<Option<File>>,
 }
 
 pub trait ReportInner {
@@ -97,11 +200,14 @@ impl Report {
     pub fn new(inner: Box<dyn ReportInner + Send>) -> Report {
         Report {
             outcome: Mutex::new(Success),
+            nr_errors: AtomicU64::new(0),
             inner: Mutex::new(inner),
+            progress_fd: Mutex::new(None),
         }
     }
 
     fn update_outcome(&self, rhs: ReportOutcome) {
+        self.nr_errors.fetch_add(1, Ordering::Relaxed);
         let mut lhs = self.outcome.lock().unwrap();
         *lhs = ReportOutcome::combine(&lhs, &rhs);
     }
@@ -121,9 +227,30 @@ impl Report {
         inner.set_level(level)
     }
 
+    pub fn set_progress_fd(&self, file: File) {
+        *self.progress_fd.lock().unwrap() = Some(file);
+    }
+
     pub fn progress(&self, percent: u8) {
+        self.progress_of(percent as u64, 100)
+    }
+
+    // Reports the amount of work completed out of the total, which is
+    // written as is to the progress fd, and as a percentage elsewhere.
+    pub fn progress_of(&self, completed: u64, total: u64) {
+        let completed = std::cmp::min(completed, total);
+        if let Some(file) = self.progress_fd.lock().unwrap().as_mut() {
+            // A reader that went away mustn't stop the tool
+            let _ = writeln!(file, "{}/{}", completed, total);
+        }
+
+        let percent = if total > 0 {
+            completed * 100 / total
+        } else {
+            100
+        };
         let mut inner = self.inner.lock().unwrap();
-        inner.progress(percent)
+        inner.progress(percent as u8)
     }
 
     pub fn info(&self, txt: &str) {
@@ -163,6 +290,11 @@ impl Report {
         outcome.clone()
     }
 
+    // The number of fatal and non-fatal errors reported so far
+    pub fn get_nr_errors(&self) -> u64 {
+        self.nr_errors.load(Ordering::Relaxed)
+    }
+
     // Force a message to be printed to stdout.  eg,
     // TRANSACTION_ID = <blah>
     pub fn to_stdout(&self, txt: &str) {
@@ -352,6 +484,113 @@ pub fn mk_quiet_report() -> Report {
 
 //------------------------------------------
 
+// Where the syslog report sends its messages, so the filtering may be
+// tested without writing to the system log
+trait SyslogSink {
+    fn write(&mut self, priority: libc::c_int, msg: &str);
+}
+
+struct LibcSyslog {
+    _ident: CString, // openlog() keeps a pointer to this
+}
+
+impl LibcSyslog {
+    fn new(ident: &str, facility: libc::c_int) -> LibcSyslog {
+        let ident = CString::new(ident).unwrap_or_default();
+        unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID, facility) };
+        LibcSyslog { _ident: ident }
+    }
+}
+
+impl Drop for LibcSyslog {
+    fn drop(&mut self) {
+        unsafe { libc::closelog() };
+    }
+}
+
+impl SyslogSink for LibcSyslog {
+    fn write(&mut self, priority: libc::c_int, msg: &str) {
+        let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+        unsafe {
+            libc::syslog(
+                priority,
+                b"%s\0".as_ptr() as *const libc::c_char,
+                msg.as_ptr(),
+            )
+        };
+    }
+}
+
+// Logs to syslog, or journald, with the severity of each message, for
+// checks run unattended at boot.  Progress and titles are dropped, but the
+// KEY=value lines written to stdout are kept unless quiet, since scripts
+// parse them.
+struct SyslogInner {
+    sink: Box<dyn SyslogSink + Send>,
+    level: LogLevel,
+    quiet: bool,
+}
+
+impl SyslogInner {
+    fn new(sink: Box<dyn SyslogSink + Send>, quiet: bool) -> SyslogInner {
+        SyslogInner {
+            sink,
+            level: LogLevel::Warning,
+            quiet,
+        }
+    }
+
+    fn priority(level: LogLevel) -> libc::c_int {
+        match level {
+            LogLevel::Fatal => libc::LOG_CRIT,
+            LogLevel::Error => libc::LOG_ERR,
+            LogLevel::Warning => libc::LOG_WARNING,
+            LogLevel::Info => libc::LOG_INFO,
+            LogLevel::Debug => libc::LOG_DEBUG,
+        }
+    }
+}
+
+impl ReportInner for SyslogInner {
+    fn set_title(&mut self, _txt: &str) {}
+
+    fn set_sub_title(&mut self, _txt: &str) {}
+
+    fn set_level(&mut self, level: LogLevel) {
+        self.level = level;
+    }
+
+    fn progress(&mut self, _percent: u8) {}
+
+    fn log(&mut self, txt: &str, level: LogLevel) {
+        if level > self.level {
+            return;
+        }
+        self.sink.write(Self::priority(level), txt);
+    }
+
+    fn to_stdout(&mut self, txt: &str) {
+        if !self.quiet {
+            println!("{}", txt);
+        }
+    }
+
+    fn complete(&mut self) {}
+
+    fn get_prompt_input(&mut self, _prompt: &str) -> io::Result<String> {
+        Ok(String::new()) // nobody is there to answer when unattended
+    }
+}
+
+pub fn mk_syslog_report(ident: &str, facility: libc::c_int, quiet: bool) -> Report {
+    Report::new(Box::new(SyslogInner::new(
+        Box::new(LibcSyslog::new(ident, facility)),
+        quiet,
+    )))
+}
+
+//------------------------------------------
+
 pub struct ProgressMonitor {
     tid: JoinHandle<()>,
     stop_flag: Arc<AtomicBool>,
@@ -367,14 +606,13 @@ impl ProgressMonitor {
         let stopped = stop_flag.clone();
         let tid = thread::spawn(move || {
             let interval = std::time::Duration::from_millis(500);
+            // Always report once more after being stopped, so the final
+            // state is seen
             loop {
+                report.progress_of(processed(), total);
                 if stopped.load(Ordering::Relaxed) {
                     break;
                 }
-
-                let n = processed() * 100 / total;
-
-                report.progress(n as u8);
                 thread::sleep(interval);
             }
         });
@@ -390,3 +628,62 @@ impl ProgressMonitor {
 }
 
 //------------------------------------------
+
+#[cfg(test)]
+mod report_tests {
+    use super::*;
+
+    #[test]
+    fn log_targets_are_parsed() {
+        assert_eq!("stderr".parse::<LogTarget>(), Ok(LogTarget::Stderr));
+        assert_eq!(
+            "syslog".parse::<LogTarget>(),
+            Ok(LogTarget::Syslog(libc::LOG_USER))
+        );
+        assert_eq!(
+            "syslog:local3".parse::<LogTarget>(),
+            Ok(LogTarget::Syslog(libc::LOG_LOCAL3))
+        );
+        assert!("syslog:kern".parse::<LogTarget>().is_err());
+        assert!("file".parse::<LogTarget>().is_err());
+    }
+
+    struct Recorder(Arc<Mutex<Vec<(libc::c_int, String)>>>);
+
+    impl SyslogSink for Recorder {
+        fn write(&mut self, priority: libc::c_int, msg: &str) {
+            self.0.lock().unwrap().push((priority, msg.to_string()));
+        }
+    }
+
+    #[test]
+    fn syslog_messages_keep_their_severity() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let report = Report::new(Box::new(SyslogInner::new(
+            Box::new(Recorder(logged.clone())),
+            false,
+        )));
+
+        report.fatal("bad checksum in superblock");
+        report.non_fatal("leaked blocks");
+        report.warning("unusual block size");
+        report.info("examining superblock");
+        report.progress(50);
+
+        // below the warning level nothing is logged, and progress is dropped
+        assert_eq!(
+            *logged.lock().unwrap(),
+            vec![
+                (libc::LOG_CRIT, "bad checksum in superblock".to_string()),
+                (libc::LOG_ERR, "leaked blocks".to_string()),
+                (libc::LOG_WARNING, "unusual block size".to_string()),
+            ]
+        );
+
+        report.set_level(LogLevel::Info);
+        report.info("examining superblock");
+        assert_eq!(logged.lock().unwrap().len(), 4);
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,748 @@
+use anyhow::{anyhow, Result};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::io_engine::errors::IoErrorClass;
+use crate::io_engine::*;
+use crate::math::div_up;
+use crate::pdata::btree::*;
+use crate::pdata::btree_walker::*;
+use crate::pdata::space_map::common::{SMRoot, ENTRIES_PER_BITMAP};
+use crate::pdata::space_map::disk::DiskSpaceMap;
+use crate::pdata::space_map::*;
+use crate::pdata::unpack::unpack;
+use crate::report::{ProgressMonitor, Report};
+use crate::thin::block_time::BlockTime;
+use crate::thin::node_map::*;
+use crate::thin::superblock::Superblock;
+use crate::utils::hashvec::HashVec;
+
+//------------------------------------------
+
+// minimum number of entries of a node with 64-bit mapped type
+const MIN_ENTRIES: u8 = 84;
+
+struct Context {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+}
+
+//------------------------------------------
+
+#[derive(Debug, Clone, Default)]
+struct NodeSummary {
+    key_low: u64,        // min mapped block
+    key_high: u64,       // max mapped block, inclusive
+    nr_mappings: u64,    // number of valid mappings in this subtree
+    nr_shared: u64,      // number of shared mappings in this subtree
+    nr_reclaimable: u64, // number of exclusive mappings the pool only counts once
+    nr_entries: u8,      // number of entries in this node
+    nr_errors: u8,       // number of errors found in this subtree, up to 255
+}
+
+impl NodeSummary {
+    fn from_leaf(keys: &[u64], nr_shared: u64, nr_reclaimable: u64) -> Self {
+        let nr_entries = keys.len();
+        let key_low = if nr_entries > 0 { keys[0] } else { 0 };
+        let key_high = if nr_entries > 0 {
+            keys[nr_entries - 1]
+        } else {
+            0
+        };
+
+        NodeSummary {
+            key_low,
+            key_high,
+            nr_mappings: nr_entries as u64,
+            nr_shared,
+            nr_reclaimable,
+            nr_entries: nr_entries as u8,
+            nr_errors: 0,
+        }
+    }
+
+    fn error() -> Self {
+        Self {
+            key_low: 0,
+            key_high: 0,
+            nr_mappings: 0,
+            nr_shared: 0,
+            nr_reclaimable: 0,
+            nr_entries: 0,
+            nr_errors: 1,
+        }
+    }
+
+    fn append(&mut self, child: &NodeSummary) -> anyhow::Result<()> {
+        if self.nr_mappings == 0 {
+            self.key_low = child.key_low;
+            self.key_high = child.key_high;
+        } else if child.nr_mappings > 0 {
+            if child.key_low <= self.key_high {
+                return Err(anyhow!("overlapped keys"));
+            }
+            self.key_high = child.key_high;
+        }
+        self.nr_mappings += child.nr_mappings;
+        self.nr_shared += child.nr_shared;
+        self.nr_reclaimable += child.nr_reclaimable;
+        self.nr_entries += 1;
+        self.nr_errors = self.nr_errors.saturating_add(child.nr_errors);
+
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+// Summarize a subtree rooted at the speicifc block.
+// Only a good internal node will have a summary stored.
+// TODO: check the tree is balanced by comparing the height of visited nodes
+#[allow(clippy::too_many_arguments)]
+fn summarize_tree(
+    path: &mut Vec<u64>,
+    kr: &KeyRange,
+    root: u32,
+    is_root: bool,
+    nodes: &NodeMap,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    summaries: &mut HashVec<NodeSummary>,
+    ignore_non_fatal: bool,
+) -> NodeSummary {
+    if let Some(sum) = summaries.get(root) {
+        // Check underfull
+        if !ignore_non_fatal && !is_root && sum.nr_entries < MIN_ENTRIES {
+            return NodeSummary::error();
+        }
+
+        // Check the key range against the parent keys.
+        if sum.nr_mappings > 0 {
+            if let Some(n) = kr.start {
+                // The parent key could be less than or equal to,
+                // but not greater than the child's first key
+                if n > sum.key_low {
+                    return NodeSummary::error();
+                }
+            }
+            if let Some(n) = kr.end {
+                // note that KeyRange is a right-opened interval
+                if n < sum.key_high {
+                    return NodeSummary::error();
+                }
+            }
+        }
+
+        return sum.clone();
+    }
+
+    match nodes.get_type(root) {
+        NodeType::Internal => {
+            if let Some(info) = nodes.internal_info.get(root) {
+                // Check underfull
+                if !ignore_non_fatal && !is_root && info.keys.len() < MIN_ENTRIES as usize {
+                    return NodeSummary::error();
+                }
+
+                // Split up the key range for the children.
+                // Return immediately if the keys don't match.
+                let child_keys = match split_key_ranges(path, kr, &info.keys) {
+                    Ok(keys) => keys,
+                    Err(_) => return NodeSummary::error(),
+                };
+
+                // Gather information from the children
+                let mut sum = NodeSummary::default();
+                for (i, b) in info.children.iter().enumerate() {
+                    path.push(*b as u64);
+                    let child_sums = summarize_tree(
+                        path,
+                        &child_keys[i],
+                        *b,
+                        false,
+                        nodes,
+                        metadata_sm,
+                        summaries,
+                        ignore_non_fatal,
+                    );
+                    let _ = sum.append(&child_sums);
+                    path.pop();
+                }
+
+                // Adjust the number of shared blocks if it is a shared internal node
+                let metadata_sm = metadata_sm.lock().unwrap();
+                if metadata_sm.get(root as u64).unwrap_or(0) > 1 {
+                    sum.nr_shared = sum.nr_mappings;
+                    sum.nr_reclaimable = 0;
+                }
+
+                summaries.insert(root, sum.clone());
+
+                sum
+            } else {
+                // This is unexpected. However, we would like to skip that kind of error.
+                NodeSummary::error()
+            }
+        }
+
+        // This is unexpected since a leaf should have been summarized
+        _ => NodeSummary::error(),
+    }
+}
+
+fn count_mapped_blocks(
+    roots: &[u64],
+    nodes: &NodeMap,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    summaries: &mut HashVec<NodeSummary>,
+    ignore_non_fatal: bool,
+) {
+    for root in roots.iter() {
+        let mut path = vec![0, *root]; // the path is just for error reporting
+        let kr = KeyRange::new();
+        summarize_tree(
+            &mut path,
+            &kr,
+            *root as u32,
+            true,
+            nodes,
+            metadata_sm,
+            summaries,
+            ignore_non_fatal,
+        );
+    }
+}
+
+//------------------------------------------
+
+fn unpacker(
+    blocks_rx: &Arc<Mutex<mpsc::Receiver<Vec<Block>>>>,
+    nodes_tx: SyncSender<Vec<Node<BlockTime>>>,
+    node_map: Arc<Mutex<NodeMap>>,
+    ignore_non_fatal: bool,
+) {
+    loop {
+        let blocks = {
+            let blocks_rx = blocks_rx.lock().unwrap();
+            if let Ok(blocks) = blocks_rx.recv() {
+                blocks
+            } else {
+                break;
+            }
+        };
+
+        let mut nodes = Vec::with_capacity(blocks.len());
+        let mut errs = Vec::new();
+
+        for b in blocks {
+            // Allow under full nodes in this phase.  The under full
+            // property will be check later based on the path context.
+            match check_and_unpack_node::<BlockTime>(&b, ignore_non_fatal, true) {
+                Ok(n) => {
+                    nodes.push(n);
+                }
+                Err(e) => {
+                    errs.push((b.loc, e));
+                }
+            }
+        }
+
+        if !errs.is_empty() {
+            let mut node_map = node_map.lock().unwrap();
+            for (b, e) in errs {
+                // theoretically never fail
+                let _ = node_map.insert_error(b as u32, e);
+            }
+        }
+
+        if nodes_tx.send(nodes).is_err() {
+            break;
+        }
+    }
+}
+
+fn summariser(
+    nodes_rx: mpsc::Receiver<Vec<Node<BlockTime>>>,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    summaries: &Arc<Mutex<HashVec<NodeSummary>>>,
+) {
+    let mut summaries = summaries.lock().unwrap();
+
+    loop {
+        let nodes = {
+            if let Ok(nodes) = nodes_rx.recv() {
+                nodes
+            } else {
+                break;
+            }
+        };
+
+        for n in nodes {
+            if let Node::Leaf {
+                keys,
+                values,
+                header,
+            } = n
+            {
+                {
+                    let mut data_sm = data_sm.lock().unwrap();
+                    for v in values {
+                        let _ = data_sm.inc(v.block, 1);
+                    }
+                }
+
+                // summarize shared leaves in this phase
+                let metadata_sm = metadata_sm.lock().unwrap();
+                if metadata_sm.get(header.block).unwrap_or(0) > 1 {
+                    let sum = NodeSummary::from_leaf(&keys, keys.len() as u64, 0);
+                    summaries.insert(header.block as u32, sum);
+                }
+            } else {
+                // Do not report error here. The error will be captured
+                // in the second phase.
+            }
+        }
+    }
+}
+
+fn exclusive_leaves_summariser(
+    nodes_rx: mpsc::Receiver<Vec<Node<BlockTime>>>,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    pool_sm: Option<&DiskSpaceMap>,
+    summaries: &Arc<Mutex<HashVec<NodeSummary>>>,
+) {
+    let mut summaries = summaries.lock().unwrap();
+
+    loop {
+        let nodes = {
+            if let Ok(nodes) = nodes_rx.recv() {
+                nodes
+            } else {
+                break;
+            }
+        };
+
+        for n in nodes {
+            if let Node::Leaf {
+                keys,
+                values,
+                header,
+            } = n
+            {
+                let metadata_sm = metadata_sm.lock().unwrap();
+                if metadata_sm.get(header.block).unwrap_or(0) == 1 {
+                    let data_sm = data_sm.lock().unwrap();
+
+                    let mut nr_shared: u64 = 0;
+                    let mut nr_reclaimable: u64 = 0;
+                    for bt in values {
+                        if data_sm.get(bt.block).unwrap_or(0) > 1 {
+                            nr_shared += 1;
+                        } else if let Some(sm) = pool_sm {
+                            // Blocks the pool holds more references to,
+                            // such as leaked ones, stay allocated.
+                            if sm.get(bt.block).unwrap_or(0) == 1 {
+                                nr_reclaimable += 1;
+                            }
+                        }
+                    }
+
+                    let sum = NodeSummary::from_leaf(&keys, nr_shared, nr_reclaimable);
+                    summaries.insert(header.block as u32, sum);
+                }
+            } else {
+                // Do not report error here. The error will be captured
+                // in the second phase.
+            }
+        }
+    }
+}
+
+fn read_leaf_nodes(
+    ctx: &Context,
+    nodes: NodeMap,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    ignore_non_fatal: bool,
+) -> Result<(NodeMap, HashVec<NodeSummary>)> {
+    const QUEUE_DEPTH: usize = 4;
+    const NR_UNPACKERS: usize = 4;
+
+    // Single IO thread reads vecs of blocks
+    // Many unpackers take the block vecs and turn them into btree nodes
+    // Single 'summariser' thread processes the nodes
+
+    // Build a vec of the leaf locations.  These will be in disk location
+    // order.
+    let mut leaves = Vec::with_capacity(nodes.nr_leaves as usize);
+    for loc in nodes.leaf_nodes.ones() {
+        leaves.push(loc as u64);
+    }
+
+    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(QUEUE_DEPTH);
+    let blocks_rx = Arc::new(Mutex::new(blocks_rx));
+
+    let (nodes_tx, nodes_rx) = mpsc::sync_channel::<Vec<Node<BlockTime>>>(QUEUE_DEPTH);
+
+    // Process chunks of leaves at once so the io engine can aggregate reads.
+    let summaries = Arc::new(Mutex::new(HashVec::with_capacity(nodes.len())));
+    let nodes = Arc::new(Mutex::new(nodes));
+
+    // Kick off the unpackers
+    let mut unpackers = Vec::with_capacity(NR_UNPACKERS);
+    for _i in 0..NR_UNPACKERS {
+        let blocks_rx = blocks_rx.clone();
+        let nodes_tx = nodes_tx.clone();
+        let node_map = nodes.clone();
+        unpackers.push(thread::spawn(move || {
+            unpacker(&blocks_rx, nodes_tx, node_map, ignore_non_fatal)
+        }));
+    }
+    drop(blocks_rx);
+    drop(nodes_tx);
+
+    // Kick off the summariser
+    let summariser_tid = {
+        let metadata_sm = metadata_sm.clone();
+        let data_sm = data_sm.clone();
+        let summaries = summaries.clone();
+        thread::spawn(move || {
+            summariser(nodes_rx, &metadata_sm, &data_sm, &summaries);
+        })
+    };
+
+    // IO is done in the main thread
+    let engine = ctx.engine.clone();
+    for c in leaves.chunks(1024) {
+        let mut bs = Vec::with_capacity(c.len());
+
+        // TODO: Retry blocks ignored by vectored io
+        match engine.read_many(c) {
+            Ok(blocks) => {
+                for b in blocks {
+                    if b.is_err() {
+                        continue;
+                    }
+
+                    let b = b.unwrap();
+                    bs.push(b);
+                }
+
+                blocks_tx
+                    .send(bs)
+                    .expect("couldn't send blocks to unpacker");
+            }
+            Err(e) => {
+                let class = IoErrorClass::of(&e);
+                let mut nodes = nodes.lock().unwrap();
+                for b in c {
+                    let _ = nodes.insert_error(*b as u32, NodeError::IoError(class));
+                }
+            }
+        }
+    }
+
+    drop(blocks_tx);
+
+    // Wait for child threads
+    for tid in unpackers {
+        tid.join().expect("couldn't join unpacker");
+    }
+    summariser_tid.join().expect("couldn't join summariser");
+
+    // extract the results
+    let nodes = Arc::try_unwrap(nodes).unwrap().into_inner().unwrap();
+    let summaries = Arc::try_unwrap(summaries).unwrap().into_inner().unwrap();
+
+    Ok((nodes, summaries))
+}
+
+fn read_exclusive_leaves(
+    ctx: &Context,
+    nodes: NodeMap,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    pool_sm: &Option<Arc<DiskSpaceMap>>,
+    summaries: HashVec<NodeSummary>,
+    ignore_non_fatal: bool,
+) -> Result<(NodeMap, HashVec<NodeSummary>)> {
+    const QUEUE_DEPTH: usize = 4;
+    const NR_UNPACKERS: usize = 4;
+
+    // Single IO thread reads vecs of blocks
+    // Many unpackers take the block vecs and turn them into btree nodes
+    // Single 'summariser' thread processes the nodes
+
+    // Build a vec of the leaf locations.  These will be in disk location
+    // order.
+    let mut leaves = Vec::with_capacity(nodes.nr_leaves as usize);
+    {
+        let metadata_sm = metadata_sm.lock().unwrap();
+        for loc in nodes.leaf_nodes.ones() {
+            if metadata_sm.get(loc as u64).unwrap_or(0) == 1 {
+                leaves.push(loc as u64);
+            }
+        }
+    }
+
+    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(QUEUE_DEPTH);
+    let blocks_rx = Arc::new(Mutex::new(blocks_rx));
+
+    let (nodes_tx, nodes_rx) = mpsc::sync_channel::<Vec<Node<BlockTime>>>(QUEUE_DEPTH);
+
+    // Process chunks of leaves at once so the io engine can aggregate reads.
+    let summaries = Arc::new(Mutex::new(summaries));
+    let nodes = Arc::new(Mutex::new(nodes));
+
+    // Kick off the unpackers
+    let mut unpackers = Vec::with_capacity(NR_UNPACKERS);
+    for _i in 0..NR_UNPACKERS {
+        let blocks_rx = blocks_rx.clone();
+        let nodes_tx = nodes_tx.clone();
+        let node_map = nodes.clone();
+        unpackers.push(thread::spawn(move || {
+            unpacker(&blocks_rx, nodes_tx, node_map, ignore_non_fatal)
+        }));
+    }
+    drop(blocks_rx);
+    drop(nodes_tx);
+
+    // Kick off the summariser
+    let summariser_tid = {
+        let metadata_sm = metadata_sm.clone();
+        let data_sm = data_sm.clone();
+        let pool_sm = pool_sm.clone();
+        let summaries = summaries.clone();
+        thread::spawn(move || {
+            exclusive_leaves_summariser(
+                nodes_rx,
+                &metadata_sm,
+                &data_sm,
+                pool_sm.as_deref(),
+                &summaries,
+            );
+        })
+    };
+
+    // IO is done in the main thread
+    let engine = ctx.engine.clone();
+    for c in leaves.chunks(1024) {
+        let mut bs = Vec::with_capacity(c.len());
+
+        // TODO: Retry blocks ignored by vectored io
+        match engine.read_many(c) {
+            Ok(blocks) => {
+                for b in blocks {
+                    if b.is_err() {
+                        continue;
+                    }
+
+                    let b = b.unwrap();
+                    bs.push(b);
+                }
+
+                blocks_tx
+                    .send(bs)
+                    .expect("couldn't send blocks to unpacker");
+            }
+            Err(e) => {
+                let class = IoErrorClass::of(&e);
+                let mut nodes = nodes.lock().unwrap();
+                for b in c {
+                    let _ = nodes.insert_error(*b as u32, NodeError::IoError(class));
+                }
+            }
+        }
+    }
+
+    drop(blocks_tx);
+
+    // Wait for child threads
+    for tid in unpackers {
+        tid.join().expect("couldn't join unpacker");
+    }
+    summariser_tid.join().expect("couldn't join summariser");
+
+    // extract the results
+    let nodes = Arc::try_unwrap(nodes).unwrap().into_inner().unwrap();
+    let summaries = Arc::try_unwrap(summaries).unwrap().into_inner().unwrap();
+
+    Ok((nodes, summaries))
+}
+
+//------------------------------------------
+
+fn count_data_mappings_(
+    ctx: &Context,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    pool_sm: &Option<Arc<DiskSpaceMap>>,
+    roots: &[u64],
+    ignore_non_fatal: bool,
+) -> Result<HashVec<NodeSummary>> {
+    let start = std::time::Instant::now();
+    let nodes = collect_nodes_in_use(
+        ctx.engine.as_ref(),
+        metadata_sm,
+        roots,
+        ignore_non_fatal,
+        None,
+    )?;
+    let duration = start.elapsed();
+    ctx.report
+        .debug(&format!("reading internal nodes: {:?}", duration));
+
+    let start = std::time::Instant::now();
+    let (nodes, summaries) = read_leaf_nodes(ctx, nodes, metadata_sm, data_sm, ignore_non_fatal)?;
+    let duration = start.elapsed();
+    ctx.report
+        .debug(&format!("reading leaf nodes: {:?}", duration));
+
+    let start = std::time::Instant::now();
+    let nr_summarized = summaries.len();
+    let (nodes, mut summaries) = read_exclusive_leaves(
+        ctx,
+        nodes,
+        metadata_sm,
+        data_sm,
+        pool_sm,
+        summaries,
+        ignore_non_fatal,
+    )?;
+    let nr_exclusive_leaves = summaries.len() - nr_summarized;
+    let duration = start.elapsed();
+    ctx.report
+        .debug(&format!("reading exclusive leaves: {:?}", duration));
+
+    let start = std::time::Instant::now();
+    count_mapped_blocks(roots, &nodes, metadata_sm, &mut summaries, ignore_non_fatal);
+    let duration = start.elapsed();
+    ctx.report
+        .debug(&format!("counting mapped blocks: {:?}", duration));
+
+    ctx.report
+        .info(&format!("nr internal nodes: {}", nodes.internal_info.len()));
+    ctx.report.info(&format!("nr leaves: {}", nodes.nr_leaves));
+    ctx.report
+        .info(&format!("nr exclusive leaves: {}", nr_exclusive_leaves));
+
+    Ok(summaries)
+}
+
+/// The data blocks mapped by a thin device, split by whether any other
+/// device maps them too.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceAccount {
+    pub dev_id: u64,
+    pub mapped_blocks: u64,
+    pub exclusive_blocks: u64,
+    pub shared_blocks: u64,
+
+    /// The exclusive blocks that the data space map of the pool only counts
+    /// once, so deleting the device would free them.  None unless asked for.
+    pub reclaimable_blocks: Option<u64>,
+
+    /// None if nothing is mapped
+    pub highest_mapped_block: Option<u64>,
+}
+
+pub struct AccountingOptions {
+    /// Read the data space map of the pool to count the reclaimable blocks
+    pub reclaimable: bool,
+    pub ignore_non_fatal: bool,
+}
+
+/// Accounts for the data blocks of every device under a mapping tree.
+///
+/// The mapping root may be that of a metadata snapshot, while the space
+/// maps are always taken from the given superblock, which should be the
+/// live one.  The leaves shared by several devices are only read once, so
+/// this is much quicker than walking each mapping tree, and the trees are
+/// walked by several threads at once.  The devices are returned in order of
+/// their ids.
+pub fn account_devices(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    sb: &Superblock,
+    mapping_root: u64,
+    opts: &AccountingOptions,
+) -> Result<Vec<DeviceAccount>> {
+    let ctx = Context { engine, report };
+
+    let mut path = Vec::new();
+    let devs = btree_to_map::<u64>(
+        &mut path,
+        ctx.engine.clone(),
+        opts.ignore_non_fatal,
+        mapping_root,
+    )?;
+    let roots: Vec<u64> = devs.values().cloned().collect();
+
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[..])?;
+    let data_sm: Arc<Mutex<dyn SpaceMap + Send + Sync>> =
+        Arc::new(Mutex::new(RestrictedTwoSpaceMap::new(data_root.nr_blocks)));
+    let metadata_sm: Arc<Mutex<dyn SpaceMap + Send + Sync>> = Arc::new(Mutex::new(
+        RestrictedTwoSpaceMap::new(ctx.engine.get_nr_blocks()),
+    ));
+
+    // Every bitmap is cached, as the blocks are looked up in mapping order
+    let pool_sm = if opts.reclaimable {
+        let nr_bitmaps = div_up(data_root.nr_blocks, ENTRIES_PER_BITMAP as u64) as usize;
+        let sm = DiskSpaceMap::open_data(ctx.engine.clone(), data_root.clone())?
+            .with_cache_size(nr_bitmaps);
+        Some(Arc::new(sm))
+    } else {
+        None
+    };
+
+    ctx.report.set_title("Scanning data mappings");
+    let mon_meta_sm = metadata_sm.clone();
+    let mon_data_sm = data_sm.clone();
+    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let monitor = ProgressMonitor::new(
+        ctx.report.clone(),
+        metadata_root.nr_allocated + (data_root.nr_allocated / 8),
+        move || {
+            mon_meta_sm.lock().unwrap().get_nr_allocated().unwrap()
+                + (mon_data_sm.lock().unwrap().get_nr_allocated().unwrap() / 8)
+        },
+    );
+
+    let summaries = count_data_mappings_(
+        &ctx,
+        &metadata_sm,
+        &data_sm,
+        &pool_sm,
+        &roots,
+        opts.ignore_non_fatal,
+    )?;
+    let accounts: Vec<DeviceAccount> = devs
+        .iter()
+        .map_while(|(dev_id, root)| {
+            summaries
+                .get(*root as u32)
+                .filter(|sum| sum.nr_errors == 0)
+                .map(|sum| DeviceAccount {
+                    dev_id: *dev_id,
+                    mapped_blocks: sum.nr_mappings,
+                    exclusive_blocks: sum.nr_mappings - sum.nr_shared,
+                    shared_blocks: sum.nr_shared,
+                    reclaimable_blocks: opts.reclaimable.then_some(sum.nr_reclaimable),
+                    highest_mapped_block: (sum.nr_mappings > 0).then_some(sum.key_high),
+                })
+        })
+        .collect();
+
+    monitor.stop();
+    ctx.report.complete();
+
+    if accounts.len() != roots.len() {
+        return Err(anyhow!("metadata contains errors"));
+    }
+
+    Ok(accounts)
+}
+
+//------------------------------------------
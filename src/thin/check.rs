@@ -1,25 +1,32 @@
-use anyhow::{anyhow, Result};
-use fixedbitset::FixedBitSet;
-use std::collections::BTreeMap;
+use anyhow::{anyhow, Context as _, Result};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::path::Path;
-use std::sync::mpsc::{self, SyncSender};
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
+use crate::checksum::*;
 use crate::commands::engine::*;
+use crate::file_utils;
 use crate::hashvec::HashVec;
+use crate::io_engine::errors::IoErrorClass;
 use crate::io_engine::*;
-use crate::pdata::btree::{self, *};
+use crate::pdata::btree::*;
+use crate::pdata::btree_checksum::ChecksumFixer;
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::checker::*;
+use crate::pdata::space_map::chunked::*;
 use crate::pdata::space_map::common::*;
 use crate::pdata::space_map::*;
 use crate::pdata::unpack::*;
 use crate::report::*;
 use crate::thin::block_time::*;
+use crate::thin::check_history::{append_history, HistoryEntry};
 use crate::thin::device_detail::*;
-use crate::thin::metadata_repair::is_superblock_consistent;
+use crate::thin::metadata_repair::{is_superblock_consistent, Override, SuperblockOverrides};
+use crate::thin::node_map::*;
 use crate::thin::superblock::*;
 
 //------------------------------------------
@@ -38,6 +45,7 @@ fn inc_superblock(sm: &ASpaceMap, metadata_snap: u64) -> Result<()> {
 
 //------------------------------------------
 
+#[derive(Clone)]
 pub struct ThinCheckOptions<'a> {
     pub input: &'a Path,
     pub engine_opts: EngineOptions,
@@ -48,12 +56,45 @@ pub struct ThinCheckOptions<'a> {
     pub clear_needs_check: bool,
     pub override_mapping_root: Option<u64>,
     pub override_details_root: Option<u64>,
+    pub memory_limit: Option<u64>, // in bytes
+    pub nr_threads: Option<usize>,
+    pub data_dev: Option<&'a Path>,
+    pub verify_data_bounds: bool,
+    pub data_samples: Option<u64>,
+    pub error_budget: Option<u64>,
+    pub mapping_sample: Option<u8>, // percentage of the leaves to check
+    pub metrics_file: Option<&'a Path>,
+    /// The device named in the metrics, when the input is a temporary copy
+    /// of it, such as unpacked metadata
+    pub metrics_device: Option<&'a Path>,
+    /// Append the result to a history of the checks of the pool
+    pub history: Option<&'a Path>,
+    pub ref_count_histogram: bool,
+    pub fix_checksums: bool,
+    pub overrides: SuperblockOverrides,
     pub report: Arc<Report>,
 }
 
+/// The result of a check that didn't find any serious problems
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// No problems found, or those found were repaired or ignored
+    Clean,
+
+    /// Minor problems found, but within the error budget
+    Warnings,
+}
+
+/// An inconsistency that --auto-repair is able to fix, such as leaked blocks
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct RepairableError(String);
+
 struct Context {
     report: Arc<Report>,
     engine: Arc<dyn IoEngine + Send + Sync>,
+    nr_threads: usize,
+    memory_limit: Option<u64>,
 }
 
 //----------------------------------------
@@ -67,12 +108,6 @@ struct Context {
 // We know the metadata area is limited to 16G, so u32 is large enough
 // hold block numbers.
 
-#[derive(Debug, Clone)]
-struct InternalNodeInfo {
-    keys: Vec<u64>,
-    children: Vec<u32>,
-}
-
 #[derive(Debug, Clone, Default)]
 struct NodeSummary {
     key_low: u64,     // min mapped block
@@ -129,275 +164,40 @@ impl NodeSummary {
     }
 }
 
-#[derive(PartialEq)]
-enum NodeType {
-    None,
-    Internal,
-    Leaf,
-    Error,
-}
-
-#[derive(Debug)]
-struct NodeMap {
-    node_type: FixedBitSet,
-    leaf_nodes: FixedBitSet, // FIXME: remove this one
-    nr_leaves: u32,
-    internal_info: HashVec<InternalNodeInfo>,
-
-    // Stores errors of the node itself; errors in children are not included
-    node_errors: HashVec<NodeError>,
+// A damaged node found while summarizing the mapping trees, with the
+// context needed to locate it.
+#[derive(Debug, Clone)]
+struct Corruption {
+    path: Vec<u64>, // the superblock, then the blocks from the device root down
+    keys: KeyRange,
+    error: String,
+    roots: BTreeSet<u64>, // roots of the trees the node is reachable from
 }
 
-impl NodeMap {
-    fn new(nr_blocks: u32) -> NodeMap {
-        NodeMap {
-            node_type: FixedBitSet::with_capacity((nr_blocks as usize) * 2),
-            leaf_nodes: FixedBitSet::with_capacity(nr_blocks as usize),
-            nr_leaves: 0,
-            internal_info: HashVec::new(),
-            node_errors: HashVec::new(),
-        }
+impl Corruption {
+    fn block(&self) -> u64 {
+        *self.path.last().unwrap()
     }
 
-    fn get_type(&self, blocknr: u32) -> NodeType {
-        // FIXME: query two bits at once
-        let lsb = self.node_type.contains(blocknr as usize * 2);
-        let msb = self.node_type.contains(blocknr as usize * 2 + 1);
-        if !lsb && msb {
-            NodeType::Error
-        } else if lsb && !msb {
-            NodeType::Leaf
-        } else if lsb && msb {
-            NodeType::Internal
-        } else {
-            NodeType::None
-        }
-    }
-
-    fn set_type_(&mut self, blocknr: u32, t: NodeType) {
-        match t {
-            NodeType::Leaf => {
-                self.node_type.insert(blocknr as usize * 2);
-                self.leaf_nodes.insert(blocknr as usize);
-                self.nr_leaves += 1;
-            }
-            NodeType::Internal => {
-                // FIXME: update two bits at once
-                self.node_type.insert(blocknr as usize * 2);
-                self.node_type.insert(blocknr as usize * 2 + 1);
-                if self.leaf_nodes.contains(blocknr as usize) {
-                    self.leaf_nodes.toggle(blocknr as usize);
-                    self.nr_leaves -= 1;
-                }
-            }
-            NodeType::Error => {
-                // FIXME: update two bits at once
-                self.node_type.insert(blocknr as usize * 2 + 1);
-                if self.leaf_nodes.contains(blocknr as usize) {
-                    self.node_type.toggle(blocknr as usize * 2);
-                    self.leaf_nodes.toggle(blocknr as usize);
-                    self.nr_leaves -= 1;
-                }
-            }
-            _ => {}
-        }
-    }
-
-    fn insert_internal_node(&mut self, blocknr: u32, info: InternalNodeInfo) -> Result<()> {
-        // Only accepts converting a potential unread leaf
-        let node_type = self.get_type(blocknr);
-        if node_type != NodeType::None && node_type != NodeType::Leaf {
-            return Err(anyhow!("type changed"));
-        }
-        self.internal_info.insert(blocknr, info);
-        self.set_type_(blocknr, NodeType::Internal);
-        Ok(())
-    }
-
-    fn insert_leaf(&mut self, blocknr: u32) -> Result<()> {
-        // Only accepts an unread block
-        if self.get_type(blocknr) != NodeType::None {
-            return Err(anyhow!("type changed"));
-        }
-        self.set_type_(blocknr, NodeType::Leaf);
-        Ok(())
-    }
-
-    fn insert_error(&mut self, blocknr: u32, e: NodeError) -> Result<()> {
-        // Only accepts converting a potential unread leaf
-        let node_type = self.get_type(blocknr);
-        if node_type != NodeType::None && node_type != NodeType::Leaf {
-            return Err(anyhow!("type changed"));
-        }
-        self.node_errors.insert(blocknr, e);
-        self.set_type_(blocknr, NodeType::Error);
-        Ok(())
-    }
-
-    // Returns total number of nodes found
-    fn len(&self) -> u32 {
-        self.internal_info.len() as u32 + self.nr_leaves
-    }
-}
-
-fn is_seen(loc: u32, metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>) -> Result<bool> {
-    let mut sm = metadata_sm.lock().unwrap();
-    sm.inc(loc as u64, 1)?;
-    Ok(sm.get(loc as u64).unwrap_or(0) > 1)
-}
-
-// FIXME: split up this function
-fn read_node_(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    b: &Block,
-    depth: usize,
-    ignore_non_fatal: bool,
-    nodes: &mut NodeMap,
-) {
-    // allow underfull nodes in the first pass
-    let node = match check_and_unpack_node::<u64>(b, ignore_non_fatal, true) {
-        Ok(n) => n,
-        Err(e) => {
-            // theoretically never fail
-            let _ = nodes.insert_error(b.loc as u32, e);
-            return;
-        }
-    };
-
-    use btree::Node::*;
-    if let Internal { keys, values, .. } = node {
-        let children = values.iter().map(|v| *v as u32).collect::<Vec<u32>>(); // FIXME: slow
-
-        // insert the node info in pre-order fashion to better detect loops in the path
-        let info = InternalNodeInfo { keys, children };
-
-        let _ = nodes.insert_internal_node(b.loc as u32, info);
-
-        // filter out previously visited nodes
-        let mut new_values = Vec::with_capacity(values.len());
-        for v in values {
-            if let Ok(seen) = is_seen(v as u32, metadata_sm) {
-                // Add the unread leaf if now it looks like an internal.
-                // Add the visited internal if now it looks like a leaf.
-                let node_type = nodes.get_type(v as u32);
-                let maybe_internal = depth > 0 && node_type == NodeType::Leaf;
-                let maybe_leaf = depth == 0 && node_type == NodeType::None;
-                if !seen || maybe_internal || maybe_leaf {
-                    new_values.push(v);
-                }
-            }
-        }
-        let values = new_values;
-
-        if depth == 0 {
-            for loc in values {
-                let _ = nodes.insert_leaf(loc as u32);
-            }
-        } else {
-            // we could error each child rather than the current node
-            match ctx.engine.read_many(&values) {
-                Ok(bs) => {
-                    for (i, b) in bs.iter().enumerate() {
-                        if let Ok(b) = b {
-                            read_node(ctx, metadata_sm, b, depth - 1, ignore_non_fatal, nodes);
-                        } else {
-                            // theoretically never fail
-                            let _ = nodes.insert_error(values[i] as u32, NodeError::IoError);
-                        }
-                    }
-                }
-                Err(_) => {
-                    // error every child node
-                    for loc in values {
-                        // theoretically never fail
-                        let _ = nodes.insert_error(loc as u32, NodeError::IoError);
-                    }
-                }
-            };
-        }
+    // The root of the mapping tree is level 0
+    fn level(&self) -> usize {
+        self.path.len().saturating_sub(2)
     }
 }
 
-/// Reads a btree node and all internal btree nodes below it into the
-/// nodes parameter.  No errors are returned, instead the optional
-/// error field of the nodes will be filled in.
-fn read_node(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    b: &Block,
-    depth: usize,
-    ignore_non_fatal: bool,
-    nodes: &mut NodeMap,
-) {
-    read_node_(ctx, metadata_sm, b, depth, ignore_non_fatal, nodes);
-}
-
-/// Gets the depth of a bottom level mapping tree.  0 means the root is a leaf node.
-// FIXME: what if there's an error on the path to the leftmost leaf?
-fn get_depth(ctx: &Context, path: &mut Vec<u64>, root: u64, is_root: bool) -> Result<usize> {
-    use Node::*;
-
-    let b = ctx.engine.read(root).map_err(|_| io_err(path))?;
-    let node =
-        check_and_unpack_node::<BlockTime>(&b, true, is_root).map_err(|e| node_err(path, e))?;
-
-    match node {
-        Internal { values, .. } => {
-            // recurse down to the first good leaf
-            let mut last_err = None;
-            for child in values {
-                if path.contains(&child) {
-                    continue; // skip loops
-                }
-
-                path.push(child);
-                match get_depth(ctx, path, child, false) {
-                    Ok(n) => return Ok(n + 1),
-                    Err(e) => {
-                        last_err = Some(e);
-                    }
-                }
-                path.pop();
-            }
-            Err(last_err.unwrap_or_else(|| node_err(path, NodeError::NumEntriesTooSmall).into()))
-        }
-        Leaf { .. } => Ok(0),
-    }
-}
-
-fn read_internal_nodes(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    root: u32,
-    ignore_non_fatal: bool,
-    nodes: &mut NodeMap,
-) {
-    match is_seen(root, metadata_sm) {
-        Ok(true) | Err(_) => return,
-        _ => {}
-    }
-
-    // FIXME: make get-depth more resilient
-    let mut path = Vec::new();
-    let depth = if let Ok(d) = get_depth(ctx, &mut path, root as u64, true) {
-        d
-    } else {
-        return;
-    };
-
-    if depth == 0 {
-        // The root will be skipped if it is a confirmed internal
-        let _ = nodes.insert_leaf(root);
-        return;
-    }
-
-    if let Ok(b) = ctx.engine.read(root as u64) {
-        read_node(ctx, metadata_sm, &b, depth - 1, ignore_non_fatal, nodes);
-    } else {
-        // FIXME: factor out common code
-        let _ = nodes.insert_error(root, NodeError::IoError);
-    }
+fn corrupted(
+    corruptions: &mut Vec<Corruption>,
+    path: &[u64],
+    kr: &KeyRange,
+    error: String,
+) -> NodeSummary {
+    corruptions.push(Corruption {
+        path: path.to_vec(),
+        keys: kr.clone(),
+        error,
+        roots: BTreeSet::new(),
+    });
+    NodeSummary::error()
 }
 
 // Summarize a subtree rooted at the specified block.
@@ -410,28 +210,29 @@ fn summarize_tree(
     is_root: bool,
     nodes: &NodeMap,
     summaries: &mut HashVec<NodeSummary>,
+    corruptions: &mut Vec<Corruption>,
     ignore_non_fatal: bool,
 ) -> NodeSummary {
     if let Some(sum) = summaries.get(root) {
         // Check underfull
         if !ignore_non_fatal && !is_root && sum.nr_entries < MIN_ENTRIES {
-            return NodeSummary::error();
+            let e = format!("underfull node with {} entries", sum.nr_entries);
+            return corrupted(corruptions, path, kr, e);
         }
 
         // Check the key range against the parent keys.
         if sum.nr_mappings > 0 {
-            if let Some(n) = kr.start {
-                // The parent key could be less than or equal to,
-                // but not greater than the child's first key
-                if n > sum.key_low {
-                    return NodeSummary::error();
-                }
-            }
-            if let Some(n) = kr.end {
-                // note that KeyRange is a right-opened interval
-                if n < sum.key_high {
-                    return NodeSummary::error();
-                }
+            // The parent key could be less than or equal to,
+            // but not greater than the child's first key.
+            // Note that KeyRange is a right-opened interval.
+            let below = kr.start.map_or(false, |n| n > sum.key_low);
+            let above = kr.end.map_or(false, |n| n < sum.key_high);
+            if below || above {
+                let e = format!(
+                    "keys {}..={} fall outside of the parent key range",
+                    sum.key_low, sum.key_high
+                );
+                return corrupted(corruptions, path, kr, e);
             }
         }
 
@@ -443,14 +244,18 @@ fn summarize_tree(
             if let Some(info) = nodes.internal_info.get(root) {
                 // Check underfull
                 if !ignore_non_fatal && !is_root && info.keys.len() < MIN_ENTRIES as usize {
-                    return NodeSummary::error();
+                    let e = format!("underfull node with {} entries", info.keys.len());
+                    return corrupted(corruptions, path, kr, e);
                 }
 
                 // Split up the key range for the children.
                 // Return immediately if the keys don't match.
                 let child_keys = match split_key_ranges(path, kr, &info.keys) {
                     Ok(keys) => keys,
-                    Err(_) => return NodeSummary::error(),
+                    Err(_) => {
+                        let e = "keys don't match the parent key range".to_string();
+                        return corrupted(corruptions, path, kr, e);
+                    }
                 };
 
                 // Gather information from the children
@@ -464,6 +269,7 @@ fn summarize_tree(
                         false,
                         nodes,
                         summaries,
+                        corruptions,
                         ignore_non_fatal,
                     );
                     let _ = sum.append(&child_sums);
@@ -479,8 +285,89 @@ fn summarize_tree(
             }
         }
 
+        NodeType::Error => {
+            let e = nodes
+                .node_errors
+                .get(root)
+                .map_or_else(|| "bad node".to_string(), |e| e.to_string());
+            corrupted(corruptions, path, kr, e)
+        }
+
         // This is unexpected since a leaf should have been summarized
-        _ => NodeSummary::error(),
+        _ => corrupted(corruptions, path, kr, "couldn't read node".to_string()),
+    }
+}
+
+// Finds the damaged nodes reachable from the given tree, only descending
+// into subtrees that contain errors.
+fn find_damaged_nodes(
+    root: u32,
+    nodes: &NodeMap,
+    summaries: &HashVec<NodeSummary>,
+    damaged: &BTreeSet<u64>,
+    seen: &mut BTreeSet<u32>,
+    found: &mut BTreeSet<u64>,
+) {
+    if !seen.insert(root) {
+        return;
+    }
+
+    if damaged.contains(&(root as u64)) {
+        found.insert(root as u64);
+    }
+
+    if let Some(info) = nodes.internal_info.get(root) {
+        for child in &info.children {
+            let clean = summaries.get(*child).map_or(false, |s| s.nr_errors == 0);
+            if !clean || damaged.contains(&(*child as u64)) {
+                find_damaged_nodes(*child, nodes, summaries, damaged, seen, found);
+            }
+        }
+    }
+}
+
+// Works out which trees each damaged node belongs to.  A node that's
+// shared between snapshots is only summarized once, so the path it was
+// found on names just one of them.
+fn find_impacted_roots(
+    roots: &[u64],
+    nodes: &NodeMap,
+    summaries: &HashVec<NodeSummary>,
+    corruptions: &mut [Corruption],
+) {
+    let damaged: BTreeSet<u64> = corruptions.iter().map(|c| c.block()).collect();
+
+    let mut impacts: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+    for root in roots {
+        let clean = summaries
+            .get(*root as u32)
+            .map_or(false, |s| s.nr_errors == 0);
+        if clean && !damaged.contains(root) {
+            continue;
+        }
+
+        let mut seen = BTreeSet::new();
+        let mut found = BTreeSet::new();
+        find_damaged_nodes(
+            *root as u32,
+            nodes,
+            summaries,
+            &damaged,
+            &mut seen,
+            &mut found,
+        );
+        for b in found {
+            impacts.entry(b).or_default().insert(*root);
+        }
+    }
+
+    for c in corruptions {
+        if let Some(roots) = impacts.get(&c.block()) {
+            c.roots = roots.clone();
+        }
+        if let Some(root) = c.path.get(1) {
+            c.roots.insert(*root);
+        }
     }
 }
 
@@ -491,11 +378,17 @@ fn check_mappings_bottom_level_(
     data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     roots: &[u64],
     ignore_non_fatal: bool,
-) -> Result<HashVec<NodeSummary>> {
+) -> Result<(HashVec<NodeSummary>, Vec<Corruption>)> {
     let report = &ctx.report;
 
     let start = std::time::Instant::now();
-    let nodes = collect_nodes_in_use(ctx, metadata_sm, roots, ignore_non_fatal);
+    let nodes = collect_nodes_in_use(
+        ctx.engine.as_ref(),
+        metadata_sm,
+        roots,
+        ignore_non_fatal,
+        node_map_limit(ctx.memory_limit),
+    )?;
     let duration = start.elapsed();
     report.debug(&format!("reading internal nodes: {:?}", duration));
 
@@ -505,7 +398,8 @@ fn check_mappings_bottom_level_(
     report.debug(&format!("reading leaf nodes: {:?}", duration));
 
     let start = std::time::Instant::now();
-    count_mapped_blocks(roots, &nodes, &mut summaries, ignore_non_fatal);
+    let mut corruptions = count_mapped_blocks(roots, &nodes, &mut summaries, ignore_non_fatal);
+    find_impacted_roots(roots, &nodes, &summaries, &mut corruptions);
     let duration = start.elapsed();
     report.debug(&format!("counting mapped blocks: {:?}", duration));
 
@@ -515,9 +409,19 @@ fn check_mappings_bottom_level_(
     if !nodes.node_errors.is_empty() {
         let mut nr_io_errors = 0;
         let mut nr_checksum_errors = 0;
-        for e in nodes.node_errors.values() {
+        let mut classified: BTreeMap<&'static str, (IoErrorClass, Vec<u32>)> = BTreeMap::new();
+        for (loc, e) in nodes.node_errors.iter() {
             match e {
-                NodeError::IoError => nr_io_errors += 1,
+                NodeError::IoError(class) => {
+                    nr_io_errors += 1;
+                    if *class != IoErrorClass::Other {
+                        classified
+                            .entry(class.as_str())
+                            .or_insert_with(|| (*class, Vec::new()))
+                            .1
+                            .push(loc);
+                    }
+                }
                 NodeError::ChecksumError => nr_checksum_errors += 1,
                 _ => {}
             }
@@ -530,32 +434,74 @@ fn check_mappings_bottom_level_(
             "{} io errors, {} checksum errors",
             nr_io_errors, nr_checksum_errors
         ));
+        for (class, mut locs) in classified.into_values() {
+            report_classified_io_errors(report, class, &mut locs);
+        }
     }
 
-    Ok(summaries)
+    Ok((summaries, corruptions))
 }
 
-fn collect_nodes_in_use(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    roots: &[u64],
-    ignore_non_fatal: bool,
-) -> NodeMap {
-    let mut nodes = NodeMap::new(ctx.engine.get_nr_blocks() as u32);
+// Says what went wrong with the io of each block, up to a limit, rather
+// than leave the cause to a bare count.
+fn report_classified_io_errors(report: &Report, class: IoErrorClass, locs: &mut [u32]) {
+    const MAX_REPORTED: usize = 10;
 
-    for root in roots {
-        read_internal_nodes(ctx, metadata_sm, *root as u32, ignore_non_fatal, &mut nodes);
+    locs.sort_unstable();
+    for loc in locs.iter().take(MAX_REPORTED) {
+        report.fatal(&format!("{} at block {}", class, loc));
     }
+    if locs.len() > MAX_REPORTED {
+        report.fatal(&format!(
+            "{} at {} more blocks",
+            class,
+            locs.len() - MAX_REPORTED
+        ));
+    }
+}
 
-    nodes
+// Leaf nodes are checked by a pool of worker threads.  Each worker builds
+// its own node summaries, which are merged once all the leaves have been
+// read, and batches up the data block increments so the shared data space
+// map is only locked once per batch.
+const DEFAULT_NR_THREADS: usize = 4;
+const INC_BATCH_SIZE: usize = 16384;
+
+// Applies the batched increments, coalescing runs of adjacent blocks.
+fn flush_data_incs(data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>, blocks: &mut Vec<u64>) {
+    blocks.sort_unstable();
+
+    let mut data_sm = data_sm.lock().unwrap();
+    let mut i = 0;
+    while i < blocks.len() {
+        let begin = blocks[i];
+        let mut len = 1;
+        while i + len < blocks.len() && blocks[i + len] == begin + len as u64 {
+            len += 1;
+        }
+
+        // Ignore errors on increment, but make sure the valid blocks
+        // within a run that crosses the end of the space map are counted.
+        if data_sm.inc(begin, len as u64).is_err() {
+            for b in begin..(begin + len as u64) {
+                let _ = data_sm.inc(b, 1);
+            }
+        }
+        i += len;
+    }
+
+    blocks.clear();
 }
 
-fn unpacker(
+fn leaf_checker(
     blocks_rx: &Arc<Mutex<mpsc::Receiver<Vec<Block>>>>,
-    nodes_tx: SyncSender<Vec<Node<BlockTime>>>,
-    node_map: Arc<Mutex<NodeMap>>,
+    node_map: &Arc<Mutex<NodeMap>>,
+    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     ignore_non_fatal: bool,
-) {
+) -> HashVec<NodeSummary> {
+    let mut summaries = HashVec::new();
+    let mut incs = Vec::with_capacity(INC_BATCH_SIZE);
+
     loop {
         let blocks = {
             let blocks_rx = blocks_rx.lock().unwrap();
@@ -566,15 +512,28 @@ fn unpacker(
             }
         };
 
-        let mut nodes = Vec::with_capacity(blocks.len());
         let mut errs = Vec::new();
 
         for b in blocks {
             // Allow under full nodes in this phase.  The under full
             // property will be check later based on the path context.
             match check_and_unpack_node::<BlockTime>(&b, ignore_non_fatal, true) {
-                Ok(n) => {
-                    nodes.push(n);
+                Ok(Node::Leaf {
+                    keys,
+                    values,
+                    header,
+                }) => {
+                    incs.extend(values.iter().map(|v| v.block));
+                    if incs.len() >= INC_BATCH_SIZE {
+                        flush_data_incs(data_sm, &mut incs);
+                    }
+
+                    let sum = NodeSummary::from_leaf(&keys);
+                    summaries.insert(header.block as u32, sum);
+                }
+                Ok(_) => {
+                    // Do not report error here. The error will be captured
+                    // in the second phase.
                 }
                 Err(e) => {
                     errs.push((b.loc, e));
@@ -589,50 +548,11 @@ fn unpacker(
                 let _ = node_map.insert_error(b as u32, e);
             }
         }
-
-        if nodes_tx.send(nodes).is_err() {
-            break;
-        }
     }
-}
-
-fn summariser(
-    nodes_rx: mpsc::Receiver<Vec<Node<BlockTime>>>,
-    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    summaries: &Arc<Mutex<HashVec<NodeSummary>>>,
-) {
-    let mut summaries = summaries.lock().unwrap();
 
-    loop {
-        let nodes = {
-            if let Ok(nodes) = nodes_rx.recv() {
-                nodes
-            } else {
-                break;
-            }
-        };
-
-        for n in nodes {
-            if let Node::Leaf {
-                keys,
-                values,
-                header,
-            } = n
-            {
-                let mut data_sm = data_sm.lock().unwrap();
-                for v in values {
-                    // Ignore errors on increment
-                    let _ = data_sm.inc(v.block, 1);
-                }
+    flush_data_incs(data_sm, &mut incs);
 
-                let sum = NodeSummary::from_leaf(&keys);
-                summaries.insert(header.block as u32, sum);
-            } else {
-                // Do not report error here. The error will be captured
-                // in the second phase.
-            }
-        }
-    }
+    summaries
 }
 
 fn read_leaf_nodes(
@@ -641,12 +561,10 @@ fn read_leaf_nodes(
     data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     ignore_non_fatal: bool,
 ) -> Result<(NodeMap, HashVec<NodeSummary>)> {
-    const QUEUE_DEPTH: usize = 4;
-    const NR_UNPACKERS: usize = 4;
-
     // Single IO thread reads vecs of blocks
-    // Many unpackers take the block vecs and turn them into btree nodes
-    // Single 'summariser' thread processes the nodes
+    // Many leaf checkers take the block vecs, turn them into btree nodes,
+    // and count the mapped data blocks.
+    let queue_depth = ctx.nr_threads * 2;
 
     // Build a vec of the leaf locations.  These will be in disk location
     // order.
@@ -655,75 +573,76 @@ fn read_leaf_nodes(
         leaves.push(loc as u64);
     }
 
-    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(QUEUE_DEPTH);
+    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(queue_depth);
     let blocks_rx = Arc::new(Mutex::new(blocks_rx));
 
-    let (nodes_tx, nodes_rx) = mpsc::sync_channel::<Vec<Node<BlockTime>>>(QUEUE_DEPTH);
-
-    // Process chunks of leaves at once so the io engine can aggregate reads.
-    let summaries = Arc::new(Mutex::new(HashVec::with_capacity(nodes.len())));
+    let nr_nodes = nodes.len();
     let nodes = Arc::new(Mutex::new(nodes));
 
-    // Kick off the unpackers
-    let mut unpackers = Vec::with_capacity(NR_UNPACKERS);
-    for _i in 0..NR_UNPACKERS {
+    // Kick off the leaf checkers
+    let mut checkers = Vec::with_capacity(ctx.nr_threads);
+    for _i in 0..ctx.nr_threads {
         let blocks_rx = blocks_rx.clone();
-        let nodes_tx = nodes_tx.clone();
         let node_map = nodes.clone();
-        unpackers.push(thread::spawn(move || {
-            unpacker(&blocks_rx, nodes_tx, node_map, ignore_non_fatal)
+        let data_sm = data_sm.clone();
+        checkers.push(thread::spawn(move || {
+            leaf_checker(&blocks_rx, &node_map, &data_sm, ignore_non_fatal)
         }));
     }
     drop(blocks_rx);
-    drop(nodes_tx);
-
-    // Kick off the summariser
-    let summariser_tid = {
-        let data_sm = data_sm.clone();
-        let summaries = summaries.clone();
-        thread::spawn(move || {
-            summariser(nodes_rx, &data_sm, &summaries);
-        })
-    };
 
-    // IO is done in the main thread
+    // IO is done in the main thread.
+    // Process chunks of leaves at once so the io engine can aggregate reads.
     let engine = ctx.engine.clone();
     for c in leaves.chunks(1024) {
         let mut bs = Vec::with_capacity(c.len());
 
         // TODO: Retry blocks ignored by vectored io
-        if let Ok(blocks) = engine.read_many(c) {
-            for b in blocks {
-                if b.is_err() {
-                    continue;
+        match engine.read_many(c) {
+            Ok(blocks) => {
+                // Unreadable leaves are recorded, so the failures are
+                // reported by cause.
+                let mut failed = Vec::new();
+                for (loc, b) in c.iter().zip(blocks) {
+                    match b {
+                        Ok(b) => bs.push(b),
+                        Err(e) => failed.push((*loc, IoErrorClass::of(&e))),
+                    }
+                }
+                if !failed.is_empty() {
+                    let mut nodes = nodes.lock().unwrap();
+                    for (loc, class) in failed {
+                        let _ = nodes.insert_error(loc as u32, NodeError::IoError(class));
+                    }
                 }
 
-                let b = b.unwrap();
-                bs.push(b);
+                blocks_tx
+                    .send(bs)
+                    .expect("couldn't send blocks to leaf checker");
             }
-
-            blocks_tx
-                .send(bs)
-                .expect("couldn't send blocks to unpacker");
-        } else {
-            let mut nodes = nodes.lock().unwrap();
-            for b in c {
-                let _ = nodes.insert_error(*b as u32, NodeError::IoError);
+            Err(e) => {
+                let class = IoErrorClass::of(&e);
+                let mut nodes = nodes.lock().unwrap();
+                for b in c {
+                    let _ = nodes.insert_error(*b as u32, NodeError::IoError(class));
+                }
             }
         }
     }
 
     drop(blocks_tx);
 
-    // Wait for child threads
-    for tid in unpackers {
-        tid.join().expect("couldn't join unpacker");
+    // Wait for child threads, merging their summaries
+    let mut summaries = HashVec::with_capacity(nr_nodes);
+    for tid in checkers {
+        let sums = tid.join().expect("couldn't join leaf checker");
+        for (loc, sum) in sums.iter() {
+            summaries.insert(loc, sum.clone());
+        }
     }
-    summariser_tid.join().expect("couldn't join summariser");
 
     // extract the results
     let nodes = Arc::try_unwrap(nodes).unwrap().into_inner().unwrap();
-    let summaries = Arc::try_unwrap(summaries).unwrap().into_inner().unwrap();
 
     Ok((nodes, summaries))
 }
@@ -744,8 +663,12 @@ fn count_mapped_blocks(
     nodes: &NodeMap,
     summaries: &mut HashVec<NodeSummary>,
     ignore_non_fatal: bool,
-) {
-    for root in roots.iter() {
+) -> Vec<Corruption> {
+    let mut corruptions = Vec::new();
+
+    // A root may be shared by several devices, only visit it once
+    let roots: BTreeSet<&u64> = roots.iter().collect();
+    for root in roots {
         let mut path = vec![0, *root]; // the path is just for error reporting
         let kr = KeyRange::new();
         summarize_tree(
@@ -755,9 +678,76 @@ fn count_mapped_blocks(
             true,
             nodes,
             summaries,
+            &mut corruptions,
             ignore_non_fatal,
         );
     }
+    corruptions
+}
+
+//------------------------------------------
+
+const MAX_REPORTED_CORRUPTIONS: usize = 32;
+
+fn format_ids<'a>(ids: impl Iterator<Item = &'a u64>) -> String {
+    ids.map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+// Reports where each damaged node lies, and which thin devices it puts
+// at risk, to help decide between thin_repair and restoring a backup.
+fn report_corruptions(
+    ctx: &Context,
+    corruptions: &[Corruption],
+    dev_ids: &BTreeMap<u64, BTreeSet<u64>>, // root to thin ids
+) {
+    let report = &ctx.report;
+    let mut at_risk = BTreeSet::new();
+
+    for (i, c) in corruptions.iter().enumerate() {
+        let ids: BTreeSet<u64> = c
+            .roots
+            .iter()
+            .filter_map(|root| dev_ids.get(root))
+            .flatten()
+            .cloned()
+            .collect();
+
+        if i < MAX_REPORTED_CORRUPTIONS {
+            let path = c
+                .path
+                .iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            report.fatal(&format!(
+                "damaged mapping tree node at block {}, level {}: {}",
+                c.block(),
+                c.level(),
+                c.error
+            ));
+            report.fatal(&format!(
+                "  keys {}, path {}, thin devices {}",
+                c.keys,
+                path,
+                format_ids(ids.iter())
+            ));
+        }
+        at_risk.extend(ids);
+    }
+
+    if corruptions.len() > MAX_REPORTED_CORRUPTIONS {
+        report.fatal(&format!(
+            "{} more damaged nodes not shown",
+            corruptions.len() - MAX_REPORTED_CORRUPTIONS
+        ));
+    }
+
+    if !at_risk.is_empty() {
+        report.fatal(&format!(
+            "thin devices at risk: {}",
+            format_ids(at_risk.iter())
+        ));
+    }
 }
 
 //------------------------------------------
@@ -809,16 +799,259 @@ fn check_mapped_blocks(
     }
 }
 
-fn mk_context_(engine: Arc<dyn IoEngine + Send + Sync>, report: Arc<Report>) -> Result<Context> {
-    Ok(Context { report, engine })
+//------------------------------------------
+
+// Reads a random sample of the leaves of the mapping trees, checking the
+// nodes and that they only map blocks within the pool.  The internal nodes
+// are all read, but since most leaves aren't, neither the mapped block
+// counts nor the data space map can be verified.
+fn check_sampled_mappings(
+    ctx: &Context,
+    sb: &Superblock,
+    metadata_sm: &ASpaceMap,
+    roots: &[u64],
+    percent: u8,
+    ignore_non_fatal: bool,
+) -> Result<()> {
+    use rand::Rng;
+
+    let report = &ctx.report;
+    let nodes = collect_nodes_in_use(
+        ctx.engine.as_ref(),
+        metadata_sm,
+        roots,
+        ignore_non_fatal,
+        node_map_limit(ctx.memory_limit),
+    )?;
+    let nr_data_blocks = unpack::<SMRoot>(&sb.data_sm_root[0..])?.nr_blocks;
+
+    let fraction = percent as f64 / 100.0;
+    let mut rng = rand::thread_rng();
+    let leaves: Vec<u64> = nodes
+        .leaf_nodes
+        .ones()
+        .map(|loc| loc as u64)
+        .filter(|_| rng.gen_bool(fraction))
+        .collect();
+
+    let mut nr_errors = nodes.node_errors.len();
+    for c in leaves.chunks(1024) {
+        let blocks = ctx.engine.read_many(c)?;
+        for (loc, b) in c.iter().zip(blocks) {
+            let node = b
+                .map_err(|e| NodeError::IoError(IoErrorClass::of(&e)))
+                .and_then(|b| check_and_unpack_node::<BlockTime>(&b, ignore_non_fatal, true));
+            match node {
+                Ok(Node::Leaf { values, .. }) => {
+                    if let Some(v) = values.iter().find(|v| v.block >= nr_data_blocks) {
+                        report.fatal(&format!(
+                            "leaf node {} maps data block {}, beyond the {} blocks of the pool",
+                            loc, v.block, nr_data_blocks
+                        ));
+                        nr_errors += 1;
+                    }
+                }
+                Ok(_) => {
+                    report.fatal(&format!("node {} was expected to be a leaf", loc));
+                    nr_errors += 1;
+                }
+                Err(e) => {
+                    report.fatal(&format!("leaf node {}: {}", loc, e));
+                    nr_errors += 1;
+                }
+            }
+        }
+    }
+
+    report.info(&format!("nr internal nodes: {}", nodes.internal_info.len()));
+    report.info(&format!(
+        "sampled {} of {} leaves",
+        leaves.len(),
+        nodes.nr_leaves
+    ));
+
+    // so a clean outcome isn't taken for a full check
+    report.warning(&format!(
+        "only {}% of the mapping leaves were sampled ({} of {}), not a full check",
+        percent,
+        leaves.len(),
+        nodes.nr_leaves
+    ));
+
+    if nr_errors > 0 {
+        report.fatal(&format!(
+            "{} nodes in data mapping tree contain errors",
+            nr_errors
+        ));
+        return Err(anyhow!("Check of sampled mappings failed"));
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
+
+// Cross checks the mappings against the data device, to catch metadata
+// that has been paired with the wrong data volume.
+fn check_data_dev(
+    ctx: &Context,
+    sb: &Superblock,
+    data_sm: &ASpaceMap,
+    opts: &ThinCheckOptions,
+    data_dev: &Path,
+) -> Result<()> {
+    use rand::Rng;
+    use std::os::unix::fs::FileExt;
+
+    let report = &ctx.report;
+    report.set_sub_title("data device");
+
+    // a damaged superblock may carry a zero block size
+    if sb.data_block_size == 0 {
+        return Err(anyhow!("the superblock has a data block size of zero"));
+    }
+    let block_bytes = (sb.data_block_size as u64) << SECTOR_SHIFT;
+    let dev_size = file_utils::file_size(data_dev)
+        .map_err(|e| anyhow!("couldn't get the size of the data device: {}", e))?;
+    let dev_blocks = dev_size / block_bytes;
+    let nr_data_blocks = data_sm.lock().unwrap().get_nr_blocks()?;
+    report.info(&format!("data device blocks: {}", dev_blocks));
+
+    let file = std::fs::File::open(data_dev)?;
+
+    // A data device that starts with thin metadata is most likely the
+    // metadata device given twice.
+    if dev_size >= BLOCK_SIZE as u64 {
+        let mut buf = vec![0; BLOCK_SIZE];
+        file.read_exact_at(&mut buf, 0)?;
+        if metadata_block_type(&buf) == BT::THIN_SUPERBLOCK {
+            report.warning("the data device starts with a thin pool superblock");
+        }
+    }
+
+    if dev_blocks < nr_data_blocks {
+        let sm = data_sm.lock().unwrap();
+        let mut nr_beyond = 0;
+        for b in dev_blocks..nr_data_blocks {
+            if sm.get(b)? > 0 {
+                nr_beyond += 1;
+            }
+        }
+
+        if nr_beyond > 0 && opts.verify_data_bounds {
+            report.fatal(&format!(
+                "{} mapped data blocks lie beyond the end of the data device ({} blocks)",
+                nr_beyond, dev_blocks
+            ));
+            return Err(anyhow!("Check of data device bounds failed"));
+        }
+
+        report.warning(&format!(
+            "data device is smaller than the pool, {} blocks expected, {} found",
+            nr_data_blocks, dev_blocks
+        ));
+    }
+
+    if let Some(nr_samples) = opts.data_samples {
+        // reservoir sample the mapped blocks that are on the device
+        let mut rng = rand::thread_rng();
+        let mut samples = Vec::new();
+        let mut nr_seen = 0u64;
+        {
+            let sm = data_sm.lock().unwrap();
+            for b in 0..std::cmp::min(dev_blocks, nr_data_blocks) {
+                if sm.get(b)? == 0 {
+                    continue;
+                }
+                nr_seen += 1;
+                if (samples.len() as u64) < nr_samples {
+                    samples.push(b);
+                } else {
+                    let i = rng.gen_range(0..nr_seen);
+                    if i < nr_samples {
+                        samples[i as usize] = b;
+                    }
+                }
+            }
+        }
+        samples.sort_unstable();
+
+        let mut buf = vec![0; std::cmp::min(block_bytes, BLOCK_SIZE as u64) as usize];
+        let mut nr_zeroed = 0;
+        for b in &samples {
+            file.read_exact_at(&mut buf, b * block_bytes)
+                .map_err(|e| anyhow!("couldn't read data block {}: {}", b, e))?;
+            if buf.iter().all(|v| *v == 0) {
+                nr_zeroed += 1;
+            }
+        }
+        report.info(&format!("sampled data blocks: {}", samples.len()));
+
+        if !samples.is_empty() && nr_zeroed == samples.len() {
+            report.warning(&format!(
+                "all {} sampled data blocks are zeroed, is this the right data device?",
+                samples.len()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
+
+fn mk_context_(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    nr_threads: usize,
+    memory_limit: Option<u64>,
+) -> Result<Context> {
+    if nr_threads == 0 {
+        return Err(anyhow!("the number of threads must be greater than zero"));
+    }
+
+    Ok(Context {
+        report,
+        engine,
+        nr_threads,
+        memory_limit,
+    })
 }
 
 fn mk_context(opts: &ThinCheckOptions) -> Result<Context> {
+    if opts.engine_opts.io_threads == 0 {
+        return Err(anyhow!(
+            "the number of io threads must be greater than zero"
+        ));
+    }
+
+    // The worker threads are spawned later, so they inherit the affinity
+    // of the main thread.
+    if let Some(cpus) = &opts.engine_opts.cpu_affinity {
+        cpus.pin_current_thread()
+            .map_err(|e| anyhow!("couldn't restrict the threads to cpus {}: {}", cpus, e))?;
+    }
+
+    let writable = opts.auto_repair || opts.clear_needs_check || opts.fix_checksums;
     let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
-        .write(opts.auto_repair || opts.clear_needs_check)
+        .write(writable)
+        .read_only(!writable)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
-    mk_context_(engine, opts.report.clone())
+    mk_context_(
+        engine,
+        opts.report.clone(),
+        opts.nr_threads.unwrap_or(DEFAULT_NR_THREADS),
+        opts.memory_limit,
+    )
+}
+
+fn print_ref_count_histogram(report: &Report, h: &RefCountHistogram) {
+    report.to_stdout(&format!("DATA_REF_COUNT_1={}", h.nr_single));
+    report.to_stdout(&format!("DATA_REF_COUNT_2={}", h.nr_double));
+    report.to_stdout(&format!("DATA_REF_COUNT_3_PLUS={}", h.nr_shared));
+    report.to_stdout(&format!("DATA_REF_COUNT_OVERFLOW={}", h.nr_overflow));
+    report.to_stdout(&format!("DATA_REF_COUNT_MAX={}", h.max_count));
 }
 
 fn print_info(sb: &Superblock, report: Arc<Report>) -> Result<()> {
@@ -831,6 +1064,64 @@ fn print_info(sb: &Superblock, report: Arc<Report>) -> Result<()> {
     Ok(())
 }
 
+//------------------------------------------
+
+// Values that are valid, but unlikely to be what was intended.  They're
+// reported as warnings, each with its own code, and never fail the check.
+
+const MIN_DATA_BLOCK_SIZE: u32 = 128; // sectors
+const MAX_LIKELY_TRANSACTION_ID: u64 = 1 << 48;
+
+struct Lint {
+    code: &'static str,
+    msg: String,
+}
+
+fn lint_superblock(sb: &Superblock) -> Result<Vec<Lint>> {
+    let mut lints = Vec::new();
+
+    let bs = sb.data_block_size;
+    if bs % MIN_DATA_BLOCK_SIZE == 0 && !bs.is_power_of_two() {
+        lints.push(Lint {
+            code: "L001",
+            msg: format!(
+                "the data block size of {} sectors is not a power of two",
+                bs
+            ),
+        });
+    }
+
+    if sb.transaction_id >= MAX_LIKELY_TRANSACTION_ID {
+        lints.push(Lint {
+            code: "L002",
+            msg: format!(
+                "the transaction id {} is implausibly high",
+                sb.transaction_id
+            ),
+        });
+    }
+
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    if data_root.nr_blocks == 0 && data_root.nr_allocated > 0 {
+        lints.push(Lint {
+            code: "L003",
+            msg: format!(
+                "the pool has no data blocks, but {} are allocated",
+                data_root.nr_allocated
+            ),
+        });
+    }
+
+    Ok(lints)
+}
+
+fn report_lints(sb: &Superblock, report: &Report) -> Result<()> {
+    for l in lint_superblock(sb)? {
+        report.warning(&format!("warning {}: {}", l.code, l.msg));
+    }
+    Ok(())
+}
+
 #[derive(thiserror::Error, Debug)]
 struct MetadataError {
     context: String,
@@ -850,6 +1141,37 @@ fn metadata_err(context: &str, err: anyhow::Error) -> MetadataError {
     }
 }
 
+// Creates a counting space map.  If the in-core version would exceed the
+// memory limit then a disk backed space map is used instead, caching at most
+// memory_limit bytes of counts.
+fn create_counting_sm(
+    nr_entries: u64,
+    max_count: u32,
+    memory_limit: Option<u64>,
+) -> Result<ASpaceMap> {
+    match memory_limit {
+        Some(limit) if core_sm_size(nr_entries, max_count) > limit => {
+            chunked_sm(nr_entries, max_count, limit as usize)
+        }
+        _ => Ok(core_sm(nr_entries, max_count)),
+    }
+}
+
+// The memory limit is shared out between the space maps, leaving a quarter
+// for the node maps and summaries built while walking the mapping trees.
+// Half of that quarter goes to the bitsets of the node maps.
+fn metadata_sm_limit(memory_limit: Option<u64>) -> Option<u64> {
+    memory_limit.map(|limit| limit / 4)
+}
+
+fn node_map_limit(memory_limit: Option<u64>) -> Option<u64> {
+    memory_limit.map(|limit| limit / 8)
+}
+
+fn data_sm_limit(memory_limit: Option<u64>) -> Option<u64> {
+    memory_limit.map(|limit| limit / 2)
+}
+
 // We read the top-level tree once to get the number of thin devices, and hence the
 // maximum metadata ref count.  Then create metadata space map.
 fn create_metadata_sm(
@@ -857,12 +1179,16 @@ fn create_metadata_sm(
     sb: &Superblock,
     sb_snap: &Option<Result<Superblock>>,
     ignore_non_fatal: bool,
+    memory_limit: Option<u64>,
 ) -> Result<ASpaceMap> {
     let mut path = vec![0];
 
     // Use a temporary space map to reach out non-shared leaves so we could get
     // the maximum reference count of a bottom-level leaf it could be.
-    let metadata_sm = Arc::new(Mutex::new(RestrictedSpaceMap::new(engine.get_nr_blocks())));
+    let metadata_sm = Arc::new(Mutex::new(RestrictedSpaceMap::with_limit(
+        engine.get_nr_blocks(),
+        metadata_sm_limit(memory_limit),
+    )?));
 
     let roots = btree_to_map_with_sm::<u64>(
         &mut path,
@@ -887,9 +1213,11 @@ fn create_metadata_sm(
         nr_devs += roots_snap.len();
     }
 
-    let metadata_sm = core_sm(engine.get_nr_blocks(), nr_devs as u32);
-
-    Ok(metadata_sm)
+    create_counting_sm(
+        engine.get_nr_blocks(),
+        nr_devs as u32,
+        metadata_sm_limit(memory_limit),
+    )
 }
 
 fn get_devices_(
@@ -1018,7 +1346,7 @@ fn check_mappings_bottom_level(
     data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     roots: &[u64],
     ignore_non_fatal: bool,
-) -> Result<HashVec<NodeSummary>> {
+) -> Result<(HashVec<NodeSummary>, Vec<Corruption>)> {
     let report = &ctx.report;
 
     let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
@@ -1035,28 +1363,32 @@ fn check_mappings_bottom_level(
         },
     );
 
-    let summaries =
-        check_mappings_bottom_level_(ctx, metadata_sm, data_sm, roots, ignore_non_fatal);
+    let r = check_mappings_bottom_level_(ctx, metadata_sm, data_sm, roots, ignore_non_fatal);
 
     monitor.stop();
 
-    summaries
+    r
 }
 
-fn create_data_sm(sb: &Superblock, nr_devs: u32) -> Result<ASpaceMap> {
+fn create_data_sm(sb: &Superblock, nr_devs: u32, memory_limit: Option<u64>) -> Result<ASpaceMap> {
     let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
 
-    let data_sm = if nr_devs <= 1 {
-        Arc::new(Mutex::new(RestrictedSpaceMap::new(data_root.nr_blocks)))
+    let data_sm: ASpaceMap = if nr_devs <= 1 {
+        Arc::new(Mutex::new(RestrictedSpaceMap::with_limit(
+            data_root.nr_blocks,
+            data_sm_limit(memory_limit),
+        )?))
     } else {
-        core_sm(data_root.nr_blocks, nr_devs)
+        create_counting_sm(data_root.nr_blocks, nr_devs, data_sm_limit(memory_limit))?
     };
 
     Ok(data_sm)
 }
 
-pub fn check(opts: ThinCheckOptions) -> Result<()> {
-    if (opts.auto_repair || opts.clear_needs_check)
+// `transaction_id` - Set to the transaction of the superblock checked, once
+// it has been read
+fn check_(opts: ThinCheckOptions, transaction_id: &mut Option<u64>) -> Result<CheckOutcome> {
+    if (opts.auto_repair || opts.clear_needs_check || opts.fix_checksums)
         && (opts.engine_opts.use_metadata_snap
             || opts.override_mapping_root.is_some()
             || opts.override_details_root.is_some())
@@ -1070,7 +1402,8 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     let report = &ctx.report;
     let engine = &ctx.engine;
 
-    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)
+        .and_then(|sb| sb.overrides(&opts.overrides))?;
 
     // Read the superblock in metadata snapshot (allow errors)
     let sb_snap = if sb.metadata_snap > 0 {
@@ -1089,7 +1422,21 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         }
     }
 
+    *transaction_id = Some(match sb_snap {
+        Some(Ok(ref snap)) if opts.engine_opts.use_metadata_snap => snap.transaction_id,
+        _ => sb.transaction_id,
+    });
+
     let _ = print_info(&sb, report.clone());
+    let _ = report_lints(&sb, report);
+
+    if opts.fix_checksums {
+        report.set_sub_title("btree node checksums");
+        let nr_fixed = fix_checksums(engine.clone(), report.clone(), &sb)?;
+        if nr_fixed > 0 {
+            report.warning(&format!("Fixed the checksums of {} btree nodes", nr_fixed));
+        }
+    }
 
     if opts.sb_only {
         if opts.clear_needs_check {
@@ -1098,7 +1445,7 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
                 report.warning("Cleared needs_check flag");
             }
         }
-        return Ok(());
+        return Ok(CheckOutcome::Clean);
     }
 
     //------------------------------------
@@ -1106,9 +1453,18 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     report.set_title("Checking thin metadata");
 
     let metadata_sm = if opts.engine_opts.use_metadata_snap {
-        Arc::new(Mutex::new(RestrictedSpaceMap::new(engine.get_nr_blocks())))
+        Arc::new(Mutex::new(RestrictedSpaceMap::with_limit(
+            engine.get_nr_blocks(),
+            metadata_sm_limit(opts.memory_limit),
+        )?))
     } else {
-        create_metadata_sm(engine, &sb, &sb_snap, opts.ignore_non_fatal)?
+        create_metadata_sm(
+            engine,
+            &sb,
+            &sb_snap,
+            opts.ignore_non_fatal,
+            opts.memory_limit,
+        )?
     };
 
     inc_superblock(&metadata_sm, sb.metadata_snap)?;
@@ -1153,12 +1509,57 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         None => Ok(BTreeMap::new()),
     };
 
+    if let Some(percent) = opts.mapping_sample {
+        if let Err(e) = thins_snap {
+            return Err(metadata_err("metadata snap", e).into());
+        }
+
+        report.set_sub_title("mapping tree");
+        check_sampled_mappings(
+            &ctx,
+            &sb,
+            &metadata_sm,
+            &all_roots,
+            percent,
+            opts.ignore_non_fatal,
+        )?;
+
+        if !opts.engine_opts.use_metadata_snap {
+            report.set_sub_title("data space map");
+            let root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+            check_space_map_structure(
+                engine.clone(),
+                report.clone(),
+                "data",
+                root,
+                false,
+                opts.ignore_non_fatal,
+            )
+            .map_err(|e| metadata_err("data space map", e))?;
+
+            report.set_sub_title("metadata space map");
+            let root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+            check_space_map_structure(
+                engine.clone(),
+                report.clone(),
+                "metadata",
+                root,
+                true,
+                opts.ignore_non_fatal,
+            )
+            .map_err(|e| metadata_err("metadata space map", e))?;
+        }
+
+        // Only part of the metadata was checked, so needs_check is left alone
+        return Ok(CheckOutcome::Clean);
+    }
+
     if opts.skip_mappings {
         let cleared = clear_needs_check_flag(engine.clone())?;
         if cleared {
             report.warning("Cleared needs_check flag");
         }
-        return Ok(());
+        return Ok(CheckOutcome::Clean);
     }
 
     //----------------------------------------
@@ -1169,12 +1570,12 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     report.info(&format!("number of devices to check: {}", all_roots.len()));
 
     let data_sm = if opts.engine_opts.use_metadata_snap {
-        create_data_sm(&sb, 1)?
+        create_data_sm(&sb, 1, opts.memory_limit)?
     } else {
-        create_data_sm(&sb, all_roots.len() as u32)?
+        create_data_sm(&sb, all_roots.len() as u32, opts.memory_limit)?
     };
 
-    let summaries = check_mappings_bottom_level(
+    let (summaries, corruptions) = check_mappings_bottom_level(
         &ctx,
         &sb,
         &metadata_sm,
@@ -1183,6 +1584,15 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         opts.ignore_non_fatal,
     )?;
 
+    if !corruptions.is_empty() {
+        let mut dev_ids: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+        let snap_devs = thins_snap.iter().flat_map(|devs| devs.iter());
+        for (id, (root, _)) in thins.iter().chain(snap_devs) {
+            dev_ids.entry(*root).or_default().insert(*id);
+        }
+        report_corruptions(&ctx, &corruptions, &dev_ids);
+    }
+
     // Check the number of mapped blocks
     let mut iter = thins
         .iter()
@@ -1201,71 +1611,77 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         }
     }
 
+    if let Some(data_dev) = opts.data_dev {
+        check_data_dev(&ctx, &sb, &data_sm, &opts, data_dev)?;
+    }
+
     if opts.engine_opts.use_metadata_snap {
-        return Ok(());
+        return Ok(CheckOutcome::Clean);
     }
 
     //-----------------------------------------
-    // Check the data space map
+    // Check the data and metadata space maps
 
-    report.set_sub_title("data space map");
+    report.set_sub_title("space maps");
     let start = std::time::Instant::now();
-    let root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
-    let data_leaks = check_disk_space_map(
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    let mut histogram = RefCountHistogram::default();
+    let (data_leaks, metadata_leaks) = check_space_maps(
         engine.clone(),
         report.clone(),
-        root,
+        data_root,
+        metadata_root,
         data_sm.clone(),
         metadata_sm.clone(),
         opts.ignore_non_fatal,
-    )
-    .map_err(|e| metadata_err("data space map", e))?;
+        opts.ref_count_histogram.then_some(&mut histogram),
+    )?;
     let duration = start.elapsed();
-    report.debug(&format!("checking data space map: {:?}", duration));
+    report.debug(&format!("checking space maps: {:?}", duration));
 
-    //-----------------------------------------
-    // Check the metadata space map
-
-    report.set_sub_title("metadata space map");
-    let start = std::time::Instant::now();
-    let root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
-
-    // Now the counts should be correct and we can check it.
-    let metadata_leaks = check_metadata_space_map(
-        engine.clone(),
-        report.clone(),
-        root,
-        metadata_sm.clone(),
-        opts.ignore_non_fatal,
-    )
-    .map_err(|e| metadata_err("metadata space map", e))?;
-    let duration = start.elapsed();
-    report.debug(&format!("checking metadata space map: {:?}", duration));
+    if opts.ref_count_histogram {
+        print_ref_count_histogram(report, &histogram);
+    }
 
     //-----------------------------------------
     // Fix minor issues found in the metadata
 
+    let repair = opts.auto_repair || opts.clear_needs_check;
+    let nr_leaked = count_leaked_blocks(&data_leaks) + count_leaked_blocks(&metadata_leaks);
+    let within_budget = opts
+        .error_budget
+        .map_or(false, |budget| nr_leaked <= budget);
+
     if !data_leaks.is_empty() {
-        if opts.auto_repair || opts.clear_needs_check {
+        if repair {
             report.warning("Repairing data leaks.");
             repair_space_map(engine.clone(), data_leaks, data_sm.clone())?;
-        } else if !opts.ignore_non_fatal {
-            return Err(anyhow!(concat!(
-                "data space map contains leaks\n",
-                "perhaps you wanted to run with --auto-repair"
-            )));
+        } else if !opts.ignore_non_fatal && !within_budget {
+            return Err(RepairableError(
+                concat!(
+                    "data space map contains leaks\n",
+                    "perhaps you wanted to run with --auto-repair"
+                )
+                .to_string(),
+            )
+            .into());
         }
     }
 
     if !metadata_leaks.is_empty() {
-        if opts.auto_repair || opts.clear_needs_check {
+        if repair {
             report.warning("Repairing metadata leaks.");
             repair_space_map(engine.clone(), metadata_leaks, metadata_sm.clone())?;
-        } else if !opts.ignore_non_fatal {
-            return Err(anyhow!(concat!(
-                "metadata space map contains leaks\n",
-                "perhaps you wanted to run with --auto-repair"
-            )));
+        } else if !opts.ignore_non_fatal && !within_budget {
+            return Err(RepairableError(
+                concat!(
+                    "metadata space map contains leaks\n",
+                    "perhaps you wanted to run with --auto-repair"
+                )
+                .to_string(),
+            )
+            .into());
         }
     }
 
@@ -1276,9 +1692,309 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         }
     }
 
+    if nr_leaked > 0 && !repair && !opts.ignore_non_fatal {
+        report.warning(&format!(
+            "{} leaked blocks are within the error budget of {}",
+            nr_leaked,
+            opts.error_budget.unwrap_or(0)
+        ));
+        return Ok(CheckOutcome::Warnings);
+    }
+
+    Ok(CheckOutcome::Clean)
+}
+
+pub fn check(opts: ThinCheckOptions) -> Result<CheckOutcome> {
+    check_and_record(opts, &mut 0)
+}
+
+// Checks the metadata, then writes the metrics and history asked for.
+// The errors found are added to nr_errors_total, which is carried across
+// the passes of a watch so the counter in the metrics never goes back.
+fn check_and_record(opts: ThinCheckOptions, nr_errors_total: &mut u64) -> Result<CheckOutcome> {
+    let start = std::time::Instant::now();
+    let input = opts.input;
+    let device = opts.metrics_device.unwrap_or(input);
+    let metrics_file = opts.metrics_file;
+    let history = opts.history;
+    let report = opts.report.clone();
+    let nr_errors = report.get_nr_errors();
+
+    let mut transaction_id = None;
+    let result = check_(opts, &mut transaction_id);
+
+    // The error returned hasn't been reported yet, so count it here
+    let nr_errors = report.get_nr_errors() - nr_errors + result.is_err() as u64;
+    *nr_errors_total += nr_errors;
+    if let Some(path) = metrics_file {
+        let duration = start.elapsed();
+        if let Err(e) = write_metrics(path, input, device, nr_errors, *nr_errors_total, duration) {
+            report.warning(&format!("couldn't write metrics file: {:#}", e));
+        }
+    }
+    if let Some(path) = history {
+        if let Err(e) = record_history(path, transaction_id, nr_errors, start.elapsed(), &result) {
+            report.warning(&format!("couldn't update the history: {:#}", e));
+        }
+    }
+
+    result
+}
+
+fn outcome_name(result: &Result<CheckOutcome>) -> &'static str {
+    match result {
+        Ok(CheckOutcome::Clean) => "clean",
+        Ok(CheckOutcome::Warnings) => "warnings",
+        Err(e) if e.downcast_ref::<RepairableError>().is_some() => "repairable",
+        Err(_) => "fatal",
+    }
+}
+
+// The transaction id is left out if the superblock couldn't be read
+fn record_history(
+    path: &Path,
+    transaction_id: Option<u64>,
+    nr_errors: u64,
+    duration: Duration,
+    result: &Result<CheckOutcome>,
+) -> Result<()> {
+    let entry = HistoryEntry::new(transaction_id, nr_errors, duration, outcome_name(result));
+    append_history(path, &entry)
+}
+
+//------------------------------------------
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_metric_of(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    labels: &str,
+    value: impl fmt::Display,
+) {
+    use std::fmt::Write;
+
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, labels: &str, value: impl fmt::Display) {
+    write_metric_of(out, name, "gauge", help, labels, value);
+}
+
+/// Writes the result of a check, along with a few pool statistics, in the
+/// Prometheus text exposition format.  The file is replaced atomically so
+/// a node exporter never picks up a partial file.  Statistics that can't
+/// be read from the metadata are left out.  The errors of the last check
+/// are a gauge, while those of every check run are kept as a counter.
+fn write_metrics(
+    path: &Path,
+    input: &Path,
+    device: &Path,
+    nr_errors: u64,
+    nr_errors_total: u64,
+    duration: Duration,
+) -> Result<()> {
+    let labels = format!("device=\"{}\"", escape_label(&device.display().to_string()));
+    let mut out = String::new();
+
+    write_metric(
+        &mut out,
+        "thin_check_errors",
+        "Number of errors found by the last check.",
+        &labels,
+        nr_errors,
+    );
+    write_metric_of(
+        &mut out,
+        "thin_check_errors_total",
+        "counter",
+        "Number of errors found by all the checks run.",
+        &labels,
+        nr_errors_total,
+    );
+    write_metric(
+        &mut out,
+        "thin_check_duration_seconds",
+        "Time taken by the last check.",
+        &labels,
+        duration.as_secs_f64(),
+    );
+
+    let engine: Arc<dyn IoEngine + Send + Sync> =
+        Arc::new(SyncIoEngine::new_with(input, false, false)?);
+    if let Ok(sb) = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION) {
+        if let Ok(root) = unpack::<SMRoot>(&sb.metadata_sm_root[0..]) {
+            write_metric(
+                &mut out,
+                "thin_pool_metadata_used_blocks",
+                "Number of metadata blocks in use.",
+                &labels,
+                root.nr_allocated,
+            );
+            write_metric(
+                &mut out,
+                "thin_pool_metadata_blocks",
+                "Total number of metadata blocks.",
+                &labels,
+                root.nr_blocks,
+            );
+        }
+
+        if let Ok(root) = unpack::<SMRoot>(&sb.data_sm_root[0..]) {
+            write_metric(
+                &mut out,
+                "thin_pool_data_used_blocks",
+                "Number of data blocks in use.",
+                &labels,
+                root.nr_allocated,
+            );
+        }
+
+        if let Ok(devs) =
+            btree_to_map::<DeviceDetail>(&mut vec![0], engine.clone(), false, sb.details_root)
+        {
+            write_metric(
+                &mut out,
+                "thin_pool_thin_count",
+                "Number of thin devices in the pool.",
+                &labels,
+                devs.len(),
+            );
+        }
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, out)
+        .with_context(|| format!("couldn't write '{}'", Path::new(&tmp).display()))?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+//------------------------------------------
+
+// Checks the metadata snapshot, if the pool has one
+fn watch_pass(opts: &ThinCheckOptions, nr_errors_total: &mut u64) -> Result<Option<CheckOutcome>> {
+    let engine = SyncIoEngine::new_with(opts.input, false, false)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    if sb.metadata_snap == 0 {
+        return Ok(None);
+    }
+
+    let mut pass_opts = opts.clone();
+    pass_opts.engine_opts.use_metadata_snap = true;
+    check_and_record(pass_opts, nr_errors_total).map(Some)
+}
+
+/// Repeatedly checks the metadata snapshot of a live pool, logging whenever
+/// the outcome changes, so it may be run as a health monitor.  Reserving
+/// the snapshot is left to the caller, passes are skipped while there isn't
+/// one.  Runs forever unless a count of passes is given, in which case the
+/// outcome of the last check is returned, or an error if any earlier pass
+/// failed.
+pub fn watch(
+    opts: ThinCheckOptions,
+    interval: Duration,
+    count: Option<u64>,
+) -> Result<CheckOutcome> {
+    if opts.auto_repair || opts.clear_needs_check {
+        return Err(anyhow!("cannot perform repair outside the actual metadata"));
+    }
+
+    let report = opts.report.clone();
+    let mut last: Result<CheckOutcome> = Ok(CheckOutcome::Clean);
+    let mut nr_passes = 0;
+    let mut nr_failed = 0;
+    let mut nr_errors_total = 0;
+    loop {
+        match watch_pass(&opts, &mut nr_errors_total) {
+            Ok(None) => report.info("no metadata snapshot, skipping pass"),
+            Ok(Some(outcome)) => {
+                if last.is_err() {
+                    report.warning("metadata snapshot passes the check again");
+                }
+                last = Ok(outcome);
+            }
+            Err(e) => {
+                // a failure is only logged again if it has changed
+                match &last {
+                    Ok(_) => {
+                        report.fatal(&format!("metadata snapshot check regressed: {:#}", e));
+                    }
+                    Err(prev) if format!("{:#}", prev) != format!("{:#}", e) => {
+                        report.fatal(&format!("metadata snapshot check failed: {:#}", e));
+                    }
+                    Err(_) => {}
+                }
+                nr_failed += 1;
+                last = Err(e);
+            }
+        }
+
+        nr_passes += 1;
+        if count.map_or(false, |c| nr_passes >= c) {
+            if nr_failed > 0 && last.is_ok() {
+                return Err(anyhow!(
+                    "{} of {} passes failed the check",
+                    nr_failed,
+                    nr_passes
+                ));
+            }
+            return last;
+        }
+        thread::sleep(interval);
+    }
+}
+
+//------------------------------------------
+
+fn fix_metadata_checksums(fixer: &mut ChecksumFixer, sb: &Superblock) -> Result<()> {
+    fixer.fix_tree::<DeviceDetail>(sb.details_root)?;
+
+    let mut roots = Vec::new();
+    fixer.fix_tree_with::<u64, _>(sb.mapping_root, |values| roots.extend_from_slice(values))?;
+    for root in roots {
+        fixer.fix_tree::<BlockTime>(root)?;
+    }
     Ok(())
 }
 
+/// Rewrites the checksums of btree nodes that are intact apart from their
+/// checksum, such as those left by a torn write.  Returns the number of
+/// nodes fixed.
+pub fn fix_checksums(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    sb: &Superblock,
+) -> Result<u64> {
+    let mut fixer = ChecksumFixer::new(engine.clone(), report);
+
+    fix_metadata_checksums(&mut fixer, sb)?;
+    if sb.metadata_snap > 0 {
+        if let Ok(sb_snap) = read_superblock(engine.as_ref(), sb.metadata_snap) {
+            fix_metadata_checksums(&mut fixer, &sb_snap)?;
+        }
+    }
+
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    fixer.fix_tree::<IndexEntry>(data_root.bitmap_root)?;
+    fixer.fix_tree::<u32>(data_root.ref_count_root)?;
+
+    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
+    fixer.fix_tree::<u32>(metadata_root.ref_count_root)?;
+
+    Ok(fixer.nr_fixed())
+}
+
 pub fn clear_needs_check_flag(engine: Arc<dyn IoEngine + Send + Sync>) -> Result<bool> {
     let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
     if !sb.flags.needs_check {
@@ -1300,11 +2016,11 @@ pub fn check_with_maps(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
 ) -> Result<CheckMaps> {
-    let ctx = mk_context_(engine.clone(), report.clone())?;
+    let ctx = mk_context_(engine.clone(), report.clone(), DEFAULT_NR_THREADS, None)?;
     report.set_title("Checking thin metadata");
 
     let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
-    let metadata_sm = create_metadata_sm(&engine, &sb, &None, false)?;
+    let metadata_sm = create_metadata_sm(&engine, &sb, &None, false, None)?;
     inc_superblock(&metadata_sm, sb.metadata_snap)?;
 
     //-----------------------------------------
@@ -1318,8 +2034,9 @@ pub fn check_with_maps(
 
     report.set_sub_title("mapping tree");
 
-    let data_sm = create_data_sm(&sb, all_roots.len() as u32)?;
-    let summaries = check_mappings_bottom_level_(&ctx, &metadata_sm, &data_sm, &all_roots, false)?;
+    let data_sm = create_data_sm(&sb, all_roots.len() as u32, None)?;
+    let (summaries, _) =
+        check_mappings_bottom_level_(&ctx, &metadata_sm, &data_sm, &all_roots, false)?;
 
     // Check the number of mapped blocks
     let mut iter = thins
@@ -1338,6 +2055,7 @@ pub fn check_with_maps(
         data_sm.clone(),
         metadata_sm.clone(),
         false,
+        None,
     )?;
 
     //-----------------------------------------
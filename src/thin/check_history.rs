@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//------------------------------------------
+
+// A record of each check of a pool, so changes in its health can be seen
+// over months of runs.  The file is plain text, a line per check, and is
+// only ever appended to, so a crash loses at most the line being written.
+
+const HEADER: &str = "# time transaction_id errors duration_secs outcome";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// Seconds since the epoch
+    pub time: u64,
+    /// None if the superblock couldn't be read
+    pub transaction_id: Option<u64>,
+    pub nr_errors: u64,
+    pub duration: Duration,
+    /// One of "clean", "warnings", "repairable" or "fatal"
+    pub outcome: String,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        transaction_id: Option<u64>,
+        nr_errors: u64,
+        duration: Duration,
+        outcome: &str,
+    ) -> Self {
+        HistoryEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            transaction_id,
+            nr_errors,
+            duration,
+            outcome: outcome.to_string(),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {:.3} {}",
+            self.time,
+            self.transaction_id
+                .map_or_else(|| "-".to_string(), |t| t.to_string()),
+            self.nr_errors,
+            self.duration.as_secs_f64(),
+            self.outcome
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(anyhow!("expected 5 fields"));
+        }
+        let transaction_id = match fields[1] {
+            "-" => None,
+            t => Some(t.parse::<u64>()?),
+        };
+        Ok(HistoryEntry {
+            time: fields[0].parse::<u64>()?,
+            transaction_id,
+            nr_errors: fields[2].parse::<u64>()?,
+            duration: Duration::try_from_secs_f64(fields[3].parse::<f64>()?)?,
+            outcome: fields[4].to_string(),
+        })
+    }
+}
+
+/// Adds an entry to the end of the history, creating the file if needed
+pub fn append_history(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("couldn't open the history '{}'", path.display()))?;
+    let mut out = String::new();
+    if file.metadata()?.len() == 0 {
+        out += HEADER;
+        out += "\n";
+    }
+    out += &entry.to_line();
+    out += "\n";
+
+    // a single write, so concurrent checks don't interleave their lines
+    file.write_all(out.as_bytes())?;
+    Ok(())
+}
+
+pub fn read_history(path: &Path) -> Result<Vec<HistoryEntry>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("couldn't open the history '{}'", path.display()))?;
+
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = HistoryEntry::from_line(line)
+            .with_context(|| format!("bad history entry on line {}", i + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Formats seconds since the epoch as a UTC date and time.  The days are
+// converted to a civil date with Howard Hinnant's days_from_civil inverse.
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60
+    )
+}
+
+/// Writes the history as a table, followed by a count of each outcome
+pub fn show_history(path: &Path, w: &mut dyn Write) -> Result<()> {
+    let entries = read_history(path)?;
+
+    writeln!(
+        w,
+        "{:<19} {:>14} {:>8} {:>10}  outcome",
+        "time (UTC)", "transaction_id", "errors", "duration"
+    )?;
+    for e in &entries {
+        writeln!(
+            w,
+            "{:<19} {:>14} {:>8} {:>9.3}s  {}",
+            format_time(e.time),
+            e.transaction_id
+                .map_or_else(|| "-".to_string(), |t| t.to_string()),
+            e.nr_errors,
+            e.duration.as_secs_f64(),
+            e.outcome
+        )?;
+    }
+
+    let count = |outcome: &str| entries.iter().filter(|e| e.outcome == outcome).count();
+    writeln!(
+        w,
+        "runs: {}, clean: {}, warnings: {}, repairable: {}, fatal: {}",
+        entries.len(),
+        count("clean"),
+        count("warnings"),
+        count("repairable"),
+        count("fatal")
+    )?;
+    Ok(())
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_shown_as_utc_dates() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1791979199), "2026-10-14 11:59:59");
+    }
+}
+
+//------------------------------------------
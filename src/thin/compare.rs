@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::pdata::btree_walker::btree_to_map;
+use crate::pdata::space_map::common::SMRoot;
+use crate::pdata::unpack::unpack;
+use crate::thin::check::{check, CheckOutcome, ThinCheckOptions};
+use crate::thin::device_detail::DeviceDetail;
+use crate::thin::metadata_repair::Override;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+// A copy of the metadata needn't hold anything at the same blocks as the
+// source, eg. if it was made by a dump and restore, so only what the pool
+// sees is compared: the superblock fields and the details of each device.
+struct Summary {
+    sb: Superblock,
+    data_root: SMRoot,
+    devs: BTreeMap<u64, DeviceDetail>,
+}
+
+fn read_summary(path: &Path, opts: &ThinCheckOptions) -> Result<Summary> {
+    let engine = EngineBuilder::new(path, &opts.engine_opts)
+        .read_only(true)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+    let sb = if opts.engine_opts.use_metadata_snap {
+        read_superblock_snap(engine.as_ref())?
+    } else {
+        read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)
+            .and_then(|sb| sb.overrides(&opts.overrides))?
+    };
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+    let devs = btree_to_map::<DeviceDetail>(&mut Vec::new(), engine, false, sb.details_root)?;
+
+    Ok(Summary {
+        sb,
+        data_root,
+        devs,
+    })
+}
+
+fn diff_field<T: PartialEq + fmt::Display>(diffs: &mut Vec<String>, name: &str, lhs: T, rhs: T) {
+    if lhs != rhs {
+        diffs.push(format!(
+            "{} differs: {} in the input, {} in the copy",
+            name, lhs, rhs
+        ));
+    }
+}
+
+fn diff_summaries(lhs: &Summary, rhs: &Summary) -> Vec<String> {
+    let mut diffs = Vec::new();
+
+    diff_field(
+        &mut diffs,
+        "transaction id",
+        lhs.sb.transaction_id,
+        rhs.sb.transaction_id,
+    );
+    diff_field(&mut diffs, "time", lhs.sb.time, rhs.sb.time);
+    diff_field(
+        &mut diffs,
+        "data block size",
+        lhs.sb.data_block_size,
+        rhs.sb.data_block_size,
+    );
+    diff_field(
+        &mut diffs,
+        "nr data blocks",
+        lhs.data_root.nr_blocks,
+        rhs.data_root.nr_blocks,
+    );
+    diff_field(
+        &mut diffs,
+        "nr allocated data blocks",
+        lhs.data_root.nr_allocated,
+        rhs.data_root.nr_allocated,
+    );
+    diff_field(
+        &mut diffs,
+        "needs_check flag",
+        lhs.sb.flags.needs_check,
+        rhs.sb.flags.needs_check,
+    );
+
+    for (id, l) in &lhs.devs {
+        let r = match rhs.devs.get(id) {
+            Some(r) => r,
+            None => {
+                diffs.push(format!("device {} is missing from the copy", id));
+                continue;
+            }
+        };
+
+        let name = |field: &str| format!("device {} {}", id, field);
+        diff_field(
+            &mut diffs,
+            &name("mapped blocks"),
+            l.mapped_blocks,
+            r.mapped_blocks,
+        );
+        diff_field(
+            &mut diffs,
+            &name("transaction id"),
+            l.transaction_id,
+            r.transaction_id,
+        );
+        diff_field(
+            &mut diffs,
+            &name("creation time"),
+            l.creation_time,
+            r.creation_time,
+        );
+        diff_field(
+            &mut diffs,
+            &name("snapshotted time"),
+            l.snapshotted_time,
+            r.snapshotted_time,
+        );
+    }
+
+    for id in rhs.devs.keys().filter(|id| !lhs.devs.contains_key(id)) {
+        diffs.push(format!("device {} is only in the copy", id));
+    }
+
+    diffs
+}
+
+/// Checks a copy of the metadata as well as the input, then reports any
+/// differences in the superblocks, or in the devices they hold.
+pub fn compare(opts: ThinCheckOptions, copy: &Path) -> Result<CheckOutcome> {
+    let report = opts.report.clone();
+    let input = opts.input;
+    let copy_opts = ThinCheckOptions {
+        input: copy,
+        metrics_file: None,
+        metrics_device: None,
+        history: None,
+        ..opts.clone()
+    };
+
+    let outcome = check(opts.clone())
+        .with_context(|| format!("the input '{}' failed its check", input.display()))?;
+    let copy_outcome = check(copy_opts)
+        .with_context(|| format!("the copy '{}' failed its check", copy.display()))?;
+
+    let diffs = diff_summaries(&read_summary(input, &opts)?, &read_summary(copy, &opts)?);
+    if !diffs.is_empty() {
+        for d in &diffs {
+            report.fatal(d);
+        }
+        return Err(anyhow!(
+            "the copy differs from the input in {} places",
+            diffs.len()
+        ));
+    }
+
+    report.info("the copy matches the input");
+    if outcome == CheckOutcome::Warnings || copy_outcome == CheckOutcome::Warnings {
+        Ok(CheckOutcome::Warnings)
+    } else {
+        Ok(CheckOutcome::Clean)
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::commands::engine::*;
+use crate::io_engine::*;
+use crate::pdata::space_map::metadata::*;
+use crate::report::*;
+use crate::thin::dump::*;
+use crate::thin::metadata::*;
+use crate::thin::restore::*;
+use crate::thin::superblock::*;
+use crate::write_batcher::*;
+
+//------------------------------------------
+
+pub struct ThinConvertOptions<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub to_version: u32,
+    pub report: Arc<Report>,
+}
+
+struct Context {
+    report: Arc<Report>,
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    engine_out: Arc<dyn IoEngine + Send + Sync>,
+}
+
+fn new_context(opts: &ThinConvertOptions) -> Result<Context> {
+    let engine_in = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
+    let engine_out = EngineBuilder::new(opts.output, &opts.engine_opts)
+        .write(true)
+        .build()?;
+
+    Ok(Context {
+        report: opts.report.clone(),
+        engine_in,
+        engine_out,
+    })
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if (MIN_METADATA_VERSION..=MAX_METADATA_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "unsupported metadata version {}, expected {} to {}",
+            version,
+            MIN_METADATA_VERSION,
+            MAX_METADATA_VERSION
+        ))
+    }
+}
+
+//------------------------------------------
+
+// The metadata is rebuilt from scratch on the output, rather than patched
+// in place, so that versions with a different layout can transform the
+// trees as they are written.  Versions 1 and 2 share the same layout; the
+// only difference is that version 1 predates the needs_check flag.
+pub fn convert(opts: ThinConvertOptions) -> Result<()> {
+    check_version(opts.to_version)?;
+
+    let ctx = new_context(&opts)?;
+    let sb = read_superblock(ctx.engine_in.as_ref(), SUPERBLOCK_LOCATION)?;
+    check_version(sb.version)?;
+
+    if sb.flags.needs_check {
+        return Err(anyhow!(
+            "the metadata is flagged as needing a check, run thin_check or thin_repair first"
+        ));
+    }
+
+    if sb.version == opts.to_version {
+        ctx.report.info(&format!(
+            "metadata is already at version {}, copying it",
+            sb.version
+        ));
+    } else {
+        ctx.report.info(&format!(
+            "converting metadata from version {} to {}",
+            sb.version, opts.to_version
+        ));
+    }
+
+    let md = build_metadata(ctx.engine_in.clone(), &sb)?;
+    let md = optimise_metadata(md)?;
+
+    let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
+    let batch_size = ctx.engine_out.get_batch_size();
+    let mut w = WriteBatcher::new(ctx.engine_out.clone(), sm, batch_size);
+    let mut restorer = Restorer::new(&mut w, ctx.report.clone());
+    dump_metadata(ctx.engine_in, &mut restorer, &sb, &md)?;
+
+    // the restorer always writes the latest version
+    let mut new_sb = read_superblock(ctx.engine_out.as_ref(), SUPERBLOCK_LOCATION)?;
+    if new_sb.version != opts.to_version {
+        new_sb.version = opts.to_version;
+        write_superblock(ctx.engine_out.as_ref(), SUPERBLOCK_LOCATION, &new_sb)?;
+    }
+
+    Ok(())
+}
+
+//------------------------------------------
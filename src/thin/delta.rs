@@ -1,19 +1,24 @@
-use anyhow::{anyhow, Result};
-use std::io::BufWriter;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Context as _, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::commands::engine::*;
 use crate::io_engine::*;
-use crate::pdata::btree::{self, KeyRange, NodeHeader};
-use crate::pdata::btree_walker::{btree_to_map, BTreeWalker, NodeVisitor};
+use crate::pdata::btree_iterator::BTreeIterator;
+use crate::pdata::btree_walker::btree_to_map;
 use crate::pdata::space_map::common::SMRoot;
 use crate::pdata::unpack::unpack;
 use crate::report::Report;
 use crate::thin::block_time::BlockTime;
 use crate::thin::delta_visitor::*;
-use crate::thin::ir;
+use crate::thin::ir::{self, Visit};
 use crate::thin::metadata_repair::is_superblock_consistent;
+use crate::thin::reconcile::{read_backup, DeviceMappings};
 use crate::thin::superblock::*;
 
 #[cfg(test)]
@@ -50,88 +55,65 @@ impl RunBuilder {
     }
 }
 
-struct RecorderInner {
-    mappings: Vec<DataMapping>,
+// Streams the runs of mappings of a device in order of thin block.  The tree
+// is read a leaf at a time, so only the path to the current leaf is held in
+// memory, however many mappings the device has.
+//
+// In order to compare snapshots that are not derived from the same origin,
+// thin_delta compares mappings based on the data block addresses, thus the
+// mapping timestamps are not extracted.
+struct TreeMappings {
+    iter: BTreeIterator<BlockTime>,
     builder: RunBuilder,
 }
 
-struct MappingRecorder {
-    inner: Mutex<RecorderInner>,
-}
-
-impl MappingRecorder {
-    fn new() -> MappingRecorder {
-        MappingRecorder {
-            inner: Mutex::new(RecorderInner {
-                mappings: Vec::new(),
-                builder: RunBuilder::new(),
-            }),
-        }
-    }
-
-    fn complete(self) -> Vec<DataMapping> {
-        let mut inner = self.inner.lock().unwrap();
-        if let Some(m) = inner.builder.complete() {
-            inner.mappings.push(m);
-        }
-        let mut mappings = Vec::new();
-        std::mem::swap(&mut mappings, &mut inner.mappings);
-        mappings
+impl TreeMappings {
+    fn new(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<TreeMappings> {
+        Ok(TreeMappings {
+            iter: BTreeIterator::new(engine, root)?,
+            builder: RunBuilder::new(),
+        })
     }
 }
 
-impl NodeVisitor<BlockTime> for MappingRecorder {
-    fn visit(
-        &self,
-        _path: &[u64],
-        _kr: &KeyRange,
-        _h: &NodeHeader,
-        keys: &[u64],
-        values: &[BlockTime],
-    ) -> btree::Result<()> {
-        let mut inner = self.inner.lock().unwrap();
-        for (k, v) in keys.iter().zip(values) {
-            if let Some(m) = inner.builder.next(*k, v.block) {
-                inner.mappings.push(m);
+impl Iterator for TreeMappings {
+    type Item = Result<DataMapping>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (thin_block, data_block) = match self.iter.get() {
+                Some((k, v)) => (k, v.block),
+                None => return self.builder.complete().map(Ok),
+            };
+            if let Err(e) = self.iter.step() {
+                return Some(Err(e));
+            }
+            if let Some(m) = self.builder.next(thin_block, data_block) {
+                return Some(Ok(m));
             }
         }
-        Ok(())
-    }
-
-    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
-        Ok(())
-    }
-
-    fn end_walk(&self) -> btree::Result<()> {
-        Ok(())
     }
 }
 
-// In order to compare snapshots that are not derived from the same origin,
-// thin_delta compares mappings based on the data block addresses, thus the
-// mapping timestamps are not extracted.
-pub fn get_mappings(
-    engine: Arc<dyn IoEngine + Send + Sync>,
-    root: u64,
-) -> Result<Vec<DataMapping>> {
-    let mr = MappingRecorder::new();
-    let w = Arc::new(BTreeWalker::new(engine.clone(), false));
-    let mut path = Vec::new();
-    w.walk(&mut path, &mr, root)?;
-    Ok(mr.complete())
+/// The runs of mappings of a device, in order of thin block
+pub type MappingIter = Box<dyn Iterator<Item = Result<DataMapping>>>;
+
+/// Streams the mappings of the device with the given mapping tree.
+pub fn get_mappings(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<MappingIter> {
+    Ok(Box::new(TreeMappings::new(engine, root)?))
 }
 
 //------------------------------------------
 
-struct MappingStream<'a> {
-    iter: &'a mut dyn Iterator<Item = &'a DataMapping>,
+struct MappingStream {
+    iter: MappingIter,
     current: Option<DataMapping>,
 }
 
-impl<'a> MappingStream<'a> {
-    fn new(iter: &'a mut dyn Iterator<Item = &'a DataMapping>) -> MappingStream<'a> {
-        let current = iter.next().cloned();
-        MappingStream { iter, current }
+impl MappingStream {
+    fn new(mut iter: MappingIter) -> Result<MappingStream> {
+        let current = iter.next().transpose()?;
+        Ok(MappingStream { iter, current })
     }
 
     fn more_mappings(&self) -> bool {
@@ -149,7 +131,7 @@ impl<'a> MappingStream<'a> {
             }
 
             if len == current.len {
-                self.current = self.iter.next().cloned();
+                self.current = self.iter.next().transpose()?;
             } else {
                 current.thin_begin += len;
                 current.data_begin += len;
@@ -162,15 +144,15 @@ impl<'a> MappingStream<'a> {
     }
 }
 
+// Merges the two streams of mappings, emitting each range as soon as it's
+// known, so nothing more than the current mapping of each side is held.
 fn dump_delta_mappings(
-    left: &[DataMapping],
-    right: &[DataMapping],
+    left: MappingIter,
+    right: MappingIter,
     visitor: &mut dyn DeltaVisitor,
 ) -> Result<()> {
-    let mut left_iter = left.iter();
-    let mut right_iter = right.iter();
-    let mut ls = MappingStream::new(&mut left_iter);
-    let mut rs = MappingStream::new(&mut right_iter);
+    let mut ls = MappingStream::new(left)?;
+    let mut rs = MappingStream::new(right)?;
 
     while ls.more_mappings() && rs.more_mappings() {
         let lm = ls.get_mapping().unwrap();
@@ -208,8 +190,8 @@ fn dump_delta_mappings(
             let len = std::cmp::min(lm.len, rm.len);
             let delta = Delta::Differ(DiffMapping {
                 thin_begin: lm.thin_begin,
-                left_data_begin: lm.data_begin,
-                right_data_begin: rm.data_begin,
+                left_data_begin: Some(lm.data_begin),
+                right_data_begin: Some(rm.data_begin),
                 len,
             });
             visitor.delta(&delta)?;
@@ -235,31 +217,106 @@ fn dump_delta_mappings(
     Ok(())
 }
 
+// Extracts the mappings of a device from a pack or xml dump, merging the
+// runs split by their timestamps.
+fn get_dump_mappings(devs: &DeviceMappings, dump: &Path, dev_id: u64) -> Result<Vec<DataMapping>> {
+    let maps = u32::try_from(dev_id)
+        .ok()
+        .and_then(|id| devs.get(&id))
+        .ok_or_else(|| anyhow!("device {} isn't in the dump '{}'", dev_id, dump.display()))?;
+
+    let mut mappings: Vec<DataMapping> = Vec::new();
+    for m in maps {
+        if let Some(last) = mappings.last_mut() {
+            if last.thin_begin + last.len == m.thin_begin
+                && last.data_begin + last.len == m.data_begin
+            {
+                last.len += m.len;
+                continue;
+            }
+        }
+        mappings.push(DataMapping {
+            thin_begin: m.thin_begin,
+            data_begin: m.data_begin,
+            len: m.len,
+        });
+    }
+    Ok(mappings)
+}
+
+// The mappings of the input are streamed from their trees for every pair,
+// only the roots of the devices are read up front.  Dumps have to be read
+// whole, so each is read once and dropped after the last pair using it.
+struct MappingSource {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    roots: BTreeMap<u64, u64>,
+    dumps: BTreeMap<PathBuf, DeviceMappings>,
+    nr_uses: BTreeMap<PathBuf, usize>,
+}
+
+impl MappingSource {
+    fn new(
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        sb: &Superblock,
+        pairs: &[(ThinSpec, ThinSpec)],
+    ) -> Result<Self> {
+        let mut path = Vec::new();
+        let roots = btree_to_map::<u64>(&mut path, engine.clone(), false, sb.mapping_root)?;
+
+        let mut nr_uses = BTreeMap::new();
+        for (snap1, snap2) in pairs {
+            for snap in [snap1, snap2] {
+                if let ThinSpec::Dump(dump, _) = snap {
+                    *nr_uses.entry(dump.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Ok(MappingSource {
+            engine,
+            roots,
+            dumps: BTreeMap::new(),
+            nr_uses,
+        })
+    }
+
+    fn get(&mut self, snap: &ThinSpec, name: &str) -> Result<MappingIter> {
+        match snap {
+            ThinSpec::Input(Snap::DeviceId(dev_id)) => {
+                let root = self.roots.get(dev_id).ok_or_else(|| {
+                    anyhow!("Unable to find mapping tree for {} ({})", name, dev_id)
+                })?;
+                get_mappings(self.engine.clone(), *root)
+            }
+            ThinSpec::Input(Snap::RootBlock(b)) => get_mappings(self.engine.clone(), *b),
+            ThinSpec::Dump(dump, dev_id) => {
+                if !self.dumps.contains_key(dump) {
+                    let devs = read_backup(dump)
+                        .with_context(|| format!("couldn't read the dump '{}'", dump.display()))?;
+                    self.dumps.insert(dump.clone(), devs);
+                }
+                let mappings = get_dump_mappings(&self.dumps[dump], dump, *dev_id)?;
+
+                if let Some(n) = self.nr_uses.get_mut(dump) {
+                    *n -= 1;
+                    if *n == 0 {
+                        self.dumps.remove(dump);
+                    }
+                }
+
+                Ok(Box::new(mappings.into_iter().map(Ok)))
+            }
+        }
+    }
+}
+
 fn dump_diff(
     engine: Arc<dyn IoEngine + Send + Sync>,
     visitor: &mut dyn DeltaVisitor,
     sb: &Superblock,
-    snap1: Snap,
-    snap2: Snap,
+    pairs: &[(ThinSpec, ThinSpec)],
 ) -> Result<()> {
-    let mut path = Vec::new();
-    let roots = btree_to_map::<u64>(&mut path, engine.clone(), false, sb.mapping_root)?;
-
-    let root1 = match snap1 {
-        Snap::DeviceId(dev_id) => *roots
-            .get(&dev_id)
-            .ok_or_else(|| anyhow!("Unable to find mapping tree for snap1 ({})", dev_id))?,
-        Snap::RootBlock(b) => b,
-    };
-    let mappings1 = get_mappings(engine.clone(), root1)?;
-
-    let root2 = match snap2 {
-        Snap::DeviceId(dev_id) => *roots
-            .get(&dev_id)
-            .ok_or_else(|| anyhow!("Unable to find mapping tree for snap2 ({})", dev_id))?,
-        Snap::RootBlock(b) => b,
-    };
-    let mappings2 = get_mappings(engine.clone(), root2)?;
+    let mut source = MappingSource::new(engine, sb, pairs)?;
 
     let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
     let out_sb = ir::Superblock {
@@ -274,9 +331,14 @@ fn dump_diff(
     };
 
     visitor.superblock_b(&out_sb)?;
-    visitor.diff_b(snap1, snap2)?;
-    dump_delta_mappings(&mappings1, &mappings2, visitor)?;
-    visitor.diff_e()?;
+    for (snap1, snap2) in pairs {
+        let mappings1 = source.get(snap1, "snap1")?;
+        let mappings2 = source.get(snap2, "snap2")?;
+
+        visitor.diff_b(snap1.snap(), snap2.snap())?;
+        dump_delta_mappings(mappings1, mappings2, visitor)?;
+        visitor.diff_e()?;
+    }
     visitor.superblock_e()?;
 
     Ok(())
@@ -284,13 +346,325 @@ fn dump_diff(
 
 //------------------------------------------
 
+// Reports the ranges mapped in only one device as different, rather than as
+// left or right only, for consumers that only care whether the contents of
+// a range changed.  The data block of the unmapped side is left out.
+struct UnmappedFolder<'a> {
+    inner: &'a mut dyn DeltaVisitor,
+}
+
+impl<'a> DeltaVisitor for UnmappedFolder<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn diff_b(&mut self, snap1: Snap, snap2: Snap) -> Result<Visit> {
+        self.inner.diff_b(snap1, snap2)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        self.inner.diff_e()
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        let (m, left, right) = match d {
+            Delta::LeftOnly(m) => (m, Some(m.data_begin), None),
+            Delta::RightOnly(m) => (m, None, Some(m.data_begin)),
+            _ => return self.inner.delta(d),
+        };
+        self.inner.delta(&Delta::Differ(DiffMapping {
+            thin_begin: m.thin_begin,
+            left_data_begin: left,
+            right_data_begin: right,
+            len: m.len,
+        }))
+    }
+}
+
+//------------------------------------------
+
+// Reads the data of the ranges sharing a mapping from the data devices of
+// the two volumes, and reports any blocks whose contents differ as
+// different.  This catches the divergence of a replica.
+struct DataVerifier<'a> {
+    inner: &'a mut dyn DeltaVisitor,
+    left: (File, PathBuf),
+    right: (File, PathBuf),
+    block_size: u64, // bytes
+    left_buf: Vec<u8>,
+    right_buf: Vec<u8>,
+    nr_mismatched: u64,
+}
+
+// The most data read from each device at a time
+const VERIFY_CHUNK_SIZE: u64 = 4 << 20;
+
+impl<'a> DataVerifier<'a> {
+    fn new(
+        inner: &'a mut dyn DeltaVisitor,
+        left_dev: &Path,
+        right_dev: &Path,
+        data_block_size: u32,
+    ) -> Result<Self> {
+        let open = |path: &Path| -> Result<(File, PathBuf)> {
+            let f = File::open(path)
+                .with_context(|| format!("couldn't open data device '{}'", path.display()))?;
+            Ok((f, path.to_path_buf()))
+        };
+
+        let block_size = (data_block_size as u64) << SECTOR_SHIFT;
+        let chunk_size = std::cmp::max(VERIFY_CHUNK_SIZE, block_size) as usize;
+        Ok(DataVerifier {
+            inner,
+            left: open(left_dev)?,
+            right: open(right_dev)?,
+            block_size,
+            left_buf: vec![0; chunk_size],
+            right_buf: vec![0; chunk_size],
+            nr_mismatched: 0,
+        })
+    }
+
+    fn read_blocks(dev: &(File, PathBuf), buf: &mut [u8], block: u64, bs: u64) -> Result<()> {
+        dev.0.read_exact_at(buf, block * bs).with_context(|| {
+            format!(
+                "couldn't read data block {} from '{}'",
+                block,
+                dev.1.display()
+            )
+        })
+    }
+
+    fn verify(&mut self, m: &DataMapping) -> Result<()> {
+        let bs = self.block_size;
+        let blocks_per_chunk = self.left_buf.len() as u64 / bs;
+
+        // runs of blocks are passed on as they switch between matching
+        let mut run: Option<(bool, DataMapping)> = None;
+        let mut done = 0;
+        while done < m.len {
+            let len = std::cmp::min(blocks_per_chunk, m.len - done);
+            let nr_bytes = (len * bs) as usize;
+            let data_block = m.data_begin + done;
+            Self::read_blocks(&self.left, &mut self.left_buf[..nr_bytes], data_block, bs)?;
+            Self::read_blocks(&self.right, &mut self.right_buf[..nr_bytes], data_block, bs)?;
+
+            let left = self.left_buf[..nr_bytes].chunks(bs as usize);
+            let right = self.right_buf[..nr_bytes].chunks(bs as usize);
+            let matches: Vec<bool> = left.zip(right).map(|(l, r)| l == r).collect();
+            for (i, same) in matches.into_iter().enumerate() {
+                if !same {
+                    self.nr_mismatched += 1;
+                }
+
+                match run {
+                    Some((s, ref mut cur)) if s == same => cur.len += 1,
+                    _ => {
+                        let offset = done + i as u64;
+                        let next = DataMapping {
+                            thin_begin: m.thin_begin + offset,
+                            data_begin: m.data_begin + offset,
+                            len: 1,
+                        };
+                        if let Some((s, cur)) = run.replace((same, next)) {
+                            self.emit(s, cur)?;
+                        }
+                    }
+                }
+            }
+            done += len;
+        }
+
+        if let Some((s, cur)) = run {
+            self.emit(s, cur)?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, same: bool, m: DataMapping) -> Result<()> {
+        let d = if same {
+            Delta::Same(m)
+        } else {
+            Delta::Differ(DiffMapping {
+                thin_begin: m.thin_begin,
+                left_data_begin: Some(m.data_begin),
+                right_data_begin: Some(m.data_begin),
+                len: m.len,
+            })
+        };
+        self.inner.delta(&d)?;
+        Ok(())
+    }
+}
+
+impl<'a> DeltaVisitor for DataVerifier<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn diff_b(&mut self, snap1: Snap, snap2: Snap) -> Result<Visit> {
+        self.inner.diff_b(snap1, snap2)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        self.inner.diff_e()
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        match d {
+            Delta::Same(m) => {
+                self.verify(m)?;
+                Ok(Visit::Continue)
+            }
+            _ => self.inner.delta(d),
+        }
+    }
+}
+
+//------------------------------------------
+
+#[derive(Clone, Copy)]
+pub enum DeltaFormat {
+    XML,
+    Bitmap,
+    Json,
+    Csv,
+}
+
+impl FromStr for DeltaFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xml" => Ok(DeltaFormat::XML),
+            "bitmap" => Ok(DeltaFormat::Bitmap),
+            "json" => Ok(DeltaFormat::Json),
+            "csv" => Ok(DeltaFormat::Csv),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
+/// A thin volume to diff, either in the input metadata or in a pack or xml
+/// dump of some other metadata.  A volume in a dump may only be given by its
+/// device id.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThinSpec {
+    Input(Snap),
+    Dump(PathBuf, u64),
+}
+
+impl ThinSpec {
+    fn snap(&self) -> Snap {
+        match self {
+            ThinSpec::Input(snap) => *snap,
+            ThinSpec::Dump(_, dev_id) => Snap::DeviceId(*dev_id),
+        }
+    }
+}
+
+// Parses the device id of a volume given on the command line, either as a
+// plain number, "dev:<id>" for a volume of the input, or "file:<path>#<id>"
+// for a volume in a dump.
+impl FromStr for ThinSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_id = |id: &str| {
+            id.parse::<u64>()
+                .map_err(|_| anyhow!("invalid device id '{}'", id))
+        };
+
+        if let Some(rest) = s.strip_prefix("file:") {
+            let (path, id) = rest
+                .rsplit_once('#')
+                .ok_or_else(|| anyhow!("expected file:<path>#<dev_id>, got '{}'", s))?;
+            if path.is_empty() {
+                return Err(anyhow!("no dump given in '{}'", s));
+            }
+            Ok(ThinSpec::Dump(PathBuf::from(path), parse_id(id)?))
+        } else {
+            let id = s.strip_prefix("dev:").unwrap_or(s);
+            Ok(ThinSpec::Input(Snap::DeviceId(parse_id(id)?)))
+        }
+    }
+}
+
+/// Reads a file listing the pairs of thin volumes to diff, a pair to a
+/// line.  The volumes of a pair are separated by whitespace or a comma, and
+/// are given as they would be to --thin1 and --thin2.  Blank lines and lines
+/// starting with '#' are skipped.
+pub fn read_pairs_file(path: &Path) -> Result<Vec<(ThinSpec, ThinSpec)>> {
+    let file = File::open(path)?;
+    let mut pairs = Vec::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        if fields.len() != 2 {
+            return Err(anyhow!("line {}: expected a pair of thin volumes", i + 1));
+        }
+
+        let parse = |f: &str| {
+            f.parse::<ThinSpec>()
+                .map_err(|e| anyhow!("line {}: {}", i + 1, e))
+        };
+        pairs.push((parse(fields[0])?, parse(fields[1])?));
+    }
+
+    if pairs.is_empty() {
+        return Err(anyhow!(
+            "no pairs of thin volumes listed in '{}'",
+            path.display()
+        ));
+    }
+
+    Ok(pairs)
+}
+
+/// Pairs each volume of an ordered chain of snapshots with the one
+/// following it, so the diffs give the changes made in each generation.
+pub fn chain_pairs(chain: &[ThinSpec]) -> Result<Vec<(ThinSpec, ThinSpec)>> {
+    if chain.len() < 2 {
+        return Err(anyhow!("a chain needs at least two thin volumes"));
+    }
+
+    Ok(chain
+        .windows(2)
+        .map(|w| (w[0].clone(), w[1].clone()))
+        .collect())
+}
+
 pub struct ThinDeltaOptions<'a> {
     pub input: &'a Path,
+    pub output: Option<&'a Path>,
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
-    pub snap1: Snap,
-    pub snap2: Snap,
+    pub metadata_snap: Option<u64>,
+    pub pairs: Vec<(ThinSpec, ThinSpec)>,
     pub verbose: bool,
+    pub format: DeltaFormat,
+    pub summary_only: bool,
+    pub estimate_transfer: bool,
+    pub granularity: Option<u64>,
+    pub exclude_unmapped: bool,
+    pub verify_data: Vec<&'a Path>,
 }
 
 struct Context {
@@ -300,6 +674,7 @@ struct Context {
 
 fn mk_context(opts: &ThinDeltaOptions) -> Result<Context> {
     let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
 
@@ -313,7 +688,10 @@ pub fn delta(opts: ThinDeltaOptions) -> Result<()> {
     let ctx = mk_context(&opts)?;
 
     let sb = if opts.engine_opts.use_metadata_snap {
-        read_superblock_snap(ctx.engine.as_ref())?
+        match opts.metadata_snap {
+            Some(b) => read_superblock(ctx.engine.as_ref(), b)?,
+            None => read_superblock_snap(ctx.engine.as_ref())?,
+        }
     } else {
         read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?
     };
@@ -321,14 +699,66 @@ pub fn delta(opts: ThinDeltaOptions) -> Result<()> {
     // ensure the metadata is consistent
     is_superblock_consistent(sb.clone(), ctx.engine.clone(), false)?;
 
-    let w = BufWriter::new(std::io::stdout());
-    let mut writer: Box<dyn DeltaVisitor> = if opts.verbose {
-        Box::new(VerboseXmlWriter::new(w))
+    // Both sides of a shared mapping have the same data block, so reading
+    // them from a single device would always find them equal
+    if !opts.verify_data.is_empty() && opts.verify_data.len() != 2 {
+        return Err(anyhow!(
+            "--verify-data must be given twice, with the data device of each volume"
+        ));
+    }
+
+    if opts.pairs.len() > 1
+        && (matches!(opts.format, DeltaFormat::Bitmap | DeltaFormat::Csv)
+            || opts.granularity.is_some())
+    {
+        return Err(anyhow!(
+            "only the xml and json formats can hold more than one diff"
+        ));
+    }
+
+    let w: Box<dyn Write> = match opts.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    let mut writer: Box<dyn DeltaVisitor> = match opts.format {
+        _ if opts.summary_only => Box::new(SummaryWriter::new(w)),
+        _ if opts.estimate_transfer => Box::new(EstimateWriter::new(w)),
+        _ if opts.granularity.is_some() => Box::new(ChunkWriter::new(w, opts.granularity.unwrap())),
+        DeltaFormat::Bitmap => Box::new(BitmapWriter::new(w)),
+        DeltaFormat::Json => Box::new(JsonWriter::new(w)),
+        DeltaFormat::Csv => Box::new(CsvWriter::new(w)),
+        DeltaFormat::XML if opts.verbose => Box::new(VerboseXmlWriter::new(w)),
+        DeltaFormat::XML => Box::new(SimpleXmlWriter::new(w)),
+    };
+
+    let mut folder;
+    let writer: &mut dyn DeltaVisitor = if opts.exclude_unmapped {
+        folder = UnmappedFolder {
+            inner: writer.as_mut(),
+        };
+        &mut folder
     } else {
-        Box::new(SimpleXmlWriter::new(w))
+        writer.as_mut()
     };
 
-    dump_diff(ctx.engine, writer.as_mut(), &sb, opts.snap1, opts.snap2)
+    if opts.verify_data.is_empty() {
+        return dump_diff(ctx.engine, writer, &sb, &opts.pairs);
+    }
+
+    let mut verifier = DataVerifier::new(
+        writer,
+        opts.verify_data[0],
+        opts.verify_data[1],
+        sb.data_block_size,
+    )?;
+    dump_diff(ctx.engine, &mut verifier, &sb, &opts.pairs)?;
+    if verifier.nr_mismatched > 0 {
+        return Err(anyhow!(
+            "the data of {} blocks sharing a mapping differs",
+            verifier.nr_mismatched
+        ));
+    }
+    Ok(())
 }
 
 //------------------------------------------
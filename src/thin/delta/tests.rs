@@ -72,6 +72,42 @@ fn test_build_runs() -> Result<()> {
     test_build_runs_(&keys, &values, &expected)
 }
 
+// Runs of 100 thin blocks, each run mapped to its own stretch of data
+// blocks, plenty to span many leaves.
+#[test]
+fn test_stream_tree_mappings() -> Result<()> {
+    use crate::io_engine::core::CoreIoEngine;
+    use crate::pdata::btree_builder::test_utils::build_btree_from_mappings;
+    use crate::pdata::space_map::CoreSpaceMap;
+    use crate::write_batcher::WriteBatcher;
+
+    let nr_runs = 100;
+    let nr_metadata_blocks = 1024;
+    let engine = Arc::new(CoreIoEngine::new(nr_metadata_blocks));
+    let sm = Arc::new(std::sync::Mutex::new(CoreSpaceMap::<u8>::new(
+        nr_metadata_blocks,
+    )));
+    let mut w = WriteBatcher::new(engine.clone(), sm, 16);
+
+    let mut values = Vec::new();
+    for thin_block in 0..nr_runs * 100 {
+        let block = (thin_block / 100) * 1000 + thin_block % 100;
+        values.push((thin_block, BlockTime { block, time: 0 }));
+    }
+    let root = build_btree_from_mappings(&mut w, &values).root().block;
+
+    let actual = get_mappings(engine, root)?.collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = (0..nr_runs)
+        .map(|i| DataMapping {
+            thin_begin: i * 100,
+            data_begin: i * 1000,
+            len: 100,
+        })
+        .collect();
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
 //------------------------------------------
 
 fn test_delta(
@@ -117,15 +153,19 @@ fn test_delta(
             }),
             _ => Delta::Differ(DiffMapping {
                 thin_begin: *arg1,
-                left_data_begin: *arg2,
-                right_data_begin: *arg3,
+                left_data_begin: Some(*arg2),
+                right_data_begin: Some(*arg3),
                 len: *arg4,
             }),
         })
         .collect();
 
     let mut visitor = DeltaCollector::new();
-    dump_delta_mappings(&lm, &rm, &mut visitor)?;
+    dump_delta_mappings(
+        Box::new(lm.into_iter().map(Ok)),
+        Box::new(rm.into_iter().map(Ok)),
+        &mut visitor,
+    )?;
 
     let actual = visitor.deltas;
     assert_eq!(actual, expected);
@@ -160,3 +200,54 @@ fn test_delta_ends_by_left() -> Result<()> {
 }
 
 //------------------------------------------
+
+//------------------------------------------
+
+fn delta_csv(left: &[(u64, u64, u64)], right: &[(u64, u64, u64)]) -> Result<Vec<String>> {
+    let mk_mappings = |ms: &[(u64, u64, u64)]| -> Vec<DataMapping> {
+        ms.iter()
+            .map(|(t, d, l)| DataMapping {
+                thin_begin: *t,
+                data_begin: *d,
+                len: *l,
+            })
+            .collect()
+    };
+
+    let mut out = Vec::new();
+    {
+        let mut w = CsvWriter::new(&mut out);
+        w.diff_b(Snap::DeviceId(0), Snap::DeviceId(1))?;
+        dump_delta_mappings(
+            Box::new(mk_mappings(left).into_iter().map(Ok)),
+            Box::new(mk_mappings(right).into_iter().map(Ok)),
+            &mut w,
+        )?;
+        w.diff_e()?;
+    }
+    Ok(String::from_utf8(out)?
+        .lines()
+        .skip(1)
+        .map(str::to_string)
+        .collect())
+}
+
+#[test]
+fn test_csv_merges_adjacent_ranges() -> Result<()> {
+    // split by the left mappings, but the data blocks follow on
+    let left = [(0, 100, 10), (10, 110, 10)];
+    let right = [(0, 100, 20)];
+    assert_eq!(delta_csv(&left, &right)?, ["same,0,20,100,100"]);
+    Ok(())
+}
+
+#[test]
+fn test_csv_keeps_ranges_with_gaps_in_the_data() -> Result<()> {
+    let left = [(0, 100, 10), (10, 200, 10)];
+    let right = [(0, 300, 20)];
+    assert_eq!(
+        delta_csv(&left, &right)?,
+        ["different,0,10,100,300", "different,10,10,200,310"]
+    );
+    Ok(())
+}
@@ -1,8 +1,9 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::io::Write;
 
 use quick_xml::events::{BytesEnd, BytesStart, Event};
 use quick_xml::Writer;
+use roaring::RoaringTreemap;
 
 use crate::thin::ir::{self, Visit};
 use crate::xml::mk_attr;
@@ -22,8 +23,10 @@ pub struct DataMapping {
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct DiffMapping {
     pub thin_begin: u64,
-    pub left_data_begin: u64,
-    pub right_data_begin: u64,
+    // a side is None if the range is reported as different despite being
+    // unmapped there, see --exclude-unmapped
+    pub left_data_begin: Option<u64>,
+    pub right_data_begin: Option<u64>,
     pub len: u64,
 }
 
@@ -36,6 +39,7 @@ pub enum Delta {
     Same(DataMapping),
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Snap {
     DeviceId(u64),
     RootBlock(u64),
@@ -53,18 +57,46 @@ pub trait DeltaVisitor {
 
 struct DeltaRunBuilder {
     run: Option<Delta>,
+    // the data blocks of a run must follow on too
+    data_contiguous: bool,
+}
+
+// Whether a run of len blocks mapped from cur is followed by next
+fn data_follows(strict: bool, cur: Option<u64>, len: u64, next: Option<u64>) -> bool {
+    if !strict {
+        return true;
+    }
+    match (cur, next) {
+        (Some(cur), Some(next)) => cur + len == next,
+        (None, None) => true,
+        _ => false,
+    }
 }
 
 impl DeltaRunBuilder {
     fn new() -> DeltaRunBuilder {
-        DeltaRunBuilder { run: None }
+        DeltaRunBuilder {
+            run: None,
+            data_contiguous: false,
+        }
+    }
+
+    // For formats that give the data blocks of a range
+    fn with_data() -> DeltaRunBuilder {
+        DeltaRunBuilder {
+            run: None,
+            data_contiguous: true,
+        }
     }
 
     fn next(&mut self, d: &Delta) -> Option<Delta> {
+        let strict = self.data_contiguous;
         match d {
             Delta::LeftOnly(r) => {
                 if let Some(Delta::LeftOnly(ref mut cur)) = self.run {
-                    if r.thin_begin == cur.thin_begin + cur.len {
+                    if r.thin_begin == cur.thin_begin + cur.len
+                        && data_follows(strict, Some(cur.data_begin), cur.len, Some(r.data_begin))
+                    {
                         cur.len += r.len;
                         return None;
                     }
@@ -73,7 +105,9 @@ impl DeltaRunBuilder {
             }
             Delta::RightOnly(r) => {
                 if let Some(Delta::RightOnly(ref mut cur)) = self.run {
-                    if r.thin_begin == cur.thin_begin + cur.len {
+                    if r.thin_begin == cur.thin_begin + cur.len
+                        && data_follows(strict, Some(cur.data_begin), cur.len, Some(r.data_begin))
+                    {
                         cur.len += r.len;
                         return None;
                     }
@@ -82,7 +116,10 @@ impl DeltaRunBuilder {
             }
             Delta::Differ(r) => {
                 if let Some(Delta::Differ(ref mut cur)) = self.run {
-                    if r.thin_begin == cur.thin_begin + cur.len {
+                    if r.thin_begin == cur.thin_begin + cur.len
+                        && data_follows(strict, cur.left_data_begin, cur.len, r.left_data_begin)
+                        && data_follows(strict, cur.right_data_begin, cur.len, r.right_data_begin)
+                    {
                         cur.len += r.len;
                         return None;
                     }
@@ -91,7 +128,9 @@ impl DeltaRunBuilder {
             }
             Delta::Same(r) => {
                 if let Some(Delta::Same(ref mut cur)) = self.run {
-                    if r.thin_begin == cur.thin_begin + cur.len {
+                    if r.thin_begin == cur.thin_begin + cur.len
+                        && data_follows(strict, Some(cur.data_begin), cur.len, Some(r.data_begin))
+                    {
                         cur.len += r.len;
                         return None;
                     }
@@ -257,8 +296,12 @@ impl<W: Write> VerboseXmlWriter<W> {
     fn write_diff_range(&mut self, m: &DiffMapping) -> Result<()> {
         let mut elem = BytesStart::new("range");
         elem.push_attribute(mk_attr(b"begin", m.thin_begin));
-        elem.push_attribute(mk_attr(b"left_data_begin", m.left_data_begin));
-        elem.push_attribute(mk_attr(b"right_data_begin", m.right_data_begin));
+        if let Some(b) = m.left_data_begin {
+            elem.push_attribute(mk_attr(b"left_data_begin", b));
+        }
+        if let Some(b) = m.right_data_begin {
+            elem.push_attribute(mk_attr(b"right_data_begin", b));
+        }
         elem.push_attribute(mk_attr(b"length", m.len));
         self.w.write_event(Event::Empty(elem))?;
         Ok(())
@@ -323,6 +366,579 @@ impl<W: Write> DeltaVisitor for VerboseXmlWriter<W> {
 
 //------------------------------------------
 
+// Records the thin blocks that differ between the two devices, and writes
+// them as a serialized roaring treemap once the diff is complete.  The
+// superblock isn't recorded, so the bitmap is all a consumer gets.
+pub struct BitmapWriter<W: Write> {
+    w: W,
+    changed: RoaringTreemap,
+}
+
+impl<W: Write> BitmapWriter<W> {
+    pub fn new(w: W) -> BitmapWriter<W> {
+        BitmapWriter {
+            w,
+            changed: RoaringTreemap::new(),
+        }
+    }
+}
+
+impl<W: Write> DeltaVisitor for BitmapWriter<W> {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, _snap1: Snap, _snap2: Snap) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        self.changed.serialize_into(&mut self.w)?;
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        let (begin, len) = match d {
+            Delta::LeftOnly(r) | Delta::RightOnly(r) => (r.thin_begin, r.len),
+            Delta::Differ(r) => (r.thin_begin, r.len),
+            Delta::Same(_) => return Ok(Visit::Continue),
+        };
+        self.changed.insert_range(begin..begin + len);
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// The json and csv formats give every range the same fields, so consumers
+// needn't switch on the type.  The data block of a side without a mapping
+// is left out.
+struct RangeFields {
+    kind: &'static str,
+    begin: u64,
+    len: u64,
+    left_data_begin: Option<u64>,
+    right_data_begin: Option<u64>,
+}
+
+fn range_fields(d: &Delta) -> RangeFields {
+    match d {
+        Delta::LeftOnly(r) => RangeFields {
+            kind: "left_only",
+            begin: r.thin_begin,
+            len: r.len,
+            left_data_begin: Some(r.data_begin),
+            right_data_begin: None,
+        },
+        Delta::RightOnly(r) => RangeFields {
+            kind: "right_only",
+            begin: r.thin_begin,
+            len: r.len,
+            left_data_begin: None,
+            right_data_begin: Some(r.data_begin),
+        },
+        Delta::Differ(r) => RangeFields {
+            kind: "different",
+            begin: r.thin_begin,
+            len: r.len,
+            left_data_begin: r.left_data_begin,
+            right_data_begin: r.right_data_begin,
+        },
+        Delta::Same(r) => RangeFields {
+            kind: "same",
+            begin: r.thin_begin,
+            len: r.len,
+            left_data_begin: Some(r.data_begin),
+            right_data_begin: Some(r.data_begin),
+        },
+    }
+}
+
+fn snap_field(snap: &Snap, side: &str) -> (String, u64) {
+    match snap {
+        Snap::DeviceId(dev_id) => (side.to_string(), *dev_id),
+        Snap::RootBlock(blocknr) => (format!("{}_root", side), *blocknr),
+    }
+}
+
+//------------------------------------------
+
+// Writes a single json object holding the superblock and a list of the
+// diffs, each with the devices compared and their ranges.  The ranges are
+// streamed out as they're found, with adjacent ranges of a type, whose data
+// blocks follow on, written as one.
+pub struct JsonWriter<W: Write> {
+    w: W,
+    builder: DeltaRunBuilder,
+    nr_diffs: u64,
+    nr_ranges: u64,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(w: W) -> JsonWriter<W> {
+        JsonWriter {
+            w,
+            builder: DeltaRunBuilder::with_data(),
+            nr_diffs: 0,
+            nr_ranges: 0,
+        }
+    }
+
+    fn write_delta(&mut self, d: &Delta) -> Result<()> {
+        let f = range_fields(d);
+        let sep = if self.nr_ranges > 0 { "," } else { "" };
+        write!(
+            self.w,
+            "{}\n        {{\"type\": \"{}\", \"begin\": {}, \"length\": {}, \"left_data_begin\": {}, \"right_data_begin\": {}}}",
+            sep,
+            f.kind,
+            f.begin,
+            f.len,
+            json_opt(f.left_data_begin),
+            json_opt(f.right_data_begin)
+        )?;
+        self.nr_ranges += 1;
+        Ok(())
+    }
+}
+
+fn json_opt(v: Option<u64>) -> String {
+    v.map_or("null".to_string(), |v| v.to_string())
+}
+
+impl<W: Write> DeltaVisitor for JsonWriter<W> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        writeln!(self.w, "{{")?;
+        writeln!(
+            self.w,
+            "  \"superblock\": {{\"uuid\": \"{}\", \"time\": {}, \"transaction\": {}, \"data_block_size\": {}, \"nr_data_blocks\": {}}},",
+            sb.uuid, sb.time, sb.transaction, sb.data_block_size, sb.nr_data_blocks
+        )?;
+        write!(self.w, "  \"diffs\": [")?;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        if self.nr_diffs > 0 {
+            write!(self.w, "\n  ")?;
+        }
+        writeln!(self.w, "]")?;
+        writeln!(self.w, "}}")?;
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, snap1: Snap, snap2: Snap) -> Result<Visit> {
+        let (left, left_id) = snap_field(&snap1, "left");
+        let (right, right_id) = snap_field(&snap2, "right");
+        let sep = if self.nr_diffs > 0 { "," } else { "" };
+        writeln!(self.w, "{}\n    {{", sep)?;
+        writeln!(self.w, "      \"{}\": {},", left, left_id)?;
+        writeln!(self.w, "      \"{}\": {},", right, right_id)?;
+        write!(self.w, "      \"ranges\": [")?;
+        self.nr_ranges = 0;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        if let Some(r) = self.builder.complete() {
+            self.write_delta(&r)?;
+        }
+        if self.nr_ranges > 0 {
+            write!(self.w, "\n      ")?;
+        }
+        writeln!(self.w, "]")?;
+        write!(self.w, "    }}")?;
+        self.nr_diffs += 1;
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        if let Some(run) = self.builder.next(d) {
+            self.write_delta(&run)?;
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// Writes a row per range, after a header naming the columns.  Ranges are
+// joined as they are by the json writer.  The superblock and devices aren't
+// recorded.
+pub struct CsvWriter<W: Write> {
+    w: W,
+    builder: DeltaRunBuilder,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(w: W) -> CsvWriter<W> {
+        CsvWriter {
+            w,
+            builder: DeltaRunBuilder::with_data(),
+        }
+    }
+
+    fn write_delta(&mut self, d: &Delta) -> Result<()> {
+        let f = range_fields(d);
+        writeln!(
+            self.w,
+            "{},{},{},{},{}",
+            f.kind,
+            f.begin,
+            f.len,
+            csv_opt(f.left_data_begin),
+            csv_opt(f.right_data_begin)
+        )?;
+        Ok(())
+    }
+}
+
+fn csv_opt(v: Option<u64>) -> String {
+    v.map_or(String::new(), |v| v.to_string())
+}
+
+impl<W: Write> DeltaVisitor for CsvWriter<W> {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, _snap1: Snap, _snap2: Snap) -> Result<Visit> {
+        writeln!(self.w, "type,begin,length,left_data_begin,right_data_begin")?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        if let Some(r) = self.builder.complete() {
+            self.write_delta(&r)?;
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        if let Some(run) = self.builder.next(d) {
+            self.write_delta(&run)?;
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+#[derive(Default)]
+struct DiffSummary {
+    left_only: u64,
+    right_only: u64,
+    different: u64,
+    same: u64,
+}
+
+// Writes the number of blocks of each type in a diff, rather than the
+// ranges, for sizing incremental backups.  Blocks are counted in bytes
+// too, which needs the data block size of the superblock.
+pub struct SummaryWriter<W: Write> {
+    w: W,
+    block_size: u64, // bytes
+    nr_diffs: u64,
+    summary: DiffSummary,
+}
+
+impl<W: Write> SummaryWriter<W> {
+    pub fn new(w: W) -> SummaryWriter<W> {
+        SummaryWriter {
+            w,
+            block_size: 0,
+            nr_diffs: 0,
+            summary: DiffSummary::default(),
+        }
+    }
+}
+
+impl<W: Write> DeltaVisitor for SummaryWriter<W> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.block_size = sb.data_block_size as u64 * 512;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, snap1: Snap, snap2: Snap) -> Result<Visit> {
+        let (left, left_id) = snap_field(&snap1, "left");
+        let (right, right_id) = snap_field(&snap2, "right");
+        if self.nr_diffs > 0 {
+            writeln!(self.w)?;
+        }
+        writeln!(self.w, "{}: {}", left, left_id)?;
+        writeln!(self.w, "{}: {}", right, right_id)?;
+        self.summary = DiffSummary::default();
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        let s = &self.summary;
+        let differing = s.left_only + s.right_only + s.different;
+        writeln!(self.w, "left_only_blocks: {}", s.left_only)?;
+        writeln!(self.w, "right_only_blocks: {}", s.right_only)?;
+        writeln!(self.w, "different_blocks: {}", s.different)?;
+        writeln!(self.w, "same_blocks: {}", s.same)?;
+        writeln!(self.w, "differing_blocks: {}", differing)?;
+        writeln!(self.w, "differing_bytes: {}", differing * self.block_size)?;
+        self.nr_diffs += 1;
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        match d {
+            Delta::LeftOnly(r) => self.summary.left_only += r.len,
+            Delta::RightOnly(r) => self.summary.right_only += r.len,
+            Delta::Differ(r) => self.summary.different += r.len,
+            Delta::Same(r) => self.summary.same += r.len,
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// A differing range, with the first data block of the right side if mapped
+struct PendingRange {
+    kind: &'static str,
+    begin: u64,
+    len: u64,
+    right_data_begin: Option<u64>,
+}
+
+// Writes the number of data blocks an incremental backup would need to read
+// for each differing range, and in all.  Only the data blocks of the right
+// side are read, and not those the left already maps, wherever in the
+// device, since the backup of the left holds them.  A data block mapped more
+// than once is only read once.  Which data blocks the left maps isn't known
+// until the diff is complete, so the ranges are held until then.
+pub struct EstimateWriter<W: Write> {
+    w: W,
+    block_size: u64, // bytes
+    nr_diffs: u64,
+    have: RoaringTreemap,
+    ranges: Vec<PendingRange>,
+}
+
+impl<W: Write> EstimateWriter<W> {
+    pub fn new(w: W) -> EstimateWriter<W> {
+        EstimateWriter {
+            w,
+            block_size: 0,
+            nr_diffs: 0,
+            have: RoaringTreemap::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    // The blocks of the run not already held, which are then held
+    fn nr_to_read(&mut self, data_begin: u64, len: u64) -> u64 {
+        let mut need = RoaringTreemap::new();
+        need.insert_range(data_begin..data_begin + len);
+        need -= &self.have;
+        let n = need.len();
+        self.have |= need;
+        n
+    }
+
+    fn write_range(&mut self, kind: &str, begin: u64, len: u64, nr_to_read: u64) -> Result<()> {
+        writeln!(
+            self.w,
+            "{} {}..{} read_blocks: {}",
+            kind,
+            begin,
+            begin + len,
+            nr_to_read
+        )?;
+        Ok(())
+    }
+}
+
+impl<W: Write> DeltaVisitor for EstimateWriter<W> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.block_size = sb.data_block_size as u64 * 512;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, snap1: Snap, snap2: Snap) -> Result<Visit> {
+        let (left, left_id) = snap_field(&snap1, "left");
+        let (right, right_id) = snap_field(&snap2, "right");
+        if self.nr_diffs > 0 {
+            writeln!(self.w)?;
+        }
+        writeln!(self.w, "{}: {}", left, left_id)?;
+        writeln!(self.w, "{}: {}", right, right_id)?;
+        self.have = RoaringTreemap::new();
+        self.ranges.clear();
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        let ranges = std::mem::take(&mut self.ranges);
+
+        // adjacent ranges of a type are written as one
+        let mut current: Option<(&'static str, u64, u64, u64)> = None; // kind, begin, len, nr to read
+        let mut differing = 0;
+        let mut to_read = 0;
+        for r in ranges {
+            let n = r.right_data_begin.map_or(0, |b| self.nr_to_read(b, r.len));
+            differing += r.len;
+            to_read += n;
+
+            match current {
+                Some((kind, begin, ref mut len, ref mut nr))
+                    if kind == r.kind && begin + *len == r.begin =>
+                {
+                    *len += r.len;
+                    *nr += n;
+                }
+                _ => {
+                    if let Some((kind, begin, len, nr)) =
+                        current.replace((r.kind, r.begin, r.len, n))
+                    {
+                        self.write_range(kind, begin, len, nr)?;
+                    }
+                }
+            }
+        }
+        if let Some((kind, begin, len, nr)) = current {
+            self.write_range(kind, begin, len, nr)?;
+        }
+
+        writeln!(self.w, "differing_blocks: {}", differing)?;
+        writeln!(self.w, "read_blocks: {}", to_read)?;
+        writeln!(self.w, "read_bytes: {}", to_read * self.block_size)?;
+        self.nr_diffs += 1;
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        let f = range_fields(d);
+        if let Some(b) = f.left_data_begin {
+            self.have.insert_range(b..b + f.len);
+        }
+        if !matches!(d, Delta::Same(_)) {
+            self.ranges.push(PendingRange {
+                kind: f.kind,
+                begin: f.begin,
+                len: f.len,
+                right_data_begin: f.right_data_begin,
+            });
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// Writes the chunks of the thin device that hold any changes, along with
+// the number of changed blocks within them, for backup engines that copy
+// fixed size chunks.  Chunks are numbered from the start of the device,
+// and must contain a whole number of data blocks.
+pub struct ChunkWriter<W: Write> {
+    w: W,
+    chunk_size: u64, // bytes
+    blocks_per_chunk: u64,
+    current: Option<(u64, u64)>, // chunk, nr dirty blocks
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(w: W, chunk_size: u64) -> ChunkWriter<W> {
+        ChunkWriter {
+            w,
+            chunk_size,
+            blocks_per_chunk: 0,
+            current: None,
+        }
+    }
+
+    fn flush_chunk(&mut self) -> Result<()> {
+        if let Some((chunk, nr_dirty)) = self.current.take() {
+            writeln!(self.w, "{},{},{}", chunk, chunk * self.chunk_size, nr_dirty)?;
+        }
+        Ok(())
+    }
+
+    fn mark(&mut self, begin: u64, len: u64) -> Result<()> {
+        let end = begin + len;
+        let mut b = begin;
+        while b < end {
+            let chunk = b / self.blocks_per_chunk;
+            let e = std::cmp::min(end, (chunk + 1) * self.blocks_per_chunk);
+            match self.current {
+                Some((c, ref mut nr_dirty)) if c == chunk => *nr_dirty += e - b,
+                _ => {
+                    self.flush_chunk()?;
+                    self.current = Some((chunk, e - b));
+                }
+            }
+            b = e;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> DeltaVisitor for ChunkWriter<W> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        let block_size = sb.data_block_size as u64 * 512;
+        if self.chunk_size == 0 || self.chunk_size % block_size != 0 {
+            return Err(anyhow!(
+                "the granularity must be a multiple of the data block size ({} bytes)",
+                block_size
+            ));
+        }
+        self.blocks_per_chunk = self.chunk_size / block_size;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, _snap1: Snap, _snap2: Snap) -> Result<Visit> {
+        writeln!(self.w, "chunk,offset,dirty_blocks")?;
+        self.current = None;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        self.flush_chunk()?;
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        match d {
+            Delta::LeftOnly(r) | Delta::RightOnly(r) => self.mark(r.thin_begin, r.len)?,
+            Delta::Differ(r) => self.mark(r.thin_begin, r.len)?,
+            Delta::Same(_) => {}
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
 // TODO: move these common functions into an abstract class
 fn write_superblock_b<W: Write>(w: &mut Writer<W>, sb: &ir::Superblock) -> Result<()> {
     let mut elem = BytesStart::new("superblock");
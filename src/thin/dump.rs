@@ -19,6 +19,7 @@ use crate::thin::human_readable_format::HumanReadableWriter;
 use crate::thin::ir::{self, MetadataVisitor};
 use crate::thin::metadata::*;
 use crate::thin::metadata_repair::*;
+use crate::thin::renumber::*;
 use crate::thin::superblock::*;
 use crate::thin::xml;
 
@@ -204,6 +205,7 @@ impl FromStr for OutputFormat {
 pub struct ThinDumpOptions<'a> {
     pub input: &'a Path,
     pub output: Option<&'a Path>,
+    pub output_dir: Option<&'a Path>,
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
     pub repair: bool,
@@ -211,6 +213,9 @@ pub struct ThinDumpOptions<'a> {
     pub overrides: SuperblockOverrides,
     pub selected_devs: Option<Vec<u64>>,
     pub format: OutputFormat,
+    pub renumber_from: Option<u32>,
+    pub id_map: Option<&'a Path>,
+    pub throttle_io: Option<u64>, // bytes per second
 }
 
 struct ThinDumpContext {
@@ -220,7 +225,9 @@ struct ThinDumpContext {
 
 fn mk_context(opts: &ThinDumpOptions) -> Result<ThinDumpContext> {
     let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
         .exclusive(!opts.engine_opts.use_metadata_snap)
+        .throttle(opts.throttle_io)
         .build()?;
 
     Ok(ThinDumpContext {
@@ -361,16 +368,7 @@ pub fn dump_metadata(
     }
 
     for dev in &md.devs {
-        let device = ir::Device {
-            dev_id: dev.thin_id,
-            mapped_blocks: dev.detail.mapped_blocks,
-            transaction: dev.detail.transaction_id,
-            creation_time: dev.detail.creation_time,
-            snap_time: dev.detail.snapshotted_time,
-        };
-        out.device_b(&device)?;
-        emit_entries(engine.clone(), out, &dev.map.entries)?;
-        out.device_e()?;
+        emit_device(engine.clone(), out, dev)?;
     }
     out.superblock_e()?;
     out.eof()?;
@@ -378,10 +376,47 @@ pub fn dump_metadata(
     Ok(())
 }
 
+fn emit_device(
+    engine: Arc<dyn IoEngine>,
+    out: &mut dyn MetadataVisitor,
+    dev: &Device,
+) -> Result<()> {
+    let device = ir::Device {
+        dev_id: dev.thin_id,
+        mapped_blocks: dev.detail.mapped_blocks,
+        transaction: dev.detail.transaction_id,
+        creation_time: dev.detail.creation_time,
+        snap_time: dev.detail.snapshotted_time,
+    };
+    out.device_b(&device)?;
+    emit_entries(engine, out, &dev.map.entries)?;
+    out.device_e()?;
+    Ok(())
+}
+
+// Writes a complete metadata file holding just the one device, which may
+// be restored on its own.
+fn dump_device(
+    engine: Arc<dyn IoEngine>,
+    out: &mut dyn MetadataVisitor,
+    sb: &ir::Superblock,
+    dev: &Device,
+) -> Result<()> {
+    let out: &mut dyn MetadataVisitor = &mut OutputVisitor::new(out);
+    out.superblock_b(sb)?;
+    emit_device(engine, out, dev)?;
+    out.superblock_e()?;
+    out.eof()?;
+    Ok(())
+}
+
 //------------------------------------------
 
-pub fn dump_with_formatter(opts: ThinDumpOptions, out: &mut dyn MetadataVisitor) -> Result<()> {
-    let ctx = mk_context(&opts)?;
+fn read_metadata(
+    ctx: &ThinDumpContext,
+    opts: &ThinDumpOptions,
+    optimise: bool,
+) -> Result<(ThinSuperblock, Metadata)> {
     let sb = if opts.repair {
         read_or_rebuild_superblock(
             ctx.engine.clone(),
@@ -401,14 +436,73 @@ pub fn dump_with_formatter(opts: ThinDumpOptions, out: &mut dyn MetadataVisitor)
     let md = if opts.skip_mappings {
         build_metadata_without_mappings(ctx.engine.clone(), &sb)?
     } else {
-        let m = build_metadata_with_dev(ctx.engine.clone(), &sb, opts.selected_devs)?;
-        optimise_metadata(m)?
+        let m = build_metadata_with_dev(ctx.engine.clone(), &sb, opts.selected_devs.clone())?;
+        if optimise {
+            optimise_metadata(m)?
+        } else {
+            m
+        }
     };
 
+    Ok((sb, md))
+}
+
+pub fn dump_with_formatter(opts: ThinDumpOptions, out: &mut dyn MetadataVisitor) -> Result<()> {
+    let ctx = mk_context(&opts)?;
+    let (sb, md) = read_metadata(&ctx, &opts, true)?;
+
+    if let Some(first) = opts.renumber_from {
+        let ids = DevIdMap::sequential(md.devs.iter().map(|d| d.thin_id), first)?;
+        if let Some(path) = opts.id_map {
+            ids.write(path)?;
+        }
+        let mut out = RenumberVisitor::new(out, ids);
+        return dump_metadata(ctx.engine, &mut out, &sb, &md);
+    }
+
     dump_metadata(ctx.engine, out, &sb, &md)
 }
 
+// The devices are written without shared definitions, since those can't
+// be referenced across files.  The manifest lists one device per line, with
+// its id, number of mapped blocks and file name.
+fn dump_to_dir(opts: ThinDumpOptions, dir: &Path) -> Result<()> {
+    let ctx = mk_context(&opts)?;
+    let (sb, md) = read_metadata(&ctx, &opts, false)?;
+    let out_sb = to_superblock_ir(&sb)?;
+
+    std::fs::create_dir_all(dir).context(OutputError)?;
+    let mut manifest = Vec::new();
+    writeln!(manifest, "# dev_id mapped_blocks file")?;
+
+    for dev in &md.devs {
+        let name = format!("thin_{}.xml", dev.thin_id);
+        let path = dir.join(&name);
+        let f = File::create(&path).context(OutputError)?;
+        let mut out = xml::XmlWriter::new(BufWriter::new(f));
+        dump_device(ctx.engine.clone(), &mut out, &out_sb, dev)?;
+        writeln!(
+            manifest,
+            "{} {} {}",
+            dev.thin_id, dev.detail.mapped_blocks, name
+        )?;
+    }
+
+    // Written last, so a manifest is only present if every device was
+    std::fs::write(dir.join("manifest"), manifest).context(OutputError)?;
+    ctx.report.info(&format!(
+        "dumped {} devices to {}",
+        md.devs.len(),
+        dir.display()
+    ));
+    Ok(())
+}
+
 pub fn dump(opts: ThinDumpOptions) -> Result<()> {
+    if let Some(dir) = opts.output_dir {
+        return dump_to_dir(opts, dir);
+    }
+
     let writer: Box<dyn Write> = if opts.output.is_some() {
         let f = File::create(opts.output.unwrap()).context(OutputError)?;
         Box::new(BufWriter::new(f))
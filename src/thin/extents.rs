@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::thin::ir::{self, MetadataVisitor};
+use crate::thin::metadata_repair::SuperblockOverrides;
+
+//------------------------------------------
+
+// A minimal extent table, as may be exported from the mapping tables of
+// other storage systems.  Each line holds the fields
+//
+//     dev_id, virt_begin, data_begin, length, time
+//
+// separated by commas or tabs, with the block addresses and length in
+// units of data blocks.  Blank lines, lines starting with '#', and a
+// leading header line naming the fields are skipped.
+//
+// The table carries no superblock, so the data block size has to be given
+// by the caller.  The number of data blocks defaults to the end of the
+// highest mapped block, and the transaction id to zero.
+
+const FIELDS: [&str; 5] = ["dev_id", "virt_begin", "data_begin", "length", "time"];
+
+#[derive(Clone, Copy)]
+struct Extent {
+    virt_begin: u64,
+    data_begin: u64,
+    len: u64,
+    time: u32,
+}
+
+fn parse_field<T: std::str::FromStr>(line_nr: usize, name: &str, s: &str) -> Result<T> {
+    s.trim()
+        .parse::<T>()
+        .map_err(|_| anyhow!("line {}: invalid {} '{}'", line_nr, name, s.trim()))
+}
+
+fn parse_line(line_nr: usize, line: &str) -> Result<(u32, Extent)> {
+    let fields: Vec<&str> = line.split([',', '\t']).collect();
+    if fields.len() != FIELDS.len() {
+        return Err(anyhow!(
+            "line {}: expected {} fields, found {}",
+            line_nr,
+            FIELDS.len(),
+            fields.len()
+        ));
+    }
+
+    let dev_id = parse_field(line_nr, FIELDS[0], fields[0])?;
+    let e = Extent {
+        virt_begin: parse_field(line_nr, FIELDS[1], fields[1])?,
+        data_begin: parse_field(line_nr, FIELDS[2], fields[2])?,
+        len: parse_field(line_nr, FIELDS[3], fields[3])?,
+        time: parse_field(line_nr, FIELDS[4], fields[4])?,
+    };
+
+    if e.len == 0 {
+        return Err(anyhow!("line {}: zero length extent", line_nr));
+    }
+    if e.virt_begin.checked_add(e.len).is_none() || e.data_begin.checked_add(e.len).is_none() {
+        return Err(anyhow!(
+            "line {}: extent overflows the address space",
+            line_nr
+        ));
+    }
+
+    Ok((dev_id, e))
+}
+
+fn is_header(line: &str) -> bool {
+    line.split([',', '\t']).next().map(str::trim) == Some(FIELDS[0])
+}
+
+fn read_extents<R: Read>(input: R) -> Result<BTreeMap<u32, Vec<Extent>>> {
+    let mut devs: BTreeMap<u32, Vec<Extent>> = BTreeMap::new();
+    let mut seen_data = false;
+
+    for (i, line) in BufReader::new(input).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !seen_data && is_header(line) {
+            seen_data = true;
+            continue;
+        }
+        seen_data = true;
+
+        let (dev_id, e) = parse_line(i + 1, line)?;
+        devs.entry(dev_id).or_default().push(e);
+    }
+
+    // The mapping trees are built in key order
+    for (dev_id, extents) in devs.iter_mut() {
+        extents.sort_by_key(|e| e.virt_begin);
+        for w in extents.windows(2) {
+            if w[0].virt_begin + w[0].len > w[1].virt_begin {
+                return Err(anyhow!(
+                    "overlapping extents at virtual block {} of device {}",
+                    w[1].virt_begin,
+                    dev_id
+                ));
+            }
+        }
+    }
+
+    Ok(devs)
+}
+
+//------------------------------------------
+
+/// Reads an extent table, passing it to the visitor as if it were xml
+/// metadata for a pool with the given superblock overrides.
+pub fn read<R, M>(input: R, overrides: &SuperblockOverrides, visitor: &mut M) -> Result<()>
+where
+    R: Read,
+    M: MetadataVisitor,
+{
+    let data_block_size = overrides
+        .data_block_size
+        .ok_or_else(|| anyhow!("the data block size must be given for extent tables"))?;
+
+    let devs = read_extents(input)?;
+
+    let extents = devs.values().flatten();
+    let data_end = extents
+        .clone()
+        .map(|e| e.data_begin + e.len)
+        .max()
+        .unwrap_or(0);
+    let time = extents.map(|e| e.time).max().unwrap_or(0);
+
+    let nr_data_blocks = match overrides.nr_data_blocks {
+        Some(n) if n < data_end => {
+            return Err(anyhow!(
+                "extents reach data block {}, beyond the {} data blocks given",
+                data_end - 1,
+                n
+            ));
+        }
+        Some(n) => n,
+        None => data_end,
+    };
+
+    let sb = ir::Superblock {
+        uuid: "".to_string(),
+        time,
+        transaction: overrides.transaction_id.unwrap_or(0),
+        flags: None,
+        version: None,
+        data_block_size,
+        nr_data_blocks,
+        metadata_snap: None,
+    };
+
+    visitor.superblock_b(&sb)?;
+    for (dev_id, extents) in devs {
+        let d = ir::Device {
+            dev_id,
+            mapped_blocks: extents.iter().map(|e| e.len).sum(),
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        };
+        visitor.device_b(&d)?;
+        for e in extents {
+            visitor.map(&ir::Map {
+                thin_begin: e.virt_begin,
+                data_begin: e.data_begin,
+                time: e.time,
+                len: e.len,
+            })?;
+        }
+        visitor.device_e()?;
+    }
+    visitor.superblock_e()?;
+    visitor.eof()?;
+
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::commands::engine::*;
+use crate::io_engine::IoEngine;
+use crate::pdata::btree_walker::btree_to_map;
+use crate::pdata::layout::*;
+use crate::pdata::space_map::common::*;
+use crate::pdata::unpack::unpack;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+fn claim_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    layout: &mut MetadataLayout,
+    loc: u64,
+    structure: &'static str,
+) -> Result<()> {
+    if !layout.claim(loc, BlockRole::new(BlockKind::Superblock, structure))? {
+        return Ok(());
+    }
+    let sb = read_superblock(engine.as_ref(), loc)?;
+
+    // The space maps are only valid in the live superblock.
+    if loc == SUPERBLOCK_LOCATION {
+        let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root)?;
+        claim_metadata_sm(engine.as_ref(), layout, &metadata_root)?;
+        let data_root = unpack::<SMRoot>(&sb.data_sm_root)?;
+        claim_data_sm(engine.clone(), layout, &data_root)?;
+    }
+
+    claim_btree(engine.as_ref(), layout, sb.details_root, "details", None)?;
+    claim_btree(engine.as_ref(), layout, sb.mapping_root, "top_level", None)?;
+
+    // The roots of the devices are collected first, so the nodes of the top
+    // level tree are claimed above as such.
+    let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), true, sb.mapping_root)?;
+    for (dev_id, root) in roots {
+        claim_btree(engine.as_ref(), layout, root, "mapping", Some(dev_id))?;
+    }
+
+    if loc == SUPERBLOCK_LOCATION && sb.metadata_snap != 0 {
+        claim_superblock(engine, layout, sb.metadata_snap, "metadata_snap")?;
+    }
+
+    Ok(())
+}
+
+/// Builds the role of every block of the metadata.  The blocks of the
+/// metadata snapshot, if there is one, are included, with those it shares
+/// with the live metadata attributed to the latter.
+pub fn build_metadata_layout(engine: Arc<dyn IoEngine + Send + Sync>) -> Result<MetadataLayout> {
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root)?;
+
+    let mut layout = MetadataLayout::new(engine.get_nr_blocks());
+    claim_superblock(
+        engine.clone(),
+        &mut layout,
+        SUPERBLOCK_LOCATION,
+        "superblock",
+    )?;
+    fill_from_metadata_sm(engine, &mut layout, &metadata_root)?;
+
+    Ok(layout)
+}
+
+//------------------------------------------
+
+pub struct ThinLayoutOptions<'a> {
+    pub input: &'a Path,
+    pub output: Option<&'a Path>,
+    pub engine_opts: EngineOptions,
+    pub format: LayoutFormat,
+}
+
+pub fn metadata_layout(opts: ThinLayoutOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
+    let layout = build_metadata_layout(engine)?;
+
+    let mut w: Box<dyn Write> = if let Some(path) = opts.output {
+        Box::new(BufWriter::new(File::create(path)?))
+    } else {
+        Box::new(BufWriter::new(std::io::stdout()))
+    };
+    write_layout(&mut w, &layout, opts.format)?;
+    w.flush()?;
+
+    Ok(())
+}
+
+//------------------------------------------
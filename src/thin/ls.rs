@@ -1,33 +1,29 @@
 use anyhow::{anyhow, Result};
-use fixedbitset::FixedBitSet;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::str::FromStr;
-use std::sync::mpsc::{self, SyncSender};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::Arc;
 
 use crate::commands::engine::*;
+use crate::file_utils::TempFile;
 use crate::grid_layout::GridLayout;
 use crate::io_engine::SECTOR_SHIFT;
 use crate::io_engine::*;
-use crate::pdata::btree::{self, *};
+use crate::pack::toplevel::{is_pack_file, unpack as unpack_file};
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::common::SMRoot;
-use crate::pdata::space_map::*;
 use crate::pdata::unpack::unpack;
-use crate::report::{ProgressMonitor, Report};
-use crate::thin::block_time::BlockTime;
+use crate::report::Report;
+use crate::thin::accounting::*;
 use crate::thin::device_detail::DeviceDetail;
+use crate::thin::ir::{self, MetadataVisitor, Visit};
 use crate::thin::metadata_repair::is_superblock_consistent;
+use crate::thin::snap_reservation::SnapReservation;
 use crate::thin::superblock::*;
+use crate::thin::xml;
 use crate::units::*;
-use crate::utils::hashvec::HashVec;
-
-//------------------------------------------
-
-// minimum number of entries of a node with 64-bit mapped type
-const MIN_ENTRIES: u8 = 84;
 
 //------------------------------------------
 
@@ -38,21 +34,25 @@ pub enum OutputField {
     MappedBlocks,
     ExclusiveBlocks,
     SharedBlocks,
+    ReclaimableBlocks,
     HighestMappedBlock,
 
     MappedSectors,
     ExclusiveSectors,
     SharedSectors,
+    ReclaimableSectors,
     HighestMappedSector,
 
     MappedBytes,
     ExclusiveBytes,
     SharedBytes,
+    ReclaimableBytes,
     HighestMappedByte,
 
     Mapped,
     Exclusive,
     Shared,
+    Reclaimable,
     HighestMapped,
 
     TransactionId,
@@ -71,21 +71,25 @@ impl FromStr for OutputField {
             "MAPPED_BLOCKS" => Ok(MappedBlocks),
             "EXCLUSIVE_BLOCKS" => Ok(ExclusiveBlocks),
             "SHARED_BLOCKS" => Ok(SharedBlocks),
-            "HIGHEST_BLOCK" => Ok(HighestMappedBlock),
+            "RECLAIMABLE_BLOCKS" => Ok(ReclaimableBlocks),
+            "HIGHEST_BLOCK" | "HIGHEST_MAPPED_BLOCK" => Ok(HighestMappedBlock),
 
             "MAPPED_SECTORS" => Ok(MappedSectors),
             "EXCLUSIVE_SECTORS" => Ok(ExclusiveSectors),
             "SHARED_SECTORS" => Ok(SharedSectors),
-            "HIGHEST_SECTOR" => Ok(HighestMappedSector),
+            "RECLAIMABLE_SECTORS" => Ok(ReclaimableSectors),
+            "HIGHEST_SECTOR" | "HIGHEST_MAPPED_SECTOR" => Ok(HighestMappedSector),
 
             "MAPPED_BYTES" => Ok(MappedBytes),
             "EXCLUSIVE_BYTES" => Ok(ExclusiveBytes),
             "SHARED_BYTES" => Ok(SharedBytes),
-            "HIGHEST_BYTE" => Ok(HighestMappedByte),
+            "RECLAIMABLE_BYTES" => Ok(ReclaimableBytes),
+            "HIGHEST_BYTE" | "HIGHEST_MAPPED_BYTE" => Ok(HighestMappedByte),
 
             "MAPPED" => Ok(Mapped),
             "EXCLUSIVE" => Ok(Exclusive),
             "SHARED" => Ok(Shared),
+            "RECLAIMABLE" => Ok(Reclaimable),
             "HIGHEST_MAPPED" => Ok(HighestMapped),
 
             "TRANSACTION" => Ok(TransactionId),
@@ -106,21 +110,25 @@ impl ToString for OutputField {
             MappedBlocks => "MAPPED_BLOCKS",
             ExclusiveBlocks => "EXCLUSIVE_BLOCKS",
             SharedBlocks => "SHARED_BLOCKS",
+            ReclaimableBlocks => "RECLAIMABLE_BLOCKS",
             HighestMappedBlock => "HIGHEST_BLOCK",
 
             MappedSectors => "MAPPED_SECTORS",
             ExclusiveSectors => "EXCLUSIVE_SECTORS",
             SharedSectors => "SHARED_SECTORS",
+            ReclaimableSectors => "RECLAIMABLE_SECTORS",
             HighestMappedSector => "HIGHEST_SECTOR",
 
             MappedBytes => "MAPPED_BYTES",
             ExclusiveBytes => "EXCLUSIVE_BYTES",
             SharedBytes => "SHARED_BYTES",
+            ReclaimableBytes => "RECLAIMABLE_BYTES",
             HighestMappedByte => "HIGHEST_BYTE",
 
             Mapped => "MAPPED",
             Exclusive => "EXCLUSIVE",
             Shared => "SHARED",
+            Reclaimable => "RECLAIMABLE",
             HighestMapped => "HIGHEST_MAPPED",
 
             TransactionId => "TRANSACTION",
@@ -132,1005 +140,534 @@ impl ToString for OutputField {
 
 //------------------------------------------
 
-pub struct LsTable<'a> {
-    fields: &'a [OutputField],
-    grid: GridLayout,
-    data_block_size: u64,
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LsFormat {
+    Table,
+    Json,
 }
 
-impl<'a> LsTable<'a> {
-    fn new(fields: &'a [OutputField], nr_rows: usize, bs: u32) -> LsTable {
-        let grid = GridLayout::new_with_size(nr_rows, fields.len());
+impl FromStr for LsFormat {
+    type Err = anyhow::Error;
 
-        LsTable {
-            fields,
-            grid,
-            data_block_size: bs as u64,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(LsFormat::Table),
+            "json" => Ok(LsFormat::Json),
+            _ => Err(anyhow!("unknown format")),
         }
     }
+}
 
-    fn push_headers(&mut self) {
-        if self.fields.is_empty() {
-            return;
-        }
+/// Orders the devices by the value of a field
+#[derive(Clone)]
+pub struct SortKey {
+    pub field: OutputField,
+    pub descending: bool,
+}
 
-        for i in self.fields {
-            self.grid.field(i.to_string());
-        }
-        self.grid.new_row();
+// Parses "FIELD", or "FIELD:desc" for the largest first.  Field names may
+// be given in lower case.
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, descending) = match s.rsplit_once(':') {
+            Some((name, "desc")) => (name, true),
+            Some((name, "asc")) => (name, false),
+            Some(_) => return Err(anyhow!("expected FIELD, FIELD:asc or FIELD:desc")),
+            None => (s, false),
+        };
+        let field = name
+            .to_uppercase()
+            .parse::<OutputField>()
+            .map_err(|_| anyhow!("unknown field '{}'", name))?;
+        Ok(SortKey { field, descending })
     }
+}
 
-    fn push_row(
-        &mut self,
-        dev_id: u64,
-        detail: &DeviceDetail,
-        mapped_blocks: u64,
-        shared_blocks: u64,
-        highest_mapped_block: u64,
-    ) {
-        use OutputField::*;
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
+}
 
-        if self.fields.is_empty() {
-            return;
-        }
+/// Keeps the devices whose value of a field compares to a constant
+#[derive(Clone)]
+pub struct Filter {
+    field: OutputField,
+    cmp: Comparison,
+    value: u64,
+}
 
-        let bs = self.data_block_size;
-        let ex_blocks = mapped_blocks - shared_blocks;
-
-        for field in self.fields {
-            let val: u64 = match field {
-                DeviceId => dev_id,
-                TransactionId => detail.transaction_id,
-                CreationTime => detail.creation_time as u64,
-                SnapshottedTime => detail.snapshotted_time as u64,
-                MappedBlocks => mapped_blocks,
-                MappedSectors => mapped_blocks * bs,
-                MappedBytes | Mapped => (mapped_blocks * bs) << SECTOR_SHIFT as u64,
-                ExclusiveBlocks => ex_blocks,
-                ExclusiveSectors => ex_blocks * bs,
-                ExclusiveBytes | Exclusive => (ex_blocks * bs) << SECTOR_SHIFT as u64,
-                SharedBlocks => shared_blocks,
-                SharedSectors => shared_blocks * bs,
-                SharedBytes | Shared => (shared_blocks * bs) << SECTOR_SHIFT as u64,
-                HighestMappedBlock => highest_mapped_block,
-                HighestMappedSector => (highest_mapped_block + 1) * bs - 1,
-                HighestMappedByte | HighestMapped => {
-                    (((highest_mapped_block + 1) * bs) << SECTOR_SHIFT) - 1
-                }
-            };
-
-            let cell = match field {
-                Mapped | Exclusive | Shared | HighestMapped => {
-                    let (val, unit) = to_pretty_print_size(val);
-                    let mut s = val.to_string();
-                    s.push_str(&unit.to_string_short());
-                    s
-                }
-                _ => val.to_string(),
-            };
+impl Filter {
+    fn matches(&self, usage: &DeviceUsage, bs: u64) -> bool {
+        use Comparison::*;
 
-            self.grid.field(cell);
+        let v = usage.value(&self.field, bs);
+        match self.cmp {
+            Lt => v < self.value,
+            Le => v <= self.value,
+            Eq => v == self.value,
+            Ne => v != self.value,
+            Ge => v >= self.value,
+            Gt => v > self.value,
         }
-        self.grid.new_row();
-    }
-
-    // grid
-    pub fn render(&self, w: &mut dyn Write) -> Result<()> {
-        self.grid.render(w)
     }
 }
 
-//------------------------------------------
+// Parses expressions such as "mapped_blocks>1000000".  The comparisons are
+// <, <=, =, ==, !=, >= and >.
+impl FromStr for Filter {
+    type Err = anyhow::Error;
 
-#[derive(Debug, Clone)]
-struct InternalNodeInfo {
-    keys: Vec<u64>,
-    children: Vec<u32>,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use Comparison::*;
+
+        let bad_filter = || anyhow!("expected FIELD<op>VALUE, got '{}'", s);
+        let pos = s.find(['<', '>', '=', '!']).ok_or_else(bad_filter)?;
+        let (name, rest) = s.split_at(pos);
+        let (cmp, value) = if let Some(v) = rest.strip_prefix("<=") {
+            (Le, v)
+        } else if let Some(v) = rest.strip_prefix(">=") {
+            (Ge, v)
+        } else if let Some(v) = rest.strip_prefix("!=") {
+            (Ne, v)
+        } else if let Some(v) = rest.strip_prefix("==") {
+            (Eq, v)
+        } else if let Some(v) = rest.strip_prefix('<') {
+            (Lt, v)
+        } else if let Some(v) = rest.strip_prefix('>') {
+            (Gt, v)
+        } else if let Some(v) = rest.strip_prefix('=') {
+            (Eq, v)
+        } else {
+            return Err(bad_filter());
+        };
+
+        let name = name.trim();
+        let field = name
+            .to_uppercase()
+            .parse::<OutputField>()
+            .map_err(|_| anyhow!("unknown field '{}'", name))?;
+        let value = value.trim().parse::<u64>().map_err(|_| bad_filter())?;
+        Ok(Filter { field, cmp, value })
+    }
 }
 
-#[derive(Debug, Clone, Default)]
-struct NodeSummary {
-    key_low: u64,     // min mapped block
-    key_high: u64,    // max mapped block, inclusive
-    nr_mappings: u64, // number of valid mappings in this subtree
-    nr_shared: u64,   // number of shared mappings in this subtree
-    nr_entries: u8,   // number of entries in this node
-    nr_errors: u8,    // number of errors found in this subtree, up to 255
+// What the fields of a device are computed from
+struct DeviceUsage<'a> {
+    dev_id: u64,
+    detail: &'a DeviceDetail,
+    mapped_blocks: u64,
+    shared_blocks: u64,
+    reclaimable_blocks: u64,
+    highest_mapped_block: u64,
+    baseline_blocks: Option<u64>, // None if the device isn't in the baseline
 }
 
-impl NodeSummary {
-    fn from_leaf(keys: &[u64], nr_shared: u64) -> Self {
-        let nr_entries = keys.len();
-        let key_low = if nr_entries > 0 { keys[0] } else { 0 };
-        let key_high = if nr_entries > 0 {
-            keys[nr_entries - 1]
-        } else {
-            0
-        };
+impl<'a> DeviceUsage<'a> {
+    // The value of a field, before any pretty printing
+    fn value(&self, field: &OutputField, bs: u64) -> u64 {
+        use OutputField::*;
 
-        NodeSummary {
-            key_low,
-            key_high,
-            nr_mappings: nr_entries as u64,
-            nr_shared,
-            nr_entries: nr_entries as u8,
-            nr_errors: 0,
-        }
-    }
+        let ex_blocks = self.mapped_blocks - self.shared_blocks;
+        let highest = self.highest_mapped_block;
 
-    fn error() -> Self {
-        Self {
-            key_low: 0,
-            key_high: 0,
-            nr_mappings: 0,
-            nr_shared: 0,
-            nr_entries: 0,
-            nr_errors: 1,
+        match field {
+            DeviceId => self.dev_id,
+            TransactionId => self.detail.transaction_id,
+            CreationTime => self.detail.creation_time as u64,
+            SnapshottedTime => self.detail.snapshotted_time as u64,
+            MappedBlocks => self.mapped_blocks,
+            MappedSectors => self.mapped_blocks * bs,
+            MappedBytes | Mapped => (self.mapped_blocks * bs) << SECTOR_SHIFT as u64,
+            ExclusiveBlocks => ex_blocks,
+            ExclusiveSectors => ex_blocks * bs,
+            ExclusiveBytes | Exclusive => (ex_blocks * bs) << SECTOR_SHIFT as u64,
+            SharedBlocks => self.shared_blocks,
+            SharedSectors => self.shared_blocks * bs,
+            SharedBytes | Shared => (self.shared_blocks * bs) << SECTOR_SHIFT as u64,
+            ReclaimableBlocks => self.reclaimable_blocks,
+            ReclaimableSectors => self.reclaimable_blocks * bs,
+            ReclaimableBytes | Reclaimable => (self.reclaimable_blocks * bs) << SECTOR_SHIFT as u64,
+            HighestMappedBlock => highest,
+            HighestMappedSector => (highest + 1) * bs - 1,
+            HighestMappedByte | HighestMapped => (((highest + 1) * bs) << SECTOR_SHIFT) - 1,
         }
     }
+}
 
-    fn append(&mut self, child: &NodeSummary) -> anyhow::Result<()> {
-        if self.nr_mappings == 0 {
-            self.key_low = child.key_low;
-            self.key_high = child.key_high;
-        } else if child.nr_mappings > 0 {
-            if child.key_low <= self.key_high {
-                return Err(anyhow!("overlapped keys"));
-            }
-            self.key_high = child.key_high;
-        }
-        self.nr_mappings += child.nr_mappings;
-        self.nr_shared += child.nr_shared;
-        self.nr_entries += 1;
-        self.nr_errors = self.nr_errors.saturating_add(child.nr_errors);
+// The columns added by --baseline, after the fields asked for
+const BASELINE_HEADERS: [&str; 2] = ["BASELINE_BLOCKS", "GROWTH_BLOCKS"];
 
-        Ok(())
+impl<'a> DeviceUsage<'a> {
+    // Both are taken from the device details, as the baseline is, rather
+    // than by counting the mappings
+    fn growth(&self) -> i128 {
+        self.detail.mapped_blocks as i128 - self.baseline_blocks.unwrap_or(0) as i128
     }
 }
 
-//------------------------------------------
-
-#[derive(PartialEq)]
-enum NodeType {
-    None,
-    Internal,
-    Leaf,
-    Error,
+trait LsWriter {
+    fn push_row(&mut self, usage: &DeviceUsage);
+    fn render(&self, w: &mut dyn Write) -> Result<()>;
 }
 
-#[derive(Debug)]
-struct NodeMap {
-    node_type: FixedBitSet,
-    leaf_nodes: FixedBitSet, // FIXME: remove this one
-    nr_leaves: u32,
-    internal_info: HashVec<InternalNodeInfo>,
+//------------------------------------------
 
-    // Stores errors of the node itself; errors in children are not included
-    node_errors: HashVec<NodeError>,
+pub struct LsTable<'a> {
+    fields: &'a [OutputField],
+    grid: GridLayout,
+    data_block_size: u64,
+    baseline: bool,
 }
 
-impl NodeMap {
-    fn new(nr_blocks: u32) -> NodeMap {
-        NodeMap {
-            node_type: FixedBitSet::with_capacity((nr_blocks as usize) * 2),
-            leaf_nodes: FixedBitSet::with_capacity(nr_blocks as usize),
-            nr_leaves: 0,
-            internal_info: HashVec::new(),
-            node_errors: HashVec::new(),
-        }
-    }
+impl<'a> LsTable<'a> {
+    fn new(fields: &'a [OutputField], nr_rows: usize, bs: u32, baseline: bool) -> LsTable {
+        let grid = GridLayout::new_with_size(nr_rows, fields.len());
 
-    fn get_type(&self, blocknr: u32) -> NodeType {
-        // FIXME: query two bits at once
-        let lsb = self.node_type.contains(blocknr as usize * 2);
-        let msb = self.node_type.contains(blocknr as usize * 2 + 1);
-        if !lsb && msb {
-            NodeType::Error
-        } else if lsb && !msb {
-            NodeType::Leaf
-        } else if lsb && msb {
-            NodeType::Internal
-        } else {
-            NodeType::None
+        LsTable {
+            fields,
+            grid,
+            data_block_size: bs as u64,
+            baseline,
         }
     }
 
-    fn set_type_(&mut self, blocknr: u32, t: NodeType) {
-        match t {
-            NodeType::Leaf => {
-                self.node_type.insert(blocknr as usize * 2);
-                self.leaf_nodes.insert(blocknr as usize);
-                self.nr_leaves += 1;
-            }
-            NodeType::Internal => {
-                // FIXME: update two bits at once
-                self.node_type.insert(blocknr as usize * 2);
-                self.node_type.insert(blocknr as usize * 2 + 1);
-                if self.leaf_nodes.contains(blocknr as usize) {
-                    self.leaf_nodes.toggle(blocknr as usize);
-                    self.nr_leaves -= 1;
-                }
-            }
-            NodeType::Error => {
-                // FIXME: update two bits at once
-                self.node_type.insert(blocknr as usize * 2 + 1);
-                if self.leaf_nodes.contains(blocknr as usize) {
-                    self.node_type.toggle(blocknr as usize * 2);
-                    self.leaf_nodes.toggle(blocknr as usize);
-                    self.nr_leaves -= 1;
-                }
-            }
-            _ => {}
+    fn headers(&self) -> Vec<String> {
+        let mut headers: Vec<String> = self.fields.iter().map(|f| f.to_string()).collect();
+        if self.baseline {
+            headers.extend(BASELINE_HEADERS.iter().map(|h| h.to_string()));
         }
+        headers
     }
 
-    fn insert_internal_node(&mut self, blocknr: u32, info: InternalNodeInfo) -> Result<()> {
-        // Only accepts converting a potential unread leaf
-        let node_type = self.get_type(blocknr);
-        if node_type != NodeType::None && node_type != NodeType::Leaf {
-            return Err(anyhow!("type changed"));
+    fn push_headers(&mut self) {
+        if self.fields.is_empty() {
+            return;
         }
-        self.internal_info.insert(blocknr, info);
-        self.set_type_(blocknr, NodeType::Internal);
-        Ok(())
-    }
 
-    fn insert_leaf(&mut self, blocknr: u32) -> Result<()> {
-        // Only accepts an unread block
-        if self.get_type(blocknr) != NodeType::None {
-            return Err(anyhow!("type changed"));
+        for h in self.headers() {
+            self.grid.field(h);
         }
-        self.set_type_(blocknr, NodeType::Leaf);
-        Ok(())
+        self.grid.new_row();
     }
 
-    fn insert_error(&mut self, blocknr: u32, e: NodeError) -> Result<()> {
-        // Only accepts converting a potential unread leaf
-        let node_type = self.get_type(blocknr);
-        if node_type != NodeType::None && node_type != NodeType::Leaf {
-            return Err(anyhow!("type changed"));
-        }
-        self.node_errors.insert(blocknr, e);
-        self.set_type_(blocknr, NodeType::Error);
-        Ok(())
-    }
+    fn cells(&self, usage: &DeviceUsage) -> Vec<String> {
+        use OutputField::*;
 
-    // Returns total number of nodes found
-    fn len(&self) -> u32 {
-        self.internal_info.len() as u32 + self.nr_leaves
+        let mut cells: Vec<String> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let val = usage.value(field, self.data_block_size);
+                match field {
+                    Mapped | Exclusive | Shared | Reclaimable | HighestMapped => {
+                        let (val, unit) = to_pretty_print_size(val);
+                        let mut s = val.to_string();
+                        s.push_str(&unit.to_string_short());
+                        s
+                    }
+                    _ => val.to_string(),
+                }
+            })
+            .collect();
+
+        if self.baseline {
+            cells.push(
+                usage
+                    .baseline_blocks
+                    .map_or_else(|| "-".to_string(), |b| b.to_string()),
+            );
+            let growth = usage.growth();
+            cells.push(if growth > 0 {
+                format!("+{}", growth)
+            } else {
+                growth.to_string()
+            });
+        }
+        cells
     }
-}
 
-//------------------------------------------
-
-fn is_seen(loc: u32, metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>) -> Result<bool> {
-    let mut sm = metadata_sm.lock().unwrap();
-    sm.inc(loc as u64, 1)?;
-    Ok(sm.get(loc as u64).unwrap_or(0) > 1)
-}
-
-// FIXME: split up this function
-fn read_node_(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    b: &Block,
-    depth: usize,
-    ignore_non_fatal: bool,
-    nodes: &mut NodeMap,
-) {
-    // allow underfull nodes in the first pass
-    let node = match check_and_unpack_node::<u64>(b, ignore_non_fatal, true) {
-        Ok(n) => n,
-        Err(e) => {
-            // theoretically never fail
-            let _ = nodes.insert_error(b.loc as u32, e);
+    // The first column is drawn as a tree, so it's left aligned rather
+    // than right aligned like the others.
+    fn push_tree(&mut self, headers: bool, nodes: &[TreeNode]) {
+        if self.fields.is_empty() {
             return;
         }
-    };
 
-    use btree::Node::*;
-    if let Internal { keys, values, .. } = node {
-        let children = values.iter().map(|v| *v as u32).collect::<Vec<u32>>(); // FIXME: slow
-
-        // insert the node info in pre-order fashion to better detect loops in the path
-        let info = InternalNodeInfo { keys, children };
-
-        let _ = nodes.insert_internal_node(b.loc as u32, info);
-
-        // filter out previously visited nodes
-        let mut new_values = Vec::with_capacity(values.len());
-        for v in values {
-            if let Ok(seen) = is_seen(v as u32, metadata_sm) {
-                // Add the unread leaf if now it looks like an internal.
-                // Add the visited internal if now it looks like a leaf.
-                let node_type = nodes.get_type(v as u32);
-                let maybe_internal = depth > 0 && node_type == NodeType::Leaf;
-                let maybe_leaf = depth == 0 && node_type == NodeType::None;
-                if !seen || maybe_internal || maybe_leaf {
-                    new_values.push(v);
-                }
-            }
+        let mut rows: Vec<Vec<String>> = Vec::with_capacity(nodes.len() + 1);
+        if headers {
+            rows.push(self.headers());
         }
-        let values = new_values;
-
-        if depth == 0 {
-            for loc in values {
-                let _ = nodes.insert_leaf(loc as u32);
-            }
-        } else {
-            // we could error each child rather than the current node
-            match ctx.engine.read_many(&values) {
-                Ok(bs) => {
-                    for (i, b) in bs.iter().enumerate() {
-                        if let Ok(b) = b {
-                            read_node(ctx, metadata_sm, b, depth - 1, ignore_non_fatal, nodes);
-                        } else {
-                            // theoretically never fail
-                            let _ = nodes.insert_error(values[i] as u32, NodeError::IoError);
-                        }
-                    }
-                }
-                Err(_) => {
-                    // error every child node
-                    for loc in values {
-                        // theoretically never fail
-                        let _ = nodes.insert_error(loc as u32, NodeError::IoError);
-                    }
-                }
-            };
+        for node in nodes {
+            let mut cells = self.cells(node.usage);
+            cells[0] = format!("{}{}", node.prefix, cells[0]);
+            rows.push(cells);
         }
-    }
-}
-
-/// Reads a btree node and all internal btree nodes below it into the
-/// nodes parameter.  No errors are returned, instead the optional
-/// error field of the nodes will be filled in.
-fn read_node(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    b: &Block,
-    depth: usize,
-    ignore_non_fatal: bool,
-    nodes: &mut NodeMap,
-) {
-    read_node_(ctx, metadata_sm, b, depth, ignore_non_fatal, nodes);
-}
 
-/// Gets the depth of a bottom level mapping tree.  0 means the root is a leaf node.
-// FIXME: what if there's an error on the path to the leftmost leaf?
-fn get_depth(ctx: &Context, path: &mut Vec<u64>, root: u64, is_root: bool) -> Result<usize> {
-    use Node::*;
-
-    let b = ctx.engine.read(root).map_err(|_| io_err(path))?;
-    let node =
-        check_and_unpack_node::<BlockTime>(&b, true, is_root).map_err(|e| node_err(path, e))?;
-
-    match node {
-        Internal { values, .. } => {
-            // recurse down to the first good leaf
-            let mut last_err = None;
-            for child in values {
-                if path.contains(&child) {
-                    continue; // skip loops
-                }
-
-                path.push(child);
-                match get_depth(ctx, path, child, false) {
-                    Ok(n) => return Ok(n + 1),
-                    Err(e) => {
-                        last_err = Some(e);
-                    }
+        let width = rows.iter().map(|r| r[0].len()).max().unwrap_or(0);
+        for row in rows {
+            for (i, cell) in row.into_iter().enumerate() {
+                if i == 0 {
+                    self.grid.field(format!("{:<width$}", cell, width = width));
+                } else {
+                    self.grid.field(cell);
                 }
-                path.pop();
             }
-            Err(last_err.unwrap_or_else(|| node_err(path, NodeError::NumEntriesTooSmall).into()))
+            self.grid.new_row();
         }
-        Leaf { .. } => Ok(0),
     }
 }
 
-fn read_internal_nodes(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    root: u32,
-    ignore_non_fatal: bool,
-    nodes: &mut NodeMap,
-) {
-    match is_seen(root, metadata_sm) {
-        Ok(true) | Err(_) => return,
-        _ => {}
-    }
-
-    // FIXME: make get-depth more resilient
-    let mut path = Vec::new();
-    let depth = if let Ok(d) = get_depth(ctx, &mut path, root as u64, true) {
-        d
-    } else {
-        return;
-    };
-
-    if depth == 0 {
-        // The root will be skipped if it is a confirmed internal
-        let _ = nodes.insert_leaf(root);
-        return;
-    }
+impl<'a> LsWriter for LsTable<'a> {
+    fn push_row(&mut self, usage: &DeviceUsage) {
+        if self.fields.is_empty() {
+            return;
+        }
 
-    if let Ok(b) = ctx.engine.read(root as u64) {
-        read_node(ctx, metadata_sm, &b, depth - 1, ignore_non_fatal, nodes);
-    } else {
-        // FIXME: factor out common code
-        let _ = nodes.insert_error(root, NodeError::IoError);
+        for cell in self.cells(usage) {
+            self.grid.field(cell);
+        }
+        self.grid.new_row();
     }
-}
-
-fn collect_nodes_in_use(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    roots: &[u64],
-    ignore_non_fatal: bool,
-) -> NodeMap {
-    let mut nodes = NodeMap::new(ctx.engine.get_nr_blocks() as u32);
 
-    for root in roots {
-        read_internal_nodes(ctx, metadata_sm, *root as u32, ignore_non_fatal, &mut nodes);
+    // grid
+    fn render(&self, w: &mut dyn Write) -> Result<()> {
+        self.grid.render(w)
     }
-
-    nodes
 }
 
 //------------------------------------------
 
-// Summarize a subtree rooted at the speicifc block.
-// Only a good internal node will have a summary stored.
-// TODO: check the tree is balanced by comparing the height of visited nodes
-#[allow(clippy::too_many_arguments)]
-fn summarize_tree(
-    path: &mut Vec<u64>,
-    kr: &KeyRange,
-    root: u32,
-    is_root: bool,
-    nodes: &NodeMap,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    summaries: &mut HashVec<NodeSummary>,
-    ignore_non_fatal: bool,
-) -> NodeSummary {
-    if let Some(sum) = summaries.get(root) {
-        // Check underfull
-        if !ignore_non_fatal && !is_root && sum.nr_entries < MIN_ENTRIES {
-            return NodeSummary::error();
-        }
+// Writes an array holding an object per device, keyed by the field names.
+// Sizes are always given in their units, never pretty printed, and the
+// highest mapped fields of a device with nothing mapped are null.
+pub struct LsJson<'a> {
+    fields: &'a [OutputField],
+    rows: Vec<String>,
+    data_block_size: u64,
+    baseline: bool,
+}
 
-        // Check the key range against the parent keys.
-        if sum.nr_mappings > 0 {
-            if let Some(n) = kr.start {
-                // The parent key could be less than or equal to,
-                // but not greater than the child's first key
-                if n > sum.key_low {
-                    return NodeSummary::error();
-                }
-            }
-            if let Some(n) = kr.end {
-                // note that KeyRange is a right-opened interval
-                if n < sum.key_high {
-                    return NodeSummary::error();
-                }
-            }
+impl<'a> LsJson<'a> {
+    fn new(fields: &'a [OutputField], bs: u32, baseline: bool) -> LsJson {
+        LsJson {
+            fields,
+            rows: Vec::new(),
+            data_block_size: bs as u64,
+            baseline,
         }
-
-        return sum.clone();
     }
+}
 
-    match nodes.get_type(root) {
-        NodeType::Internal => {
-            if let Some(info) = nodes.internal_info.get(root) {
-                // Check underfull
-                if !ignore_non_fatal && !is_root && info.keys.len() < MIN_ENTRIES as usize {
-                    return NodeSummary::error();
-                }
+impl<'a> LsWriter for LsJson<'a> {
+    fn push_row(&mut self, usage: &DeviceUsage) {
+        use OutputField::*;
 
-                // Split up the key range for the children.
-                // Return immediately if the keys don't match.
-                let child_keys = match split_key_ranges(path, kr, &info.keys) {
-                    Ok(keys) => keys,
-                    Err(_) => return NodeSummary::error(),
+        let mut members: Vec<String> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let val = match field {
+                    HighestMappedBlock | HighestMappedSector | HighestMappedByte
+                    | HighestMapped
+                        if usage.mapped_blocks == 0 =>
+                    {
+                        "null".to_string()
+                    }
+                    _ => usage.value(field, self.data_block_size).to_string(),
                 };
-
-                // Gather information from the children
-                let mut sum = NodeSummary::default();
-                for (i, b) in info.children.iter().enumerate() {
-                    path.push(*b as u64);
-                    let child_sums = summarize_tree(
-                        path,
-                        &child_keys[i],
-                        *b,
-                        false,
-                        nodes,
-                        metadata_sm,
-                        summaries,
-                        ignore_non_fatal,
-                    );
-                    let _ = sum.append(&child_sums);
-                    path.pop();
-                }
-
-                // Adjust the number of shared blocks if it is a shared internal node
-                let metadata_sm = metadata_sm.lock().unwrap();
-                if metadata_sm.get(root as u64).unwrap_or(0) > 1 {
-                    sum.nr_shared = sum.nr_mappings;
-                }
-
-                summaries.insert(root, sum.clone());
-
-                sum
-            } else {
-                // This is unexpected. However, we would like to skip that kind of error.
-                NodeSummary::error()
-            }
+                format!("\"{}\": {}", field.to_string(), val)
+            })
+            .collect();
+        if self.baseline {
+            let baseline = usage
+                .baseline_blocks
+                .map_or_else(|| "null".to_string(), |b| b.to_string());
+            members.push(format!("\"{}\": {}", BASELINE_HEADERS[0], baseline));
+            members.push(format!("\"{}\": {}", BASELINE_HEADERS[1], usage.growth()));
         }
-
-        // This is unexpected since a leaf should have been summarized
-        _ => NodeSummary::error(),
-    }
-}
-
-fn count_mapped_blocks(
-    roots: &[u64],
-    nodes: &NodeMap,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    summaries: &mut HashVec<NodeSummary>,
-    ignore_non_fatal: bool,
-) {
-    for root in roots.iter() {
-        let mut path = vec![0, *root]; // the path is just for error reporting
-        let kr = KeyRange::new();
-        summarize_tree(
-            &mut path,
-            &kr,
-            *root as u32,
-            true,
-            nodes,
-            metadata_sm,
-            summaries,
-            ignore_non_fatal,
-        );
+        self.rows.push(format!("{{{}}}", members.join(", ")));
     }
-}
-
-//------------------------------------------
 
-fn unpacker(
-    blocks_rx: &Arc<Mutex<mpsc::Receiver<Vec<Block>>>>,
-    nodes_tx: SyncSender<Vec<Node<BlockTime>>>,
-    node_map: Arc<Mutex<NodeMap>>,
-    ignore_non_fatal: bool,
-) {
-    loop {
-        let blocks = {
-            let blocks_rx = blocks_rx.lock().unwrap();
-            if let Ok(blocks) = blocks_rx.recv() {
-                blocks
-            } else {
-                break;
-            }
-        };
-
-        let mut nodes = Vec::with_capacity(blocks.len());
-        let mut errs = Vec::new();
-
-        for b in blocks {
-            // Allow under full nodes in this phase.  The under full
-            // property will be check later based on the path context.
-            match check_and_unpack_node::<BlockTime>(&b, ignore_non_fatal, true) {
-                Ok(n) => {
-                    nodes.push(n);
-                }
-                Err(e) => {
-                    errs.push((b.loc, e));
-                }
-            }
+    fn render(&self, w: &mut dyn Write) -> Result<()> {
+        if self.rows.is_empty() {
+            writeln!(w, "[]")?;
+            return Ok(());
         }
 
-        if !errs.is_empty() {
-            let mut node_map = node_map.lock().unwrap();
-            for (b, e) in errs {
-                // theoretically never fail
-                let _ = node_map.insert_error(b as u32, e);
-            }
-        }
-
-        if nodes_tx.send(nodes).is_err() {
-            break;
+        writeln!(w, "[")?;
+        for (i, row) in self.rows.iter().enumerate() {
+            let sep = if i + 1 < self.rows.len() { "," } else { "" };
+            writeln!(w, "  {}{}", row, sep)?;
         }
+        writeln!(w, "]")?;
+        Ok(())
     }
 }
 
-fn summariser(
-    nodes_rx: mpsc::Receiver<Vec<Node<BlockTime>>>,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    summaries: &Arc<Mutex<HashVec<NodeSummary>>>,
-) {
-    let mut summaries = summaries.lock().unwrap();
-
-    loop {
-        let nodes = {
-            if let Ok(nodes) = nodes_rx.recv() {
-                nodes
-            } else {
-                break;
-            }
-        };
+//------------------------------------------
 
-        for n in nodes {
-            if let Node::Leaf {
-                keys,
-                values,
-                header,
-            } = n
-            {
-                {
-                    let mut data_sm = data_sm.lock().unwrap();
-                    for v in values {
-                        let _ = data_sm.inc(v.block, 1);
-                    }
-                }
+// A device of the tree view, and the lines drawn before it
+struct TreeNode<'a, 'b> {
+    prefix: String,
+    usage: &'b DeviceUsage<'a>,
+}
 
-                // summarize shared leaves in this phase
-                let metadata_sm = metadata_sm.lock().unwrap();
-                if metadata_sm.get(header.block).unwrap_or(0) > 1 {
-                    let sum = NodeSummary::from_leaf(&keys, keys.len() as u64);
-                    summaries.insert(header.block as u32, sum);
-                }
-            } else {
-                // Do not report error here. The error will be captured
-                // in the second phase.
-            }
-        }
-    }
+// Guesses the origin of each snapshot.  Taking a snapshot sets the
+// snapshotted time of the origin, and the creation time of the snapshot,
+// to the current time, so the origin is a device created earlier whose
+// snapshotted time is the creation time of the snapshot.  An origin that's
+// been snapshotted again since only remembers the latest time, so its
+// earlier snapshots are shown as having no origin.
+fn infer_origins(usages: &[DeviceUsage]) -> Vec<Option<usize>> {
+    usages
+        .iter()
+        .map(|snap| {
+            usages
+                .iter()
+                .enumerate()
+                .filter(|(_, o)| {
+                    o.detail.snapshotted_time == snap.detail.creation_time
+                        && o.detail.creation_time < snap.detail.creation_time
+                })
+                .min_by_key(|(_, o)| o.dev_id)
+                .map(|(i, _)| i)
+        })
+        .collect()
 }
 
-fn exclusive_leaves_summariser(
-    nodes_rx: mpsc::Receiver<Vec<Node<BlockTime>>>,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    summaries: &Arc<Mutex<HashVec<NodeSummary>>>,
+fn walk_tree<'a, 'b>(
+    usages: &'b [DeviceUsage<'a>],
+    children: &[Vec<usize>],
+    index: usize,
+    indent: &str,
+    branch: &str,
+    nodes: &mut Vec<TreeNode<'a, 'b>>,
 ) {
-    let mut summaries = summaries.lock().unwrap();
-
-    loop {
-        let nodes = {
-            if let Ok(nodes) = nodes_rx.recv() {
-                nodes
-            } else {
-                break;
-            }
-        };
-
-        for n in nodes {
-            if let Node::Leaf {
-                keys,
-                values,
-                header,
-            } = n
-            {
-                let metadata_sm = metadata_sm.lock().unwrap();
-                if metadata_sm.get(header.block).unwrap_or(0) == 1 {
-                    let data_sm = data_sm.lock().unwrap();
-
-                    let mut nr_shared: u64 = 0;
-                    for bt in values {
-                        if data_sm.get(bt.block).unwrap_or(0) > 1 {
-                            nr_shared += 1;
-                        }
-                    }
-
-                    let sum = NodeSummary::from_leaf(&keys, nr_shared);
-                    summaries.insert(header.block as u32, sum);
-                }
-            } else {
-                // Do not report error here. The error will be captured
-                // in the second phase.
-            }
-        }
+    nodes.push(TreeNode {
+        prefix: format!("{}{}", indent, branch),
+        usage: &usages[index],
+    });
+
+    let indent = match branch {
+        "" => String::new(),
+        "|- " => format!("{}|  ", indent),
+        _ => format!("{}   ", indent),
+    };
+    let kids = &children[index];
+    for (n, child) in kids.iter().enumerate() {
+        let branch = if n + 1 < kids.len() { "|- " } else { "`- " };
+        walk_tree(usages, children, *child, &indent, branch, nodes);
     }
 }
 
-fn read_leaf_nodes(
-    ctx: &Context,
-    nodes: NodeMap,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    ignore_non_fatal: bool,
-) -> Result<(NodeMap, HashVec<NodeSummary>)> {
-    const QUEUE_DEPTH: usize = 4;
-    const NR_UNPACKERS: usize = 4;
-
-    // Single IO thread reads vecs of blocks
-    // Many unpackers take the block vecs and turn them into btree nodes
-    // Single 'summariser' thread processes the nodes
-
-    // Build a vec of the leaf locations.  These will be in disk location
-    // order.
-    let mut leaves = Vec::with_capacity(nodes.nr_leaves as usize);
-    for loc in nodes.leaf_nodes.ones() {
-        leaves.push(loc as u64);
-    }
-
-    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(QUEUE_DEPTH);
-    let blocks_rx = Arc::new(Mutex::new(blocks_rx));
-
-    let (nodes_tx, nodes_rx) = mpsc::sync_channel::<Vec<Node<BlockTime>>>(QUEUE_DEPTH);
-
-    // Process chunks of leaves at once so the io engine can aggregate reads.
-    let summaries = Arc::new(Mutex::new(HashVec::with_capacity(nodes.len())));
-    let nodes = Arc::new(Mutex::new(nodes));
-
-    // Kick off the unpackers
-    let mut unpackers = Vec::with_capacity(NR_UNPACKERS);
-    for _i in 0..NR_UNPACKERS {
-        let blocks_rx = blocks_rx.clone();
-        let nodes_tx = nodes_tx.clone();
-        let node_map = nodes.clone();
-        unpackers.push(thread::spawn(move || {
-            unpacker(&blocks_rx, nodes_tx, node_map, ignore_non_fatal)
-        }));
-    }
-    drop(blocks_rx);
-    drop(nodes_tx);
-
-    // Kick off the summariser
-    let summariser_tid = {
-        let metadata_sm = metadata_sm.clone();
-        let data_sm = data_sm.clone();
-        let summaries = summaries.clone();
-        thread::spawn(move || {
-            summariser(nodes_rx, &metadata_sm, &data_sm, &summaries);
-        })
-    };
-
-    // IO is done in the main thread
-    let engine = ctx.engine.clone();
-    for c in leaves.chunks(1024) {
-        let mut bs = Vec::with_capacity(c.len());
-
-        // TODO: Retry blocks ignored by vectored io
-        if let Ok(blocks) = engine.read_many(c) {
-            for b in blocks {
-                if b.is_err() {
-                    continue;
-                }
-
-                let b = b.unwrap();
-                bs.push(b);
-            }
-
-            blocks_tx
-                .send(bs)
-                .expect("couldn't send blocks to unpacker");
-        } else {
-            let mut nodes = nodes.lock().unwrap();
-            for b in c {
-                let _ = nodes.insert_error(*b as u32, NodeError::IoError);
-            }
+// Orders the devices depth first, the snapshots of each below it, keeping
+// the order the devices are given in among siblings.
+fn mk_tree<'a, 'b>(usages: &'b [DeviceUsage<'a>]) -> Vec<TreeNode<'a, 'b>> {
+    let origins = infer_origins(usages);
+    let mut children = vec![Vec::new(); usages.len()];
+    for (i, origin) in origins.iter().enumerate() {
+        if let Some(o) = origin {
+            children[*o].push(i);
         }
     }
 
-    drop(blocks_tx);
-
-    // Wait for child threads
-    for tid in unpackers {
-        tid.join().expect("couldn't join unpacker");
+    let mut nodes = Vec::with_capacity(usages.len());
+    for (i, origin) in origins.iter().enumerate() {
+        if origin.is_none() {
+            walk_tree(usages, &children, i, "", "", &mut nodes);
+        }
     }
-    summariser_tid.join().expect("couldn't join summariser");
+    nodes
+}
 
-    // extract the results
-    let nodes = Arc::try_unwrap(nodes).unwrap().into_inner().unwrap();
-    let summaries = Arc::try_unwrap(summaries).unwrap().into_inner().unwrap();
+//------------------------------------------
 
-    Ok((nodes, summaries))
+// Collects the number of blocks mapped by each device of a dump, as given
+// in the device headers, so the mappings themselves are ignored.
+#[derive(Default)]
+struct BaselineReader {
+    seen_superblock: bool,
+    mapped: BTreeMap<u64, u64>,
 }
 
-fn read_exclusive_leaves(
-    ctx: &Context,
-    nodes: NodeMap,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    summaries: HashVec<NodeSummary>,
-    ignore_non_fatal: bool,
-) -> Result<(NodeMap, HashVec<NodeSummary>)> {
-    const QUEUE_DEPTH: usize = 4;
-    const NR_UNPACKERS: usize = 4;
-
-    // Single IO thread reads vecs of blocks
-    // Many unpackers take the block vecs and turn them into btree nodes
-    // Single 'summariser' thread processes the nodes
-
-    // Build a vec of the leaf locations.  These will be in disk location
-    // order.
-    let mut leaves = Vec::with_capacity(nodes.nr_leaves as usize);
-    {
-        let metadata_sm = metadata_sm.lock().unwrap();
-        for loc in nodes.leaf_nodes.ones() {
-            if metadata_sm.get(loc as u64).unwrap_or(0) == 1 {
-                leaves.push(loc as u64);
-            }
-        }
+impl MetadataVisitor for BaselineReader {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        self.seen_superblock = true;
+        Ok(Visit::Continue)
     }
 
-    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(QUEUE_DEPTH);
-    let blocks_rx = Arc::new(Mutex::new(blocks_rx));
-
-    let (nodes_tx, nodes_rx) = mpsc::sync_channel::<Vec<Node<BlockTime>>>(QUEUE_DEPTH);
-
-    // Process chunks of leaves at once so the io engine can aggregate reads.
-    let summaries = Arc::new(Mutex::new(summaries));
-    let nodes = Arc::new(Mutex::new(nodes));
-
-    // Kick off the unpackers
-    let mut unpackers = Vec::with_capacity(NR_UNPACKERS);
-    for _i in 0..NR_UNPACKERS {
-        let blocks_rx = blocks_rx.clone();
-        let nodes_tx = nodes_tx.clone();
-        let node_map = nodes.clone();
-        unpackers.push(thread::spawn(move || {
-            unpacker(&blocks_rx, nodes_tx, node_map, ignore_non_fatal)
-        }));
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
     }
-    drop(blocks_rx);
-    drop(nodes_tx);
-
-    // Kick off the summariser
-    let summariser_tid = {
-        let metadata_sm = metadata_sm.clone();
-        let data_sm = data_sm.clone();
-        let summaries = summaries.clone();
-        thread::spawn(move || {
-            exclusive_leaves_summariser(nodes_rx, &metadata_sm, &data_sm, &summaries);
-        })
-    };
 
-    // IO is done in the main thread
-    let engine = ctx.engine.clone();
-    for c in leaves.chunks(1024) {
-        let mut bs = Vec::with_capacity(c.len());
-
-        // TODO: Retry blocks ignored by vectored io
-        if let Ok(blocks) = engine.read_many(c) {
-            for b in blocks {
-                if b.is_err() {
-                    continue;
-                }
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
 
-                let b = b.unwrap();
-                bs.push(b);
-            }
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
 
-            blocks_tx
-                .send(bs)
-                .expect("couldn't send blocks to unpacker");
-        } else {
-            let mut nodes = nodes.lock().unwrap();
-            for b in c {
-                let _ = nodes.insert_error(*b as u32, NodeError::IoError);
-            }
-        }
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.mapped.insert(d.dev_id as u64, d.mapped_blocks);
+        Ok(Visit::Continue)
     }
 
-    drop(blocks_tx);
+    fn device_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
 
-    // Wait for child threads
-    for tid in unpackers {
-        tid.join().expect("couldn't join unpacker");
+    fn map(&mut self, _m: &ir::Map) -> Result<Visit> {
+        Ok(Visit::Continue)
     }
-    summariser_tid.join().expect("couldn't join summariser");
 
-    // extract the results
-    let nodes = Arc::try_unwrap(nodes).unwrap().into_inner().unwrap();
-    let summaries = Arc::try_unwrap(summaries).unwrap().into_inner().unwrap();
+    fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
 
-    Ok((nodes, summaries))
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
 }
 
-//------------------------------------------
-
-fn count_data_mappings_(
-    ctx: &Context,
-    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
-    roots: &[u64],
-    ignore_non_fatal: bool,
-) -> Result<HashVec<NodeSummary>> {
-    let start = std::time::Instant::now();
-    let nodes = collect_nodes_in_use(ctx, metadata_sm, roots, ignore_non_fatal);
-    let duration = start.elapsed();
-    ctx.report
-        .debug(&format!("reading internal nodes: {:?}", duration));
-
-    let start = std::time::Instant::now();
-    let (nodes, summaries) = read_leaf_nodes(ctx, nodes, metadata_sm, data_sm, ignore_non_fatal)?;
-    let duration = start.elapsed();
-    ctx.report
-        .debug(&format!("reading leaf nodes: {:?}", duration));
-
-    let start = std::time::Instant::now();
-    let nr_summarized = summaries.len();
-    let (nodes, mut summaries) = read_exclusive_leaves(
-        ctx,
-        nodes,
-        metadata_sm,
-        data_sm,
-        summaries,
-        ignore_non_fatal,
-    )?;
-    let nr_exclusive_leaves = summaries.len() - nr_summarized;
-    let duration = start.elapsed();
-    ctx.report
-        .debug(&format!("reading exclusive leaves: {:?}", duration));
-
-    let start = std::time::Instant::now();
-    count_mapped_blocks(roots, &nodes, metadata_sm, &mut summaries, ignore_non_fatal);
-    let duration = start.elapsed();
-    ctx.report
-        .debug(&format!("counting mapped blocks: {:?}", duration));
-
-    ctx.report
-        .info(&format!("nr internal nodes: {}", nodes.internal_info.len()));
-    ctx.report.info(&format!("nr leaves: {}", nodes.nr_leaves));
-    ctx.report
-        .info(&format!("nr exclusive leaves: {}", nr_exclusive_leaves));
-
-    Ok(summaries)
+// Only the device details of a pack are read, from a temporary file under
+// $TMPDIR that's removed once they have been.
+fn read_packed_baseline(path: &Path) -> Result<BTreeMap<u64, u64>> {
+    let tmp = TempFile::new(&std::env::temp_dir())?;
+    unpack_file(path, tmp.path())?;
+
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(tmp.path(), false)?);
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let details = btree_to_map::<DeviceDetail>(&mut vec![0], engine, false, sb.details_root)?;
+    Ok(details
+        .into_iter()
+        .map(|(dev_id, d)| (dev_id, d.mapped_blocks))
+        .collect())
 }
 
-fn count_data_mappings(
-    ctx: &Context,
-    sb: &Superblock,
-    mapping_root: u64,
-    ignore_non_fatal: bool,
-) -> Result<Vec<NodeSummary>> {
-    let mut path = Vec::new();
-    let roots = btree_to_value_vec(
-        &mut path,
-        ctx.engine.clone(),
-        ignore_non_fatal,
-        mapping_root,
-    )?;
-
-    let data_root = unpack::<SMRoot>(&sb.data_sm_root[..])?;
-    let data_sm: Arc<Mutex<dyn SpaceMap + Send + Sync>> =
-        Arc::new(Mutex::new(RestrictedTwoSpaceMap::new(data_root.nr_blocks)));
-    let metadata_sm: Arc<Mutex<dyn SpaceMap + Send + Sync>> = Arc::new(Mutex::new(
-        RestrictedTwoSpaceMap::new(ctx.engine.get_nr_blocks()),
-    ));
-
-    ctx.report.set_title("Scanning data mappings");
-    let mon_meta_sm = metadata_sm.clone();
-    let mon_data_sm = data_sm.clone();
-    let metadata_root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
-    let monitor = ProgressMonitor::new(
-        ctx.report.clone(),
-        metadata_root.nr_allocated + (data_root.nr_allocated / 8),
-        move || {
-            mon_meta_sm.lock().unwrap().get_nr_allocated().unwrap()
-                + (mon_data_sm.lock().unwrap().get_nr_allocated().unwrap() / 8)
-        },
-    );
-
-    let summaries = count_data_mappings_(ctx, &metadata_sm, &data_sm, &roots, ignore_non_fatal)?;
-    let summaries: Vec<NodeSummary> = roots
-        .iter()
-        .map_while(|root| summaries.get(*root as u32).filter(|sum| sum.nr_errors == 0))
-        .cloned()
-        .collect();
-
-    monitor.stop();
-    ctx.report.complete();
-
-    if summaries.len() != roots.len() {
-        return Err(anyhow!("metadata contains errors"));
+fn read_xml_baseline(path: &Path) -> Result<BTreeMap<u64, u64>> {
+    let mut v = BaselineReader::default();
+    xml::read(File::open(path)?, &mut v)?;
+    if !v.seen_superblock {
+        return Err(anyhow!("'{}' isn't a metadata dump", path.display()));
     }
+    Ok(v.mapped)
+}
 
-    Ok(summaries)
+fn read_baseline(path: &Path) -> Result<BTreeMap<u64, u64>> {
+    let r = if is_pack_file(path)? {
+        read_packed_baseline(path)
+    } else {
+        read_xml_baseline(path)
+    };
+    r.map_err(|e| e.context(format!("couldn't read the baseline '{}'", path.display())))
 }
 
 //------------------------------------------
@@ -1139,10 +676,25 @@ pub struct ThinLsOptions<'a> {
     pub input: &'a Path,
     pub engine_opts: EngineOptions,
     pub fields: Vec<OutputField>,
+    pub format: LsFormat,
+    pub sort: Option<SortKey>,
+    pub filters: Vec<Filter>,
+    pub tree: bool,
+    pub baseline: Option<&'a Path>,
+    pub auto_snap: Option<&'a str>, // the pool to reserve a metadata snapshot of
     pub no_headers: bool,
+    pub warn_data: Option<u8>,     // percent
+    pub warn_metadata: Option<u8>, // percent
     pub report: Arc<Report>,
 }
 
+/// The usage thresholds that were crossed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UsageAlarms {
+    pub data: bool,
+    pub metadata: bool,
+}
+
 struct Context {
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
@@ -1150,6 +702,7 @@ struct Context {
 
 fn mk_context(opts: &ThinLsOptions) -> Result<Context> {
     let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
 
@@ -1176,8 +729,40 @@ fn some_counting_fields(fields: &[OutputField]) -> bool {
     false
 }
 
-pub fn ls(opts: ThinLsOptions) -> Result<()> {
-    let ctx = mk_context(&opts)?;
+fn some_reclaimable_fields(fields: &[OutputField]) -> bool {
+    use OutputField::*;
+
+    fields.iter().any(|field| {
+        matches!(
+            field,
+            ReclaimableBlocks | ReclaimableSectors | ReclaimableBytes | Reclaimable
+        )
+    })
+}
+
+pub fn ls(mut opts: ThinLsOptions) -> Result<UsageAlarms> {
+    // The snapshot is released even if listing the devices fails
+    let snap = match opts.auto_snap {
+        Some(pool) => {
+            opts.engine_opts.use_metadata_snap = true;
+            Some(SnapReservation::reserve(pool)?)
+        }
+        None => None,
+    };
+    let alarms = list_devices(&opts);
+    match snap {
+        Some(snap) => {
+            let released = snap.release();
+            let alarms = alarms?;
+            released?;
+            Ok(alarms)
+        }
+        None => alarms,
+    }
+}
+
+fn list_devices(opts: &ThinLsOptions) -> Result<UsageAlarms> {
+    let ctx = mk_context(opts)?;
 
     let sb = if opts.engine_opts.use_metadata_snap {
         read_superblock_snap(ctx.engine.as_ref())?
@@ -1192,30 +777,130 @@ pub fn ls(opts: ThinLsOptions) -> Result<()> {
     let details =
         btree_to_map::<DeviceDetail>(&mut path, ctx.engine.clone(), false, sb.details_root)?;
 
-    let mut table = LsTable::new(&opts.fields, details.len(), sb.data_block_size);
-    if !opts.no_headers {
-        table.push_headers();
-    }
+    let baseline = opts.baseline.map(read_baseline).transpose()?;
+
+    // The devices may be sorted or filtered on fields that aren't output,
+    // which may need counting too.
+    let mut keys: Vec<OutputField> = opts.filters.iter().map(|f| f.field.clone()).collect();
+    keys.extend(opts.sort.iter().map(|k| k.field.clone()));
 
-    if some_counting_fields(&opts.fields) {
+    let mut usages = Vec::with_capacity(details.len());
+    if some_counting_fields(&opts.fields) || some_counting_fields(&keys) {
         let actual_sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
-        let mapped = count_data_mappings(&ctx, &actual_sb, sb.mapping_root, false)?;
-        for ((dev_id, detail), summary) in details.iter().zip(mapped) {
-            table.push_row(
-                *dev_id,
+        let accounting_opts = AccountingOptions {
+            reclaimable: some_reclaimable_fields(&opts.fields) || some_reclaimable_fields(&keys),
+            ignore_non_fatal: false,
+        };
+        let accounts = account_devices(
+            ctx.engine.clone(),
+            ctx.report.clone(),
+            &actual_sb,
+            sb.mapping_root,
+            &accounting_opts,
+        )?;
+        for ((dev_id, detail), account) in details.iter().zip(accounts) {
+            usages.push(DeviceUsage {
+                dev_id: *dev_id,
                 detail,
-                summary.nr_mappings,
-                summary.nr_shared,
-                summary.key_high,
-            );
+                mapped_blocks: account.mapped_blocks,
+                shared_blocks: account.shared_blocks,
+                reclaimable_blocks: account.reclaimable_blocks.unwrap_or(0),
+                highest_mapped_block: account.highest_mapped_block.unwrap_or(0),
+                baseline_blocks: baseline.as_ref().and_then(|b| b.get(dev_id).cloned()),
+            });
         }
     } else {
         for (dev_id, detail) in details.iter() {
-            table.push_row(*dev_id, detail, 0, 0, 0);
+            usages.push(DeviceUsage {
+                dev_id: *dev_id,
+                detail,
+                mapped_blocks: 0,
+                shared_blocks: 0,
+                reclaimable_blocks: 0,
+                highest_mapped_block: 0,
+                baseline_blocks: baseline.as_ref().and_then(|b| b.get(dev_id).cloned()),
+            });
+        }
+    }
+
+    let bs = sb.data_block_size as u64;
+    usages.retain(|u| opts.filters.iter().all(|f| f.matches(u, bs)));
+    if let Some(key) = &opts.sort {
+        usages.sort_by(|a, b| {
+            let ord = a.value(&key.field, bs).cmp(&b.value(&key.field, bs));
+            if key.descending {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+    }
+
+    if opts.tree {
+        let nodes = mk_tree(&usages);
+        let mut table = LsTable::new(
+            &opts.fields,
+            nodes.len() + 1,
+            sb.data_block_size,
+            baseline.is_some(),
+        );
+        table.push_tree(!opts.no_headers, &nodes);
+        table.render(&mut std::io::stdout())?;
+    } else {
+        let mut writer: Box<dyn LsWriter + '_> = match opts.format {
+            LsFormat::Table => {
+                let mut table = LsTable::new(
+                    &opts.fields,
+                    usages.len(),
+                    sb.data_block_size,
+                    baseline.is_some(),
+                );
+                if !opts.no_headers {
+                    table.push_headers();
+                }
+                Box::new(table)
+            }
+            LsFormat::Json => Box::new(LsJson::new(
+                &opts.fields,
+                sb.data_block_size,
+                baseline.is_some(),
+            )),
+        };
+        for u in &usages {
+            writer.push_row(u);
         }
+        writer.render(&mut std::io::stdout())?;
     }
 
-    table.render(&mut std::io::stdout())
+    // Usage is always taken from the live superblock, the space maps of a
+    // metadata snapshot are stale.
+    let actual_sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let data_root = unpack::<SMRoot>(&actual_sb.data_sm_root[0..])?;
+    let metadata_root = unpack::<SMRoot>(&actual_sb.metadata_sm_root[0..])?;
+    Ok(UsageAlarms {
+        data: check_usage(&ctx.report, "DATA", &data_root, opts.warn_data),
+        metadata: check_usage(&ctx.report, "METADATA", &metadata_root, opts.warn_metadata),
+    })
+}
+
+// Emits an alarm record if the usage has reached the threshold
+fn check_usage(report: &Report, kind: &str, root: &SMRoot, threshold: Option<u8>) -> bool {
+    let threshold = match threshold {
+        Some(t) => t as u64,
+        None => return false,
+    };
+
+    let (used, total) = (root.nr_allocated, root.nr_blocks);
+    if used * 100 < threshold * total {
+        return false;
+    }
+
+    let percent = if total > 0 { used * 100 / total } else { 0 };
+    report.warning(&format!(
+        "{}_USAGE_ALARM percent={} used={} total={} threshold={}",
+        kind, percent, used, total, threshold
+    ));
+    true
 }
 
 //------------------------------------------
@@ -2,6 +2,7 @@ use anyhow::Result;
 use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
+use crate::checksum;
 use crate::io_engine::IoEngine;
 use crate::pdata::btree::{self, *};
 use crate::pdata::btree_leaf_walker::*;
@@ -127,23 +128,31 @@ fn build_metadata_with_dev_(
     devices: &BTreeMap<u64, (u64, DeviceDetail)>,
 ) -> Result<Metadata> {
     let mapping_roots: BTreeSet<u64> = devices.values().map(|(root, _)| *root).collect();
-    let entry_map = collect_leaves(engine.clone(), &mapping_roots)?;
-
-    let devs: Vec<Device> = devices
-        .iter()
-        .map(|(&thin_id, &(root, detail))| {
-            let kr = KeyRange::new(); // FIXME: finish
-            let es = entry_map.get(&root).unwrap();
-            Device {
-                thin_id: thin_id as u32,
-                detail,
-                map: Mapping {
-                    kr,
-                    entries: es.to_vec(),
-                },
-            }
-        })
-        .collect();
+    let mut entry_map = collect_leaves(engine.clone(), &mapping_roots)?;
+
+    // As with a salvage, the leaves of a tree are moved to the last device
+    // using it, so a pool without snapshots holds a single list per device.
+    let mut nr_uses: BTreeMap<u64, usize> = BTreeMap::new();
+    for (root, _) in devices.values() {
+        *nr_uses.entry(*root).or_insert(0) += 1;
+    }
+
+    let mut devs = Vec::with_capacity(devices.len());
+    for (&thin_id, &(root, detail)) in devices {
+        let kr = KeyRange::new(); // FIXME: finish
+        let uses = nr_uses.get_mut(&root).unwrap();
+        *uses -= 1;
+        let entries = if *uses == 0 {
+            entry_map.remove(&root).unwrap()
+        } else {
+            entry_map[&root].clone()
+        };
+        devs.push(Device {
+            thin_id: thin_id as u32,
+            detail,
+            map: Mapping { kr, entries },
+        });
+    }
 
     Ok(Metadata {
         defs: Vec::new(),
@@ -166,11 +175,11 @@ fn devices_iter(
         .map(|((thin_id, root), detail)| (thin_id, (root, detail))))
 }
 
-pub fn build_metadata_with_dev(
+fn select_devices(
     engine: Arc<dyn IoEngine + Send + Sync>,
     sb: &ThinSuperblock,
     selected_dev: Option<Vec<u64>>,
-) -> Result<Metadata> {
+) -> Result<BTreeMap<u64, (u64, DeviceDetail)>> {
     let devs: BTreeMap<u64, (u64, DeviceDetail)> = match sb {
         ThinSuperblock::OnDisk(sb) => {
             let iter = devices_iter(engine.clone(), sb)?;
@@ -195,9 +204,174 @@ pub fn build_metadata_with_dev(
         }
     };
 
+    Ok(devs)
+}
+
+pub fn build_metadata_with_dev(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    selected_dev: Option<Vec<u64>>,
+) -> Result<Metadata> {
+    let devs = select_devices(engine.clone(), sb, selected_dev)?;
     build_metadata_with_dev_(engine, &devs)
 }
 
+//------------------------------------------
+
+/// The virtual block ranges of a device whose mappings couldn't be read
+pub struct LostMappings {
+    pub thin_id: ThinId,
+    pub ranges: Vec<KeyRange>,
+}
+
+// Unlike CollectLeaves, the key range of each leaf is kept so it can be
+// reported if the leaf turns out to be damaged.
+struct SalvageLeaves {
+    leaves: Vec<(KeyRange, u64)>,
+}
+
+impl LeafVisitor<BlockTime> for SalvageLeaves {
+    fn visit(&mut self, kr: &KeyRange, b: u64) -> btree::Result<()> {
+        self.leaves.push((kr.clone(), b));
+        Ok(())
+    }
+
+    fn visit_again(&mut self, _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&mut self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+struct SalvagedTree {
+    entries: Vec<Entry>,
+    lost: Vec<KeyRange>,
+    nr_mapped: u64,
+}
+
+fn salvage_tree(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sm: &mut dyn SpaceMap,
+    root: u64,
+) -> SalvagedTree {
+    let mut w = LeafWalker::new(engine.clone(), sm, false).salvage();
+    let mut v = SalvageLeaves { leaves: Vec::new() };
+    let mut path = vec![0];
+    let walked = w.walk::<SalvageLeaves, BlockTime>(&mut path, &mut v, root);
+    if walked.is_err() {
+        // nothing below a damaged root can be found
+        return SalvagedTree {
+            entries: Vec::new(),
+            lost: vec![KeyRange::new()],
+            nr_mapped: 0,
+        };
+    }
+    let mut lost = w.take_lost();
+
+    // The leaves aren't read by the walk, so check them here, the same
+    // way the dump will unpack them.
+    let mut entries = Vec::with_capacity(v.leaves.len());
+    let mut nr_mapped = 0;
+    for chunk in v.leaves.chunks(engine.get_batch_size().max(64)) {
+        let locs: Vec<u64> = chunk.iter().map(|(_, b)| *b).collect();
+        let blocks = engine.read_many(&locs);
+        for (i, (kr, loc)) in chunk.iter().enumerate() {
+            let node = match &blocks {
+                Ok(bs) => match &bs[i] {
+                    Ok(b) if checksum::metadata_block_type(b.get_data()) == checksum::BT::NODE => {
+                        unpack_node::<BlockTime>(&path, b.get_data(), true, true).ok()
+                    }
+                    _ => None,
+                },
+                Err(_) => None,
+            };
+
+            match node {
+                Some(Node::Leaf { keys, .. }) => {
+                    nr_mapped += keys.len() as u64;
+                    entries.push(Entry::Leaf(*loc));
+                }
+                _ => lost.push(kr.clone()),
+            }
+        }
+    }
+
+    lost.sort_by_key(|kr| kr.start);
+    SalvagedTree {
+        entries,
+        lost,
+        nr_mapped,
+    }
+}
+
+/// Builds the metadata from whatever can be read of each mapping tree.
+///
+/// Damaged subtrees and leaves are skipped, and the device details of the
+/// devices affected are corrected to count only the mappings recovered.
+pub fn build_metadata_salvage(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+) -> Result<(Metadata, Vec<LostMappings>)> {
+    let devices = select_devices(engine.clone(), sb, None)?;
+
+    // Snapshots that haven't diverged share a root, so each tree is only
+    // walked once.
+    let mut sm = RestrictedSpaceMap::new(engine.get_nr_blocks());
+    let mut trees: BTreeMap<u64, SalvagedTree> = BTreeMap::new();
+    for (root, _) in devices.values() {
+        if !trees.contains_key(root) {
+            let tree = salvage_tree(engine.clone(), &mut sm, *root);
+            trees.insert(*root, tree);
+        }
+    }
+
+    // The leaves of a tree are moved to the last device using it, rather
+    // than copied, so there's only ever one list per tree.
+    let mut nr_uses: BTreeMap<u64, usize> = BTreeMap::new();
+    for (root, _) in devices.values() {
+        *nr_uses.entry(*root).or_insert(0) += 1;
+    }
+
+    let mut devs = Vec::with_capacity(devices.len());
+    let mut losses = Vec::new();
+    for (&thin_id, &(root, mut detail)) in &devices {
+        let tree = &trees[&root];
+        if !tree.lost.is_empty() {
+            detail.mapped_blocks = tree.nr_mapped;
+            losses.push(LostMappings {
+                thin_id: thin_id as u32,
+                ranges: tree.lost.clone(),
+            });
+        }
+
+        let uses = nr_uses.get_mut(&root).unwrap();
+        *uses -= 1;
+        let entries = if *uses == 0 {
+            trees.remove(&root).unwrap().entries
+        } else {
+            tree.entries.clone()
+        };
+
+        devs.push(Device {
+            thin_id: thin_id as u32,
+            detail,
+            map: Mapping {
+                kr: KeyRange::new(),
+                entries,
+            },
+        });
+    }
+
+    let md = Metadata {
+        defs: Vec::new(),
+        devs,
+        nr_blocks: engine.get_nr_blocks(),
+    };
+    Ok((md, losses))
+}
+
 fn build_metadata_without_mappings_(
     engine: Arc<dyn IoEngine + Send + Sync>,
     details: &mut dyn Iterator<Item = (&u64, &DeviceDetail)>,
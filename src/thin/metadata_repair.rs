@@ -15,6 +15,7 @@ use crate::report::Report;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
 use crate::thin::metadata::{CoreSuperblock, ThinSuperblock};
+use crate::thin::repair_answers::*;
 use crate::thin::superblock::*;
 
 #[cfg(test)]
@@ -29,6 +30,18 @@ pub struct SuperblockOverrides {
     pub nr_data_blocks: Option<u64>,
 }
 
+/// Controls how the fields lost with the superblock are inferred, when they
+/// aren't given as overrides.
+#[derive(Clone, Default)]
+pub struct SuperblockInference {
+    /// Apply the inferred values without asking
+    pub apply: bool,
+    /// The size of the data device, in sectors
+    pub data_dev_sectors: Option<u64>,
+    /// Where the answers to the questions about the rebuild come from
+    pub answers: RepairAnswers,
+}
+
 struct RootPair {
     mapping_root: u64,
     details_root: u64,
@@ -46,6 +59,9 @@ struct FoundRoots {
     time: u32,
     transaction_id: u64,
     nr_data_blocks: u64,
+    mapping_root: u64,
+    nr_devices: u64,
+    nr_mappings: u64,
 }
 
 fn devices_identical(
@@ -674,6 +690,9 @@ fn to_found_roots(dev_root: &DevInfo, details_root: &DetailsInfo) -> Result<Foun
         time: std::cmp::max(dev_root.age, details_root.age),
         transaction_id: details_root.max_tid + 1, // tid in superblock is ahead by 1
         nr_data_blocks: dev_root.highest_mapped_data_block + 1,
+        mapping_root: dev_root.b,
+        nr_devices: dev_root.nr_devices,
+        nr_mappings: dev_root.nr_mappings,
     })
 }
 
@@ -710,6 +729,9 @@ fn to_partial_found_roots(
         time: dev_root.age + 1,
         transaction_id: 1,
         nr_data_blocks: dev_root.highest_mapped_data_block + 1,
+        mapping_root: dev_root.b,
+        nr_devices: dev_root.nr_devices,
+        nr_mappings: dev_root.nr_mappings,
     })
 }
 
@@ -780,6 +802,92 @@ fn find_roots(
         .collect()
 }
 
+fn compare_ranks(lhs: &FoundRoots, rhs: &FoundRoots) -> Ordering {
+    rhs.transaction_id
+        .cmp(&lhs.transaction_id)
+        .then(rhs.nr_mappings.cmp(&lhs.nr_mappings))
+}
+
+// Ranks the roots found for --scan-roots, the latest transaction first, then
+// those reaching the most mappings.  The sort is stable so ties keep the
+// order of find_roots.
+fn rank_roots(roots: &mut [FoundRoots]) {
+    roots.sort_by(compare_ranks);
+}
+
+/// A generation of the metadata that a repair could start from
+pub struct RootCandidate {
+    pub mapping_root: u64,
+    /// None if the device details were lost, and will be regenerated
+    pub details_root: Option<u64>,
+    pub transaction_id: u64,
+    pub time: u32,
+    pub nr_devices: u64,
+    pub nr_mappings: u64,
+    /// Locations of the intact superblocks referring to these roots
+    pub superblocks: Vec<u64>,
+}
+
+// Sweeps the device for blocks that pass as superblocks, such as the copy
+// taken for a metadata snapshot.
+fn find_superblocks(engine: &dyn IoEngine) -> Result<Vec<(u64, Superblock)>> {
+    const CHUNK_SIZE: u64 = 1024;
+
+    let mut sbs = Vec::new();
+    let nr_blocks = engine.get_nr_blocks();
+    for begin in (0..nr_blocks).step_by(CHUNK_SIZE as usize) {
+        let end = std::cmp::min(begin + CHUNK_SIZE, nr_blocks);
+        let blocks: Vec<u64> = (begin..end).collect();
+        for b in engine.read_many(&blocks)?.into_iter().flatten() {
+            if checksum::metadata_block_type(b.get_data()) == checksum::BT::THIN_SUPERBLOCK {
+                if let Ok(sb) = read_superblock(engine, b.loc) {
+                    sbs.push((b.loc, sb));
+                }
+            }
+        }
+    }
+
+    Ok(sbs)
+}
+
+/// Lists the roots a repair could start from, in the order they're chosen
+/// by index with read_or_infer_superblock.
+pub fn scan_roots(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+) -> Result<Vec<RootCandidate>> {
+    let mut found_roots = find_roots(engine.clone(), report)?;
+    rank_roots(&mut found_roots);
+    let sbs = find_superblocks(engine.as_ref())?;
+
+    Ok(found_roots
+        .iter()
+        .map(|roots| {
+            let details_root = match &roots.devices {
+                TreeRoots::OnDisk(r) => Some(r.details_root),
+                TreeRoots::InCore(_) => None,
+            };
+            let superblocks = sbs
+                .iter()
+                .filter(|(_, sb)| {
+                    sb.mapping_root == roots.mapping_root && Some(sb.details_root) == details_root
+                })
+                .map(|(loc, _)| *loc)
+                .collect();
+
+            RootCandidate {
+                mapping_root: roots.mapping_root,
+                details_root,
+                transaction_id: roots.transaction_id,
+                time: roots.time,
+                nr_devices: roots.nr_devices,
+                nr_mappings: roots.nr_mappings,
+                superblocks,
+            }
+        })
+        .collect())
+}
+
 fn check_data_block_size(bs: u32) -> Result<u32> {
     if !(128..=2097152).contains(&bs) || (bs & 0x7F != 0) {
         return Err(anyhow!("invalid data block size"));
@@ -787,6 +895,130 @@ fn check_data_block_size(bs: u32) -> Result<u32> {
     Ok(bs)
 }
 
+// lvm creates pools with 64k data blocks unless told otherwise
+const DEFAULT_DATA_BLOCK_SIZE: u32 = 128;
+
+// The data device of a pool holds a whole number of data blocks, and at
+// least as many as the mappings refer to.  Any block size meeting both is
+// as likely as another, so if more than one does the operator has to choose.
+fn infer_data_block_size(
+    nr_data_blocks: u64,
+    inference: &SuperblockInference,
+    report: &Report,
+) -> Result<u32> {
+    let sectors = match inference.data_dev_sectors {
+        Some(sectors) => sectors,
+        None => return Ok(DEFAULT_DATA_BLOCK_SIZE),
+    };
+
+    let fits: Vec<u32> = (0..=14)
+        .map(|shift| 128u32 << shift)
+        .filter(|bs| sectors % *bs as u64 == 0 && sectors / *bs as u64 >= nr_data_blocks)
+        .collect();
+    let sizes: Vec<String> = fits.iter().map(|bs| bs.to_string()).collect();
+
+    let largest = match fits.as_slice() {
+        [] => {
+            return Err(anyhow!(
+                "couldn't infer the data block size from a data device of {} sectors",
+                sectors
+            ))
+        }
+        [bs] => return Ok(*bs),
+        [.., bs] => *bs,
+    };
+
+    let question = format!(
+        "sizes of {} sectors all fit the data device, choose the data block size:",
+        sizes.join(", ")
+    );
+    let answer = inference
+        .answers
+        .ask(report, "data_block_size", &question, &largest.to_string())?
+        .ok_or_else(|| {
+            anyhow!(
+                "the data block size is ambiguous, sizes of {} sectors all fit a data device \
+                 of {} sectors, provide it with --data-block-size",
+                sizes.join(", "),
+                sectors
+            )
+        })?;
+    answer
+        .parse::<u32>()
+        .ok()
+        .filter(|bs| fits.contains(bs))
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid answer '{}' to 'data_block_size', expected one of {}",
+                answer,
+                sizes.join(", ")
+            )
+        })
+}
+
+fn confirm_inference(report: &Report, proposal: &str) -> bool {
+    // Only ask if there's someone to answer
+    if !atty::is(atty::Stream::Stdin) {
+        return false;
+    }
+
+    report
+        .get_prompt_input(&format!("use the inferred {}? [y/n]: ", proposal))
+        .map(|input| {
+            let input = input.trim_end().to_lowercase();
+            input.eq("yes") || input.eq("y")
+        })
+        .unwrap_or(false)
+}
+
+// Fills in the overrides missing for a rebuilt superblock with the values
+// inferred from the trees and data device, once they have been accepted.
+fn infer_overrides(
+    roots: &FoundRoots,
+    ref_sb: Option<&Superblock>,
+    opts: &SuperblockOverrides,
+    inference: &SuperblockInference,
+    report: &Report,
+) -> Result<SuperblockOverrides> {
+    let mut opts = *opts;
+    if opts.data_block_size.is_some() || ref_sb.is_some() {
+        return Ok(opts);
+    }
+
+    let bs = infer_data_block_size(roots.nr_data_blocks, inference, report)?;
+    let nr_data_blocks = inference
+        .data_dev_sectors
+        .map_or(roots.nr_data_blocks, |sectors| sectors / bs as u64);
+    let proposal = format!(
+        "data block size of {} sectors, with {} data blocks",
+        bs,
+        opts.nr_data_blocks.unwrap_or(nr_data_blocks)
+    );
+
+    let accepted = if inference.apply {
+        report.info(&format!("inferred a {}", proposal));
+        true
+    } else {
+        let question = format!("the superblock is lost, use the inferred {}?", proposal);
+        match inference.answers.ask(report, "infer", &question, "n")? {
+            Some(answer) => parse_yes_no("infer", &answer)?,
+            None => confirm_inference(report, &proposal),
+        }
+    };
+
+    if !accepted {
+        return Err(anyhow!(
+            "data block size needs to be provided due to corruption in the superblock, \
+             or pass --infer to use the inferred {}",
+            proposal
+        ));
+    }
+
+    opts.data_block_size = Some(bs);
+    opts.nr_data_blocks = opts.nr_data_blocks.or(Some(nr_data_blocks));
+    Ok(opts)
+}
+
 //------------------------------------------
 
 #[derive(Debug)]
@@ -964,7 +1196,108 @@ pub fn read_or_rebuild_superblock(
     loc: u64,
     opts: &SuperblockOverrides,
 ) -> Result<ThinSuperblock> {
-    let found_roots = find_roots(engine.clone(), report)?;
+    read_or_infer_superblock(engine, report, loc, opts, None, None)
+}
+
+// Asks which of the roots found to rebuild the superblock from, if there's a
+// choice and someone to answer.  The candidates are listed in the order of
+// --scan-roots, with the first found taken by default.
+fn choose_roots<'a>(
+    found_roots: &'a [FoundRoots],
+    inference: Option<&SuperblockInference>,
+    report: &Report,
+) -> Result<&'a FoundRoots> {
+    let answers = match inference {
+        Some(inference) if found_roots.len() > 1 && !inference.answers.is_automatic() => {
+            &inference.answers
+        }
+        _ => return Ok(&found_roots[0]),
+    };
+
+    let mut ranked: Vec<usize> = (0..found_roots.len()).collect();
+    ranked.sort_by(|lhs, rhs| compare_ranks(&found_roots[*lhs], &found_roots[*rhs]));
+    let default = ranked.iter().position(|i| *i == 0).unwrap_or(0);
+
+    let mut question = String::from(
+        "the superblock doesn't match the trees found, choose the roots to repair from:",
+    );
+    for (index, i) in ranked.iter().enumerate() {
+        let roots = &found_roots[*i];
+        let details_root = match &roots.devices {
+            TreeRoots::OnDisk(r) => r.details_root.to_string(),
+            TreeRoots::InCore(_) => "-".to_string(),
+        };
+        question.push_str(&format!(
+            "\n  {}: transaction {}, time {}, {} devices, {} mappings, mapping root {}, details root {}",
+            index,
+            roots.transaction_id,
+            roots.time,
+            roots.nr_devices,
+            roots.nr_mappings,
+            roots.mapping_root,
+            details_root
+        ));
+    }
+
+    let answer = match answers.ask(report, "roots", &question, &default.to_string())? {
+        Some(answer) => answer,
+        None => return Ok(&found_roots[0]),
+    };
+    let index = answer
+        .parse::<usize>()
+        .ok()
+        .filter(|index| *index < ranked.len())
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid answer '{}' to 'roots', expected an index below {}",
+                answer,
+                ranked.len()
+            )
+        })?;
+    Ok(&found_roots[ranked[index]])
+}
+
+// Rebuilds the superblock from the given roots, inferring what hasn't been
+// overridden if asked to.
+fn rebuild_with(
+    roots: &FoundRoots,
+    ref_sb: Option<Superblock>,
+    opts: &SuperblockOverrides,
+    inference: Option<&SuperblockInference>,
+    report: &Report,
+) -> Result<ThinSuperblock> {
+    let opts = match inference {
+        Some(inference) => infer_overrides(roots, ref_sb.as_ref(), opts, inference, report)?,
+        None => *opts,
+    };
+    rebuild_superblock(roots, ref_sb, &opts)
+}
+
+/// As read_or_rebuild_superblock, but the fields of a lost superblock that
+/// weren't overridden may be inferred.  If the index of a candidate from
+/// scan_roots is given, the superblock is always rebuilt from those roots.
+pub fn read_or_infer_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    loc: u64,
+    opts: &SuperblockOverrides,
+    inference: Option<&SuperblockInference>,
+    roots: Option<usize>,
+) -> Result<ThinSuperblock> {
+    let mut found_roots = find_roots(engine.clone(), report.clone())?;
+
+    if let Some(index) = roots {
+        rank_roots(&mut found_roots);
+        let chosen = found_roots.get(index).ok_or_else(|| {
+            anyhow!(
+                "there is no candidate root {}, only {} were found",
+                index,
+                found_roots.len()
+            )
+        })?;
+        let ref_sb = read_superblock(engine.as_ref(), loc).ok();
+        return rebuild_with(chosen, ref_sb, opts, inference, &report);
+    }
 
     read_superblock(engine.as_ref(), loc)
         .and_then(|sb| is_superblock_consistent_(sb, &found_roots))
@@ -974,7 +1307,8 @@ pub fn read_or_rebuild_superblock(
                 let ref_sb = e
                     .downcast_ref::<SuperblockError>()
                     .and_then(|err| err.failed_sb.clone());
-                rebuild_superblock(&found_roots[0], ref_sb, opts)
+                let chosen = choose_roots(&found_roots, inference, &report)?;
+                rebuild_with(chosen, ref_sb, opts, inference, &report)
             },
             |sb| Ok(ThinSuperblock::OnDisk(sb)),
         )
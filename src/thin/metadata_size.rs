@@ -3,8 +3,10 @@ use anyhow::{anyhow, Result};
 use crate::io_engine::BLOCK_SIZE;
 use crate::math::div_up;
 use crate::pdata::btree::calc_max_entries;
+use crate::pdata::space_map::common::{IndexEntry, ENTRIES_PER_BITMAP};
 use crate::pdata::space_map::metadata::MAX_METADATA_BLOCKS;
 use crate::thin::block_time::BlockTime;
+use crate::thin::device_detail::DeviceDetail;
 
 //------------------------------------------
 
@@ -14,6 +16,11 @@ const MAX_DATA_BLOCK_SIZE: u64 = 1073741824;
 pub struct ThinMetadataSizeOptions {
     pub nr_blocks: u64,
     pub max_thins: u64,
+    /// The expected number of snapshots of each thin
+    pub snapshots_per_thin: u64,
+    /// The percentage of the blocks of a snapshot expected to diverge from
+    /// its origin
+    pub divergence: f64,
 }
 
 pub fn check_data_block_size(block_size: u64) -> Result<()> {
@@ -28,6 +35,32 @@ pub fn check_data_block_size(block_size: u64) -> Result<()> {
     Ok(())
 }
 
+pub fn check_divergence(divergence: f64) -> Result<()> {
+    if !(0.0..=100.0).contains(&divergence) {
+        return Err(anyhow!("divergence must be a percentage between 0 and 100"));
+    }
+    Ok(())
+}
+
+// A snapshot shares the leaves of its origin until either is written to,
+// when the whole leaf holding the block written is copied.  Writes spread
+// over the device soon leave few leaves shared, even if only a small
+// fraction of the blocks diverge, so the leaves are counted rather than
+// the blocks.
+fn nr_diverged_leaves(
+    nr_leaves: u64,
+    entries_per_node: u64,
+    opts: &ThinMetadataSizeOptions,
+) -> u64 {
+    if opts.snapshots_per_thin == 0 || opts.divergence == 0.0 {
+        return 0;
+    }
+
+    let kept = 1.0 - opts.divergence / 100.0;
+    let copied = 1.0 - kept.powf(entries_per_node as f64);
+    (nr_leaves as f64 * opts.snapshots_per_thin as f64 * copied).ceil() as u64
+}
+
 // Returns estimated size in bytes
 pub fn metadata_size(opts: &ThinMetadataSizeOptions) -> Result<u64> {
     // assuming 50% residency on mapping tree leaves
@@ -35,11 +68,55 @@ pub fn metadata_size(opts: &ThinMetadataSizeOptions) -> Result<u64> {
     // number of leaves for data mappings
     let nr_leaves = div_up(opts.nr_blocks, entries_per_node);
 
+    // the leaves copied for snapshots, and the internal nodes above them
+    let nr_copied = nr_diverged_leaves(nr_leaves, entries_per_node, opts);
+    let nr_copied = nr_copied + div_up(nr_copied, calc_max_entries::<u64>() as u64);
+
     // one for the superblock, plus additional roots for each device
-    let mut nr_blocks = 1 + nr_leaves + opts.max_thins;
+    let mut nr_blocks = 1 + nr_leaves + nr_copied + opts.max_thins;
     nr_blocks = std::cmp::min(nr_blocks, MAX_METADATA_BLOCKS as u64);
 
     Ok(nr_blocks * BLOCK_SIZE as u64)
 }
 
 //------------------------------------------
+
+// The number of nodes above the leaves of a set of btrees, each with at
+// least a root.
+fn nr_internal_nodes(nr_leaves: u64, nr_trees: u64) -> u64 {
+    let max_entries = calc_max_entries::<u64>() as u64;
+    let mut total = nr_trees;
+    let mut n = nr_leaves;
+    while n > nr_trees {
+        n = div_up(n, max_entries);
+        total += n;
+    }
+    total
+}
+
+fn nr_space_map_blocks(nr_blocks: u64) -> u64 {
+    let nr_bitmaps = div_up(nr_blocks, ENTRIES_PER_BITMAP as u64);
+    let nr_index_blocks = div_up(nr_bitmaps, calc_max_entries::<IndexEntry>() as u64);
+    // one more for the root of the reference count tree
+    nr_bitmaps + nr_index_blocks + 1
+}
+
+/// Estimates the number of blocks needed to write out metadata given the
+/// number of mapping tree leaves, which are assumed to be full.  The
+/// sharing between devices is expected to be reflected in the leaves
+/// counted.
+pub fn estimate_metadata_blocks(nr_leaves: u64, nr_devs: u64, nr_data_blocks: u64) -> u64 {
+    let mapping_trees = nr_leaves + nr_internal_nodes(nr_leaves, nr_devs);
+    let top_level_leaves = div_up(nr_devs, calc_max_entries::<u64>() as u64);
+    let details_leaves = div_up(nr_devs, calc_max_entries::<DeviceDetail>() as u64);
+    let top_level_trees = top_level_leaves
+        + nr_internal_nodes(top_level_leaves, 1)
+        + details_leaves
+        + nr_internal_nodes(details_leaves, 1);
+
+    // the superblock, plus the metadata space map covering the rest
+    let nr_blocks = mapping_trees + top_level_trees + nr_space_map_blocks(nr_data_blocks) + 1;
+    nr_blocks + nr_space_map_blocks(nr_blocks)
+}
+
+//------------------------------------------
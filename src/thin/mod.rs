@@ -1,22 +1,38 @@
+pub mod accounting;
 pub mod block_time;
 pub mod check;
+pub mod check_history;
+pub mod compare;
+pub mod convert;
 pub mod delta;
 pub mod delta_visitor;
 pub mod device_detail;
 pub mod dump;
+pub mod extents;
 pub mod human_readable_format;
 pub mod ir;
+pub mod layout;
 pub mod ls;
 pub mod metadata;
 pub mod metadata_repair;
 pub mod metadata_size;
+pub mod node_map;
+pub mod provision_report;
+pub mod query;
+pub mod reconcile;
+pub mod renumber;
 pub mod repair;
+pub mod repair_answers;
 pub mod restore;
 pub mod rmap;
+pub mod rmap_index;
 pub mod runs;
 pub mod shrink;
+pub mod sm_report;
+pub mod snap_reservation;
 pub mod superblock;
 pub mod trim;
+pub mod trim_journal;
 pub mod xml;
 
 #[cfg(feature = "devtools")]
@@ -27,3 +43,6 @@ pub mod damage_generator;
 
 #[cfg(feature = "devtools")]
 pub mod stat;
+
+#[cfg(feature = "devtools")]
+pub mod top;
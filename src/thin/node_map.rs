@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Result};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::io_engine::errors::IoErrorClass;
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::space_map::*;
+use crate::pdata::spill_bitset::SpillBitSet;
+use crate::thin::block_time::BlockTime;
+use crate::utils::hashvec::HashVec;
+
+//------------------------------------------
+
+// The upper levels of the mapping trees are walked to build a list of the
+// leaf nodes, so the leaves may then be read in location order.  This is
+// shared by thin_check and the block accounting of thin_ls.
+
+// We know the metadata area is limited to 16G, so u32 is large enough
+// hold block numbers.
+
+#[derive(Debug, Clone)]
+pub(crate) struct InternalNodeInfo {
+    pub(crate) keys: Vec<u64>,
+    pub(crate) children: Vec<u32>,
+}
+
+#[derive(PartialEq)]
+pub(crate) enum NodeType {
+    None,
+    Internal,
+    Leaf,
+    Error,
+}
+
+#[derive(Debug)]
+pub(crate) struct NodeMap {
+    node_type: SpillBitSet,
+    pub(crate) leaf_nodes: SpillBitSet, // FIXME: remove this one
+    pub(crate) nr_leaves: u32,
+    pub(crate) internal_info: HashVec<InternalNodeInfo>,
+
+    // Stores errors of the node itself; errors in children are not included
+    pub(crate) node_errors: HashVec<NodeError>,
+}
+
+impl NodeMap {
+    // The bitsets are spilled to a temporary file if they take more than
+    // core_limit bytes between them.  The node types take two bits a block,
+    // so two thirds of the limit.
+    fn new(nr_blocks: u32, core_limit: Option<u64>) -> io::Result<NodeMap> {
+        Ok(NodeMap {
+            node_type: SpillBitSet::with_limit(
+                (nr_blocks as usize) * 2,
+                core_limit.map(|limit| limit / 3 * 2),
+            )?,
+            leaf_nodes: SpillBitSet::with_limit(
+                nr_blocks as usize,
+                core_limit.map(|limit| limit / 3),
+            )?,
+            nr_leaves: 0,
+            internal_info: HashVec::new(),
+            node_errors: HashVec::new(),
+        })
+    }
+
+    pub(crate) fn get_type(&self, blocknr: u32) -> NodeType {
+        // FIXME: query two bits at once
+        let lsb = self.node_type.contains(blocknr as usize * 2);
+        let msb = self.node_type.contains(blocknr as usize * 2 + 1);
+        if !lsb && msb {
+            NodeType::Error
+        } else if lsb && !msb {
+            NodeType::Leaf
+        } else if lsb && msb {
+            NodeType::Internal
+        } else {
+            NodeType::None
+        }
+    }
+
+    fn set_type_(&mut self, blocknr: u32, t: NodeType) {
+        match t {
+            NodeType::Leaf => {
+                self.node_type.insert(blocknr as usize * 2);
+                self.leaf_nodes.insert(blocknr as usize);
+                self.nr_leaves += 1;
+            }
+            NodeType::Internal => {
+                // FIXME: update two bits at once
+                self.node_type.insert(blocknr as usize * 2);
+                self.node_type.insert(blocknr as usize * 2 + 1);
+                if self.leaf_nodes.contains(blocknr as usize) {
+                    self.leaf_nodes.toggle(blocknr as usize);
+                    self.nr_leaves -= 1;
+                }
+            }
+            NodeType::Error => {
+                // FIXME: update two bits at once
+                self.node_type.insert(blocknr as usize * 2 + 1);
+                if self.leaf_nodes.contains(blocknr as usize) {
+                    self.node_type.toggle(blocknr as usize * 2);
+                    self.leaf_nodes.toggle(blocknr as usize);
+                    self.nr_leaves -= 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_internal_node(&mut self, blocknr: u32, info: InternalNodeInfo) -> Result<()> {
+        // Only accepts converting a potential unread leaf
+        let node_type = self.get_type(blocknr);
+        if node_type != NodeType::None && node_type != NodeType::Leaf {
+            return Err(anyhow!("type changed"));
+        }
+        self.internal_info.insert(blocknr, info);
+        self.set_type_(blocknr, NodeType::Internal);
+        Ok(())
+    }
+
+    fn insert_leaf(&mut self, blocknr: u32) -> Result<()> {
+        // Only accepts an unread block
+        if self.get_type(blocknr) != NodeType::None {
+            return Err(anyhow!("type changed"));
+        }
+        self.set_type_(blocknr, NodeType::Leaf);
+        Ok(())
+    }
+
+    pub(crate) fn insert_error(&mut self, blocknr: u32, e: NodeError) -> Result<()> {
+        // Only accepts converting a potential unread leaf
+        let node_type = self.get_type(blocknr);
+        if node_type != NodeType::None && node_type != NodeType::Leaf {
+            return Err(anyhow!("type changed"));
+        }
+        self.node_errors.insert(blocknr, e);
+        self.set_type_(blocknr, NodeType::Error);
+        Ok(())
+    }
+
+    // Returns total number of nodes found
+    pub(crate) fn len(&self) -> u32 {
+        self.internal_info.len() as u32 + self.nr_leaves
+    }
+}
+
+//------------------------------------------
+
+fn is_seen(loc: u32, metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>) -> Result<bool> {
+    let mut sm = metadata_sm.lock().unwrap();
+    sm.inc(loc as u64, 1)?;
+    Ok(sm.get(loc as u64).unwrap_or(0) > 1)
+}
+
+// FIXME: split up this function
+fn read_node_(
+    engine: &dyn IoEngine,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    b: &Block,
+    depth: usize,
+    ignore_non_fatal: bool,
+    nodes: &Mutex<NodeMap>,
+) {
+    // allow underfull nodes in the first pass
+    let node = match check_and_unpack_node::<u64>(b, ignore_non_fatal, true) {
+        Ok(n) => n,
+        Err(e) => {
+            // theoretically never fail
+            let _ = nodes.lock().unwrap().insert_error(b.loc as u32, e);
+            return;
+        }
+    };
+
+    use btree::Node::*;
+    if let Internal { keys, values, .. } = node {
+        let children = values.iter().map(|v| *v as u32).collect::<Vec<u32>>(); // FIXME: slow
+
+        // insert the node info in pre-order fashion to better detect loops in the path
+        let info = InternalNodeInfo { keys, children };
+
+        // The children are filtered, and any leaves inserted, under the one
+        // lock so that walkers meeting in a shared subtree agree on the type
+        // of each node.
+        let values = {
+            let mut nodes = nodes.lock().unwrap();
+            let _ = nodes.insert_internal_node(b.loc as u32, info);
+
+            // filter out previously visited nodes
+            let mut new_values = Vec::with_capacity(values.len());
+            for v in values {
+                if let Ok(seen) = is_seen(v as u32, metadata_sm) {
+                    // Add the unread leaf if now it looks like an internal.
+                    // Add the visited internal if now it looks like a leaf.
+                    let node_type = nodes.get_type(v as u32);
+                    let maybe_internal = depth > 0 && node_type == NodeType::Leaf;
+                    let maybe_leaf = depth == 0 && node_type == NodeType::None;
+                    if !seen || maybe_internal || maybe_leaf {
+                        new_values.push(v);
+                    }
+                }
+            }
+
+            if depth == 0 {
+                for loc in new_values {
+                    let _ = nodes.insert_leaf(loc as u32);
+                }
+                return;
+            }
+            new_values
+        };
+
+        // we could error each child rather than the current node
+        match engine.read_many(&values) {
+            Ok(bs) => {
+                for (i, b) in bs.iter().enumerate() {
+                    match b {
+                        Ok(b) => {
+                            read_node(engine, metadata_sm, b, depth - 1, ignore_non_fatal, nodes)
+                        }
+                        Err(e) => {
+                            // theoretically never fail
+                            let _ = nodes.lock().unwrap().insert_error(
+                                values[i] as u32,
+                                NodeError::IoError(IoErrorClass::of(e)),
+                            );
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                // error every child node
+                let class = IoErrorClass::of(&e);
+                let mut nodes = nodes.lock().unwrap();
+                for loc in values {
+                    // theoretically never fail
+                    let _ = nodes.insert_error(loc as u32, NodeError::IoError(class));
+                }
+            }
+        };
+    }
+}
+
+/// Reads a btree node and all internal btree nodes below it into the
+/// nodes parameter.  No errors are returned, instead the optional
+/// error field of the nodes will be filled in.
+fn read_node(
+    engine: &dyn IoEngine,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    b: &Block,
+    depth: usize,
+    ignore_non_fatal: bool,
+    nodes: &Mutex<NodeMap>,
+) {
+    read_node_(engine, metadata_sm, b, depth, ignore_non_fatal, nodes);
+}
+
+/// Gets the depth of a bottom level mapping tree.  0 means the root is a leaf node.
+// FIXME: what if there's an error on the path to the leftmost leaf?
+fn get_depth(
+    engine: &dyn IoEngine,
+    path: &mut Vec<u64>,
+    root: u64,
+    is_root: bool,
+) -> Result<usize> {
+    use Node::*;
+
+    let b = engine.read(root).map_err(|e| io_err(path, &e))?;
+    let node =
+        check_and_unpack_node::<BlockTime>(&b, true, is_root).map_err(|e| node_err(path, e))?;
+
+    match node {
+        Internal { values, .. } => {
+            // recurse down to the first good leaf
+            let mut last_err = None;
+            for child in values {
+                if path.contains(&child) {
+                    continue; // skip loops
+                }
+
+                path.push(child);
+                match get_depth(engine, path, child, false) {
+                    Ok(n) => return Ok(n + 1),
+                    Err(e) => {
+                        last_err = Some(e);
+                    }
+                }
+                path.pop();
+            }
+            Err(last_err.unwrap_or_else(|| node_err(path, NodeError::NumEntriesTooSmall).into()))
+        }
+        Leaf { .. } => Ok(0),
+    }
+}
+
+fn read_internal_nodes(
+    engine: &dyn IoEngine,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    root: u32,
+    ignore_non_fatal: bool,
+    nodes: &Mutex<NodeMap>,
+) {
+    match is_seen(root, metadata_sm) {
+        Ok(true) | Err(_) => return,
+        _ => {}
+    }
+
+    // FIXME: make get-depth more resilient
+    let mut path = Vec::new();
+    let depth = match get_depth(engine, &mut path, root as u64, true) {
+        Ok(d) => d,
+        Err(e) => {
+            // Record the error if it's the root itself that is damaged
+            match e.downcast_ref::<BTreeError>() {
+                Some(BTreeError::Path(p, e)) if p.is_empty() => {
+                    if let BTreeError::NodeError(e) = e.as_ref() {
+                        let _ = nodes.lock().unwrap().insert_error(root, e.clone());
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+    };
+
+    if depth == 0 {
+        // The root will be skipped if it is a confirmed internal
+        let _ = nodes.lock().unwrap().insert_leaf(root);
+        return;
+    }
+
+    match engine.read(root as u64) {
+        Ok(b) => read_node(engine, metadata_sm, &b, depth - 1, ignore_non_fatal, nodes),
+        Err(e) => {
+            // FIXME: factor out common code
+            let _ = nodes
+                .lock()
+                .unwrap()
+                .insert_error(root, NodeError::IoError(IoErrorClass::of(&e)));
+        }
+    }
+}
+
+/// Reads the internal nodes of the mapping trees with the given roots, and
+/// notes every leaf below them.  Each node is counted in the metadata space
+/// map as it's found.  The bitsets of the node map are kept within
+/// core_limit bytes, if given.
+pub(crate) fn collect_nodes_in_use(
+    engine: &dyn IoEngine,
+    metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+    roots: &[u64],
+    ignore_non_fatal: bool,
+    core_limit: Option<u64>,
+) -> Result<NodeMap> {
+    let nodes = Mutex::new(NodeMap::new(engine.get_nr_blocks() as u32, core_limit)?);
+
+    // The devices are handed out to the walkers one at a time.  A subtree
+    // shared by several devices is only read by the first walker to reach
+    // it, as the metadata space map is shared too.
+    let nr_walkers = engine.suggest_nr_threads().clamp(1, roots.len().max(1));
+    let next_root = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..nr_walkers {
+            s.spawn(|| loop {
+                let i = next_root.fetch_add(1, Ordering::Relaxed);
+                if i >= roots.len() {
+                    break;
+                }
+                read_internal_nodes(
+                    engine,
+                    metadata_sm,
+                    roots[i] as u32,
+                    ignore_non_fatal,
+                    &nodes,
+                );
+            });
+        }
+    });
+
+    Ok(nodes.into_inner().unwrap())
+}
+
+//------------------------------------------
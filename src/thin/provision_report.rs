@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::commands::engine::*;
+use crate::grid_layout::GridLayout;
+use crate::pdata::btree_lookup::btree_lookup;
+use crate::pdata::btree_walker::btree_to_map;
+use crate::pdata::space_map::common::SMRoot;
+use crate::pdata::space_map::disk::DiskSpaceMap;
+use crate::pdata::space_map::SpaceMap;
+use crate::pdata::unpack::unpack;
+use crate::report::Report;
+use crate::thin::block_time::BlockTime;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+// The extent map of a filesystem gives where the data of each file lies on
+// the device the filesystem sits on.  Two formats are read, that of
+// 'filefrag -e', whose physical offsets are in filesystem blocks:
+//
+//     File size of /mnt/a is 8192 (2 blocks of 4096 bytes)
+//      ext:     logical_offset:        physical_offset: length:   expected: flags:
+//        0:        0..       1:      34816..     34817:      2:             last,eof
+//     /mnt/a: 1 extent found
+//
+// and that of 'xfs_bmap', with or without -v, in 512 byte units:
+//
+//     /mnt/a:
+//         0: [0..15]: 96..111
+//         1: [16..31]: hole
+//
+// The output of either may be concatenated for many files.  Extents whose
+// data hasn't been given a place on the device yet, those flagged
+// unknown_loc or delalloc, are skipped.
+
+// The filefrag flags of extents with no meaningful physical offset
+const UNPLACED_FLAGS: [&str; 2] = ["unknown_loc", "delalloc"];
+
+/// Where the data of a file lies on the device, in bytes
+pub struct FileExtents {
+    pub path: String,
+    pub extents: Vec<Range<u64>>,
+}
+
+fn parse_range(line_nr: usize, s: &str) -> Result<(u64, u64)> {
+    let bad_range = || anyhow!("line {}: invalid range '{}'", line_nr, s.trim());
+    let (begin, end) = s.split_once("..").ok_or_else(bad_range)?;
+    let begin = begin.trim().parse::<u64>().map_err(|_| bad_range())?;
+    let end = end.trim().parse::<u64>().map_err(|_| bad_range())?;
+    if end < begin {
+        return Err(bad_range());
+    }
+    Ok((begin, end))
+}
+
+// Parses the filesystem block size out of "... (2 blocks of 4096 bytes)"
+fn parse_fs_block_size(line_nr: usize, line: &str) -> Result<u64> {
+    line.rsplit_once("blocks of ")
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .and_then(|n| n.parse::<u64>().ok())
+        .filter(|n| *n > 0)
+        .ok_or_else(|| anyhow!("line {}: no block size in '{}'", line_nr, line))
+}
+
+pub fn read_extent_map<R: Read>(r: R) -> Result<Vec<FileExtents>> {
+    let mut files: Vec<FileExtents> = Vec::new();
+    let mut unit = 0; // bytes
+
+    for (i, line) in BufReader::new(r).lines().enumerate() {
+        let line = line?;
+        let line_nr = i + 1;
+
+        if let Some(rest) = line.strip_prefix("File size of ") {
+            // filefrag
+            let (path, _) = rest
+                .rsplit_once(" is ")
+                .ok_or_else(|| anyhow!("line {}: no file size in '{}'", line_nr, line))?;
+            unit = parse_fs_block_size(line_nr, &line)?;
+            files.push(FileExtents {
+                path: path.to_string(),
+                extents: Vec::new(),
+            });
+            continue;
+        }
+
+        if !line.starts_with(char::is_whitespace) && line.ends_with(':') {
+            // xfs_bmap
+            unit = 512;
+            files.push(FileExtents {
+                path: line[..line.len() - 1].to_string(),
+                extents: Vec::new(),
+            });
+            continue;
+        }
+
+        // extents are indented, and numbered, so this skips the headers
+        // and the lines counting the extents
+        let trimmed = line.trim_start();
+        if trimmed.len() == line.len() || !trimmed.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+
+        let physical = if let Some((_, rest)) = trimmed.split_once("]:") {
+            match rest.split_whitespace().next() {
+                Some("hole") | Some("delalloc") | None => continue,
+                Some(range) => range,
+            }
+        } else {
+            // the flags follow the last colon
+            let flags = trimmed.rsplit(':').next().unwrap_or("");
+            if flags.trim().split(',').any(|f| UNPLACED_FLAGS.contains(&f)) {
+                continue;
+            }
+            trimmed
+                .split(':')
+                .nth(2)
+                .ok_or_else(|| anyhow!("line {}: no physical offset in '{}'", line_nr, line))?
+        };
+
+        let (begin, end) = parse_range(line_nr, physical)?;
+        let file = files
+            .last_mut()
+            .ok_or_else(|| anyhow!("line {}: extent given before any file", line_nr))?;
+        file.extents.push(begin * unit..(end + 1) * unit);
+    }
+
+    if files.is_empty() {
+        return Err(anyhow!("no files listed in the extent map"));
+    }
+
+    Ok(files)
+}
+
+//------------------------------------------
+
+pub struct ThinProvisionReportOptions<'a> {
+    pub input: &'a Path,
+    pub extents: &'a Path,
+    pub output: Option<&'a Path>,
+    pub thin_id: u64,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+}
+
+#[derive(Default)]
+struct Usage {
+    exclusive: u64,
+    shared: u64,
+    unmapped: u64,
+}
+
+// The thin blocks holding any part of the extents
+fn thin_blocks(extents: &[Range<u64>], block_size: u64) -> BTreeSet<u64> {
+    let mut blocks = BTreeSet::new();
+    for e in extents.iter().filter(|e| e.end > e.start) {
+        blocks.extend(e.start / block_size..(e.end - 1) / block_size + 1);
+    }
+    blocks
+}
+
+// The names of the files are quoted, so none can be taken for the total
+fn quote_path(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Reports how much of each file of a filesystem on a thin device lies on
+/// data blocks the device holds alone, and so would be freed by deleting
+/// the device, and how much on blocks shared with other devices.
+pub fn provision_report(opts: ThinProvisionReportOptions) -> Result<()> {
+    let files = read_extent_map(File::open(opts.extents)?)?;
+
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
+        .exclusive(!opts.engine_opts.use_metadata_snap)
+        .build()?;
+
+    // The metadata snapshot doesn't hold a space map, so the reference
+    // counts are always those of the current superblock.
+    let current = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let sb = if opts.engine_opts.use_metadata_snap {
+        read_superblock_snap(engine.as_ref())?
+    } else {
+        current.clone()
+    };
+
+    let root = btree_lookup::<u64>(engine.as_ref(), sb.mapping_root, opts.thin_id)?
+        .ok_or_else(|| anyhow!("thin device {} doesn't exist", opts.thin_id))?;
+    let mappings = btree_to_map::<BlockTime>(&mut vec![0], engine.clone(), false, root)?;
+    let data_sm = DiskSpaceMap::open_data(engine, unpack::<SMRoot>(&current.data_sm_root)?)?;
+
+    let block_size = sb.data_block_size as u64 * 512;
+    let classify = |blocks: &BTreeSet<u64>| -> Result<Usage> {
+        let mut usage = Usage::default();
+        for b in blocks {
+            match mappings.get(b) {
+                None => usage.unmapped += 1,
+                Some(bt) if data_sm.get(bt.block)? > 1 => usage.shared += 1,
+                Some(_) => usage.exclusive += 1,
+            }
+        }
+        Ok(usage)
+    };
+
+    let mut grid = GridLayout::new_with_size(files.len() + 2, 4);
+    for h in [
+        "EXCLUSIVE_BLOCKS",
+        "SHARED_BLOCKS",
+        "UNMAPPED_BLOCKS",
+        "FILE",
+    ] {
+        grid.field(h.to_string());
+    }
+    grid.new_row();
+
+    let mut push_row = |usage: &Usage, name: &str| {
+        grid.field(usage.exclusive.to_string());
+        grid.field(usage.shared.to_string());
+        grid.field(usage.unmapped.to_string());
+        grid.field(name.to_string());
+        grid.new_row();
+    };
+
+    // A block holding parts of several files is counted for each of them,
+    // but only once in the total.
+    let mut all_blocks = BTreeSet::new();
+    for f in &files {
+        let blocks = thin_blocks(&f.extents, block_size);
+        push_row(&classify(&blocks)?, &quote_path(&f.path));
+        all_blocks.extend(blocks);
+    }
+    push_row(&classify(&all_blocks)?, "total");
+
+    let mut w: Box<dyn Write> = match opts.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    grid.render(&mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::io_engine::*;
+use crate::pdata::btree_lookup::btree_lookup;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+// Point queries descend the trees directly rather than walking them, so a
+// lookup costs a handful of reads whatever the size of the pool.  The
+// metadata is opened read only and without an exclusive lock, so live
+// metadata may be queried through its metadata snapshot.
+
+/// An open handle on thin metadata, for programs that make occasional
+/// queries of individual devices or blocks.
+pub struct MetadataQuery {
+    engine: Box<dyn IoEngine>,
+    sb: Superblock,
+}
+
+impl MetadataQuery {
+    pub fn open(metadata: &Path, use_metadata_snap: bool) -> Result<Self> {
+        let engine = Box::new(SyncIoEngine::new_with(metadata, false, false)?);
+        let sb = if use_metadata_snap {
+            read_superblock_snap(engine.as_ref())?
+        } else {
+            read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?
+        };
+        Ok(MetadataQuery { engine, sb })
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.sb
+    }
+
+    /// Returns the data block and time of a mapping, or None if the virtual
+    /// block, or the device itself, is unmapped.
+    pub fn lookup_mapping(&self, dev_id: u32, virt_block: u64) -> Result<Option<BlockTime>> {
+        let engine = self.engine.as_ref();
+        match btree_lookup::<u64>(engine, self.sb.mapping_root, dev_id as u64)? {
+            Some(root) => btree_lookup::<BlockTime>(engine, root, virt_block),
+            None => Ok(None),
+        }
+    }
+
+    pub fn device_info(&self, dev_id: u32) -> Result<Option<DeviceDetail>> {
+        btree_lookup::<DeviceDetail>(self.engine.as_ref(), self.sb.details_root, dev_id as u64)
+    }
+}
+
+//------------------------------------------
+
+/// Returns the data block a virtual block of a thin device is mapped to.
+pub fn lookup_mapping(metadata: &Path, dev_id: u32, virt_block: u64) -> Result<Option<u64>> {
+    let q = MetadataQuery::open(metadata, false)?;
+    Ok(q.lookup_mapping(dev_id, virt_block)?.map(|bt| bt.block))
+}
+
+/// Returns the details of a thin device, or None if it doesn't exist.
+pub fn device_info(metadata: &Path, dev_id: u32) -> Result<Option<DeviceDetail>> {
+    MetadataQuery::open(metadata, false)?.device_info(dev_id)
+}
+
+//------------------------------------------
@@ -0,0 +1,552 @@
+use anyhow::{anyhow, Context, Result};
+use roaring::RoaringTreemap;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::file_utils::TempFile;
+use crate::io_engine::*;
+use crate::pack::toplevel::{is_pack_file, unpack};
+use crate::pdata::btree::KeyRange;
+use crate::thin::dump::dump_metadata;
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::metadata::*;
+use crate::thin::superblock::*;
+use crate::thin::xml;
+
+//------------------------------------------
+
+// A repair may be reconciled with an earlier backup of the metadata, a
+// thin_dump xml file or a pack.  Whatever validates in the live metadata is
+// kept, and the mappings of the subtrees that were lost are filled in from
+// the backup.  A mapping from the backup is dropped if its data block is
+// beyond the pool, or has since been reused by a mapping that survived.
+
+/// The mappings of each device, indexed by thin id
+pub type DeviceMappings = BTreeMap<u32, Vec<ir::Map>>;
+
+// Collects the mappings of every device, expanding the shared definitions
+struct MappingCollector {
+    defs: BTreeMap<String, Vec<ir::Map>>,
+    devs: DeviceMappings,
+    current: Option<Vec<ir::Map>>,
+    current_def: Option<String>,
+    current_dev: Option<u32>,
+}
+
+impl MappingCollector {
+    fn new() -> Self {
+        MappingCollector {
+            defs: BTreeMap::new(),
+            devs: BTreeMap::new(),
+            current: None,
+            current_def: None,
+            current_dev: None,
+        }
+    }
+
+    fn current(&mut self) -> Result<&mut Vec<ir::Map>> {
+        self.current
+            .as_mut()
+            .ok_or_else(|| anyhow!("mapping outside of a device or definition"))
+    }
+}
+
+impl MetadataVisitor for MappingCollector {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.current_def = Some(name.to_string());
+        self.current = Some(Vec::new());
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        if let (Some(name), Some(maps)) = (self.current_def.take(), self.current.take()) {
+            self.defs.insert(name, maps);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.current_dev = Some(d.dev_id);
+        self.current = Some(Vec::new());
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        if let (Some(dev_id), Some(maps)) = (self.current_dev.take(), self.current.take()) {
+            self.devs.insert(dev_id, maps);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.current()?.push(m.clone());
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        let maps = self
+            .defs
+            .get(name)
+            .ok_or_else(|| anyhow!("reference to undefined shared subtree '{}'", name))?
+            .clone();
+        self.current()?.extend(maps);
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+// The pack is unpacked to a temporary file under $TMPDIR, which is removed
+// however the read ends, and the mappings are streamed from it to the
+// visitor.
+fn read_packed_backup<V: MetadataVisitor>(backup: &Path, v: &mut V) -> Result<()> {
+    let tmp = TempFile::new(&std::env::temp_dir())?;
+    unpack(backup, tmp.path()).context("unable to unpack the backup")?;
+
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(tmp.path(), false)?);
+    let sb = ThinSuperblock::OnDisk(read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?);
+    let md = build_metadata(engine.clone(), &sb)?;
+    dump_metadata(engine, v, &sb, &md)
+}
+
+/// Passes the metadata held in a pack or a thin_dump xml file to a visitor.
+pub fn visit_backup<V: MetadataVisitor>(backup: &Path, v: &mut V) -> Result<()> {
+    if is_pack_file(backup)? {
+        read_packed_backup(backup, v)
+    } else {
+        xml::read(File::open(backup)?, v).context("the backup is neither a pack nor an xml dump")
+    }
+}
+
+/// Reads the mappings of every device in a pack or a thin_dump xml file.
+pub fn read_backup(backup: &Path) -> Result<DeviceMappings> {
+    let mut v = MappingCollector::new();
+    visit_backup(backup, &mut v)?;
+    Ok(v.devs)
+}
+
+//------------------------------------------
+
+// Records the data blocks used by the mappings that survived
+struct DataBlockRecorder {
+    used: RoaringTreemap,
+    nr_data_blocks: u64,
+}
+
+impl MetadataVisitor for DataBlockRecorder {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.nr_data_blocks = sb.nr_data_blocks;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, _d: &ir::Device) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.used.insert_range(m.data_begin..m.data_begin + m.len);
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+// Clips a mapping to a range of thin blocks
+fn clip(m: &ir::Map, kr: &KeyRange) -> Option<ir::Map> {
+    let begin = kr.start.map_or(m.thin_begin, |s| s.max(m.thin_begin));
+    let end = kr
+        .end
+        .map_or(m.thin_begin + m.len, |e| e.min(m.thin_begin + m.len));
+    if begin >= end {
+        return None;
+    }
+
+    Some(ir::Map {
+        thin_begin: begin,
+        data_begin: m.data_begin + (begin - m.thin_begin),
+        time: m.time,
+        len: end - begin,
+    })
+}
+
+// Splits a mapping into the runs whose data blocks are free to use
+fn split_free(m: &ir::Map, used: &RoaringTreemap, nr_data_blocks: u64, out: &mut Vec<ir::Map>) {
+    let mut run: Option<ir::Map> = None;
+    for i in 0..m.len {
+        let data_block = m.data_begin + i;
+        if data_block < nr_data_blocks && !used.contains(data_block) {
+            match run {
+                Some(ref mut r) => r.len += 1,
+                None => {
+                    run = Some(ir::Map {
+                        thin_begin: m.thin_begin + i,
+                        data_begin: data_block,
+                        time: m.time,
+                        len: 1,
+                    })
+                }
+            }
+        } else if let Some(r) = run.take() {
+            out.push(r);
+        }
+    }
+    out.extend(run);
+}
+
+// Collects the mappings of a backup that lie in the ranges lost from each
+// device as the backup is read, so only those are held rather than every
+// mapping of the pool.  It isn't known which devices reference a shared
+// definition until they do, so a definition keeps the mappings touching a
+// loss of any device.
+struct FillCollector<'a> {
+    losses: BTreeMap<u32, &'a [KeyRange]>,
+    defs: BTreeMap<String, Vec<ir::Map>>,
+    devs: DeviceMappings,
+    current_def: Option<(String, Vec<ir::Map>)>,
+    current_dev: Option<(u32, Vec<ir::Map>)>,
+}
+
+impl<'a> FillCollector<'a> {
+    fn new(losses: &'a [LostMappings]) -> Self {
+        FillCollector {
+            losses: losses
+                .iter()
+                .map(|l| (l.thin_id, l.ranges.as_slice()))
+                .collect(),
+            defs: BTreeMap::new(),
+            devs: BTreeMap::new(),
+            current_def: None,
+            current_dev: None,
+        }
+    }
+
+    fn push_clipped(&mut self, m: &ir::Map) {
+        if let Some((thin_id, maps)) = self.current_dev.as_mut() {
+            for kr in self.losses[&*thin_id] {
+                maps.extend(clip(m, kr));
+            }
+        }
+    }
+}
+
+impl<'a> MetadataVisitor for FillCollector<'a> {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.current_def = Some((name.to_string(), Vec::new()));
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        if let Some((name, maps)) = self.current_def.take() {
+            self.defs.insert(name, maps);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        if self.losses.contains_key(&d.dev_id) {
+            self.current_dev = Some((d.dev_id, Vec::new()));
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        if let Some((thin_id, maps)) = self.current_dev.take() {
+            self.devs.insert(thin_id, maps);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        if let Some((_, maps)) = &mut self.current_def {
+            let touches_loss = self
+                .losses
+                .values()
+                .any(|ranges| ranges.iter().any(|kr| clip(m, kr).is_some()));
+            if touches_loss {
+                maps.push(m.clone());
+            }
+        } else {
+            self.push_clipped(m);
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        if self.current_dev.is_none() {
+            return Ok(Visit::Continue);
+        }
+
+        let maps = self
+            .defs
+            .remove(name)
+            .ok_or_else(|| anyhow!("reference to undefined shared subtree '{}'", name))?;
+        for m in &maps {
+            self.push_clipped(m);
+        }
+        self.defs.insert(name.to_string(), maps);
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+/// The mappings taken from a backup to fill in the ranges lost from each
+/// device
+#[derive(Default)]
+pub struct Fills {
+    devs: DeviceMappings,
+    pub nr_filled: u64,
+    pub nr_dropped: u64,
+}
+
+impl Fills {
+    pub fn is_empty(&self) -> bool {
+        self.devs.is_empty()
+    }
+
+    /// The number of devices with mappings filled in
+    pub fn nr_devs(&self) -> usize {
+        self.devs.len()
+    }
+}
+
+/// Works out the mappings of a backup that fill in the losses of a
+/// salvaged repair.
+pub fn plan_fills(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    md: &Metadata,
+    losses: &[LostMappings],
+    backup: &Path,
+) -> Result<Fills> {
+    let mut fills = Fills::default();
+    if losses.is_empty() {
+        return Ok(fills);
+    }
+
+    let mut collector = FillCollector::new(losses);
+    visit_backup(backup, &mut collector)
+        .with_context(|| format!("couldn't read the backup '{}'", backup.display()))?;
+    let backup_devs = collector.devs;
+
+    let mut recorder = DataBlockRecorder {
+        used: RoaringTreemap::new(),
+        nr_data_blocks: 0,
+    };
+    dump_metadata(engine, &mut recorder, sb, md)?;
+
+    for loss in losses {
+        let maps = match backup_devs.get(&loss.thin_id) {
+            Some(maps) => maps,
+            None => continue,
+        };
+
+        let mut dev_fills = Vec::new();
+        for kr in &loss.ranges {
+            for m in maps.iter().filter_map(|m| clip(m, kr)) {
+                let before = dev_fills.len();
+                split_free(&m, &recorder.used, recorder.nr_data_blocks, &mut dev_fills);
+                let kept: u64 = dev_fills[before..].iter().map(|f| f.len).sum();
+                fills.nr_filled += kept;
+                fills.nr_dropped += m.len - kept;
+            }
+        }
+
+        if !dev_fills.is_empty() {
+            dev_fills.sort_by_key(|m| m.thin_begin);
+            fills.devs.insert(loss.thin_id, dev_fills);
+        }
+    }
+
+    Ok(fills)
+}
+
+//------------------------------------------
+
+/// Merges the fills for each device into the mappings passing through to
+/// the inner visitor, keeping them in order of thin block.  The lost ranges
+/// never overlap the mappings that survived.
+pub struct Reconciler<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    fills: Fills,
+    pending: VecDeque<ir::Map>,
+}
+
+impl<'a> Reconciler<'a> {
+    pub fn new(inner: &'a mut dyn MetadataVisitor, fills: Fills) -> Self {
+        Self {
+            inner,
+            fills,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn flush_before(&mut self, thin_block: u64) -> Result<()> {
+        while let Some(m) = self.pending.front() {
+            if m.thin_begin >= thin_block {
+                break;
+            }
+            let m = self.pending.pop_front().unwrap();
+            self.inner.map(&m)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MetadataVisitor for Reconciler<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.inner.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.inner.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        let maps = self.fills.devs.remove(&d.dev_id).unwrap_or_default();
+        let nr_filled: u64 = maps.iter().map(|m| m.len).sum();
+        self.pending = maps.into();
+
+        let d = ir::Device {
+            mapped_blocks: d.mapped_blocks + nr_filled,
+            ..d.clone()
+        };
+        self.inner.device_b(&d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.flush_before(u64::MAX)?;
+        self.inner.device_e()
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.flush_before(m.thin_begin)?;
+        self.inner.map(m)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.inner.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod fill_tests {
+    use super::*;
+
+    fn map(thin_begin: u64, data_begin: u64, len: u64) -> ir::Map {
+        ir::Map {
+            thin_begin,
+            data_begin,
+            time: 0,
+            len,
+        }
+    }
+
+    fn dev(dev_id: u32) -> ir::Device {
+        ir::Device {
+            dev_id,
+            mapped_blocks: 0,
+            transaction: 0,
+            creation_time: 0,
+            snap_time: 0,
+        }
+    }
+
+    fn ranges(maps: &[ir::Map]) -> Vec<(u64, u64, u64)> {
+        maps.iter()
+            .map(|m| (m.thin_begin, m.data_begin, m.len))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_only_the_lost_ranges() -> Result<()> {
+        let losses = vec![LostMappings {
+            thin_id: 1,
+            ranges: vec![KeyRange {
+                start: Some(10),
+                end: Some(20),
+            }],
+        }];
+        let mut c = FillCollector::new(&losses);
+
+        c.def_shared_b("0")?;
+        c.map(&map(0, 100, 15))?;
+        c.map(&map(50, 200, 5))?;
+        c.def_shared_e()?;
+
+        // device 0 lost nothing, so its mappings are skipped
+        c.device_b(&dev(0))?;
+        c.map(&map(0, 300, 30))?;
+        c.device_e()?;
+
+        c.device_b(&dev(1))?;
+        c.ref_shared("0")?;
+        c.map(&map(15, 400, 10))?;
+        c.device_e()?;
+
+        assert_eq!(c.defs["0"].len(), 1);
+        assert!(!c.devs.contains_key(&0));
+        assert_eq!(ranges(&c.devs[&1]), [(10, 110, 5), (15, 400, 5)]);
+        Ok(())
+    }
+}
+
+//------------------------------------------
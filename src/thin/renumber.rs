@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+
+//------------------------------------------
+
+/// A mapping of thin device ids, as written by `thin_dump --renumber-from`.
+/// The file holds one '<original id> <new id>' pair per line.
+#[derive(Clone, Default)]
+pub struct DevIdMap {
+    ids: BTreeMap<u32, u32>,
+}
+
+impl DevIdMap {
+    /// Assigns sequential ids, beginning at first, to the devices in the
+    /// order given.
+    pub fn sequential<I>(dev_ids: I, first: u32) -> Result<DevIdMap>
+    where
+        I: Iterator<Item = u32>,
+    {
+        let mut ids = BTreeMap::new();
+        let mut next = Some(first);
+        for id in dev_ids {
+            let new_id = next.ok_or_else(|| anyhow!("too many devices to renumber"))?;
+            ids.insert(id, new_id);
+            next = new_id.checked_add(1);
+        }
+        Ok(DevIdMap { ids })
+    }
+
+    pub fn get(&self, dev_id: u32) -> Option<u32> {
+        self.ids.get(&dev_id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Returns the map that undoes this one
+    pub fn inverse(&self) -> DevIdMap {
+        DevIdMap {
+            ids: self.ids.iter().map(|(from, to)| (*to, *from)).collect(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("couldn't create id map '{}'", path.display()))?;
+        let mut w = BufWriter::new(file);
+        for (from, to) in &self.ids {
+            writeln!(w, "{} {}", from, to)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<DevIdMap> {
+        let file = File::open(path)
+            .with_context(|| format!("couldn't open id map '{}'", path.display()))?;
+
+        let mut ids = BTreeMap::new();
+        let mut targets = BTreeSet::new();
+        for (nr, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let bad_line = || anyhow!("badly formed id map at line {}", nr + 1);
+            let mut fields = line.split_whitespace();
+            let from = fields
+                .next()
+                .and_then(|s| s.parse::<u32>().ok())
+                .ok_or_else(bad_line)?;
+            let to = fields
+                .next()
+                .and_then(|s| s.parse::<u32>().ok())
+                .ok_or_else(bad_line)?;
+            if fields.next().is_some() {
+                return Err(bad_line());
+            }
+
+            if ids.insert(from, to).is_some() || !targets.insert(to) {
+                return Err(anyhow!("duplicate device id in id map at line {}", nr + 1));
+            }
+        }
+
+        Ok(DevIdMap { ids })
+    }
+}
+
+//------------------------------------------
+
+/// Rewrites the ids of the devices passing through to the inner visitor.
+/// Every device must be present in the map.
+pub struct RenumberVisitor<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    ids: DevIdMap,
+}
+
+impl<'a> RenumberVisitor<'a> {
+    pub fn new(inner: &'a mut dyn MetadataVisitor, ids: DevIdMap) -> Self {
+        Self { inner, ids }
+    }
+}
+
+impl<'a> MetadataVisitor for RenumberVisitor<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.inner.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.inner.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        let dev_id = self
+            .ids
+            .get(d.dev_id)
+            .ok_or_else(|| anyhow!("device {} is missing from the id map", d.dev_id))?;
+        let d = ir::Device {
+            dev_id,
+            ..d.clone()
+        };
+        self.inner.device_b(&d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.inner.device_e()
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.inner.map(m)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.inner.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+//------------------------------------------
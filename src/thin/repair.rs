@@ -1,67 +1,665 @@
-use anyhow::Result;
-use std::path::Path;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::BufWriter;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::commands::engine::*;
+use crate::file_utils;
 use crate::io_engine::*;
+use crate::math::div_up;
+use crate::pack::toplevel::pack;
+use crate::pdata::btree::calc_max_entries;
+use crate::pdata::space_map::allocated_blocks::allocated_blocks;
+use crate::pdata::space_map::common::SMRoot;
 use crate::pdata::space_map::metadata::*;
+use crate::pdata::unpack::unpack;
 use crate::report::*;
+use crate::thin::block_time::BlockTime;
 use crate::thin::dump::*;
+use crate::thin::ir::{self, MetadataVisitor, Visit};
 use crate::thin::metadata::*;
 use crate::thin::metadata_repair::*;
+use crate::thin::metadata_size::estimate_metadata_blocks;
+use crate::thin::reconcile::*;
 use crate::thin::restore::*;
+use crate::thin::sm_report::*;
 use crate::thin::superblock::*;
+use crate::thin::xml;
 use crate::write_batcher::*;
 
 //------------------------------------------
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepairFormat {
+    /// Binary metadata, written to a device or file
+    Metadata,
+    /// An xml dump, which may be inspected before restoring it
+    XML,
+}
+
+impl FromStr for RepairFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "metadata" => Ok(RepairFormat::Metadata),
+            "xml" => Ok(RepairFormat::XML),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
 pub struct ThinRepairOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
+    pub format: RepairFormat,
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
     pub overrides: SuperblockOverrides,
+    pub inference: SuperblockInference,
+    pub salvage: bool,
+    /// Fill in the mappings lost from the input from an earlier backup
+    pub reconcile: Option<&'a Path>,
+    pub backup: Option<PathBuf>,
+    pub roots: Option<usize>,
+    /// Repair the input in place, through the given journal
+    pub journal: Option<PathBuf>,
+    /// Write a summary of the changes to the data space map
+    pub sm_report: Option<&'a Path>,
+    pub sm_report_format: SmReportFormat,
+    /// Only warn if the output looks too small
+    pub ignore_space_estimate: bool,
+    /// Lay the repaired metadata out the same way on any machine
+    pub deterministic: bool,
 }
 
 struct Context {
     report: Arc<Report>,
     engine_in: Arc<dyn IoEngine + Send + Sync>,
-    engine_out: Arc<dyn IoEngine + Send + Sync>,
 }
 
 fn new_context(opts: &ThinRepairOptions) -> Result<Context> {
     let engine_in = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
-    let engine_out = EngineBuilder::new(opts.output, &opts.engine_opts)
-        .write(true)
-        .build()?;
 
     Ok(Context {
         report: opts.report.clone(),
         engine_in,
-        engine_out,
     })
 }
 
 //------------------------------------------
 
+// Files kept for an input go beside an input file, or in the current
+// directory for a device, named after the input.
+fn beside_input(input: &Path, suffix: &str) -> PathBuf {
+    let name = format!(
+        "{}{}",
+        input
+            .file_name()
+            .map_or("metadata".into(), |n| n.to_string_lossy()),
+        suffix
+    );
+
+    match input.parent() {
+        Some(dir) if matches!(file_utils::is_file(input), Ok(true)) => dir.join(name),
+        _ => PathBuf::from(name),
+    }
+}
+
+/// Returns a new file in the given directory to back the input up to.  The
+/// name holds the input's name, the time to the nanosecond and the process
+/// id, so backups from earlier or concurrent repairs are never reused.
+pub fn backup_path_in(dir: &Path, input: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = input
+        .file_name()
+        .map_or("metadata".into(), |n| n.to_string_lossy());
+    dir.join(format!(
+        "{}-{}.{:09}-{}.pack",
+        name,
+        now.as_secs(),
+        now.subsec_nanos(),
+        std::process::id()
+    ))
+}
+
+/// Returns the journal of an in-place repair.  The name is fixed, so an
+/// interrupted repair finds its journal again.
+pub fn default_journal_path(input: &Path) -> PathBuf {
+    beside_input(input, ".journal")
+}
+
+// The damaged metadata is packed before anything is written, so a failed
+// repair never loses the original.  The backup was asked for, so the repair
+// stops rather than going ahead without it.
+fn backup_input(input: &Path, backup: &Path, report: &Report) -> Result<()> {
+    if backup.exists() {
+        return Err(anyhow!(
+            "the backup file '{}' already exists",
+            backup.display()
+        ));
+    }
+
+    pack(input, backup).map_err(|e| {
+        anyhow!(
+            "couldn't back up the input to '{}': {}",
+            backup.display(),
+            e
+        )
+    })?;
+    report.info(&format!("backed up the input to {}", backup.display()));
+    Ok(())
+}
+
+fn report_losses(report: &Report, losses: &[LostMappings]) {
+    if losses.is_empty() {
+        report.info("all the mappings were salvaged");
+        return;
+    }
+
+    for l in losses {
+        let ranges: Vec<String> = l.ranges.iter().map(|kr| kr.to_string()).collect();
+        report.warning(&format!(
+            "thin device {}: lost the mappings of virtual blocks {}",
+            l.thin_id,
+            ranges.join(", ")
+        ));
+    }
+}
+
+fn report_fills(report: &Report, fills: &Fills) {
+    report.info(&format!(
+        "filled in the mappings of {} blocks from the backup",
+        fills.nr_filled
+    ));
+    if fills.nr_dropped > 0 {
+        report.warning(&format!(
+            "dropped the mappings of {} blocks from the backup, as their data blocks are in use",
+            fills.nr_dropped
+        ));
+    }
+}
+
+//------------------------------------------
+
+// An in-place repair is first restored to a sparse image of the device, the
+// journal, which is committed by renaming it into place once synced.  Only
+// then is the device overwritten, so a crash leaves either the device
+// untouched, or a committed journal to replay.
+
+fn uncommitted_path(journal: &Path) -> PathBuf {
+    let mut name = journal.as_os_str().to_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+fn sync_path(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+// Counts the mappings passing through to the inner visitor, including those
+// of the shared definitions referenced.
+struct MappingCounter<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    counted: Arc<AtomicU64>,
+    defs: BTreeMap<String, u64>,
+    current_def: Option<(String, u64)>,
+}
+
+impl<'a> MappingCounter<'a> {
+    fn new(inner: &'a mut dyn MetadataVisitor, counted: Arc<AtomicU64>) -> Self {
+        Self {
+            inner,
+            counted,
+            defs: BTreeMap::new(),
+            current_def: None,
+        }
+    }
+}
+
+impl<'a> MetadataVisitor for MappingCounter<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.current_def = Some((name.to_string(), 0));
+        self.inner.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        if let Some((name, len)) = self.current_def.take() {
+            self.defs.insert(name, len);
+        }
+        self.inner.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.inner.device_b(d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.inner.device_e()
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        match &mut self.current_def {
+            Some((_, len)) => *len += m.len,
+            None => {
+                self.counted.fetch_add(m.len, Ordering::Relaxed);
+            }
+        }
+        self.inner.map(m)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        if let Some(len) = self.defs.get(name) {
+            self.counted.fetch_add(*len, Ordering::Relaxed);
+        }
+        self.inner.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+fn dump_reconciled(
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    out: &mut dyn MetadataVisitor,
+    sb: &ThinSuperblock,
+    md: &Metadata,
+    fills: Fills,
+) -> Result<()> {
+    if fills.is_empty() {
+        return dump_metadata(engine_in, out, sb, md);
+    }
+
+    let mut out = Reconciler::new(out, fills);
+    dump_metadata(engine_in, &mut out, sb, md)
+}
+
+fn restore_to(
+    engine_out: Arc<dyn IoEngine + Send + Sync>,
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    sb: &ThinSuperblock,
+    md: &Metadata,
+    fills: Fills,
+    opts: &ThinRepairOptions,
+) -> Result<()> {
+    let nr_mappings = md.devs.iter().map(|d| d.detail.mapped_blocks).sum::<u64>() + fills.nr_filled;
+
+    let sm = core_metadata_sm(engine_out.get_nr_blocks(), u32::MAX);
+    let batch_size = engine_out.get_batch_size();
+    let mut w = WriteBatcher::new(engine_out, sm.clone(), batch_size)
+        .with_sync_policy(opts.engine_opts.sync_policy)
+        .with_deterministic_layout(opts.deterministic);
+    let mut restorer = Restorer::new(&mut w, report.clone());
+
+    report.set_title("Writing the repaired metadata");
+    let written = Arc::new(AtomicU64::new(0));
+    let monitor = {
+        let written = written.clone();
+        ProgressMonitor::new(report, nr_mappings, move || written.load(Ordering::Relaxed))
+    };
+
+    let mut counter = MappingCounter::new(&mut restorer, written);
+    let r = dump_reconciled(engine_in, &mut counter, sb, md, fills);
+    monitor.stop();
+    r
+}
+
+fn get_nr_data_blocks(sb: &ThinSuperblock) -> Result<u64> {
+    match sb {
+        ThinSuperblock::OnDisk(sb) => Ok(unpack::<SMRoot>(&sb.data_sm_root)?.nr_blocks),
+        ThinSuperblock::InCore(sb) => Ok(sb.nr_data_blocks),
+    }
+}
+
+// The leaves are counted as they are in the input, where the sharing of
+// snapshots is seen, along with those needed for the mappings filled in.
+fn estimate_repaired_blocks(sb: &ThinSuperblock, md: &Metadata, fills: &Fills) -> Result<u64> {
+    let nr_leaves = |m: &Mapping| {
+        m.entries
+            .iter()
+            .filter(|e| matches!(e, Entry::Leaf(_)))
+            .count() as u64
+    };
+    let nr_input_leaves = md.defs.iter().map(|d| nr_leaves(&d.map)).sum::<u64>()
+        + md.devs.iter().map(|d| nr_leaves(&d.map)).sum::<u64>();
+    let nr_filled_leaves =
+        div_up(fills.nr_filled, calc_max_entries::<BlockTime>() as u64) + fills.nr_devs() as u64;
+
+    Ok(estimate_metadata_blocks(
+        nr_input_leaves + nr_filled_leaves,
+        md.devs.len() as u64,
+        get_nr_data_blocks(sb)?,
+    ))
+}
+
+// The estimate counts the leaves of the input as they are, and they're
+// packed at least as full when written, so an output the estimate doesn't
+// fit is probably, though not certainly, too small.
+fn check_output_space(
+    engine_out: &dyn IoEngine,
+    nr_needed: u64,
+    opts: &ThinRepairOptions,
+) -> Result<()> {
+    let nr_blocks = engine_out.get_nr_blocks();
+    if nr_needed > nr_blocks {
+        let msg = format!(
+            "the output is too small, the repaired metadata needs about {} blocks, but the output only has {}",
+            nr_needed, nr_blocks
+        );
+        if !opts.ignore_space_estimate {
+            return Err(anyhow!(
+                "{}, pass --ignore-space-estimate to try anyway",
+                msg
+            ));
+        }
+        opts.report.warning(&msg);
+    }
+    Ok(())
+}
+
+// The journal holds the repaired metadata, followed by a copy of the
+// superblock of the input as it was when the journal was written.  The copy
+// ties the journal to its input, so it's never replayed onto a device that
+// has since changed, or onto another one.
+fn write_journal(
+    ctx: Context,
+    opts: &ThinRepairOptions,
+    journal: &Path,
+    sb: &ThinSuperblock,
+    md: &Metadata,
+    fills: Fills,
+) -> Result<Option<SmAdjustments>> {
+    let tmp = uncommitted_path(journal);
+    let nr_blocks = ctx.engine_in.get_nr_blocks();
+    let input_sb = ctx.engine_in.read(SUPERBLOCK_LOCATION)?;
+
+    let r = (|| -> Result<Option<SmAdjustments>> {
+        file_utils::create_sized_file(&tmp, nr_blocks * BLOCK_SIZE as u64)?;
+        let engine_out = EngineBuilder::new(&tmp, &opts.engine_opts)
+            .write(true)
+            .build()?;
+        restore_to(
+            engine_out.clone(),
+            ctx.engine_in.clone(),
+            ctx.report,
+            sb,
+            md,
+            fills,
+            opts,
+        )?;
+        let adj = compare_if_reported(opts, ctx.engine_in, sb, engine_out)?;
+
+        OpenOptions::new()
+            .write(true)
+            .open(&tmp)?
+            .write_all_at(input_sb.get_data(), nr_blocks * BLOCK_SIZE as u64)?;
+        sync_path(&tmp)?;
+        Ok(adj)
+    })();
+
+    // a journal that wasn't committed is of no use to a later run
+    let adj = r.map_err(|e| {
+        let _ = std::fs::remove_file(&tmp);
+        e
+    })?;
+
+    std::fs::rename(&tmp, journal)?;
+    if let Some(dir) = journal.parent().filter(|d| !d.as_os_str().is_empty()) {
+        sync_path(dir)?;
+    }
+    Ok(adj)
+}
+
+// The superblock of the device is only written at the end of a replay, so
+// it's either the one the journal was written against, or that of the
+// journal if an earlier replay got as far as writing it.
+fn check_journal_matches(
+    dev: &Path,
+    src: &dyn IoEngine,
+    dest: &dyn IoEngine,
+    nr_metadata_blocks: u64,
+) -> Result<()> {
+    let nr_blocks = dest.get_nr_blocks();
+    if src.get_nr_blocks() != nr_blocks + 1 || nr_metadata_blocks != nr_blocks {
+        return Err(anyhow!(
+            "the journal was written for a device of another size than '{}'",
+            dev.display()
+        ));
+    }
+
+    let current = dest.read(SUPERBLOCK_LOCATION)?;
+    let recorded = src.read(nr_blocks)?;
+    let repaired = src.read(SUPERBLOCK_LOCATION)?;
+    if current.get_data() != recorded.get_data() && current.get_data() != repaired.get_data() {
+        return Err(anyhow!(
+            "the journal doesn't match '{}', its superblock has changed since the journal was written",
+            dev.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Copies the repaired metadata held in a committed journal to the device,
+/// then removes the journal.  The superblock is written last.
+pub fn replay_journal(
+    dev: &Path,
+    journal: &Path,
+    engine_opts: &EngineOptions,
+    report: &Report,
+) -> Result<()> {
+    let src = EngineBuilder::new(journal, engine_opts)
+        .read_only(true)
+        .build()?;
+    let sb = read_superblock(src.as_ref(), SUPERBLOCK_LOCATION)?;
+    let root = unpack::<SMRoot>(&sb.metadata_sm_root)?;
+    let mut blocks = allocated_blocks(src.clone(), root.bitmap_root, root.nr_blocks)?;
+    blocks.remove(SUPERBLOCK_LOCATION as u32);
+
+    let dest_engine = EngineBuilder::new(dev, engine_opts).write(true).build()?;
+    check_journal_matches(dev, src.as_ref(), dest_engine.as_ref(), root.nr_blocks)?;
+
+    const BATCH_SIZE: usize = 1024;
+    let blocks: Vec<u64> = blocks.iter().map(|b| b as u64).collect();
+    for batch in blocks.chunks(BATCH_SIZE) {
+        let bs = src
+            .read_many(batch)?
+            .into_iter()
+            .collect::<std::io::Result<Vec<_>>>()?;
+        for r in dest_engine.write_many(&bs)? {
+            r?;
+        }
+    }
+    dest_engine.sync()?;
+
+    let b = src.read(SUPERBLOCK_LOCATION)?;
+    dest_engine.write(&b)?;
+    dest_engine.sync()?;
+
+    std::fs::remove_file(journal)?;
+    report.info(&format!(
+        "wrote {} repaired blocks to {}",
+        blocks.len() + 1,
+        dev.display()
+    ));
+    Ok(())
+}
+
+// Comparing the space maps reads every count of both, so it's only done
+// when a summary was asked for.
+fn compare_if_reported(
+    opts: &ThinRepairOptions,
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    engine_out: Arc<dyn IoEngine + Send + Sync>,
+) -> Result<Option<SmAdjustments>> {
+    match opts.sm_report {
+        Some(_) => Ok(Some(compare_data_sms(engine_in, sb, engine_out)?)),
+        None => Ok(None),
+    }
+}
+
+fn finish_adjustments(opts: &ThinRepairOptions, adj: Option<SmAdjustments>) -> Result<()> {
+    if let (Some(adj), Some(path)) = (adj, opts.sm_report) {
+        report_adjustments(&opts.report, &adj);
+        adj.write(path, opts.sm_report_format)?;
+    }
+    Ok(())
+}
+
+/// Repairs the metadata of the input.
+///
+/// The mappings are streamed from the leaves of the input into the
+/// builder as they're written, so what's held in core is a list of the
+/// leaf blocks of each mapping tree, along with the shared runs found in
+/// them: a few bytes per leaf, rather than per mapping.  A --reconcile
+/// backup is read the same way, keeping only the mappings of the ranges
+/// that were lost.
 pub fn repair(opts: ThinRepairOptions) -> Result<()> {
+    if opts.sm_report.is_some() && opts.format == RepairFormat::XML {
+        return Err(anyhow!(
+            "an xml dump has no space maps, so there are no adjustments to report"
+        ));
+    }
+
+    if let Some(journal) = &opts.journal {
+        if journal.exists() {
+            opts.report
+                .warning("replaying the journal of an interrupted in-place repair");
+            return replay_journal(opts.input, journal, &opts.engine_opts, &opts.report);
+        }
+    }
+
+    if let Some(backup) = &opts.backup {
+        backup_input(opts.input, backup, &opts.report)?;
+    }
+
     let ctx = new_context(&opts)?;
 
-    let sb = read_or_rebuild_superblock(
+    let sb = read_or_infer_superblock(
         ctx.engine_in.clone(),
         ctx.report.clone(),
         SUPERBLOCK_LOCATION,
         &opts.overrides,
+        Some(&opts.inference),
+        opts.roots,
     )?;
-    let md = build_metadata(ctx.engine_in.clone(), &sb)?;
-    let md = optimise_metadata(md)?;
+    let (md, fills) = if let Some(backup) = opts.reconcile {
+        // The fills are merged in by thin block, so the devices are kept
+        // apart rather than optimised into shared definitions.
+        let (md, losses) = build_metadata_salvage(ctx.engine_in.clone(), &sb)?;
+        report_losses(&ctx.report, &losses);
+        let fills = plan_fills(ctx.engine_in.clone(), &sb, &md, &losses, backup)?;
+        report_fills(&ctx.report, &fills);
+        (md, fills)
+    } else if opts.salvage {
+        let (md, losses) = build_metadata_salvage(ctx.engine_in.clone(), &sb)?;
+        report_losses(&ctx.report, &losses);
+        (optimise_metadata(md)?, Fills::default())
+    } else {
+        let md = build_metadata(ctx.engine_in.clone(), &sb)?;
+        (optimise_metadata(md)?, Fills::default())
+    };
+
+    let nr_needed = estimate_repaired_blocks(&sb, &md, &fills)?;
+    ctx.report.info(&format!(
+        "the repaired metadata needs about {} blocks",
+        nr_needed
+    ));
+
+    if let Some(journal) = &opts.journal {
+        // the journal is the size of the input it's copied back to
+        check_output_space(ctx.engine_in.as_ref(), nr_needed, &opts)?;
+
+        // the input is closed before it's overwritten
+        let adj = write_journal(ctx, &opts, journal, &sb, &md, fills)?;
+        replay_journal(opts.input, journal, &opts.engine_opts, &opts.report)?;
+        return finish_adjustments(&opts, adj);
+    }
+
+    match opts.format {
+        RepairFormat::Metadata => {
+            let engine_out = EngineBuilder::new(opts.output, &opts.engine_opts)
+                .write(true)
+                .build()?;
+            check_output_space(engine_out.as_ref(), nr_needed, &opts)?;
+            restore_to(
+                engine_out.clone(),
+                ctx.engine_in.clone(),
+                ctx.report,
+                &sb,
+                &md,
+                fills,
+                &opts,
+            )?;
+            let adj = compare_if_reported(&opts, ctx.engine_in, &sb, engine_out)?;
+            finish_adjustments(&opts, adj)
+        }
+        RepairFormat::XML => {
+            let f = File::create(opts.output)?;
+            let mut out = xml::XmlWriter::new(BufWriter::new(f));
+
+            dump_reconciled(ctx.engine_in, &mut out, &sb, &md, fills)
+        }
+    }
+}
+
+//------------------------------------------
+
+pub struct ThinScanRootsOptions<'a> {
+    pub input: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+}
+
+pub fn scan(opts: ThinScanRootsOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
+        .build()?;
+    let candidates = scan_roots(engine, opts.report.clone())?;
 
-    let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
-    let batch_size = ctx.engine_out.get_batch_size();
-    let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size);
-    let mut restorer = Restorer::new(&mut w, ctx.report);
+    opts.report.to_stdout(
+        "# index transaction time devices mappings mapping_root details_root superblocks",
+    );
+    for (i, c) in candidates.iter().enumerate() {
+        let details_root = c.details_root.map_or("-".to_string(), |b| b.to_string());
+        let superblocks: Vec<String> = c.superblocks.iter().map(|b| b.to_string()).collect();
+        opts.report.to_stdout(&format!(
+            "{} {} {} {} {} {} {} {}",
+            i,
+            c.transaction_id,
+            c.time,
+            c.nr_devices,
+            c.nr_mappings,
+            c.mapping_root,
+            details_root,
+            if superblocks.is_empty() {
+                "-".to_string()
+            } else {
+                superblocks.join(",")
+            }
+        ));
+    }
 
-    dump_metadata(ctx.engine_in, &mut restorer, &sb, &md)
+    Ok(())
 }
 
 //------------------------------------------
@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::report::Report;
+
+//------------------------------------------
+
+// Some decisions of a repair can't be made with certainty from the damaged
+// metadata, such as which of several generations of the trees to repair
+// from.  Without answers the repair goes with its best guess, as it always
+// has.  Each question has a fixed name, so the answers given interactively
+// can be written to a file and replayed by a script.
+
+/// Where the answers to the questions asked by a repair come from
+#[derive(Clone, Debug, Default)]
+pub enum RepairAnswers {
+    /// Make the decisions without asking
+    #[default]
+    Automatic,
+    /// Ask the operator at a terminal
+    Interactive,
+    /// Take the answers, by question name, from a file
+    Scripted(BTreeMap<String, String>),
+}
+
+impl RepairAnswers {
+    /// Reads a file of answers.  Each line holds the name of a question and
+    /// its answer, separated by '='.  Blank lines and lines starting with '#'
+    /// are skipped.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut answers = BTreeMap::new();
+        let file = File::open(path)?;
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, answer) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {}: expected a question and its answer", i + 1))?;
+            answers.insert(name.trim().to_string(), answer.trim().to_string());
+        }
+
+        Ok(RepairAnswers::Scripted(answers))
+    }
+
+    pub fn is_automatic(&self) -> bool {
+        matches!(self, RepairAnswers::Automatic)
+    }
+
+    /// Returns the answer to the named question, or None if the decision is
+    /// to be made without asking.  An empty answer at the terminal takes the
+    /// default.
+    pub fn ask(
+        &self,
+        report: &Report,
+        name: &str,
+        question: &str,
+        default: &str,
+    ) -> Result<Option<String>> {
+        match self {
+            RepairAnswers::Automatic => Ok(None),
+            RepairAnswers::Interactive => {
+                let input =
+                    report.get_prompt_input(&format!("{}\n{} [{}]: ", question, name, default))?;
+                let input = input.trim();
+                if input.is_empty() {
+                    Ok(Some(default.to_string()))
+                } else {
+                    Ok(Some(input.to_string()))
+                }
+            }
+            RepairAnswers::Scripted(answers) => answers
+                .get(name)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| anyhow!("the answers file has no answer to '{}'", name)),
+        }
+    }
+}
+
+pub fn parse_yes_no(name: &str, answer: &str) -> Result<bool> {
+    match answer.to_lowercase().as_str() {
+        "y" | "yes" => Ok(true),
+        "n" | "no" => Ok(false),
+        _ => Err(anyhow!(
+            "invalid answer '{}' to '{}', expected y or n",
+            answer,
+            name
+        )),
+    }
+}
+
+//------------------------------------------
@@ -1,12 +1,14 @@
 use anyhow::{anyhow, Result};
 
 use std::collections::BTreeMap;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::ops::Deref;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 use crate::commands::engine::*;
+use crate::file_utils;
 use crate::io_engine::*;
 use crate::pdata::btree_builder::*;
 use crate::pdata::space_map::common::pack_root;
@@ -16,8 +18,10 @@ use crate::pdata::space_map::*;
 use crate::report::*;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
+use crate::thin::extents;
 use crate::thin::ir::{self, MetadataVisitor, Visit};
 use crate::thin::metadata_repair::{Override, SuperblockOverrides};
+use crate::thin::renumber::*;
 use crate::thin::superblock::{self, *};
 use crate::thin::xml;
 use crate::write_batcher::*;
@@ -84,6 +88,9 @@ pub struct Restorer<'a> {
     data_sm: Option<Arc<Mutex<dyn SpaceMap>>>,
     in_section: Section,
     overrides: SuperblockOverrides,
+
+    nr_mappings: u64,
+    stats: Option<RestoreStats>,
 }
 
 impl<'a> Restorer<'a> {
@@ -99,6 +106,8 @@ impl<'a> Restorer<'a> {
             data_sm: None,
             in_section: Section::None,
             overrides: SuperblockOverrides::default(),
+            nr_mappings: 0,
+            stats: None,
         }
     }
 
@@ -118,9 +127,16 @@ impl<'a> Restorer<'a> {
             data_sm: None,
             in_section: Section::None,
             overrides: *overrides,
+            nr_mappings: 0,
+            stats: None,
         }
     }
 
+    /// What was written, once the restore is complete
+    pub fn stats(&self) -> Option<RestoreStats> {
+        self.stats
+    }
+
     fn begin_section(&mut self, section: MappedSection) -> Result<Visit> {
         if let Some((outer, _)) = self.current_map.as_ref() {
             let msg = format!(
@@ -220,6 +236,13 @@ impl<'a> Restorer<'a> {
         write_superblock(self.w.engine.as_ref(), SUPERBLOCK_LOCATION, &sb)?;
         self.in_section = Section::Finalized;
 
+        self.stats = Some(RestoreStats {
+            nr_devices: self.devices.len() as u64,
+            nr_mappings: self.nr_mappings,
+            nr_metadata_blocks: metadata_sm.nr_blocks,
+            metadata_blocks_used: metadata_sm.nr_allocated,
+        });
+
         Ok(())
     }
 }
@@ -301,6 +324,7 @@ impl<'a> MetadataVisitor for Restorer<'a> {
 
     fn map(&mut self, m: &ir::Map) -> Result<Visit> {
         if let Some((_, builder)) = self.current_map.as_mut() {
+            self.nr_mappings += m.len;
             for i in 0..m.len {
                 let bt = BlockTime {
                     block: m.data_begin + i,
@@ -361,12 +385,100 @@ fn build_data_sm(w: &mut WriteBatcher, sm: &dyn SpaceMap) -> Result<Vec<u8>> {
 
 //------------------------------------------
 
+/// A summary of the metadata written by a restore, so scripts can check it
+/// and users can see how full the new metadata device is.  The mappings of
+/// shared subtrees are counted once, however many devices refer to them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RestoreStats {
+    pub nr_devices: u64,
+    pub nr_mappings: u64,
+    pub nr_metadata_blocks: u64,
+    pub metadata_blocks_used: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SummaryFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(SummaryFormat::Text),
+            "json" => Ok(SummaryFormat::Json),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
+impl RestoreStats {
+    fn fields(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("devices_restored", self.nr_devices),
+            ("mappings_written", self.nr_mappings),
+            ("metadata_blocks", self.nr_metadata_blocks),
+            ("metadata_blocks_used", self.metadata_blocks_used),
+            (
+                "metadata_blocks_free",
+                self.nr_metadata_blocks - self.metadata_blocks_used,
+            ),
+        ]
+    }
+
+    /// Prints the summary, which the quiet report leaves out
+    pub fn write_summary(&self, report: &Report, format: SummaryFormat) {
+        match format {
+            SummaryFormat::Text => {
+                for (name, v) in self.fields() {
+                    report.to_stdout(&format!("{}: {}", name, v));
+                }
+            }
+            SummaryFormat::Json => {
+                let fields: Vec<String> = self
+                    .fields()
+                    .iter()
+                    .map(|(name, v)| format!("  \"{}\": {}", name, v))
+                    .collect();
+                report.to_stdout(&format!("{{\n{}\n}}", fields.join(",\n")));
+            }
+        }
+    }
+}
+
+//------------------------------------------
+
+#[derive(Clone, Copy)]
+pub enum InputFormat {
+    Xml,
+    Extents,
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xml" => Ok(InputFormat::Xml),
+            "extents" => Ok(InputFormat::Extents),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
 pub struct ThinRestoreOptions<'a> {
     pub input: &'a Path,
+    pub input_format: InputFormat,
     pub output: &'a Path,
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
     pub overrides: SuperblockOverrides,
+    pub id_map: Option<&'a Path>,
+    /// Lay the metadata out the same way on any machine
+    pub deterministic: bool,
+    pub summary_format: SummaryFormat,
 }
 
 struct Context {
@@ -387,6 +499,56 @@ fn new_context(opts: &ThinRestoreOptions) -> Result<Context> {
 
 //------------------------------------------
 
+fn read_input<M: MetadataVisitor>(
+    input: File,
+    opts: &ThinRestoreOptions,
+    visitor: &mut M,
+) -> Result<()> {
+    match opts.input_format {
+        InputFormat::Xml => xml::read(input, visitor),
+        InputFormat::Extents => extents::read(input, &opts.overrides, visitor),
+    }
+}
+
+// Deallocates the blocks of an output file that the restored metadata
+// doesn't use, so the image only takes up the space of the metadata.  The
+// file may have been fully allocated, or hold stale metadata, beforehand.
+fn punch_unused_blocks(path: &Path, sm: &dyn SpaceMap, report: &Report) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    let block_size = BLOCK_SIZE as u64;
+    let nr_blocks = file_utils::file_size(path)? / block_size;
+
+    // Blocks beyond the space map are never used
+    let nr_sm_blocks = sm.get_nr_blocks()?;
+    let is_used = |b: u64| -> Result<bool> { Ok(b < nr_sm_blocks && sm.get(b)? > 0) };
+
+    let mut nr_punched = 0;
+    let mut b = 0;
+    while b < nr_blocks {
+        if is_used(b)? {
+            b += 1;
+            continue;
+        }
+
+        let begin = b;
+        while b < nr_blocks && !is_used(b)? {
+            b += 1;
+        }
+
+        if !file_utils::punch_hole(&file, begin * block_size, (b - begin) * block_size)? {
+            report.info("the output filesystem can't punch holes, so the output isn't sparse");
+            return Ok(());
+        }
+        nr_punched += b - begin;
+    }
+
+    report.info(&format!(
+        "left {} unused metadata blocks unallocated",
+        nr_punched
+    ));
+    Ok(())
+}
+
 pub fn restore(opts: ThinRestoreOptions) -> Result<()> {
     let input = OpenOptions::new()
         .read(true)
@@ -397,10 +559,29 @@ pub fn restore(opts: ThinRestoreOptions) -> Result<()> {
     let max_count = u32::MAX;
 
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), max_count);
-    let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
+    let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size())
+        .with_sync_policy(opts.engine_opts.sync_policy)
+        .with_deterministic_layout(opts.deterministic);
     let mut restorer = Restorer::new_with(&mut w, &opts.overrides, ctx.report);
-    xml::read(input, &mut restorer)?;
 
+    if let Some(path) = opts.id_map {
+        // Undo the renumbering performed by thin_dump
+        let ids = DevIdMap::read(path)?.inverse();
+        let mut out = RenumberVisitor::new(&mut restorer, ids);
+        read_input(input, &opts, &mut out)?;
+    } else {
+        read_input(input, &opts, &mut restorer)?;
+    }
+    let stats = restorer
+        .stats()
+        .ok_or_else(|| anyhow!("incomplete source metadata"))?;
+
+    // The output is left untouched when the writes go to an overlay
+    if opts.engine_opts.overlay.is_none() && file_utils::is_file(opts.output)? {
+        punch_unused_blocks(opts.output, sm.lock().unwrap().deref(), &opts.report)?;
+    }
+
+    stats.write_summary(&opts.report, opts.summary_format);
     Ok(())
 }
 
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
 use std::io::BufWriter;
@@ -6,25 +6,26 @@ use std::io::Write;
 use std::ops::DerefMut;
 use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 use crate::commands::engine::*;
 use crate::io_engine::*;
 use crate::pdata::btree::{self, KeyRange, NodeHeader};
 use crate::pdata::btree_walker::{btree_to_map, BTreeWalker, NodeVisitor};
-use crate::pdata::space_map::RestrictedSpaceMap;
 use crate::report::Report;
 use crate::thin::block_time::BlockTime;
+use crate::thin::rmap_index::query_rmap_index;
 use crate::thin::superblock::*;
 
 //------------------------------------------
 
-#[derive(Clone, Copy, Default)]
-struct RmapRegion {
-    begin: u64,
-    end: u64,
-    dev_id: u32,
-    thin_begin: u64,
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RmapRegion {
+    pub(crate) begin: u64,
+    pub(crate) end: u64,
+    pub(crate) dev_id: u32,
+    pub(crate) thin_begin: u64,
 }
 
 impl RmapRegion {
@@ -57,7 +58,7 @@ impl RmapRegion {
         self.thin_begin = thin_block;
     }
 
-    fn compare(lhs: &Self, rhs: &Self) -> Ordering {
+    pub(crate) fn compare(lhs: &Self, rhs: &Self) -> Ordering {
         if lhs.begin < rhs.begin {
             Ordering::Less
         } else if lhs.begin > rhs.begin {
@@ -78,6 +79,17 @@ impl RmapRegion {
             Ordering::Equal
         }
     }
+
+    fn to_json(self) -> String {
+        format!(
+            "{{\"data_begin\": {}, \"data_end\": {}, \"dev_id\": {}, \"thin_begin\": {}, \"thin_end\": {}}}",
+            self.begin,
+            self.end,
+            self.dev_id,
+            self.thin_begin,
+            self.thin_begin + (self.end - self.begin)
+        )
+    }
 }
 
 impl Display for RmapRegion {
@@ -96,6 +108,20 @@ impl Display for RmapRegion {
 
 //------------------------------------------
 
+// Sorts and merges the regions, so a block is looked up with a binary
+// search however many there are.
+pub(crate) fn merge_regions(mut regions: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    regions.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(regions.len());
+    for r in regions {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
 struct RmapInner {
     rmap: Vec<RmapRegion>,
     current: RmapRegion,
@@ -115,7 +141,7 @@ impl RmapVisitor {
                 current: RmapRegion::default(),
                 dev_id: 0,
             }),
-            regions,
+            regions: merge_regions(regions),
         }
     }
 
@@ -125,12 +151,9 @@ impl RmapVisitor {
     }
 
     fn in_regions(&self, b: u64) -> bool {
-        for range in self.regions.iter() {
-            if range.contains(&b) {
-                return true;
-            }
-        }
-        false
+        // the index of the first region starting after the block
+        let i = self.regions.partition_point(|r| r.start <= b);
+        i > 0 && self.regions[i - 1].contains(&b)
     }
 
     fn complete(self) -> Result<Vec<RmapRegion>> {
@@ -184,10 +207,140 @@ impl NodeVisitor<BlockTime> for RmapVisitor {
 
 //------------------------------------------
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RmapFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for RmapFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(RmapFormat::Text),
+            "json" => Ok(RmapFormat::Json),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
+fn write_rmap_text(w: &mut dyn Write, rmap: &[RmapRegion]) -> Result<()> {
+    for m in rmap {
+        writeln!(w, "{}", m)?;
+    }
+    Ok(())
+}
+
+fn write_rmap_json(w: &mut dyn Write, rmap: &[RmapRegion]) -> Result<()> {
+    if rmap.is_empty() {
+        writeln!(w, "[]")?;
+        return Ok(());
+    }
+
+    writeln!(w, "[")?;
+    for (i, m) in rmap.iter().enumerate() {
+        let sep = if i + 1 < rmap.len() { "," } else { "" };
+        writeln!(w, "  {}{}", m.to_json(), sep)?;
+    }
+    writeln!(w, "]")?;
+    Ok(())
+}
+
+pub(crate) fn write_rmap(w: &mut dyn Write, rmap: &[RmapRegion], format: RmapFormat) -> Result<()> {
+    match format {
+        RmapFormat::Text => write_rmap_text(w, rmap),
+        RmapFormat::Json => write_rmap_json(w, rmap),
+    }
+}
+
+//------------------------------------------
+
+/// Collects the runs of data blocks within the regions, and the thin
+/// devices mapping them, sorted by the data blocks.
+pub(crate) fn build_rmap(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    regions: Vec<Range<u64>>,
+) -> Result<Vec<RmapRegion>> {
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+
+    let mut path = Vec::new();
+    let roots = btree_to_map(&mut path, engine.clone(), false, sb.mapping_root)?;
+
+    let rv = RmapVisitor::new(regions);
+    for (dev_id, root) in roots.iter() {
+        // Snapshots share subtrees with their origin.  Each device is walked
+        // with a walker of its own, so the shared subtrees are visited for
+        // every device that references them, rather than only the first.
+        // TODO: multi-threaded
+        let w = BTreeWalker::new(engine.clone(), false);
+        rv.set_dev_id(*dev_id as u32);
+        path.clear();
+        w.walk(&mut path, &rv, *root)?;
+    }
+
+    rv.complete()
+}
+
+//------------------------------------------
+
+/// The units the regions are given in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionUnits {
+    Blocks,
+    Sectors,
+    Bytes,
+}
+
+impl FromStr for RegionUnits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(RegionUnits::Blocks),
+            "sectors" => Ok(RegionUnits::Sectors),
+            "bytes" => Ok(RegionUnits::Bytes),
+            _ => Err(anyhow!("unknown units")),
+        }
+    }
+}
+
+// Converts the regions to the data blocks holding them, so a region that
+// covers part of a block takes in the whole block.  A zero block size, as
+// read from a damaged superblock, is rejected.
+fn to_data_blocks(
+    regions: Vec<Range<u64>>,
+    units: RegionUnits,
+    data_block_size: u32, // sectors
+) -> Result<Vec<Range<u64>>> {
+    if units == RegionUnits::Blocks {
+        return Ok(regions);
+    }
+    if data_block_size == 0 {
+        return Err(anyhow!("the superblock has a data block size of zero"));
+    }
+
+    let per_block = match units {
+        RegionUnits::Sectors => data_block_size as u64,
+        _ => (data_block_size as u64) << SECTOR_SHIFT,
+    };
+    Ok(regions
+        .into_iter()
+        .map(|r| r.start / per_block..r.end / per_block + (r.end % per_block != 0) as u64)
+        .collect())
+}
+
+//------------------------------------------
+
 pub struct ThinRmapOptions<'a> {
     pub input: &'a Path,
     pub engine_opts: EngineOptions,
     pub regions: Vec<Range<u64>>,
+    pub units: RegionUnits,
+    /// Answer from an index built by build_rmap_index(), rather than
+    /// walking the mappings
+    pub index: Option<&'a Path>,
+    pub format: RmapFormat,
     pub report: Arc<Report>,
 }
 
@@ -197,7 +350,9 @@ struct Context {
 }
 
 fn mk_context(opts: &ThinRmapOptions) -> Result<Context> {
-    let engine = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
+        .build()?;
 
     Ok(Context {
         engine,
@@ -208,33 +363,55 @@ fn mk_context(opts: &ThinRmapOptions) -> Result<Context> {
 pub fn rmap(opts: ThinRmapOptions) -> Result<()> {
     let ctx = mk_context(&opts)?;
 
-    let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
-    let metadata_sm = Arc::new(Mutex::new(RestrictedSpaceMap::new(
-        ctx.engine.get_nr_blocks(),
-    )));
+    let regions = if opts.units == RegionUnits::Blocks {
+        opts.regions
+    } else {
+        let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
+        to_data_blocks(opts.regions, opts.units, sb.data_block_size)?
+    };
 
-    let mut path = Vec::new();
-    let roots = btree_to_map(&mut path, ctx.engine.clone(), false, sb.mapping_root)?;
-
-    let rv = RmapVisitor::new(opts.regions);
-    let w = Arc::new(BTreeWalker::new_with_sm(
-        ctx.engine.clone(),
-        metadata_sm,
-        false,
-    )?);
-    for (dev_id, root) in roots.iter() {
-        // TODO: multi-threaded
-        rv.set_dev_id(*dev_id as u32);
-        path.clear();
-        w.walk(&mut path, &rv, *root)?;
-    }
+    let rmap = match opts.index {
+        Some(index) => query_rmap_index(ctx.engine.as_ref(), index, regions)?,
+        None => build_rmap(ctx.engine.clone(), regions)?,
+    };
 
-    let rmap = rv.complete()?;
     let mut writer = BufWriter::new(std::io::stdout());
-    for m in rmap.iter() {
-        writer.write_all(m.to_string().as_bytes())?;
-        writer.write_all(b"\n")?;
-    }
+    write_rmap(&mut writer, &rmap, opts.format)?;
+    writer.flush()?;
 
     Ok(())
 }
+
+//------------------------------------------
+
+#[cfg(test)]
+mod rmap_tests {
+    use super::*;
+
+    #[test]
+    fn regions_are_rounded_out_to_data_blocks() -> Result<()> {
+        let regions = vec![640..896, 700..800];
+        assert_eq!(
+            to_data_blocks(regions.clone(), RegionUnits::Sectors, 128)?,
+            [5..7, 5..7]
+        );
+        assert_eq!(
+            to_data_blocks(vec![65536..65537], RegionUnits::Bytes, 128)?,
+            [1..2]
+        );
+        assert_eq!(
+            to_data_blocks(regions.clone(), RegionUnits::Blocks, 0)?,
+            regions
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn zero_data_block_size_is_rejected() {
+        for units in [RegionUnits::Sectors, RegionUnits::Bytes] {
+            assert!(to_data_blocks(vec![0..8], units, 0).is_err());
+        }
+    }
+}
+
+//------------------------------------------
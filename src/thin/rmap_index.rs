@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::io_engine::*;
+use crate::pdata::space_map::common::SMRoot;
+use crate::pdata::unpack::unpack;
+use crate::thin::rmap::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+// An index of the reverse map of every data block, so repeated queries
+// don't walk the mappings each time.  The runs are stored sorted by their
+// first data block, each with the furthest end of the runs up to it, which
+// is what a binary search for the runs overlapping a region needs, since
+// the runs vary in length.
+//
+// The index records the superblock it was built from, and queries refuse
+// an index that doesn't match the metadata any more.
+
+const MAGIC: u64 = 0x70616d7220706e74; // "tnp rmap"
+const INDEX_VERSION: u64 = 1;
+const HEADER_SIZE: u64 = 48;
+const ENTRY_SIZE: u64 = 40;
+
+struct IndexHeader {
+    transaction_id: u64,
+    mapping_root: u64,
+    time: u32,
+    nr_entries: u64,
+}
+
+impl IndexHeader {
+    fn from_superblock(sb: &Superblock, nr_entries: u64) -> Self {
+        IndexHeader {
+            transaction_id: sb.transaction_id,
+            mapping_root: sb.mapping_root,
+            time: sb.time,
+            nr_entries,
+        }
+    }
+
+    fn matches(&self, sb: &Superblock) -> bool {
+        self.transaction_id == sb.transaction_id
+            && self.mapping_root == sb.mapping_root
+            && self.time == sb.time
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_u64::<LittleEndian>(MAGIC)?;
+        w.write_u64::<LittleEndian>(INDEX_VERSION)?;
+        w.write_u64::<LittleEndian>(self.transaction_id)?;
+        w.write_u64::<LittleEndian>(self.mapping_root)?;
+        w.write_u32::<LittleEndian>(self.time)?;
+        w.write_u32::<LittleEndian>(0)?;
+        w.write_u64::<LittleEndian>(self.nr_entries)?;
+        Ok(())
+    }
+
+    fn read<R: Read>(r: &mut R) -> Result<Self> {
+        if r.read_u64::<LittleEndian>()? != MAGIC {
+            return Err(anyhow!("not a reverse map index"));
+        }
+        let version = r.read_u64::<LittleEndian>()?;
+        if version != INDEX_VERSION {
+            return Err(anyhow!("unsupported index version {}", version));
+        }
+        let transaction_id = r.read_u64::<LittleEndian>()?;
+        let mapping_root = r.read_u64::<LittleEndian>()?;
+        let time = r.read_u32::<LittleEndian>()?;
+        let _pad = r.read_u32::<LittleEndian>()?;
+        let nr_entries = r.read_u64::<LittleEndian>()?;
+        Ok(IndexHeader {
+            transaction_id,
+            mapping_root,
+            time,
+            nr_entries,
+        })
+    }
+}
+
+fn write_entry<W: Write>(w: &mut W, m: &RmapRegion, max_end: u64) -> Result<()> {
+    w.write_u64::<LittleEndian>(m.begin)?;
+    w.write_u64::<LittleEndian>(m.end)?;
+    w.write_u64::<LittleEndian>(max_end)?;
+    w.write_u64::<LittleEndian>(m.thin_begin)?;
+    w.write_u32::<LittleEndian>(m.dev_id)?;
+    w.write_u32::<LittleEndian>(0)?;
+    Ok(())
+}
+
+// Returns the run, and the furthest end of the runs up to it
+fn read_entry<R: Read>(r: &mut R) -> Result<(RmapRegion, u64)> {
+    let begin = r.read_u64::<LittleEndian>()?;
+    let end = r.read_u64::<LittleEndian>()?;
+    let max_end = r.read_u64::<LittleEndian>()?;
+    let thin_begin = r.read_u64::<LittleEndian>()?;
+    let dev_id = r.read_u32::<LittleEndian>()?;
+    let _pad = r.read_u32::<LittleEndian>()?;
+    let m = RmapRegion {
+        begin,
+        end,
+        dev_id,
+        thin_begin,
+    };
+    Ok((m, max_end))
+}
+
+fn entry_offset(i: u64) -> u64 {
+    HEADER_SIZE + i * ENTRY_SIZE
+}
+
+//------------------------------------------
+
+pub struct RmapIndexOptions<'a> {
+    pub input: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub output: &'a Path,
+}
+
+/// Writes an index of the reverse map of every data block
+pub fn build_rmap_index(opts: RmapIndexOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .read_only(true)
+        .build()?;
+
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root)?;
+    let rmap = build_rmap(engine, vec![0..data_root.nr_blocks])?;
+
+    let file = File::create(opts.output)
+        .with_context(|| format!("couldn't create the index '{}'", opts.output.display()))?;
+    let mut w = BufWriter::new(file);
+    IndexHeader::from_superblock(&sb, rmap.len() as u64).write(&mut w)?;
+
+    let mut max_end = 0;
+    for m in &rmap {
+        max_end = max_end.max(m.end);
+        write_entry(&mut w, m, max_end)?;
+    }
+    w.flush()?;
+
+    Ok(())
+}
+
+// The index of the first run that ends after the block, found with a
+// read of log(nr_entries) entries
+fn first_ending_after(file: &File, nr_entries: u64, b: u64) -> Result<u64> {
+    let mut buf = [0u8; ENTRY_SIZE as usize];
+    let (mut lo, mut hi) = (0, nr_entries);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        file.read_exact_at(&mut buf, entry_offset(mid))?;
+        let (_, max_end) = read_entry(&mut &buf[..])?;
+        if max_end > b {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Ok(lo)
+}
+
+/// Answers a reverse map query from an index, with the same runs that
+/// walking the mappings would give.
+pub(crate) fn query_rmap_index(
+    engine: &dyn IoEngine,
+    path: &Path,
+    regions: Vec<Range<u64>>,
+) -> Result<Vec<RmapRegion>> {
+    let file = File::open(path)
+        .with_context(|| format!("couldn't open the index '{}'", path.display()))?;
+    let header = IndexHeader::read(&mut BufReader::new(&file))
+        .with_context(|| format!("couldn't read the index '{}'", path.display()))?;
+
+    let sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+    if !header.matches(&sb) {
+        return Err(anyhow!(
+            "the index was built from different metadata, rebuild it with --build-index"
+        ));
+    }
+
+    let mut rmap = Vec::new();
+    for r in merge_regions(regions) {
+        let first = first_ending_after(&file, header.nr_entries, r.start)?;
+
+        let mut reader = BufReader::new(&file);
+        reader.seek(SeekFrom::Start(entry_offset(first)))?;
+        for _ in first..header.nr_entries {
+            let (m, _) = read_entry(&mut reader)?;
+            if m.begin >= r.end {
+                break;
+            }
+            if m.end <= r.start {
+                continue;
+            }
+
+            // trim the run to the region
+            let begin = m.begin.max(r.start);
+            rmap.push(RmapRegion {
+                begin,
+                end: m.end.min(r.end),
+                dev_id: m.dev_id,
+                thin_begin: m.thin_begin + (begin - m.begin),
+            });
+        }
+    }
+    rmap.sort_by(RmapRegion::compare);
+
+    Ok(rmap)
+}
+
+//------------------------------------------
@@ -15,8 +15,10 @@ use std::vec::Vec;
 use crate::copier::batcher::CopyOpBatcher;
 use crate::copier::*;
 use crate::io_engine::utils::VectoredBlockIo;
-use crate::io_engine::{IoEngine, SyncIoEngine, SECTOR_SHIFT};
+use crate::file_utils::{create_sized_file, TempFile};
+use crate::io_engine::{IoEngine, SyncIoEngine, BLOCK_SIZE, SECTOR_SHIFT};
 use crate::pdata::space_map::metadata::core_metadata_sm;
+use crate::pdata::space_map::SpaceMap;
 use crate::report::Report;
 use crate::shrink::toplevel::*;
 use crate::thin::dump::dump_metadata;
@@ -294,6 +296,7 @@ pub struct ThinShrinkOptions {
     pub output: PathBuf,
     pub data_device: PathBuf,
     pub nr_blocks: u64,
+    pub nr_metadata_blocks: Option<u64>,
     pub do_copy: bool,
     pub binary_mode: bool,
     pub report: Arc<Report>,
@@ -326,6 +329,44 @@ fn rewrite_xml(opts: ThinShrinkOptions) -> Result<()> {
     xml::read(input, &mut remapper)
 }
 
+// Returns the number of blocks up to and including the highest allocated.
+fn high_water_mark(sm: &dyn SpaceMap) -> Result<u64> {
+    for b in (0..sm.get_nr_blocks()?).rev() {
+        if sm.get(b)? > 0 {
+            return Ok(b + 1);
+        }
+    }
+    Ok(0)
+}
+
+// Rebuilds the remapped metadata into the first nr_metadata_blocks of the
+// output, returning the number of blocks used.  The rebuilt trees and space
+// maps are allocated from the start of the device, so the blocks above
+// those used may be discarded.
+fn write_metadata(
+    input: Arc<dyn IoEngine + Send + Sync>,
+    output: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    md: &Metadata,
+    nr_metadata_blocks: u64,
+    remaps: Vec<(BlockRange, u64)>,
+    opts: &ThinShrinkOptions,
+) -> Result<u64> {
+    let sm = core_metadata_sm(nr_metadata_blocks, u32::MAX);
+    let mut w = WriteBatcher::new(output.clone(), sm.clone(), output.get_batch_size());
+    let mut restorer = Restorer::new(&mut w, opts.report.clone());
+    let mut remapper = DataRemapper::new(&mut restorer, opts.nr_blocks, remaps);
+    dump_metadata(
+        input,
+        &mut remapper,
+        &ThinSuperblock::OnDisk(sb.clone()),
+        md,
+    )?;
+
+    let sm = sm.lock().unwrap();
+    high_water_mark(&*sm)
+}
+
 fn rebuild_metadata(opts: ThinShrinkOptions) -> Result<()> {
     let input = Arc::new(SyncIoEngine::new(&opts.input, false)?);
     let sb = read_superblock(input.as_ref(), SUPERBLOCK_LOCATION)?;
@@ -335,6 +376,47 @@ fn rebuild_metadata(opts: ThinShrinkOptions) -> Result<()> {
     // 1st pass
     let remaps = build_remaps_from_metadata(input.clone(), &sb, &md, opts.nr_blocks)?;
 
+    let output = Arc::new(SyncIoEngine::new(&opts.output, true)?);
+    let nr_metadata_blocks = match opts.nr_metadata_blocks {
+        Some(n) if n > output.get_nr_blocks() => {
+            return Err(anyhow!(
+                "the output has only {} blocks, {} requested",
+                output.get_nr_blocks(),
+                n
+            ));
+        }
+        Some(n) => {
+            // A trial rebuild finds the smallest metadata device possible,
+            // before any data has been moved.  It's written to a scratch
+            // file, as the output may still hold the only good copy of the
+            // metadata, or be the input itself.
+            let scratch = TempFile::new(&std::env::temp_dir())?;
+            create_sized_file(scratch.path(), output.get_nr_blocks() * BLOCK_SIZE as u64)?;
+            let trial = Arc::new(SyncIoEngine::new_with(scratch.path(), true, false)?);
+            let min = write_metadata(
+                input.clone(),
+                trial,
+                &sb,
+                &md,
+                output.get_nr_blocks(),
+                remaps.clone(),
+                &opts,
+            )?;
+
+            opts.report
+                .to_stdout(&format!("MIN_METADATA_BLOCKS={}", min));
+            if n < min {
+                return Err(anyhow!(
+                    "the metadata needs at least {} blocks, {} requested",
+                    min,
+                    n
+                ));
+            }
+            n
+        }
+        None => output.get_nr_blocks(),
+    };
+
     let progress = Arc::new(IgnoreProgress {});
     if opts.do_copy {
         let bs = (sb.data_block_size as usize) << SECTOR_SHIFT;
@@ -342,17 +424,8 @@ fn rebuild_metadata(opts: ThinShrinkOptions) -> Result<()> {
     }
 
     // 2nd pass
-    let output = Arc::new(SyncIoEngine::new(&opts.output, true)?);
-    let sm = core_metadata_sm(output.get_nr_blocks(), u32::MAX);
-    let mut w = WriteBatcher::new(output.clone(), sm, output.get_batch_size());
-    let mut restorer = Restorer::new(&mut w, opts.report);
-    let mut remapper = DataRemapper::new(&mut restorer, opts.nr_blocks, remaps);
-    dump_metadata(
-        input,
-        &mut remapper,
-        &ThinSuperblock::OnDisk(sb.clone()),
-        &md,
-    )
+    write_metadata(input, output, &sb, &md, nr_metadata_blocks, remaps, &opts)?;
+    Ok(())
 }
 
 pub fn shrink(opts: ThinShrinkOptions) -> Result<()> {
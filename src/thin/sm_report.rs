@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::io_engine::*;
+use crate::pdata::space_map::common::SMRoot;
+use crate::pdata::space_map::disk::DiskSpaceMap;
+use crate::pdata::space_map::SpaceMap;
+use crate::pdata::unpack::unpack;
+use crate::report::Report;
+use crate::thin::metadata::ThinSuperblock;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+// A repair rebuilds the data space map from the mappings it writes.  The
+// reference counts it ends up with are compared against those of the input,
+// so there's a record of exactly how the metadata changed.  The counts of
+// the input are only known if its superblock and space map can be read.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmReportFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for SmReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(SmReportFormat::Text),
+            "json" => Ok(SmReportFormat::Json),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
+/// The changes to the reference counts of the input
+#[derive(Debug, Default)]
+pub struct InputChanges {
+    /// Blocks still in use, with a different count
+    pub nr_fixed: u64,
+    /// Leaked blocks, which no mapping refers to any more
+    pub nr_freed: u64,
+    /// Blocks in use that the input had marked free
+    pub nr_claimed: u64,
+    /// Blocks whose count in the input couldn't be read
+    pub nr_unreadable: u64,
+    pub nr_overflow: u64,
+}
+
+/// The adjustments made to the data space map by a repair
+#[derive(Debug, Default)]
+pub struct SmAdjustments {
+    pub nr_data_blocks: u64,
+    pub input: Option<InputChanges>,
+    /// The entries of the rebuilt ref count tree
+    pub nr_overflow: u64,
+}
+
+fn open_data_sm(engine: Arc<dyn IoEngine + Send + Sync>, root: &[u8]) -> Result<DiskSpaceMap> {
+    DiskSpaceMap::open_data(engine, unpack::<SMRoot>(root)?)
+}
+
+/// Compares the data space map of the input with that of the repaired
+/// metadata.
+pub fn compare_data_sms(
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    sb_in: &ThinSuperblock,
+    engine_out: Arc<dyn IoEngine + Send + Sync>,
+) -> Result<SmAdjustments> {
+    let sb_out = read_superblock(engine_out.as_ref(), SUPERBLOCK_LOCATION)?;
+    let new_sm = open_data_sm(engine_out, &sb_out.data_sm_root)?;
+    let old_sm = match sb_in {
+        ThinSuperblock::OnDisk(sb) => open_data_sm(engine_in, &sb.data_sm_root).ok(),
+        ThinSuperblock::InCore(_) => None,
+    };
+    let old_nr_blocks = match &old_sm {
+        Some(sm) => sm.get_nr_blocks()?,
+        None => 0,
+    };
+
+    let mut adj = SmAdjustments {
+        nr_data_blocks: new_sm.get_nr_blocks()?,
+        input: old_sm.as_ref().map(|_| InputChanges::default()),
+        nr_overflow: 0,
+    };
+
+    for b in 0..adj.nr_data_blocks {
+        let new = new_sm.get(b)?;
+        if new > 2 {
+            adj.nr_overflow += 1;
+        }
+
+        if let (Some(sm), Some(changes)) = (&old_sm, &mut adj.input) {
+            let old = if b < old_nr_blocks {
+                match sm.get(b) {
+                    Ok(count) => count,
+                    Err(_) => {
+                        changes.nr_unreadable += 1;
+                        continue;
+                    }
+                }
+            } else {
+                0
+            };
+
+            if old > 2 {
+                changes.nr_overflow += 1;
+            }
+            if old != new {
+                match (old, new) {
+                    (_, 0) => changes.nr_freed += 1,
+                    (0, _) => changes.nr_claimed += 1,
+                    _ => changes.nr_fixed += 1,
+                }
+            }
+        }
+    }
+
+    Ok(adj)
+}
+
+//------------------------------------------
+
+impl SmAdjustments {
+    // The fields of the report, with those comparing against the input left
+    // out if its counts are unknown
+    fn fields(&self) -> Vec<(&'static str, Option<u64>)> {
+        let input = self.input.as_ref();
+        vec![
+            ("nr_data_blocks", Some(self.nr_data_blocks)),
+            ("counts_fixed", input.map(|c| c.nr_fixed)),
+            ("leaked_blocks_freed", input.map(|c| c.nr_freed)),
+            ("blocks_claimed", input.map(|c| c.nr_claimed)),
+            ("counts_unreadable", input.map(|c| c.nr_unreadable)),
+            ("overflow_entries_before", input.map(|c| c.nr_overflow)),
+            ("overflow_entries", Some(self.nr_overflow)),
+        ]
+    }
+
+    fn write_text<W: Write>(&self, w: &mut W) -> Result<()> {
+        for (name, v) in self.fields() {
+            match v {
+                Some(v) => writeln!(w, "{}: {}", name, v)?,
+                None => writeln!(w, "{}: unknown", name)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_json<W: Write>(&self, w: &mut W) -> Result<()> {
+        let fields: Vec<String> = self
+            .fields()
+            .iter()
+            .map(|(name, v)| match v {
+                Some(v) => format!("  \"{}\": {}", name, v),
+                None => format!("  \"{}\": null", name),
+            })
+            .collect();
+        writeln!(w, "{{\n{}\n}}", fields.join(",\n"))?;
+        Ok(())
+    }
+
+    pub fn write(&self, path: &Path, format: SmReportFormat) -> Result<()> {
+        let mut f = File::create(path)?;
+        match format {
+            SmReportFormat::Text => self.write_text(&mut f),
+            SmReportFormat::Json => self.write_json(&mut f),
+        }
+    }
+}
+
+pub fn report_adjustments(report: &Report, adj: &SmAdjustments) {
+    match &adj.input {
+        Some(c) => {
+            report.info(&format!(
+                "rebuilt the data space map: fixed {} counts, freed {} leaked blocks, claimed {} blocks",
+                c.nr_fixed, c.nr_freed, c.nr_claimed
+            ));
+            if c.nr_unreadable > 0 {
+                report.warning(&format!(
+                    "couldn't read the counts of {} data blocks in the input",
+                    c.nr_unreadable
+                ));
+            }
+        }
+        None => report.info("rebuilt the data space map, the counts of the input are unknown"),
+    }
+    report.info(&format!(
+        "the ref count tree holds {} entries",
+        adj.nr_overflow
+    ));
+}
+
+//------------------------------------------
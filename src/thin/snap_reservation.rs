@@ -0,0 +1,62 @@
+use anyhow::{anyhow, Context, Result};
+use std::process::Command;
+
+//------------------------------------------
+
+fn send_message(pool: &str, msg: &str) -> Result<()> {
+    let output = Command::new("dmsetup")
+        .args(["message", pool, "0", msg])
+        .output()
+        .context("unable to run dmsetup")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "dmsetup message {} 0 {} failed: {}",
+            pool,
+            msg,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// A metadata snapshot reserved on a live pool.
+///
+/// The pool holds a single metadata snapshot, so the reservation fails if
+/// one is already held, rather than have another tool's snapshot released
+/// under it.  The snapshot is released when the reservation is dropped, so
+/// it isn't left pinning metadata blocks if the tool fails.
+pub struct SnapReservation {
+    pool: String,
+    released: bool,
+}
+
+impl SnapReservation {
+    /// Reserves a metadata snapshot of the pool device, given by its
+    /// device-mapper name or path.
+    pub fn reserve(pool: &str) -> Result<Self> {
+        send_message(pool, "reserve_metadata_snap")
+            .context("unable to reserve a metadata snapshot")?;
+        Ok(SnapReservation {
+            pool: pool.to_string(),
+            released: false,
+        })
+    }
+
+    /// Releases the snapshot, reporting any failure that dropping the
+    /// reservation would hide.
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        send_message(&self.pool, "release_metadata_snap")
+            .context("unable to release the metadata snapshot")
+    }
+}
+
+impl Drop for SnapReservation {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = send_message(&self.pool, "release_metadata_snap");
+        }
+    }
+}
+
+//------------------------------------------
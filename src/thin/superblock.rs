@@ -14,6 +14,10 @@ pub const SUPERBLOCK_LOCATION: u64 = 0;
 const UUID_SIZE: usize = 16;
 pub const SPACE_MAP_ROOT_SIZE: usize = 128;
 
+// The range of metadata versions the tools read and write
+pub const MIN_METADATA_VERSION: u32 = 1;
+pub const MAX_METADATA_VERSION: u32 = 2;
+
 #[derive(Debug, Clone)]
 pub struct SuperblockFlags {
     pub needs_check: bool,
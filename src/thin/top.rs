@@ -0,0 +1,263 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::commands::engine::*;
+use crate::report::{mk_quiet_report, Report};
+use crate::thin::accounting::{account_devices, AccountingOptions};
+use crate::thin::snap_reservation::SnapReservation;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+/// The data block counts of a device at the time of a sample
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceCounts {
+    pub mapped_blocks: u64,
+    pub exclusive_blocks: u64,
+    pub shared_blocks: u64,
+}
+
+pub struct Sample {
+    pub taken: Instant,
+    pub transaction_id: u64,
+    pub data_block_size: u32, // sectors
+    pub devs: BTreeMap<u64, DeviceCounts>,
+}
+
+pub struct TopOptions<'a> {
+    pub input: &'a Path,
+    pub engine_opts: EngineOptions,
+    /// The live pool to reserve a metadata snapshot of for each sample
+    pub auto_snap: Option<&'a str>,
+}
+
+fn read_sample(opts: &TopOptions, report: Arc<Report>) -> Result<Sample> {
+    let mut engine_opts = opts.engine_opts.clone();
+    engine_opts.use_metadata_snap |= opts.auto_snap.is_some();
+    let engine = EngineBuilder::new(opts.input, &engine_opts)
+        .read_only(true)
+        .exclusive(!engine_opts.use_metadata_snap)
+        .build()?;
+
+    let sb = if engine_opts.use_metadata_snap {
+        read_superblock_snap(engine.as_ref())?
+    } else {
+        read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?
+    };
+    let actual_sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let accounting_opts = AccountingOptions {
+        reclaimable: false,
+        ignore_non_fatal: false,
+    };
+    let accounts = account_devices(
+        engine,
+        report,
+        &actual_sb,
+        sb.mapping_root,
+        &accounting_opts,
+    )?;
+
+    let devs = accounts
+        .iter()
+        .map(|a| {
+            let counts = DeviceCounts {
+                mapped_blocks: a.mapped_blocks,
+                exclusive_blocks: a.exclusive_blocks,
+                shared_blocks: a.shared_blocks,
+            };
+            (a.dev_id, counts)
+        })
+        .collect();
+
+    Ok(Sample {
+        taken: Instant::now(),
+        transaction_id: sb.transaction_id,
+        data_block_size: sb.data_block_size,
+        devs,
+    })
+}
+
+/// Counts the blocks of every device.  The metadata snapshot is released
+/// before returning, so the pool isn't held up between samples.
+pub fn take_sample(opts: &TopOptions) -> Result<Sample> {
+    // Progress output would garble the display
+    let report = Arc::new(mk_quiet_report());
+
+    let snap = opts.auto_snap.map(SnapReservation::reserve).transpose()?;
+    let sample = read_sample(opts, report);
+    match snap {
+        Some(snap) => {
+            let released = snap.release();
+            let sample = sample?;
+            released?;
+            Ok(sample)
+        }
+        None => sample,
+    }
+}
+
+//------------------------------------------
+
+/// How a device changed between two samples, in blocks per second
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceActivity {
+    pub dev_id: u64,
+    pub counts: DeviceCounts,
+    /// Newly mapped blocks, less those unmapped
+    pub alloc_rate: f64,
+    /// Shared blocks that stopped being shared, as writes to them break the
+    /// sharing
+    pub unshare_rate: f64,
+}
+
+impl DeviceActivity {
+    fn busyness(&self) -> f64 {
+        self.alloc_rate.abs() + self.unshare_rate.abs()
+    }
+}
+
+/// Lists the devices of the current sample, the busiest first.  Devices
+/// that weren't in the previous sample, such as new snapshots, are shown
+/// as idle, rather than as having allocated all their blocks at once.
+pub fn activity(prev: &Sample, cur: &Sample) -> Vec<DeviceActivity> {
+    let secs = cur
+        .taken
+        .duration_since(prev.taken)
+        .as_secs_f64()
+        .max(f64::EPSILON);
+
+    let mut devs: Vec<DeviceActivity> = cur
+        .devs
+        .iter()
+        .map(|(dev_id, counts)| {
+            let before = prev.devs.get(dev_id).unwrap_or(counts);
+            DeviceActivity {
+                dev_id: *dev_id,
+                counts: *counts,
+                alloc_rate: (counts.mapped_blocks as f64 - before.mapped_blocks as f64) / secs,
+                unshare_rate: (before.shared_blocks as f64 - counts.shared_blocks as f64).max(0.0)
+                    / secs,
+            }
+        })
+        .collect();
+
+    devs.sort_by(|a, b| {
+        b.busyness()
+            .partial_cmp(&a.busyness())
+            .unwrap()
+            .then(a.dev_id.cmp(&b.dev_id))
+    });
+    devs
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod top_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn counts(mapped_blocks: u64, shared_blocks: u64) -> DeviceCounts {
+        DeviceCounts {
+            mapped_blocks,
+            exclusive_blocks: mapped_blocks - shared_blocks,
+            shared_blocks,
+        }
+    }
+
+    fn mk_sample(taken: Instant, devs: &[(u64, DeviceCounts)]) -> Sample {
+        Sample {
+            taken,
+            transaction_id: 1,
+            data_block_size: 128,
+            devs: devs.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn rates_are_per_second() {
+        let t = Instant::now();
+        let prev = mk_sample(t, &[(1, counts(100, 50))]);
+        let cur = mk_sample(t + Duration::from_secs(2), &[(1, counts(120, 40))]);
+
+        let devs = activity(&prev, &cur);
+        assert_eq!(devs.len(), 1);
+        assert_eq!(devs[0].dev_id, 1);
+        assert_eq!(devs[0].counts, counts(120, 40));
+        assert_eq!(devs[0].alloc_rate, 10.0);
+        assert_eq!(devs[0].unshare_rate, 5.0);
+    }
+
+    #[test]
+    fn unmapping_and_new_sharing_arent_unsharing() {
+        // a discard, and a new snapshot sharing the device's blocks
+        let t = Instant::now();
+        let prev = mk_sample(t, &[(1, counts(100, 0))]);
+        let cur = mk_sample(t + Duration::from_secs(1), &[(1, counts(90, 90))]);
+
+        let devs = activity(&prev, &cur);
+        assert_eq!(devs[0].alloc_rate, -10.0);
+        assert_eq!(devs[0].unshare_rate, 0.0);
+    }
+
+    #[test]
+    fn new_devices_are_idle() {
+        let t = Instant::now();
+        let prev = mk_sample(t, &[(1, counts(10, 0)), (2, counts(10, 0))]);
+        let cur = mk_sample(
+            t + Duration::from_secs(1),
+            &[(1, counts(10, 0)), (3, counts(1000, 10))],
+        );
+
+        // the deleted device is dropped, and idle devices are in id order
+        let devs = activity(&prev, &cur);
+        assert_eq!(
+            devs.iter().map(|a| a.dev_id).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert!(devs.iter().all(|a| a.alloc_rate == 0.0 && a.unshare_rate == 0.0));
+    }
+
+    #[test]
+    fn busiest_devices_come_first() {
+        let t = Instant::now();
+        let prev = mk_sample(
+            t,
+            &[
+                (1, counts(10, 0)),
+                (2, counts(10, 10)),
+                (3, counts(10, 0)),
+                (4, counts(10, 0)),
+            ],
+        );
+        let cur = mk_sample(
+            t + Duration::from_secs(1),
+            &[
+                (1, counts(11, 0)),
+                (2, counts(10, 5)),
+                (3, counts(10, 0)),
+                (4, counts(1, 0)),
+            ],
+        );
+
+        // unmapping counts as much as mapping
+        let devs = activity(&prev, &cur);
+        assert_eq!(
+            devs.iter().map(|a| a.dev_id).collect::<Vec<_>>(),
+            vec![4, 2, 1, 3]
+        );
+    }
+
+    #[test]
+    fn a_sample_is_idle_against_itself() {
+        let prev = mk_sample(Instant::now(), &[(1, counts(10, 5))]);
+        let devs = activity(&prev, &prev);
+        assert_eq!(devs[0].alloc_rate, 0.0);
+        assert_eq!(devs[0].unshare_rate, 0.0);
+    }
+}
+
+//------------------------------------------
@@ -1,12 +1,15 @@
 use anyhow::{anyhow, Result};
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
 use std::ops::Range;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::commands::engine::*;
-use crate::file_utils::file_size;
+use crate::file_utils::{self, file_size};
 use crate::io_engine::*;
 use crate::ioctl::{self, *};
 use crate::pdata::btree_walker::*;
@@ -14,6 +17,7 @@ use crate::pdata::space_map::common::*;
 use crate::pdata::unpack::unpack;
 use crate::report::Report;
 use crate::thin::superblock::{read_superblock, Superblock, SUPERBLOCK_LOCATION};
+use crate::thin::trim_journal::TrimJournal;
 
 //------------------------------------------
 
@@ -165,33 +169,301 @@ fn read_bitmaps(engine: Arc<dyn IoEngine + Send + Sync>, bitmap_root: u64) -> Re
     Ok(bitmaps)
 }
 
-fn trim_data_device(ctx: &Context, sb: &Superblock, data_dev: &Path) -> Result<()> {
-    let root = unpack::<SMRoot>(&sb.data_sm_root[..])?;
-    let bs = (sb.data_block_size as u64) << SECTOR_SHIFT; // in bytes
-    let expected = root.nr_blocks * bs;
-    if expected > file_size(data_dev)? {
+// Spaces the discards out to keep them under a rate, so they don't starve
+// the foreground io of the data device.  As with ThrottledIoEngine, time
+// spent idle isn't banked.
+struct DiscardPacer {
+    bytes_per_sec: u64,
+    start: Instant,
+    nr_bytes: u64,
+}
+
+impl DiscardPacer {
+    fn new(bytes_per_sec: u64) -> Self {
+        DiscardPacer {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            nr_bytes: 0,
+        }
+    }
+
+    fn wait_for(&mut self, nr_bytes: u64) {
+        let now = Instant::now();
+        let earned =
+            now.duration_since(self.start).as_nanos() * self.bytes_per_sec as u128 / 1_000_000_000;
+        if earned > self.nr_bytes as u128 {
+            // we've fallen behind the rate, start a new burst
+            self.start = now;
+            self.nr_bytes = 0;
+        }
+        self.nr_bytes += nr_bytes;
+        let nanos = self.nr_bytes as u128 * 1_000_000_000 / self.bytes_per_sec as u128;
+        let due = self.start + Duration::from_nanos(nanos as u64);
+
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+}
+
+/// How the discard granularity of the data device is found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscardGranularity {
+    /// Read the granularity and alignment of the device from sysfs
+    Device,
+    /// A granularity in bytes, aligned to the start of the device
+    Bytes(u64),
+}
+
+// The granules a device acts on discards of, in bytes.  Devices that work
+// in units larger than a data block quietly ignore the parts of a discard
+// that don't cover whole granules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct DiscardLimits {
+    granularity: u64,
+    alignment: u64, // the offset of the first granule
+}
+
+impl DiscardLimits {
+    fn new(granularity: u64, alignment: u64) -> Result<Self> {
+        if granularity == 0 || granularity % (1 << SECTOR_SHIFT) != 0 {
+            return Err(anyhow!(
+                "the discard granularity must be a non-zero multiple of 512 bytes"
+            ));
+        }
+        Ok(DiscardLimits {
+            granularity,
+            alignment: alignment % granularity,
+        })
+    }
+
+    // The whole granules within a range of bytes, which may be none
+    fn shrink(&self, r: &Range<u64>) -> Range<u64> {
+        let (g, a) = (self.granularity, self.alignment);
+        let start = if r.start <= a {
+            a
+        } else {
+            a + (r.start - a + g - 1) / g * g
+        };
+        if r.end < a + g {
+            return start..start;
+        }
+        let end = a + (r.end - a) / g * g;
+        start..end.max(start)
+    }
+}
+
+fn read_sysfs_u64(path: &str) -> std::io::Result<u64> {
+    let v = std::fs::read_to_string(path)?;
+    v.trim()
+        .parse::<u64>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// Partitions share the request queue of their disk, so its limits are
+// found in the parent directory
+fn sysfs_discard_limits(dev: &Path) -> Result<DiscardLimits> {
+    let md = std::fs::metadata(dev)?;
+    if !md.file_type().is_block_device() {
         return Err(anyhow!(
-            "unexpected data device size, wanted {} bytes",
-            expected
+            "'{}' isn't a block device, give the granularity with --discard-granularity",
+            dev.display()
         ));
     }
 
-    let bitmaps = read_bitmaps(ctx.engine.clone(), root.bitmap_root)?;
+    let rdev = md.rdev();
+    let dir = format!("/sys/dev/block/{}:{}", libc::major(rdev), libc::minor(rdev));
+    let granularity = read_sysfs_u64(&format!("{}/queue/discard_granularity", dir))
+        .or_else(|_| read_sysfs_u64(&format!("{}/../queue/discard_granularity", dir)))
+        .map_err(|e| {
+            anyhow!(
+                "couldn't read the discard granularity of the data device: {}",
+                e
+            )
+        })?;
+    if granularity == 0 {
+        return Err(anyhow!("the data device doesn't support discards"));
+    }
+    let alignment = read_sysfs_u64(&format!("{}/discard_alignment", dir)).unwrap_or(0);
+
+    DiscardLimits::new(granularity, alignment)
+}
+
+fn get_discard_limits(opts: &ThinTrimOptions) -> Result<Option<DiscardLimits>> {
+    match opts.discard_granularity {
+        None => Ok(None),
+        Some(DiscardGranularity::Device) => sysfs_discard_limits(opts.data_dev).map(Some),
+        Some(DiscardGranularity::Bytes(g)) => DiscardLimits::new(g, 0).map(Some),
+    }
+}
+
+// The bytes of a run of free blocks that a discard would act on
+fn discardable(limits: Option<&DiscardLimits>, blocks: &Range<u64>, bs: u64) -> Range<u64> {
+    let bytes = blocks.start * bs..blocks.end * bs;
+    match limits {
+        Some(limits) => limits.shrink(&bytes),
+        None => bytes,
+    }
+}
+
+struct Discarder<'a> {
+    ctx: &'a Context,
+    dev: &'a File,
+    is_file: bool,    // a pool on a file has its free blocks punched out
+    bs: u64,          // in bytes
+    span: Range<u64>, // the blocks being trimmed, for the progress
+    limits: Option<DiscardLimits>,
+    pacer: Option<DiscardPacer>,
+    journal: Option<TrimJournal>,
+    chunk: u64,           // the most bytes discarded at once
+    percent: Option<u64>, // the progress last reported
+}
+
+// Journaled discards are split, so a trim interrupted part way through a
+// run of free blocks doesn't need to discard it all again
+const JOURNAL_CHUNK: u64 = 1 << 30;
+
+impl<'a> Discarder<'a> {
+    fn new(
+        ctx: &'a Context,
+        dev: &'a File,
+        bs: u64,
+        span: Range<u64>,
+        limits: Option<DiscardLimits>,
+        rate: Option<u64>,
+        journal: Option<TrimJournal>,
+    ) -> Result<Self> {
+        // Throttled discards are split, so that each takes about a tenth
+        // of a second of the rate.  The pieces stay whole granules, so
+        // they're as aligned as the discard they were split from.
+        let unit = limits.map_or(bs, |l| l.granularity);
+        let mut chunk = rate.map_or(u64::MAX, |r| (r / 10 / unit).max(1) * unit);
+        if journal.is_some() {
+            chunk = chunk.min((JOURNAL_CHUNK / unit).max(1) * unit);
+        }
+        Ok(Discarder {
+            ctx,
+            dev,
+            is_file: dev.metadata()?.file_type().is_file(),
+            bs,
+            span,
+            limits,
+            pacer: rate.map(DiscardPacer::new),
+            journal,
+            chunk,
+            percent: None,
+        })
+    }
+
+    fn discard_bytes(&self, offset: u64, len: u64) -> Result<()> {
+        if !self.is_file {
+            ioctl_blkdiscard(self.dev.as_raw_fd(), &[offset, len])?;
+        } else if !file_utils::punch_hole(self.dev, offset, len)? {
+            return Err(anyhow!(
+                "the file system of the data device can't punch holes"
+            ));
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, blocks: Range<u64>) -> Result<()> {
+        let bytes = discardable(self.limits.as_ref(), &blocks, self.bs);
+        if bytes.is_empty() {
+            self.ctx.report.debug(&format!(
+                "skipping blocks [{}, {}], they don't cover a whole discard granule",
+                blocks.start,
+                blocks.end - 1
+            ));
+            return Ok(());
+        }
+
+        self.ctx.report.debug(&format!(
+            "emitting discard for blocks [{}, {}]",
+            blocks.start,
+            blocks.end - 1
+        ));
+
+        let mut b = bytes.start;
+        while b < bytes.end {
+            let len = (bytes.end - b).min(self.chunk);
+            if let Some(pacer) = self.pacer.as_mut() {
+                pacer.wait_for(len);
+            }
+            self.discard_bytes(b, len)?;
+            b += len;
+            self.progress(b / self.bs);
+            self.advance(b / self.bs)?;
+        }
+        Ok(())
+    }
+
+    // Records that the free blocks before this one have been discarded
+    fn advance(&mut self, block: u64) -> Result<()> {
+        match self.journal.as_mut() {
+            Some(journal) => journal.advance(block),
+            None => Ok(()),
+        }
+    }
+
+    // Saves the position of the journal, so a trim that failed part way
+    // through resumes from the last discard done
+    fn save(&mut self) -> Result<()> {
+        match self.journal.as_mut() {
+            Some(journal) => journal.save(),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.journal {
+            Some(journal) => journal.finish(),
+            None => Ok(()),
+        }
+    }
+
+    // Only reported as each percent is passed, as there may be millions of
+    // free runs, and a record for each would flood the progress fd
+    fn progress(&mut self, done: u64) {
+        let total = self.span.end - self.span.start;
+        let completed = (done.max(self.span.start) - self.span.start).min(total);
+        let percent = completed * 100 / total.max(1);
+        if self.percent.map_or(true, |p| percent > p) {
+            self.percent = Some(percent);
+            self.ctx.report.progress_of(completed, total);
+        }
+    }
+}
+
+// Calls the function with each run of free data blocks within the span,
+// in order
+fn for_each_free_range<F>(
+    bitmaps: &[Block],
+    nr_blocks: u64,
+    span: &Range<u64>,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(Range<u64>) -> Result<()>,
+{
+    let mut emit = |r: Range<u64>| {
+        let r = r.start.max(span.start)..r.end.min(span.end);
+        if r.is_empty() {
+            Ok(())
+        } else {
+            f(r)
+        }
+    };
 
-    let dev_file = OpenOptions::new().read(false).write(true).open(data_dev)?;
-    let fd = dev_file.as_raw_fd();
     let mut last_seen = 0;
-    for r in RangeIterator::new(&bitmaps[..], root.nr_blocks)? {
+    for r in RangeIterator::new(bitmaps, nr_blocks)? {
         match r {
             Ok(range) => {
+                if last_seen >= span.end {
+                    break;
+                }
                 if range.start > last_seen {
-                    let len = range.start - last_seen;
-                    ctx.report.debug(&format!(
-                        "emitting discard for blocks [{}, {}]",
-                        last_seen,
-                        last_seen + len - 1
-                    ));
-                    ioctl_blkdiscard(fd, &[last_seen * bs, len * bs])?;
+                    emit(last_seen..range.start)?;
                 }
                 last_seen = range.end;
             }
@@ -199,10 +471,120 @@ fn trim_data_device(ctx: &Context, sb: &Superblock, data_dev: &Path) -> Result<(
         }
     }
 
-    if root.nr_blocks > last_seen {
-        let len = root.nr_blocks - last_seen;
-        ioctl_blkdiscard(fd, &[last_seen * bs, len * bs])?;
+    if nr_blocks > last_seen {
+        emit(last_seen..nr_blocks)?;
+    }
+    Ok(())
+}
+
+// Prints the free runs that would be discarded, and their total.  The bytes
+// are those the device would act on, once the runs are aligned.
+fn report_reclaimable(
+    bitmaps: &[Block],
+    nr_blocks: u64,
+    span: &Range<u64>,
+    bs: u64,
+    limits: Option<&DiscardLimits>,
+) -> Result<()> {
+    let mut out = BufWriter::new(std::io::stdout());
+    let mut nr_free = 0;
+    let mut nr_bytes = 0;
+    for_each_free_range(bitmaps, nr_blocks, span, |r| {
+        let len = r.end - r.start;
+        let bytes = discardable(limits, &r, bs);
+        let bytes = bytes.end - bytes.start;
+        writeln!(
+            out,
+            "blocks {}..{}: {} blocks, {} bytes",
+            r.start, r.end, len, bytes
+        )?;
+        nr_free += len;
+        nr_bytes += bytes;
+        Ok(())
+    })?;
+    writeln!(out, "total: {} blocks, {} bytes", nr_free, nr_bytes)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn trim_data_device(ctx: &Context, sb: &Superblock, opts: &ThinTrimOptions) -> Result<()> {
+    let root = unpack::<SMRoot>(&sb.data_sm_root[..])?;
+    let bs = (sb.data_block_size as u64) << SECTOR_SHIFT; // in bytes
+    let expected = root.nr_blocks * bs;
+    if expected > file_size(opts.data_dev)? {
+        return Err(anyhow!(
+            "unexpected data device size, wanted {} bytes",
+            expected
+        ));
+    }
+
+    let span = match &opts.range {
+        Some(r) if r.end > root.nr_blocks => {
+            return Err(anyhow!(
+                "the range {}..{} is beyond the end of the data device, which has {} blocks",
+                r.start,
+                r.end,
+                root.nr_blocks
+            ));
+        }
+        Some(r) => r.clone(),
+        None => 0..root.nr_blocks,
+    };
+
+    let limits = get_discard_limits(opts)?;
+    let bitmaps = read_bitmaps(ctx.engine.clone(), root.bitmap_root)?;
+
+    if opts.dry_run {
+        return report_reclaimable(&bitmaps, root.nr_blocks, &span, bs, limits.as_ref());
+    }
+
+    let journal = match opts.journal {
+        Some(path) => {
+            let journal = TrimJournal::open(path, sb.transaction_id, root.nr_blocks, &span)?;
+            if journal.resumed() {
+                ctx.report.info(&format!(
+                    "resuming the trim from block {}",
+                    journal.next_block()
+                ));
+            }
+            Some(journal)
+        }
+        None => None,
+    };
+    let todo = journal.as_ref().map_or(span.start, |j| j.next_block())..span.end;
+
+    let dev_file = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .open(opts.data_dev)?;
+    let mut discarder = Discarder::new(
+        ctx,
+        &dev_file,
+        bs,
+        span.clone(),
+        limits,
+        opts.max_discard_rate,
+        journal,
+    )?;
+
+    ctx.report.set_title("Discarding free data blocks");
+    discarder.progress(todo.start);
+    let result = for_each_free_range(&bitmaps, root.nr_blocks, &todo, |r| {
+        let end = r.end;
+        discarder.discard(r)?;
+        discarder.progress(end);
+        discarder.advance(end)
+    });
+    if let Err(e) = result {
+        if let Err(save_err) = discarder.save() {
+            ctx.report
+                .warning(&format!("couldn't save the trim journal: {:#}", save_err));
+        }
+        return Err(e);
     }
+    discarder.progress(span.end);
+    discarder.finish()?;
+    ctx.report.complete();
 
     Ok(())
 }
@@ -213,6 +595,16 @@ pub struct ThinTrimOptions<'a> {
     pub metadata_dev: &'a Path,
     pub data_dev: &'a Path,
     pub engine_opts: EngineOptions,
+    /// Limit the rate discards are issued at, in bytes per second
+    pub max_discard_rate: Option<u64>,
+    /// Round discards in to whole granules of the data device
+    pub discard_granularity: Option<DiscardGranularity>,
+    /// Only trim the free data blocks within this range
+    pub range: Option<Range<u64>>,
+    /// Record the progress of the trim here, and resume from it
+    pub journal: Option<&'a Path>,
+    /// Print the space that would be discarded, rather than discarding it
+    pub dry_run: bool,
     pub report: Arc<Report>,
 }
 
@@ -235,7 +627,45 @@ pub fn trim(opts: ThinTrimOptions) -> Result<()> {
     let ctx = mk_context(&opts)?;
     let sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
 
-    trim_data_device(&ctx, &sb, opts.data_dev)
+    trim_data_device(&ctx, &sb, &opts)
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod trim_tests {
+    use super::*;
+
+    #[test]
+    fn pacer_holds_discards_to_the_rate() {
+        // a tenth of a second's worth at 1MB/s, twice over
+        let mut pacer = DiscardPacer::new(1_000_000);
+        let start = Instant::now();
+        pacer.wait_for(100_000);
+        pacer.wait_for(100_000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn pacer_doesnt_bank_idle_time() {
+        let mut pacer = DiscardPacer::new(1_000_000);
+        std::thread::sleep(Duration::from_millis(200));
+
+        // the idle time doesn't pay for this one
+        let start = Instant::now();
+        pacer.wait_for(100_000);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn shrinks_runs_to_whole_granules() {
+        let limits = DiscardLimits::new(4096, 1024).unwrap();
+        assert_eq!(limits.shrink(&(0..8192)), 1024..5120);
+        assert_eq!(limits.shrink(&(2048..6144)), 5120..5120);
+        assert!(DiscardLimits::new(1000, 0).is_err());
+    }
 }
 
 //------------------------------------------
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+//------------------------------------------
+
+// A record of how far a trim got, so an interrupted trim of a huge data
+// device can carry on from there rather than discard it all again.  The
+// file holds a single line, replaced as the trim goes on, naming the
+// metadata and range being trimmed and the block up to which every free
+// run has been discarded.
+//
+// Discards are idempotent, so the worst an out of date journal can do is
+// leave some free space untrimmed until the next full trim.
+
+const HEADER: &str = "# transaction_id nr_data_blocks begin end next_block";
+
+// Saving the position more often would only slow the trim
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct JournalEntry {
+    transaction_id: u64,
+    nr_blocks: u64,
+    span: Range<u64>,
+    next_block: u64,
+}
+
+impl JournalEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.transaction_id, self.nr_blocks, self.span.start, self.span.end, self.next_block
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let fields = line
+            .split_whitespace()
+            .map(|f| f.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()?;
+        if fields.len() != 5 {
+            return Err(anyhow!("expected 5 fields"));
+        }
+        Ok(JournalEntry {
+            transaction_id: fields[0],
+            nr_blocks: fields[1],
+            span: fields[2]..fields[3],
+            next_block: fields[4],
+        })
+    }
+}
+
+fn read_entry(path: &Path) -> Result<Option<JournalEntry>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let line = contents
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('#'));
+    match line {
+        Some(line) => JournalEntry::from_line(line)
+            .map(Some)
+            .with_context(|| format!("bad trim journal '{}'", path.display())),
+        None => Ok(None),
+    }
+}
+
+pub struct TrimJournal {
+    path: PathBuf,
+    entry: JournalEntry,
+    resumed: bool,
+    last_saved: Instant,
+}
+
+impl TrimJournal {
+    /// Opens the journal of a trim of the span, picking up the position of
+    /// an earlier trim of the same metadata and span.  A journal left by
+    /// any other trim is started afresh.
+    pub fn open(
+        path: &Path,
+        transaction_id: u64,
+        nr_blocks: u64,
+        span: &Range<u64>,
+    ) -> Result<Self> {
+        let mut entry = JournalEntry {
+            transaction_id,
+            nr_blocks,
+            span: span.clone(),
+            next_block: span.start,
+        };
+
+        let mut resumed = false;
+        if let Some(old) = read_entry(path)? {
+            let next_block = old.next_block;
+            let old = JournalEntry {
+                next_block: span.start,
+                ..old
+            };
+            // a trim that completed, but wasn't able to remove its
+            // journal, resumes from the end of the span
+            if old == entry && span.start <= next_block && next_block <= span.end {
+                entry.next_block = next_block;
+                resumed = true;
+            }
+        }
+
+        Ok(TrimJournal {
+            path: path.to_path_buf(),
+            entry,
+            resumed,
+            last_saved: Instant::now(),
+        })
+    }
+
+    /// The first block left to trim
+    pub fn next_block(&self) -> u64 {
+        self.entry.next_block
+    }
+
+    /// Whether the position was taken from an earlier trim
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Saves the position now, eg. before giving up on a failed trim.
+    /// The journal is written aside and renamed over the old one, so a
+    /// crash leaves either the old position or the new one.
+    pub fn save(&mut self) -> Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut file = File::create(&tmp)
+            .with_context(|| format!("couldn't write the trim journal '{}'", tmp.display()))?;
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "{}", self.entry.to_line())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        self.last_saved = Instant::now();
+        Ok(())
+    }
+
+    /// Records that every free run before the block has been discarded
+    pub fn advance(&mut self, next_block: u64) -> Result<()> {
+        self.entry.next_block = next_block;
+        if self.last_saved.elapsed() >= SAVE_INTERVAL {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the journal once the trim is complete, so the next trim
+    /// starts from the beginning
+    pub fn finish(self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod trim_journal_tests {
+    use super::*;
+    use crate::file_utils::TempFile;
+
+    #[test]
+    fn trims_of_the_same_metadata_resume() -> Result<()> {
+        // an empty journal, as if it had just been created
+        let tmp = TempFile::new(&std::env::temp_dir())?;
+        let path = tmp.path();
+
+        let mut journal = TrimJournal::open(path, 3, 100, &(0..100))?;
+        assert!(!journal.resumed());
+        journal.advance(40)?;
+        journal.save()?;
+
+        let journal = TrimJournal::open(path, 3, 100, &(0..100))?;
+        assert!(journal.resumed());
+        assert_eq!(journal.next_block(), 40);
+
+        // the metadata has changed since
+        let journal = TrimJournal::open(path, 4, 100, &(0..100))?;
+        assert!(!journal.resumed());
+        assert_eq!(journal.next_block(), 0);
+
+        // a trim that reached the end of the span
+        let mut journal = TrimJournal::open(path, 5, 100, &(0..100))?;
+        journal.advance(100)?;
+        journal.save()?;
+        let journal = TrimJournal::open(path, 5, 100, &(0..100))?;
+        assert!(journal.resumed());
+        assert_eq!(journal.next_block(), 100);
+
+        journal.finish()?;
+        assert!(!path.exists());
+        Ok(())
+    }
+}
+
+//------------------------------------------
@@ -63,6 +63,13 @@ impl<T: Clone> HashVec<T> {
     pub fn values(&self) -> std::slice::Iter<T> {
         self.entries.iter()
     }
+
+    /// Iterates the (index, value) pairs in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &T)> {
+        self.map
+            .iter()
+            .map(|(index, i)| (*index, &self.entries[*i as usize]))
+    }
 }
 
 //------------------------------------------
@@ -1,6 +1,8 @@
 use anyhow::{anyhow, Result};
 use rangemap::RangeSet;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::checksum;
 use crate::io_engine::*;
@@ -11,6 +13,55 @@ mod tests;
 
 //------------------------------------------
 
+/// How often the written blocks are forced to stable storage.  Syncing
+/// during a long restore bounds how much must be rewritten after a crash,
+/// and spreads out the flushing that would otherwise stall the device at
+/// the end.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave the syncing to the caller
+    #[default]
+    Never,
+    /// Sync after every so many blocks are written
+    Blocks(u64),
+    /// Sync once this long has passed since the last sync
+    Interval(Duration),
+}
+
+// Parsed from a number of blocks, or a number of seconds suffixed with 's'
+impl FromStr for SyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (n, secs) = match s.strip_suffix('s') {
+            Some(n) => (n, true),
+            None => (s, false),
+        };
+        let n = match n.parse::<u64>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                return Err(anyhow!(
+                    "invalid sync interval '{}', expected a number of blocks or seconds, eg. 65536 or 30s",
+                    s
+                ))
+            }
+        };
+
+        if secs {
+            Ok(SyncPolicy::Interval(Duration::from_secs(n)))
+        } else {
+            Ok(SyncPolicy::Blocks(n))
+        }
+    }
+}
+
+// The batch size used for a deterministic layout, whatever the engine
+// suggests.  It only keeps the order of the writes the same, the blocks
+// allocated don't depend on it.
+const DETERMINISTIC_BATCH_SIZE: usize = 64;
+
+//------------------------------------------
+
 pub struct WriteBatcher {
     pub engine: Arc<dyn IoEngine + Send + Sync>,
 
@@ -24,6 +75,13 @@ pub struct WriteBatcher {
     // The blocks in allocations doesn't necessarily have non-zero ref counts,
     // if the caller returns the allocated blocks via SpaceMap::dec().
     allocations: RangeSet<u64>,
+
+    sync_policy: SyncPolicy,
+    nr_unsynced: u64,
+    last_sync: Instant,
+
+    // Zero every allocated block, not only those asked for zeroed
+    zero_allocations: bool,
 }
 
 impl WriteBatcher {
@@ -38,26 +96,35 @@ impl WriteBatcher {
             batch_size,
             queue: Vec::with_capacity(batch_size),
             allocations: RangeSet::<u64>::new(),
+            sync_policy: SyncPolicy::Never,
+            nr_unsynced: 0,
+            last_sync: Instant::now(),
+            zero_allocations: false,
         }
     }
 
-    pub fn alloc(&mut self) -> Result<Block> {
-        let mut sm = self.sm.lock().unwrap();
-        let b = sm.alloc()?;
-        if b.is_none() {
-            return Err(anyhow!("out of metadata space"));
-        }
-
-        let loc = b.unwrap();
-        self.allocations.insert(std::ops::Range {
-            start: loc,
-            end: loc + 1,
-        });
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> WriteBatcher {
+        self.sync_policy = sync_policy;
+        self
+    }
 
-        Ok(Block::new(loc))
+    /// Makes the blocks written depend only on what's pushed to the
+    /// builders, so restoring the same input gives byte identical metadata
+    /// on any machine.  The blocks are already allocated in the same order
+    /// whatever the engine; what differs is the unused tails of nodes,
+    /// which hold whatever memory the allocator handed out unless every
+    /// allocated block is zeroed, as it is here.  The writes are also
+    /// flushed in batches of a fixed size.
+    pub fn with_deterministic_layout(mut self, deterministic: bool) -> WriteBatcher {
+        if deterministic {
+            self.zero_allocations = true;
+            self.batch_size = DETERMINISTIC_BATCH_SIZE;
+            self.queue = Vec::with_capacity(self.batch_size);
+        }
+        self
     }
 
-    pub fn alloc_zeroed(&mut self) -> Result<Block> {
+    fn alloc_(&mut self, zeroed: bool) -> Result<Block> {
         let mut sm = self.sm.lock().unwrap();
         let b = sm.alloc()?;
         if b.is_none() {
@@ -70,7 +137,19 @@ impl WriteBatcher {
             end: loc + 1,
         });
 
-        Ok(Block::zeroed(loc))
+        if zeroed || self.zero_allocations {
+            Ok(Block::zeroed(loc))
+        } else {
+            Ok(Block::new(loc))
+        }
+    }
+
+    pub fn alloc(&mut self) -> Result<Block> {
+        self.alloc_(false)
+    }
+
+    pub fn alloc_zeroed(&mut self) -> Result<Block> {
+        self.alloc_(true)
     }
 
     pub fn clear_allocations(&mut self) -> RangeSet<u64> {
@@ -116,6 +195,18 @@ impl WriteBatcher {
 
     fn flush_(&mut self, queue: Vec<Block>) -> Result<()> {
         self.engine.write_many(&queue)?;
+        self.nr_unsynced += queue.len() as u64;
+
+        let due = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Blocks(n) => self.nr_unsynced >= n,
+            SyncPolicy::Interval(d) => self.last_sync.elapsed() >= d,
+        };
+        if due {
+            self.engine.sync()?;
+            self.nr_unsynced = 0;
+            self.last_sync = Instant::now();
+        }
         Ok(())
     }
 
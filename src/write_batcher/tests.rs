@@ -20,6 +20,7 @@ mock! {
         fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>>;
         fn write(&self, block: &Block) -> io::Result<()>;
         fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>>;
+        fn sync(&self) -> io::Result<()>;
     }
 }
 
@@ -94,6 +95,65 @@ fn writes_should_be_performed_in_batch() {
     }
 }
 
+#[test]
+fn syncs_after_the_given_number_of_blocks() {
+    let mut engine = MockEngine::new();
+    engine.expect_write_many().times(4).returning(|blocks| {
+        let mut ret = Vec::new();
+        ret.resize_with(blocks.len(), || Ok(()));
+        Ok(ret)
+    });
+    engine.expect_sync().times(2).returning(|| Ok(()));
+
+    let sm = Arc::new(Mutex::new(MockTestSpaceMap::new()));
+
+    let mut w =
+        WriteBatcher::new(Arc::new(engine), sm, 16).with_sync_policy(SyncPolicy::Blocks(32));
+    for i in 0..64 {
+        let b = Block::zeroed(i);
+        assert!(w.write(b, BT::NODE).is_ok());
+    }
+    assert!(w.flush().is_ok());
+}
+
+#[test]
+fn deterministic_layout_zeroes_blocks_and_fixes_batches() {
+    let mut engine = MockEngine::new();
+    engine
+        .expect_write_many()
+        .withf(|blocks: &[Block]| blocks.len() == DETERMINISTIC_BATCH_SIZE)
+        .times(1)
+        .returning(|blocks| {
+            let mut ret = Vec::new();
+            ret.resize_with(blocks.len(), || Ok(()));
+            Ok(ret)
+        });
+
+    let sm = Arc::new(Mutex::new(CoreSpaceMap::<u8>::new(NR_BLOCKS)));
+    let mut w = WriteBatcher::new(Arc::new(engine), sm, 16).with_deterministic_layout(true);
+    for _i in 0..DETERMINISTIC_BATCH_SIZE {
+        let b = w.alloc().unwrap();
+        assert!(b.get_data()[4..].iter().all(|v| *v == 0));
+        assert!(w.write(b, BT::NODE).is_ok());
+    }
+    assert!(w.flush().is_ok());
+}
+
+#[test]
+fn parses_sync_policies() {
+    assert_eq!(
+        "4096".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Blocks(4096)
+    );
+    assert_eq!(
+        "30s".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Interval(Duration::from_secs(30))
+    );
+    for s in ["", "0", "s", "10m", "-1"] {
+        assert!(s.parse::<SyncPolicy>().is_err(), "'{}' was accepted", s);
+    }
+}
+
 #[test]
 fn write_hit() {
     type SeedType = <SmallRng as rand::SeedableRng>::Seed;
@@ -1,8 +1,14 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
+use flate2::read::MultiGzDecoder;
 use quick_xml::events::attributes::Attribute;
 use quick_xml::name::QName;
 use std::borrow::Cow;
 use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
 
 //------------------------------------------
 
@@ -68,3 +74,85 @@ fn mk_attr_<'a, T: Display>(n: T) -> Cow<'a, [u8]> {
 }
 
 //------------------------------------------
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// Reads the output of a zstd process decompressing the input, which is fed
+// to it by a thread.  The process is waited for at the end of the output,
+// so a failed decompression is an error rather than a short read.
+struct ZstdDecoder {
+    child: Child,
+    stdout: ChildStdout,
+    feeder: Option<JoinHandle<io::Result<u64>>>,
+}
+
+impl ZstdDecoder {
+    fn new(mut input: BufReader<Box<dyn Read + Send>>) -> anyhow::Result<ZstdDecoder> {
+        let mut child = Command::new("zstd")
+            .arg("-dcq")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("couldn't run zstd to decompress the input")?;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let feeder = thread::spawn(move || io::copy(&mut input, &mut stdin));
+
+        Ok(ZstdDecoder {
+            child,
+            stdout,
+            feeder: Some(feeder),
+        })
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(feeder) = self.feeder.take() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("zstd couldn't decompress the input ({})", status),
+                ));
+            }
+            feeder.join().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "zstd input thread panicked")
+            })??;
+        }
+        Ok(())
+    }
+}
+
+impl Read for ZstdDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(n)
+    }
+}
+
+/// Opens an xml input, which may be '-' to read from stdin.  Dumps are often
+/// compressed to be copied between hosts, so gzip and zstd input is
+/// decompressed.  There's no zstd decoder among the dependencies, so zstd
+/// input is piped through the zstd tool.
+pub fn open_input(path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    let input: Box<dyn Read + Send> = if path == Path::new("-") {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+
+    let mut input = BufReader::new(input);
+    let magic = input.fill_buf()?;
+    if magic.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(input)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(ZstdDecoder::new(input)?))
+    } else {
+        Ok(Box::new(input))
+    }
+}
+
+//------------------------------------------
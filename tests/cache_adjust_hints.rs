@@ -0,0 +1,257 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use thinp::cache::ir::{self, MetadataVisitor, Visit};
+use thinp::cache::xml;
+
+mod common;
+
+use common::cache::*;
+use common::common_args::*;
+use common::fixture::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "Rewrite the policy hints of an inactive cache
+
+Usage: cache_adjust_hints [OPTIONS] <--reset|--import <FILE>|--promote <BLOCK_RANGE>> <INPUT>
+
+Arguments:
+  <INPUT>  Specify the input device
+
+Options:
+  -h, --help                   Print help
+      --import <FILE>          Import the levels of cache blocks from a file
+      --level <NUM>            Specify the level given to promoted blocks [default: 63]
+      --overlay <FILE>         Keep the writes to the metadata in an overlay file, and read through it
+      --promote <BLOCK_RANGE>  Promote the cache blocks holding a range of origin blocks
+  -q, --quiet                  Suppress output messages, return only exit code.
+      --reset                  Reset the hints of all the cache blocks to the lowest level
+  -V, --version                Print version";
+
+//------------------------------------------
+
+struct CacheAdjustHints;
+
+impl<'a> Program<'a> for CacheAdjustHints {
+    fn name() -> &'a str {
+        "cache_adjust_hints"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        cache_adjust_hints_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(CacheAdjustHints);
+test_accepts_version!(CacheAdjustHints);
+test_rejects_bad_option!(CacheAdjustHints);
+
+//------------------------------------------
+
+#[derive(Default)]
+struct Collector {
+    mappings: BTreeMap<u32, u64>,
+    levels: BTreeMap<u32, u32>,
+}
+
+impl MetadataVisitor for Collector {
+    fn superblock_b(&mut self, _sb: &ir::Superblock) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn mappings_b(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn mappings_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn mapping(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.mappings.insert(m.cblock, m.oblock);
+        Ok(Visit::Continue)
+    }
+
+    fn hints_b(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn hints_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn hint(&mut self, h: &ir::Hint) -> Result<Visit> {
+        let level = u32::from_le_bytes(h.data[0..4].try_into()?);
+        self.levels.insert(h.cblock, level);
+        Ok(Visit::Continue)
+    }
+
+    fn discards_b(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn discards_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn discard(&mut self, _d: &ir::Discard) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+fn read_metadata(md: &Path) -> Result<Collector> {
+    let output = run_ok_raw(cache_dump_cmd(args![md]))?;
+    let mut collector = Collector::default();
+    xml::read(&output.stdout[..], &mut collector)?;
+    Ok(collector)
+}
+
+//------------------------------------------
+
+#[test]
+fn resets_all_hints() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_ok(cache_adjust_hints_cmd(args!["--reset", &md]))?;
+
+    let after = read_metadata(&md)?;
+    assert!(!after.levels.is_empty());
+    assert!(after.levels.values().all(|level| *level == 0));
+    run_ok(cache_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn promotes_origin_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_ok(cache_adjust_hints_cmd(args![
+        "--reset",
+        "--promote",
+        "0..16384",
+        "--level",
+        "40",
+        &md
+    ]))?;
+
+    let after = read_metadata(&md)?;
+    let mut nr_promoted = 0;
+    for (cblock, oblock) in &after.mappings {
+        let expected = if *oblock < 16384 { 40 } else { 0 };
+        assert_eq!(after.levels.get(cblock), Some(&expected));
+        if expected > 0 {
+            nr_promoted += 1;
+        }
+    }
+    assert!(nr_promoted > 0);
+    run_ok(cache_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn imports_levels() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let before = read_metadata(&md)?;
+    let cblocks: Vec<u32> = before.mappings.keys().take(3).cloned().collect();
+
+    let import = td.mk_path("levels.txt");
+    let contents = format!(
+        "# cache block, level\n{},7\n{}\t12\n\n{} 63\n",
+        cblocks[0], cblocks[1], cblocks[2]
+    );
+    std::fs::write(&import, contents)?;
+    run_ok(cache_adjust_hints_cmd(args!["--import", &import, &md]))?;
+
+    let after = read_metadata(&md)?;
+    assert_eq!(after.levels.get(&cblocks[0]), Some(&7));
+    assert_eq!(after.levels.get(&cblocks[1]), Some(&12));
+    assert_eq!(after.levels.get(&cblocks[2]), Some(&63));
+    for (cblock, level) in &before.levels {
+        if !cblocks.contains(cblock) {
+            assert_eq!(after.levels.get(cblock), Some(level));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn rejects_import_beyond_cache() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let import = td.mk_path("levels.txt");
+    std::fs::write(&import, "4096 1\n")?;
+    let stderr = run_fail(cache_adjust_hints_cmd(args!["--import", &import, &md]))?;
+    assert!(stderr.contains("beyond the 4096 cache blocks"));
+    Ok(())
+}
+
+#[test]
+fn rejects_import_above_the_highest_level() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let import = td.mk_path("levels.txt");
+    std::fs::write(&import, "0 64\n")?;
+    ensure_untouched(&md, || {
+        let stderr = run_fail(cache_adjust_hints_cmd(args!["--import", &import, &md]))?;
+        assert!(stderr.contains("level 64 is above the highest level, 63"));
+        Ok(())
+    })
+}
+
+#[test]
+fn rejects_other_policies() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let xml = td.mk_path("meta.xml");
+    let dumped = run_ok(cache_dump_cmd(args![&md]))?;
+    std::fs::write(&xml, dumped.replace("policy=\"smq\"", "policy=\"custom\""))?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md2]))?;
+
+    let stderr = run_fail(cache_adjust_hints_cmd(args!["--reset", &md2]))?;
+    assert!(stderr.contains("the hints of the custom policy aren't levels"));
+    Ok(())
+}
+
+#[test]
+fn requires_an_adjustment() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(cache_adjust_hints_cmd(args![&md]))?;
+    Ok(())
+}
+
+//------------------------------------------
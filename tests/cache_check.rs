@@ -8,6 +8,7 @@ use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
 use common::program::*;
+use common::progress::*;
 use common::target::*;
 use common::test_dir::*;
 
@@ -25,6 +26,9 @@ Options:
       --clear-needs-check-flag   Clears the 'needs_check' flag in the superblock
   -h, --help                     Print help
       --ignore-non-fatal-errors  Only return a non-zero exit code if a fatal error is found.
+      --log-target <TARGET>      Send log messages to stderr, or to syslog[:facility]
+      --overlay <FILE>           Keep the writes to the metadata in an overlay file, and read through it
+      --progress-fd <FD>         Write progress records to the given file descriptor
   -q, --quiet                    Suppress output messages, return only exit code.
       --skip-discards            Don't check the discard bitset
       --skip-hints               Don't check the hint array
@@ -444,3 +448,65 @@ fn metadata_v2_without_slow_dev_size_info() -> Result<()> {
 }
 
 //------------------------------------------
+
+#[test]
+fn writes_progress_records() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stdout = run_ok(cache_check_cmd(args!["--progress-fd", "1", &md]))?;
+    assert!(!progress_records(&stdout).is_empty());
+    Ok(())
+}
+
+// Only clean metadata is checked, so nothing reaches the system log; what's
+// logged at each severity is covered by the unit tests of the report.
+#[test]
+fn syslog_target_keeps_stderr_clear() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_ok_raw(cache_check_cmd(args!["--log-target", "syslog", &md]))?;
+    assert!(output.stderr.is_empty());
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_log_targets() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(cache_check_cmd(args!["--log-target", "file", &md]))?;
+    run_fail(cache_check_cmd(args!["--log-target", "syslog:kern", &md]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test the discard bitset
+
+#[test]
+fn discard_block_size_must_be_a_multiple_of_the_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    update_superblock(&md, |sb| {
+        sb.discard_block_size = 768;
+        sb.discard_nr_blocks = 10;
+    })?;
+    let stderr = run_fail(cache_check_cmd(args!["--super-block-only", &md]))?;
+    assert!(stderr.contains("error D001"));
+    Ok(())
+}
+
+#[test]
+fn discard_bitset_must_hold_every_discard_block() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+
+    // covers the whole origin, but cache_restore leaves the bitset empty
+    update_superblock(&md, |sb| {
+        sb.discard_block_size = 512 * 64;
+        sb.discard_nr_blocks = 512;
+    })?;
+    let stderr = run_fail(cache_check_cmd(args![&md]))?;
+    assert!(stderr.contains("error D003"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -21,10 +21,13 @@ Arguments:
   <INPUT>  Specify the input device to dump
 
 Options:
-  -h, --help           Print help
-  -o, --output <FILE>  Specify the output file rather than stdout
-  -r, --repair         Repair the metadata whilst dumping it
-  -V, --version        Print version";
+  -h, --help                        Print help
+      --origin-range <BLOCK_RANGE>  Only dump the mappings into a range of origin blocks
+  -o, --output <FILE>               Specify the output file rather than stdout
+      --overlay <FILE>              Keep the writes to the metadata in an overlay file, and read through it
+  -q, --quiet                       Suppress output messages, return only exit code.
+  -r, --repair                      Repair the metadata whilst dumping it
+  -V, --version                     Print version";
 
 //------------------------------------------
 
@@ -108,6 +111,66 @@ fn dump_restore_cycle() -> Result<()> {
     Ok(())
 }
 
+fn attr(line: &str, name: &str) -> u64 {
+    let pat = format!("{}=\"", name);
+    let v = &line[line.find(&pat).unwrap() + pat.len()..];
+    v[..v.find('"').unwrap()].parse().unwrap()
+}
+
+#[test]
+fn dump_origin_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let full = run_ok(cache_dump_cmd(args![&md]))?;
+    let filtered = run_ok(cache_dump_cmd(args!["--origin-range", "0..16384", &md]))?;
+
+    let lines = |xml: &str, tag: &str| -> Vec<String> {
+        xml.lines()
+            .map(str::trim)
+            .filter(|l| l.starts_with(tag))
+            .map(str::to_string)
+            .collect()
+    };
+
+    let mappings: Vec<String> = lines(&full, "<mapping")
+        .into_iter()
+        .filter(|l| attr(l, "origin_block") < 16384)
+        .collect();
+    assert!(!mappings.is_empty());
+    assert_eq!(lines(&filtered, "<mapping"), mappings);
+
+    let cblocks: Vec<u64> = mappings.iter().map(|l| attr(l, "cache_block")).collect();
+    let hints: Vec<String> = lines(&full, "<hint")
+        .into_iter()
+        .filter(|l| cblocks.contains(&attr(l, "cache_block")))
+        .collect();
+    assert_eq!(lines(&filtered, "<hint"), hints);
+
+    // the filtered dump is still restorable
+    let xml = td.mk_path("meta.xml");
+    write_file(&xml, filtered.as_bytes())?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md2]))?;
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_origin_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(cache_dump_cmd(args!["--origin-range", "100..10", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn quiet_suppresses_the_error_messages() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(cache_dump_cmd(args!["-q", &md]))?;
+    assert!(stderr.is_empty());
+    Ok(())
+}
+
 //------------------------------------------
 // test no stderr on broken pipe errors
 
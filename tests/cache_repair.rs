@@ -8,6 +8,7 @@ use common::fixture::*;
 use common::input_arg::*;
 use common::output_option::*;
 use common::program::*;
+use common::progress::*;
 use common::target::*;
 use common::test_dir::*;
 
@@ -18,11 +19,13 @@ const USAGE: &str = "Repair binary cache metadata, and write it to a different d
 Usage: cache_repair [OPTIONS] --input <FILE> --output <FILE>
 
 Options:
-  -h, --help           Print help
-  -i, --input <FILE>   Specify the input device
-  -o, --output <FILE>  Specify the output device
-  -q, --quiet          Suppress output messages, return only exit code.
-  -V, --version        Print version";
+  -h, --help              Print help
+  -i, --input <FILE>      Specify the input device
+  -o, --output <FILE>     Specify the output device
+      --overlay <FILE>    Keep the writes to the metadata in an overlay file, and read through it
+      --progress-fd <FD>  Write progress records to the given file descriptor
+  -q, --quiet             Suppress output messages, return only exit code.
+  -V, --version           Print version";
 
 //-----------------------------------------
 
@@ -111,3 +114,29 @@ fn accepts_empty_argument() -> Result<()> {
 }
 
 //-----------------------------------------
+// progress records
+
+#[test]
+fn writes_progress_records() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = mk_valid_md(&mut td)?;
+    let output = mk_zeroed_md(&mut td)?;
+    let stdout = run_ok(cache_repair_cmd(args![
+        "-i",
+        &input,
+        "-o",
+        &output,
+        "--progress-fd",
+        "1"
+    ]))?;
+
+    // the last record shows every cache block done
+    let (done, total) = *progress_records(&stdout)
+        .last()
+        .expect("no progress records");
+    assert_eq!(done, total);
+    assert!(total > 0);
+    Ok(())
+}
+
+//-----------------------------------------
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use std::path::Path;
 
 mod common;
 
@@ -19,11 +20,13 @@ const USAGE: &str = "Convert XML format metadata to binary.
 Usage: cache_restore [OPTIONS] --input <FILE> --output <FILE>
 
 Options:
+      --deterministic           Lay the metadata out the same way on any machine, for reproducible output
   -h, --help                    Print help
-  -i, --input <FILE>            Specify the input xml
+  -i, --input <FILE>            Specify the input xml, or '-' for stdin
       --metadata-version <NUM>  Specify the output metadata version [default: 2] [possible values: 1, 2]
   -o, --output <FILE>           Specify the output device
       --omit-clean-shutdown     Don't set the clean shutdown flag
+      --overlay <FILE>          Keep the writes to the metadata in an overlay file, and read through it
   -q, --quiet                   Suppress output messages, return only exit code
   -V, --version                 Print version";
 
@@ -154,4 +157,109 @@ fn omit_clean_shutdown() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn deterministic_restores_are_byte_identical() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let md = mk_zeroed_md(&mut td)?;
+        run_ok(cache_restore_cmd(args![
+            "-i",
+            &xml,
+            "-o",
+            &md,
+            "--deterministic"
+        ]))?;
+        outputs.push(std::fs::read(&md)?);
+    }
+    assert!(outputs[0] == outputs[1]);
+    Ok(())
+}
+
+//-----------------------------------------
+
+// Restores the xml from stdin, returning the dump of the metadata
+fn restore_from_stdin(td: &mut TestDir, input: &Path) -> Result<String> {
+    let md = mk_zeroed_md(td)?;
+    let output = cache_restore_cmd(args!["-i", "-", "-o", &md])
+        .to_expr()
+        .stdin_path(input)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+    assert!(output.status.success());
+    run_ok(cache_dump_cmd(args![&md]))
+}
+
+#[test]
+fn restores_from_stdin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let expected = run_ok(cache_dump_cmd(args![&md]))?;
+    assert_eq!(restore_from_stdin(&mut td, &xml)?, expected);
+    Ok(())
+}
+
+#[test]
+fn restores_gzip_compressed_xml() -> Result<()> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let expected = run_ok(cache_dump_cmd(args![&md]))?;
+
+    let gz = td.mk_path("meta.xml.gz");
+    let mut encoder = GzEncoder::new(std::fs::File::create(&gz)?, Compression::default());
+    encoder.write_all(&std::fs::read(&xml)?)?;
+    encoder.finish()?;
+
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &gz, "-o", &md]))?;
+    assert_eq!(run_ok(cache_dump_cmd(args![&md]))?, expected);
+    assert_eq!(restore_from_stdin(&mut td, &gz)?, expected);
+    Ok(())
+}
+
+#[test]
+fn restores_zstd_compressed_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let expected = run_ok(cache_dump_cmd(args![&md]))?;
+
+    let zst = td.mk_path("meta.xml.zst");
+    let status = std::process::Command::new("zstd")
+        .arg("-q")
+        .arg(&xml)
+        .arg("-o")
+        .arg(&zst)
+        .status()?;
+    assert!(status.success());
+
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(cache_restore_cmd(args!["-i", &zst, "-o", &md]))?;
+    assert_eq!(run_ok(cache_dump_cmd(args![&md]))?, expected);
+    assert_eq!(restore_from_stdin(&mut td, &zst)?, expected);
+    Ok(())
+}
+
+#[test]
+fn rejects_truncated_zstd_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = td.mk_path("meta.xml.zst");
+    std::fs::write(&input, [0x28, 0xb5, 0x2f, 0xfd, 0, 0, 0, 0])?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_fail(cache_restore_cmd(args!["-i", &input, "-o", &md]))?;
+    Ok(())
+}
+
 //-----------------------------------------
@@ -159,6 +159,7 @@ struct WritebackTest {
     origin_dev: PathBuf,
     fast_dev_offset: u64,   // bytes
     origin_dev_offset: u64, // bytes
+    json_progress: Option<PathBuf>,
     seed: u64,
 }
 
@@ -179,6 +180,7 @@ impl WritebackTest {
             origin_dev,
             fast_dev_offset: 0,
             origin_dev_offset: 0,
+            json_progress: None,
             seed: rand::thread_rng().gen::<u64>(),
         })
     }
@@ -344,6 +346,11 @@ impl WritebackTest {
             args.push(OsStr::new(&origin_dev_offset));
         }
 
+        if let Some(path) = &self.json_progress {
+            args.push(OsStr::new("--json-progress"));
+            args.push(path.as_os_str());
+        }
+
         if expect_ok {
             run_ok(cache_writeback_cmd(args))?;
         } else {
@@ -805,6 +812,86 @@ mod format2 {
         Ok(())
     }
 
+    // Extracts a field from a json line
+    fn json_field<'a>(line: &'a str, name: &str) -> &'a str {
+        let pat = format!("\"{}\": ", name);
+        let v = &line[line.find(&pat).unwrap() + pat.len()..];
+        &v[..v.find(|c| c == ',' || c == '}').unwrap()]
+    }
+
+    #[test]
+    fn writes_json_progress_and_summary() -> Result<()> {
+        let cache_block_size: usize = 32768; // 32 KiB
+        let nr_cache_blocks: u32 = 1024;
+        let nr_origin_blocks: u64 = 4096;
+
+        let mut t = WritebackTest::new()?;
+        t.format_metadata(cache_block_size, nr_cache_blocks, nr_origin_blocks, 2)?;
+        t.stamp_cache_blocks()?;
+        t.stamp_origin_blocks()?;
+
+        let rmap = format2::read_rmap(&t.metadata_dev)?;
+        let dirty_bits = format2::read_dirty_bits(&t.metadata_dev, t.nr_cache_blocks)?;
+        let nr_dirty = rmap
+            .values()
+            .filter(|cblock| dirty_bits.contains(**cblock as usize))
+            .count();
+
+        let events = t.td.mk_path("events.json");
+        t.json_progress = Some(events.clone());
+        t.writeback(true)?;
+
+        let contents = std::fs::read_to_string(&events)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines.len() >= 2);
+        assert!(lines[..lines.len() - 1]
+            .iter()
+            .all(|l| json_field(l, "event") == "\"progress\""));
+
+        let summary = lines[lines.len() - 1];
+        assert_eq!(json_field(summary, "event"), "\"summary\"");
+        assert_eq!(json_field(summary, "dirty"), nr_dirty.to_string());
+        assert_eq!(json_field(summary, "flushed"), nr_dirty.to_string());
+        assert_eq!(
+            json_field(summary, "skipped"),
+            (rmap.len() - nr_dirty).to_string()
+        );
+        assert_eq!(json_field(summary, "failed"), "0");
+        assert_eq!(json_field(summary, "metadata_updated"), "true");
+        assert_eq!(json_field(summary, "succeeded"), "true");
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_progress_summarises_a_failed_writeback() -> Result<()> {
+        let cache_block_size: usize = 32768; // 32 KiB
+        let nr_cache_blocks: u32 = 1024;
+        let nr_origin_blocks: u64 = 4096;
+
+        let mut t = WritebackTest::new()?;
+        t.format_metadata(cache_block_size, nr_cache_blocks, nr_origin_blocks, 2)?;
+        t.stamp_cache_blocks()?;
+        t.stamp_origin_blocks()?;
+        t.damage_metadata()?;
+
+        let events = t.td.mk_path("events.json");
+        t.json_progress = Some(events.clone());
+        t.writeback_fail(true)?;
+
+        let contents = std::fs::read_to_string(&events)?;
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines.len() >= 2);
+        assert!(lines.iter().any(|l| json_field(l, "event") == "\"error\""));
+
+        let summary = lines[lines.len() - 1];
+        assert_eq!(json_field(summary, "event"), "\"summary\"");
+        assert_eq!(json_field(summary, "metadata_updated"), "false");
+        assert_eq!(json_field(summary, "succeeded"), "false");
+
+        Ok(())
+    }
+
     #[test]
     fn disallow_corrupted_metadata() -> Result<()> {
         let cache_block_size: usize = 32768; // 32 KiB
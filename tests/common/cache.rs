@@ -92,3 +92,18 @@ pub fn set_needs_check(md: &Path) -> Result<()> {
 }
 
 //-----------------------------------------------
+
+pub fn update_superblock<F>(md: &Path, f: F) -> Result<()>
+where
+    F: FnOnce(&mut thinp::cache::superblock::Superblock),
+{
+    use thinp::cache::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    f(&mut sb);
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+    Ok(())
+}
+
+//-----------------------------------------------
@@ -33,3 +33,50 @@ pub fn mk_valid_md(td: &mut TestDir) -> Result<PathBuf> {
 }
 
 //-----------------------------------------------
+
+// Restores metadata for an origin of nr_blocks, of block_size sectors,
+// where the blocks in [begin, end) were written in era 3.
+pub fn mk_written_md_with_block_size(
+    td: &mut TestDir,
+    name: &str,
+    block_size: u32,
+    nr_blocks: u32,
+    begin: u32,
+    end: u32,
+) -> Result<PathBuf> {
+    let xml = td.mk_path(&format!("{}.xml", name));
+    let md = td.mk_path(&format!("{}.bin", name));
+
+    let mut contents = format!(
+        "<superblock uuid=\"\" block_size=\"{}\" nr_blocks=\"{}\" current_era=\"4\">\n",
+        block_size, nr_blocks
+    );
+    contents += &format!("  <writeset era=\"3\" nr_bits=\"{}\">\n", nr_blocks);
+    contents += &format!(
+        "    <marked block_begin=\"{}\" len=\"{}\"/>\n",
+        begin,
+        end - begin
+    );
+    contents += "  </writeset>\n  <era_array>\n";
+    for b in 0..nr_blocks {
+        contents += &format!("    <era block=\"{}\" era=\"0\"/>\n", b);
+    }
+    contents += "  </era_array>\n</superblock>\n";
+    std::fs::write(&xml, contents)?;
+
+    let _file = file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(era_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+pub fn mk_written_md(
+    td: &mut TestDir,
+    name: &str,
+    nr_blocks: u32,
+    begin: u32,
+    end: u32,
+) -> Result<PathBuf> {
+    mk_written_md_with_block_size(td, name, 128, nr_blocks, begin, end)
+}
+
+//-----------------------------------------------
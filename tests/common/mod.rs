@@ -13,6 +13,7 @@ pub mod output_option;
 pub mod piping;
 pub mod process;
 pub mod program;
+pub mod progress;
 pub mod target;
 pub mod test_dir;
 pub mod thin;
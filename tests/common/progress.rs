@@ -0,0 +1,15 @@
+//------------------------------------------
+
+/// Picks out the "completed/total" records written with --progress-fd 1
+/// from the rest of stdout.
+pub fn progress_records(stdout: &str) -> Vec<(u64, u64)> {
+    stdout
+        .lines()
+        .filter_map(|l| {
+            let (done, total) = l.split_once('/')?;
+            Some((done.parse::<u64>().ok()?, total.parse::<u64>().ok()?))
+        })
+        .collect()
+}
+
+//------------------------------------------
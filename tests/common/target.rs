@@ -44,6 +44,22 @@ where
     rust_cmd("thin_check", args)
 }
 
+pub fn thin_commit_overlay_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_commit_overlay", args)
+}
+
+pub fn thin_convert_metadata_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_convert_metadata", args)
+}
+
 pub fn thin_rmap_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -76,6 +92,22 @@ where
     rust_cmd("thin_restore", args)
 }
 
+pub fn thin_top_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_top", args)
+}
+
+pub fn thin_trim_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_trim", args)
+}
+
 pub fn thin_repair_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -132,6 +164,14 @@ where
     rust_cmd("thin_metadata_unpack", args)
 }
 
+pub fn thin_provision_report_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_provision_report", args)
+}
+
 pub fn thin_shrink_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -140,6 +180,14 @@ where
     rust_cmd("thin_shrink", args)
 }
 
+pub fn cache_adjust_hints_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("cache_adjust_hints", args)
+}
+
 pub fn cache_check_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -244,6 +292,14 @@ where
     rust_cmd("era_repair", args)
 }
 
+pub fn era_writeset_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("era_writeset", args)
+}
+
 //------------------------------------------
 
 pub mod msg {
@@ -136,6 +136,19 @@ pub fn get_superblock(md: &Path) -> Result<thinp::thin::superblock::Superblock>
     read_superblock(&engine, SUPERBLOCK_LOCATION)
 }
 
+pub fn update_superblock<F>(md: &Path, f: F) -> Result<()>
+where
+    F: FnOnce(&mut thinp::thin::superblock::Superblock) -> Result<()>,
+{
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    f(&mut sb)?;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+    Ok(())
+}
+
 pub fn get_needs_check(md: &Path) -> Result<bool> {
     use thinp::thin::superblock::*;
 
@@ -8,6 +8,7 @@ use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
 use common::program::*;
+use common::progress::*;
 use common::target::*;
 use common::test_dir::*;
 
@@ -23,6 +24,8 @@ Arguments:
 Options:
   -h, --help                     Print help
       --ignore-non-fatal-errors  Only return a non-zero exit code if a fatal error is found.
+      --overlay <FILE>           Keep the writes to the metadata in an overlay file, and read through it
+      --progress-fd <FD>         Write progress records to the given file descriptor
   -q, --quiet                    Suppress output messages, return only exit code.
       --super-block-only         Only check the superblock.
   -V, --version                  Print version";
@@ -137,3 +140,14 @@ fn failing_quiet() -> Result<()> {
 }
 
 //------------------------------------------
+
+#[test]
+fn writes_progress_records() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stdout = run_ok(era_check_cmd(args!["--progress-fd", "1", &md]))?;
+    assert!(!progress_records(&stdout).is_empty());
+    Ok(())
+}
+
+//------------------------------------------
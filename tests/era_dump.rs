@@ -21,11 +21,13 @@ Arguments:
   <INPUT>  Specify the input device to dump
 
 Options:
-  -h, --help           Print help
-      --logical        Fold any unprocessed write sets into the final era array
-  -o, --output <FILE>  Specify the output file rather than stdout
-  -r, --repair         Repair the metadata whilst dumping it
-  -V, --version        Print version";
+  -h, --help            Print help
+      --logical         Fold any unprocessed write sets into the final era array
+  -o, --output <FILE>   Specify the output file rather than stdout
+      --overlay <FILE>  Keep the writes to the metadata in an overlay file, and read through it
+  -r, --repair          Repair the metadata whilst dumping it
+      --stats           Print the number of blocks marked in each writeset rather than the metadata
+  -V, --version         Print version";
 
 //------------------------------------------
 
@@ -112,6 +114,46 @@ fn dump_restore_cycle() -> Result<()> {
     Ok(())
 }
 
+// Counts the blocks marked in each writeset of an xml dump
+fn marked_blocks(xml: &str) -> Vec<(u32, usize)> {
+    let mut marked = Vec::new();
+    for line in xml.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("<writeset era=\"") {
+            let era = rest.split('"').next().unwrap().parse().unwrap();
+            marked.push((era, 0));
+        } else if line.starts_with("<bit ") && line.contains("value=\"true\"") {
+            marked.last_mut().unwrap().1 += 1;
+        }
+    }
+    marked
+}
+
+#[test]
+fn stats_count_the_marked_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let xml = run_ok(era_dump_cmd(args![&md]))?;
+    let marked = marked_blocks(&xml);
+    assert!(!marked.is_empty());
+
+    let stats = run_ok(era_dump_cmd(args!["--stats", &md]))?;
+    let (_, rest) = xml.split_once("current_era=\"").unwrap();
+    let current_era = rest.split('"').next().unwrap();
+    assert!(stats.starts_with(&format!("current_era: {}\nnr_blocks: 512\n", current_era)));
+    for (era, n) in marked {
+        assert!(stats.contains(&format!("era_{}_marked_blocks: {}", era, n)));
+    }
+    Ok(())
+}
+
+#[test]
+fn stats_conflict_with_logical() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(era_dump_cmd(args!["--stats", "--logical", &md]))?;
+    Ok(())
+}
+
 //------------------------------------------
 // test no stderr on broken pipe errors
 
@@ -5,6 +5,7 @@ mod common;
 use common::common_args::*;
 use common::era::*;
 use common::input_arg::*;
+use common::process::*;
 use common::program::*;
 use common::target::*;
 use common::test_dir::*;
@@ -13,18 +14,26 @@ use common::test_dir::*;
 
 const USAGE: &str = "List blocks that may have changed since a given era
 
-Usage: era_invalidate [OPTIONS] --written-since <ERA> <INPUT>
+Usage: era_invalidate [OPTIONS] --written-since <ERA> <INPUT>...
 
 Arguments:
-  <INPUT>  Specify the input device
+  <INPUT>...  Specify the input devices
 
 Options:
+      --clone-region-size <SECTORS>
+          Specify the region size of the dm-clone target in sectors, if not the era block size
+      --combine <OP>
+          Combine the blocks of several inputs by union or intersection
+  -f, --format <TYPE>
+          Choose the output format, xml or a dm-clone region list
   -h, --help
           Print help
       --metadata-snapshot <METADATA_SNAPSHOT>
           Use the metadata snapshot rather than the current superblock
   -o, --output <FILE>
           Specify the output file rather than stdout
+      --overlay <FILE>
+          Keep the writes to the metadata in an overlay file, and read through it
   -V, --version
           Print version
       --written-since <ERA>
@@ -113,3 +122,121 @@ fn written_since_unspecified() -> Result<()> {
 }
 
 //------------------------------------------
+
+#[test]
+fn combines_inputs_by_union() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 8, 0, 4)?;
+    let md2 = mk_written_md(&mut td, "meta2", 8, 2, 6)?;
+    let stdout = run_ok(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        &md1,
+        &md2
+    ]))?;
+    assert!(stdout.contains("<range begin=\"0\" end=\"6\"/>"));
+    Ok(())
+}
+
+#[test]
+fn combines_inputs_by_intersection() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 8, 0, 4)?;
+    let md2 = mk_written_md(&mut td, "meta2", 8, 2, 6)?;
+    let stdout = run_ok(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        "--combine",
+        "intersection",
+        &md1,
+        &md2
+    ]))?;
+    assert!(stdout.contains("<range begin=\"2\" end=\"4\"/>"));
+    assert!(!stdout.contains("begin=\"0\""));
+    Ok(())
+}
+
+#[test]
+fn dm_clone_format_lists_regions() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_written_md(&mut td, "meta1", 16, 3, 9)?;
+    let stdout = run_ok(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        "--format",
+        "dm-clone",
+        &md
+    ]))?;
+    assert_eq!(stdout, "3 6");
+    Ok(())
+}
+
+#[test]
+fn dm_clone_regions_are_rounded_out() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 16, 3, 5)?;
+    let md2 = mk_written_md(&mut td, "meta2", 16, 6, 9)?;
+
+    // the era blocks are 128 sectors, so each region holds 4 of them
+    let stdout = run_ok(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        "--format",
+        "dm-clone",
+        "--clone-region-size",
+        "512",
+        &md1,
+        &md2
+    ]))?;
+    assert_eq!(stdout, "0 3");
+    Ok(())
+}
+
+#[test]
+fn clone_region_size_must_be_a_power_of_two() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_written_md(&mut td, "meta1", 16, 3, 9)?;
+    let stderr = run_fail(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        "--format",
+        "dm-clone",
+        "--clone-region-size",
+        "384",
+        &md
+    ]))?;
+    assert!(stderr.contains("power of two"));
+    Ok(())
+}
+
+#[test]
+fn rejects_inputs_of_different_sizes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 8, 0, 4)?;
+    let md2 = mk_written_md(&mut td, "meta2", 16, 0, 4)?;
+    let stderr = run_fail(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        &md1,
+        &md2
+    ]))?;
+    assert!(stderr.contains("tracks 16 blocks, but the inputs before it track 8"));
+    Ok(())
+}
+
+#[test]
+fn rejects_inputs_of_different_block_sizes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 8, 0, 4)?;
+    let md2 = mk_written_md_with_block_size(&mut td, "meta2", 256, 8, 0, 4)?;
+    let stderr = run_fail(era_invalidate_cmd(args![
+        "--written-since",
+        "1",
+        &md1,
+        &md2
+    ]))?;
+    assert!(stderr.contains("has a block size of 256 sectors, but the inputs before it have 128"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -19,11 +19,15 @@ const USAGE: &str = "Convert XML format metadata to binary.
 Usage: era_restore [OPTIONS] --input <FILE> --output <FILE>
 
 Options:
-  -h, --help           Print help
-  -i, --input <FILE>   Specify the input xml
-  -o, --output <FILE>  Specify the output device
-  -q, --quiet          Suppress output messages, return only exit code.
-  -V, --version        Print version";
+      --data-block-size <SECTORS>  Override the data block size if needed
+  -h, --help                       Print help
+  -i, --input <FILE>               Specify the input xml
+      --nr-blocks <NUM>            Override the number of blocks if needed
+  -o, --output <FILE>              Specify the output device
+      --overlay <FILE>             Keep the writes to the metadata in an overlay file, and read through it
+  -q, --quiet                      Suppress output messages, return only exit code.
+      --uuid <UUID>                Override the uuid if needed
+  -V, --version                    Print version";
 
 //------------------------------------------
 
@@ -137,3 +141,88 @@ fn successfully_restores() -> Result<()> {
 }
 
 //-----------------------------------------
+
+#[test]
+fn override_superblock_fields() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(era_restore_cmd(args![
+        "-i",
+        &xml,
+        "-o",
+        &md,
+        "--uuid",
+        "0123456789abcdef0123456789ABCDEF",
+        "--data-block-size",
+        "256",
+        "--nr-blocks",
+        "1024"
+    ]))?;
+    run_ok(era_check_cmd(args![&md]))?;
+
+    let stdout = run_ok(era_dump_cmd(args![&md]))?;
+    assert!(stdout.contains("uuid=\"01234567-89ab-cdef-0123-456789abcdef\""));
+    assert!(stdout.contains("block_size=\"256\""));
+    assert!(stdout.contains("nr_blocks=\"1024\""));
+    Ok(())
+}
+
+#[test]
+fn rejects_nr_blocks_too_small() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(era_restore_cmd(args![
+        "-i",
+        &xml,
+        "-o",
+        &md,
+        "--nr-blocks",
+        "256"
+    ]))?;
+    assert!(stderr.contains("beyond the 256 blocks"));
+    Ok(())
+}
+
+fn test_rejects_uuid(uuid: &str) -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(era_restore_cmd(args![
+        "-i", &xml, "-o", &md, "--uuid", uuid
+    ]))?;
+    assert!(stderr.contains("invalid uuid"));
+    Ok(())
+}
+
+#[test]
+fn rejects_invalid_uuid() -> Result<()> {
+    test_rejects_uuid("not-a-uuid")
+}
+
+#[test]
+fn rejects_uuid_with_misplaced_dashes() -> Result<()> {
+    test_rejects_uuid("0123-456789abcdef0123456789abcdef")?;
+    test_rejects_uuid("01234567-89ab-cdef-0123-4567-89abcdef")?;
+    test_rejects_uuid("-0123456789abcdef0123456789abcdef")?;
+    test_rejects_uuid("01234567--89abcdef-0123-456789abcdef")
+}
+
+#[test]
+fn rejects_zero_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_fail(era_restore_cmd(args![
+        "-i",
+        &xml,
+        "-o",
+        &md,
+        "--data-block-size",
+        "0"
+    ]))?;
+    Ok(())
+}
+
+//-----------------------------------------
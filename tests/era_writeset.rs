@@ -0,0 +1,144 @@
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::era::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "Export or import the writeset of a single era
+
+Usage: era_writeset [OPTIONS] --output <FILE> <--export <ERA>|--import <FILE>> <INPUT>
+
+Arguments:
+  <INPUT>  Specify the input device
+
+Options:
+      --era <ERA>       Import the writeset as the given era
+      --export <ERA>    Export the writeset of an era to the output file
+  -h, --help            Print help
+      --import <FILE>   Import a writeset file, writing the metadata to the output
+  -o, --output <FILE>   Specify the output file
+      --overlay <FILE>  Keep the writes to the metadata in an overlay file, and read through it
+  -q, --quiet           Suppress output messages, return only exit code.
+  -V, --version         Print version";
+
+//------------------------------------------
+
+struct EraWriteset;
+
+impl<'a> Program<'a> for EraWriteset {
+    fn name() -> &'a str {
+        "era_writeset"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        era_writeset_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(EraWriteset);
+test_accepts_version!(EraWriteset);
+test_rejects_bad_option!(EraWriteset);
+
+//------------------------------------------
+
+#[test]
+fn merges_into_the_writeset_of_the_era() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 16, 0, 4)?;
+    let md2 = mk_written_md(&mut td, "meta2", 16, 8, 12)?;
+    let ws = td.mk_path("era3.ws");
+    let out = mk_output(&mut td)?;
+
+    run_ok(era_writeset_cmd(args!["--export", "3", "-o", &ws, &md1]))?;
+    run_ok(era_writeset_cmd(args!["--import", &ws, "-o", &out, &md2]))?;
+    run_ok(era_check_cmd(args![&out]))?;
+
+    let stdout = run_ok(era_invalidate_cmd(args!["--written-since", "3", &out]))?;
+    assert!(stdout.contains("<range begin=\"0\" end=\"4\"/>"));
+    assert!(stdout.contains("<range begin=\"8\" end=\"12\"/>"));
+    Ok(())
+}
+
+#[test]
+fn raises_the_era_array_for_a_rolled_up_era() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 16, 0, 4)?;
+    let md2 = mk_written_md(&mut td, "meta2", 16, 8, 12)?;
+    let ws = td.mk_path("era3.ws");
+    let out = mk_output(&mut td)?;
+
+    run_ok(era_writeset_cmd(args!["--export", "3", "-o", &ws, &md1]))?;
+    run_ok(era_writeset_cmd(args![
+        "--import", &ws, "--era", "2", "-o", &out, &md2
+    ]))?;
+    run_ok(era_check_cmd(args![&out]))?;
+
+    let stdout = run_ok(era_invalidate_cmd(args!["--written-since", "2", &out]))?;
+    assert!(stdout.contains("<range begin=\"0\" end=\"4\"/>"));
+    let stdout = run_ok(era_invalidate_cmd(args!["--written-since", "3", &out]))?;
+    assert!(!stdout.contains("begin=\"0\""));
+    Ok(())
+}
+
+#[test]
+fn rejects_export_of_missing_era() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_written_md(&mut td, "meta", 16, 0, 4)?;
+    let ws = td.mk_path("era7.ws");
+    let stderr = run_fail(era_writeset_cmd(args!["--export", "7", "-o", &ws, &md]))?;
+    assert!(stderr.contains("there's no writeset for era 7"));
+    Ok(())
+}
+
+#[test]
+fn rejects_import_of_different_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_written_md(&mut td, "meta1", 16, 0, 4)?;
+    let md2 = mk_written_md(&mut td, "meta2", 32, 8, 12)?;
+    let ws = td.mk_path("era3.ws");
+    let out = mk_output(&mut td)?;
+
+    run_ok(era_writeset_cmd(args!["--export", "3", "-o", &ws, &md1]))?;
+    let stderr = run_fail(era_writeset_cmd(args!["--import", &ws, "-o", &out, &md2]))?;
+    assert!(stderr.contains("the writeset covers 16 blocks, but the metadata has 32"));
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_writeset_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let ws = td.mk_path("bad.ws");
+    let out = mk_output(&mut td)?;
+    std::fs::write(&ws, "not a writeset")?;
+    let stderr = run_fail(era_writeset_cmd(args!["--import", &ws, "-o", &out, &md]))?;
+    assert!(stderr.contains("isn't a writeset file"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -1,4 +1,6 @@
 use anyhow::Result;
+use std::path::{Path, PathBuf};
+use thinp::file_utils;
 
 mod common;
 
@@ -7,6 +9,7 @@ use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
 use common::program::*;
+use common::progress::*;
 use common::target::*;
 use common::test_dir::*;
 use common::thin::*;
@@ -15,23 +18,47 @@ use common::thin::*;
 
 const USAGE: &str = "Validates thin provisioning metadata on a device or file.
 
-Usage: thin_check [OPTIONS] <INPUT>
+Usage: thin_check [OPTIONS] [INPUT]
 
 Arguments:
-  <INPUT>  Specify the input device to check
+  [INPUT]  Specify the input device, or packed metadata file, to check
 
 Options:
       --auto-repair                      Auto repair trivial issues.
       --clear-needs-check-flag           Clears the 'needs_check' flag in the superblock
+      --compare <FILE>                   Check a copy of the metadata too, and report how it differs
+      --count <NUM>                      Stop watching after the given number of passes
+      --cpu-affinity <CPUS>              Restrict the checker and io threads to a list of cpus
+      --data-block-size <SECTORS>        Override the data block size if needed
+      --data-dev <FILE>                  Specify the data device to cross check the mappings against
+      --error-budget <NUM>               Tolerate up to this many leaked blocks before failing
+      --fix-checksums                    Rewrite the checksums of btree nodes that are otherwise intact
   -h, --help                             Print help
+      --history <FILE>                   Append the result of the check to a history file
       --ignore-non-fatal-errors          Only return a non-zero exit code if a fatal error is found.
+      --interval <SECS>                  Specify the seconds between passes when watching [default: 60]
+      --io-threads <NUM>                 Specify the number of threads issuing reads
+      --log-target <TARGET>              Send log messages to stderr, or to syslog[:facility]
   -m, --metadata-snap                    Check the metadata snapshot on a live pool
+      --memory-limit <MB>                Limit memory used for reference counting, spilling to disk
+      --metrics-file <PATH>              Write the check results in Prometheus text format to a file
+      --nr-data-blocks <NUM>             Override the number of data blocks if needed
+      --overlay <FILE>                   Keep the writes to the metadata in an overlay file, and read through it
       --override-details-root <BLOCKNR>  Specify a details root to use
       --override-mapping-root <BLOCKNR>  Specify a mapping root to use
+      --progress-fd <FD>                 Write progress records to the given file descriptor
   -q, --quiet                            Suppress output messages, return only exit code.
+      --ref-count-histogram              Print a histogram of the data block reference counts
+      --sample-data <NUM>                Read a sample of the mapped data blocks from the data device
+      --sample-mappings <PERCENT>        Only check a random sample of the mapping leaves, in percent
+      --show-history                     Print the history of checks, rather than checking
       --skip-mappings                    Don't check the mapping tree
       --super-block-only                 Only check the superblock.
-  -V, --version                          Print version";
+      --threads <NUM>                    Specify the number of threads for checking the mappings
+      --transaction-id <NUM>             Override the transaction id if needed
+  -V, --version                          Print version
+      --verify-data-bounds               Check the mapped blocks are within the data device
+      --watch                            Repeatedly check the metadata snapshot of a live pool";
 
 //-----------------------------------------
 
@@ -207,6 +234,34 @@ fn prints_info_with_superblock_only() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn prints_ref_count_histogram() -> Result<()> {
+    let mut td = TestDir::new()?;
+
+    // Data blocks 0..2 are shared by three devices, 2..5 by two
+    let table = td.mk_path("extents.csv");
+    std::fs::write(&table, "0,0,0,10,0\n1,0,0,5,0\n2,100,0,2,0\n")?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args![
+        "-i",
+        &table,
+        "-o",
+        &md,
+        "--input-format",
+        "extents",
+        "--data-block-size",
+        "128"
+    ]))?;
+
+    let stdout = run_ok(thin_check_cmd(args!["--ref-count-histogram", &md]))?;
+    assert!(stdout.contains("DATA_REF_COUNT_1=5"));
+    assert!(stdout.contains("DATA_REF_COUNT_2=3"));
+    assert!(stdout.contains("DATA_REF_COUNT_3_PLUS=2"));
+    assert!(stdout.contains("DATA_REF_COUNT_OVERFLOW=2"));
+    assert!(stdout.contains("DATA_REF_COUNT_MAX=3"));
+    Ok(())
+}
+
 //------------------------------------------
 // test compatibility between options
 
@@ -496,6 +551,53 @@ fn fatal_errors_cant_be_ignored() -> Result<()> {
     })
 }
 
+//------------------------------------------
+// test memory-limit
+
+#[test]
+fn checks_with_memory_limit() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_ok(thin_check_cmd(args!["--memory-limit", "0", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn memory_limit_detects_metadata_leaks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 1, 0, 1)?;
+    run_fail(thin_check_cmd(args!["--memory-limit", "0", &md]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test threads
+
+#[test]
+fn checks_with_single_thread() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_ok(thin_check_cmd(args!["--threads", "1", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn checks_with_many_threads() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_ok(thin_check_cmd(args!["--threads", "16", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn rejects_zero_threads() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_fail(thin_check_cmd(args!["--threads", "0", &md]))?;
+    Ok(())
+}
+
 //------------------------------------------
 // test auto-repair
 
@@ -660,3 +762,763 @@ fn offline_check_metadata_with_deleted_snapshot_should_success() -> Result<()> {
 }
 
 //------------------------------------------
+// test data device cross checking
+
+// mk_valid_md() maps 1024 blocks of 64KiB from an 20480 blocks data device
+fn mk_data_dev(td: &mut TestDir, nr_blocks: u64) -> Result<std::path::PathBuf> {
+    let data = td.mk_path("data.bin");
+    file_utils::create_sized_file(&data, nr_blocks * 65536)?;
+    Ok(data)
+}
+
+#[test]
+fn verify_data_bounds_passes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let data = mk_data_dev(&mut td, 20480)?;
+    run_ok(thin_check_cmd(args![
+        "--data-dev",
+        &data,
+        "--verify-data-bounds",
+        &md
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn verify_data_bounds_detects_small_data_dev() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let data = mk_data_dev(&mut td, 16)?;
+    let stderr = run_fail(thin_check_cmd(args![
+        "--data-dev",
+        &data,
+        "--verify-data-bounds",
+        &md
+    ]))?;
+    assert!(stderr.contains("1008 mapped data blocks lie beyond the end of the data device"));
+    Ok(())
+}
+
+#[test]
+fn rejects_zero_data_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let data = mk_data_dev(&mut td, 20480)?;
+    run_fail(thin_check_cmd(args![
+        "--data-block-size",
+        "0",
+        "--data-dev",
+        &data,
+        &md
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn verify_data_bounds_requires_data_dev() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_check_cmd(args!["--verify-data-bounds", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn sample_data_warns_on_zeroed_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let data = mk_data_dev(&mut td, 20480)?;
+    let output = run_ok_raw(thin_check_cmd(args![
+        "--data-dev",
+        &data,
+        "--sample-data",
+        "32",
+        &md
+    ]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("all 32 sampled data blocks are zeroed"));
+    Ok(())
+}
+
+//------------------------------------------
+// test exit codes and error budget
+
+#[test]
+fn exit_code_for_fatal_errors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 1, 1, 0)?;
+    let output = run_fail_raw(thin_check_cmd(args![&md]))?;
+    assert_eq!(output.status.code(), Some(1));
+    Ok(())
+}
+
+#[test]
+fn exit_code_for_repairable_errors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 1, 0, 1)?;
+    let output = run_fail_raw(thin_check_cmd(args![&md]))?;
+    assert_eq!(output.status.code(), Some(4));
+    Ok(())
+}
+
+#[test]
+fn repairable_errors_are_told_apart_from_usage_errors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 1, 0, 1)?;
+    let repairable = run_fail_raw(thin_check_cmd(args![&md]))?;
+    let usage = run_fail_raw(thin_check_cmd(args!["--no-such-option", &md]))?;
+    assert_ne!(repairable.status.code(), usage.status.code());
+    Ok(())
+}
+
+#[test]
+fn leaks_within_error_budget() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 2, 0, 1)?;
+    let output = run_fail_raw(thin_check_cmd(args!["--error-budget", "2", &md]))?;
+    assert_eq!(output.status.code(), Some(3));
+    Ok(())
+}
+
+#[test]
+fn leaks_exceeding_error_budget() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 2, 0, 1)?;
+    let output = run_fail_raw(thin_check_cmd(args!["--error-budget", "1", &md]))?;
+    assert_eq!(output.status.code(), Some(4));
+    Ok(())
+}
+
+#[test]
+fn error_budget_doesnt_cover_fatal_errors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 1, 1, 0)?;
+    let output = run_fail_raw(thin_check_cmd(args!["--error-budget", "100", &md]))?;
+    assert_eq!(output.status.code(), Some(1));
+    Ok(())
+}
+
+//------------------------------------------
+// test watch mode
+
+#[test]
+fn watch_checks_metadata_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    run_ok(thin_check_cmd(args![
+        "--watch",
+        "--interval",
+        "0",
+        "--count",
+        "2",
+        &md
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn watch_skips_passes_without_metadata_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_ok(thin_check_cmd(args![
+        "--watch",
+        "--interval",
+        "0",
+        "--count",
+        "1",
+        &md
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn watch_reports_corrupted_metadata_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_from_file(&mut td, "tmeta_with_corrupted_metadata_snap.pack")?;
+    let stderr = run_fail(thin_check_cmd(args![
+        "--watch",
+        "--interval",
+        "0",
+        "--count",
+        "2",
+        &md
+    ]))?;
+    assert_eq!(stderr.matches("check regressed").count(), 1);
+    Ok(())
+}
+
+#[test]
+fn interval_requires_watch() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_fail(thin_check_cmd(args!["--interval", "1", &md]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test metrics file
+
+#[test]
+fn writes_metrics_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let metrics = td.mk_path("metrics.prom");
+    run_ok(thin_check_cmd(args!["--metrics-file", &metrics, &md]))?;
+
+    let text = std::fs::read_to_string(&metrics)?;
+    assert!(text.contains("# TYPE thin_check_errors gauge"));
+    assert!(text.contains("# TYPE thin_check_errors_total counter"));
+    assert!(text.contains("thin_check_errors{device="));
+    assert!(text.contains("thin_check_duration_seconds{device="));
+    assert!(text.contains("thin_pool_metadata_used_blocks{device="));
+    assert!(text.contains("thin_pool_thin_count{device="));
+    assert!(text
+        .lines()
+        .any(|l| l.starts_with("thin_check_errors{") && l.ends_with(" 0")));
+    Ok(())
+}
+
+#[test]
+fn metrics_file_counts_errors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let metrics = td.mk_path("metrics.prom");
+    generate_metadata_leaks(&md, 1, 0, 1)?;
+    run_fail(thin_check_cmd(args!["--metrics-file", &metrics, &md]))?;
+
+    let text = std::fs::read_to_string(&metrics)?;
+    assert!(text
+        .lines()
+        .any(|l| l.starts_with("thin_check_errors{") && !l.ends_with(" 0")));
+    Ok(())
+}
+
+fn metric_value(text: &str, name: &str) -> Option<u64> {
+    text.lines()
+        .find(|l| l.starts_with(&format!("{}{{", name)))
+        .and_then(|l| l.rsplit(' ').next())
+        .and_then(|v| v.parse().ok())
+}
+
+#[test]
+fn metrics_error_counter_is_kept_across_watch_passes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_from_file(&mut td, "tmeta_with_corrupted_metadata_snap.pack")?;
+    let metrics = td.mk_path("metrics.prom");
+    run_fail(thin_check_cmd(args![
+        "--watch",
+        "--interval",
+        "0",
+        "--count",
+        "2",
+        "--metrics-file",
+        &metrics,
+        &md
+    ]))?;
+
+    let text = std::fs::read_to_string(&metrics)?;
+    let last = metric_value(&text, "thin_check_errors").expect("no error gauge");
+    let total = metric_value(&text, "thin_check_errors_total").expect("no error counter");
+    assert!(last > 0);
+    assert_eq!(total, last * 2);
+    Ok(())
+}
+
+//------------------------------------------
+// test check history
+
+#[test]
+fn history_records_each_check() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let history = td.mk_path("history");
+    run_ok(thin_check_cmd(args!["--history", &history, &md]))?;
+    generate_metadata_leaks(&md, 1, 0, 1)?;
+    run_fail(thin_check_cmd(args!["--history", &history, &md]))?;
+
+    let text = std::fs::read_to_string(&history)?;
+    let entries: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].ends_with(" clean"));
+    assert!(!entries[1].ends_with(" clean"));
+
+    let stdout = run_ok(thin_check_cmd(args![
+        "--history",
+        &history,
+        "--show-history"
+    ]))?;
+    assert_eq!(stdout.lines().count(), 4);
+    assert!(stdout.starts_with("time (UTC)"));
+
+    // the time is shown as a date, eg. "2026-10-14 11:59:59"
+    let row: Vec<&str> = stdout.lines().nth(1).unwrap().split_whitespace().collect();
+    assert_eq!(row[0].len(), 10);
+    assert_eq!(row[0].matches('-').count(), 2);
+    assert_eq!(row[1].matches(':').count(), 2);
+    assert!(stdout.ends_with("runs: 2, clean: 1, warnings: 0, repairable: 1, fatal: 0"));
+    Ok(())
+}
+
+#[test]
+fn history_records_the_transaction_of_the_metadata_snap() -> Result<()> {
+    use thinp::io_engine::SyncIoEngine;
+    use thinp::thin::superblock::read_superblock;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let history = td.mk_path("history");
+    run_ok(thin_check_cmd(args!["-m", "--history", &history, &md]))?;
+
+    let sb = get_superblock(&md)?;
+    let snap = read_superblock(&SyncIoEngine::new(&md, false)?, sb.metadata_snap)?;
+
+    let text = std::fs::read_to_string(&history)?;
+    let entry = text.lines().find(|l| !l.starts_with('#')).unwrap();
+    let fields: Vec<&str> = entry.split_whitespace().collect();
+    assert_eq!(fields[1], snap.transaction_id.to_string());
+    Ok(())
+}
+
+#[test]
+fn show_history_requires_history() -> Result<()> {
+    run_fail(thin_check_cmd(args!["--show-history"]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test corruption localization
+
+#[test]
+fn locates_damaged_mapping_tree_nodes() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    let (thin_id, (root, _)) = thins.iter().next().unwrap();
+
+    // break the checksum of the root of the first device
+    {
+        let f = std::fs::OpenOptions::new().write(true).open(&md)?;
+        f.write_all_at(&[0xff; 16], root * 4096 + 512)?;
+    }
+
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains(&format!(
+        "damaged mapping tree node at block {}, level 0: checksum error",
+        root
+    )));
+    let at_risk = stderr
+        .lines()
+        .find(|l| l.starts_with("thin devices at risk: "))
+        .unwrap();
+    assert!(at_risk
+        .trim_start_matches("thin devices at risk: ")
+        .split(", ")
+        .any(|id| id == thin_id.to_string()));
+    Ok(())
+}
+
+#[test]
+fn fixes_stale_node_checksums() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    let (_, (root, _)) = thins.iter().next().unwrap();
+
+    // only break the checksum, as a torn write might
+    {
+        let f = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&md)?;
+        let mut csum = [0u8; 4];
+        f.read_exact_at(&mut csum, root * 4096)?;
+        csum[0] ^= 0xff;
+        f.write_all_at(&csum, root * 4096)?;
+    }
+    run_fail(thin_check_cmd(args![&md]))?;
+
+    let stderr = run_ok_raw(thin_check_cmd(args!["--fix-checksums", &md]))?.stderr;
+    let stderr = String::from_utf8(stderr)?;
+    assert!(stderr.contains(&format!("fixed the checksum of btree node {}", root)));
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test packed input
+
+#[test]
+fn checks_packed_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+    run_ok(thin_check_cmd(args![&packed]))?;
+    Ok(())
+}
+
+#[test]
+fn packed_metadata_damage_is_reported() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    let (_, (root, _)) = thins.iter().next().unwrap();
+
+    // the damaged node isn't packed, leaving a hole in the mapping tree
+    {
+        let f = std::fs::OpenOptions::new().write(true).open(&md)?;
+        f.write_all_at(&[0xff; 16], root * 4096 + 512)?;
+    }
+
+    let packed = td.mk_path("meta.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+    run_fail(thin_check_cmd(args![&packed]))?;
+    Ok(())
+}
+
+#[test]
+fn metrics_of_packed_metadata_name_the_packed_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+    let metrics = td.mk_path("metrics.prom");
+    run_ok(thin_check_cmd(args!["--metrics-file", &metrics, &packed]))?;
+
+    let text = std::fs::read_to_string(&metrics)?;
+    let label = format!("{{device=\"{}\"}}", packed.display());
+    assert!(text.contains(&format!("thin_check_errors{} 0", label)));
+    Ok(())
+}
+
+#[test]
+fn packed_metadata_is_read_only() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+    let stderr = run_fail(thin_check_cmd(args!["--auto-repair", &packed]))?;
+    assert!(stderr.contains("--auto-repair can't be used with packed metadata"));
+    Ok(())
+}
+
+//------------------------------------------
+// test superblock overrides
+
+// Clobbers the number of data blocks recorded in the superblock, as a
+// damaged superblock might, returning the original value.
+fn damage_nr_data_blocks(md: &std::path::Path) -> Result<u64> {
+    use thinp::io_engine::SyncIoEngine;
+    use thinp::pdata::space_map::common::{pack_root, SMRoot};
+    use thinp::pdata::unpack::unpack;
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    let mut root = unpack::<SMRoot>(&sb.data_sm_root)?;
+    let nr_blocks = root.nr_blocks;
+    root.nr_blocks = 1;
+    sb.data_sm_root = pack_root(&root, SPACE_MAP_ROOT_SIZE)?;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+    Ok(nr_blocks)
+}
+
+#[test]
+fn overrides_nr_data_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let nr_blocks = damage_nr_data_blocks(&md)?.to_string();
+    run_fail(thin_check_cmd(args![&md]))?;
+    run_ok(thin_check_cmd(args!["--nr-data-blocks", &nr_blocks, &md]))?;
+    Ok(())
+}
+
+#[test]
+fn overrides_conflict_with_repairs() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    for flag in ["--auto-repair", "--clear-needs-check-flag"] {
+        run_fail(thin_check_cmd(args![flag, "--transaction-id", "1", &md]))?;
+    }
+    Ok(())
+}
+
+//------------------------------------------
+// test the superblock lints
+
+#[test]
+fn warns_of_odd_data_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let stderr = run_ok_raw(thin_check_cmd(args![&md]))?.stderr;
+    assert!(!std::str::from_utf8(&stderr)?.contains("warning L"));
+
+    update_superblock(&md, |sb| {
+        sb.data_block_size = 384;
+        Ok(())
+    })?;
+    let output = run_ok_raw(thin_check_cmd(args![&md]))?;
+    assert!(std::str::from_utf8(&output.stderr)?
+        .contains("warning L001: the data block size of 384 sectors is not a power of two"));
+    Ok(())
+}
+
+#[test]
+fn warns_of_high_transaction_id() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    update_superblock(&md, |sb| {
+        sb.transaction_id = 1 << 50;
+        Ok(())
+    })?;
+    let output = run_ok_raw(thin_check_cmd(args!["--super-block-only", &md]))?;
+    assert!(std::str::from_utf8(&output.stderr)?.contains("warning L002"));
+    Ok(())
+}
+
+#[test]
+fn warns_of_allocations_without_data_blocks() -> Result<()> {
+    use thinp::pdata::space_map::common::{pack_root, SMRoot};
+    use thinp::pdata::unpack::unpack;
+    use thinp::thin::superblock::SPACE_MAP_ROOT_SIZE;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    update_superblock(&md, |sb| {
+        let mut root = unpack::<SMRoot>(&sb.data_sm_root)?;
+        root.nr_blocks = 0;
+        sb.data_sm_root = pack_root(&root, SPACE_MAP_ROOT_SIZE)?;
+        Ok(())
+    })?;
+    let output = run_ok_raw(thin_check_cmd(args!["--super-block-only", &md]))?;
+    assert!(std::str::from_utf8(&output.stderr)?.contains("warning L003"));
+    Ok(())
+}
+
+//------------------------------------------
+// test sampled mappings
+
+#[test]
+fn checks_sampled_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let output = run_ok_raw(thin_check_cmd(args!["--sample-mappings", "10", &md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("only 10% of the mapping leaves were sampled"));
+    Ok(())
+}
+
+#[test]
+fn sampled_mappings_detect_damaged_leaves() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    let (_, (root, _)) = thins.iter().next().unwrap();
+
+    // break the checksum of the root of the first device, the roots are
+    // always read, so this is found whatever the sample
+    {
+        let f = std::fs::OpenOptions::new().write(true).open(&md)?;
+        f.write_all_at(&[0xff; 16], root * 4096 + 512)?;
+    }
+
+    run_fail(thin_check_cmd(args!["--sample-mappings", "1", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn sampled_mappings_leave_needs_check_set() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    set_needs_check(&md)?;
+    run_ok(thin_check_cmd(args!["--sample-mappings", "100", &md]))?;
+    assert!(get_needs_check(&md)?);
+    Ok(())
+}
+
+#[test]
+fn sample_mappings_rejects_bad_percentages() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_fail(thin_check_cmd(args!["--sample-mappings", "0", &md]))?;
+    run_fail(thin_check_cmd(args!["--sample-mappings", "101", &md]))?;
+    Ok(())
+}
+
+//------------------------------------------
+// test progress records
+
+#[test]
+fn writes_progress_records() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let stdout = run_ok(thin_check_cmd(args!["--progress-fd", "1", &md]))?;
+    assert!(!progress_records(&stdout).is_empty());
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_progress_fd() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let stderr = run_fail(thin_check_cmd(args!["--progress-fd", "1000", &md]))?;
+    assert!(stderr.contains("bad progress file descriptor"));
+    Ok(())
+}
+
+//------------------------------------------
+// test the affinity options
+
+#[test]
+fn checks_with_io_threads_and_cpu_affinity() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_ok(thin_check_cmd(args![
+        "--io-threads",
+        "4",
+        "--cpu-affinity",
+        "0",
+        &md
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn io_threads_find_damage() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 1, 0, 1)?;
+    run_fail(thin_check_cmd(args!["--io-threads", "4", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn rejects_zero_io_threads() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let stderr = run_fail(thin_check_cmd(args!["--io-threads", "0", &md]))?;
+    assert!(stderr.contains("invalid value '0' for '--io-threads <NUM>'"));
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_cpu_lists() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_fail(thin_check_cmd(args!["--cpu-affinity", "3-1", &md]))?;
+    run_fail(thin_check_cmd(args!["--cpu-affinity", "cpu0", &md]))?;
+
+    // valid, but no such cpu
+    let stderr = run_fail(thin_check_cmd(args!["--cpu-affinity", "1023", &md]))?;
+    assert!(stderr.contains("couldn't restrict the threads"));
+    Ok(())
+}
+
+//------------------------------------------
+// test log targets
+
+// Only clean metadata is checked, so nothing reaches the system log; what's
+// logged at each severity is covered by the unit tests of the report.
+#[test]
+fn syslog_target_keeps_stderr_clear() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let output = run_ok_raw(thin_check_cmd(args!["--log-target", "syslog:daemon", &md]))?;
+    assert!(output.stderr.is_empty());
+    Ok(())
+}
+
+#[test]
+fn syslog_target_keeps_stdout_records() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let stdout = run_ok(thin_check_cmd(args![
+        "--log-target",
+        "syslog",
+        "--ref-count-histogram",
+        &md
+    ]))?;
+    assert!(stdout.contains("DATA_REF_COUNT_1="));
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_log_targets() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    run_fail(thin_check_cmd(args!["--log-target", "file", &md]))?;
+    run_fail(thin_check_cmd(args!["--log-target", "syslog:kern", &md]))?;
+    Ok(())
+}
+
+//------------------------------------------
+
+// Dumps the metadata, edits the xml, and restores it as a copy
+fn mk_copy(td: &mut TestDir, md: &Path, edit: fn(String) -> String) -> Result<PathBuf> {
+    let xml = td.mk_path("copy.xml");
+    let copy = td.mk_path("copy.bin");
+    let dump = run_ok(thin_dump_cmd(args![md]))?;
+    std::fs::write(&xml, edit(dump))?;
+
+    let _file = file_utils::create_sized_file(&copy, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &copy]))?;
+    Ok(copy)
+}
+
+#[test]
+fn compare_matches_a_restored_copy() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let copy = mk_copy(&mut td, &md, |xml| xml)?;
+
+    let output = run_ok_raw(thin_check_cmd(args!["--compare", &copy, &md]))?;
+    assert_eq!(output.stderr.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn compare_reports_the_differences() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let copy = mk_copy(&mut td, &md, |xml| {
+        xml.replace(" transaction=\"1\"", " transaction=\"2\"")
+            .replace("dev_id=\"0\"", "dev_id=\"7\"")
+    })?;
+
+    let stderr = run_fail(thin_check_cmd(args!["--compare", &copy, &md]))?;
+    assert!(stderr.contains("transaction id differs: 1 in the input, 2 in the copy"));
+    assert!(stderr.contains("device 0 is missing from the copy"));
+    assert!(stderr.contains("device 7 is only in the copy"));
+    assert!(stderr.contains("the copy differs from the input in 3 places"));
+    Ok(())
+}
+
+#[test]
+fn compare_rejects_repairs() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let copy = mk_copy(&mut td, &md, |xml| xml)?;
+    run_fail(thin_check_cmd(args![
+        "--compare",
+        &copy,
+        "--auto-repair",
+        &md
+    ]))?;
+    Ok(())
+}
+
+//------------------------------------------
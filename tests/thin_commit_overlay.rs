@@ -0,0 +1,135 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+mod common;
+
+use common::common_args::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+const USAGE: &str = "Copy the blocks held in an overlay file to the metadata it was created for
+
+Usage: thin_commit_overlay [OPTIONS] <INPUT>
+
+Arguments:
+  <INPUT>  Specify the overlay file
+
+Options:
+  -h, --help     Print help
+  -q, --quiet    Suppress output messages, return only exit code.
+  -V, --version  Print version";
+
+//------------------------------------------
+
+// Restores the xml onto zeroed metadata, keeping the writes in an overlay
+fn mk_overlay(td: &mut TestDir) -> Result<(PathBuf, PathBuf)> {
+    let xml = mk_valid_xml(td)?;
+    let md = mk_zeroed_md(td)?;
+    let overlay = td.mk_path("overlay");
+    run_ok(thin_restore_cmd(args![
+        "-i",
+        &xml,
+        "-o",
+        &md,
+        "--overlay",
+        &overlay
+    ]))?;
+    Ok((md, overlay))
+}
+
+struct ThinCommitOverlay;
+
+impl<'a> Program<'a> for ThinCommitOverlay {
+    fn name() -> &'a str {
+        "thin_commit_overlay"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_commit_overlay_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for ThinCommitOverlay {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_overlay(td).map(|(_, overlay)| overlay)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        msg::BAD_SUPERBLOCK
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinCommitOverlay);
+test_accepts_version!(ThinCommitOverlay);
+test_rejects_bad_option!(ThinCommitOverlay);
+
+test_missing_input_arg!(ThinCommitOverlay);
+test_input_file_not_found!(ThinCommitOverlay);
+test_input_cannot_be_a_directory!(ThinCommitOverlay);
+
+//------------------------------------------
+
+#[test]
+fn commit_writes_the_overlay_to_the_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, overlay) = mk_overlay(&mut td)?;
+
+    // the restore only reached the overlay
+    run_fail(thin_check_cmd(args![&md]))?;
+    let through_overlay = run_ok_raw(thin_dump_cmd(args![&md, "--overlay", &overlay]))?;
+
+    run_ok(thin_commit_overlay_cmd(args![&overlay]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    let committed = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    assert_eq!(through_overlay.stdout, committed.stdout);
+    Ok(())
+}
+
+#[test]
+fn commit_rejects_a_resized_origin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, overlay) = mk_overlay(&mut td)?;
+
+    let file = std::fs::OpenOptions::new().write(true).open(&md)?;
+    file.set_len(file.metadata()?.len() / 2)?;
+    drop(file);
+
+    let stderr = run_fail(thin_commit_overlay_cmd(args![&overlay]))?;
+    assert!(stderr.contains("changed size"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,170 @@
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::output_option::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+const USAGE: &str = "Convert thin-provisioning metadata to another on-disk version
+
+Usage: thin_convert_metadata [OPTIONS] --input <FILE> --output <FILE>
+
+Options:
+  -h, --help              Print help
+  -i, --input <FILE>      Specify the input device
+  -o, --output <FILE>     Specify the output device
+      --overlay <FILE>    Keep the writes to the metadata in an overlay file, and read through it
+  -q, --quiet             Suppress output messages, return only exit code.
+      --to-version <NUM>  Specify the metadata version to convert to, defaults to the latest
+  -V, --version           Print version";
+
+//-----------------------------------------
+
+struct ThinConvertMetadata;
+
+impl<'a> Program<'a> for ThinConvertMetadata {
+    fn name() -> &'a str {
+        "thin_convert_metadata"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_convert_metadata_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::IoOptions
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for ThinConvertMetadata {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_valid_md(td)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        "bad checksum in superblock"
+    }
+}
+
+impl<'a> OutputProgram<'a> for ThinConvertMetadata {
+    fn missing_output_arg() -> &'a str {
+        msg::MISSING_OUTPUT_ARG
+    }
+}
+
+impl<'a> MetadataWriter<'a> for ThinConvertMetadata {
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+}
+
+//-----------------------------------------
+
+test_accepts_help!(ThinConvertMetadata);
+test_accepts_version!(ThinConvertMetadata);
+test_rejects_bad_option!(ThinConvertMetadata);
+
+test_input_file_not_found!(ThinConvertMetadata);
+test_input_cannot_be_a_directory!(ThinConvertMetadata);
+test_corrupted_input_data!(ThinConvertMetadata);
+
+test_readonly_input_file!(ThinConvertMetadata);
+
+test_missing_output_option!(ThinConvertMetadata);
+
+//-----------------------------------------
+
+#[test]
+fn downgrade_and_upgrade() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let before = run_ok_raw(thin_dump_cmd(args![&md]))?;
+
+    let v1 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_convert_metadata_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &v1,
+        "--to-version",
+        "1"
+    ]))?;
+    assert_eq!(get_superblock(&v1)?.version, 1);
+    run_ok(thin_check_cmd(args![&v1]))?;
+
+    // converts to the latest version by default
+    let v2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_convert_metadata_cmd(args!["-i", &v1, "-o", &v2]))?;
+    assert_eq!(get_superblock(&v2)?.version, 2);
+    run_ok(thin_check_cmd(args![&v2]))?;
+
+    let after = run_ok_raw(thin_dump_cmd(args![&v2]))?;
+    assert_eq!(before.stdout, after.stdout);
+    Ok(())
+}
+
+#[test]
+fn rejects_unsupported_version() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_convert_metadata_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &output,
+        "--to-version",
+        "3"
+    ]))?;
+    assert!(stderr.contains("unsupported metadata version 3"));
+    Ok(())
+}
+
+#[test]
+fn rejects_metadata_needing_check() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    set_needs_check(&md)?;
+    let output = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_convert_metadata_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &output,
+        "--to-version",
+        "1"
+    ]))?;
+    assert!(stderr.contains("needing a check"));
+    Ok(())
+}
+
+//-----------------------------------------
@@ -1,4 +1,6 @@
 use anyhow::Result;
+use roaring::RoaringTreemap;
+use std::path::{Path, PathBuf};
 
 mod common;
 
@@ -8,6 +10,7 @@ use common::program::*;
 use common::target::*;
 use common::test_dir::*;
 use common::thin::*;
+use common::thin_xml_generator::{write_xml, FragmentedS};
 
 //------------------------------------------
 
@@ -19,14 +22,25 @@ Arguments:
   <INPUT>  Specify the input device
 
 Options:
-  -h, --help             Print help
-  -m, --metadata-snap    Use metadata snapshot
-      --root1 <BLOCKNR>  The root block for the first thin volume to diff
-      --root2 <BLOCKNR>  The root block for the second thin volume to diff
-      --thin1 <DEV_ID>   The numeric identifier for the first thin volume to diff [aliases: snap1]
-      --thin2 <DEV_ID>   The numeric identifier for the second thin volume to diff [aliases: snap2]
-  -V, --version          Print version
-      --verbose          Provide extra information on the mappings";
+      --chain <DEV_IDS>            Diff each consecutive pair of an ordered list of thin volumes
+      --estimate-transfer          Print the number of data blocks a backup of each differing range needs to read
+      --exclude-unmapped           Report the ranges mapped in only one device as different
+  -f, --format <TYPE>              Choose the output format
+      --granularity <SIZE>         List the chunks of the given size holding changes
+  -h, --help                       Print help
+      --include-unmapped           Report the ranges mapped in only one device as left or right only
+  -m, --metadata-snap[=<BLOCKNR>]  Access the metadata snapshot on a live pool
+  -o, --output <FILE>              Specify the output file rather than stdout
+      --overlay <FILE>             Keep the writes to the metadata in an overlay file, and read through it
+      --pairs-file <FILE>          Diff each pair of thin volumes listed in a file
+      --root1 <BLOCKNR>            The root block for the first thin volume to diff
+      --root2 <BLOCKNR>            The root block for the second thin volume to diff
+      --summary-only               Print the number of blocks of each type rather than the ranges
+      --thin1 <DEV_ID>             The numeric identifier for the first thin volume to diff [aliases: snap1]
+      --thin2 <DEV_ID>             The numeric identifier for the second thin volume to diff [aliases: snap2]
+  -V, --version                    Print version
+      --verbose                    Provide extra information on the mappings
+      --verify-data <DEV>          Compare the data of blocks sharing a mapping on data devices";
 
 //------------------------------------------
 
@@ -130,3 +144,810 @@ fn test_same_root() -> Result<()> {
 }
 
 //------------------------------------------
+
+// Restores metadata with two fragmented thins, returning it with their ids
+fn mk_fragmented_md(td: &mut TestDir) -> Result<(PathBuf, String, String)> {
+    let xml = td.mk_path("meta.xml");
+    let md = td.mk_path("meta.bin");
+    write_xml(&xml, &mut FragmentedS::new(2, 4096))?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let thins = get_thins(&md)?;
+    let mut ids = thins.keys().map(|id| id.to_string());
+    let thin1 = ids.next().unwrap();
+    let thin2 = ids.next().unwrap();
+    Ok((md, thin1, thin2))
+}
+
+#[test]
+fn diffs_against_xml_dump() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let dump = td.mk_path("backup.xml");
+    run_ok(thin_dump_cmd(args![&md, "-o", &dump]))?;
+
+    let expected = run_ok(thin_delta_cmd(args![
+        "--snap1", &thin1, "--snap2", &thin2, &md
+    ]))?;
+    let snap2 = format!("file:{}#{}", dump.display(), thin2);
+    let stdout = run_ok(thin_delta_cmd(args![
+        "--snap1",
+        &format!("dev:{}", thin1),
+        "--snap2",
+        &snap2,
+        &md
+    ]))?;
+    assert_eq!(stdout, expected);
+    Ok(())
+}
+
+#[test]
+fn diffs_against_packed_dump() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, _) = mk_fragmented_md(&mut td)?;
+    let pack = td.mk_path("backup.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &pack]))?;
+
+    let snap1 = format!("file:{}#{}", pack.display(), thin1);
+    let stdout = run_ok(thin_delta_cmd(args![
+        "--snap1", &snap1, "--snap2", &thin1, "-f", "csv", &md
+    ]))?;
+    assert!(stdout.lines().count() > 1);
+    assert!(stdout.lines().skip(1).all(|line| line.starts_with("same,")));
+    Ok(())
+}
+
+#[test]
+fn rejects_device_missing_from_dump() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let dump = td.mk_path("backup.xml");
+    run_ok(thin_dump_cmd(args![&md, "-o", &dump]))?;
+
+    let thins = get_thins(&md)?;
+    let thin_id = thins.keys().next().unwrap().to_string();
+
+    let snap2 = format!("file:{}#4096", dump.display());
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--snap1", &thin_id, "--snap2", &snap2, &md
+    ]))?;
+    assert!(stderr.contains("device 4096 isn't in the dump"));
+    Ok(())
+}
+
+#[test]
+fn rejects_badly_formed_dump_spec() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    for spec in ["file:backup.xml", "file:#1", "dev:", "file:backup.xml#a"] {
+        run_fail(thin_delta_cmd(args!["--snap1", "0", "--snap2", spec, &md]))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn diffs_each_listed_pair() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let pairs = td.mk_path("pairs.txt");
+    let contents = format!(
+        "# origin, snapshot\n{} {}\n\n{},{}\n{}\t{}\n",
+        thin1, thin2, thin2, thin1, thin1, thin1
+    );
+    std::fs::write(&pairs, contents)?;
+
+    let single = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, &md
+    ]))?;
+    let stdout = run_ok(thin_delta_cmd(args!["--pairs-file", &pairs, &md]))?;
+    assert_eq!(stdout.matches("<diff ").count(), 3);
+    assert!(stdout.contains(&format!("<diff left=\"{}\" right=\"{}\">", thin2, thin1)));
+
+    // The first diff is the same as diffing the pair on its own
+    let first = &stdout[..stdout.find("</diff>").unwrap()];
+    assert!(single.starts_with(first));
+
+    let json = run_ok(thin_delta_cmd(args![
+        "--pairs-file",
+        &pairs,
+        "-f",
+        "json",
+        &md
+    ]))?;
+    assert_eq!(json.matches("\"ranges\": [").count(), 3);
+    Ok(())
+}
+
+#[test]
+fn json_of_one_pair_lists_a_single_diff() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let pairs = td.mk_path("pairs.txt");
+    std::fs::write(&pairs, format!("{} {}\n", thin1, thin2))?;
+
+    let single = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, "-f", "json", &md
+    ]))?;
+    assert!(single.contains("\"diffs\": ["));
+    assert_eq!(single.matches("\"ranges\": [").count(), 1);
+
+    // a pairs file of the same pair gives the same output
+    let listed = run_ok(thin_delta_cmd(args![
+        "--pairs-file",
+        &pairs,
+        "-f",
+        "json",
+        &md
+    ]))?;
+    assert_eq!(single, listed);
+    Ok(())
+}
+
+#[test]
+fn pairs_need_a_format_holding_many_diffs() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let pairs = td.mk_path("pairs.txt");
+    std::fs::write(
+        &pairs,
+        format!("{} {}\n{} {}\n", thin1, thin2, thin2, thin1),
+    )?;
+
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--pairs-file",
+        &pairs,
+        "-f",
+        "csv",
+        &md
+    ]))?;
+    assert!(stderr.contains("only the xml and json formats"));
+    Ok(())
+}
+
+#[test]
+fn diffs_each_generation_of_a_chain() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let chain = format!("{},{},{}", thin1, thin2, thin1);
+
+    let stdout = run_ok(thin_delta_cmd(args!["--chain", &chain, &md]))?;
+    assert_eq!(stdout.matches("<diff ").count(), 2);
+    assert!(stdout.contains(&format!("<diff left=\"{}\" right=\"{}\">", thin1, thin2)));
+    assert!(stdout.contains(&format!("<diff left=\"{}\" right=\"{}\">", thin2, thin1)));
+
+    // The same as listing the consecutive pairs
+    let pairs = td.mk_path("pairs.txt");
+    std::fs::write(
+        &pairs,
+        format!("{} {}\n{} {}\n", thin1, thin2, thin2, thin1),
+    )?;
+    let listed = run_ok(thin_delta_cmd(args!["--pairs-file", &pairs, &md]))?;
+    assert_eq!(stdout, listed);
+    Ok(())
+}
+
+#[test]
+fn rejects_a_chain_of_one() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_delta_cmd(args!["--chain", "0", &md]))?;
+    assert!(stderr.contains("a chain needs at least two thin volumes"));
+    run_fail(thin_delta_cmd(args!["--chain", "0,0", "--thin1", "0", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn rejects_badly_formed_pairs_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let pairs = td.mk_path("pairs.txt");
+
+    std::fs::write(&pairs, "0 1\n2\n")?;
+    let stderr = run_fail(thin_delta_cmd(args!["--pairs-file", &pairs, &md]))?;
+    assert!(stderr.contains("line 2: expected a pair of thin volumes"));
+
+    std::fs::write(&pairs, "# nothing\n")?;
+    run_fail(thin_delta_cmd(args!["--pairs-file", &pairs, &md]))?;
+
+    std::fs::write(&pairs, "0 1\n")?;
+    run_fail(thin_delta_cmd(args![
+        "--pairs-file",
+        &pairs,
+        "--thin1",
+        "0",
+        &md
+    ]))?;
+    Ok(())
+}
+
+// Collects the thin blocks of the ranges that aren't the same in an xml delta
+fn changed_blocks(xml: &str) -> RoaringTreemap {
+    let attr = |line: &str, name: &str| -> u64 {
+        let pat = format!("{}=\"", name);
+        let v = &line[line.find(&pat).unwrap() + pat.len()..];
+        v[..v.find('"').unwrap()].parse().unwrap()
+    };
+
+    let mut changed = RoaringTreemap::new();
+    for line in xml.lines().map(str::trim) {
+        if ["<left_only", "<right_only", "<different"]
+            .iter()
+            .any(|tag| line.starts_with(tag))
+        {
+            let begin = attr(line, "begin");
+            changed.insert_range(begin..begin + attr(line, "length"));
+        }
+    }
+    changed
+}
+
+fn read_bitmap(path: &Path) -> Result<RoaringTreemap> {
+    let f = std::fs::File::open(path)?;
+    Ok(RoaringTreemap::deserialize_from(f)?)
+}
+
+#[test]
+fn bitmap_of_same_dev_is_empty() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let thins = get_thins(&md)?;
+    let thin_id = thins.keys().next().unwrap().to_string();
+    let bitmap = td.mk_path("delta.bitmap");
+
+    run_ok(thin_delta_cmd(args![
+        "--thin1", &thin_id, "--thin2", &thin_id, "--format", "bitmap", "-o", &bitmap, &md
+    ]))?;
+    assert!(read_bitmap(&bitmap)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn bitmap_matches_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let bitmap = td.mk_path("delta.bitmap");
+
+    let stdout = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, &md
+    ]))?;
+    run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, "-f", "bitmap", "-o", &bitmap, &md
+    ]))?;
+
+    let changed = read_bitmap(&bitmap)?;
+    assert!(!changed.is_empty());
+    assert_eq!(changed, changed_blocks(&stdout));
+    Ok(())
+}
+
+// Collects the thin blocks of the rows that aren't the same in a csv delta
+fn changed_blocks_csv(csv: &str) -> RoaringTreemap {
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next(),
+        Some("type,begin,length,left_data_begin,right_data_begin")
+    );
+
+    let mut changed = RoaringTreemap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(',').collect();
+        assert_eq!(fields.len(), 5);
+        if fields[0] != "same" {
+            let begin: u64 = fields[1].parse().unwrap();
+            let len: u64 = fields[2].parse().unwrap();
+            changed.insert_range(begin..begin + len);
+        }
+    }
+    changed
+}
+
+// Collects the thin blocks of the ranges that aren't the same in a json
+// delta.  Each range is written on a line of its own.
+fn changed_blocks_json(json: &str) -> RoaringTreemap {
+    let field = |line: &str, name: &str| -> String {
+        let pat = format!("\"{}\": ", name);
+        let v = &line[line.find(&pat).unwrap() + pat.len()..];
+        let end = v.find(|c| c == ',' || c == '}').unwrap();
+        v[..end].trim_matches('"').to_string()
+    };
+
+    let mut changed = RoaringTreemap::new();
+    for line in json.lines().map(str::trim) {
+        if line.starts_with("{\"type\"") && field(line, "type") != "same" {
+            let begin: u64 = field(line, "begin").parse().unwrap();
+            let len: u64 = field(line, "length").parse().unwrap();
+            changed.insert_range(begin..begin + len);
+        }
+    }
+    changed
+}
+
+#[test]
+fn csv_and_json_match_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+
+    let stdout = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, &md
+    ]))?;
+    let expected = changed_blocks(&stdout);
+    assert!(!expected.is_empty());
+
+    let csv = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, "-f", "csv", &md
+    ]))?;
+    assert_eq!(changed_blocks_csv(&csv), expected);
+
+    let json = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, "-f", "json", &md
+    ]))?;
+    assert!(json.contains(&format!("\"left\": {},", thin1)));
+    assert!(json.contains(&format!("\"right\": {},", thin2)));
+    assert_eq!(changed_blocks_json(&json), expected);
+    Ok(())
+}
+
+#[test]
+fn json_of_same_dev_has_no_changes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let thins = get_thins(&md)?;
+    let thin_id = thins.keys().next().unwrap().to_string();
+
+    let json = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin_id, "--thin2", &thin_id, "--format", "json", &md
+    ]))?;
+    assert!(json.starts_with('{') && json.trim_end().ends_with('}'));
+    assert!(json.contains("\"ranges\": ["));
+    assert!(changed_blocks_json(&json).is_empty());
+    Ok(())
+}
+
+#[test]
+fn summary_counts_the_changed_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+
+    let xml = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, &md
+    ]))?;
+    let changed = changed_blocks(&xml).len();
+    assert!(changed > 0);
+    let block_size = data_block_size(&xml)?;
+
+    let summary = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        &thin1,
+        "--thin2",
+        &thin2,
+        "--summary-only",
+        &md
+    ]))?;
+    assert!(summary.contains(&format!("left: {}\nright: {}\n", thin1, thin2)));
+    assert!(summary.contains(&format!("differing_blocks: {}\n", changed)));
+    assert!(summary.contains(&format!("differing_bytes: {}", changed * block_size * 512)));
+    Ok(())
+}
+
+#[test]
+fn summary_of_same_dev_has_no_changes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let thins = get_thins(&md)?;
+    let thin_id = thins.keys().next().unwrap().to_string();
+
+    let summary = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        &thin_id,
+        "--thin2",
+        &thin_id,
+        "--summary-only",
+        &md
+    ]))?;
+    assert!(summary.contains("same_blocks: 1024\n"));
+    assert!(summary.contains("differing_blocks: 0\n"));
+    assert!(summary.contains("differing_bytes: 0"));
+    Ok(())
+}
+
+fn data_block_size(xml: &str) -> Result<u64> {
+    let v = &xml[xml.find("data_block_size=\"").unwrap() + 17..];
+    Ok(v[..v.find('"').unwrap()].parse()?)
+}
+
+#[test]
+fn granularity_lists_the_changed_chunks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+
+    let xml = run_ok(thin_delta_cmd(args![
+        "--thin1", &thin1, "--thin2", &thin2, &md
+    ]))?;
+    let block_size = data_block_size(&xml)?;
+
+    // chunks of four data blocks
+    let mut expected = std::collections::BTreeMap::new();
+    for b in changed_blocks(&xml) {
+        *expected.entry(b / 4).or_insert(0u64) += 1;
+    }
+    assert!(!expected.is_empty());
+
+    let granularity = (block_size * 4).to_string();
+    let chunks = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        &thin1,
+        "--thin2",
+        &thin2,
+        "--granularity",
+        &granularity,
+        &md
+    ]))?;
+    let mut lines = chunks.lines();
+    assert_eq!(lines.next(), Some("chunk,offset,dirty_blocks"));
+    let mut actual = std::collections::BTreeMap::new();
+    for line in lines {
+        let fields: Vec<u64> = line.split(',').map(|f| f.parse().unwrap()).collect();
+        assert_eq!(fields[1], fields[0] * block_size * 4 * 512);
+        assert!(actual.insert(fields[0], fields[2]).is_none());
+    }
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+#[test]
+fn granularity_must_be_a_multiple_of_the_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, thin1, thin2) = mk_fragmented_md(&mut td)?;
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--thin1",
+        &thin1,
+        "--thin2",
+        &thin2,
+        "--granularity",
+        "1k",
+        &md
+    ]))?;
+    assert!(stderr.contains("the granularity must be a multiple of the data block size"));
+    Ok(())
+}
+
+// Restores metadata where thins 0 and 1 share the mappings of blocks 0..4,
+// along with a data device holding a distinct pattern in each block
+fn mk_shared_md(td: &mut TestDir) -> Result<(PathBuf, PathBuf)> {
+    let xml = td.mk_path("shared.xml");
+    let md = td.mk_path("shared.bin");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    for dev_id in 0..2 {
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"4\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n",
+            dev_id
+        );
+        contents +=
+            "    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"4\" time=\"0\"/>\n";
+        contents += "  </device>\n";
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    let data = td.mk_path("data.bin");
+    let mut blocks = Vec::new();
+    for b in 0..16u8 {
+        blocks.extend(std::iter::repeat(b).take(128 * 512));
+    }
+    std::fs::write(&data, blocks)?;
+    Ok((md, data))
+}
+
+#[test]
+fn verifies_the_data_of_shared_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_shared_md(&mut td)?;
+    let replica = td.mk_path("replica.bin");
+    std::fs::copy(&data, &replica)?;
+
+    let stdout = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "--verify-data",
+        &data,
+        "--verify-data",
+        &replica,
+        &md
+    ]))?;
+    assert!(changed_blocks(&stdout).is_empty());
+    Ok(())
+}
+
+#[test]
+fn reports_shared_mappings_with_different_data() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_shared_md(&mut td)?;
+    let replica = td.mk_path("replica.bin");
+    let mut blocks = std::fs::read(&data)?;
+    blocks[2 * 128 * 512 + 100] ^= 0xff;
+    std::fs::write(&replica, blocks)?;
+    let delta = td.mk_path("delta.xml");
+
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "--verify-data",
+        &data,
+        "--verify-data",
+        &replica,
+        "-o",
+        &delta,
+        &md
+    ]))?;
+    assert!(stderr.contains("the data of 1 blocks sharing a mapping differs"));
+
+    let changed = changed_blocks(&std::fs::read_to_string(&delta)?);
+    assert_eq!(changed.iter().collect::<Vec<u64>>(), vec![2]);
+    Ok(())
+}
+
+#[test]
+fn verify_data_needs_two_data_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_shared_md(&mut td)?;
+
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "--verify-data",
+        &data,
+        &md
+    ]))?;
+    assert!(stderr.contains("--verify-data must be given twice"));
+
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "--verify-data",
+        &data,
+        "--verify-data",
+        &data,
+        "--verify-data",
+        &data,
+        &md
+    ]))?;
+    assert!(stderr.contains("--verify-data must be given twice"));
+    Ok(())
+}
+
+// Restores metadata where thin 0 maps blocks 0..4 and thin 1 blocks 2..6,
+// each to data blocks of its own
+fn mk_overlapping_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("overlapping.xml");
+    let md = td.mk_path("overlapping.bin");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    for (dev_id, thin_begin, data_begin) in [(0, 0, 0), (1, 2, 10)] {
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"4\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n",
+            dev_id
+        );
+        contents += &format!(
+            "    <range_mapping origin_begin=\"{}\" data_begin=\"{}\" length=\"4\" time=\"0\"/>\n",
+            thin_begin, data_begin
+        );
+        contents += "  </device>\n";
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn unmapped_ranges_are_reported_as_left_or_right_only() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_overlapping_md(&mut td)?;
+    let expected = [
+        "type,begin,length,left_data_begin,right_data_begin",
+        "left_only,0,2,0,",
+        "different,2,2,2,10",
+        "right_only,4,2,,12",
+    ];
+
+    let csv = run_ok(thin_delta_cmd(args![
+        "--thin1", "0", "--thin2", "1", "-f", "csv", &md
+    ]))?;
+    assert_eq!(csv.lines().collect::<Vec<_>>(), expected);
+
+    // the last of the toggles given wins
+    let csv = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "-f",
+        "csv",
+        "--exclude-unmapped",
+        "--include-unmapped",
+        &md
+    ]))?;
+    assert_eq!(csv.lines().collect::<Vec<_>>(), expected);
+    Ok(())
+}
+
+#[test]
+fn exclude_unmapped_reports_them_as_different() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_overlapping_md(&mut td)?;
+
+    let csv = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "-f",
+        "csv",
+        "--exclude-unmapped",
+        &md
+    ]))?;
+    assert_eq!(
+        csv.lines().collect::<Vec<_>>(),
+        [
+            "type,begin,length,left_data_begin,right_data_begin",
+            "different,0,2,0,",
+            "different,2,2,2,10",
+            "different,4,2,,12",
+        ]
+    );
+
+    let xml = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "--exclude-unmapped",
+        &md
+    ]))?;
+    assert!(xml.contains("<different begin=\"0\" length=\"6\"/>"));
+    assert!(!xml.contains("_only "));
+    Ok(())
+}
+
+// Restores metadata where thin 1 remaps blocks 2..4 to the data blocks
+// thin 0 maps at 0..2, and maps 4..6 to data blocks of its own
+fn mk_remapped_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("remapped.xml");
+    let md = td.mk_path("remapped.bin");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    for (dev_id, ranges) in [
+        (0, vec![(0, 0, 4)]),
+        (1, vec![(0, 0, 2), (2, 0, 2), (4, 10, 2)]),
+    ] {
+        let mapped: u64 = ranges.iter().map(|r| r.2).sum();
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"{}\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n",
+            dev_id, mapped
+        );
+        for (thin_begin, data_begin, len) in ranges {
+            contents += &format!(
+                "    <range_mapping origin_begin=\"{}\" data_begin=\"{}\" length=\"{}\" time=\"0\"/>\n",
+                thin_begin, data_begin, len
+            );
+        }
+        contents += "  </device>\n";
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn estimate_skips_the_data_blocks_the_left_maps() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_remapped_md(&mut td)?;
+
+    let estimate = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        "0",
+        "--thin2",
+        "1",
+        "--estimate-transfer",
+        &md
+    ]))?;
+    assert_eq!(
+        estimate.lines().collect::<Vec<_>>(),
+        [
+            "left: 0",
+            "right: 1",
+            "different 2..4 read_blocks: 0",
+            "right_only 4..6 read_blocks: 2",
+            "differing_blocks: 4",
+            "read_blocks: 2",
+            "read_bytes: 131072",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn estimate_of_same_dev_reads_nothing() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let thins = get_thins(&md)?;
+    let thin_id = thins.keys().next().unwrap().to_string();
+
+    let estimate = run_ok(thin_delta_cmd(args![
+        "--thin1",
+        &thin_id,
+        "--thin2",
+        &thin_id,
+        "--estimate-transfer",
+        &md
+    ]))?;
+    assert!(estimate.contains("differing_blocks: 0\n"));
+    assert!(estimate.contains("read_blocks: 0\n"));
+    Ok(())
+}
+
+//------------------------------------------
+
+fn metadata_snap_location(md: &Path) -> Result<u64> {
+    use thinp::io_engine::*;
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, false)?;
+    Ok(read_superblock(&engine, SUPERBLOCK_LOCATION)?.metadata_snap)
+}
+
+#[test]
+fn diffs_the_metadata_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let snap = format!("--metadata-snap={}", metadata_snap_location(&md)?);
+
+    // take a device held in the snapshot
+    let dump = run_ok(thin_dump_cmd(args!["-m", &md]))?;
+    let (_, rest) = dump.split_once("dev_id=\"").unwrap();
+    let thin_id = rest.split('"').next().unwrap().to_string();
+
+    for flag in ["-m", snap.as_str()] {
+        let stdout = run_ok(thin_delta_cmd(args![
+            flag, "--thin1", &thin_id, "--thin2", &thin_id, &md
+        ]))?;
+        assert!(stdout.contains(&format!(
+            "<diff left=\"{}\" right=\"{}\">",
+            thin_id, thin_id
+        )));
+        assert!(!stdout.contains("<different ") && !stdout.contains("_only "));
+    }
+    Ok(())
+}
+
+//------------------------------------------
+
+#[test]
+fn rejects_unknown_format() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_delta_cmd(args![
+        "--thin1", "0", "--thin2", "1", "--format", "ranges", &md
+    ]))?;
+    Ok(())
+}
+
+//------------------------------------------
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use std::path::Path;
 
 mod common;
 
@@ -25,12 +26,17 @@ Options:
       --dev-id <THIN_ID>           Dump the specified device
   -f, --format <TYPE>              Choose the output format
   -h, --help                       Print help
+      --id-map <FILE>              Write the mapping of original to renumbered device ids
   -m, --metadata-snap[=<BLOCKNR>]  Access the metadata snapshot on a live pool
       --nr-data-blocks <NUM>       Override the number of data blocks if needed
   -o, --output <FILE>              Specify the output file rather than stdout
+      --output-dir <DIR>           Write each device to its own file in a directory, with a manifest
+      --overlay <FILE>             Keep the writes to the metadata in an overlay file, and read through it
   -q, --quiet                      Suppress output messages, return only exit code.
   -r, --repair                     Repair the metadata whilst dumping it
+      --renumber-from <THIN_ID>    Renumber the devices sequentially, starting from the given id
       --skip-mappings              Do not dump the mappings
+      --throttle-io <MiB/s>        Limit the rate the metadata is read at, in MiB/s
       --transaction-id <NUM>       Override the transaction id if needed
   -V, --version                    Print version";
 
@@ -117,6 +123,223 @@ fn dump_restore_cycle() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn dump_with_cached_engine() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let direct = run_ok_raw(thin_dump_cmd(args![&md]))?;
+
+    // the second run finds the blocks cached by the first
+    for _ in 0..2 {
+        let cached = run_ok_raw(thin_dump_cmd(args!["--io-engine", "cached", &md]))?;
+        assert_eq!(direct.stdout, cached.stdout);
+    }
+    Ok(())
+}
+
+#[test]
+fn cached_engine_is_read_only() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--io-engine",
+        "cached",
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+    assert!(stderr.contains("only be used for reading"));
+    Ok(())
+}
+
+#[test]
+fn dump_with_engine_opts() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let direct = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    let tuned = run_ok_raw(thin_dump_cmd(args![
+        "--engine-opts",
+        "threads=2,buffered=true",
+        &md
+    ]))?;
+    assert_eq!(direct.stdout, tuned.stdout);
+    Ok(())
+}
+
+#[test]
+fn rejects_engine_opts_of_another_engine() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_dump_cmd(args!["--engine-opts", "queue_depth=64", &md]))?;
+    assert!(stderr.contains("only apply to the async io engine"));
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_engine_opts() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_dump_cmd(args!["--engine-opts", "depth=64", &md]))?;
+    run_fail(thin_dump_cmd(args!["--engine-opts", "threads=0", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn dump_with_throttled_io() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let direct = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    let throttled = run_ok_raw(thin_dump_cmd(args!["--throttle-io", "1024", &md]))?;
+    assert_eq!(direct.stdout, throttled.stdout);
+    Ok(())
+}
+
+#[test]
+fn rejects_zero_throttle_rate() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_dump_cmd(args!["--throttle-io", "0", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn rejects_overflowing_throttle_rate() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_dump_cmd(args!["--throttle-io", "17592186044416", &md]))?;
+    assert!(stderr.contains("too large"));
+    Ok(())
+}
+
+//------------------------------------------
+// test device renumbering
+
+#[test]
+fn renumber_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stdout = run_ok(thin_dump_cmd(args![&md, "--renumber-from", "100"]))?;
+    assert!(stdout.contains("dev_id=\"100\""));
+    assert!(!stdout.contains("dev_id=\"0\""));
+    Ok(())
+}
+
+#[test]
+fn id_map_requires_renumber_from() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let map = td.mk_path("ids.txt");
+    run_fail(thin_dump_cmd(args![&md, "--id-map", &map]))?;
+    Ok(())
+}
+
+#[test]
+fn renumber_restore_cycle() -> Result<()> {
+    let mut td = TestDir::new()?;
+
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let before = run_ok_raw(thin_dump_cmd(args![&md]))?;
+
+    let xml = td.mk_path("meta.xml");
+    let map = td.mk_path("ids.txt");
+    run_ok(thin_dump_cmd(args![
+        &md,
+        "--renumber-from",
+        "1000",
+        "--id-map",
+        &map,
+        "-o",
+        &xml
+    ]))?;
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args![
+        "-i", &xml, "-o", &md2, "--id-map", &map
+    ]))?;
+
+    let after = run_ok_raw(thin_dump_cmd(args![&md2]))?;
+    assert_eq!(before.stdout, after.stdout);
+
+    Ok(())
+}
+
+//------------------------------------------
+// test dumping to a directory
+
+fn read_manifest(dir: &Path) -> Result<Vec<(u64, u64, String)>> {
+    let mut entries = Vec::new();
+    for line in std::fs::read_to_string(dir.join("manifest"))?.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 3);
+        entries.push((
+            fields[0].parse()?,
+            fields[1].parse()?,
+            fields[2].to_string(),
+        ));
+    }
+    Ok(entries)
+}
+
+#[test]
+fn output_dir_writes_a_file_per_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+
+    let dir = td.mk_path("devs");
+    run_ok(thin_dump_cmd(args![&md, "--output-dir", &dir]))?;
+
+    let manifest = read_manifest(&dir)?;
+    assert_eq!(manifest.len(), thins.len());
+    for (dev_id, mapped_blocks, name) in manifest {
+        assert_eq!(thins[&dev_id].1.mapped_blocks, mapped_blocks);
+
+        // each file is restorable by itself
+        let md2 = mk_zeroed_md(&mut td)?;
+        run_ok(thin_restore_cmd(args!["-i", &dir.join(&name), "-o", &md2]))?;
+        let restored = get_thins(&md2)?;
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[&dev_id].1.mapped_blocks, mapped_blocks);
+    }
+    Ok(())
+}
+
+#[test]
+fn output_dir_with_selected_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let dev_id = *get_thins(&md)?.keys().next().unwrap();
+
+    let dir = td.mk_path("devs");
+    run_ok(thin_dump_cmd(args![
+        &md,
+        "--output-dir",
+        &dir,
+        "--dev-id",
+        &dev_id.to_string()
+    ]))?;
+
+    let manifest = read_manifest(&dir)?;
+    assert_eq!(manifest.len(), 1);
+    assert_eq!(manifest[0].0, dev_id);
+    Ok(())
+}
+
+#[test]
+fn output_dir_conflicts_with_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let dir = td.mk_path("devs");
+    let xml = td.mk_path("meta.xml");
+    run_fail(thin_dump_cmd(args![&md, "--output-dir", &dir, "-o", &xml]))?;
+    Ok(())
+}
+
 //------------------------------------------
 // test no stderr with a normal dump
 
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use std::path::PathBuf;
 
 mod common;
 
@@ -20,11 +21,21 @@ Arguments:
   <INPUT>  Specify the input device
 
 Options:
-  -h, --help             Print help
-  -m, --metadata-snap    Use metadata snapshot
-      --no-headers       Don't output headers
-  -o, --format <FIELDS>  Give a comma separated list of fields to be output
-  -V, --version          Print version";
+      --alert-threshold <PERCENT>  Raise the data and metadata alarms at this usage percentage
+      --auto-snap <POOL>           Reserve a metadata snapshot of a live pool while listing
+      --baseline <FILE>            Print the growth of each device since an xml or pack dump
+      --filter <EXPR>              Only list the devices matching an expression, such as 'mapped_blocks>1000'
+  -h, --help                       Print help
+  -m, --metadata-snap              Use metadata snapshot
+      --no-headers                 Don't output headers
+  -o, --format <FIELDS>            Give a comma separated list of fields to be output
+      --output-format <TYPE>       Choose the output format
+      --overlay <FILE>             Keep the writes to the metadata in an overlay file, and read through it
+      --sort <FIELD>               Sort the devices by a field, append ':desc' for the largest first
+      --tree                       Show the snapshots of each device below it
+  -V, --version                    Print version
+      --warn-data <PERCENT>        Exit with code 2 if the data usage reaches this percentage
+      --warn-metadata <PERCENT>    Exit with code 3 if the metadata usage reaches this percentage";
 
 //-----------------------------------------
 
@@ -101,3 +112,449 @@ fn read_metadata_snapshot() -> Result<()> {
 }
 
 //------------------------------------------
+// test usage alarms
+
+#[test]
+fn no_alarm_below_thresholds() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let (nr_blocks, nr_allocated) = get_data_usage(&md)?;
+    assert!(nr_allocated < nr_blocks);
+
+    let output = run_ok_raw(thin_ls_cmd(args![&md, "--warn-data", "100"]))?;
+    assert!(!std::str::from_utf8(&output.stderr)?.contains("ALARM"));
+    Ok(())
+}
+
+#[test]
+fn data_alarm_exit_code() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_fail_raw(thin_ls_cmd(args![&md, "--warn-data", "0"]))?;
+    assert_eq!(output.status.code(), Some(3));
+    assert!(std::str::from_utf8(&output.stderr)?.contains("DATA_USAGE_ALARM percent="));
+    Ok(())
+}
+
+#[test]
+fn metadata_alarm_exit_code() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_fail_raw(thin_ls_cmd(args![&md, "--warn-metadata", "0"]))?;
+    assert_eq!(output.status.code(), Some(4));
+    assert!(std::str::from_utf8(&output.stderr)?.contains("METADATA_USAGE_ALARM percent="));
+    Ok(())
+}
+
+#[test]
+fn both_alarms_exit_code() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_fail_raw(thin_ls_cmd(args![
+        &md,
+        "--warn-data",
+        "0",
+        "--warn-metadata",
+        "0"
+    ]))?;
+    assert_eq!(output.status.code(), Some(5));
+    Ok(())
+}
+
+#[test]
+fn alarms_are_told_apart_from_usage_errors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let usage = run_fail_raw(thin_ls_cmd(args![&md, "--no-such-option"]))?;
+    for arg in ["--warn-data", "--warn-metadata"] {
+        let alarm = run_fail_raw(thin_ls_cmd(args![&md, arg, "0"]))?;
+        assert_ne!(alarm.status.code(), usage.status.code());
+    }
+    Ok(())
+}
+
+#[test]
+fn alert_threshold_raises_both_alarms() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_fail_raw(thin_ls_cmd(args![&md, "--alert-threshold", "0"]))?;
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("DATA_USAGE_ALARM percent="));
+    assert!(stderr.contains("METADATA_USAGE_ALARM percent="));
+    Ok(())
+}
+
+#[test]
+fn warn_data_overrides_alert_threshold() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let output = run_fail_raw(thin_ls_cmd(args![
+        &md,
+        "--alert-threshold",
+        "0",
+        "--warn-data",
+        "100"
+    ]))?;
+    assert_eq!(output.status.code(), Some(4));
+    Ok(())
+}
+
+//------------------------------------------
+// test the output formats
+
+#[test]
+fn json_lists_each_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "--output-format",
+        "json",
+        "-o",
+        "DEV,MAPPED_BLOCKS,EXCLUSIVE_BLOCKS,SHARED_BLOCKS,HIGHEST_MAPPED_SECTOR"
+    ]))?;
+
+    // the single device maps blocks 0..1024 of 64KiB each
+    assert_eq!(
+        stdout,
+        "[\n  {\"DEV\": 0, \"MAPPED_BLOCKS\": 1024, \"EXCLUSIVE_BLOCKS\": 1024, \"SHARED_BLOCKS\": 0, \"HIGHEST_SECTOR\": 131071}\n]"
+    );
+    Ok(())
+}
+
+#[test]
+fn json_matches_table() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let table = run_ok(thin_ls_cmd(args![
+        &md,
+        "--no-headers",
+        "-o",
+        "DEV,MAPPED_SECTORS,CREATE_TIME"
+    ]))?;
+    let json = run_ok(thin_ls_cmd(args![
+        &md,
+        "--output-format",
+        "json",
+        "-o",
+        "DEV,MAPPED_SECTORS,CREATE_TIME"
+    ]))?;
+
+    let fields: Vec<&str> = table.split_whitespace().collect();
+    assert_eq!(
+        json,
+        format!(
+            "[\n  {{\"DEV\": {}, \"MAPPED_SECTORS\": {}, \"CREATE_TIME\": {}}}\n]",
+            fields[0], fields[1], fields[2]
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_output_format() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_ls_cmd(args![&md, "--output-format", "yaml"]))?;
+    Ok(())
+}
+
+// Devices 0, 1 and 2 map 8, 2 and 4 blocks respectively
+fn mk_sized_devices_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("sized.xml");
+    let md = td.mk_path("sized.bin");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    let mut data_begin = 0;
+    for (dev_id, len) in [(0, 8), (1, 2), (2, 4)] {
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"{}\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n",
+            dev_id, len
+        );
+        contents += &format!(
+            "    <range_mapping origin_begin=\"0\" data_begin=\"{}\" length=\"{}\" time=\"0\"/>\n",
+            data_begin, len
+        );
+        contents += "  </device>\n";
+        data_begin += len;
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+fn listed_devices(stdout: &str) -> Vec<String> {
+    stdout.lines().map(|l| l.trim().to_string()).collect()
+}
+
+#[test]
+fn sort_by_mapped_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_sized_devices_md(&mut td)?;
+    let asc = run_ok(thin_ls_cmd(args![
+        &md,
+        "--no-headers",
+        "-o",
+        "DEV",
+        "--sort",
+        "mapped_blocks"
+    ]))?;
+    assert_eq!(listed_devices(&asc), ["1", "2", "0"]);
+
+    let desc = run_ok(thin_ls_cmd(args![
+        &md,
+        "--no-headers",
+        "-o",
+        "DEV",
+        "--sort",
+        "MAPPED_BLOCKS:desc"
+    ]))?;
+    assert_eq!(listed_devices(&desc), ["0", "2", "1"]);
+    Ok(())
+}
+
+#[test]
+fn filter_by_mapped_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_sized_devices_md(&mut td)?;
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "--no-headers",
+        "-o",
+        "DEV",
+        "--filter",
+        "mapped_blocks>2"
+    ]))?;
+    assert_eq!(listed_devices(&stdout), ["0", "2"]);
+
+    // every filter given must match
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "--no-headers",
+        "-o",
+        "DEV",
+        "--filter",
+        "mapped_blocks>=2",
+        "--filter",
+        "dev!=0"
+    ]))?;
+    assert_eq!(listed_devices(&stdout), ["1", "2"]);
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_filter() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_ls_cmd(args![&md, "--filter", "mapped_blocks"]))?;
+    run_fail(thin_ls_cmd(args![&md, "--filter", "no_such_field>1"]))?;
+    run_fail(thin_ls_cmd(args![&md, "--filter", "mapped_blocks>lots"]))?;
+    run_fail(thin_ls_cmd(args![&md, "--sort", "mapped_blocks:sideways"]))?;
+    Ok(())
+}
+
+// Device 0 is snapshotted as 1 and 4, then 1 as 3.  Device 2 is unrelated.
+fn mk_snapshots_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("snapshots.xml");
+    let md = td.mk_path("snapshots.bin");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"2\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    for (dev_id, creation_time, snap_time, data_begin) in [
+        (0, 0, 1, 0),
+        (1, 1, 2, 0),
+        (2, 0, 0, 8),
+        (3, 2, 2, 0),
+        (4, 1, 1, 0),
+    ] {
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"4\" transaction=\"0\" creation_time=\"{}\" snap_time=\"{}\">\n",
+            dev_id, creation_time, snap_time
+        );
+        contents += &format!(
+            "    <range_mapping origin_begin=\"0\" data_begin=\"{}\" length=\"4\" time=\"0\"/>\n",
+            data_begin
+        );
+        contents += "  </device>\n";
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn tree_shows_snapshots_below_their_origin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshots_md(&mut td)?;
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "--tree",
+        "-o",
+        "DEV,EXCLUSIVE_BLOCKS"
+    ]))?;
+    let lines: Vec<&str> = stdout.lines().map(|l| l.trim_end()).collect();
+    assert_eq!(
+        lines,
+        [
+            "DEV     EXCLUSIVE_BLOCKS",
+            "0                      0",
+            "|- 1                   0",
+            "|  `- 3                0",
+            "`- 4                   0",
+            "2                      4",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn only_exclusive_blocks_are_reclaimable() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshots_md(&mut td)?;
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "--output-format",
+        "json",
+        "-o",
+        "DEV,SHARED_BLOCKS,RECLAIMABLE_BLOCKS"
+    ]))?;
+    let rows: Vec<String> = [(0, 4, 0), (1, 4, 0), (2, 0, 4), (3, 4, 0), (4, 4, 0)]
+        .iter()
+        .map(|(dev, shared, reclaimable)| {
+            format!(
+                "{{\"DEV\": {}, \"SHARED_BLOCKS\": {}, \"RECLAIMABLE_BLOCKS\": {}}}",
+                dev, shared, reclaimable
+            )
+        })
+        .collect();
+    assert_eq!(stdout, format!("[\n  {}\n]", rows.join(",\n  ")));
+    Ok(())
+}
+
+#[test]
+fn tree_conflicts_with_filter() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_ls_cmd(args![&md, "--tree", "--filter", "dev>0"]))?;
+    Ok(())
+}
+
+#[test]
+fn baseline_reports_growth() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_sized_devices_md(&mut td)?;
+
+    // device 2 is new, and device 5 has since been deleted
+    let baseline = td.mk_path("baseline.xml");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    for (dev_id, len) in [(0, 6), (1, 3), (5, 1)] {
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"{}\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n",
+            dev_id, len
+        );
+        contents += &format!(
+            "    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"{}\" time=\"0\"/>\n",
+            len
+        );
+        contents += "  </device>\n";
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&baseline, contents)?;
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV",
+        "--baseline",
+        &baseline
+    ]))?;
+    let rows: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|l| l.split_whitespace().collect())
+        .collect();
+    assert_eq!(
+        rows,
+        [
+            ["DEV", "BASELINE_BLOCKS", "GROWTH_BLOCKS"],
+            ["0", "6", "+2"],
+            ["1", "3", "-1"],
+            ["2", "-", "+4"],
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn baseline_may_be_a_pack() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_sized_devices_md(&mut td)?;
+    let packed = td.mk_path("baseline.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "--output-format",
+        "json",
+        "-o",
+        "DEV",
+        "--baseline",
+        &packed
+    ]))?;
+    for dev_id in 0..3 {
+        assert!(stdout.contains(&format!("{{\"DEV\": {}, \"BASELINE_BLOCKS\"", dev_id)));
+    }
+    assert_eq!(stdout.matches("\"GROWTH_BLOCKS\": 0}").count(), 3);
+
+    // nothing is left beside the pack
+    let names: Vec<_> = std::fs::read_dir(packed.parent().unwrap())?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    assert!(!names
+        .iter()
+        .any(|n| n.to_string_lossy().contains("unpacked")));
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_baseline() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let baseline = td.mk_path("baseline.xml");
+    std::fs::write(&baseline, "not a dump")?;
+    run_fail(thin_ls_cmd(args![&md, "--baseline", &baseline]))?;
+    Ok(())
+}
+
+//------------------------------------------
+
+#[test]
+fn auto_snap_conflicts_with_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_ls_cmd(args![&md, "-m", "--auto-snap", "pool"]))?;
+    assert!(stderr.contains("cannot be used with"));
+    Ok(())
+}
+
+#[test]
+fn auto_snap_fails_if_the_snapshot_cant_be_reserved() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_ls_cmd(args![
+        &md,
+        "--auto-snap",
+        "thinp-test-no-such-pool"
+    ]))?;
+    assert!(stderr.contains("unable to reserve a metadata snapshot"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+use thinp::io_engine::*;
+use thinp::pdata::layout::*;
+use thinp::thin::layout::build_metadata_layout;
+use thinp::thin::superblock::*;
+
+mod common;
+
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+fn layout_of(md: &Path) -> Result<MetadataLayout> {
+    let engine = Arc::new(SyncIoEngine::new(md, false)?);
+    build_metadata_layout(engine)
+}
+
+#[test]
+fn every_allocated_block_of_restored_metadata_is_reached() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let layout = layout_of(&md)?;
+    let (nr_blocks, nr_allocated) = get_metadata_usage(&md)?;
+    assert_eq!(layout.nr_blocks(), nr_blocks);
+
+    let roles: Vec<&BlockRole> = (0..layout.nr_blocks())
+        .map(|b| layout.get(b).expect("unclassed block"))
+        .collect();
+    assert!(roles.iter().all(|r| r.kind != BlockKind::Unknown));
+    let nr_used = roles.iter().filter(|r| r.kind != BlockKind::Free).count();
+    assert_eq!(nr_used as u64, nr_allocated);
+
+    let sb = roles[SUPERBLOCK_LOCATION as usize];
+    assert_eq!(sb.kind, BlockKind::Superblock);
+    assert_eq!(sb.structure, "superblock");
+
+    // the one thin device is mapped by a tree of several levels
+    let mapping: Vec<&&BlockRole> = roles.iter().filter(|r| r.structure == "mapping").collect();
+    assert!(mapping
+        .iter()
+        .all(|r| r.kind == BlockKind::Node && r.dev_id == Some(0)));
+    assert!(mapping.iter().any(|r| r.level == Some(0)));
+    assert!(mapping.iter().any(|r| r.level > Some(0)));
+
+    for structure in ["details", "top_level", "data_sm_index", "metadata_sm"] {
+        assert!(roles.iter().any(|r| r.structure == structure));
+    }
+    assert!(roles
+        .iter()
+        .any(|r| r.kind == BlockKind::Bitmap && r.structure == "data_sm"));
+    Ok(())
+}
+
+#[test]
+fn the_metadata_snap_is_laid_out_too() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let sb = get_superblock(&md)?;
+    assert!(sb.metadata_snap != 0);
+
+    let layout = layout_of(&md)?;
+    let snap = layout.get(sb.metadata_snap).expect("unclassed block");
+    assert_eq!(snap.kind, BlockKind::Superblock);
+    assert_eq!(snap.structure, "metadata_snap");
+    Ok(())
+}
+
+//------------------------------------------
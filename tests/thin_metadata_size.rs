@@ -15,10 +15,12 @@ Usage: thin_metadata_size [OPTIONS] --block-size <SIZE[bskmg]> --pool-size <SIZE
 
 Options:
   -b, --block-size <SIZE[bskmg]>   Specify the data block size
+      --divergence <PERCENT>       Expected percentage of each snapshot diverging from its origin
   -h, --help                       Print help
   -m, --max-thins <NUM>            Maximum number of thin devices and snapshots
   -n, --numeric-only[=<OPT>]       Output numeric value only
   -s, --pool-size <SIZE[bskmgtp]>  Specify the size of pool device
+      --snapshots-per-thin <NUM>   Expected number of snapshots of each thin device
   -u, --unit <UNIT>                Specify the output unit in {bskKmMgG} [default: sector]
   -V, --version                    Print version";
 
@@ -152,4 +154,56 @@ fn invalid_block_size_should_fail() -> Result<()> {
     Ok(())
 }
 
+// Returns the estimate in sectors
+fn estimate(extra: &[&str]) -> Result<u64> {
+    let mut args = vec![
+        "--pool-size",
+        "2097152",
+        "--block-size",
+        "128",
+        "-m",
+        "1",
+        "-n",
+    ];
+    args.extend_from_slice(extra);
+    let stdout = run_ok(thin_metadata_size_cmd(args))?;
+    Ok(stdout.trim().parse::<u64>()?)
+}
+
+#[test]
+fn snapshots_without_divergence_share_everything() -> Result<()> {
+    assert_eq!(estimate(&["--snapshots-per-thin", "10"])?, 1064);
+    assert_eq!(estimate(&["--divergence", "50"])?, 1064);
+    Ok(())
+}
+
+#[test]
+fn divergence_copies_whole_leaves() -> Result<()> {
+    let baseline = estimate(&[])?;
+    let diverged = estimate(&["--snapshots-per-thin", "10", "--divergence", "1"])?;
+
+    // 1% of the blocks diverging copies most of the leaves of each snapshot,
+    // rather than 1% of them
+    let nr_leaves = (baseline / 8) - 2;
+    assert!(diverged > baseline + (nr_leaves * 10 / 2) * 8);
+    assert!(diverged <= baseline + (nr_leaves * 10 + nr_leaves) * 8);
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_divergence() -> Result<()> {
+    let stderr = run_fail(thin_metadata_size_cmd(args![
+        "--pool-size",
+        "2097152",
+        "--block-size",
+        "128",
+        "-m",
+        "1",
+        "--divergence",
+        "150"
+    ]))?;
+    assert!(stderr.contains("divergence must be a percentage"));
+    Ok(())
+}
+
 //------------------------------------------
@@ -3,6 +3,7 @@ use anyhow::Result;
 mod common;
 
 use common::common_args::*;
+use common::fixture::*;
 use common::input_arg::*;
 use common::output_option::*;
 use common::process::*;
@@ -18,11 +19,12 @@ const USAGE: &str = "Unpack a compressed file of thin metadata.
 Usage: thin_metadata_unpack [OPTIONS] --input <FILE> --output <DEV>
 
 Options:
-  -f, --force         Force overwrite the output file
-  -h, --help          Print help
-  -i, --input <FILE>  Specify packed input file
-  -o, --output <DEV>  Specify thinp metadata binary device/file
-  -V, --version       Print version";
+  -f, --force           Force overwrite the output, even onto a device smaller than the metadata
+  -h, --help            Print help
+  -i, --input <FILE>    Specify packed input file
+  -o, --output <DEV>    Specify thinp metadata binary device/file
+      --overlay <FILE>  Keep the writes to the metadata in an overlay file, and read through it
+  -V, --version         Print version";
 
 //------------------------------------------
 
@@ -113,4 +115,22 @@ fn end_to_end() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn overlay_is_refused() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_valid_md(&mut td)?;
+    let md_packed = td.mk_path("meta.pack");
+    let md_out = mk_zeroed_md(&mut td)?;
+    let overlay = td.mk_path("overlay");
+    run_ok(thin_metadata_pack_cmd(args![
+        "-i", &md_in, "-o", &md_packed
+    ]))?;
+    let stderr = run_fail(thin_metadata_unpack_cmd(args![
+        "-i", &md_packed, "-o", &md_out, "--overlay", &overlay
+    ]))?;
+    assert!(stderr.contains("--overlay"));
+    assert!(!overlay.exists());
+    Ok(())
+}
+
 //------------------------------------------
@@ -0,0 +1,270 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+mod common;
+
+use common::common_args::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+const USAGE: &str = "Report which files of a filesystem on a thin device share its data blocks
+
+Usage: thin_provision_report [OPTIONS] --extents <FILE> --thin <DEV_ID> <INPUT>
+
+Arguments:
+  <INPUT>  Specify the input device
+
+Options:
+      --extents <FILE>  Specify the extent map of the filesystem, from filefrag -e or xfs_bmap
+  -h, --help            Print help
+  -m, --metadata-snap   Use metadata snapshot
+  -o, --output <FILE>   Specify the output file rather than stdout
+      --overlay <FILE>  Keep the writes to the metadata in an overlay file, and read through it
+      --thin <DEV_ID>   The numeric identifier of the thin device holding the filesystem
+  -V, --version         Print version";
+
+//------------------------------------------
+
+struct ThinProvisionReport;
+
+impl<'a> Program<'a> for ThinProvisionReport {
+    fn name() -> &'a str {
+        "thin_provision_report"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_provision_report_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinProvisionReport);
+test_accepts_version!(ThinProvisionReport);
+test_rejects_bad_option!(ThinProvisionReport);
+
+//------------------------------------------
+
+// Restores metadata with 64KiB data blocks, where thins 0 and 1 share the
+// mappings of blocks 0..4, and thin 0 alone maps blocks 4..8.
+fn mk_snapshotted_md(td: &mut TestDir) -> Result<PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    let md = td.mk_path("meta.bin");
+    let mut contents = String::from(
+        "<superblock uuid=\"\" time=\"1\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+    );
+    contents += "  <device dev_id=\"0\" mapped_blocks=\"8\" transaction=\"0\" creation_time=\"0\" snap_time=\"1\">\n";
+    contents +=
+        "    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"4\" time=\"0\"/>\n";
+    contents +=
+        "    <range_mapping origin_begin=\"4\" data_begin=\"8\" length=\"4\" time=\"1\"/>\n";
+    contents += "  </device>\n";
+    contents += "  <device dev_id=\"1\" mapped_blocks=\"4\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">\n";
+    contents +=
+        "    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"4\" time=\"0\"/>\n";
+    contents += "  </device>\n";
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+// /a lies on thin blocks 0..2, /b on 4..6 from filefrag, and /c on block 8
+// from xfs_bmap
+fn mk_extent_map(td: &mut TestDir) -> Result<PathBuf> {
+    let map = td.mk_path("extents.txt");
+    let contents = "\
+Filesystem type is: ef53
+File size of /a is 131072 (32 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..      31:          0..        31:     32:             last,eof
+/a: 1 extent found
+File size of /b is 262144 (64 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..      31:         64..        95:     32:
+   1:       32..      63:         96..       127:     32:             last,eof
+/b: 2 extents found
+/c:
+\t0: [0..127]: 1024..1151
+\t1: [128..255]: hole
+";
+    std::fs::write(&map, contents)?;
+    Ok(map)
+}
+
+// Maps the name of each row to the exclusive, shared and unmapped blocks
+fn parse_report(stdout: &str) -> Vec<(String, [u64; 3])> {
+    let mut lines = stdout.lines();
+    assert_eq!(
+        lines.next().unwrap().split_whitespace().collect::<Vec<_>>(),
+        [
+            "EXCLUSIVE_BLOCKS",
+            "SHARED_BLOCKS",
+            "UNMAPPED_BLOCKS",
+            "FILE"
+        ]
+    );
+    lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let count = |i: usize| fields[i].parse::<u64>().unwrap();
+            (fields[3].to_string(), [count(0), count(1), count(2)])
+        })
+        .collect()
+}
+
+fn row(name: &str, counts: [u64; 3]) -> (String, [u64; 3]) {
+    (name.to_string(), counts)
+}
+
+fn run_report(md: &Path, map: &Path, thin: &str) -> Result<Vec<(String, [u64; 3])>> {
+    let stdout = run_ok(thin_provision_report_cmd(args![
+        "--thin",
+        thin,
+        "--extents",
+        map,
+        md
+    ]))?;
+    Ok(parse_report(&stdout))
+}
+
+//------------------------------------------
+
+#[test]
+fn reports_shared_and_exclusive_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshotted_md(&mut td)?;
+    let map = mk_extent_map(&mut td)?;
+
+    assert_eq!(
+        run_report(&md, &map, "0")?,
+        [
+            row("\"/a\"", [0, 2, 0]),
+            row("\"/b\"", [4, 0, 0]),
+            row("\"/c\"", [0, 0, 1]),
+            row("total", [4, 2, 1]),
+        ]
+    );
+    assert_eq!(
+        run_report(&md, &map, "1")?,
+        [
+            row("\"/a\"", [0, 2, 0]),
+            row("\"/b\"", [0, 0, 4]),
+            row("\"/c\"", [0, 0, 1]),
+            row("total", [0, 2, 5]),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn total_is_told_apart_from_a_file_named_total() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshotted_md(&mut td)?;
+    let map = td.mk_path("extents.txt");
+    std::fs::write(&map, "total:\n\t0: [0..127]: 0..127\n")?;
+
+    assert_eq!(
+        run_report(&md, &map, "0")?,
+        [row("\"total\"", [0, 1, 0]), row("total", [0, 1, 0])]
+    );
+    Ok(())
+}
+
+#[test]
+fn extents_without_a_location_are_skipped() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshotted_md(&mut td)?;
+    let map = td.mk_path("extents.txt");
+    let contents = "\
+File size of /a is 262144 (64 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..      31:         64..        95:     32:
+   1:       32..      47:          0..        15:     16:         96: unknown_loc,delalloc
+   2:       48..      63:          0..        15:     16:             unknown_loc,last,eof
+/a: 3 extents found
+/c:
+\t0: [0..127]: delalloc
+";
+    std::fs::write(&map, contents)?;
+
+    assert_eq!(
+        run_report(&md, &map, "0")?,
+        [
+            row("\"/a\"", [2, 0, 0]),
+            row("\"/c\"", [0, 0, 0]),
+            row("total", [2, 0, 0]),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn rejects_missing_thin() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshotted_md(&mut td)?;
+    let map = mk_extent_map(&mut td)?;
+    let stderr = run_fail(thin_provision_report_cmd(args![
+        "--thin",
+        "7",
+        "--extents",
+        &map,
+        &md
+    ]))?;
+    assert!(stderr.contains("thin device 7 doesn't exist"));
+    Ok(())
+}
+
+#[test]
+fn rejects_badly_formed_extent_map() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_snapshotted_md(&mut td)?;
+    let map = td.mk_path("extents.txt");
+
+    std::fs::write(
+        &map,
+        "   0:        0..      31:          0..        31:     32:\n",
+    )?;
+    let stderr = run_fail(thin_provision_report_cmd(args![
+        "--thin",
+        "0",
+        "--extents",
+        &map,
+        &md
+    ]))?;
+    assert!(stderr.contains("line 1: extent given before any file"));
+
+    std::fs::write(&map, "/a:\n\t0: [0..127]: 1151..1024\n")?;
+    let stderr = run_fail(thin_provision_report_cmd(args![
+        "--thin",
+        "0",
+        "--extents",
+        &map,
+        &md
+    ]))?;
+    assert!(stderr.contains("line 2: invalid range"));
+    Ok(())
+}
+
+//------------------------------------------
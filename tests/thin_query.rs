@@ -0,0 +1,84 @@
+use anyhow::Result;
+use std::sync::Arc;
+
+use thinp::io_engine::*;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::thin::block_time::BlockTime;
+use thinp::thin::query::*;
+
+mod common;
+
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+#[test]
+fn device_info_matches_details_tree() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    assert!(!thins.is_empty());
+
+    for (dev_id, (_, expected)) in &thins {
+        let details = device_info(&md, *dev_id as u32)?.expect("device is missing");
+        assert_eq!(details.mapped_blocks, expected.mapped_blocks);
+        assert_eq!(details.transaction_id, expected.transaction_id);
+        assert_eq!(details.creation_time, expected.creation_time);
+        assert_eq!(details.snapshotted_time, expected.snapshotted_time);
+    }
+
+    let unused = thins.keys().last().unwrap() + 1;
+    assert!(device_info(&md, unused as u32)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn lookup_matches_mapping_tree() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    let (dev_id, (root, _)) = thins.iter().next().unwrap();
+
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(&md, false)?);
+    let mappings = btree_to_map::<BlockTime>(&mut Vec::new(), engine, false, *root)?;
+    let max_block = *mappings.keys().last().unwrap();
+
+    let q = MetadataQuery::open(&md, false)?;
+    for virt_block in (0..max_block + 2).step_by(7) {
+        let expected = mappings.get(&virt_block).map(|bt| bt.block);
+        let actual = q
+            .lookup_mapping(*dev_id as u32, virt_block)?
+            .map(|bt| bt.block);
+        assert_eq!(actual, expected);
+    }
+    assert_eq!(
+        lookup_mapping(&md, *dev_id as u32, max_block)?,
+        mappings.get(&max_block).map(|bt| bt.block)
+    );
+    Ok(())
+}
+
+#[test]
+fn lookup_on_missing_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let unused = get_thins(&md)?.keys().last().unwrap() + 1;
+    assert!(lookup_mapping(&md, unused as u32, 0)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn queries_the_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let q = MetadataQuery::open(&md, true)?;
+    assert!(q.superblock().block > 0);
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    assert!(MetadataQuery::open(&md, true).is_err());
+    Ok(())
+}
+
+//------------------------------------------
@@ -1,4 +1,6 @@
 use anyhow::Result;
+use std::path::{Path, PathBuf};
+use thinp::file_utils;
 
 mod common;
 
@@ -8,6 +10,7 @@ use common::input_arg::*;
 use common::output_option::*;
 use common::process::*;
 use common::program::*;
+use common::progress::*;
 use common::target::*;
 use common::test_dir::*;
 use common::thin::*;
@@ -16,16 +19,35 @@ use common::thin::*;
 
 const USAGE: &str = "Repair thin-provisioning metadata, and write it to different device or file
 
-Usage: thin_repair [OPTIONS] --input <FILE> --output <FILE>
+Usage: thin_repair [OPTIONS] --input <FILE>
 
 Options:
+      --answers-file <FILE>        Take the answers to the questions of --interactive from a file
+      --backup <FILE>              Specify the file the input is packed to before repairing
+      --backup-dir <DIR>           Pack the input to a new file in a directory before repairing
       --data-block-size <SECTORS>  Provide the data block size for repairing
+      --data-dev <FILE>            Specify the data device to infer the block size from
+      --deterministic              Lay the metadata out the same way on any machine, for reproducible output
   -h, --help                       Print help
+      --ignore-space-estimate      Go ahead even if the output looks too small for the repaired metadata
+      --in-place                   Repair the input in place, through a journal
+      --infer                      Apply the inferred values of lost superblock fields
   -i, --input <FILE>               Specify the input device
+      --interactive                Ask at the choices made in rebuilding a lost superblock
+      --journal <FILE>             Specify the journal file of an in-place repair
       --nr-data-blocks <NUM>       Override the number of data blocks if needed
   -o, --output <FILE>              Specify the output device
+      --output-format <TYPE>       Write the repaired metadata as binary metadata or an xml dump
+      --overlay <FILE>             Keep the writes to the metadata in an overlay file, and read through it
+      --progress-fd <FD>           Write progress records to the given file descriptor
   -q, --quiet                      Suppress output messages, return only exit code.
+      --reconcile <BACKUP_FILE>    Fill in the lost mappings from an earlier xml or pack backup
+      --salvage                    Recover what can be read of damaged mapping trees
+      --scan-roots                 List the candidate roots to repair from, then exit
+      --sm-report <FILE>           Write a summary of the space map adjustments to a file
+      --sm-report-format <TYPE>    Write the summary as text or json
       --transaction-id <NUM>       Override the transaction id if needed
+      --use-roots <INDEX>          Repair from the candidate roots of the given index
   -V, --version                    Print version";
 
 //-----------------------------------------
@@ -263,6 +285,233 @@ fn recovers_tid_and_nr_data_blocks_from_damaged_superblock() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn infers_superblock_fields() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    run_ok(thin_repair_cmd(args!["--infer", "-i", &src, "-o", &dest]))?;
+    let repaired = run_ok(thin_dump_cmd(args![&dest]))?;
+    assert!(repaired.contains("transaction=\"1\""));
+    assert!(repaired.contains("data_block_size=\"128\""));
+    assert!(repaired.contains("nr_data_blocks=\"1024\""));
+    Ok(())
+}
+
+#[test]
+fn infers_data_block_size_from_data_dev() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    // 1025 blocks of 128 sectors, no larger size divides the device
+    let data_dev = td.mk_path("data.bin");
+    let _file = file_utils::create_sized_file(&data_dev, 1025 * 128 * 512)?;
+    run_ok(thin_repair_cmd(args![
+        "--infer",
+        "--data-dev",
+        &data_dev,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    let repaired = run_ok(thin_dump_cmd(args![&dest]))?;
+    assert!(repaired.contains("data_block_size=\"128\""));
+    assert!(repaired.contains("nr_data_blocks=\"1025\""));
+    Ok(())
+}
+
+#[test]
+fn ambiguous_data_block_size_must_be_given() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    // 1024 blocks of 256 sectors, or 2048 of 128
+    let data_dev = td.mk_path("data.bin");
+    let _file = file_utils::create_sized_file(&data_dev, 1024 * 256 * 512)?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--infer",
+        "--data-dev",
+        &data_dev,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    assert!(stderr.contains("sizes of 128, 256 sectors"));
+    assert!(stderr.contains("--data-block-size"));
+
+    run_ok(thin_repair_cmd(args![
+        "--data-block-size",
+        "256",
+        "--nr-data-blocks",
+        "1024",
+        "--data-dev",
+        &data_dev,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    let repaired = run_ok(thin_dump_cmd(args![&dest]))?;
+    assert!(repaired.contains("data_block_size=\"256\""));
+    Ok(())
+}
+
+#[test]
+fn answers_file_chooses_the_data_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    let data_dev = td.mk_path("data.bin");
+    let _file = file_utils::create_sized_file(&data_dev, 1024 * 256 * 512)?;
+    let answers = td.mk_path("answers");
+    write_file(&answers, b"data_block_size=256\ninfer=y\n")?;
+    run_ok(thin_repair_cmd(args![
+        "--answers-file",
+        &answers,
+        "--data-dev",
+        &data_dev,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    let repaired = run_ok(thin_dump_cmd(args![&dest]))?;
+    assert!(repaired.contains("data_block_size=\"256\""));
+    assert!(repaired.contains("nr_data_blocks=\"1024\""));
+
+    // only the sizes that fit are accepted
+    write_file(&answers, b"data_block_size=512\ninfer=y\n")?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--answers-file",
+        &answers,
+        "--data-dev",
+        &data_dev,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    assert!(stderr.contains("expected one of 128, 256"));
+    Ok(())
+}
+
+#[test]
+fn proposes_inferred_fields_without_infer() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_repair_cmd(args!["-i", &src, "-o", &dest]))?;
+    assert!(stderr.contains("data block size of 128 sectors"));
+    Ok(())
+}
+
+#[test]
+fn answers_file_accepts_inferred_fields() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let answers = td.mk_path("answers.txt");
+    write_file(&answers, b"# scripted replay\ninfer = y\nroots = 0\n")?;
+    run_ok(thin_repair_cmd(args![
+        "--answers-file",
+        &answers,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    let repaired = run_ok(thin_dump_cmd(args![&dest]))?;
+    assert!(repaired.contains("data_block_size=\"128\""));
+    assert!(repaired.contains("nr_data_blocks=\"1024\""));
+    Ok(())
+}
+
+#[test]
+fn answers_file_declines_inferred_fields() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let answers = td.mk_path("answers.txt");
+    write_file(&answers, b"infer=n\n")?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--answers-file",
+        &answers,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    assert!(stderr.contains("data block size needs to be provided"));
+    Ok(())
+}
+
+#[test]
+fn answers_file_must_answer_each_question() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    damage_superblock(&src)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let answers = td.mk_path("answers.txt");
+    write_file(&answers, b"roots=0\n")?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--answers-file",
+        &answers,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    assert!(stderr.contains("no answer to 'infer'"));
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_answers_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let answers = td.mk_path("answers.txt");
+    write_file(&answers, b"infer\n")?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--answers-file",
+        &answers,
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    assert!(stderr.contains("line 1: expected a question and its answer"));
+    Ok(())
+}
+
+#[test]
+fn interactive_needs_a_terminal() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_valid_md(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--interactive",
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+    assert!(stderr.contains("needs a terminal"));
+    Ok(())
+}
+
 #[test]
 fn repair_metadata_with_stale_superblock() -> Result<()> {
     let mut td = TestDir::new()?;
@@ -366,4 +615,707 @@ fn repair_device_details_tree() -> Result<()> {
 
     Ok(())
 }
+
+//------------------------------------------
+// test salvaging damaged mapping trees
+
+// Finds a device with an internal root, and the second child of that root
+fn find_internal_root(md: &std::path::Path) -> Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::FileExt;
+    use thinp::pdata::btree::{unpack_node, Node};
+    use thinp::thin::block_time::BlockTime;
+
+    let file = std::fs::File::open(md)?;
+    for (thin_id, (root, _)) in get_thins(md)? {
+        let mut buf = vec![0; 4096];
+        file.read_exact_at(&mut buf, root * 4096)?;
+        if let Node::Internal { values, .. } = unpack_node::<BlockTime>(&[0], &buf, true, true)? {
+            if values.len() > 1 {
+                return Ok(Some((thin_id, values[1])));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn salvages_damaged_mapping_tree() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let orig_thins = get_thins(&orig)?;
+    let (thin_id, child) = find_internal_root(&orig)?.expect("no device with an internal root");
+
+    // break the checksum of one child of the root
+    {
+        let file = std::fs::OpenOptions::new().write(true).open(&orig)?;
+        file.write_all_at(&[0xff; 16], child * 4096 + 512)?;
+    }
+
+    let repaired = mk_zeroed_md(&mut td)?;
+    run_fail(thin_repair_cmd(args!["-i", &orig, "-o", &repaired]))?;
+
+    let output = run_ok_raw(thin_repair_cmd(args![
+        "-i",
+        &orig,
+        "-o",
+        &repaired,
+        "--salvage"
+    ]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains(&format!(
+        "thin device {}: lost the mappings of virtual blocks [",
+        thin_id
+    )));
+
+    // the rest of the device was recovered
+    let repaired_thins = get_thins(&repaired)?;
+    let recovered = repaired_thins[&thin_id].1.mapped_blocks;
+    assert!(recovered > 0);
+    assert!(recovered < orig_thins[&thin_id].1.mapped_blocks);
+    run_ok(thin_check_cmd(args![&repaired]))?;
+
+    Ok(())
+}
+
+#[test]
+fn salvage_of_healthy_metadata_loses_nothing() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let repaired = mk_zeroed_md(&mut td)?;
+    let output = run_ok_raw(thin_repair_cmd(args![
+        "-i",
+        &orig,
+        "-o",
+        &repaired,
+        "--salvage"
+    ]))?;
+    assert!(!std::str::from_utf8(&output.stderr)?.contains("lost the mappings"));
+
+    let orig_thins = get_thins(&orig)?;
+    let repaired_thins = get_thins(&repaired)?;
+    assert!(repaired_thins
+        .iter()
+        .map(|(k, (_, d))| (k, d.mapped_blocks))
+        .eq(orig_thins.iter().map(|(k, (_, d))| (k, d.mapped_blocks))));
+    Ok(())
+}
+
+// Breaks the checksum of one child of the root of a mapping tree
+fn damage_mapping_subtree(md: &Path) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let (_, child) = find_internal_root(md)?.expect("no device with an internal root");
+    let file = std::fs::OpenOptions::new().write(true).open(md)?;
+    file.write_all_at(&[0xff; 16], child * 4096 + 512)?;
+    Ok(())
+}
+
+// Repairs the damaged metadata, expecting the backup to make up for all
+// that was lost
+fn reconciles_from(td: &mut TestDir, orig: &Path, backup: &Path) -> Result<()> {
+    let orig_thins = get_thins(orig)?;
+    damage_mapping_subtree(orig)?;
+
+    let repaired = mk_zeroed_md(td)?;
+    let output = run_ok_raw(thin_repair_cmd(args![
+        "-i",
+        orig,
+        "-o",
+        &repaired,
+        "--reconcile",
+        backup
+    ]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("lost the mappings of virtual blocks"));
+    assert!(!stderr.contains("dropped the mappings"));
+
+    run_ok(thin_check_cmd(args![&repaired]))?;
+    let repaired_thins = get_thins(&repaired)?;
+    assert!(repaired_thins
+        .iter()
+        .map(|(k, (_, d))| (k, d.mapped_blocks))
+        .eq(orig_thins.iter().map(|(k, (_, d))| (k, d.mapped_blocks))));
+    Ok(())
+}
+
+#[test]
+fn reconciles_with_xml_backup() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let backup = td.mk_path("backup.xml");
+    let dumped = run_ok_raw(thin_dump_cmd(args![&orig]))?;
+    write_file(&backup, &dumped.stdout)?;
+    reconciles_from(&mut td, &orig, &backup)
+}
+
+#[test]
+fn reconciles_with_packed_backup() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let backup = td.mk_path("backup.pack");
+    thinp::pack::toplevel::pack(&orig, &backup)?;
+    reconciles_from(&mut td, &orig, &backup)?;
+    assert!(!td.mk_path("backup.pack.unpacked").exists());
+    Ok(())
+}
+
+// Only the mappings of the lost ranges are taken from the backup as it's
+// streamed, so a device the input doesn't have is passed over.
+#[test]
+fn reconciles_with_backup_of_other_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let backup = td.mk_path("backup.xml");
+    let dumped = run_ok_raw(thin_dump_cmd(args![&orig]))?;
+    let xml = std::str::from_utf8(&dumped.stdout)?.replace(
+        "</superblock>",
+        "  <device dev_id=\"4000000\" mapped_blocks=\"1\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n    <single_mapping origin_block=\"0\" data_block=\"0\" time=\"0\"/>\n  </device>\n</superblock>",
+    );
+    write_file(&backup, xml.as_bytes())?;
+    reconciles_from(&mut td, &orig, &backup)
+}
+
+#[test]
+fn rejects_unreadable_reconcile_backup() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let backup = td.mk_path("backup.xml");
+    write_file(&backup, b"not a backup")?;
+
+    damage_mapping_subtree(&orig)?;
+    let repaired = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "-i",
+        &orig,
+        "-o",
+        &repaired,
+        "--reconcile",
+        &backup
+    ]))?;
+    assert!(stderr.contains("couldn't read the backup"));
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[test]
+fn rejects_output_too_small() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = td.mk_path("meta2.bin");
+    let _file = file_utils::create_sized_file(&md2, 8 * 4096);
+    let stderr = run_fail(thin_repair_cmd(args!["-i", &md1, "-o", &md2]))?;
+    assert!(stderr.contains("the output is too small"));
+    assert!(stderr.contains("--ignore-space-estimate"));
+
+    // nothing was written
+    assert!(std::fs::read(&md2)?.iter().all(|b| *b == 0));
+    Ok(())
+}
+
+#[test]
+fn writes_progress_records() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let stdout = run_ok(thin_repair_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--progress-fd",
+        "1"
+    ]))?;
+
+    // the last record shows all the mappings written
+    let (done, total) = *progress_records(&stdout)
+        .last()
+        .expect("no progress records");
+    assert_eq!(done, total);
+    assert_eq!(total, 1024);
+    Ok(())
+}
+
+#[test]
+fn sm_report_of_consistent_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let sm_report = td.mk_path("sm_report.txt");
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--sm-report",
+        &sm_report
+    ]))?;
+
+    let report = std::fs::read_to_string(&sm_report)?;
+    for line in [
+        "nr_data_blocks: 20480",
+        "counts_fixed: 0",
+        "leaked_blocks_freed: 0",
+        "blocks_claimed: 0",
+        "overflow_entries: 0",
+    ] {
+        assert!(report.lines().any(|l| l == line), "missing '{}'", line);
+    }
+    Ok(())
+}
+
+// Picks a number out of the json summary
+fn json_field(json: &str, name: &str) -> Option<u64> {
+    let key = format!("\"{}\": ", name);
+    let rest = &json[json.find(&key)? + key.len()..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+#[test]
+fn sm_report_counts_freed_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = prep_metadata(&mut td)?;
+    damage_mapping_subtree(&md1)?;
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    let sm_report = td.mk_path("sm_report.json");
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--salvage",
+        "--sm-report",
+        &sm_report,
+        "--sm-report-format",
+        "json"
+    ]))?;
+    run_ok(thin_check_cmd(args![&md2]))?;
+
+    // the blocks of the lost mappings are freed, or have fewer references
+    let json = std::fs::read_to_string(&sm_report)?;
+    let freed = json_field(&json, "leaked_blocks_freed").expect("no freed blocks");
+    let fixed = json_field(&json, "counts_fixed").expect("no fixed counts");
+    assert!(freed + fixed > 0);
+    assert_eq!(json_field(&json, "blocks_claimed"), Some(0));
+    Ok(())
+}
+
+#[test]
+fn sm_report_needs_binary_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let xml = td.mk_path("repaired.xml");
+    let sm_report = td.mk_path("sm_report.txt");
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--output-format",
+        "xml",
+        "-i",
+        &md1,
+        "-o",
+        &xml,
+        "--sm-report",
+        &sm_report
+    ]))?;
+    assert!(stderr.contains("no adjustments to report"));
+    Ok(())
+}
+
+// Damages the superblock of valid metadata, then repairs it with the given
+// arguments, overriding the fields that were lost.  Returns the dump of the
+// metadata taken before the damage, and the damaged input.
+fn repair_damaged_superblock(
+    td: &mut TestDir,
+    extra_args: &[&std::ffi::OsStr],
+) -> Result<(Vec<u8>, PathBuf)> {
+    let md = mk_valid_md(td)?;
+    let original = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    damage_superblock(&md)?;
+
+    let mut repair_args = args![
+        "--transaction-id=1",
+        "--data-block-size=128",
+        "--nr-data-blocks=20480",
+        "-i",
+        &md
+    ]
+    .to_vec();
+    repair_args.extend_from_slice(extra_args);
+    run_ok(thin_repair_cmd(repair_args))?;
+    Ok((original.stdout, md))
+}
+
+#[test]
+fn repairs_to_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = td.mk_path("repaired.xml");
+    let (original, _) =
+        repair_damaged_superblock(&mut td, &args!["--output-format", "xml", "-o", &xml])?;
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md2]))?;
+    let repaired = run_ok_raw(thin_dump_cmd(args![&md2]))?;
+    assert_eq!(original, repaired.stdout);
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_output_format() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_fail(thin_repair_cmd(args![
+        "--output-format",
+        "json",
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+fn find_packs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut packs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "pack") {
+            packs.push(path);
+        }
+    }
+    Ok(packs)
+}
+
+#[test]
+fn backs_up_the_input_to_the_given_dir() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let original = run_ok_raw(thin_dump_cmd(args![&md1]))?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let dir = td.mk_path("backups");
+    std::fs::create_dir(&dir)?;
+    run_ok(thin_repair_cmd(args![
+        "--backup-dir",
+        &dir,
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+
+    let packs = find_packs(&dir)?;
+    assert_eq!(packs.len(), 1);
+
+    let md3 = td.mk_path("unpacked.bin");
+    run_ok(thin_metadata_unpack_cmd(args!["-i", &packs[0], "-o", &md3]))?;
+    let unpacked = run_ok_raw(thin_dump_cmd(args![&md3]))?;
+    assert_eq!(original.stdout, unpacked.stdout);
+    Ok(())
+}
+
+#[test]
+fn backups_in_a_dir_dont_collide() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let dir = td.mk_path("backups");
+    std::fs::create_dir(&dir)?;
+    run_ok(thin_repair_cmd(args![
+        "--backup-dir",
+        &dir,
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+    run_ok(thin_repair_cmd(args![
+        "--backup-dir",
+        &dir,
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+    assert_eq!(find_packs(&dir)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn backs_up_the_input_to_the_given_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let backup = td.mk_path("backup.pack");
+    run_ok(thin_repair_cmd(args![
+        "--backup", &backup, "-i", &md1, "-o", &md2
+    ]))?;
+    assert_eq!(find_packs(md1.parent().unwrap())?, vec![backup]);
+    Ok(())
+}
+
+#[test]
+fn no_backup_by_default() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_repair_cmd(args!["-i", &md1, "-o", &md2]))?;
+    assert!(find_packs(md1.parent().unwrap())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn failed_backup_stops_the_repair() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let before = std::fs::read(&md2)?;
+    let dir = td.mk_path("missing");
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--backup-dir",
+        &dir,
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+    assert!(stderr.contains("couldn't back up the input"));
+    assert_eq!(std::fs::read(&md2)?, before);
+    Ok(())
+}
+
+#[test]
+fn wont_overwrite_a_backup() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let backup = td.mk_path("backup.pack");
+    std::fs::write(&backup, "an earlier backup")?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--backup", &backup, "-i", &md1, "-o", &md2
+    ]))?;
+    assert!(stderr.contains("already exists"));
+    assert_eq!(std::fs::read(&backup)?, b"an earlier backup");
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[test]
+fn scan_roots_lists_candidates() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stdout = run_ok(thin_repair_cmd(args!["--scan-roots", "-i", &md]))?;
+
+    let mut lines = stdout.lines();
+    assert!(lines.next().unwrap().starts_with("# index"));
+    let first: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+    assert_eq!(first.len(), 8);
+    assert_eq!(first[0], "0");
+    // the superblock refers to the latest roots
+    assert_eq!(first[7], "0");
+    Ok(())
+}
+
+#[test]
+fn repairs_from_chosen_roots() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let (original, _) = repair_damaged_superblock(&mut td, &args!["--use-roots", "0", "-o", &md2])?;
+    let repaired = run_ok_raw(thin_dump_cmd(args![&md2]))?;
+    assert_eq!(original, repaired.stdout);
+    Ok(())
+}
+
+#[test]
+fn rejects_missing_candidate_roots() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "--use-roots",
+        "1000",
+        "-i",
+        &md1,
+        "-o",
+        &md2
+    ]))?;
+    assert!(stderr.contains("there is no candidate root 1000"));
+    Ok(())
+}
+
+//-----------------------------------------
+
+fn journal_of(md: &Path) -> PathBuf {
+    let mut name = md.as_os_str().to_os_string();
+    name.push(".journal");
+    PathBuf::from(name)
+}
+
+#[test]
+fn repairs_in_place() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (original, md) = repair_damaged_superblock(&mut td, &args!["--in-place"])?;
+    let repaired = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    assert_eq!(original, repaired.stdout);
+    assert!(!journal_of(&md).exists());
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+// A committed journal holds the repaired metadata, followed by the
+// superblock of the device it was written against.
+fn mk_journal(repaired: &Path, dev: &Path) -> Result<()> {
+    let mut contents = std::fs::read(repaired)?;
+    contents.extend_from_slice(&std::fs::read(dev)?[..4096]);
+    write_file(&journal_of(dev), &contents)
+}
+
+#[test]
+fn replays_an_interrupted_repair() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let original = run_ok_raw(thin_dump_cmd(args![&md1]))?;
+
+    // a committed journal, and a device left half written
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_repair_cmd(args!["-i", &md1, "-o", &md2]))?;
+    damage_superblock(&md1)?;
+    mk_journal(&md2, &md1)?;
+
+    let output = run_ok_raw(thin_repair_cmd(args!["--in-place", "-i", &md1]))?;
+    assert!(std::str::from_utf8(&output.stderr)?.contains("replaying the journal"));
+    let replayed = run_ok_raw(thin_dump_cmd(args![&md1]))?;
+    assert_eq!(original.stdout, replayed.stdout);
+    assert!(!journal_of(&md1).exists());
+    Ok(())
+}
+
+#[test]
+fn wont_replay_a_journal_onto_a_changed_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_repair_cmd(args!["-i", &md1, "-o", &md2]))?;
+    mk_journal(&md2, &md1)?;
+
+    // the superblock of the device has changed since
+    damage_superblock(&md1)?;
+    ensure_untouched(&md1, || {
+        let stderr = run_fail(thin_repair_cmd(args!["--in-place", "-i", &md1]))?;
+        assert!(stderr.contains("the journal doesn't match"));
+        Ok(())
+    })?;
+    assert!(journal_of(&md1).exists());
+    Ok(())
+}
+
+#[test]
+fn wont_replay_a_journal_of_another_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md_sized(&mut td, 1024 * 1024 * 8)?;
+    run_ok(thin_repair_cmd(args!["-i", &md1, "-o", &md2]))?;
+    damage_superblock(&md1)?;
+    mk_journal(&md2, &md1)?;
+
+    ensure_untouched(&md1, || {
+        let stderr = run_fail(thin_repair_cmd(args!["--in-place", "-i", &md1]))?;
+        assert!(stderr.contains("for a device of another size"));
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[test]
+fn in_place_conflicts_with_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_fail(thin_repair_cmd(args!["--in-place", "-i", &md1, "-o", &md2]))?;
+    Ok(())
+}
+
+#[test]
+fn in_place_conflicts_with_overlay() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let overlay = td.mk_path("overlay");
+    ensure_untouched(&md, || {
+        run_fail(thin_repair_cmd(args![
+            "--in-place",
+            "-i",
+            &md,
+            "--overlay",
+            &overlay
+        ]))?;
+        Ok(())
+    })?;
+    assert!(!overlay.exists());
+    Ok(())
+}
+
+#[test]
+fn repair_to_an_overlay() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md1 = mk_valid_md(&mut td)?;
+    let original = run_ok_raw(thin_dump_cmd(args![&md1]))?;
+    let md2 = mk_zeroed_md(&mut td)?;
+    let overlay = td.mk_path("overlay");
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md2,
+        "--overlay",
+        &overlay
+    ]))?;
+
+    // the output is untouched, but reads as repaired through the overlay
+    run_fail(thin_dump_cmd(args![&md2]))?;
+    let repaired = run_ok_raw(thin_dump_cmd(args![&md2, "--overlay", &overlay]))?;
+    assert_eq!(original.stdout, repaired.stdout);
+
+    // and the overlay can't be applied to another device
+    let md3 = mk_zeroed_md(&mut td)?;
+    run_fail(thin_repair_cmd(args![
+        "-i",
+        &md1,
+        "-o",
+        &md3,
+        "--overlay",
+        &overlay
+    ]))?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[test]
+fn deterministic_repairs_are_byte_identical() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let out = mk_zeroed_md(&mut td)?;
+        run_ok(thin_repair_cmd(args![
+            "-i",
+            &md,
+            "-o",
+            &out,
+            "--deterministic"
+        ]))?;
+        outputs.push(std::fs::read(&out)?);
+    }
+    assert!(outputs[0] == outputs[1]);
+    Ok(())
+}
+
 //-----------------------------------------
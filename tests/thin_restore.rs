@@ -20,11 +20,16 @@ Usage: thin_restore [OPTIONS] --input <FILE> --output <FILE>
 
 Options:
       --data-block-size <SECTORS>  Override the data block size if needed
+      --deterministic              Lay the metadata out the same way on any machine, for reproducible output
   -h, --help                       Print help
   -i, --input <FILE>               Specify the input xml
+      --id-map <FILE>              Restore the original device ids recorded by thin_dump --id-map
+      --input-format <TYPE>        Choose the input format, xml or extents
       --nr-data-blocks <NUM>       Override the number of data blocks if needed
   -o, --output <FILE>              Specify the output device
+      --overlay <FILE>             Keep the writes to the metadata in an overlay file, and read through it
   -q, --quiet                      Suppress output messages, return only exit code.
+      --summary-format <TYPE>      Print the summary of what was restored as text or json
       --transaction-id <NUM>       Override the transaction id if needed
   -V, --version                    Print version";
 
@@ -159,3 +164,195 @@ fn override_nr_data_blocks() -> Result<()> {
 }
 
 //-----------------------------------------
+
+#[test]
+fn leaves_unused_blocks_of_a_file_unallocated() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+
+    // Fill the output, so every block starts off allocated
+    let md = td.mk_path("meta.bin");
+    let nr_bytes = 16 * 1024 * 1024;
+    write_file(&md, &vec![0xff; nr_bytes])?;
+
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?;
+
+    // The metadata only takes up a few dozen of the 4096 blocks
+    let info = std::fs::metadata(&md)?;
+    assert_eq!(info.len(), nr_bytes as u64);
+    assert!(info.blocks() * 512 < nr_bytes as u64 / 4);
+    Ok(())
+}
+
+#[test]
+fn deterministic_restores_are_byte_identical() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+
+    let mut outputs = Vec::new();
+    let mut paths = Vec::new();
+    for name in ["meta1.bin", "meta2.bin"] {
+        let md = td.mk_path(name);
+        let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+        run_ok(thin_restore_cmd(args![
+            "-i",
+            &xml,
+            "-o",
+            &md,
+            "--deterministic"
+        ]))?;
+        outputs.push(std::fs::read(&md)?);
+        paths.push(md);
+    }
+    assert!(outputs[0] == outputs[1]);
+
+    // and a dump of the restored metadata restores to the same blocks
+    let dumped = td.mk_path("dumped.xml");
+    run_ok(thin_dump_cmd(args![&paths[0], "-o", &dumped]))?;
+    let again = td.mk_path("meta3.bin");
+    let _file = thinp::file_utils::create_sized_file(&again, 4096 * 4096);
+    run_ok(thin_restore_cmd(args![
+        "-i",
+        &dumped,
+        "-o",
+        &again,
+        "--deterministic"
+    ]))?;
+    let redumped = td.mk_path("redumped.xml");
+    run_ok(thin_dump_cmd(args![&again, "-o", &redumped]))?;
+    assert_eq!(std::fs::read(&dumped)?, std::fs::read(&redumped)?);
+    Ok(())
+}
+
+//-----------------------------------------
+
+fn restore_extents(td: &mut TestDir, table: &str) -> Result<std::path::PathBuf> {
+    let input = td.mk_path("extents.csv");
+    std::fs::write(&input, table)?;
+    let md = mk_zeroed_md(td)?;
+
+    run_ok(thin_restore_cmd(args![
+        "-i",
+        &input,
+        "-o",
+        &md,
+        "--input-format",
+        "extents",
+        "--data-block-size",
+        "128"
+    ]))?;
+    Ok(md)
+}
+
+#[test]
+fn prints_a_summary_of_what_was_restored() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = td.mk_path("extents.csv");
+    std::fs::write(
+        &input,
+        "dev_id,virt_begin,data_begin,length,time\n0,0,0,10,0\n1,0,10,5,0\n",
+    )?;
+
+    let mut restore = |format: &str| -> Result<String> {
+        let md = mk_zeroed_md(&mut td)?;
+        run_ok(thin_restore_cmd(args![
+            "-i",
+            &input,
+            "-o",
+            &md,
+            "--input-format",
+            "extents",
+            "--data-block-size",
+            "128",
+            "--summary-format",
+            format
+        ]))
+    };
+
+    let text = restore("text")?;
+    let fields: Vec<(&str, u64)> = text
+        .lines()
+        .map(|l| {
+            let (name, v) = l.split_once(": ").unwrap();
+            (name, v.parse::<u64>().unwrap())
+        })
+        .collect();
+    assert_eq!(fields[0], ("devices_restored", 2));
+    assert_eq!(fields[1], ("mappings_written", 15));
+    assert_eq!(fields[2].0, "metadata_blocks");
+    assert_eq!(fields[3].0, "metadata_blocks_used");
+    assert_eq!(fields[4].0, "metadata_blocks_free");
+    assert_eq!(fields[3].1 + fields[4].1, fields[2].1);
+
+    let json = restore("json")?;
+    assert!(json.starts_with("{\n  \"devices_restored\": 2,\n  \"mappings_written\": 15,"));
+    assert!(json.ends_with('}'));
+    Ok(())
+}
+
+#[test]
+fn restores_extent_table() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let table = "dev_id,virt_begin,data_begin,length,time\n\
+                 # the origin\n\
+                 0,100,0,10,1\n\
+                 0,0,10,5,0\n\
+                 \n\
+                 1\t0\t15\t20\t2\n";
+    let md = restore_extents(&mut td, table)?;
+
+    run_ok(thin_check_cmd(args![&md]))?;
+    let output = run_ok(thin_dump_cmd(args![&md]))?;
+    assert!(output.contains("data_block_size=\"128\""));
+    assert!(output.contains("nr_data_blocks=\"35\""));
+    assert!(output.contains("dev_id=\"0\" mapped_blocks=\"15\""));
+    assert!(output.contains("dev_id=\"1\" mapped_blocks=\"20\""));
+    assert!(output.contains("range_mapping origin_begin=\"0\" data_begin=\"10\" length=\"5\""));
+    assert!(output.contains("origin_begin=\"100\" data_begin=\"0\" length=\"10\" time=\"1\""));
+    Ok(())
+}
+
+#[test]
+fn extent_table_needs_data_block_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = td.mk_path("extents.csv");
+    std::fs::write(&input, "0,0,0,1,0\n")?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    let stderr = run_fail(thin_restore_cmd(args![
+        "-i",
+        &input,
+        "-o",
+        &md,
+        "--input-format",
+        "extents"
+    ]))?;
+    assert!(stderr.contains("data block size"));
+    Ok(())
+}
+
+#[test]
+fn extent_table_rejects_overlaps() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = td.mk_path("extents.csv");
+    std::fs::write(&input, "0,0,0,10,0\n0,5,20,10,0\n")?;
+    let md = mk_zeroed_md(&mut td)?;
+
+    let stderr = run_fail(thin_restore_cmd(args![
+        "-i",
+        &input,
+        "-o",
+        &md,
+        "--input-format",
+        "extents",
+        "--data-block-size",
+        "128"
+    ]))?;
+    assert!(stderr.contains("overlapping extents"));
+    Ok(())
+}
+
+//-----------------------------------------
@@ -13,14 +13,21 @@ use common::thin::*;
 
 const USAGE: &str = "Output reverse map of a thin provisioned region of blocks
 
-Usage: thin_rmap --region <BLOCK_RANGE> <INPUT>
+Usage: thin_rmap [OPTIONS] <INPUT>
 
 Arguments:
   <INPUT>  Specify the input device
 
 Options:
+      --build-index <FILE>    Write an index of the reverse map of every data block, for queries with --index
+  -f, --format <TYPE>         Choose the output format [default: text] [possible values: text, json]
   -h, --help                  Print help
+      --index <FILE>          Answer from an index built with --build-index, rather than the mappings
+      --overlay <FILE>        Keep the writes to the metadata in an overlay file, and read through it
       --region <BLOCK_RANGE>  Specify range of blocks on the data device
+      --regions-file <FILE>   Read the ranges of blocks from a file, one per line, or '-' for stdin
+      --sector-list <FILE>    Read a list of bad 512 byte sectors, one per line, or '-' for stdin
+      --units <UNITS>         Give the regions in data blocks, 512 byte sectors or bytes [default: blocks] [possible values: blocks, sectors, bytes]
   -V, --version               Print version";
 
 //------------------------------------------
@@ -99,6 +106,202 @@ fn multiple_regions_should_pass() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn regions_file_matches_region_args() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let regions = td.mk_path("regions");
+    std::fs::write(&regions, "# scattered errors\n45..78\n\n1..23\n")?;
+
+    let expected = run_ok(thin_rmap_cmd(args![
+        &md, "--region", "1..23", "--region", "45..78"
+    ]))?;
+    let actual = run_ok(thin_rmap_cmd(args![&md, "--regions-file", &regions]))?;
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+#[test]
+fn bad_line_in_regions_file_should_fail() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let regions = td.mk_path("regions");
+    std::fs::write(&regions, "1..23\n89..88\n")?;
+
+    let stderr = run_fail(thin_rmap_cmd(args![&md, "--regions-file", &regions]))?;
+    assert!(stderr.contains("line 2"));
+    Ok(())
+}
+
+// Devices 1 and 2 share the leaf of device 0, as snapshots do
+fn mk_shared_md(td: &mut TestDir, transaction: u64) -> Result<std::path::PathBuf> {
+    let xml = td.mk_path(&format!("shared{}.xml", transaction));
+    let md = td.mk_path(&format!("shared{}.bin", transaction));
+    let mut contents = format!(
+        "<superblock uuid=\"\" time=\"1\" transaction=\"{}\" data_block_size=\"128\" nr_data_blocks=\"16\">\n",
+        transaction
+    );
+    contents += "  <def name=\"0\">\n";
+    contents +=
+        "    <range_mapping origin_begin=\"0\" data_begin=\"4\" length=\"4\" time=\"0\"/>\n";
+    contents += "  </def>\n";
+    for dev_id in 0..3 {
+        contents += &format!(
+            "  <device dev_id=\"{}\" mapped_blocks=\"4\" transaction=\"0\" creation_time=\"0\" snap_time=\"1\">\n",
+            dev_id
+        );
+        contents += "    <ref name=\"0\"/>\n";
+        contents += "  </device>\n";
+    }
+    contents += "</superblock>\n";
+    std::fs::write(&xml, contents)?;
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+#[test]
+fn every_sharing_device_is_listed() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let stdout = run_ok(thin_rmap_cmd(args![&md, "--region", "5..7"]))?;
+    assert_eq!(
+        stdout,
+        "data 5..7 -> thin(0) 1..3\ndata 5..7 -> thin(1) 1..3\ndata 5..7 -> thin(2) 1..3"
+    );
+    Ok(())
+}
+
+#[test]
+fn json_output_lists_every_sharing_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let stdout = run_ok(thin_rmap_cmd(args![
+        &md, "--region", "0..16", "--format", "json"
+    ]))?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], "[");
+    for dev_id in 0..3 {
+        let expected = format!(
+            "\"data_begin\": 4, \"data_end\": 8, \"dev_id\": {}, \"thin_begin\": 0, \"thin_end\": 4",
+            dev_id
+        );
+        assert!(lines[dev_id + 1].contains(&expected));
+    }
+    assert_eq!(lines[4], "]");
+    Ok(())
+}
+
+#[test]
+fn sectors_and_bytes_are_converted_to_data_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let expected = run_ok(thin_rmap_cmd(args![&md, "--region", "5..7"]))?;
+
+    // the data blocks are 128 sectors, and partly covered blocks are included
+    for (units, region) in [
+        ("sectors", "640..896"),
+        ("sectors", "700..800"),
+        ("bytes", "327680..458752"),
+        ("bytes", "327681..400000"),
+    ] {
+        let actual = run_ok(thin_rmap_cmd(args![
+            &md, "--units", units, "--region", region
+        ]))?;
+        assert_eq!(actual, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn sector_list_reports_the_devices_of_bad_sectors() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let expected = run_ok(thin_rmap_cmd(args![
+        &md, "--region", "1..2", "--region", "5..7"
+    ]))?;
+
+    // unsorted, with a repeat, as a merged list of scans may be
+    let sectors = td.mk_path("sectors");
+    std::fs::write(&sectors, "# from smartctl\n800\n700\n\n701\n700\n150\n")?;
+    let actual = run_ok(thin_rmap_cmd(args![&md, "--sector-list", &sectors]))?;
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+#[test]
+fn bad_line_in_sector_list_should_fail() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let sectors = td.mk_path("sectors");
+    std::fs::write(&sectors, "700\n70x\n")?;
+    let stderr = run_fail(thin_rmap_cmd(args![&md, "--sector-list", &sectors]))?;
+    assert!(stderr.contains("bad sector '70x' on line 2"));
+    Ok(())
+}
+
+#[test]
+fn index_answers_as_the_mappings_do() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let index = td.mk_path("rmap.idx");
+    run_ok(thin_rmap_cmd(args![&md, "--build-index", &index]))?;
+
+    for regions in [
+        vec!["--region", "23..7890"],
+        vec!["--region", "1..23", "--region", "45..78"],
+        vec![
+            "--region",
+            "0..1",
+            "--region",
+            "100..200",
+            "--region",
+            "150..4000",
+        ],
+    ] {
+        let mut direct = vec![md.as_os_str()];
+        direct.extend(regions.iter().map(std::ffi::OsStr::new));
+        let expected = run_ok(thin_rmap_cmd(direct.clone()))?;
+
+        direct.extend([std::ffi::OsStr::new("--index"), index.as_os_str()]);
+        let actual = run_ok(thin_rmap_cmd(direct))?;
+        assert_eq!(actual, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn index_lists_every_sharing_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let index = td.mk_path("rmap.idx");
+    run_ok(thin_rmap_cmd(args![&md, "--build-index", &index]))?;
+    let stdout = run_ok(thin_rmap_cmd(args![
+        &md, "--region", "5..7", "--index", &index
+    ]))?;
+    assert_eq!(
+        stdout,
+        "data 5..7 -> thin(0) 1..3\ndata 5..7 -> thin(1) 1..3\ndata 5..7 -> thin(2) 1..3"
+    );
+    Ok(())
+}
+
+#[test]
+fn stale_index_should_fail() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_shared_md(&mut td, 1)?;
+    let index = td.mk_path("rmap.idx");
+    run_ok(thin_rmap_cmd(args![&md, "--build-index", &index]))?;
+
+    let other = mk_shared_md(&mut td, 2)?;
+    let stderr = run_fail(thin_rmap_cmd(args![
+        &other, "--region", "5..7", "--index", &index
+    ]))?;
+    assert!(stderr.contains("rebuild it with --build-index"));
+    Ok(())
+}
+
 #[test]
 fn junk_input() -> Result<()> {
     let mut td = TestDir::new()?;
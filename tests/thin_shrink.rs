@@ -669,3 +669,108 @@ fn shrink_multiple_snaps_in_binary() -> Result<()> {
 }
 
 //------------------------------------
+
+// Rebuilds the metadata, without moving any data, to find the smallest
+// metadata device the shrunk pool could use.
+fn get_min_metadata_blocks(meta_before: &Path, meta_after: &Path, nr_blocks: &str) -> Result<u64> {
+    let nr_metadata_blocks = (file_utils::file_size(meta_after)? / 4096).to_string();
+    let stdout = run_ok(thin_shrink_cmd(args![
+        "-i",
+        meta_before,
+        "-o",
+        meta_after,
+        "--data",
+        "/dev/null",
+        "--nr-blocks",
+        nr_blocks,
+        "--binary",
+        "--no-copy",
+        "--metadata-nr-blocks",
+        &nr_metadata_blocks
+    ]))?;
+    let min = stdout
+        .lines()
+        .find_map(|l| l.strip_prefix("MIN_METADATA_BLOCKS="))
+        .ok_or_else(|| anyhow!("minimum metadata size not reported"))?;
+    Ok(min.parse::<u64>()?)
+}
+
+#[test]
+fn shrink_metadata_in_binary() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = prep_rebuilt_metadata(&mut td)?;
+    let xml_before = td.mk_path("before.xml");
+    let data_path = td.mk_path("data.bin");
+
+    run_ok(thin_dump_cmd(args![&meta_before, "-o", &xml_before]))?;
+    create_data_file(&data_path, &xml_before)?;
+
+    let mut rng = rand::thread_rng();
+    let seed = rng.gen::<u64>();
+    stamp(&xml_before, &data_path, seed)?;
+
+    let meta_after = td.mk_path("after.bin");
+    let xml_after = td.mk_path("after.xml");
+    let new_nr_blocks = get_data_usage(&meta_before)?.1.to_string();
+
+    file_utils::create_sized_file(&meta_after, file_utils::file_size(&meta_before)?)?;
+    let min = get_min_metadata_blocks(&meta_before, &meta_after, &new_nr_blocks)?;
+    assert!(min * 4096 < file_utils::file_size(&meta_before)?);
+
+    run_ok(thin_shrink_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--data",
+        &data_path,
+        "--nr-blocks",
+        &new_nr_blocks,
+        "--binary",
+        "--metadata-nr-blocks",
+        &min.to_string()
+    ]))?;
+
+    // the metadata device can now be reduced
+    OpenOptions::new()
+        .write(true)
+        .open(&meta_after)?
+        .set_len(min * 4096)?;
+    run_ok(thin_check_cmd(args![&meta_after]))?;
+    run_ok(thin_dump_cmd(args![&meta_after, "-o", &xml_after]))?;
+    verify(&xml_after, &data_path, seed)?;
+    Ok(())
+}
+
+#[test]
+fn shrink_metadata_insufficient_space_in_binary() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let meta_before = prep_rebuilt_metadata(&mut td)?;
+    let meta_after = td.mk_path("after.bin");
+    let new_nr_blocks = get_data_usage(&meta_before)?.1.to_string();
+
+    file_utils::create_sized_file(&meta_after, file_utils::file_size(&meta_before)?)?;
+    let min = get_min_metadata_blocks(&meta_before, &meta_after, &new_nr_blocks)?;
+
+    let stderr = run_fail(thin_shrink_cmd(args![
+        "-i",
+        &meta_before,
+        "-o",
+        &meta_after,
+        "--data",
+        "/dev/null",
+        "--nr-blocks",
+        &new_nr_blocks,
+        "--binary",
+        "--no-copy",
+        "--metadata-nr-blocks",
+        &(min - 1).to_string()
+    ]))?;
+    assert!(stderr.contains(&format!("the metadata needs at least {} blocks", min)));
+
+    // the trial rebuild mustn't be left looking like valid metadata
+    run_fail(thin_check_cmd(args![&meta_after]))?;
+    Ok(())
+}
+
+//------------------------------------
@@ -0,0 +1,151 @@
+#![cfg(feature = "thin_top")]
+
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::input_arg::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+const USAGE: &str = "Show the thin devices allocating or breaking sharing the fastest
+
+Usage: thin_top [OPTIONS] <INPUT>
+
+Arguments:
+  <INPUT>  Specify the input device
+
+Options:
+      --auto-snap <POOL>  Reserve a metadata snapshot of a live pool for each sample
+      --batch             Print a table after each interval rather than refresh the screen
+      --count <NUM>       Stop after printing this many tables
+  -h, --help              Print help
+      --interval <SECS>   Specify the seconds between samples [default: 5]
+      --overlay <FILE>    Keep the writes to the metadata in an overlay file, and read through it
+  -V, --version           Print version";
+
+//------------------------------------------
+
+struct ThinTop;
+
+impl<'a> Program<'a> for ThinTop {
+    fn name() -> &'a str {
+        "thin_top"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_top_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::InputArg
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for ThinTop {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_valid_md(td)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        msg::BAD_SUPERBLOCK
+    }
+}
+
+//------------------------------------------
+
+test_accepts_help!(ThinTop);
+test_accepts_version!(ThinTop);
+test_rejects_bad_option!(ThinTop);
+
+test_missing_input_arg!(ThinTop);
+test_input_file_not_found!(ThinTop);
+test_input_cannot_be_a_directory!(ThinTop);
+
+//------------------------------------------
+
+#[test]
+fn batch_prints_a_table_of_the_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stdout = run_ok(thin_top_cmd(args![
+        "--batch",
+        "--count",
+        "1",
+        "--interval",
+        "1",
+        &md
+    ]))?;
+
+    let mut lines = stdout.lines();
+    assert!(lines.next().unwrap().starts_with("transaction "));
+    let header: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+    assert_eq!(
+        header,
+        [
+            "DEV",
+            "MAPPED",
+            "EXCLUSIVE",
+            "SHARED",
+            "ALLOC/s",
+            "UNSHARE/s"
+        ]
+    );
+
+    // the metadata doesn't change, so every device is idle
+    let rows: Vec<Vec<&str>> = lines
+        .filter(|l| !l.is_empty())
+        .map(|l| l.split_whitespace().collect())
+        .collect();
+    assert!(!rows.is_empty());
+    for row in rows {
+        assert_eq!(row.len(), 6);
+        assert_eq!(&row[4..], ["0.0", "0.0"]);
+    }
+    Ok(())
+}
+
+#[test]
+fn count_needs_batch() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_top_cmd(args!["--count", "1", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn metadata_snap_is_refused() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_top_cmd(args!["--batch", "-m", &md]))?;
+    assert!(stderr.contains("unexpected argument"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,225 @@
+use anyhow::Result;
+
+mod common;
+
+use common::process::*;
+use common::progress::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+// A pool of 16 blocks of 64KiB, with blocks 4 to 7 mapped
+fn mk_pool(td: &mut TestDir) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+    let xml = td.mk_path("meta.xml");
+    let md = td.mk_path("meta.bin");
+    let data = td.mk_path("data.bin");
+
+    let mut contents = String::new();
+    contents += "<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"16\">\n";
+    contents += "  <device dev_id=\"0\" mapped_blocks=\"4\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">\n";
+    contents +=
+        "    <range_mapping origin_begin=\"0\" data_begin=\"4\" length=\"4\" time=\"0\"/>\n";
+    contents += "  </device>\n</superblock>\n";
+    std::fs::write(&xml, contents)?;
+
+    let _file = thinp::file_utils::create_sized_file(&md, 4096 * 4096);
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let _file = thinp::file_utils::create_sized_file(&data, 16 * 65536);
+    Ok((md, data))
+}
+
+#[test]
+fn dry_run_reports_the_reclaimable_space() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    let stdout = run_ok(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--dry-run"
+    ]))?;
+    assert_eq!(
+        stdout,
+        "blocks 0..4: 4 blocks, 262144 bytes\n\
+         blocks 8..16: 8 blocks, 524288 bytes\n\
+         total: 12 blocks, 786432 bytes"
+    );
+    Ok(())
+}
+
+#[test]
+fn discards_are_rounded_to_whole_granules() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    let stdout = run_ok(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--dry-run",
+        "--discard-granularity",
+        "196608"
+    ]))?;
+    assert_eq!(
+        stdout,
+        "blocks 0..4: 4 blocks, 196608 bytes\n\
+         blocks 8..16: 8 blocks, 393216 bytes\n\
+         total: 12 blocks, 589824 bytes"
+    );
+    Ok(())
+}
+
+#[test]
+fn device_granularity_needs_a_block_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    let stderr = run_fail(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--dry-run",
+        "--align-discards"
+    ]))?;
+    assert!(stderr.contains("isn't a block device"));
+    Ok(())
+}
+
+#[test]
+fn trims_can_be_limited_to_a_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    let stdout = run_ok(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--dry-run",
+        "--range",
+        "2..10"
+    ]))?;
+    assert_eq!(
+        stdout,
+        "blocks 2..4: 2 blocks, 131072 bytes\n\
+         blocks 8..10: 2 blocks, 131072 bytes\n\
+         total: 4 blocks, 262144 bytes"
+    );
+    Ok(())
+}
+
+#[test]
+fn range_must_be_within_the_data_device() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    let stderr = run_fail(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--dry-run",
+        "--range",
+        "8..17"
+    ]))?;
+    assert!(stderr.contains("beyond the end of the data device"));
+    Ok(())
+}
+
+#[test]
+fn dry_run_checks_the_data_device_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, _) = mk_pool(&mut td)?;
+    let small = td.mk_path("small.bin");
+    let _file = thinp::file_utils::create_sized_file(&small, 65536);
+    let stderr = run_fail(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &small,
+        "--dry-run"
+    ]))?;
+    assert!(stderr.contains("unexpected data device size"));
+    Ok(())
+}
+
+#[test]
+fn trim_punches_the_free_blocks_of_a_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    std::fs::write(&data, vec![0xff; 16 * 65536])?;
+
+    let stdout = run_ok(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--progress-fd",
+        "1"
+    ]))?;
+
+    // only the free blocks are discarded
+    let contents = std::fs::read(&data)?;
+    for (b, block) in contents.chunks(65536).enumerate() {
+        let mapped = (4..8).contains(&b);
+        assert!(block.iter().all(|v| *v == if mapped { 0xff } else { 0 }));
+    }
+
+    // a record at most for each percent, ending with the whole span done
+    let records = progress_records(&stdout);
+    assert_eq!(records.len(), stdout.lines().count());
+    assert!(!records.is_empty() && records.len() <= 101);
+    assert!(records.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(records.last(), Some(&(16, 16)));
+    Ok(())
+}
+
+// Fills the data device, and runs a trim with a journal left by an earlier
+// trim that got as far as the given block
+fn trim_with_journal(next_block: u64) -> Result<(Vec<u8>, bool)> {
+    let mut td = TestDir::new()?;
+    let (md, data) = mk_pool(&mut td)?;
+    std::fs::write(&data, vec![0xff; 16 * 65536])?;
+
+    let journal = td.mk_path("trim.journal");
+    std::fs::write(
+        &journal,
+        format!(
+            "# transaction_id nr_data_blocks begin end next_block\n1 16 0 16 {}\n",
+            next_block
+        ),
+    )?;
+
+    run_ok(thin_trim_cmd(args![
+        "--metadata-dev",
+        &md,
+        "--data-dev",
+        &data,
+        "--journal",
+        &journal
+    ]))?;
+    Ok((std::fs::read(&data)?, journal.exists()))
+}
+
+#[test]
+fn trim_resumes_from_the_journal() -> Result<()> {
+    let (contents, journal_left) = trim_with_journal(8)?;
+
+    // the free blocks before the position were trimmed by the earlier run
+    for (b, block) in contents.chunks(65536).enumerate() {
+        let trimmed = b >= 8;
+        assert!(block.iter().all(|v| *v == if trimmed { 0 } else { 0xff }));
+    }
+    assert!(!journal_left);
+    Ok(())
+}
+
+#[test]
+fn trim_that_had_completed_resumes_at_the_end() -> Result<()> {
+    let (contents, journal_left) = trim_with_journal(16)?;
+    assert!(contents.iter().all(|v| *v == 0xff));
+    assert!(!journal_left);
+    Ok(())
+}
+
+//------------------------------------------